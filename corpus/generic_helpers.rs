@@ -0,0 +1,28 @@
+use std::fmt::Display;
+
+fn print_all<T: Display>(label: &str, items: &[T]) {
+    for item in items {
+        println!("{}: {}", label, item);
+    }
+}
+
+fn largest<T>(items: &[T]) -> T
+where
+    T: PartialOrd + Copy,
+{
+    let mut largest = items[0];
+    for &item in items {
+        if item > largest {
+            largest = item;
+        }
+    }
+    largest
+}
+
+fn main() {
+    let numbers = vec![3, 7, 2];
+    let words = vec!["alpha", "beta"];
+    print_all("number", &numbers);
+    print_all("word", &words);
+    println!("largest: {}", largest(&numbers));
+}
@@ -0,0 +1,8 @@
+use std::io::{self, BufRead};
+
+fn main() {
+    let stdin = io::stdin();
+    let mut name = String::new();
+    stdin.lock().read_line(&mut name).unwrap();
+    println!("Hello, {}!", name.trim());
+}
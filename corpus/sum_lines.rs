@@ -0,0 +1,13 @@
+use std::io::{self, BufRead};
+
+fn main() {
+    let mut total = 0;
+    for line in io::stdin().lock().lines() {
+        let line = line.unwrap();
+        match line.trim().parse::<i64>() {
+            Ok(n) => total += n,
+            Err(_) => eprintln!("skipping {:?}", line),
+        }
+    }
+    println!("Total: {}", total);
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{SinkExt, StreamExt};
+use hydro_deploy::Deployment;
+use hydro_template::chat_server::ChatEvent;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// Where the legacy server listened
+const ADDRESS: &str = "127.0.0.1:7878";
+
+#[tokio::main]
+async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
+
+    let mut deployment = Deployment::new();
+
+    let flow = hydro_lang::FlowBuilder::new();
+    let process = flow.process::<()>();
+    let external = flow.external_process::<()>();
+
+    let (events_port, messages_port) = hydro_template::chat_server::chat_server(&process, &external);
+
+    let nodes = flow
+        .with_process(&process, deployment.Localhost())
+        .with_external(&external, deployment.Localhost())
+        .deploy(&mut deployment);
+
+    tracing::info!(event = "deploy_started", "Starting deployment");
+
+    deployment.deploy().await.unwrap();
+
+    let mut events_sink = nodes.connect_sink_bincode(events_port).await;
+    let mut messages = nodes.connect_source_bincode(messages_port).await;
+    deployment.start().await.unwrap();
+    tracing::info!(event = "process_launched", "Dataflow started");
+
+    // Events go to the dataflow in the order they happen, from every connection
+    let (events, mut forwarded) = tokio::sync::mpsc::unbounded_channel::<ChatEvent>();
+    tokio::spawn(async move {
+        while let Some(event) = forwarded.recv().await {
+            events_sink.send(event).await.unwrap();
+        }
+    });
+    // Messages go to the connection they are for, if it is still open
+    let connections: Arc<Mutex<HashMap<u64, tokio::sync::mpsc::UnboundedSender<String>>>> = Default::default();
+    {
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            while let Some((connection, message)) = messages.next().await {
+                if let Some(sender) = connections.lock().unwrap().get(&connection) {
+                    let _ = sender.send(message);
+                }
+            }
+        });
+    }
+
+    // Accept clients where the legacy server did
+    let listener = tokio::net::TcpListener::bind(ADDRESS).await.unwrap();
+    tracing::info!(event = "service_listening", address = ADDRESS);
+    let mut next = 0u64;
+    while let Ok((stream, _)) = listener.accept().await {
+        let connection = next;
+        next += 1;
+        let (reader, mut writer) = stream.into_split();
+
+        let (sender, mut outgoing) = tokio::sync::mpsc::unbounded_channel::<String>();
+        connections.lock().unwrap().insert(connection, sender);
+        tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if writer.write_all(format!("{}\n", message).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let events = events.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            events.send(ChatEvent::Joined(connection)).unwrap();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                events.send(ChatEvent::Said(connection, line)).unwrap();
+            }
+            events.send(ChatEvent::Left(connection)).unwrap();
+            connections.lock().unwrap().remove(&connection);
+        });
+    }
+}
@@ -2,6 +2,12 @@ use hydro_deploy::Deployment;
 use tokio::time::{timeout, Duration};
 #[tokio::main]
 async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
     let mut deployment = Deployment::new();
     let flow = hydro_lang::FlowBuilder::new();
     let process = flow.process::<()>();
@@ -9,27 +15,22 @@ async fn main() {
     let _nodes = flow
         .with_process(&process, deployment.Localhost())
         .deploy(&mut deployment);
-    println!("Starting I/O-aware Hydro deployment...");
-    println!("Note: stdin input is mocked with sample data");
-    println!("Looking for 'running command:' output...");
+    tracing::info!(event = "deploy_started", stdin = "mocked", "Starting deployment");
     deployment.deploy().await.unwrap();
     let start_result = timeout(
             Duration::from_secs(60),
             async {
                 deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
             },
         )
         .await;
     match start_result {
         Ok(_) => {
-            println!("✓ Deployment completed successfully");
+            tracing::info!(event = "stream_completed", "Deployment completed successfully");
         }
         Err(_) => {
-            println!("✓ Deployment reached 60-second timeout");
-            println!("If you saw output containing:");
-            println!("  [() (process 0)] running command: `...`");
-            println!("  [() (process 0)] <your program output>");
-            println!("Then the I/O transformation worked correctly!");
+            tracing::warn!(event = "timeout", timeout_secs = 60, "Deployment did not complete in time");
         }
     }
 }
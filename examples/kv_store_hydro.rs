@@ -0,0 +1,66 @@
+use futures::{SinkExt, StreamExt};
+use hydro_deploy::Deployment;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+#[tokio::main]
+async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
+
+    let mut deployment = Deployment::new();
+
+    let flow = hydro_lang::FlowBuilder::new();
+    let process = flow.process::<()>();
+    let external = flow.external_process::<()>();
+
+    let (requests_port, responses_port) = hydro_template::kv_store::kv_store(&process, &external);
+
+    let nodes = flow
+        .with_process(&process, deployment.Localhost())
+        .with_external(&external, deployment.Localhost())
+        .deploy(&mut deployment);
+
+    tracing::info!(event = "deploy_started", "Starting deployment");
+
+    deployment.deploy().await.unwrap();
+
+    let mut requests = nodes.connect_sink_bincode(requests_port).await;
+    let mut responses = nodes.connect_source_bincode(responses_port).await;
+    deployment.start().await.unwrap();
+    tracing::info!(event = "process_launched", "Dataflow started");
+
+    // Each line of stdin is a request, sent as soon as it is read
+    let (sent, mut requests_sent) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut count = 0usize;
+        while let Ok(Some(line)) = lines.next_line().await {
+            requests.send(line).await.unwrap();
+            count += 1;
+        }
+        let _ = sent.send(count);
+    });
+
+    // Print the responses as they come, until every request of the closed stdin is answered
+    let (mut answered, mut expected) = (0usize, None);
+    while expected != Some(answered) {
+        tokio::select! {
+            count = &mut requests_sent, if expected.is_none() => expected = Some(count.unwrap()),
+            response = responses.next() => match response {
+                Some(response) => {
+                    println!("{}", response);
+                    answered += 1;
+                }
+                None => {
+                    tracing::warn!(event = "stream_ended", "Dataflow stopped without answering every request");
+                    return;
+                }
+            },
+        }
+    }
+    tracing::info!(event = "stream_completed", requests = answered, "Every request answered");
+}
@@ -0,0 +1,50 @@
+use hydro_deploy::Deployment;
+use tokio::time::{timeout, Duration};
+/// The number of workers when none is given on the command line
+const DEFAULT_WORKERS: usize = 4;
+#[tokio::main]
+async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
+    let workers_count: usize = match std::env::args().skip(1).find(|arg| arg != "--quiet") {
+        Some(arg) => {
+            arg.parse()
+                .ok()
+                .filter(|&count| count > 0)
+                .expect("usage: [number of workers, at least 1]")
+        }
+        None => DEFAULT_WORKERS,
+    };
+    let mut deployment = Deployment::new();
+    let localhost = deployment.Localhost();
+    let flow = hydro_lang::FlowBuilder::new();
+    let leader = flow.process();
+    let workers = flow.cluster();
+    hydro_template::map_reduce::map_reduce(&leader, &workers);
+    let _nodes = flow
+        .with_process(&leader, localhost.clone())
+        .with_cluster(&workers, vec![localhost.clone(); workers_count])
+        .deploy(&mut deployment);
+    tracing::info!(event = "deploy_started", workers = workers_count, "Starting deployment");
+    deployment.deploy().await.unwrap();
+    let start_result = timeout(
+            Duration::from_secs(60),
+            async {
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+            },
+        )
+        .await;
+    match start_result {
+        Ok(_) => {
+            tracing::info!(event = "stream_completed", "Deployment completed successfully");
+        }
+        Err(_) => {
+            tracing::warn!(event = "timeout", timeout_secs = 60, "Deployment did not complete in time");
+        }
+    }
+}
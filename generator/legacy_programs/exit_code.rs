@@ -0,0 +1,5 @@
+fn main() {
+    println!("Validating input...");
+    eprintln!("error: no input provided");
+    std::process::exit(3);
+}
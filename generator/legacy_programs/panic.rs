@@ -0,0 +1,6 @@
+fn main() {
+    let values = vec![1, 2, 3];
+    for i in 0..4 {
+        println!("Value: {}", values[i]);
+    }
+}
@@ -1,53 +1,369 @@
+use std::collections::BTreeSet;
 use std::fs;
-use std::path::Path;
-use regex::Regex;
-use clap::{Arg, Command};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use std::io::{BufRead, Write};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use rayon::prelude::*;
+use syn::Item;
+use syn::spanned::Spanned;
+use hydro_template::assess::Assessment;
+use hydro_template::backend::Transformer;
+use hydro_template::build::{check_module_name, legacy_files, write_if_changed};
+use hydro_template::compose::Composition;
+use hydro_template::config::{Distribution, Lowering, StdinMode, Target, TransformerConfig, UnsafeCode};
+use hydro_template::data_parallel::ParallelLoop;
+use hydro_template::io_transformer::IOOperationType;
+use hydro_template::keyed_aggregation::KeyedAggregation;
+use hydro_template::seeded_rng::seed_rng_calls;
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+use hydro_template::target_cfg::TargetCfg;
+use hydro_template::dataflow_graph::{DataflowGraph, GraphFormat};
+use hydro_template::formatting::format_wrapped_module;
+use hydro_template::json_diagnostics::{error_to_json, report_to_json};
+use hydro_template::output::TransformOutput;
+use hydro_template::remote_hosts::RemoteHosts;
+use hydro_template::report::TransformReport;
+use hydro_template::transform_diff::TransformDiff;
+use hydro_template::user_types::UserTypes;
 
-pub struct LegacyToHydroTransformer;
+/// The manifest of a fresh template crate. Generated examples name the crate
+/// `hydro_template`, so it keeps that name wherever it is created, and its metadata marks
+/// it as a template for the generator to find.
+const TEMPLATE_CARGO_TOML: &str = r#"[package]
+name = "hydro-template"
+publish = false
+version = "0.0.0"
+edition = "2024"
+
+[package.metadata.hydro-ingest]
+template = true
+
+[dependencies]
+hydro_lang = { git = "https://github.com/hydro-project/hydro.git", branch = "main" }
+hydro_std = { git = "https://github.com/hydro-project/hydro.git", branch = "main" }
+stageleft = "0.9.4"
+
+[build-dependencies]
+stageleft_tool = "0.9.4"
+
+[dev-dependencies]
+ctor = "0.2"
+hydro_deploy = { git = "https://github.com/hydro-project/hydro.git", branch = "main" }
+hydro_lang = { git = "https://github.com/hydro-project/hydro.git", branch = "main", features = ["deploy"] }
+tokio = { version = "1.29.0", features = ["full"] }
+futures = "0.3.0"
+tracing = "0.1"
+tracing-subscriber = { version = "0.3", features = ["json"] }
+
+[lints.clippy]
+uninlined_format_args = "allow"
+"#;
+
+const TEMPLATE_BUILD_RS: &str = "fn main() {\n    stageleft_tool::gen_final!();\n}\n";
+
+const TEMPLATE_TOOLCHAIN: &str = r#"[toolchain]
+channel = "nightly-2025-04-27"
+components = ["rustfmt", "clippy"]
+targets = ["x86_64-unknown-linux-musl"]
+"#;
+
+/// The manifest of the programs generated into a template crate, one per line as the
+/// module name, the legacy program it came from and the hash of its input, separated
+/// by tabs
+const MANIFEST_FILE: &str = "generated.manifest";
+
+/// The example deploying a generated program, unless `--example-template` names another.
+/// It's built into the binary, so the generator runs from anywhere.
+const EXAMPLE_TEMPLATE: &str = include_str!("../templates/generated_example.rs.template");
+
+/// The line of an example template the call of the generated function replaces
+const FUNCTION_CALL_PLACEHOLDER: &str = "// GENERATED_FUNCTION_CALL_PLACEHOLDER";
+
+/// Names a generated program can't take, as files every template crate has
+const RESERVED_NAMES: [&str; 3] = ["lib", "main", "all_examples"];
+
+/// The library of a fresh template crate, which generated modules are declared in
+const TEMPLATE_LIB_RS: &str = r#"stageleft::stageleft_no_entry_crate!();
+
+#[cfg(test)]
+mod test_init {
+    #[ctor::ctor]
+    fn init() {
+        hydro_lang::deploy::init_test();
+    }
+}
+"#;
+
+pub struct LegacyToHydroTransformer {
+    /// The format to also write the generated dataflow's graph in, if any
+    graph: Option<GraphFormat>,
+    /// The library backend generating the code, instead of the generator's own
+    backend: Option<Box<dyn Transformer>>,
+    /// Whether programs are generated again even when their input hasn't changed
+    force: bool,
+    /// Whether a module of the template crate with the name of a program is replaced
+    overwrite: bool,
+    /// Whether diagnostics are printed to stderr as rustc-style JSON, rather than for people
+    json_diagnostics: bool,
+    /// The options the backend was configured with
+    config: TransformerConfig,
+    /// How to show which operators the legacy statements became, if at all
+    diff: Option<DiffFormat>,
+    /// The machines to also write an example deploying onto, if any
+    remote_hosts: Option<RemoteHosts>,
+    /// The template of the example to write, instead of the built-in one
+    example_template: Option<PathBuf>,
+}
+
+/// The layouts of `--diff`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiffFormat {
+    Unified,
+    SideBySide,
+}
 
 impl LegacyToHydroTransformer {
     pub fn new() -> Self {
-        Self
+        Self {
+            graph: None,
+            backend: None,
+            force: false,
+            overwrite: false,
+            json_diagnostics: false,
+            config: TransformerConfig::new(),
+            diff: None,
+            remote_hosts: None,
+            example_template: None,
+        }
+    }
+
+    pub fn with_graph(mut self, graph: Option<GraphFormat>) -> Self {
+        self.graph = graph;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Option<Box<dyn Transformer>>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    pub fn with_json_diagnostics(mut self, json: bool) -> Self {
+        self.json_diagnostics = json;
+        self
+    }
+
+    pub fn with_diff(mut self, diff: Option<DiffFormat>) -> Self {
+        self.diff = diff;
+        self
+    }
+
+    pub fn with_remote_hosts(mut self, hosts: Option<RemoteHosts>) -> Self {
+        self.remote_hosts = hosts;
+        self
+    }
+
+    pub fn with_example_template(mut self, template: Option<PathBuf>) -> Self {
+        self.example_template = template;
+        self
+    }
+
+    /// Record the options the backend was configured with, so programs are generated
+    /// again when they change
+    pub fn with_config(mut self, config: TransformerConfig) -> Self {
+        self.config = config;
+        self
     }
 
     pub fn transform_program(&self, input_path: &Path, output_name: &str, template_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
-        let legacy_code = fs::read_to_string(input_path)?;
-        let main_body = self.extract_main_body(&legacy_code)?;
+        check_output_name(output_name)?;
+        if let Some(taken) = self.name_taken(template_dir, output_name, input_path)? {
+            return Err(format!("{} is already taken by {}; pass --overwrite to replace it, or choose another output name", output_name, taken).into());
+        }
+        let input_hash = self.input_hash(input_path)?;
+        if self.is_up_to_date(template_dir, output_name, input_hash)? {
+            println!("✓ {} is up to date with {}", output_name, input_path.display());
+            return Ok(());
+        }
+
+        let generated = self
+            .write_program(input_path, output_name, template_dir)
+            .inspect_err(|e| if self.json_diagnostics { eprintln!("{}", self.failure(input_path, &**e)) })?;
+        self.register_program(template_dir, output_name, input_path, input_hash)?;
+        self.write_examples_registry(template_dir)?;
+
+        println!("✓ Generated Hydro program:");
+        println!("  - Module: {}", generated.module.display());
+        println!("  - Example: {}", generated.example.display());
+        if let Some(graph_path) = &generated.graph {
+            println!("  - Graph: {}", graph_path.display());
+        }
+        if let Some(remote_path) = &generated.remote_example {
+            println!("  - Remote example: {}", remote_path.display());
+        }
+        self.print_diagnostics(input_path, &generated.output.report);
+        if let Some(format) = self.diff {
+            let diff = TransformDiff::new(&fs::read_to_string(input_path)?, &generated.output)?;
+            println!();
+            match format {
+                DiffFormat::Unified => print!("{}", diff.render_unified(&input_path.display().to_string(), &generated.module.display().to_string())),
+                DiffFormat::SideBySide => print!("{}", diff.render_side_by_side(60)),
+            }
+        }
+        println!("\nTo run: cd {} && cargo run --example {}", template_dir.display(), output_name);
         
-        let hydro_function = self.generate_hydro_function(&main_body, output_name)?;
-        let example_program = self.generate_example_program(output_name)?;
+        Ok(())
+    }
+
+    /// The constructs of a legacy program carried over as written, as rustc-style JSON
+    /// on stderr or listed for people on stdout
+    fn print_diagnostics(&self, input_path: &Path, report: &TransformReport) {
+        if self.json_diagnostics {
+            let source = fs::read_to_string(input_path).unwrap_or_default();
+            for line in report_to_json(report, &input_path.display().to_string(), &source) {
+                eprintln!("{}", line);
+            }
+        } else if !report.is_clean() {
+            println!("  To rewrite by hand in {}:", input_path.display());
+            for line in report.to_string().lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+
+    /// Why a legacy program couldn't be generated, as a JSON diagnostic when those are
+    /// asked for
+    fn failure(&self, input_path: &Path, error: &(dyn std::error::Error + 'static)) -> String {
+        if self.json_diagnostics {
+            let source = fs::read_to_string(input_path).unwrap_or_default();
+            error_to_json(error, &input_path.display().to_string(), &source)
+        } else {
+            error.to_string()
+        }
+    }
+
+    /// Generate the module and example of a legacy program and write them, without
+    /// touching the files shared by every generated program
+    fn write_program(&self, input_path: &Path, output_name: &str, template_dir: &Path) -> Result<GeneratedFiles, Box<dyn std::error::Error>> {
+        let output = match &self.backend {
+            // The library backends write examples of their own
+            Some(_) if self.example_template.is_some() => {
+                return Err("--example-template only applies to the generator's own backend; leave out --backend".into());
+            }
+            Some(backend) => backend.transform_program(input_path, output_name)?,
+            None => {
+                let legacy_code = fs::read_to_string(input_path)?;
+                let main_body = self.extract_main_body(&legacy_code)?;
+                let use_statements = self.extract_use_statements(&legacy_code);
+                // Source that doesn't parse is still wrapped as written, with nothing to report
+                let file = syn::parse_file(&legacy_code).unwrap_or(syn::File { shebang: None, attrs: Vec::new(), items: Vec::new() });
+                let hydro_function = self.generate_hydro_function(&main_body, &use_statements, output_name)? + &self.user_type_definitions(&file);
+                TransformOutput::new(&file, format_wrapped_module(&file, &hydro_function), self.generate_example_program(output_name)?)
+            }
+        };
+        let hydro_function = &output.hydro_function;
         
         // Write to template directory
         let hydro_module_path = template_dir.join("src").join(format!("{}.rs", output_name));
-        fs::write(&hydro_module_path, &hydro_function)?;
+        fs::write(&hydro_module_path, hydro_function)?;
         
         let example_path = template_dir.join("examples").join(format!("{}.rs", output_name));
-        fs::write(&example_path, &example_program)?;
-        
-        // Update lib.rs to include the new module
+        fs::write(&example_path, &output.example_program)?;
+
+        // The topology of the generated dataflow, to check against the one expected
+        let graph_path = match self.graph {
+            Some(format) => {
+                let graph = DataflowGraph::from_module(hydro_function)?;
+                let graph_path = template_dir.join("examples").join(format!("{}.{}", output_name, format.extension()));
+                fs::write(&graph_path, graph.render(format))?;
+                Some(graph_path)
+            }
+            None => None,
+        };
+
+        // The same deployment on the machines of the deployment file
+        let remote_path = match &self.remote_hosts {
+            Some(hosts) => {
+                let remote_path = template_dir.join("examples").join(format!("{}_remote.rs", output_name));
+                fs::write(&remote_path, hosts.remote_example(&output.example_program)?)?;
+                Some(remote_path)
+            }
+            None => None,
+        };
+
+        Ok(GeneratedFiles { module: hydro_module_path, example: example_path, graph: graph_path, remote_example: remote_path, output })
+    }
+
+    /// Declare a written program in lib.rs and record it in the manifest. The registry
+    /// of every generated program is written separately, once they are all recorded.
+    fn register_program(&self, template_dir: &Path, output_name: &str, input_path: &Path, input_hash: u64) -> Result<(), Box<dyn std::error::Error>> {
         self.update_lib_rs(template_dir, output_name)?;
-        
-        println!("✓ Generated Hydro program:");
-        println!("  - Module: {}", hydro_module_path.display());
-        println!("  - Example: {}", example_path.display());
-        println!("\nTo run: cd {} && cargo run --example {}", template_dir.display(), output_name);
-        
-        Ok(())
+        self.record_artifact(template_dir, output_name, input_path, input_hash)
     }
 
-    fn generate_hydro_function(&self, main_body: &str, function_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    /// A hash of a legacy program, the options that shape what is generated from it, the
+    /// example template and the generator's version, so a new release regenerates it.
+    /// It's FNV-1a, so it stays the same from one build of the generator to the next.
+    fn input_hash(&self, input_path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let backend = self.backend.as_ref().map_or("generator", |backend| backend.name());
+        let graph = self.graph.map_or("", |format| format.extension());
+        let config = self.config.to_file_contents();
+        let hosts = self.remote_hosts.as_ref().map_or(String::new(), |hosts| format!("{:?}", hosts));
+        let example_template = match &self.example_template {
+            Some(path) => fs::read(path)?,
+            None => EXAMPLE_TEMPLATE.as_bytes().to_vec(),
+        };
+        let version = env!("CARGO_PKG_VERSION");
+        for part in [fs::read(input_path)?.as_slice(), version.as_bytes(), backend.as_bytes(), graph.as_bytes(), config.as_bytes(), hosts.as_bytes(), &example_template] {
+            for byte in part.iter().chain([&0]) {
+                hash ^= u64::from(*byte);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Whether a program was last generated from an input with the same hash, and its
+    /// files are still there
+    fn is_up_to_date(&self, template_dir: &Path, output_name: &str, input_hash: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.force {
+            return Ok(false);
+        }
+        let recorded = self
+            .generated_artifacts(template_dir)?
+            .into_iter()
+            .any(|artifact| artifact.name == output_name && artifact.input_hash == Some(input_hash));
+        let module = template_dir.join("src").join(format!("{}.rs", output_name));
+        let example = template_dir.join("examples").join(format!("{}.rs", output_name));
+        let remote = template_dir.join("examples").join(format!("{}_remote.rs", output_name));
+        Ok(recorded && module.exists() && example.exists() && (self.remote_hosts.is_none() || remote.exists()))
+    }
+
+    fn generate_hydro_function(&self, main_body: &str, use_statements: &str, function_name: &str) -> Result<String, Box<dyn std::error::Error>> {
         let hydro_function = format!(
 r#"use hydro_lang::*;
-
-pub fn {}(process: &Process) {{
+use hydro_lang::location::external_process::ExternalBincodeStream;
+{}
+pub fn {}<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {{
     process
         .source_iter(q!(std::iter::once(())))
         .map(q!(|_| {{
-            // Legacy main function body wrapped in Hydro map operator
 {}
         }}))
-        .for_each(q!(|_| {{}}));
+        .send_bincode_external(external)
 }}"#, 
+            use_statements,
             function_name,
             self.indent_code(main_body, 12)
         );
@@ -55,14 +371,40 @@ pub fn {}(process: &Process) {{
         Ok(hydro_function)
     }
 
+    /// The definitions of the user types main uses, with their impls and the functions
+    /// those call, to follow the generated function as they followed `main`. Their
+    /// imports are among the file's own.
+    fn user_type_definitions(&self, file: &syn::File) -> String {
+        let main = file.items.iter().find_map(|item| match item {
+            Item::Fn(func) if func.sig.ident == "main" => Some(func),
+            _ => None,
+        });
+        let Some(main) = main else {
+            return String::new();
+        };
+        let items: Vec<Item> = UserTypes::of(&file.items, &main.block.stmts)
+            .items
+            .into_iter()
+            .filter(|item| !matches!(item, Item::Use(_)))
+            .collect();
+        if items.is_empty() {
+            return String::new();
+        }
+        format!("\n\n{}", prettyplease::unparse(&syn::File { shebang: None, attrs: Vec::new(), items }))
+    }
+
     fn generate_example_program(&self, function_name: &str) -> Result<String, Box<dyn std::error::Error>> {
-        // Read the template file
-        let template_path = Path::new("../template/examples/generated_example.rs.template");
-        let template_content = fs::read_to_string(template_path)?;
-        
+        let template_content = match &self.example_template {
+            Some(path) => fs::read_to_string(path).map_err(|e| format!("Failed to read example template {}: {}", path.display(), e))?,
+            None => EXAMPLE_TEMPLATE.to_string(),
+        };
+        if !template_content.contains(FUNCTION_CALL_PLACEHOLDER) {
+            return Err(format!("The example template has no `{}` line to put the call of {} on", FUNCTION_CALL_PLACEHOLDER, function_name).into());
+        }
+
         // Replace the placeholder with the actual function call
-        let function_call = format!("hydro_template::{}::{}(&process);", function_name, function_name);
-        let example = template_content.replace("// GENERATED_FUNCTION_CALL_PLACEHOLDER", &function_call);
+        let function_call = format!("let done_port = hydro_template::{}::{}(&process, &external);", function_name, function_name);
+        let example = template_content.replace(FUNCTION_CALL_PLACEHOLDER, &function_call);
         
         Ok(example)
     }
@@ -72,18 +414,192 @@ pub fn {}(process: &Process) {{
         let content = if lib_rs_path.exists() {
             fs::read_to_string(&lib_rs_path)?
         } else {
-            "stageleft::stageleft_no_entry_crate!();\n\n".to_string()
+            TEMPLATE_LIB_RS.to_string()
         };
         
-        // Check if module is already declared
-        if !content.contains(&format!("pub mod {};", module_name)) {
-            let new_content = format!("{}pub mod {};\n", content, module_name);
-            fs::write(&lib_rs_path, new_content)?;
+        let lib = syn::parse_file(&content)?;
+
+        // A module declared already, however it's written, is left alone
+        let declared = lib.items.iter().any(|item| matches!(item, Item::Mod(module) if module.ident == module_name));
+        if declared {
+            return Ok(());
         }
-        
+
+        // The declaration goes on a line of its own in sorted position among the plain
+        // `pub mod x;` ones, or at the end when there are none, away from any cfg'd items
+        // after them. The rest of the file is kept as written, comments included.
+        let plain = |item: &&Item| matches!(item, Item::Mod(module) if module.content.is_none() && module.attrs.is_empty());
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+        let line = lib
+            .items
+            .iter()
+            .filter(plain)
+            .find(|item| matches!(item, Item::Mod(module) if module.ident.to_string().as_str() > module_name))
+            .map(|next| {
+                // Above the comments that go with the next declaration
+                let mut line = next.span().start().line - 1;
+                while line > 0 && lines[line - 1].trim_start().starts_with("//") {
+                    line -= 1;
+                }
+                line
+            })
+            .or_else(|| lib.items.iter().rfind(plain).map(|last| last.span().end().line))
+            .unwrap_or(lines.len());
+
+        let mut updated = lines[..line].concat();
+        if !updated.is_empty() && !updated.ends_with('\n') {
+            updated.push('\n');
+        }
+        updated.push_str(&format!("pub mod {};\n", module_name));
+        updated.push_str(&lines[line..].concat());
+
+        write_if_changed(&lib_rs_path, &updated)?;
+        Ok(())
+    }
+
+    /// What in the template crate already has the name a program is about to be generated
+    /// under. The program's own earlier output doesn't count, nor does anything when
+    /// overwriting was asked for.
+    fn name_taken(&self, template_dir: &Path, output_name: &str, input_path: &Path) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        if self.overwrite {
+            return Ok(None);
+        }
+        let source = input_path.display().to_string();
+        if let Some(artifact) = self.generated_artifacts(template_dir)?.into_iter().find(|artifact| artifact.name == output_name) {
+            return Ok((artifact.source != source).then(|| format!("the program generated from {}", artifact.source)));
+        }
+
+        // Modules and examples written by hand
+        let files = [
+            template_dir.join("src").join(format!("{}.rs", output_name)),
+            template_dir.join("src").join(output_name).join("mod.rs"),
+            template_dir.join("examples").join(format!("{}.rs", output_name)),
+        ];
+        if let Some(file) = files.iter().find(|file| file.exists()) {
+            return Ok(Some(file.display().to_string()));
+        }
+        let lib_rs_path = template_dir.join("src").join("lib.rs");
+        if lib_rs_path.exists() {
+            let lib = syn::parse_file(&fs::read_to_string(&lib_rs_path)?)?;
+            if lib.items.iter().any(|item| matches!(item, Item::Mod(module) if module.ident == output_name)) {
+                return Ok(Some(format!("a module declared in {}", lib_rs_path.display())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The programs recorded in the manifest of a template crate, sorted by name
+    fn generated_artifacts(&self, template_dir: &Path) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        let manifest_path = template_dir.join(MANIFEST_FILE);
+        if !manifest_path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut artifacts = Vec::new();
+        for line in fs::read_to_string(&manifest_path)?.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let (Some(name), Some(source)) = (fields.next(), fields.next()) else {
+                return Err(format!("Malformed line in {}: {}", manifest_path.display(), line).into());
+            };
+            // Manifests written before hashes were recorded have none
+            let input_hash = fields.next().and_then(|hash| u64::from_str_radix(hash, 16).ok());
+            artifacts.push(Artifact { name: name.to_string(), source: source.to_string(), input_hash });
+        }
+        artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(artifacts)
+    }
+
+    /// Add a generated program to the manifest, replacing an earlier entry of the same name
+    fn record_artifact(&self, template_dir: &Path, module_name: &str, input_path: &Path, input_hash: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let mut artifacts = self.generated_artifacts(template_dir)?;
+        artifacts.retain(|artifact| artifact.name != module_name);
+        artifacts.push(Artifact { name: module_name.to_string(), source: input_path.display().to_string(), input_hash: Some(input_hash) });
+        artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut manifest = String::from("# Programs generated into this crate: module name, the legacy program, the hash of its input\n");
+        for artifact in &artifacts {
+            manifest.push_str(&format!("{}\t{}", artifact.name, artifact.source));
+            if let Some(hash) = artifact.input_hash {
+                manifest.push_str(&format!("\t{:016x}", hash));
+            }
+            manifest.push('\n');
+        }
+        write_if_changed(&template_dir.join(MANIFEST_FILE), &manifest)?;
         Ok(())
     }
 
+    /// Write `examples/all_examples.rs`, which lists the programs in the manifest and
+    /// runs one of them by name
+    fn write_examples_registry(&self, template_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let entries: String = self
+            .generated_artifacts(template_dir)?
+            .iter()
+            .map(|artifact| format!("    ({:?}, {:?}),\n", artifact.name, artifact.source))
+            .collect();
+        let registry = format!(
+r#"// Generated by the Hydro ingest generator from {manifest}; do not edit by hand.
+use std::process::{{exit, Command}};
+
+/// Every generated program, as its example name and the legacy program it came from
+const EXAMPLES: &[(&str, &str)] = &[
+{entries}];
+
+fn main() {{
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(name) = args.first() else {{
+        println!("Generated programs:");
+        for (name, source) in EXAMPLES {{
+            println!("  {{:<32}} from {{}}", name, source);
+        }}
+        println!("\nTo run one: cargo run --example all_examples -- <name> [args...]");
+        return;
+    }};
+    if !EXAMPLES.iter().any(|(example, _)| example == name) {{
+        eprintln!("No generated program named {{}}", name);
+        exit(2);
+    }}
+
+    // Each program is an example of its own, run with the rest of the arguments
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["run", "--quiet", "--example", name, "--"])
+        .args(&args[1..])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("Failed to run the example");
+    exit(status.code().unwrap_or(1));
+}}
+"#,
+            manifest = MANIFEST_FILE,
+            entries = entries,
+        );
+        write_if_changed(&template_dir.join("examples").join("all_examples.rs"), &registry)?;
+        Ok(())
+    }
+
+    /// Collect the top-level `use` declarations of the legacy program so the
+    /// main body still resolves names like `io::stdin()` once it is moved.
+    fn extract_use_statements(&self, code: &str) -> String {
+        let mut use_lines = Vec::new();
+        let mut in_use = false;
+        
+        for line in code.lines() {
+            if line.starts_with("use ") {
+                in_use = true;
+            }
+            if in_use {
+                use_lines.push(line);
+                // A use declaration may span several lines until its semicolon
+                if line.trim_end().ends_with(';') {
+                    in_use = false;
+                }
+            }
+        }
+        
+        use_lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
     fn extract_main_body(&self, code: &str) -> Result<String, Box<dyn std::error::Error>> {
         // Find the main function and extract its body
         let lines: Vec<&str> = code.lines().collect();
@@ -151,9 +667,805 @@ pub fn {}(process: &Process) {{
     }
 }
 
+/// A program recorded in the manifest of a template crate
+#[derive(Debug, Clone, PartialEq)]
+struct Artifact {
+    name: String,
+    /// The legacy program it was generated from
+    source: String,
+    /// The hash of the input it was last generated from
+    input_hash: Option<u64>,
+}
+
+/// The files written for a generated program
+struct GeneratedFiles {
+    module: PathBuf,
+    example: PathBuf,
+    graph: Option<PathBuf>,
+    remote_example: Option<PathBuf>,
+    /// What was generated, and what was found in the legacy program along the way
+    output: TransformOutput,
+}
+
+impl Default for LegacyToHydroTransformer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fail on an output name that can't name a module and the function in it: one that isn't
+/// a Rust identifier, is a keyword, or is the name of a file every template crate has
+fn check_output_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if RESERVED_NAMES.contains(&name) {
+        return Err(format!("{} is the name of a file every template crate has; choose another output name", name).into());
+    }
+    check_module_name(name).map_err(|e| format!("{}; choose another output name", e))?;
+    Ok(())
+}
+
+/// Score how much of each legacy program can be migrated, without generating any code
+fn assess(paths: &[&String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for path in paths {
+        legacy_files(Path::new(path), &mut files)?;
+    }
+
+    println!("{:>5}  {:>7}  {:>11}  {:>3}  {:<24}  file", "score", "native", "unsupported", "I/O", "translation");
+    let mut assessed = Vec::new();
+    for file in &files {
+        match Assessment::of_file(file) {
+            Ok(assessment) => {
+                println!(
+                    "{:>5}  {:>7}  {:>11}  {:>3}  {:<24}  {}",
+                    assessment.score(),
+                    format!("{}/{}", assessment.native, assessment.statements),
+                    assessment.report.diagnostics.len(),
+                    assessment.io_kinds,
+                    assessment.translation.unwrap_or("single map"),
+                    file.display()
+                );
+                assessed.push((file, assessment));
+            }
+            Err(e) => println!("{:>5}  {:>7}  {:>11}  {:>3}  {:<24}  {} ({})", "-", "-", "-", "-", "-", file.display(), e),
+        }
+    }
+
+    if !assessed.is_empty() {
+        let total: u32 = assessed.iter().map(|(_, assessment)| assessment.score()).sum();
+        println!("\nAverage score: {} over {} files", total / assessed.len() as u32, assessed.len());
+    }
+    for (file, assessment) in &assessed {
+        if !assessment.report.is_clean() {
+            println!("\nTo rewrite by hand in {}:", file.display());
+            print!("{}", assessment.report);
+        }
+    }
+
+    Ok(())
+}
+
+/// Generate a Hydro program for every legacy file under `paths`, each named after its
+/// file with a `_hydro` suffix. A name already taken fails the batch before anything is
+/// written, unless collisions are suffixed, when the program gets the first free one of
+/// `<name>_2`, `<name>_3` and so on. Files are parsed and generated in parallel, each
+/// worker with a backend of its own; only the updates of lib.rs, the manifest and the
+/// registry are done one after another, once every file is written.
+fn batch(paths: &[&String], template_dir: &Path, options: &GeneratorOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for path in paths {
+        legacy_files(Path::new(path), &mut files)?;
+    }
+    let generator = options.generator();
+    let mut programs: Vec<(String, PathBuf)> = Vec::new();
+    for file in files {
+        let stem = file.file_stem().ok_or_else(|| format!("{} has no file name", file.display()))?;
+        let base = format!("{}_hydro", stem.to_string_lossy());
+        check_output_name(&base).map_err(|e| format!("{}: {}", file.display(), e))?;
+
+        let mut name = base.clone();
+        for suffix in 2.. {
+            let taken = match programs.iter().find(|(other_name, _)| *other_name == name) {
+                Some((_, other)) => Some(format!("the program generated from {}", other.display())),
+                None => generator.name_taken(template_dir, &name, &file)?,
+            };
+            match taken {
+                None => break,
+                Some(_) if options.suffix_collisions => name = format!("{}_{}", base, suffix),
+                Some(taken) => {
+                    return Err(format!(
+                        "{} would generate {}, which is already taken by {}; pass --overwrite to replace it, or --suffix-collisions to generate it under a free name",
+                        file.display(), name, taken
+                    ).into())
+                }
+            }
+        }
+        programs.push((name, file));
+    }
+
+    // Programs whose input hasn't changed since they were last generated are skipped
+    let mut stale = Vec::new();
+    for (name, file) in &programs {
+        let input_hash = generator.input_hash(file)?;
+        if !generator.is_up_to_date(template_dir, name, input_hash)? {
+            stale.push((name, file, input_hash));
+        }
+    }
+
+    let started = Instant::now();
+    let results: Vec<_> = stale
+        .par_iter()
+        .map_init(
+            || options.generator(),
+            |worker, (name, file, _)| worker.write_program(file, name, template_dir).map_err(|e| worker.failure(file, &*e)),
+        )
+        .collect();
+
+    let mut generated = 0;
+    for ((name, file, input_hash), result) in stale.iter().zip(results) {
+        match result {
+            Ok(files) => {
+                generator.register_program(template_dir, name, file, *input_hash)?;
+                println!("✓ {} -> {}", file.display(), name);
+                generator.print_diagnostics(file, &files.output.report);
+                generated += 1;
+            }
+            Err(e) if options.json_diagnostics => {
+                println!("✗ {}", file.display());
+                eprintln!("{}", e);
+            }
+            Err(e) => println!("✗ {}: {}", file.display(), e),
+        }
+    }
+    generator.write_examples_registry(template_dir)?;
+
+    println!(
+        "\nGenerated {} of {} changed programs into {} in {:.2?}, {} up to date",
+        generated,
+        stale.len(),
+        template_dir.display(),
+        started.elapsed(),
+        programs.len() - stale.len()
+    );
+    if generated < stale.len() {
+        return Err(format!("{} programs could not be generated", stale.len() - generated).into());
+    }
+    Ok(())
+}
+
+/// Create a fresh template crate at `dir` for generated programs to be written into
+fn init(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if dir.join("Cargo.toml").exists() {
+        return Err(format!("{} already has a Cargo.toml", dir.display()).into());
+    }
+    fs::create_dir_all(dir.join("src"))?;
+    fs::create_dir_all(dir.join("examples"))?;
+    fs::write(dir.join("Cargo.toml"), TEMPLATE_CARGO_TOML)?;
+    fs::write(dir.join("build.rs"), TEMPLATE_BUILD_RS)?;
+    fs::write(dir.join("rust-toolchain.toml"), TEMPLATE_TOOLCHAIN)?;
+    fs::write(dir.join("src").join("lib.rs"), TEMPLATE_LIB_RS)?;
+
+    println!("✓ Created a template crate in {}", dir.display());
+    println!("\nTo generate into it: cargo run -- <input> <output> --template {}", dir.display());
+    Ok(())
+}
+
+/// Deploy generated modules of a template crate together, writing an example that places
+/// them all in a single flow, with the pipes given as `<from>:<to>` between them
+fn compose(template_dir: &Path, output_name: &str, modules: &[&String], pipes: &[&String], overwrite: bool) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    check_output_name(output_name)?;
+    // A composition has no legacy file, so no generated program's name is its own
+    if let Some(taken) = LegacyToHydroTransformer::new().with_overwrite(overwrite).name_taken(template_dir, output_name, Path::new(""))? {
+        return Err(format!("{} is already taken by {}; pass --overwrite to replace it, or choose another output name", output_name, taken).into());
+    }
+
+    let mut composition = Composition::new();
+    for module in modules {
+        let module_path = template_dir.join("src").join(format!("{}.rs", module));
+        let source = fs::read_to_string(&module_path).map_err(|e| format!("Failed to read {}: {}; generate {} first", module_path.display(), e, module))?;
+        composition = composition.with_module(module, &source)?;
+    }
+    for pipe in pipes {
+        let (from, to) = pipe.split_once(':').ok_or_else(|| format!("A pipe is given as `<from>:<to>`, not `{}`", pipe))?;
+        composition = composition.with_pipe(from, to)?;
+    }
+
+    let example_path = template_dir.join("examples").join(format!("{}.rs", output_name));
+    fs::write(&example_path, composition.example()?)?;
+    println!("✓ Composed {} modules into {}", modules.len(), example_path.display());
+    println!("\nTo run it: cargo run --example {}", output_name);
+    Ok(example_path)
+}
+
+/// The library backend with the given name
+fn backend(name: &str, config: &TransformerConfig) -> Box<dyn Transformer> {
+    let config = config.clone();
+    match name {
+        "string" => Box::new(hydro_template::transformer::LegacyToHydroTransformer::new().with_config(config)),
+        "syn" => Box::new(SynLegacyToHydroTransformer::new().with_config(config)),
+        _ => Box::new(hydro_template::io_transformer::IOToHydroTransformer::new().with_config(config)),
+    }
+}
+
+/// The backends `--backend` and saved choices can name
+const BACKENDS: [&str; 3] = ["string", "syn", "io"];
+
+/// The options shared by every way of generating programs
+struct GeneratorOptions {
+    graph: Option<GraphFormat>,
+    /// The library backend, or the generator's own when there is none
+    backend: Option<String>,
+    config: TransformerConfig,
+    force: bool,
+    overwrite: bool,
+    /// Whether a batch generates a program whose name is taken under a free one instead
+    suffix_collisions: bool,
+    json_diagnostics: bool,
+    diff: Option<DiffFormat>,
+    remote_hosts: Option<RemoteHosts>,
+    example_template: Option<PathBuf>,
+}
+
+impl GeneratorOptions {
+    /// Options generating with `backend`, or the generator's own when it is `None`,
+    /// configured by `config`, with every other option off
+    fn new(backend: Option<&str>, config: TransformerConfig) -> Self {
+        Self {
+            graph: None,
+            backend: backend.map(str::to_string),
+            config,
+            force: false,
+            overwrite: false,
+            suffix_collisions: false,
+            json_diagnostics: false,
+            diff: None,
+            remote_hosts: None,
+            example_template: None,
+        }
+    }
+
+    /// The options given on the command line. Those saved in a `--config` file apply
+    /// unless the command line names a backend of its own.
+    fn from_matches(matches: &ArgMatches) -> Result<Self, Box<dyn std::error::Error>> {
+        let (saved_backend, mut config) = match matches.get_one::<String>("config") {
+            Some(path) => load_choices(Path::new(path))?,
+            None => (None, TransformerConfig::new()),
+        };
+        // Spreading the work asks for a cluster
+        if let Some(distribution) = matches.get_one::<String>("distribute") {
+            config.target = Target::Cluster;
+            config.distribution = match distribution.as_str() {
+                "reduce" => Distribution::Reduce,
+                _ => Distribution::RoundRobin,
+            };
+        }
+        if matches.get_flag("instrument") {
+            config.instrument = true;
+        }
+        if matches.get_flag("generic-locations") {
+            config.generic_locations = true;
+        }
+        if let Some(unsafe_code) = matches.get_one::<String>("unsafe-code") {
+            config.unsafe_code = match unsafe_code.as_str() {
+                "isolate" => UnsafeCode::Isolate,
+                "refuse" => UnsafeCode::Refuse,
+                _ => UnsafeCode::Passthrough,
+            };
+        }
+        if let Some(target_cfg) = matches.get_one::<String>("target-cfg") {
+            config.target_cfg = Some(TargetCfg::parse(target_cfg)?);
+        }
+        Ok(Self {
+            graph: matches.get_one::<String>("graph").map(|format| format.parse::<GraphFormat>()).transpose()?,
+            backend: matches.get_one::<String>("backend").cloned().or(saved_backend),
+            config,
+            force: matches.get_flag("force"),
+            overwrite: matches.get_flag("overwrite"),
+            // Only a batch picks the names of its programs
+            suffix_collisions: matches.try_get_one::<bool>("suffix-collisions").ok().flatten().copied().unwrap_or(false),
+            json_diagnostics: matches.get_one::<String>("diagnostics-format").unwrap() == "json",
+            // Only a single program is shown as a diff
+            diff: matches.try_get_one::<String>("diff").ok().flatten().map(|format| match format.as_str() {
+                "unified" => DiffFormat::Unified,
+                _ => DiffFormat::SideBySide,
+            }),
+            remote_hosts: match matches.get_one::<String>("hosts") {
+                Some(path) => Some(RemoteHosts::from_file_contents(&fs::read_to_string(path)?)?),
+                None => None,
+            },
+            example_template: matches.get_one::<String>("example-template").map(PathBuf::from),
+        })
+    }
+
+    fn generator(&self) -> LegacyToHydroTransformer {
+        LegacyToHydroTransformer::new()
+            .with_graph(self.graph)
+            .with_backend(self.backend.as_deref().map(|name| backend(name, &self.config)))
+            .with_config(self.config.clone())
+            .with_force(self.force)
+            .with_overwrite(self.overwrite)
+            .with_json_diagnostics(self.json_diagnostics)
+            .with_diff(self.diff)
+            .with_remote_hosts(self.remote_hosts.clone())
+            .with_example_template(self.example_template.clone())
+    }
+}
+
+/// Save the backend and options chosen for a program, for `--config` to reuse
+fn save_choices(path: &Path, backend: &str, config: &TransformerConfig) -> std::io::Result<()> {
+    let header = "# Choices made with `generate interactive`, reusable with --config\n";
+    fs::write(path, format!("{}backend = \"{}\"\n{}", header, backend, config.to_file_contents()))
+}
+
+/// Read back the choices `save_choices` wrote
+fn load_choices(path: &Path) -> Result<(Option<String>, TransformerConfig), Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut backend = None;
+    // The backend is the generator's to pick and the rest configures it. Its line is
+    // blanked rather than dropped, so errors in the rest keep their line numbers.
+    let mut rest = String::new();
+    for line in contents.lines() {
+        match line.split_once('=') {
+            Some((key, value)) if key.trim() == "backend" => {
+                let name = value.trim().trim_matches('"');
+                if !BACKENDS.contains(&name) {
+                    return Err(format!("Unknown backend `{}` in {}, expected one of {}", name, path.display(), BACKENDS.join(", ")).into());
+                }
+                backend = Some(name.to_string());
+            }
+            _ => rest.push_str(line),
+        }
+        rest.push('\n');
+    }
+    Ok((backend, TransformerConfig::from_file_contents(&rest)?))
+}
+
+/// The backend and options picked in `interactive`
+struct Choices {
+    backend: &'static str,
+    config: TransformerConfig,
+}
+
+/// Walk through the constructs of a legacy program that can be lowered more than one
+/// way, asking which lowering to use for each, with the default taken on an empty line.
+/// Where stdin comes from picks the backend, so it is asked first, and only the
+/// questions the backend's capabilities can act on follow.
+fn interview(file: &syn::File, input: &mut impl BufRead, out: &mut impl Write) -> Result<Choices, Box<dyn std::error::Error>> {
+    let syn_backend = SynLegacyToHydroTransformer::new();
+    let body = syn_backend.extract_function_body(syn_backend.extract_main_function(file)?)?;
+    let analysis = syn_backend.analyze(file)?;
+    let mut config = TransformerConfig::new();
+    let mut backend_name = "syn";
+
+    if !analysis.report.is_clean() {
+        writeln!(out, "These are carried over as written, and need rewriting by hand:")?;
+        for line in analysis.report.to_string().lines() {
+            writeln!(out, "  {}", line)?;
+        }
+        writeln!(out)?;
+    }
+
+    // Each line once and in order, whatever order the operations were found in
+    let lines_of = |kinds: &[IOOperationType]| -> Vec<String> {
+        let lines: BTreeSet<usize> = analysis
+            .io_operations
+            .iter()
+            .filter(|op| kinds.contains(&op.operation_type))
+            .filter_map(|op| op.line_number)
+            .collect();
+        lines.iter().map(usize::to_string).collect()
+    };
+
+    let reads = lines_of(&[
+        IOOperationType::StdinRead,
+        IOOperationType::StdinReadLine,
+        IOOperationType::StdinReadLineLoop,
+        IOOperationType::StdinLines,
+    ]);
+    if !reads.is_empty() {
+        let question = format!("The reads of stdin at line {}: where do they get their input?", reads.join(", "));
+        let source = ask(input, out, &question, &[
+            "from the example's own stdin, streamed into the dataflow",
+            "from canned sample input, so the example runs without a terminal",
+            "from stdin as written, inside the dataflow",
+            "from a client task the example runs, sending the terminal's input through a port",
+        ], 0)?;
+        backend_name = if source == 2 { "syn" } else { "io" };
+        config.stdin = match source {
+            1 => StdinMode::Mock,
+            3 => StdinMode::External,
+            _ => StdinMode::Real,
+        };
+
+        // A single line read from the terminal can be read by a process of its own
+        let count = |kind: IOOperationType| analysis.io_operations.iter().filter(|op| op.operation_type == kind).count();
+        let read_once = count(IOOperationType::StdinReadLine) == 1
+            && count(IOOperationType::StdinRead) + count(IOOperationType::StdinReadLineLoop) + count(IOOperationType::StdinLines) == 0;
+        if source == 0 && read_once {
+            let split = ask(input, out, "The program reads a line and answers it. Where is the terminal handled?", &[
+                "on the process running the program",
+                "on a UI process of its own, with the logic on another process",
+            ], 0)?;
+            if split == 1 {
+                config.target = Target::Split;
+            }
+        }
+    }
+
+    // What the backend would do with each of the remaining choices made the other way
+    let capabilities = |config: TransformerConfig| backend(backend_name, &config).capabilities();
+
+    // Only a backend trying translations lowers the body more than one way
+    if !capabilities(config.clone().with_lowering(Lowering::Full)).translations.is_empty() {
+        let full = match analysis.translation {
+            Some(translation) => format!("translate it as a {}", translation),
+            None => "try the built-in translations (none applies to it as written)".to_string(),
+        };
+        let lowering = ask(input, out, "How much of `main` becomes dataflow operators?", &[
+            &full,
+            "run the pure statements it starts with as maps of their own, then the rest in one map",
+            "run the whole body inside a single map",
+        ], 0)?;
+        config.lowering = [Lowering::Full, Lowering::Stages, Lowering::Wrap][lowering];
+
+        if let Some(parallel) = ParallelLoop::detect(&body).filter(|_| config.lowering == Lowering::Full) {
+            let line = parallel.pat.span().start().line;
+            let question = format!("The `for` loop at line {} has iterations that don't depend on each other. Where does it run?", line);
+            let target = ask(input, out, &question, &["on a single process", "across a cluster of workers, handed the items by a leader"], 0)?;
+            config.target = [Target::Process, Target::Cluster][target];
+        } else if let Some(keyed) = KeyedAggregation::detect(&body).filter(|_| config.lowering == Lowering::Full) {
+            let line = keyed.pat.span().start().line;
+            let question = format!("The `for` loop at line {} groups its items by key. Where does it run?", line);
+            let target = ask(input, out, &question, &[
+                "on a single process",
+                "across a cluster of workers, each grouping the keys that hash to it for a leader to merge",
+            ], 0)?;
+            config.target = [Target::Process, Target::Cluster][target];
+        }
+    }
+
+    let prints = lines_of(&[
+        IOOperationType::StdoutPrint,
+        IOOperationType::StdoutPrintln,
+        IOOperationType::StdoutWrite,
+        IOOperationType::StderrEprint,
+        IOOperationType::StderrEprintln,
+        IOOperationType::StderrWrite,
+    ]);
+    // A backend that streams the output anyway has nothing to ask
+    if !prints.is_empty() && !capabilities(config.clone().with_print_rewriting(false)).rewrites_output {
+        let question = format!("The prints at line {}: how do they reach the terminal?", prints.join(", "));
+        let rewrite = ask(input, out, &question, &["as a stream of output the example prints", "as side effects inside the dataflow"], 0)?;
+        config.rewrite_prints = rewrite == 0;
+    }
+
+    if seed_rng_calls(&body).is_some() && capabilities(config.clone().with_seeded_rng(true)).seeds_rng {
+        let seeded = ask(input, out, "The program draws random numbers. Where from?", &[
+            "as written",
+            "from an RNG seeded by the example, so runs can be reproduced",
+        ], 0)?;
+        config.seeded_rng = seeded == 1;
+    }
+
+    Ok(Choices { backend: backend_name, config })
+}
+
+/// Ask a question until it gets one of the numbered choices, or `default` for an empty
+/// line or the end of the input
+fn ask(input: &mut impl BufRead, out: &mut impl Write, question: &str, choices: &[&str], default: usize) -> std::io::Result<usize> {
+    loop {
+        writeln!(out, "{}", question)?;
+        for (index, choice) in choices.iter().enumerate() {
+            let marker = if index == default { " (default)" } else { "" };
+            writeln!(out, "  {}) {}{}", index + 1, choice, marker)?;
+        }
+        write!(out, "> ")?;
+        out.flush()?;
+
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 || answer.trim().is_empty() {
+            writeln!(out)?;
+            return Ok(default);
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(number) if (1..=choices.len()).contains(&number) => {
+                writeln!(out)?;
+                return Ok(number - 1);
+            }
+            _ => writeln!(out, "Please answer with a number from 1 to {}.\n", choices.len())?,
+        }
+    }
+}
+
+/// Generate a program with the lowerings picked for its constructs one by one, and
+/// save the choices for `--config` to reuse
+fn interactive(input_path: &Path, output_name: &str, template_dir: &Path, save_path: &Path, overwrite: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let file = syn::parse_file(&fs::read_to_string(input_path)?)?;
+    println!("Migrating {} to {}\n", input_path.display(), output_name);
+    let choices = interview(&file, &mut std::io::stdin().lock(), &mut std::io::stdout())?;
+
+    save_choices(save_path, choices.backend, &choices.config)?;
+    let options = GeneratorOptions { force: true, overwrite, ..GeneratorOptions::new(Some(choices.backend), choices.config) };
+    options.generator().transform_program(input_path, output_name, template_dir)?;
+    println!("\nChoices saved to {}. Reuse them with --config {}", save_path.display(), save_path.display());
+    Ok(())
+}
+
+/// A Cargo package as `cargo metadata` describes it
+struct Package {
+    name: String,
+    dir: PathBuf,
+    /// Whether its manifest marks it as a template crate
+    template: bool,
+}
+
+/// The packages of the workspace a manifest belongs to, or of the one `dir` is in, and
+/// the root of that workspace
+fn workspace_packages(dir: &Path, manifest: Option<&Path>) -> Result<(PathBuf, Vec<Package>), Box<dyn std::error::Error>> {
+    // The cargo running the generator, if it was run by one
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = std::process::Command::new(cargo);
+    command.args(["metadata", "--format-version", "1", "--no-deps"]).current_dir(dir);
+    if let Some(manifest) = manifest {
+        command.arg("--manifest-path").arg(manifest);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(format!("cargo metadata failed in {}: {}", dir.display(), String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let root = metadata["workspace_root"].as_str().ok_or("cargo metadata gave no workspace root")?;
+    let packages = metadata["packages"]
+        .as_array()
+        .ok_or("cargo metadata gave no packages")?
+        .iter()
+        .filter_map(|package| {
+            let manifest_path = Path::new(package["manifest_path"].as_str()?);
+            Some(Package {
+                name: package["name"].as_str()?.to_string(),
+                dir: manifest_path.parent()?.to_path_buf(),
+                template: package["metadata"]["hydro-ingest"]["template"].as_bool() == Some(true),
+            })
+        })
+        .collect();
+    Ok((PathBuf::from(root), packages))
+}
+
+/// Find the template crate to generate into from `dir`: the package named `name` when
+/// one is given, or else the one whose manifest marks it with
+/// `[package.metadata.hydro-ingest] template = true`. The members of the workspace `dir`
+/// is in are searched first, then the crates next to that workspace, as a generator
+/// checked out beside its template crate has it.
+fn discover_template(dir: &Path, name: Option<&str>) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let wanted = |package: &Package| match name {
+        Some(name) => package.name == name,
+        None => package.template,
+    };
+    let (root, members) = workspace_packages(dir, None)?;
+    let mut found: Vec<Package> = members.into_iter().filter(wanted).collect();
+
+    if found.is_empty() {
+        if let Some(parent) = root.parent() {
+            let mut neighbours: Vec<PathBuf> = fs::read_dir(parent)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            neighbours.sort();
+            for neighbour in neighbours {
+                let manifest = neighbour.join("Cargo.toml");
+                if neighbour == root || !manifest.exists() {
+                    continue;
+                }
+                // A crate cargo can't read is no template to generate into
+                let Ok((_, packages)) = workspace_packages(&neighbour, Some(&manifest)) else {
+                    continue;
+                };
+                found.extend(packages.into_iter().filter(|package| package.dir == neighbour && wanted(package)));
+            }
+        }
+    }
+
+    let looked_for = match name {
+        Some(name) => format!("crate named {}", name),
+        None => "template crate".to_string(),
+    };
+    match found.as_slice() {
+        [] => Err(format!(
+            "Found no {} in the workspace at {} or next to it; pass --template with its directory, or mark it with `[package.metadata.hydro-ingest] template = true`",
+            looked_for, root.display()
+        ).into()),
+        [package] => Ok(package.dir.clone()),
+        several => {
+            let dirs: Vec<String> = several.iter().map(|package| format!("{} ({})", package.name, package.dir.display())).collect();
+            Err(format!("Found {} candidates for {}: {}; pick one with --template-crate or --template", several.len(), looked_for, dirs.join(", ")).into())
+        }
+    }
+}
+
+/// The template crate given by `--template`, or else found by `--template-crate` or its mark
+fn template_dir(matches: &ArgMatches) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    match matches.get_one::<String>("template") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => discover_template(&std::env::current_dir()?, matches.get_one::<String>("template-crate").map(String::as_str)),
+    }
+}
+
+fn template_arg() -> Arg {
+    Arg::new("template")
+        .help("Template directory path, by default the template crate found with cargo metadata")
+        .short('t')
+        .long("template")
+        .conflicts_with("template-crate")
+}
+
+fn template_crate_arg() -> Arg {
+    Arg::new("template-crate")
+        .help("Package name of the template crate, in the current workspace or next to it")
+        .long("template-crate")
+}
+
+fn graph_arg() -> Arg {
+    Arg::new("graph")
+        .help("Also write the generated dataflow's graph, as DOT or Mermaid")
+        .short('g')
+        .long("graph")
+        .value_parser(["dot", "mermaid"])
+}
+
+fn backend_arg() -> Arg {
+    Arg::new("backend")
+        .help("Generate the code with one of the library's transformer backends")
+        .short('b')
+        .long("backend")
+        .value_parser(["string", "syn", "io"])
+}
+
+fn force_arg() -> Arg {
+    Arg::new("force")
+        .help("Generate programs again even when their input hasn't changed")
+        .short('f')
+        .long("force")
+        .action(ArgAction::SetTrue)
+}
+
+fn overwrite_arg() -> Arg {
+    Arg::new("overwrite")
+        .help("Replace a module or example of the template crate that has the output's name, instead of failing")
+        .long("overwrite")
+        .action(ArgAction::SetTrue)
+}
+
+fn diagnostics_format_arg() -> Arg {
+    Arg::new("diagnostics-format")
+        .help("Print the constructs to rewrite by hand, and failures, for people or as rustc-style JSON on stderr")
+        .long("diagnostics-format")
+        .value_parser(["human", "json"])
+        .default_value("human")
+}
+
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .help("Backend and options saved by `interactive`, to generate with the same choices")
+        .short('c')
+        .long("config")
+}
+
+fn generic_locations_arg() -> Arg {
+    Arg::new("generic-locations")
+        .help("Make the generated function generic over the tags of its processes and clusters, to compose it into larger flows")
+        .long("generic-locations")
+        .action(ArgAction::SetTrue)
+}
+
+fn instrument_arg() -> Arg {
+    Arg::new("instrument")
+        .help("Count and time the items each operator handles, reporting its throughput on stderr every second")
+        .long("instrument")
+        .action(ArgAction::SetTrue)
+}
+
+fn unsafe_code_arg() -> Arg {
+    Arg::new("unsafe-code")
+        .help("Quote unsafe blocks as written, move those run for their effects alone into stages of their own, or refuse programs with unsafe code")
+        .long("unsafe-code")
+        .value_parser(["passthrough", "isolate", "refuse"])
+}
+
+fn target_cfg_arg() -> Arg {
+    Arg::new("target-cfg")
+        .help("Resolve the program's #[cfg]s for a platform, given as cfg options like `unix,target_os=linux`, instead of carrying them over")
+        .long("target-cfg")
+}
+
+fn example_template_arg() -> Arg {
+    Arg::new("example-template")
+        .help("Write the example from this template instead of the built-in one, its `// GENERATED_FUNCTION_CALL_PLACEHOLDER` line becoming the call of the generated function")
+        .long("example-template")
+}
+
+fn hosts_arg() -> Arg {
+    Arg::new("hosts")
+        .help("Also write an example deploying onto the cloud machines of a deployment TOML, as `<output>_remote`")
+        .long("hosts")
+}
+
+fn distribute_arg() -> Arg {
+    Arg::new("distribute")
+        .help("Run loops across a cluster, handing out their items round-robin or reducing them to partials a leader combines")
+        .long("distribute")
+        .value_parser(["round-robin", "reduce"])
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = Command::new("Hydro Ingest Generator")
         .about("Generates Hydro dataflow programs from legacy Rust code")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(Command::new("assess")
+            .about("Scores how much of each legacy program can be migrated, without generating code")
+            .arg(Arg::new("paths")
+                .help("Legacy Rust files, or directories of them")
+                .required(true)
+                .num_args(1..)))
+        .subcommand(Command::new("init")
+            .about("Creates a fresh template crate to generate programs into")
+            .arg(Arg::new("dir")
+                .help("Directory to create the crate in")
+                .required(true)))
+        .subcommand(Command::new("batch")
+            .about("Generates a Hydro program for every legacy file, in parallel")
+            .arg(Arg::new("paths")
+                .help("Legacy Rust files, or directories of them")
+                .required(true)
+                .num_args(1..))
+            .arg(template_arg())
+            .arg(template_crate_arg())
+            .arg(graph_arg())
+            .arg(backend_arg())
+            .arg(force_arg())
+            .arg(overwrite_arg())
+            .arg(Arg::new("suffix-collisions")
+                .help("Generate a program whose name is taken as `<name>_2`, `<name>_3` and so on, instead of failing")
+                .long("suffix-collisions")
+                .action(ArgAction::SetTrue))
+            .arg(diagnostics_format_arg())
+            .arg(config_arg())
+            .arg(distribute_arg())
+            .arg(hosts_arg())
+            .arg(example_template_arg())
+            .arg(instrument_arg())
+            .arg(generic_locations_arg())
+            .arg(unsafe_code_arg())
+            .arg(target_cfg_arg()))
+        .subcommand(Command::new("compose")
+            .about("Deploys generated modules together, as one example placing them all in a single flow")
+            .arg(Arg::new("output")
+                .help("Name of the example to write")
+                .required(true))
+            .arg(Arg::new("modules")
+                .help("Generated modules of the template crate, by name")
+                .required(true)
+                .num_args(1..))
+            .arg(Arg::new("pipe")
+                .help("Stream the lines one module prints into the stdin of another, as `<from>:<to>`")
+                .long("pipe")
+                .action(ArgAction::Append))
+            .arg(template_arg())
+            .arg(template_crate_arg())
+            .arg(overwrite_arg()))
+        .subcommand(Command::new("interactive")
+            .about("Asks how to lower each construct of a legacy program, then generates it")
+            .arg(Arg::new("input")
+                .help("Input legacy Rust file")
+                .required(true))
+            .arg(Arg::new("output")
+                .help("Output function name, by default the file's name with a `_hydro` suffix"))
+            .arg(template_arg())
+            .arg(template_crate_arg())
+            .arg(overwrite_arg())
+            .arg(Arg::new("save")
+                .help("Where to save the choices, by default `<output>.hydro.toml`")
+                .short('s')
+                .long("save")))
         .arg(Arg::new("input")
             .help("Input legacy Rust file")
             .required(true)
@@ -162,29 +1474,75 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .help("Output function name")
             .required(true)
             .index(2))
-        .arg(Arg::new("template")
-            .help("Template directory path")
-            .short('t')
-            .long("template")
-            .default_value("../template"))
+        .arg(template_arg())
+        .arg(template_crate_arg())
+        .arg(graph_arg())
+        .arg(backend_arg())
+        .arg(force_arg())
+        .arg(overwrite_arg())
+        .arg(diagnostics_format_arg())
+        .arg(config_arg())
+        .arg(distribute_arg())
+        .arg(hosts_arg())
+        .arg(example_template_arg())
+        .arg(instrument_arg())
+        .arg(generic_locations_arg())
+        .arg(unsafe_code_arg())
+        .arg(target_cfg_arg())
+        .arg(Arg::new("diff")
+            .help("Show which operators the legacy statements became, as a unified or side-by-side diff")
+            .long("diff")
+            .value_parser(["unified", "side-by-side"]))
         .get_matches();
 
+    if let Some(assess_matches) = matches.subcommand_matches("assess") {
+        let paths: Vec<&String> = assess_matches.get_many::<String>("paths").unwrap().collect();
+        return assess(&paths);
+    }
+    if let Some(batch_matches) = matches.subcommand_matches("batch") {
+        let paths: Vec<&String> = batch_matches.get_many::<String>("paths").unwrap().collect();
+        return batch(&paths, &template_dir(batch_matches)?, &GeneratorOptions::from_matches(batch_matches)?);
+    }
+    if let Some(interactive_matches) = matches.subcommand_matches("interactive") {
+        let input = Path::new(interactive_matches.get_one::<String>("input").unwrap());
+        let output_name = match interactive_matches.get_one::<String>("output") {
+            Some(name) => name.clone(),
+            None => format!("{}_hydro", input.file_stem().ok_or("The input has no file name")?.to_string_lossy()),
+        };
+        let save_path = match interactive_matches.get_one::<String>("save") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(format!("{}.hydro.toml", output_name)),
+        };
+        return interactive(input, &output_name, &template_dir(interactive_matches)?, &save_path, interactive_matches.get_flag("overwrite"));
+    }
+    if let Some(compose_matches) = matches.subcommand_matches("compose") {
+        let output_name = compose_matches.get_one::<String>("output").unwrap();
+        let modules: Vec<&String> = compose_matches.get_many::<String>("modules").unwrap().collect();
+        let pipes: Vec<&String> = compose_matches.get_many::<String>("pipe").unwrap_or_default().collect();
+        let template_dir = template_dir(compose_matches)?;
+        compose(&template_dir, output_name, &modules, &pipes, compose_matches.get_flag("overwrite"))?;
+        return Ok(());
+    }
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        return init(Path::new(init_matches.get_one::<String>("dir").unwrap()));
+    }
+
     let input_file = matches.get_one::<String>("input").unwrap();
     let output_name = matches.get_one::<String>("output").unwrap();
-    let template_dir = matches.get_one::<String>("template").unwrap();
+    let template_dir = template_dir(&matches)?;
 
     println!("Hydro Ingest Generator");
     println!("=====================");
     println!("Input: {}", input_file);
     println!("Output: {}", output_name);
-    println!("Template: {}", template_dir);
+    println!("Template: {}", template_dir.display());
     println!();
 
-    let transformer = LegacyToHydroTransformer::new();
+    let transformer = GeneratorOptions::from_matches(&matches)?.generator();
     transformer.transform_program(
         Path::new(input_file),
         output_name,
-        Path::new(template_dir)
+        &template_dir
     )?;
 
     Ok(())
@@ -197,6 +1555,20 @@ mod tests {
     use std::fs;
     use tempfile::TempDir;
 
+    /// A template crate made by `init`, in a temporary directory that lives as long as
+    /// the `TempDir`
+    fn fresh_template() -> (TempDir, PathBuf) {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let template_dir = temp_dir.path().join("template");
+        init(&template_dir).unwrap();
+        (temp_dir, template_dir)
+    }
+
+    /// The generator with the syn backend, configured by `config`
+    fn syn_generator(config: &TransformerConfig) -> LegacyToHydroTransformer {
+        LegacyToHydroTransformer::new().with_backend(Some(backend("syn", config)))
+    }
+
     #[tokio::test]
     async fn test_hello_world_output_equivalence() {
         // Create a temporary directory for this test
@@ -214,7 +1586,8 @@ mod tests {
             .expect("Failed to run original program");
         
         // Generate Hydro version
-        let transformer = LegacyToHydroTransformer::new();
+        // The template has a hello_world_test of its own, written by hand
+        let transformer = LegacyToHydroTransformer::new().with_overwrite(true);
         transformer.transform_program(
             Path::new("legacy_programs/hello_world.rs"),
             "hello_world_test",
@@ -234,10 +1607,462 @@ mod tests {
         );
     }
     
+    #[test]
+    fn test_extract_use_statements() {
+        let transformer = LegacyToHydroTransformer::new();
+        let code = "use std::io::{self, BufRead};\nuse std::collections::{\n    HashMap,\n};\n\nfn main() {\n    use std::fmt::Write;\n}\n";
+        
+        let uses = transformer.extract_use_statements(code);
+        assert!(uses.contains("use std::io::{self, BufRead};"));
+        assert!(uses.contains("    HashMap,\n};"));
+        // Uses nested inside main travel with the body instead
+        assert!(!uses.contains("std::fmt::Write"));
+    }
+    
+    #[test]
+    fn test_user_types_follow_the_function() {
+        let transformer = LegacyToHydroTransformer::new();
+        let code = "use std::fmt;\n\nstruct Meters(f64);\n\nimpl fmt::Display for Meters {\n    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, \"{}m\", self.0) }\n}\n\nfn main() {\n    println!(\"{}\", Meters(2.5));\n}\n";
+        let file = syn::parse_file(code).unwrap();
+
+        let definitions = transformer.user_type_definitions(&file);
+        assert!(definitions.contains("struct Meters(f64);"));
+        assert!(definitions.contains("impl fmt::Display for Meters {"));
+        // The import is carried with the others, ahead of the function
+        assert!(!definitions.contains("use std::fmt;"));
+
+        let plain = syn::parse_file("fn main() {\n    println!(\"hi\");\n}\n").unwrap();
+        assert_eq!(transformer.user_type_definitions(&plain), "");
+    }
+
+    #[test]
+    fn test_update_lib_rs_declares_modules_in_order() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        fs::create_dir(temp_dir.path().join("src")).unwrap();
+        let lib_rs = temp_dir.path().join("src").join("lib.rs");
+        fs::write(&lib_rs, "stageleft::stageleft_no_entry_crate!();\n\npub mod alpha;\npub   mod  gamma ;\n\n#[cfg(test)]\nmod test_init {}\n").unwrap();
+
+        let transformer = LegacyToHydroTransformer::new();
+        transformer.update_lib_rs(temp_dir.path(), "beta").unwrap();
+        transformer.update_lib_rs(temp_dir.path(), "gamma").unwrap();
+        transformer.update_lib_rs(temp_dir.path(), "zeta").unwrap();
+
+        let updated = fs::read_to_string(&lib_rs).unwrap();
+        let declared: Vec<_> = updated.lines().filter(|line| line.contains("mod ")).collect();
+        assert_eq!(declared, ["pub mod alpha;", "pub mod beta;", "pub   mod  gamma ;", "pub mod zeta;", "mod test_init {}"]);
+        assert!(updated.starts_with("stageleft::stageleft_no_entry_crate!();"));
+    }
+
+    #[test]
+    fn test_update_lib_rs_keeps_comments() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        fs::create_dir(temp_dir.path().join("src")).unwrap();
+        let lib_rs = temp_dir.path().join("src").join("lib.rs");
+        let original = "//! The migrated programs\n\nstageleft::stageleft_no_entry_crate!();\n\n// Written by hand\npub mod alpha;\n// Generated from legacy/gamma.rs\npub mod gamma;\n\n#[cfg(test)]\nmod test_init {} // keeps the tests' logger\n";
+        fs::write(&lib_rs, original).unwrap();
+
+        let transformer = LegacyToHydroTransformer::new();
+        transformer.update_lib_rs(temp_dir.path(), "beta").unwrap();
+        transformer.update_lib_rs(temp_dir.path(), "zeta").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&lib_rs).unwrap(),
+            original
+                .replace("pub mod alpha;\n", "pub mod alpha;\npub mod beta;\n")
+                .replace("pub mod gamma;\n", "pub mod gamma;\npub mod zeta;\n"),
+        );
+    }
+
+    #[test]
+    fn test_examples_registry_lists_generated_programs() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        fs::create_dir(temp_dir.path().join("examples")).unwrap();
+
+        let transformer = LegacyToHydroTransformer::new();
+        transformer.record_artifact(temp_dir.path(), "echo_hydro", Path::new("legacy/echo.rs"), 1).unwrap();
+        transformer.record_artifact(temp_dir.path(), "count_hydro", Path::new("legacy/count.rs"), 2).unwrap();
+        transformer.record_artifact(temp_dir.path(), "echo_hydro", Path::new("legacy/echo_lines.rs"), 3).unwrap();
+        let artifacts = transformer.generated_artifacts(temp_dir.path()).unwrap();
+        let recorded: Vec<_> = artifacts.iter().map(|artifact| (artifact.name.as_str(), artifact.source.as_str(), artifact.input_hash)).collect();
+        assert_eq!(recorded, [("count_hydro", "legacy/count.rs", Some(2)), ("echo_hydro", "legacy/echo_lines.rs", Some(3))]);
+
+        transformer.write_examples_registry(temp_dir.path()).unwrap();
+        let registry = fs::read_to_string(temp_dir.path().join("examples").join("all_examples.rs")).unwrap();
+        syn::parse_file(&registry).expect("registry should parse");
+        assert!(registry.contains("(\"count_hydro\", \"legacy/count.rs\"),\n    (\"echo_hydro\", \"legacy/echo_lines.rs\"),"));
+    }
+
+    #[test]
+    fn test_batch_generates_every_program() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy_dir = temp_dir.path().join("legacy");
+        fs::create_dir(&legacy_dir).unwrap();
+        for name in ["alpha", "beta", "gamma", "delta"] {
+            fs::write(legacy_dir.join(format!("{}.rs", name)), format!("fn main() {{\n    println!(\"{}\");\n}}\n", name)).unwrap();
+        }
+
+        let legacy = legacy_dir.display().to_string();
+        let syn_options = GeneratorOptions::new(Some("syn"), TransformerConfig::new());
+        batch(&[&legacy], &template_dir, &syn_options).unwrap();
+
+        let lib = fs::read_to_string(template_dir.join("src").join("lib.rs")).unwrap();
+        let declared: Vec<_> = lib.lines().filter(|line| line.starts_with("pub mod")).collect();
+        assert_eq!(declared, ["pub mod alpha_hydro;", "pub mod beta_hydro;", "pub mod delta_hydro;", "pub mod gamma_hydro;"]);
+        assert!(fs::read_to_string(template_dir.join("src").join("gamma_hydro.rs")).unwrap().contains("gamma"));
+        let generator = LegacyToHydroTransformer::new();
+        assert_eq!(generator.generated_artifacts(&template_dir).unwrap().len(), 4);
+
+        // A file that can't be generated is reported, without holding up the others
+        fs::write(legacy_dir.join("broken.rs"), "fn helper() {}").unwrap();
+        assert!(batch(&[&legacy], &template_dir, &syn_options).is_err());
+        assert_eq!(generator.generated_artifacts(&template_dir).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_unchanged_programs_are_not_regenerated() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy = temp_dir.path().join("hello.rs");
+        fs::write(&legacy, "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        let module = template_dir.join("src").join("hello_hydro.rs");
+
+        let generator = || syn_generator(&TransformerConfig::new());
+        generator().transform_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        let hash = generator().input_hash(&legacy).unwrap();
+        assert!(generator().is_up_to_date(&template_dir, "hello_hydro", hash).unwrap());
+
+        // An unchanged input leaves the module as it is, even edited by hand
+        fs::write(&module, "// edited").unwrap();
+        generator().transform_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        assert_eq!(fs::read_to_string(&module).unwrap(), "// edited");
+
+        // It's generated again when forced, when the backend changes, or when the input does
+        generator().with_force(true).transform_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        assert!(fs::read_to_string(&module).unwrap().contains("hello"));
+        assert_ne!(LegacyToHydroTransformer::new().with_backend(Some(backend("io", &TransformerConfig::new()))).input_hash(&legacy).unwrap(), hash);
+        fs::write(&legacy, "fn main() {\n    println!(\"goodbye\");\n}\n").unwrap();
+        generator().transform_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        assert!(fs::read_to_string(&module).unwrap().contains("goodbye"));
+    }
+
+    #[test]
+    fn test_output_names_are_checked_against_the_template() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy = temp_dir.path().join("hello.rs");
+        fs::write(&legacy, "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        let generator = || syn_generator(&TransformerConfig::new());
+
+        for (name, problem) in [("my-program", "not a valid Rust identifier"), ("type", "Rust keyword"), ("r#type", "not a valid"), ("lib", "every template crate has")] {
+            let error = generator().transform_program(&legacy, name, &template_dir).unwrap_err();
+            assert!(error.to_string().contains(problem), "{}: {}", name, error);
+        }
+
+        // A module written by hand is kept, unless overwriting is asked for
+        fs::write(template_dir.join("src").join("greeter.rs"), "pub fn greet() {}\n").unwrap();
+        let error = generator().transform_program(&legacy, "greeter", &template_dir).unwrap_err();
+        assert!(error.to_string().contains("greeter.rs"));
+        assert!(error.to_string().contains("--overwrite"));
+        assert_eq!(fs::read_to_string(template_dir.join("src").join("greeter.rs")).unwrap(), "pub fn greet() {}\n");
+        generator().with_overwrite(true).transform_program(&legacy, "greeter", &template_dir).unwrap();
+        assert!(fs::read_to_string(template_dir.join("src").join("greeter.rs")).unwrap().contains("hello"));
+
+        // A program owns the name it was generated under, but another program doesn't
+        generator().transform_program(&legacy, "greeter", &template_dir).unwrap();
+        let other = temp_dir.path().join("other.rs");
+        fs::write(&other, "fn main() {}\n").unwrap();
+        let error = generator().transform_program(&other, "greeter", &template_dir).unwrap_err();
+        assert!(error.to_string().contains(&format!("the program generated from {}", legacy.display())));
+    }
+
+    #[test]
+    fn test_batch_suffixes_names_that_are_taken() {
+        let (temp_dir, template_dir) = fresh_template();
+        fs::write(template_dir.join("src").join("alpha_hydro.rs"), "pub fn alpha_hydro() {}\n").unwrap();
+        let legacy_dir = temp_dir.path().join("legacy");
+        fs::create_dir_all(legacy_dir.join("nested")).unwrap();
+        for file in ["alpha.rs", "beta.rs", "nested/beta.rs"] {
+            fs::write(legacy_dir.join(file), "fn main() {\n    println!(\"hi\");\n}\n").unwrap();
+        }
+
+        let legacy = legacy_dir.display().to_string();
+        let mut options = GeneratorOptions::new(Some("syn"), TransformerConfig::new());
+        let error = batch(&[&legacy], &template_dir, &options).unwrap_err();
+        assert!(error.to_string().contains("alpha_hydro, which is already taken by"));
+        assert!(LegacyToHydroTransformer::new().generated_artifacts(&template_dir).unwrap().is_empty());
+
+        options.suffix_collisions = true;
+        batch(&[&legacy], &template_dir, &options).unwrap();
+        let names: Vec<_> = LegacyToHydroTransformer::new().generated_artifacts(&template_dir).unwrap().into_iter().map(|artifact| artifact.name).collect();
+        assert_eq!(names, ["alpha_hydro_2", "beta_hydro", "beta_hydro_2"]);
+        assert_eq!(fs::read_to_string(template_dir.join("src").join("alpha_hydro.rs")).unwrap(), "pub fn alpha_hydro() {}\n");
+
+        // Generating again finds each program under the name it got
+        batch(&[&legacy], &template_dir, &options).unwrap();
+        assert_eq!(LegacyToHydroTransformer::new().generated_artifacts(&template_dir).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_failures_and_reports_as_json_diagnostics() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let broken = temp_dir.path().join("broken.rs");
+        fs::write(&broken, "fn main() {\n    let x = ;\n}\n").unwrap();
+        let generator = syn_generator(&TransformerConfig::new()).with_json_diagnostics(true);
+
+        let error = generator.write_program(&broken, "broken_hydro", temp_dir.path()).err().unwrap();
+        let json = generator.failure(&broken, &*error);
+        assert!(json.starts_with("{\"$message_type\":\"diagnostic\""));
+        assert!(json.contains("\"level\":\"error\""));
+        assert!(json.contains("\"line_start\":2"));
+        assert_eq!(LegacyToHydroTransformer::new().failure(&broken, &*error), error.to_string());
+    }
+
+    #[test]
+    fn test_interview_asks_about_each_construct() {
+        let source = "use std::io;\n\nfn main() {\n    let mut name = String::new();\n    io::stdin().read_line(&mut name).unwrap();\n    println!(\"Hello, {}!\", name.trim());\n}\n";
+        let file = syn::parse_file(source).unwrap();
+
+        // Canned input, after a bad answer asked again. It picks the io backend, which
+        // streams the prints and has one lowering, so neither is asked about.
+        let mut transcript = Vec::new();
+        let choices = interview(&file, &mut "7\n2\n".as_bytes(), &mut transcript).unwrap();
+        let transcript = String::from_utf8(transcript).unwrap();
+        assert!(transcript.starts_with("The reads of stdin at line 5"));
+        assert!(transcript.contains("Please answer with a number from 1 to 4."));
+        assert!(transcript.contains("4) from a client task"));
+        assert!(!transcript.contains("How much of `main`"));
+        assert!(!transcript.contains("The prints at line 6"));
+        assert!(!transcript.contains("random numbers"));
+        assert_eq!(choices.backend, "io");
+        assert_eq!(choices.config, TransformerConfig::new().with_stdin(StdinMode::Mock));
+
+        // Reading stdin as written keeps the syn backend, and its questions
+        let mut transcript = Vec::new();
+        let written = interview(&file, &mut "3\n2\n2\n".as_bytes(), &mut transcript).unwrap();
+        let transcript = String::from_utf8(transcript).unwrap();
+        assert!(transcript.contains("How much of `main`"));
+        assert!(transcript.contains("The prints at line 6"));
+        assert_eq!(written.backend, "syn");
+        assert_eq!(written.config, TransformerConfig::new().with_lowering(Lowering::Stages).with_print_rewriting(false));
+
+        // The defaults are taken when the input runs out
+        let defaults = interview(&file, &mut "".as_bytes(), &mut Vec::new()).unwrap();
+        assert_eq!((defaults.backend, defaults.config), ("io", TransformerConfig::new()));
+
+        // Reading the line from the terminal offers a process of its own for it
+        let mut transcript = Vec::new();
+        let split = interview(&file, &mut "\n2\n".as_bytes(), &mut transcript).unwrap();
+        assert!(String::from_utf8(transcript).unwrap().contains("Where is the terminal handled?"));
+        assert_eq!(split.config, TransformerConfig::new().with_target(Target::Split));
+
+        // Nor is the io backend asked to seed an RNG it wouldn't use
+        let dice = "use rand::Rng;\nuse std::io;\n\nfn main() {\n    let mut name = String::new();\n    io::stdin().read_line(&mut name).unwrap();\n    println!(\"{} rolled {}\", name.trim(), rand::thread_rng().gen_range(1..=6));\n}\n";
+        let mut transcript = Vec::new();
+        let streamed = interview(&syn::parse_file(dice).unwrap(), &mut "\n\n".as_bytes(), &mut transcript).unwrap();
+        assert!(!String::from_utf8(transcript).unwrap().contains("random numbers"));
+        assert!(!streamed.config.seeded_rng);
+        let mut transcript = Vec::new();
+        let written = interview(&syn::parse_file(dice).unwrap(), &mut "3\n\n\n2\n".as_bytes(), &mut transcript).unwrap();
+        assert!(String::from_utf8(transcript).unwrap().contains("random numbers"));
+        assert!(written.config.seeded_rng);
+
+        // A loop grouping by key can be partitioned across a cluster
+        let words = "use std::collections::HashMap;\n\nfn main() {\n    let mut counts = HashMap::new();\n    for word in \"a b a\".split(' ') {\n        *counts.entry(word).or_insert(0) += 1;\n    }\n    println!(\"{:?}\", counts.get(\"a\"));\n}\n";
+        let mut transcript = Vec::new();
+        let grouped = interview(&syn::parse_file(words).unwrap(), &mut "1\n2\n".as_bytes(), &mut transcript).unwrap();
+        assert!(String::from_utf8(transcript).unwrap().contains("The `for` loop at line 5 groups its items by key."));
+        assert_eq!(grouped.config.target, Target::Cluster);
+
+        // ... and so can a loop with independent iterations
+        let parallel = "fn main() {\n    let items = vec![1, 2];\n    for item in items {\n        println!(\"{}\", item);\n    }\n}\n";
+        let mut transcript = Vec::new();
+        interview(&syn::parse_file(parallel).unwrap(), &mut "".as_bytes(), &mut transcript).unwrap();
+        assert!(String::from_utf8(transcript).unwrap().contains("The `for` loop at line 3 has iterations"));
+
+        // The lines of a helper's prints are listed once, in order with the body's
+        let helper = "fn greet() {\n    println!(\"hi\");\n}\n\nfn main() {\n    println!(\"start\");\n    greet();\n    greet();\n    println!(\"end\");\n}\n";
+        let mut transcript = Vec::new();
+        interview(&syn::parse_file(helper).unwrap(), &mut "".as_bytes(), &mut transcript).unwrap();
+        assert!(String::from_utf8(transcript).unwrap().contains("The prints at line 2, 6, 9:"));
+    }
+
+    #[test]
+    fn test_saved_choices_are_reused() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let path = temp_dir.path().join("choices.hydro.toml");
+        let config = TransformerConfig::new().with_target(Target::Cluster).with_seeded_rng(true);
+        save_choices(&path, "syn", &config).unwrap();
+        assert_eq!(load_choices(&path).unwrap(), (Some("syn".to_string()), config.clone()));
+
+        // A config changes the hash, so programs are generated again with it
+        let legacy = temp_dir.path().join("hello.rs");
+        fs::write(&legacy, "fn main() {}\n").unwrap();
+        let hash = |config: TransformerConfig| LegacyToHydroTransformer::new().with_config(config).input_hash(&legacy).unwrap();
+        assert_ne!(hash(config), hash(TransformerConfig::new()));
+
+        fs::write(&path, "backend = \"fast\"\n").unwrap();
+        assert!(load_choices(&path).is_err());
+        fs::write(&path, "backend = \"io\"\nlowering = \"most\"\n").unwrap();
+        let error = load_choices(&path).unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn test_generated_programs_carry_their_output() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy = temp_dir.path().join("sum.rs");
+        let source = "fn main() {\n    let total: u64 = (1..=4).sum();\n    println!(\"{}\", total);\n}\n";
+        fs::write(&legacy, source).unwrap();
+
+        let generator = syn_generator(&TransformerConfig::new());
+        let generated = generator.write_program(&legacy, "sum_hydro", &template_dir).unwrap();
+        let diff = TransformDiff::new(source, &generated.output).unwrap();
+        assert_eq!(diff.statements[0].operators(), ["map"]);
+        assert!(diff.statements[1].carried.is_empty());
+    }
+
+    #[test]
+    fn test_example_template_is_built_in_unless_given() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy = temp_dir.path().join("hello.rs");
+        fs::write(&legacy, "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+
+        // A fresh crate has no template of its own to read
+        let generated = LegacyToHydroTransformer::new().write_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        let example = &generated.output.example_program;
+        assert!(example.contains("let done_port = hydro_template::hello_hydro::hello_hydro(&process, &external);"));
+        assert!(!example.contains(FUNCTION_CALL_PLACEHOLDER));
+
+        let custom = temp_dir.path().join("example.rs.template");
+        fs::write(&custom, "fn main() {\n    // GENERATED_FUNCTION_CALL_PLACEHOLDER\n}\n").unwrap();
+        let generator = LegacyToHydroTransformer::new().with_example_template(Some(custom.clone()));
+        let generated = generator.write_program(&legacy, "hello_hydro", &template_dir).unwrap();
+        assert_eq!(generated.output.example_program, "fn main() {\n    let done_port = hydro_template::hello_hydro::hello_hydro(&process, &external);\n}\n");
+        assert_ne!(generator.input_hash(&legacy).unwrap(), LegacyToHydroTransformer::new().input_hash(&legacy).unwrap());
+        // The built-in template is part of the hash too, so a copy of it hashes the same
+        let copy = temp_dir.path().join("copy.rs.template");
+        fs::write(&copy, EXAMPLE_TEMPLATE).unwrap();
+        let generator_with_copy = LegacyToHydroTransformer::new().with_example_template(Some(copy));
+        assert_eq!(generator_with_copy.input_hash(&legacy).unwrap(), LegacyToHydroTransformer::new().input_hash(&legacy).unwrap());
+
+        fs::write(&custom, "fn main() {}\n").unwrap();
+        let error = generator.write_program(&legacy, "hello_hydro", &template_dir).err().unwrap();
+        assert!(error.to_string().contains("no `// GENERATED_FUNCTION_CALL_PLACEHOLDER` line"));
+    }
+
+    #[test]
+    fn test_generated_modules_are_composed() {
+        let (temp_dir, template_dir) = fresh_template();
+        let config = TransformerConfig::new().with_generic_locations(true);
+        let generator = syn_generator(&config);
+        for (name, message) in [("producer", "hello"), ("consumer", "world")] {
+            let legacy = temp_dir.path().join(format!("{}.rs", name));
+            fs::write(&legacy, format!("fn main() {{\n    println!(\"{}\");\n}}\n", message)).unwrap();
+            generator.transform_program(&legacy, name, &template_dir).unwrap();
+        }
+
+        let modules = ["producer".to_string(), "consumer".to_string()];
+        let modules: Vec<&String> = modules.iter().collect();
+        let pipe = "producer:consumer".to_string();
+        let example_path = compose(&template_dir, "system", &modules, &[&pipe], false).unwrap();
+        let example = fs::read_to_string(&example_path).unwrap();
+        assert!(example.contains("let producer_process = flow.process::<ProducerProcess>();"));
+        assert!(example.contains("let consumer_done = hydro_template::consumer::consumer(&consumer_process, &external);"));
+        assert!(example.contains("while let Some(line) = producer_process_stdout.recv().await {"));
+        assert!(example.contains("consumer_process_stdin.send("));
+
+        // The example's name is taken now, as are those of the programs
+        assert!(compose(&template_dir, "system", &modules, &[], false).is_err());
+        assert!(compose(&template_dir, "system", &modules, &[], true).is_ok());
+        assert!(compose(&template_dir, "producer", &modules, &[], false).is_err());
+        let missing = "missing".to_string();
+        let error = compose(&template_dir, "other", &[&missing], &[], false).unwrap_err();
+        assert!(error.to_string().contains("generate missing first"));
+        let malformed = "producer".to_string();
+        assert!(compose(&template_dir, "other", &modules, &[&malformed], false).is_err());
+    }
+
+    #[test]
+    fn test_remote_example_is_written_for_the_hosts() {
+        let (temp_dir, template_dir) = fresh_template();
+        let legacy = temp_dir.path().join("doubles.rs");
+        fs::write(&legacy, "fn main() {\n    for n in 0..10 {\n        println!(\"{}\", n * 2);\n    }\n}\n").unwrap();
+
+        let config = TransformerConfig::new().with_target(Target::Cluster);
+        let generator = syn_generator(&config)
+            .with_remote_hosts(Some(RemoteHosts::new("migration-tests").with_machine_type("workers", "e2-small")));
+        let generated = generator.write_program(&legacy, "doubles_hydro", &template_dir).unwrap();
+
+        let remote_path = generated.remote_example.unwrap();
+        assert_eq!(remote_path, template_dir.join("examples").join("doubles_hydro_remote.rs"));
+        let remote = fs::read_to_string(remote_path).unwrap();
+        assert!(remote.contains("const PROJECT: &str = \"migration-tests\";"));
+        assert!(remote.contains(".with_process(&leader, remote_host(&mut deployment, &network, \"e2-micro\"))"));
+        assert!(remote.contains("remote_host(&mut deployment, &network, \"e2-small\")"));
+        // The Localhost example is still written as before
+        assert!(fs::read_to_string(generated.example).unwrap().contains("vec![localhost.clone(); workers_count]"));
+    }
+
+    #[test]
+    fn test_init_creates_a_template_crate() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let dir = temp_dir.path().join("fresh");
+        init(&dir).unwrap();
+
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        for dependency in ["hydro_lang", "hydro_deploy", "stageleft", "stageleft_tool", "ctor", "tracing-subscriber"] {
+            assert!(manifest.contains(&format!("{} =", dependency)), "missing {}", dependency);
+        }
+        assert!(dir.join("build.rs").exists());
+        assert!(dir.join("examples").is_dir());
+        let lib = syn::parse_file(&fs::read_to_string(dir.join("src").join("lib.rs")).unwrap()).unwrap();
+        assert!(lib.items.iter().any(|item| matches!(item, Item::Mod(module) if module.ident == "test_init")));
+
+        // Generated modules are declared in it, and an existing crate isn't overwritten
+        LegacyToHydroTransformer::new().update_lib_rs(&dir, "hello_world_hydro").unwrap();
+        assert!(fs::read_to_string(dir.join("src").join("lib.rs")).unwrap().contains("pub mod hello_world_hydro;"));
+        assert!(init(&dir).is_err());
+    }
+
+    #[test]
+    fn test_template_crate_is_found_with_cargo_metadata() {
+        let temp_dir = TempDir::new().expect("Failed to create temp dir");
+        let package = |dir: &Path, name: &str, metadata: &str| {
+            fs::create_dir_all(dir.join("src")).unwrap();
+            fs::write(dir.join("src").join("lib.rs"), "").unwrap();
+            fs::write(dir.join("Cargo.toml"), format!("[package]\nname = \"{}\"\nversion = \"0.0.0\"\nedition = \"2021\"\n{}", name, metadata)).unwrap();
+        };
+        let marked = "\n[package.metadata.hydro-ingest]\ntemplate = true\n";
+
+        // A workspace member marked as a template, wherever in the workspace the generator runs
+        let workspace = temp_dir.path().join("workspace");
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(workspace.join("Cargo.toml"), "[workspace]\nmembers = [\"app\", \"programs\"]\nresolver = \"2\"\n").unwrap();
+        package(&workspace.join("app"), "app", "");
+        package(&workspace.join("programs"), "programs", marked);
+        let found = discover_template(&workspace.join("app").join("src"), None).unwrap();
+        assert_eq!(found.canonicalize().unwrap(), workspace.join("programs").canonicalize().unwrap());
+        assert_eq!(discover_template(&workspace, Some("app")).unwrap().canonicalize().unwrap(), workspace.join("app").canonicalize().unwrap());
+        assert!(discover_template(&workspace, Some("missing")).unwrap_err().to_string().starts_with("Found no crate named missing in the workspace"));
+
+        // A crate next to the generator's own, as in a checkout of this repository
+        let checkout = temp_dir.path().join("checkout");
+        package(&checkout.join("generator"), "generator", "");
+        package(&checkout.join("template"), "hydro-template", marked);
+        package(&checkout.join("other"), "other", "");
+        let found = discover_template(&checkout.join("generator"), None).unwrap();
+        assert_eq!(found.canonicalize().unwrap(), checkout.join("template").canonicalize().unwrap());
+
+        // Two marked crates have to be told apart by name
+        package(&checkout.join("second"), "second-template", marked);
+        let error = discover_template(&checkout.join("generator"), None).unwrap_err();
+        assert!(error.to_string().contains("--template-crate"));
+        let found = discover_template(&checkout.join("generator"), Some("second-template")).unwrap();
+        assert_eq!(found.canonicalize().unwrap(), checkout.join("second").canonicalize().unwrap());
+    }
+    
     async fn run_original_program(program_path: &str) -> Result<String, Box<dyn std::error::Error>> {
         // Compile and run the original Rust program
         let output = Command::new("rustc")
-            .args(&[program_path, "-o", "/tmp/original_program"])
+            .args([program_path, "-o", "/tmp/original_program"])
             .output()?;
         
         if !output.status.success() {
@@ -256,7 +2081,7 @@ mod tests {
         let output = tokio::time::timeout(
             std::time::Duration::from_secs(30),
             tokio::process::Command::new("cargo")
-                .args(&["run", "--example", program_name])
+                .args(["run", "--example", program_name])
                 .current_dir(template_dir)
                 .output()
         ).await??;
@@ -0,0 +1,75 @@
+//! `#[hydroize]`, which migrates a legacy `main` in place at compile time
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Ident, Item, ItemFn};
+
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+
+/// Generate the Hydro version of a legacy function next to it, for side-by-side testing.
+///
+/// The function is kept as written. After it comes a module with the Hydro function
+/// the transformer generates from its body, named after the function with a `_hydro`
+/// suffix unless a name is given, as in `#[hydroize(hello_hydro)]`. Only the function
+/// itself is transformed, so helpers it calls aren't carried into the module. The
+/// crate the module lands in has to be a stageleft crate depending on `hydro_lang`.
+#[proc_macro_attribute]
+pub fn hydroize(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(attr.into(), item.into()).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(attr: TokenStream, item: TokenStream) -> syn::Result<TokenStream> {
+    let name: Option<Ident> = syn::parse2(attr)?;
+    let legacy: ItemFn = syn::parse2(item)?;
+    let module_name = name.unwrap_or_else(|| format_ident!("{}_hydro", legacy.sig.ident));
+
+    // The transformer looks for `main`, whatever the annotated function is called
+    let mut main = legacy.clone();
+    main.sig.ident = format_ident!("main");
+    let file = syn::File { shebang: None, attrs: Vec::new(), items: vec![Item::Fn(main)] };
+    let output = SynLegacyToHydroTransformer::new()
+        .transform_file(&file, &module_name.to_string())
+        .map_err(|e| syn::Error::new_spanned(&legacy.sig.ident, e))?;
+    let module = syn::parse_file(&output.hydro_function)?.items;
+
+    Ok(quote! {
+        #legacy
+
+        pub mod #module_name {
+            #(#module)*
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hydroize_keeps_the_function_and_adds_a_module() {
+        let item = quote! {
+            fn main() {
+                println!("Hello, world!");
+            }
+        };
+        let expanded: syn::File = syn::parse2(expand(TokenStream::new(), item).unwrap()).unwrap();
+
+        assert!(matches!(&expanded.items[0], Item::Fn(func) if func.sig.ident == "main"));
+        let Item::Mod(module) = &expanded.items[1] else { panic!("expected the generated module") };
+        assert_eq!(module.ident, "main_hydro");
+        let (_, items) = module.content.as_ref().unwrap();
+        assert!(items.iter().any(|item| matches!(item, Item::Fn(func) if func.sig.ident == "main_hydro")));
+    }
+
+    #[test]
+    fn test_hydroize_takes_a_module_name() {
+        let item = quote! {
+            fn legacy_hello() {
+                println!("Hello, world!");
+            }
+        };
+        let expanded = expand(quote!(hello_hydro), item).unwrap().to_string();
+        assert!(expanded.contains("fn legacy_hello"));
+        assert!(expanded.contains("pub mod hello_hydro"));
+        assert!(expand(quote!(not a name), quote!(fn main() {})).is_err());
+    }
+}
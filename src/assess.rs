@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use syn::Stmt;
+
+use crate::io_transformer::IOToHydroTransformer;
+use crate::print_rewriter::{print_through_handles, OutputChannel, PrintMacro};
+use crate::report::TransformReport;
+use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+/// Points taken off the score for each construct that needs rewriting by hand
+const UNSUPPORTED_PENALTY: u32 = 10;
+/// Points taken off the score for each kind of I/O beyond the first
+const IO_PENALTY: u32 = 5;
+
+/// How much of a legacy program the transformer can migrate on its own, worked out
+/// without generating any code
+#[derive(Debug, Clone)]
+pub struct Assessment {
+    /// The top-level statements of `main`
+    pub statements: usize,
+    /// How many of them become native operators rather than code run opaquely inside a
+    /// closure: all of them when a translation recognizes the body, and only the prints
+    /// lowered into the stdout stream when the body is wrapped in a single map
+    pub native: usize,
+    /// The translation the transformer picks, if any
+    pub translation: Option<&'static str>,
+    /// The constructs carried over as written
+    pub report: TransformReport,
+    /// The I/O operations of `main`, and how many different kinds there are
+    pub io_operations: usize,
+    pub io_kinds: usize,
+}
+
+impl Assessment {
+    /// Assess the legacy program in a file
+    pub fn of_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::of_source(&fs::read_to_string(path)?)
+    }
+
+    /// Assess the source of a legacy program
+    pub fn of_source(source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = syn::parse_file(source)?;
+        let transformer = SynLegacyToHydroTransformer::new();
+        let body = print_through_handles(&transformer.extract_function_body(transformer.extract_main_function(&file)?)?);
+        let translation = transformer.translation(&file)?;
+        let native = match translation {
+            Some(_) => body.len(),
+            None => body
+                .iter()
+                .filter(|stmt| matches!(stmt, Stmt::Macro(mac) if PrintMacro::parse(&mac.mac).is_some_and(|print| print.channel == OutputChannel::Stdout)))
+                .count(),
+        };
+
+        let operations = IOToHydroTransformer::new().analyze_io_operations_in(&file.items, &body);
+        let kinds: HashSet<String> = operations.iter().map(|operation| format!("{:?}", operation.operation_type)).collect();
+
+        Ok(Self {
+            statements: body.len(),
+            native,
+            translation,
+            report: TransformReport::analyze(&file),
+            io_operations: operations.len(),
+            io_kinds: kinds.len(),
+        })
+    }
+
+    /// The fraction of statements that become native operators
+    pub fn native_fraction(&self) -> f64 {
+        if self.statements == 0 {
+            return 1.0;
+        }
+        self.native as f64 / self.statements as f64
+    }
+
+    /// A feasibility score from 0 to 100: the percentage of statements that become
+    /// native operators, less a penalty for each unsupported construct and for each
+    /// kind of I/O beyond the first
+    pub fn score(&self) -> u32 {
+        let native = (self.native_fraction() * 100.0).round() as u32;
+        let unsupported = self.report.diagnostics.len() as u32 * UNSUPPORTED_PENALTY;
+        let io = self.io_kinds.saturating_sub(1) as u32 * IO_PENALTY;
+        native.saturating_sub(unsupported + io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recognized_program_scores_full_marks() {
+        let assessment = Assessment::of_source(r#"
+            fn main() {
+                let numbers = vec![1, 2, 3];
+                numbers.iter().map(|n| n * 2).for_each(|n| println!("{}", n));
+            }
+        "#).unwrap();
+
+        assert_eq!(assessment.translation, Some("iterator chain"));
+        assert_eq!((assessment.native, assessment.statements), (2, 2));
+        assert_eq!(assessment.score(), 100);
+    }
+
+    #[test]
+    fn test_opaque_body_with_unsafe_code() {
+        let assessment = Assessment::of_source(r#"
+            use std::io::{self, BufRead};
+
+            fn main() {
+                println!("Enter numbers:");
+                let total: i64 = io::stdin().lock().lines().map(|line| line.unwrap().parse::<i64>().unwrap()).sum();
+                let doubled = unsafe { double(total) };
+                eprintln!("{}", doubled);
+            }
+        "#).unwrap();
+
+        assert!(assessment.translation.is_none());
+        // The println becomes the stdout sink, the rest runs in the map
+        assert_eq!((assessment.native, assessment.statements), (1, 4));
+        assert_eq!(assessment.report.diagnostics.len(), 1);
+        assert!(assessment.io_kinds >= 2);
+        assert_eq!(assessment.score(), 25 - UNSUPPORTED_PENALTY - (assessment.io_kinds as u32 - 1) * IO_PENALTY);
+    }
+}
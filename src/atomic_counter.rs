@@ -0,0 +1,334 @@
+use quote::ToTokens;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Pat, Stmt, Token, Type};
+
+use crate::print_rewriter::StdoutLines;
+use crate::shared_state::{state_ident, SharedState};
+use crate::syntax::mentions;
+
+/// A legacy loop that counts into an atomic, which is read once the loop is done
+pub struct AtomicCounter {
+    /// The integer (or `bool`) type the atomic holds
+    pub ty: Type,
+    pub init: Expr,
+    pub pat: Pat,
+    pub items: Expr,
+    pub update: CounterUpdate,
+    /// The statements after the loop, with `load`s replaced by reads of `shared_state`
+    pub reads: Vec<Stmt>,
+}
+
+/// How each iteration of the loop changes the counter
+pub enum CounterUpdate {
+    /// Every iteration, or those meeting the condition, add exactly one
+    Count { filter: Option<Box<Expr>> },
+    /// Anything else the atomic operations can express, as a fold over `shared_state`
+    Fold(Vec<Stmt>),
+}
+
+impl AtomicCounter {
+    /// Recognize `let c = AtomicUsize::new(0); for x in xs { .. c.fetch_add(1, ..) .. }`
+    /// followed by statements that `load` the result
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let [decl, counting, reads @ ..] = stmts else {
+            return None;
+        };
+        let Stmt::Local(local) = decl else {
+            return None;
+        };
+        let name = match &local.pat {
+            Pat::Ident(ident) => ident.ident.to_string(),
+            _ => return None,
+        };
+        let (ty, init) = atomic_decl(&local.init.as_ref()?.expr)?;
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = counting else {
+            return None;
+        };
+        if for_loop.label.is_some() {
+            return None;
+        }
+        let names = [name.clone()];
+
+        let update = match counting_filter(&for_loop.body.stmts, &name) {
+            Some(filter) => CounterUpdate::Count { filter },
+            None => {
+                let rewritten = rewrite_atomic_ops(&for_loop.body.stmts, &names)?;
+                let lowered = StdoutLines::lower(&rewritten);
+                if lowered.has_partial_lines {
+                    return None;
+                }
+                CounterUpdate::Fold(lowered.stmts)
+            }
+        };
+        if let CounterUpdate::Fold(update) = &update {
+            if mentions(update, &name) {
+                return None;
+            }
+        }
+
+        let reads = rewrite_atomic_ops(reads, &names)?;
+        if mentions(&reads, &name) {
+            return None;
+        }
+
+        Some(Self {
+            ty,
+            init,
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            update,
+            reads,
+        })
+    }
+
+    /// The general form of the counter, as state folded over the loop's items
+    pub fn into_shared_state(self) -> Option<SharedState> {
+        let CounterUpdate::Fold(update) = self.update else {
+            return None;
+        };
+        let (ty, init) = (&self.ty, &self.init);
+        Some(SharedState {
+            init: parse_quote! { #init as #ty },
+            pat: self.pat,
+            items: self.items,
+            update,
+            reads: self.reads,
+        })
+    }
+}
+
+/// `AtomicUsize::new(init)`, possibly wrapped in an `Arc`, returning the value type
+pub fn atomic_decl(expr: &Expr) -> Option<(Type, Expr)> {
+    let (owner, args) = constructor(expr)?;
+    if owner == "Arc" {
+        let [inner] = args.iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        return atomic_decl(inner);
+    }
+
+    let ty = owner.strip_prefix("Atomic")?.to_lowercase();
+    let ty = match ty.as_str() {
+        "bool" | "usize" | "isize" | "u8" | "u16" | "u32" | "u64" | "i8" | "i16" | "i32" | "i64" => {
+            syn::parse_str(&ty).ok()?
+        }
+        _ => return None,
+    };
+    let [init] = args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some((ty, init.clone()))
+}
+
+/// `Owner::new(args)`, returning `Owner` and the arguments
+fn constructor(expr: &Expr) -> Option<(String, &Punctuated<Expr, Token![,]>)> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<_> = func.path.segments.iter().collect();
+    match segments[..] {
+        [.., owner, new] if new.ident == "new" => Some((owner.ident.to_string(), &call.args)),
+        _ => None,
+    }
+}
+
+/// The condition under which a loop body adds one to the counter, if that is all it does:
+/// `Some(None)` for `c.fetch_add(1, ..);` and `Some(Some(cond))` for
+/// `if cond { c.fetch_add(1, ..); }`
+fn counting_filter(body: &[Stmt], name: &str) -> Option<Option<Box<Expr>>> {
+    let [stmt] = body else {
+        return None;
+    };
+    let Stmt::Expr(expr, _) = stmt else {
+        return None;
+    };
+    if is_increment(expr, name) {
+        return Some(None);
+    }
+    let Expr::If(cond) = expr else {
+        return None;
+    };
+    if cond.else_branch.is_some() || mentions(&[Stmt::Expr((*cond.cond).clone(), None)], name) {
+        return None;
+    }
+    match &cond.then_branch.stmts[..] {
+        [Stmt::Expr(inner, _)] if is_increment(inner, name) => Some(Some(cond.cond.clone())),
+        _ => None,
+    }
+}
+
+/// `name.fetch_add(1, ordering)`
+fn is_increment(expr: &Expr, name: &str) -> bool {
+    let Some((method, args)) = atomic_op(expr, &[name.to_string()]) else {
+        return false;
+    };
+    method == "fetch_add" && matches!(args.first(), Some(Expr::Lit(lit)) if lit.to_token_stream().to_string() == "1")
+}
+
+/// A method called on one of `names`, returning the method and its arguments
+fn atomic_op(expr: &Expr, names: &[String]) -> Option<(String, Vec<Expr>)> {
+    let Expr::MethodCall(call) = expr else {
+        return None;
+    };
+    let Expr::Path(receiver) = &*call.receiver else {
+        return None;
+    };
+    let receiver = receiver.path.get_ident()?;
+    if !names.iter().any(|name| receiver == name) {
+        return None;
+    }
+    Some((call.method.to_string(), call.args.iter().cloned().collect()))
+}
+
+/// Replace the atomic operations on `names` with the same operations on the plain value
+/// behind `shared_state`. Returns `None` if an operation has no such equivalent.
+pub fn rewrite_atomic_ops(stmts: &[Stmt], names: &[String]) -> Option<Vec<Stmt>> {
+    let mut rewriter = AtomicOpsRewriter { names, unsupported: false };
+    let mut stmts = stmts.to_vec();
+    for stmt in &mut stmts {
+        rewriter.visit_stmt_mut(stmt);
+    }
+    (!rewriter.unsupported).then_some(stmts)
+}
+
+struct AtomicOpsRewriter<'n> {
+    names: &'n [String],
+    unsupported: bool,
+}
+
+impl AtomicOpsRewriter<'_> {
+    /// The plain equivalent of an atomic operation, whose value is used
+    fn rewrite(&mut self, method: &str, args: &[Expr]) -> Option<Expr> {
+        let state = state_ident();
+        let (op, value) = match (method, args) {
+            ("load", [_]) => return Some(parse_quote! { *#state }),
+            ("store", [value, _]) => return Some(parse_quote! { *#state = #value }),
+            ("swap", [value, _]) => return Some(parse_quote! { std::mem::replace(#state, #value) }),
+            ("fetch_add", [value, _]) => (quote::quote! { += }, value),
+            ("fetch_sub", [value, _]) => (quote::quote! { -= }, value),
+            ("fetch_or", [value, _]) => (quote::quote! { |= }, value),
+            ("fetch_and", [value, _]) => (quote::quote! { &= }, value),
+            _ => {
+                self.unsupported = true;
+                return None;
+            }
+        };
+        // The fetch_ operations evaluate to the value from before the update
+        Some(parse_quote! {{
+            let previous = *#state;
+            *#state #op #value;
+            previous
+        }})
+    }
+}
+
+impl VisitMut for AtomicOpsRewriter<'_> {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        // Where the previous value is thrown away, the update alone will do
+        if let Stmt::Expr(expr, Some(_)) = stmt {
+            if let Some((method, args)) = atomic_op(expr, self.names) {
+                let state = state_ident();
+                let op = match method.as_str() {
+                    "fetch_add" => Some(quote::quote! { += }),
+                    "fetch_sub" => Some(quote::quote! { -= }),
+                    _ => None,
+                };
+                if let (Some(op), [value, _]) = (op, &args[..]) {
+                    *expr = parse_quote! { *#state #op #value };
+                    return;
+                }
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        // Loads often happen right in the format arguments
+        let Ok(mut args) = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) else {
+            return;
+        };
+        for arg in args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+        mac.tokens = args.to_token_stream();
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+        if let Some((method, args)) = atomic_op(expr, self.names) {
+            if let Some(plain) = self.rewrite(&method, &args) {
+                *expr = plain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+    use quote::quote;
+
+    fn detect(body: &str) -> Option<AtomicCounter> {
+        AtomicCounter::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_conditional_increment_is_a_filtered_count() {
+        let counter = detect(r#"{
+            let evens = AtomicUsize::new(0);
+            for n in 1..=10 {
+                if n % 2 == 0 {
+                    evens.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            println!("Evens: {}", evens.load(Ordering::SeqCst));
+        }"#).unwrap();
+
+        assert_eq!(compact(&counter.ty), "usize");
+        match &counter.update {
+            CounterUpdate::Count { filter: Some(filter) } => assert_eq!(compact(filter), "n%2==0"),
+            _ => panic!("expected a filtered count"),
+        }
+        let reads = &counter.reads;
+        assert_eq!(compact(quote! { #(#reads)* }), "println!(\"Evens:{}\",*shared_state);");
+    }
+
+    #[test]
+    fn test_other_updates_fold_into_state() {
+        let counter = detect(r#"{
+            let total = AtomicU64::new(5);
+            for n in [3, 4] {
+                let before = total.fetch_add(n, Ordering::Relaxed);
+                println!("{} + {}", before, n);
+            }
+            let result = total.load(Ordering::Relaxed);
+        }"#).unwrap();
+
+        let shared = counter.into_shared_state().unwrap();
+        assert_eq!(compact(&shared.init), "5asu64");
+        let update = &shared.update;
+        let update = compact(quote! { #(#update)* });
+        assert!(update.starts_with("letbefore={letprevious=*shared_state;*shared_state+=n;previous};"));
+        assert!(update.contains("stdout_lines.push(format!(\"{}+{}\",before,n));"));
+    }
+
+    #[test]
+    fn test_unsupported_uses_are_rejected() {
+        // compare_exchange has no plain counterpart here
+        assert!(detect(r#"{
+            let flag = AtomicBool::new(false);
+            for _ in 0..3 { let _ = flag.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst); }
+        }"#).is_none());
+        // The atomic is passed on by reference
+        assert!(detect(r#"{
+            let hits = AtomicUsize::new(0);
+            for _ in 0..3 { hits.fetch_add(1, Ordering::SeqCst); }
+            report(&hits);
+        }"#).is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::TransformError;
+use crate::io_transformer::{IOOperation, IOToHydroTransformer};
+use crate::output::{main_body, TransformOutput};
+use crate::report::TransformReport;
+
+/// What a backend can do with a legacy program, for picking one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// Whether the backend works on a syntax tree, so source that doesn't parse is
+    /// rejected rather than wrapped as it was written
+    pub parses: bool,
+    /// Whether prints become a stream of output rather than running inside the dataflow
+    pub rewrites_output: bool,
+    /// Whether reads of stdin become a stream fed by the example
+    pub streams_stdin: bool,
+    /// Whether randomness is drawn from an RNG the example seeds
+    pub seeds_rng: bool,
+    /// The translations the backend tries before wrapping the body in a single map, in
+    /// the order it tries them
+    pub translations: Vec<&'static str>,
+}
+
+/// What a backend makes of a legacy program, worked out without generating any code
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    /// The translation the backend picks, or `None` when the body is wrapped whole in a
+    /// single map
+    pub translation: Option<&'static str>,
+    /// The I/O operations of the program, the helpers it calls included
+    pub io_operations: Vec<IOOperation>,
+    /// The constructs carried over as written
+    pub report: TransformReport,
+}
+
+impl Analysis {
+    /// The analysis of a legacy file a backend translates as `translation`
+    pub fn new(file: &syn::File, translation: Option<&'static str>) -> Result<Self, TransformError> {
+        let body = main_body(file).ok_or(TransformError::NoMainFunction)?;
+        Ok(Self {
+            translation,
+            io_operations: IOToHydroTransformer::new().analyze_io_operations_in(&file.items, body),
+            report: TransformReport::analyze(file),
+        })
+    }
+}
+
+/// A backend turning legacy programs into Hydro, so callers can pick one at runtime and
+/// hold it as a `Box<dyn Transformer>`
+pub trait Transformer {
+    /// A short name for the backend
+    fn name(&self) -> &'static str;
+
+    /// What the backend, as configured, can do with a legacy program
+    fn capabilities(&self) -> Capabilities;
+
+    /// Transform legacy source code held in memory
+    fn transform_source(&self, source: &str, module_name: &str) -> Result<TransformOutput, TransformError>;
+
+    /// Transform a legacy Rust program file
+    fn transform_program(&self, legacy_path: &Path, module_name: &str) -> Result<TransformOutput, TransformError> {
+        self.transform_source(&fs::read_to_string(legacy_path)?, module_name)
+    }
+
+    /// Work out what the backend makes of a legacy program without generating any code
+    fn analyze(&self, file: &syn::File) -> Result<Analysis, TransformError> {
+        Analysis::new(file, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+    use crate::transformer::LegacyToHydroTransformer;
+
+    fn backends() -> Vec<Box<dyn Transformer>> {
+        vec![
+            Box::new(LegacyToHydroTransformer::new()),
+            Box::new(SynLegacyToHydroTransformer::new()),
+            Box::new(IOToHydroTransformer::new()),
+        ]
+    }
+
+    #[test]
+    fn test_backends_are_interchangeable() {
+        let source = "fn main() {\n    println!(\"Hello, world!\");\n}\n";
+        let names: Vec<_> = backends().iter().map(|backend| backend.name()).collect();
+        assert_eq!(names, vec!["string", "syn", "io"]);
+
+        for backend in backends() {
+            let output = backend.transform_source(source, "test_hello").unwrap();
+            assert!(output.hydro_function.contains("pub fn test_hello"), "{} backend", backend.name());
+            assert!(output.example_program.contains("test_hello::test_hello"), "{} backend", backend.name());
+        }
+    }
+
+    #[test]
+    fn test_capabilities_and_analysis() {
+        let (string, syn_backend, io) = (LegacyToHydroTransformer::new(), SynLegacyToHydroTransformer::new(), IOToHydroTransformer::new());
+        assert!(!string.capabilities().parses);
+        assert!(syn_backend.capabilities().translations.contains(&"iterator chain"));
+        assert!(!SynLegacyToHydroTransformer::new().with_print_rewriting(false).capabilities().rewrites_output);
+        assert!(io.capabilities().streams_stdin);
+        assert!(SynLegacyToHydroTransformer::new().with_seeded_rng(true).capabilities().seeds_rng);
+        assert!(!io.capabilities().seeds_rng);
+
+        let file = syn::parse_file("fn main() {\n    vec![1, 2, 3].iter().map(|x| x * 2).for_each(|x| println!(\"{}\", x));\n}\n").unwrap();
+        assert_eq!(syn_backend.analyze(&file).unwrap().translation, Some("iterator chain"));
+        assert_eq!(string.analyze(&file).unwrap().translation, None);
+        assert_eq!(io.analyze(&file).unwrap().io_operations.len(), 1);
+
+        let no_main = syn::parse_file("fn helper() {}").unwrap();
+        assert!(matches!(syn_backend.analyze(&no_main), Err(TransformError::NoMainFunction)));
+    }
+}
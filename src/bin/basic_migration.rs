@@ -14,11 +14,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let legacy_path = Path::new("src/legacy/hello_world.rs");
     
     println!("Transforming legacy Rust program with AST parsing...");
-    let (hydro_function, example_program) = transformer.transform_program(legacy_path, "syn_hello_world")?;
+    // Parse the legacy program once, for the transformation and the analysis alike
+    let file = syn::parse_file(&fs::read_to_string(legacy_path)?)?;
+    let output = transformer.transform_file(&file, "syn_hello_world")?;
     
     // Analyze function calls in the legacy code
-    let source = fs::read_to_string(legacy_path)?;
-    let file = syn::parse_file(&source)?;
     let main_fn = transformer.extract_main_function(&file)?;
     let body = transformer.extract_function_body(main_fn)?;
     let function_calls = transformer.analyze_function_calls(&body);
@@ -28,13 +28,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         println!("  - {} (with {} args)", call.name, call.args_count);
     }
     
+    // Constructs carried over as written need a rewrite by hand
+    if !output.report.is_clean() {
+        println!("Constructs that need rewriting by hand:");
+        print!("{}", output.report);
+    }
+    
     // Write the generated Hydro function
     let hydro_module_path = Path::new("src/syn_hello_world.rs");
-    fs::write(hydro_module_path, &hydro_function)?;
+    fs::write(hydro_module_path, &output.hydro_function)?;
     
     // Write the example program
     let example_path = Path::new("examples/syn_hello_world.rs");
-    fs::write(example_path, &example_program)?;
+    fs::write(example_path, &output.example_program)?;
     
     println!("✓ Successfully transformed {} to Hydro dataflow:", 
              legacy_path.display());
@@ -44,7 +50,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Display the generated Hydro function
     println!("\nGenerated Hydro function:");
     println!("{}", "-".repeat(50));
-    println!("{}", hydro_function);
+    println!("{}", output.hydro_function);
     
     println!("\nTo run the generated program:");
     println!("  cargo run --example syn_hello_world");
@@ -0,0 +1,173 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Transformer;
+use crate::error::TransformError;
+use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+/// The file in the output directory declaring every generated module
+pub const INDEX_FILE: &str = "hydro_modules.rs";
+
+/// Transform every legacy program under `legacy_dir` into a Hydro module in `out_dir`,
+/// from a build script. Each module is named after its file with a `_hydro` suffix,
+/// and is declared in `INDEX_FILE`, which the crate pulls in with
+/// `include!(concat!(env!("OUT_DIR"), "/hydro_modules.rs"));`. Cargo reruns the
+/// build script when anything under `legacy_dir` changes. Returns the module names.
+pub fn generate(legacy_dir: impl AsRef<Path>, out_dir: impl AsRef<Path>) -> Result<Vec<String>, TransformError> {
+    generate_with(&SynLegacyToHydroTransformer::new(), legacy_dir, out_dir)
+}
+
+/// Like `generate`, with the given backend
+pub fn generate_with(
+    backend: &dyn Transformer,
+    legacy_dir: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+) -> Result<Vec<String>, TransformError> {
+    let (legacy_dir, out_dir) = (legacy_dir.as_ref(), out_dir.as_ref());
+    println!("cargo:rerun-if-changed={}", legacy_dir.display());
+
+    let mut files = Vec::new();
+    legacy_files(legacy_dir, &mut files)?;
+
+    let mut modules: Vec<(String, &PathBuf)> = Vec::new();
+    for file in &files {
+        let Some(stem) = file.file_stem() else { continue };
+        let name = format!("{}_hydro", stem.to_string_lossy());
+        check_module_name(&name).map_err(|e| TransformError::InvalidModuleName(format!("{}: {}", file.display(), e)))?;
+        if let Some((_, other)) = modules.iter().find(|(other_name, _)| *other_name == name) {
+            return Err(TransformError::InvalidModuleName(format!(
+                "{} would generate {}, which is already taken by the program generated from {}",
+                file.display(),
+                name,
+                other.display()
+            )));
+        }
+        modules.push((name, file));
+    }
+
+    fs::create_dir_all(out_dir)?;
+    let mut index = String::new();
+    for (name, file) in &modules {
+        let output = backend.transform_program(file, name).inspect_err(|e| {
+            println!("cargo:warning=Could not transform {}: {}", file.display(), e);
+        })?;
+        write_if_changed(&out_dir.join(format!("{}.rs", name)), &output.hydro_function)?;
+        index.push_str(&format!(
+            "pub mod {name} {{\n    include!(concat!(env!(\"OUT_DIR\"), \"/{name}.rs\"));\n}}\n",
+            name = name
+        ));
+    }
+    write_if_changed(&out_dir.join(INDEX_FILE), &index)?;
+    Ok(modules.into_iter().map(|(name, _)| name).collect())
+}
+
+/// Fail on a name that can't name a module and the function in it: one that isn't a
+/// Rust identifier, or is a keyword
+pub fn check_module_name(name: &str) -> Result<(), TransformError> {
+    if name.starts_with("r#") || syn::parse_str::<syn::Ident>(name).is_err() {
+        // Keywords are only identifiers when written raw
+        let keyword = syn::parse_str::<syn::Ident>(&format!("r#{}", name)).is_ok();
+        let problem = if keyword { "is a Rust keyword" } else { "is not a valid Rust identifier" };
+        return Err(TransformError::InvalidModuleName(format!("{} {}, so it can't name a module", name, problem)));
+    }
+    Ok(())
+}
+
+/// The `.rs` files at `path`: the file itself, or every one under a directory, in order
+pub fn legacy_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<_, _>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|extension| extension == "rs") {
+            legacy_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a file unless it already has these contents, so regenerating nothing new
+/// leaves the crate including it untouched
+pub fn write_if_changed(path: &Path, contents: &str) -> io::Result<()> {
+    if fs::read_to_string(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_generate_writes_modules_and_index() {
+        let temp_dir = TempDir::new().unwrap();
+        let legacy_dir = temp_dir.path().join("legacy");
+        fs::create_dir_all(legacy_dir.join("nested")).unwrap();
+        fs::write(legacy_dir.join("hello.rs"), "fn main() {\n    println!(\"hello\");\n}\n").unwrap();
+        fs::write(legacy_dir.join("nested").join("count.rs"), "fn main() {\n    let n: u32 = (1..=3).sum();\n    println!(\"{}\", n);\n}\n").unwrap();
+        fs::write(legacy_dir.join("notes.txt"), "not a program").unwrap();
+        let out_dir = temp_dir.path().join("out");
+
+        let modules = generate(&legacy_dir, &out_dir).unwrap();
+        assert_eq!(modules, ["hello_hydro", "count_hydro"]);
+        assert!(fs::read_to_string(out_dir.join("hello_hydro.rs")).unwrap().contains("pub fn hello_hydro"));
+
+        let index = syn::parse_file(&fs::read_to_string(out_dir.join(INDEX_FILE)).unwrap()).unwrap();
+        let declared: Vec<_> = index
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                syn::Item::Mod(module) => Some(module.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(declared, modules);
+
+        fs::write(legacy_dir.join("broken.rs"), "fn helper() {}").unwrap();
+        assert!(matches!(generate(&legacy_dir, &out_dir), Err(TransformError::NoMainFunction)));
+    }
+
+    #[test]
+    fn test_module_names_are_checked_before_generating() {
+        let temp_dir = TempDir::new().unwrap();
+        let legacy_dir = temp_dir.path().join("legacy");
+        fs::create_dir_all(legacy_dir.join("nested")).unwrap();
+        let program = "fn main() {\n    println!(\"hello\");\n}\n";
+        fs::write(legacy_dir.join("count.rs"), program).unwrap();
+        fs::write(legacy_dir.join("nested").join("count.rs"), program).unwrap();
+        let out_dir = temp_dir.path().join("out");
+
+        // Two files with one stem would declare the same module
+        let error = generate(&legacy_dir, &out_dir).unwrap_err().to_string();
+        assert!(error.contains("nested/count.rs would generate count_hydro, which is already taken by the program generated from"), "{}", error);
+        assert!(!out_dir.exists());
+
+        fs::remove_dir_all(legacy_dir.join("nested")).unwrap();
+        fs::write(legacy_dir.join("my-prog.rs"), program).unwrap();
+        let error = generate(&legacy_dir, &out_dir).unwrap_err().to_string();
+        assert!(error.contains("my-prog_hydro is not a valid Rust identifier"), "{}", error);
+
+        assert!(check_module_name("type").unwrap_err().to_string().contains("is a Rust keyword"));
+        assert!(check_module_name("count_hydro").is_ok());
+    }
+
+    #[test]
+    fn test_legacy_files_in_directories() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir(temp_dir.path().join("nested")).unwrap();
+        for name in ["b.rs", "a.rs", "notes.txt", "nested/c.rs"] {
+            fs::write(temp_dir.path().join(name), "fn main() {}").unwrap();
+        }
+
+        let mut files = Vec::new();
+        legacy_files(temp_dir.path(), &mut files).unwrap();
+        let names: Vec<_> = files.iter().map(|file| file.strip_prefix(temp_dir.path()).unwrap().to_path_buf()).collect();
+        assert_eq!(names, [PathBuf::from("a.rs"), PathBuf::from("b.rs"), PathBuf::from("nested/c.rs")]);
+    }
+}
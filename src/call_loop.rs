@@ -0,0 +1,106 @@
+//! The loops that make one blocking call per item, which moves out of the loop body
+
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Ident, Pat, Stmt};
+
+use crate::iterator_chain::{prefix_bindings, source};
+use crate::syntax::{leaves_early, mentions};
+
+/// A `for` loop whose first statement makes the loop's only call of some shape
+pub(crate) struct CallLoop<T> {
+    /// The collection the loop iterates over, as a block that builds it
+    pub source: Expr,
+    /// Whether the loop borrowed the items, so its pattern is matched against a
+    /// reference to each
+    pub by_ref: bool,
+    pub pat: Pat,
+    /// What the loop passes to the call for an item
+    pub call: T,
+    /// The loop body, with the call replaced by its result
+    pub body: Vec<Stmt>,
+}
+
+impl<T> CallLoop<T> {
+    /// Recognize a `for` loop whose first statement makes the loop's only call outside
+    /// closures that `call` recognizes, and replace that call with `result`. Any
+    /// statements before the loop must be immutable `let`s that only build the
+    /// collection, and the loop may be followed by a final `Ok(())`, and nothing else.
+    /// The body runs on the call's result, where only the item is at hand, so it can't
+    /// leave the loop or read those `let`s.
+    pub fn detect(stmts: &[Stmt], call: fn(&Expr) -> Option<T>, result: &str) -> Option<Self> {
+        let index = stmts.iter().position(|stmt| matches!(stmt, Stmt::Expr(Expr::ForLoop(_), _)))?;
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = &stmts[index] else {
+            return None;
+        };
+        if for_loop.label.is_some() {
+            return None;
+        }
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+        let prefix = &stmts[..index];
+        let prefix_names = prefix_bindings(prefix)?;
+        let (collection, by_ref) = match &*for_loop.expr {
+            Expr::Reference(reference) if reference.mutability.is_none() => (&*reference.expr, true),
+            Expr::MethodCall(call) if call.method == "iter" && call.args.is_empty() => (&*call.receiver, true),
+            items => (items, false),
+        };
+
+        // The one call, made by the first statement
+        let mut calls = Calls { call, result: None, found: Vec::new() };
+        calls.visit_block(&for_loop.body);
+        if calls.found.len() != 1 {
+            return None;
+        }
+        let mut body = for_loop.body.stmts.clone();
+        let mut first = Calls { call, result: Some(Ident::new(result, proc_macro2::Span::call_site())), found: Vec::new() };
+        first.visit_stmt_mut(body.first_mut()?);
+        let found = first.found.pop()?;
+
+        if leaves_early(&body) || prefix_names.iter().any(|name| mentions(&body, name)) {
+            return None;
+        }
+
+        Some(Self { source: source(prefix, collection), by_ref, pat: (*for_loop.pat).clone(), call: found, body })
+    }
+}
+
+/// Finds the calls `call` recognizes outside closures and, when rewriting, replaces each
+/// by `result`
+struct Calls<T> {
+    call: fn(&Expr) -> Option<T>,
+    result: Option<Ident>,
+    found: Vec<T>,
+}
+
+impl<'ast, T> Visit<'ast> for Calls<T> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        if matches!(expr, Expr::Closure(_)) {
+            return;
+        }
+        if let Some(found) = (self.call)(expr) {
+            self.found.push(found);
+            return;
+        }
+        visit::visit_expr(self, expr);
+    }
+}
+
+impl<T> VisitMut for Calls<T> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if matches!(expr, Expr::Closure(_)) {
+            return;
+        }
+        if let Some(found) = (self.call)(expr) {
+            self.found.push(found);
+            if let Some(result) = &self.result {
+                *expr = parse_quote! { #result };
+            }
+            return;
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
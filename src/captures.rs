@@ -0,0 +1,514 @@
+use std::collections::{BTreeSet, HashSet};
+
+use quote::quote;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, FnArg, Item, Lit, Pat, Stmt, Type};
+
+use crate::error::TransformError;
+use crate::syntax::is_name;
+
+/// The methods that only borrow the value they are called on, so a closure calling them
+/// on a captured value leaves it for its next call
+const BORROWS: [&str; 25] = [
+    "len", "is_empty", "iter", "contains", "contains_key", "get", "first", "last", "keys", "values",
+    "as_str", "as_slice", "as_bytes", "trim", "starts_with", "ends_with", "chars", "bytes", "lines", "split",
+    "split_whitespace", "to_string", "to_owned", "to_uppercase", "clone",
+];
+
+/// How the code quoted by a `q!` gets a value bound ahead of it in the dataflow function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// A number, `bool` or `char`, which stageleft copies into the quoted code as it is
+    Copy,
+    /// An owned value like a `Vec` or a `String`, defined in the quoted code instead, so
+    /// the code owns it wherever it runs
+    Move,
+    /// An owned value a closure uses up, defined ahead of the closure and cloned for each
+    /// of its calls
+    Clone,
+}
+
+/// A binding of a dataflow function that the quoted code uses
+#[derive(Debug, Clone)]
+pub struct CapturedLocal {
+    pub name: String,
+    /// The value it is bound to, which uses nothing else of the function
+    pub init: Expr,
+    pub capture: Capture,
+}
+
+/// The names a quoted expression uses without binding them itself. A name bound anywhere
+/// in the expression counts as bound throughout it, which only leaves out names a
+/// closure shadows.
+pub fn free_variables(quoted: &Expr) -> BTreeSet<String> {
+    let mut names = Names::default();
+    names.visit_expr(quoted);
+    names.used.difference(&names.bound).cloned().collect()
+}
+
+/// Thread the bindings of each dataflow function in a generated module into the quoted
+/// code using them. Numbers are left for stageleft to copy. Owned values are defined in
+/// each quoted block that uses them, and cloned for each call of a closure that uses
+/// them up. Their bindings in the function are dropped once nothing else reads them.
+pub fn thread_captures(module: String) -> Result<String, TransformError> {
+    let mut file = syn::parse_file(&module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let mut threaded = false;
+    for item in &mut file.items {
+        if let Item::Fn(func) = item {
+            threaded |= thread_function(func);
+        }
+    }
+    if !threaded {
+        return Ok(module);
+    }
+    Ok(prettyplease::unparse(&file))
+}
+
+/// Reach the functions, constants and statics of a generated module from the quoted
+/// code through their paths, like `crate::squares::print_all(..)`, rather than as values
+/// stageleft captures. A generic function has no single value to capture, and is only
+/// instantiated where the quoted code calls it. The items reached become public, for
+/// the quoted code to reach them from the crate it is compiled into.
+pub fn qualify_items(module: String, module_name: &str) -> Result<String, TransformError> {
+    let mut file = syn::parse_file(&module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let items: HashSet<String> = file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(item) => Some(item.sig.ident.to_string()),
+            Item::Const(item) => Some(item.ident.to_string()),
+            Item::Static(item) => Some(item.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+    let mut paths = ItemPaths {
+        items: &items,
+        module: syn::Ident::new(module_name, proc_macro2::Span::call_site()),
+        qualified: HashSet::new(),
+    };
+    paths.visit_file_mut(&mut file);
+    if paths.qualified.is_empty() {
+        return Ok(module);
+    }
+    for item in &mut file.items {
+        let (ident, vis) = match item {
+            Item::Fn(item) => (&item.sig.ident, &mut item.vis),
+            Item::Const(item) => (&item.ident, &mut item.vis),
+            Item::Static(item) => (&item.ident, &mut item.vis),
+            _ => continue,
+        };
+        if paths.qualified.contains(&ident.to_string()) {
+            *vis = parse_quote! { pub };
+        }
+    }
+    Ok(prettyplease::unparse(&file))
+}
+
+/// The bindings of a dataflow function whose values the quoted code could compute itself
+pub fn captured_locals(func: &syn::ItemFn) -> Vec<CapturedLocal> {
+    let mut builder: HashSet<String> = func
+        .sig
+        .inputs
+        .iter()
+        .filter_map(|input| match input {
+            FnArg::Typed(typed) => Some(&*typed.pat),
+            FnArg::Receiver(_) => None,
+        })
+        .flat_map(|pat| {
+            let mut names = Names::default();
+            names.visit_pat(pat);
+            names.bound
+        })
+        .collect();
+
+    let mut locals = Vec::new();
+    for stmt in &func.block.stmts {
+        let Stmt::Local(local) = stmt else {
+            continue;
+        };
+        let (pat, ty) = match &local.pat {
+            Pat::Type(typed) => (&*typed.pat, Some(&*typed.ty)),
+            pat => (pat, None),
+        };
+        let init = local.init.as_ref().filter(|init| init.diverge.is_none()).map(|init| &*init.expr);
+        let quotable = init.filter(|init| {
+            let mut names = Names::default();
+            names.visit_expr(init);
+            !names.quotes && names.used.is_disjoint(&builder)
+        });
+        match (pat, quotable) {
+            (Pat::Ident(binding), Some(init)) if binding.mutability.is_none() && binding.subpat.is_none() => {
+                let capture = if is_copy(init, ty) { Capture::Copy } else { Capture::Move };
+                locals.push(CapturedLocal { name: binding.ident.to_string(), init: init.clone(), capture });
+            }
+            _ => {}
+        }
+        // Later values built from this binding stay outside the quoted code with it
+        let mut names = Names::default();
+        names.visit_pat(&local.pat);
+        builder.extend(names.bound);
+    }
+    locals
+}
+
+fn thread_function(func: &mut syn::ItemFn) -> bool {
+    let owned: Vec<CapturedLocal> = captured_locals(func).into_iter().filter(|local| local.capture != Capture::Copy).collect();
+    if owned.is_empty() {
+        return false;
+    }
+    let mut quotes = Quotes { owned: &owned, threaded: HashSet::new() };
+    quotes.visit_block_mut(&mut func.block);
+    if quotes.threaded.is_empty() {
+        return false;
+    }
+
+    // A binding only the quoted code read has no use left in the function
+    let unused: HashSet<String> = quotes
+        .threaded
+        .into_iter()
+        .filter(|name| {
+            let mut names = Names { skip_quotes: true, ..Names::default() };
+            func.block.stmts.iter().filter(|stmt| !binds(stmt, name)).for_each(|stmt| names.visit_stmt(stmt));
+            !names.used.contains(name)
+        })
+        .collect();
+    func.block.stmts.retain(|stmt| !unused.iter().any(|name| binds(stmt, name)));
+    true
+}
+
+/// Whether a statement is the `let` binding `name`
+fn binds(stmt: &Stmt, name: &str) -> bool {
+    let Stmt::Local(local) = stmt else {
+        return false;
+    };
+    let pat = match &local.pat {
+        Pat::Type(typed) => &*typed.pat,
+        pat => pat,
+    };
+    matches!(pat, Pat::Ident(binding) if binding.ident == name)
+}
+
+/// Whether a value is one stageleft copies into quoted code: a number, `bool` or `char`
+fn is_copy(init: &Expr, ty: Option<&Type>) -> bool {
+    const PRIMITIVES: [&str; 17] =
+        ["i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize", "f32", "f64", "bool", "char", "()"];
+    if let Some(Type::Path(path)) = ty {
+        return path.path.get_ident().is_some_and(|ident| PRIMITIVES.contains(&ident.to_string().as_str()));
+    }
+    match init {
+        Expr::Lit(lit) => matches!(lit.lit, Lit::Int(_) | Lit::Float(_) | Lit::Bool(_) | Lit::Char(_)),
+        Expr::Unary(unary) => matches!(unary.op, syn::UnOp::Neg(_)) && is_copy(&unary.expr, None),
+        Expr::Paren(paren) => is_copy(&paren.expr, None),
+        _ => false,
+    }
+}
+
+/// The names an expression uses and binds, and whether it quotes code of its own
+#[derive(Default)]
+struct Names {
+    used: HashSet<String>,
+    bound: HashSet<String>,
+    quotes: bool,
+    /// Whether to leave out what `q!` macros use
+    skip_quotes: bool,
+}
+
+impl<'ast> Visit<'ast> for Names {
+    fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+        // A generic function called with its arguments, like `parse::<u32>`, is a name too
+        if let Some(ident) = single_name(path) {
+            self.used.insert(ident.to_string());
+        }
+        visit::visit_expr_path(self, path);
+    }
+
+    fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+        self.bound.insert(pat.ident.to_string());
+        visit::visit_pat_ident(self, pat);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        if mac.path.is_ident("q") {
+            self.quotes = true;
+            if self.skip_quotes {
+                return;
+            }
+        }
+        // Format arguments and quoted code use names too
+        if let Ok(args) = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated) {
+            args.iter().for_each(|arg| self.visit_expr(arg));
+        }
+    }
+}
+
+/// Defines the owned values each `q!` uses in the code it quotes
+struct Quotes<'o> {
+    owned: &'o [CapturedLocal],
+    /// The bindings defined in some quoted code
+    threaded: HashSet<String>,
+}
+
+impl VisitMut for Quotes<'_> {
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        if !mac.path.is_ident("q") {
+            return visit_mut::visit_macro_mut(self, mac);
+        }
+        let Ok(mut quoted) = mac.parse_body::<Expr>() else {
+            return;
+        };
+        let free = free_variables(&quoted);
+        let captured: Vec<&CapturedLocal> = self.owned.iter().filter(|local| free.contains(&local.name)).collect();
+        if captured.is_empty() {
+            return;
+        }
+
+        let closure = match &mut quoted {
+            Expr::Closure(_) => true,
+            // State set up ahead of the closure, like `{ let mut seen = 0; move |x| .. }`
+            Expr::Block(block) => matches!(block.block.stmts.last(), Some(Stmt::Expr(Expr::Closure(_), None))),
+            _ => false,
+        };
+        let mut definitions: Vec<Stmt> = Vec::new();
+        let mut clones: Vec<Stmt> = Vec::new();
+        for local in captured {
+            let name = syn::Ident::new(&local.name, proc_macro2::Span::call_site());
+            let init = &local.init;
+            definitions.push(parse_quote! { let #name = #init; });
+            if closure && uses_up(&quoted, &local.name) {
+                clones.push(parse_quote! { let #name = #name.clone(); });
+            }
+            self.threaded.insert(local.name.clone());
+        }
+
+        quoted = match quoted {
+            Expr::Closure(mut closure) => {
+                move_closure(&mut closure, clones);
+                parse_quote! {{ #(#definitions)* #closure }}
+            }
+            Expr::Block(mut block) if closure => {
+                if let Some(Stmt::Expr(Expr::Closure(closure), None)) = block.block.stmts.last_mut() {
+                    move_closure(closure, clones);
+                }
+                block.block.stmts.splice(0..0, definitions);
+                Expr::Block(block)
+            }
+            Expr::Block(mut block) => {
+                block.block.stmts.splice(0..0, definitions);
+                Expr::Block(block)
+            }
+            quoted => parse_quote! {{ #(#definitions)* #quoted }},
+        };
+        mac.tokens = quote! { #quoted };
+    }
+}
+
+/// Qualifies the names of a module's items in the code its `q!`s quote
+struct ItemPaths<'i> {
+    items: &'i HashSet<String>,
+    module: syn::Ident,
+    /// The items the quoted code names
+    qualified: HashSet<String>,
+}
+
+impl VisitMut for ItemPaths<'_> {
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        if !mac.path.is_ident("q") {
+            return visit_mut::visit_macro_mut(self, mac);
+        }
+        let Ok(mut quoted) = mac.parse_body::<Expr>() else {
+            return;
+        };
+        // A name the quoted code binds itself is left alone
+        let names: HashSet<String> = free_variables(&quoted).into_iter().filter(|name| self.items.contains(name)).collect();
+        if names.is_empty() {
+            return;
+        }
+        let mut qualify = Qualify { names: &names, module: &self.module };
+        qualify.visit_expr_mut(&mut quoted);
+        mac.tokens = quote! { #quoted };
+        self.qualified.extend(names);
+    }
+}
+
+/// Prefixes the paths of `names` with their module
+struct Qualify<'n> {
+    names: &'n HashSet<String>,
+    module: &'n syn::Ident,
+}
+
+impl VisitMut for Qualify<'_> {
+    fn visit_expr_path_mut(&mut self, path: &mut syn::ExprPath) {
+        if single_name(path).is_some_and(|ident| self.names.contains(&ident.to_string())) {
+            let module = self.module;
+            let segment = &path.path.segments[0];
+            path.path = parse_quote! { crate::#module::#segment };
+        }
+    }
+
+    // Format arguments name items too
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        let Ok(mut args) = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated) else {
+            return;
+        };
+        args.iter_mut().for_each(|arg| self.visit_expr_mut(arg));
+        mac.tokens = quote! { #args };
+    }
+}
+
+/// The name of a path of one segment, which may have generic arguments, like `parse::<u32>`
+fn single_name(path: &syn::ExprPath) -> Option<&syn::Ident> {
+    let plain = path.qself.is_none() && path.path.leading_colon.is_none() && path.path.segments.len() == 1;
+    plain.then(|| &path.path.segments[0].ident)
+}
+
+/// Make a closure own what it captures, running `clones` at the start of each call
+fn move_closure(closure: &mut syn::ExprClosure, clones: Vec<Stmt>) {
+    closure.capture = Some(Default::default());
+    if !clones.is_empty() {
+        let body = &closure.body;
+        closure.body = Box::new(parse_quote! {{
+            #(#clones)*
+            #body
+        }});
+    }
+}
+
+/// Whether quoted code may use up the value of `name`: every use of it that isn't a
+/// borrow, like `&name`, `name[i]`, a format argument or a call of a borrowing method
+fn uses_up(quoted: &Expr, name: &str) -> bool {
+    struct UsesUp<'n> {
+        name: &'n str,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for UsesUp<'_> {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::Reference(reference) if is_name(&reference.expr, self.name) => {}
+                Expr::Index(index) if is_name(&index.expr, self.name) => self.visit_expr(&index.index),
+                Expr::MethodCall(call) if is_name(&call.receiver, self.name) && BORROWS.contains(&call.method.to_string().as_str()) => {
+                    call.args.iter().for_each(|arg| self.visit_expr(arg));
+                }
+                expr if is_name(expr, self.name) => self.found = true,
+                expr => visit::visit_expr(self, expr),
+            }
+        }
+
+        // Format arguments are borrowed
+        fn visit_macro(&mut self, _: &'ast syn::Macro) {}
+    }
+
+    let mut uses = UsesUp { name, found: false };
+    uses.visit_expr(quoted);
+    uses.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::compact_str;
+
+    #[test]
+    fn test_free_variables_leave_out_bound_names() {
+        let quoted: Expr = syn::parse_str("|line| { let words = line.split(sep); println!(\"{} {}\", prefix, count); words }").unwrap();
+        let free: Vec<String> = free_variables(&quoted).into_iter().collect();
+        assert_eq!(free, ["count", "prefix", "sep"]);
+    }
+
+    #[test]
+    fn test_captured_vec_string_and_number() {
+        let module = r#"
+use hydro_lang::*;
+
+pub fn f<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+    let stdin_lines = vec!["Alice".to_string(), "Bob".to_string()];
+    let greeting = String::from("Hello");
+    let limit = 3;
+    let members = process.members();
+    process
+        .source_iter(q!(stdin_lines.into_iter()))
+        .map(q!(|line| format!("{}, {}", greeting, line)))
+        .filter(q!(|line| line.len() > limit))
+        .map(q!(|line| { let known = stdin_lines; (line, known.len()) }))
+        .send_bincode_external(external)
+}
+"#;
+        let func = match &syn::parse_file(module).unwrap().items[1] {
+            Item::Fn(func) => func.clone(),
+            _ => unreachable!(),
+        };
+        let captures: Vec<(String, Capture)> = captured_locals(&func).into_iter().map(|local| (local.name, local.capture)).collect();
+        // `members` comes from the process, which only the builder has
+        assert_eq!(
+            captures,
+            [("stdin_lines".to_string(), Capture::Move), ("greeting".to_string(), Capture::Move), ("limit".to_string(), Capture::Copy)]
+        );
+
+        let threaded = compact_str(&thread_captures(module.to_string()).unwrap());
+        // The vec is defined where it's iterated, and the number is left to stageleft
+        assert!(threaded.contains(".source_iter(q!({letstdin_lines=vec![\"Alice\".to_string(),\"Bob\".to_string()];stdin_lines.into_iter()})"));
+        assert!(threaded.contains(".filter(q!(|line|line.len()>limit))"));
+        assert!(threaded.contains("letlimit=3;"));
+        // A format argument only borrows the string, which the closure then owns
+        assert!(threaded.contains(".map(q!({letgreeting=String::from(\"Hello\");move|line|format!(\"{},{}\",greeting,line)})"));
+        // A closure using the vec up gets a clone of it on each call
+        assert!(threaded.contains("move|line|{letstdin_lines=stdin_lines.clone();{letknown=stdin_lines;(line,known.len())}}"));
+        // Only the quoted code read the owned values
+        assert!(!threaded.contains("letgreeting=String::from(\"Hello\");letlimit"));
+        assert!(threaded.contains("->ExternalBincodeStream<()>{letlimit=3;letmembers"));
+        assert!(threaded.contains("letmembers=process.members();"));
+    }
+
+    #[test]
+    fn test_modules_without_captures_are_left_alone() {
+        let module = "use hydro_lang::*;\n\npub fn f<'a>(process: &Process<'a>) {\n    process.source_iter(q!(0..3)).for_each(q!(|n| println!(\"{}\", n)));\n}\n";
+        assert_eq!(thread_captures(module.to_string()).unwrap(), module);
+
+        // A binding the builder reads too stays, with its own copy in the quoted code
+        let module = "pub fn f<'a>(process: &Process<'a>) {\n    let names = vec![1, 2];\n    println!(\"{}\", names.len());\n    process.source_iter(q!(names)).for_each(q!(|n| println!(\"{}\", n)));\n}\n";
+        let threaded = compact_str(&thread_captures(module.to_string()).unwrap());
+        assert!(threaded.contains("{letnames=vec![1,2];println!(\"{}\",names.len());"));
+        assert!(threaded.contains(".source_iter(q!({letnames=vec![1,2];names})"));
+    }
+
+    #[test]
+    fn test_module_items_are_reached_through_their_paths() {
+        let module = r#"
+use std::fmt::Display;
+
+pub fn squares<'a>(process: &Process<'a>) {
+    process
+        .source_iter(q!(vec![vec![1u32, 2], vec![3]]))
+        .map(q!(|row| { print_all::<u32>(&row); row.len() * SCALE }))
+        .filter(q!(|n| { let largest = |n: &usize| *n > 1; largest(n) }))
+        .for_each(q!(|n| println!("{} {}", n, largest(&[n, SCALE]))));
+}
+
+fn print_all<T: Display>(items: &[T]) where T: Copy {
+    for item in items {
+        println!("{}", item);
+    }
+}
+
+fn largest<T: PartialOrd + Copy>(items: &[T]) -> T {
+    items.iter().copied().fold(items[0], |a, b| if b > a { b } else { a })
+}
+
+const SCALE: usize = 2;
+"#;
+        let qualified = compact_str(&qualify_items(module.to_string(), "squares").unwrap());
+        // A generic call keeps its arguments, for the quoted code to instantiate it
+        assert!(qualified.contains("crate::squares::print_all::<u32>(&row);row.len()*crate::squares::SCALE"));
+        assert!(qualified.contains("println!(\"{}{}\",n,crate::squares::largest(&[n,crate::squares::SCALE]))"));
+        // A closure binding the same name keeps its own
+        assert!(qualified.contains("letlargest=|n:&usize|*n>1;largest(n)"));
+        assert!(qualified.contains("pubfnprint_all<T:Display>(items:&[T])whereT:Copy,{"));
+        assert!(qualified.contains("pubfnlargest<T:PartialOrd+Copy>"));
+        assert!(qualified.contains("pubconstSCALE:usize=2;"));
+
+        // Nothing quoted names an item
+        let module = "pub fn f<'a>(process: &Process<'a>) {\n    process.source_iter(q!(0..3)).for_each(q!(|n| println!(\"{}\", n)));\n}\n";
+        assert_eq!(qualify_items(module.to_string(), "f").unwrap(), module);
+    }
+}
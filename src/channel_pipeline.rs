@@ -0,0 +1,313 @@
+use proc_macro2::Span;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Block, Expr, Pat, Stmt};
+
+use crate::syntax::{is_name, mentions};
+
+/// A legacy program where a spawned producer thread sends values over an `mpsc`
+/// channel and the main thread consumes them in a loop
+pub struct ChannelPipeline {
+    /// The producer thread's body, with every `send` turned into a push onto `sent`
+    pub producer: Vec<Stmt>,
+    /// The pattern the consumer binds each received value to
+    pub pat: Pat,
+    /// The body the consumer runs for each value
+    pub consumer: Vec<Stmt>,
+}
+
+impl ChannelPipeline {
+    /// Recognize a main body consisting only of a channel, a thread spawned to produce
+    /// into it, a loop draining it, and optionally a `join` of the producer
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let mut channel = None;
+        let mut producer = None;
+        let mut consumer = None;
+
+        for stmt in stmts {
+            if let Some(ends) = channel_decl(stmt) {
+                if channel.is_some() {
+                    return None;
+                }
+                channel = Some(ends);
+                continue;
+            }
+            let (tx, rx) = channel.as_ref()?;
+            if let Some(body) = spawned_body(stmt) {
+                if producer.is_some() || mentions(&body.stmts, rx) {
+                    return None;
+                }
+                producer = Some(body);
+            } else if let Some((pat, body)) = receive_loop(stmt, rx) {
+                if producer.is_none() || consumer.is_some() || mentions(&body.stmts, tx) {
+                    return None;
+                }
+                consumer = Some((pat, body));
+            } else if !is_teardown(stmt, tx) {
+                return None;
+            }
+        }
+
+        let (tx, _) = channel?;
+        let mut producer = producer?;
+        let (pat, consumer) = consumer?;
+
+        let mut sends = SendRewriter { tx: &tx, sent: false };
+        sends.visit_block_mut(&mut producer);
+        // Any other use of the sender has nothing to refer to once it is gone
+        if !sends.sent || mentions(&producer.stmts, &tx) {
+            return None;
+        }
+
+        Some(Self {
+            producer: producer.stmts,
+            pat,
+            consumer: consumer.stmts,
+        })
+    }
+}
+
+/// The buffer the producer's sends are collected into
+pub fn sent_buffer() -> syn::Ident {
+    syn::Ident::new("sent", Span::call_site())
+}
+
+/// `let (tx, rx) = mpsc::channel();` (or `sync_channel(n)`), returning the two names
+fn channel_decl(stmt: &Stmt) -> Option<(String, String)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Expr::Call(call) = &*local.init.as_ref()?.expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let name = func.path.segments.last()?.ident.to_string();
+    if name != "channel" && name != "sync_channel" {
+        return None;
+    }
+
+    let Pat::Tuple(ends) = &local.pat else {
+        return None;
+    };
+    match ends.elems.iter().map(pat_name).collect::<Option<Vec<_>>>()?.as_slice() {
+        [tx, rx] => Some((tx.clone(), rx.clone())),
+        _ => None,
+    }
+}
+
+fn pat_name(pat: &Pat) -> Option<String> {
+    match pat {
+        Pat::Ident(ident) => Some(ident.ident.to_string()),
+        _ => None,
+    }
+}
+
+/// The body of a `move ||` closure passed to `thread::spawn`, bound or not
+fn spawned_body(stmt: &Stmt) -> Option<Block> {
+    let expr = match stmt {
+        Stmt::Local(local) => &*local.init.as_ref()?.expr,
+        Stmt::Expr(expr, Some(_)) => expr,
+        _ => return None,
+    };
+    spawn_closure_body(expr)
+}
+
+/// The body of the closure in a `thread::spawn(move || ...)` call
+pub(crate) fn spawn_closure_body(expr: &Expr) -> Option<Block> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    if func.path.segments.last()?.ident != "spawn" || call.args.len() != 1 {
+        return None;
+    }
+    let Expr::Closure(closure) = &call.args[0] else {
+        return None;
+    };
+    if !closure.inputs.is_empty() {
+        return None;
+    }
+    match &*closure.body {
+        Expr::Block(block) => Some(block.block.clone()),
+        body => Some(parse_quote! {{ #body; }}),
+    }
+}
+
+/// `for x in rx`, `for x in rx.iter()` or `while let Ok(x) = rx.recv()`
+fn receive_loop(stmt: &Stmt, rx: &str) -> Option<(Pat, Block)> {
+    let Stmt::Expr(expr, _) = stmt else {
+        return None;
+    };
+    match expr {
+        Expr::ForLoop(for_loop) if for_loop.label.is_none() => {
+            let receiver = match &*for_loop.expr {
+                Expr::MethodCall(call) if call.method == "iter" || call.method == "into_iter" => &*call.receiver,
+                receiver => receiver,
+            };
+            is_name(receiver, rx).then(|| ((*for_loop.pat).clone(), for_loop.body.clone()))
+        }
+        Expr::While(while_loop) if while_loop.label.is_none() => {
+            let Expr::Let(binding) = &*while_loop.cond else {
+                return None;
+            };
+            let Expr::MethodCall(recv) = &*binding.expr else {
+                return None;
+            };
+            if recv.method != "recv" || !is_name(&recv.receiver, rx) {
+                return None;
+            }
+            let Pat::TupleStruct(ok) = &*binding.pat else {
+                return None;
+            };
+            if !ok.path.is_ident("Ok") || ok.elems.len() != 1 {
+                return None;
+            }
+            Some((ok.elems[0].clone(), while_loop.body.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Statements that only wind the threads down: `drop(tx);` and `handle.join().unwrap();`
+fn is_teardown(stmt: &Stmt, tx: &str) -> bool {
+    let Stmt::Expr(expr, Some(_)) = stmt else {
+        return false;
+    };
+    let expr = match expr {
+        Expr::MethodCall(call) if call.method == "unwrap" || call.method == "expect" => &*call.receiver,
+        expr => expr,
+    };
+    match expr {
+        Expr::MethodCall(call) => call.method == "join" && call.args.is_empty(),
+        Expr::Call(call) => {
+            matches!(&*call.func, Expr::Path(func) if func.path.is_ident("drop"))
+                && call.args.len() == 1
+                && is_name(&call.args[0], tx)
+        }
+        _ => false,
+    }
+}
+
+/// Rewrites `tx.send(v)` into pushes onto the `sent` buffer
+struct SendRewriter<'t> {
+    tx: &'t str,
+    sent: bool,
+}
+
+impl SendRewriter<'_> {
+    /// The value of a `tx.send(value)` call
+    fn sent_value(&self, expr: &Expr) -> Option<Expr> {
+        match expr {
+            Expr::MethodCall(call) if call.method == "send" && call.args.len() == 1 && is_name(&call.receiver, self.tx) => {
+                Some(call.args[0].clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl VisitMut for SendRewriter<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let sent = sent_buffer();
+        // Sending can't fail once the values are collected, so `.unwrap()` goes too
+        if let Expr::MethodCall(call) = expr {
+            if call.method == "unwrap" || call.method == "expect" {
+                if let Some(mut value) = self.sent_value(&call.receiver) {
+                    self.visit_expr_mut(&mut value);
+                    *expr = parse_quote! { #sent.push(#value) };
+                    self.sent = true;
+                    return;
+                }
+            }
+        }
+
+        visit_mut::visit_expr_mut(self, expr);
+        if let Some(value) = self.sent_value(expr) {
+            *expr = parse_quote! { { #sent.push(#value); Ok::<(), std::convert::Infallible>(()) } };
+            self.sent = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact_stmts, parse_block};
+
+    fn detect(body: &str) -> Option<ChannelPipeline> {
+        ChannelPipeline::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_producer_consumer_is_detected() {
+        let pipeline = detect(r#"{
+            let (tx, rx) = mpsc::channel();
+            let producer = thread::spawn(move || {
+                for i in 0..5 {
+                    tx.send(i * 10).unwrap();
+                }
+            });
+            for value in rx {
+                println!("Received {}", value);
+            }
+            producer.join().unwrap();
+        }"#).unwrap();
+
+        assert_eq!(compact_stmts(&pipeline.producer), "foriin0..5{sent.push(i*10);}");
+        assert_eq!(quote::ToTokens::to_token_stream(&pipeline.pat).to_string(), "value");
+        assert_eq!(compact_stmts(&pipeline.consumer), "println!(\"Received{}\",value);");
+    }
+
+    #[test]
+    fn test_recv_loop_and_unchecked_send() {
+        let pipeline = detect(r#"{
+            let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+            thread::spawn(move || {
+                let _ = sender.send("ping".to_string());
+                drop(sender);
+            });
+            while let Ok(msg) = receiver.recv() {
+                println!("{}", msg);
+            }
+        }"#);
+
+        // The sender is used for more than sending
+        assert!(pipeline.is_none());
+
+        let pipeline = detect(r#"{
+            let (sender, receiver) = std::sync::mpsc::sync_channel(4);
+            thread::spawn(move || {
+                let _ = sender.send("ping".to_string());
+            });
+            while let Ok(msg) = receiver.recv() {
+                println!("{}", msg);
+            }
+        }"#).unwrap();
+        assert!(compact_stmts(&pipeline.producer).contains("{sent.push(\"ping\".to_string());Ok::<(),std::convert::Infallible>(())}"));
+    }
+
+    #[test]
+    fn test_other_thread_patterns_are_not_pipelines() {
+        // No consumer loop
+        assert!(detect(r#"{
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || { tx.send(1).unwrap(); });
+            println!("{}", rx.recv().unwrap());
+        }"#).is_none());
+        // The consumer sends back into the channel
+        assert!(detect(r#"{
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || { tx.send(1).unwrap(); });
+            for v in rx { println!("{}", v); tx.send(v).unwrap(); }
+        }"#).is_none());
+        // ... or uses the sender only inside a nested group of a macro's tokens
+        assert!(detect(r#"{
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || { tx.send(1).unwrap(); });
+            for v in rx { println!("{} {:?}", v, vec![tx.clone()]); }
+        }"#).is_none());
+    }
+}
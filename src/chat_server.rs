@@ -0,0 +1,104 @@
+use hydro_lang::*;
+use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
+
+/// What happens on a client connection of the chat, numbered by the bridge that
+/// accepts them
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatEvent {
+    Joined(u64),
+    Said(u64, String),
+    Left(u64),
+}
+
+/// The legacy chat server (`legacy::chat_server`) as a dataflow behind a pair of external
+/// ports. The sockets stay outside: the bridge in `examples/chat_server_hydro.rs` accepts
+/// the connections and sends what happens on them as `ChatEvent`s. The dataflow keeps
+/// the clients that are connected, which the legacy server kept behind a mutex, and
+/// sends back each line said as a message for every one of them, `(connection, line)`.
+pub fn chat_server<'a>(
+    process: &Process<'a>,
+    external: &ExternalProcess<'a, ()>,
+) -> (ExternalBincodeSink<ChatEvent>, ExternalBincodeStream<(u64, String)>) {
+    let (events_port, events) = process.source_external_bincode(external);
+
+    let messages = events.flat_map_ordered(q!({
+        let mut clients = std::collections::BTreeSet::new();
+        move |event: ChatEvent| match event {
+            ChatEvent::Joined(client) => {
+                clients.insert(client);
+                Vec::new()
+            }
+            ChatEvent::Left(client) => {
+                clients.remove(&client);
+                Vec::new()
+            }
+            ChatEvent::Said(client, line) => {
+                let message = format!("[{}] {}", client, line);
+                clients.iter().map(|&to| (to, message.clone())).collect()
+            }
+        }
+    })); // : Stream<(u64, String), Process, ...>
+
+    let messages_port = messages.send_bincode_external(external);
+
+    (events_port, messages_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use hydro_deploy::Deployment;
+
+    use super::ChatEvent;
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    #[tokio::test]
+    async fn chat_server() {
+        let mut deployment = Deployment::new();
+
+        let flow = hydro_lang::FlowBuilder::new();
+        let process = flow.process();
+        let external = flow.external_process::<()>();
+        let (events_port, messages_port) = super::chat_server(&process, &external);
+
+        let nodes = flow
+            .with_process(&process, deployment.Localhost())
+            .with_external(&external, deployment.Localhost())
+            .deploy(&mut deployment);
+
+        deployment.deploy().await.unwrap();
+
+        let mut events = nodes.connect_sink_bincode(events_port).await;
+        let mut messages = nodes.connect_source_bincode(messages_port).await;
+
+        deployment.start().await.unwrap();
+
+        // A line reaches everyone connected when it is said, the speaker included
+        for event in [
+            ChatEvent::Joined(0),
+            ChatEvent::Joined(1),
+            ChatEvent::Said(0, "hi".to_string()),
+            ChatEvent::Left(1),
+            ChatEvent::Said(0, "bye".to_string()),
+        ] {
+            events.send(event).await.unwrap();
+        }
+
+        let received = messages.take(3).collect::<Vec<_>>().await;
+        assert_eq!(received, [(0, "[0] hi".to_string()), (1, "[0] hi".to_string()), (0, "[0] bye".to_string())]);
+    }
+
+    #[test]
+    fn test_legacy_chat_server_runs_whole_as_a_service() {
+        // No translation takes a plain TCP server apart yet, so the generator wraps the
+        // legacy body, sockets and all, and serves it until it is interrupted. The
+        // hand-written module above is what taking it apart should produce.
+        let legacy = syn::parse_file(include_str!("legacy/chat_server.rs")).unwrap();
+        let transformer = SynLegacyToHydroTransformer::new();
+        assert_eq!(transformer.translation(&legacy).unwrap(), None);
+        let output = transformer.transform_file(&legacy, "chat_server").unwrap();
+        assert!(output.hydro_function.contains("TcpListener::bind"));
+        assert!(output.example_program.contains("tracing::info!(event = \"service_listening\", address = \"127.0.0.1:7878\");"));
+        assert!(output.example_program.contains("deployment.run_ctrl_c().await.unwrap();"));
+    }
+}
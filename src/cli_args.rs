@@ -0,0 +1,207 @@
+use quote::ToTokens;
+use syn::{parse_quote, Expr, Item, Pat, Stmt, Type, UseTree, Visibility};
+
+use crate::json_lines::record_definitions;
+
+/// A legacy program parsing its command line with `clap`, either through a
+/// `#[derive(Parser)]` struct or a `clap::Command` builder. The parsing moves to the
+/// example, which hands the parsed arguments to the dataflow as its configuration.
+pub struct CliArgs {
+    /// The binding of the parsed arguments in the legacy body
+    pub pat: Pat,
+    /// The expression parsing them, as the example runs it
+    pub parse: Expr,
+    /// The type of the parsed arguments
+    pub ty: Type,
+    /// The definitions of the `Parser` struct and the types it uses, made public so the
+    /// example can parse into them; empty for a `Command` builder
+    pub definitions: Vec<Item>,
+    /// The legacy file's `clap` imports, which the parsing needs
+    pub imports: Vec<Item>,
+}
+
+impl CliArgs {
+    /// Find `let args = Args::parse();` for a `Parser` struct defined in `items`, or
+    /// `let matches = Command::new(..)...get_matches();`, among the top-level statements
+    /// of a legacy body. Returns the arguments and the body without that statement.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<(Self, Vec<Stmt>)> {
+        let imports: Vec<Item> = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if uses_clap(&item.tree)))
+            .cloned()
+            .collect();
+
+        stmts.iter().enumerate().find_map(|(index, stmt)| {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+            let (pat, annotated) = match &local.pat {
+                Pat::Type(typed) => ((*typed.pat).clone(), Some((*typed.ty).clone())),
+                pat => (pat.clone(), None),
+            };
+            let (ty, definitions) = match parsed_type(&init.expr, items).or(annotated.filter(|_| is_parse(&init.expr))) {
+                Some(ty) => {
+                    let mut definitions = record_definitions(items, &ty);
+                    definitions.retain(|item| !matches!(item, Item::Use(_)));
+                    if !definitions.iter().any(derives_parser) {
+                        return None;
+                    }
+                    definitions.iter_mut().for_each(publish);
+                    (ty, definitions)
+                }
+                None if is_command(&init.expr) => (parse_quote! { clap::ArgMatches }, Vec::new()),
+                None => return None,
+            };
+
+            let mut rest = stmts.to_vec();
+            rest.remove(index);
+            let args = CliArgs { pat, parse: (*init.expr).clone(), ty, definitions, imports: imports.clone() };
+            Some((args, rest))
+        })
+    }
+
+    /// The name of the parsed arguments, as the parameter passing them to the dataflow
+    pub fn param(&self) -> syn::Ident {
+        match &self.pat {
+            Pat::Ident(pat) => pat.ident.clone(),
+            _ => syn::Ident::new("cli_args", proc_macro2::Span::call_site()),
+        }
+    }
+}
+
+fn uses_clap(tree: &UseTree) -> bool {
+    matches!(tree, UseTree::Path(path) if path.ident == "clap")
+}
+
+/// `Args::parse()`, `<Args as Parser>::parse()` or `Parser::parse()` and the like
+fn is_parse(expr: &Expr) -> bool {
+    let Expr::Call(call) = expr else {
+        return false;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return false;
+    };
+    let name = func.path.segments.last().map(|segment| segment.ident.to_string());
+    call.args.is_empty() && matches!(name.as_deref(), Some("parse" | "try_parse"))
+}
+
+/// The struct `T` a `T::parse()` or `<T as Parser>::parse()` parses into
+fn parsed_type(expr: &Expr, items: &[Item]) -> Option<Type> {
+    if !is_parse(expr) {
+        return None;
+    }
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    if let Some(qself) = &func.qself {
+        return Some((*qself.ty).clone());
+    }
+    let segments: Vec<_> = func.path.segments.iter().collect();
+    let [.., ty, _] = &segments[..] else {
+        return None;
+    };
+    let defined = items.iter().any(|item| matches!(item, Item::Struct(item) if item.ident == ty.ident));
+    defined.then(|| parse_quote! { #ty })
+}
+
+/// A `clap::Command` builder chain ending in `get_matches()`
+fn is_command(expr: &Expr) -> bool {
+    let Expr::MethodCall(call) = expr else {
+        return false;
+    };
+    let tokens = call.receiver.to_token_stream().to_string();
+    call.method == "get_matches" && (tokens.starts_with("Command :: new") || tokens.starts_with("clap :: Command :: new"))
+}
+
+fn derives_parser(item: &Item) -> bool {
+    let Item::Struct(item) = item else {
+        return false;
+    };
+    item.attrs.iter().any(|attr| {
+        attr.path().is_ident("derive") && attr.meta.to_token_stream().to_string().contains("Parser")
+    })
+}
+
+fn publish(item: &mut Item) {
+    let vis = match item {
+        Item::Struct(item) => &mut item.vis,
+        Item::Enum(item) => &mut item.vis,
+        Item::Type(item) => &mut item.vis,
+        _ => return,
+    };
+    *vis = Visibility::Public(Default::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str) -> Option<(CliArgs, Vec<Stmt>)> {
+        let (items, main) = parse_main(source)?;
+        CliArgs::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_parser_struct_is_lifted() {
+        let (args, rest) = detect(r#"
+            use clap::Parser;
+
+            #[derive(Parser, Debug)]
+            struct Args {
+                #[arg(short, long, default_value_t = 1)]
+                count: u32,
+                mode: Mode,
+            }
+
+            #[derive(Clone, Debug, clap::ValueEnum)]
+            enum Mode { Fast, Slow }
+
+            fn main() {
+                let args = Args::parse();
+                for _ in 0..args.count {
+                    println!("{:?}", args.mode);
+                }
+            }
+        "#).unwrap();
+
+        assert_eq!(compact(&args.ty), "Args");
+        assert_eq!(args.param(), "args");
+        assert_eq!(rest.len(), 1);
+        assert_eq!(args.imports.len(), 1);
+        // Both definitions come along, made public for the example
+        let definitions: Vec<String> = args.definitions.iter().map(compact).collect();
+        assert!(definitions[0].contains("pubstructArgs"));
+        assert!(definitions[1].contains("pubenumMode"));
+    }
+
+    #[test]
+    fn test_command_builder_is_lifted() {
+        let (args, rest) = detect(r#"
+            use clap::{Arg, Command};
+
+            fn main() {
+                let matches = Command::new("greet").arg(Arg::new("name")).get_matches();
+                let name: &String = matches.get_one("name").unwrap();
+                println!("Hello, {}!", name);
+            }
+        "#).unwrap();
+
+        assert_eq!(compact(&args.ty), "clap::ArgMatches");
+        assert!(args.definitions.is_empty());
+        assert_eq!(args.param(), "matches");
+        assert_eq!(rest.len(), 2);
+    }
+
+    #[test]
+    fn test_other_parses_are_not_cli_args() {
+        // Not a Parser
+        assert!(detect("struct Args; fn main() { let args = Args::parse(); }").is_none());
+        // Parsing a number
+        assert!(detect("fn main() { let n: u32 = \"4\".parse().unwrap(); }").is_none());
+    }
+}
@@ -0,0 +1,270 @@
+use proc_macro2::Span;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Pat, Stmt};
+
+use crate::syntax::{leaves_early, mentions};
+
+/// A legacy loop that fills a `Vec` one `push` at a time, followed by the code that
+/// uses the finished `Vec`
+pub struct CollectLoop {
+    /// The binding of the `Vec`, which the downstream code gets the collected items in
+    pub buffer: Pat,
+    pub pat: Pat,
+    pub items: Expr,
+    pub produce: Produce,
+    /// Whether the code after the loop starts by sorting the `Vec` by its natural order
+    pub sorted: bool,
+    /// Further sorting, deduplication or reversal of the `Vec` before it is used, as
+    /// statements on `collected`
+    pub reorder: Vec<Stmt>,
+    /// The statements after the loop and the reordering
+    pub rest: Vec<Stmt>,
+}
+
+/// What each iteration contributes to the `Vec`
+pub enum Produce {
+    /// Exactly one item, the value of this body
+    One(Box<Expr>),
+    /// Any number of items: the body pushes onto `produced` and evaluates to it
+    Many(Box<Expr>),
+}
+
+impl CollectLoop {
+    /// Recognize `let mut v = Vec::new(); for x in xs { .. v.push(..) .. }` followed by
+    /// the statements consuming `v`
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let [decl, fill, rest @ ..] = stmts else {
+            return None;
+        };
+        let Stmt::Local(local) = decl else {
+            return None;
+        };
+        let Pat::Ident(buffer) = &local.pat else {
+            return None;
+        };
+        if !is_empty_vec(&local.init.as_ref()?.expr) {
+            return None;
+        }
+        let name = buffer.ident.to_string();
+
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = fill else {
+            return None;
+        };
+        if for_loop.label.is_some() || leaves_early(&for_loop.body.stmts) {
+            return None;
+        }
+        let body = &for_loop.body.stmts;
+
+        let produce = match body.split_last() {
+            // The push is the last thing each iteration does, and the only one
+            Some((Stmt::Expr(last, _), before)) if pushed_value(last, &name).is_some() && !mentions(before, &name) => {
+                let value = pushed_value(last, &name)?;
+                if before.is_empty() {
+                    Produce::One(Box::new(value))
+                } else {
+                    Produce::One(Box::new(parse_quote! {{ #(#before)* #value }}))
+                }
+            }
+            _ => {
+                let mut pushes = PushRewriter { name: &name, pushed: false };
+                let mut body = body.clone();
+                for stmt in &mut body {
+                    pushes.visit_stmt_mut(stmt);
+                }
+                if !pushes.pushed || mentions(&body, &name) {
+                    return None;
+                }
+                let produced = produced_buffer();
+                crate::print_rewriter::terminate_tail(&mut body);
+                Produce::Many(Box::new(parse_quote! {{
+                    let mut #produced = Vec::new();
+                    #(#body)*
+                    #produced
+                }}))
+            }
+        };
+
+        let mut rest = rest;
+        let mut sorted = false;
+        let mut reorder = Vec::new();
+        while let Some((stmt, after)) = rest.split_first() {
+            let Some((method, args)) = reordering(stmt, &name) else {
+                break;
+            };
+            if reorder.is_empty() && !sorted && args.is_empty() && (method == "sort" || method == "sort_unstable") {
+                sorted = true;
+            } else {
+                let collected = collected_ident();
+                reorder.push(parse_quote! { #collected.#method(#(#args),*); });
+            }
+            rest = after;
+        }
+
+        Some(Self {
+            buffer: local.pat.clone(),
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            produce,
+            sorted,
+            reorder,
+            rest: rest.to_vec(),
+        })
+    }
+}
+
+/// The per-iteration buffer of a body that pushes more than once
+pub fn produced_buffer() -> syn::Ident {
+    syn::Ident::new("produced", Span::call_site())
+}
+
+/// The name the collected `Vec` has in the generated operators
+pub fn collected_ident() -> syn::Ident {
+    syn::Ident::new("collected", Span::call_site())
+}
+
+/// `name.sort();`, `name.dedup_by_key(..);` and the like, returning the method and its
+/// arguments
+fn reordering(stmt: &Stmt, name: &str) -> Option<(syn::Ident, Vec<Expr>)> {
+    let Stmt::Expr(Expr::MethodCall(call), Some(_)) = stmt else {
+        return None;
+    };
+    if !matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(name)) {
+        return None;
+    }
+    let method = call.method.to_string();
+    let reorders = method.starts_with("sort") || method.starts_with("dedup") || method == "reverse";
+    // The closures given to sort_by and friends can't refer to anything else
+    let args: Vec<Expr> = call.args.iter().cloned().collect();
+    let captures = args.iter().any(|arg| !matches!(arg, Expr::Closure(_)));
+    (reorders && !captures).then(|| (call.method.clone(), args))
+}
+
+/// `Vec::new()`, `Vec::with_capacity(n)` or `vec![]`
+fn is_empty_vec(expr: &Expr) -> bool {
+    match expr {
+        Expr::Macro(mac) => mac.mac.path.is_ident("vec") && mac.mac.tokens.is_empty(),
+        Expr::Call(call) => match &*call.func {
+            Expr::Path(func) => {
+                let segments: Vec<_> = func.path.segments.iter().map(|s| s.ident.to_string()).collect();
+                matches!(segments.as_slice(), [.., ty, new] if ty == "Vec" && (new == "new" || new == "with_capacity"))
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The value of a `name.push(value)` call
+fn pushed_value(expr: &Expr, name: &str) -> Option<Expr> {
+    match expr {
+        Expr::MethodCall(call)
+            if call.method == "push"
+                && call.args.len() == 1
+                && matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(name)) =>
+        {
+            Some(call.args[0].clone())
+        }
+        _ => None,
+    }
+}
+
+/// Redirects `name.push(..)` onto the iteration's own `produced` buffer
+struct PushRewriter<'n> {
+    name: &'n str,
+    pushed: bool,
+}
+
+impl VisitMut for PushRewriter<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+        if let Some(value) = pushed_value(expr, self.name) {
+            let produced = produced_buffer();
+            *expr = parse_quote! { #produced.push(#value) };
+            self.pushed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<CollectLoop> {
+        CollectLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_single_push_becomes_map() {
+        let collect = detect(r#"{
+            let mut squares = Vec::new();
+            for i in 1..=5 {
+                let square = i * i;
+                squares.push(square);
+            }
+            println!("{:?}", squares);
+        }"#).unwrap();
+
+        assert_eq!(compact(&collect.buffer), "mutsquares");
+        match &collect.produce {
+            Produce::One(value) => assert_eq!(compact(value), "{letsquare=i*i;square}"),
+            Produce::Many(_) => panic!("expected one item per iteration"),
+        }
+        assert_eq!(collect.rest.len(), 1);
+        assert!(!collect.sorted);
+    }
+
+    #[test]
+    fn test_sorting_after_the_loop_is_split_out() {
+        let collect = detect(r#"{
+            let mut names = Vec::new();
+            for name in ["carol", "alice", "bob", "alice"] {
+                names.push(name.to_string());
+            }
+            names.sort();
+            names.dedup();
+            names.sort_by_key(|name| name.len());
+            for name in &names {
+                println!("{}", name);
+            }
+        }"#).unwrap();
+
+        assert!(collect.sorted);
+        let reorder = &collect.reorder;
+        assert_eq!(
+            compact(quote::quote! { #(#reorder)* }),
+            "collected.dedup();collected.sort_by_key(|name|name.len());"
+        );
+        assert_eq!(collect.rest.len(), 1);
+    }
+
+    #[test]
+    fn test_conditional_pushes_produce_many() {
+        let collect = detect(r#"{
+            let mut evens = vec![];
+            for n in 0..10 {
+                if n % 2 == 0 {
+                    evens.push(n);
+                }
+            }
+            println!("{}", evens.len());
+        }"#).unwrap();
+
+        match &collect.produce {
+            Produce::Many(body) => {
+                assert_eq!(compact(body), "{letmutproduced=Vec::new();ifn%2==0{produced.push(n);};produced}")
+            }
+            Produce::One(_) => panic!("expected a variable number of items"),
+        }
+    }
+
+    #[test]
+    fn test_other_buffer_uses_are_rejected() {
+        // The loop reads what it has collected so far
+        assert!(detect("{ let mut v = Vec::new(); for i in 0..3 { if v.is_empty() { v.push(i); } } }").is_none());
+        // Leaving the loop early
+        assert!(detect("{ let mut v = Vec::new(); for i in 0..3 { if i > 1 { break; } v.push(i); } }").is_none());
+        // Not an empty Vec to begin with
+        assert!(detect("{ let mut v = vec![0]; for i in 0..3 { v.push(i); } }").is_none());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+
+use syn::spanned::Spanned;
+
+use crate::source_map::SourceMap;
+
+/// Put the comments of a legacy `main` back in front of the statements they were
+/// written next to, once those statements are carried into a generated module. Tokens
+/// carry no comments, so they are read from the source text of `main`'s block, which its
+/// span still holds. A statement starting its line gets them as lines above it, and one
+/// sharing its line with other code gets them as `/* .. */` just before it. The statements the transformer rewrote have no place in
+/// the module, and their comments are left out, as are doc comments, which are
+/// attributes the tokens already carry.
+pub fn carry_comments(main_block: &syn::Block, module: &str) -> String {
+    let comments = leading_comments(main_block);
+    if comments.is_empty() {
+        return module.to_string();
+    }
+    let source_map = SourceMap::build(&main_block.stmts, module);
+
+    let mut lines: Vec<String> = module.lines().map(str::to_string).collect();
+    // From the end back, so the code before keeps its place
+    let mut mappings = source_map.mappings;
+    mappings.sort_by_key(|mapping| (mapping.generated_line, mapping.generated_column));
+    for mapping in mappings.iter().rev() {
+        let Some(comments) = comments.get(&mapping.legacy_line) else {
+            continue;
+        };
+        let index = mapping.generated_line - 1;
+        let Some(line) = lines.get_mut(index) else {
+            continue;
+        };
+        let Some((at, _)) = line.char_indices().nth(mapping.generated_column) else {
+            continue;
+        };
+        if line[..at].trim().is_empty() {
+            let indent = line[..at].to_string();
+            let carried = comments.iter().map(|comment| format!("{}{}", indent, comment));
+            lines.splice(index..index, carried);
+        } else {
+            let inline: String = comments.iter().map(|comment| format!("{} ", block_comment(comment))).collect();
+            line.insert_str(at, &inline);
+        }
+    }
+    let mut carried = lines.join("\n");
+    if module.ends_with('\n') {
+        carried.push('\n');
+    }
+    carried
+}
+
+/// The comments of a block, by the legacy line of the code they belong to: the comment
+/// lines above it, then the comment ending its first line
+pub fn leading_comments(block: &syn::Block) -> BTreeMap<usize, Vec<String>> {
+    let mut comments = BTreeMap::new();
+    // Spans not parsed from source in this thread have no text
+    let Some(text) = block.span().source_text() else {
+        return comments;
+    };
+    let first_line = block.span().start().line;
+
+    let mut pending: Vec<String> = Vec::new();
+    let mut in_block_comment = false;
+    // A statement with doc comments starts at the first of them
+    let mut doc_start = None;
+    for (index, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if in_block_comment {
+            pending.push(trimmed.to_string());
+            in_block_comment = !trimmed.contains("*/");
+            continue;
+        }
+        if trimmed.is_empty() {
+            continue;
+        }
+        if trimmed.starts_with("///") || trimmed.starts_with("//!") {
+            doc_start.get_or_insert(first_line + index);
+            continue;
+        }
+        if trimmed.starts_with("//") {
+            pending.push(trimmed.to_string());
+            continue;
+        }
+        if trimmed.starts_with("/*") {
+            pending.push(trimmed.to_string());
+            in_block_comment = !trimmed.contains("*/");
+            continue;
+        }
+
+        // A line of code, which may end in a comment of its own
+        if let Some(trailing) = trailing_comment(trimmed) {
+            pending.push(trailing.to_string());
+        }
+        let start = doc_start.take().unwrap_or(first_line + index);
+        if !pending.is_empty() {
+            comments.insert(start, std::mem::take(&mut pending));
+        }
+    }
+    comments
+}
+
+/// A comment as a `/* .. */`, which code can follow on its line
+fn block_comment(comment: &str) -> String {
+    match comment.strip_prefix("//") {
+        Some(text) => format!("/* {} */", text.trim().replace("*/", "* /")),
+        None => comment.to_string(),
+    }
+}
+
+/// The `//` comment ending a line of code, outside any string literal in it
+pub(crate) fn trailing_comment(line: &str) -> Option<&str> {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '/' && previous == '/' && !line[index + 1..].starts_with('/') {
+            return Some(&line[index - 1..]);
+        }
+        previous = c;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY: &str = r#"fn main() {
+    // The first ten squares
+    /* start from zero */
+    let total: u32 = (0..10).map(|n| n * n).sum();
+
+    let url = "http://example.com"; // not a comment inside the string
+    println!("{} {}", total, url);
+}
+"#;
+
+    fn main_block(file: &syn::File) -> &syn::Block {
+        match &file.items[0] {
+            syn::Item::Fn(main_fn) => &main_fn.block,
+            _ => panic!("expected main"),
+        }
+    }
+
+    #[test]
+    fn test_comments_are_found_by_the_line_they_precede() {
+        let file = syn::parse_file(LEGACY).unwrap();
+        let comments = leading_comments(main_block(&file));
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[&4], ["// The first ten squares", "/* start from zero */"]);
+        assert_eq!(comments[&6], ["// not a comment inside the string"]);
+        assert_eq!(trailing_comment("let s = \"a // b\";"), None);
+    }
+
+    #[test]
+    fn test_comments_return_above_carried_statements() {
+        let file = syn::parse_file(LEGACY).unwrap();
+        let module = "pub fn squares() {\n    stage(q!(|_| {\n        let total: u32 = (0..10).map(|n| n * n).sum();\n        let url = \"http://example.com\";\n    }));\n}\n";
+        let carried = carry_comments(main_block(&file), module);
+        assert_eq!(
+            carried,
+            "pub fn squares() {\n    stage(q!(|_| {\n        // The first ten squares\n        /* start from zero */\n        let total: u32 = (0..10).map(|n| n * n).sum();\n        // not a comment inside the string\n        let url = \"http://example.com\";\n    }));\n}\n"
+        );
+        assert!(syn::parse_file(&carried).is_ok());
+    }
+}
@@ -0,0 +1,434 @@
+use proc_macro2::{Literal, Span, TokenStream};
+use quote::{quote, ToTokens};
+use syn::{FnArg, GenericArgument, Ident, Item, Pat, PathArguments, ReturnType, Type};
+
+use crate::error::TransformError;
+use crate::lifecycle;
+use crate::location_tags::{location_kind, type_tag};
+
+/// How many members each cluster of a composed example gets
+const CLUSTER_MEMBERS: usize = 4;
+
+/// How long a composed example waits for every program to signal completion
+const TIMEOUT_SECS: u64 = 60;
+
+/// Generated modules deployed together: one `FlowBuilder` placing the locations of every
+/// module's dataflow function, each on a process or cluster of its own, with a single
+/// external process they share. A pipe streams the lines one module's process prints
+/// into the stdin of another's, as a shell pipe between the legacy programs would.
+#[derive(Debug, Clone)]
+pub struct Composition {
+    modules: Vec<ComposedModule>,
+    /// The modules each pipe streams from and into, by their index
+    pipes: Vec<(usize, usize)>,
+}
+
+/// What the dataflow function of a module in a composition takes and gives back
+#[derive(Debug, Clone)]
+struct ComposedModule {
+    name: Ident,
+    /// The processes and clusters it takes, and external processes as `None`, in order
+    parameters: Vec<Option<Location>>,
+    /// Whether it returns the port it signals completion through
+    signals_completion: bool,
+}
+
+/// A process or cluster a dataflow function takes
+#[derive(Debug, Clone)]
+struct Location {
+    binding: Ident,
+    /// `Process` or `Cluster`
+    kind: Ident,
+    tag: Tag,
+}
+
+/// The tag a location of a composed example is created with
+#[derive(Debug, Clone)]
+enum Tag {
+    /// The implicit `()`
+    Unit,
+    /// A struct of the module, for a function taking a location of that tag
+    Module(Ident),
+    /// A struct of the example, for a function generic over the tag
+    Example(Ident),
+}
+
+impl Composition {
+    pub fn new() -> Self {
+        Self { modules: Vec::new(), pipes: Vec::new() }
+    }
+
+    /// Add the generated module `name`, given as the source of its file. Its dataflow
+    /// function may only take processes, clusters and external processes, and return
+    /// nothing or the port it signals completion through.
+    pub fn with_module(mut self, name: &str, module: &str) -> Result<Self, TransformError> {
+        if self.modules.iter().any(|composed| composed.name == name) {
+            return Err(TransformError::TemplateError(format!("`{}` is already part of the composition", name)));
+        }
+        let file = syn::parse_file(module)?;
+        let dataflow = file
+            .items
+            .iter()
+            .find_map(|item| match item {
+                Item::Fn(func) if func.sig.ident == name => Some(func),
+                _ => None,
+            })
+            .ok_or_else(|| TransformError::TemplateError(format!("The module `{}` has no function named `{}` to compose", name, name)))?;
+        let generic: Vec<&Ident> = dataflow.sig.generics.type_params().map(|param| &param.ident).collect();
+
+        let mut parameters = Vec::new();
+        for input in &dataflow.sig.inputs {
+            let unsupported = || {
+                let parameter = match input {
+                    FnArg::Typed(typed) => typed.pat.to_token_stream().to_string(),
+                    FnArg::Receiver(_) => "self".to_string(),
+                };
+                TransformError::UnsupportedConstruct(format!("`{}` takes `{}`, which a composition can't supply", name, parameter))
+            };
+            let FnArg::Typed(typed) = input else {
+                return Err(unsupported());
+            };
+            let (Pat::Ident(binding), Type::Reference(reference)) = (&*typed.pat, &*typed.ty) else {
+                return Err(unsupported());
+            };
+            if is_shared_external(&reference.elem) {
+                parameters.push(None);
+                continue;
+            }
+            let (Some(kind), Some(tag)) = (location_kind(&reference.elem), type_tag(&reference.elem)) else {
+                return Err(unsupported());
+            };
+            let tag = match tag {
+                None => Tag::Unit,
+                Some(tag) if generic.contains(&&tag) => Tag::Example(example_tag(name, &binding.ident)),
+                Some(tag) => Tag::Module(tag),
+            };
+            parameters.push(Some(Location { binding: binding.ident.clone(), kind, tag }));
+        }
+
+        let signals_completion = match &dataflow.sig.output {
+            ReturnType::Default => false,
+            ReturnType::Type(_, output) if is_completion_port(output) => true,
+            ReturnType::Type(_, output) => {
+                return Err(TransformError::UnsupportedConstruct(format!(
+                    "`{}` returns `{}`, which a composition doesn't connect",
+                    name,
+                    quote!(#output)
+                )));
+            }
+        };
+        self.modules.push(ComposedModule { name: Ident::new(name, Span::call_site()), parameters, signals_completion });
+        Ok(self)
+    }
+
+    /// Stream the lines the first process of module `from` prints into the stdin of the
+    /// first process of module `to`
+    pub fn with_pipe(mut self, from: &str, to: &str) -> Result<Self, TransformError> {
+        let index = |name: &str| {
+            let index = self.modules.iter().position(|module| module.name == name);
+            let module = index.map(|index| &self.modules[index]);
+            match module.and_then(|module| module.first_process()) {
+                Some(_) => Ok(index.unwrap()),
+                None if module.is_some() => Err(TransformError::TemplateError(format!("`{}` runs on no process to pipe through", name))),
+                None => Err(TransformError::TemplateError(format!("No module `{}` in the composition to pipe through", name))),
+            }
+        };
+        let pipe = (index(from)?, index(to)?);
+        if pipe.0 == pipe.1 {
+            return Err(TransformError::TemplateError(format!("`{}` can't pipe into itself", from)));
+        }
+        self.pipes.push(pipe);
+        Ok(self)
+    }
+
+    /// The example deploying every module of the composition into one flow, on
+    /// localhost. It finishes once every module that signals completion has, and runs
+    /// until Ctrl-C when none does.
+    pub fn example(&self) -> Result<String, TransformError> {
+        if self.modules.is_empty() {
+            return Err(TransformError::TemplateError("A composition needs at least one module".to_string()));
+        }
+        let crate_name = Ident::new("hydro_template", Span::call_site());
+        let external = Ident::new("external", Span::call_site());
+        let shares_external = self.modules.iter().any(|module| module.parameters.iter().any(Option::is_none));
+        let has_clusters = self.modules.iter().any(|module| module.locations().any(|location| location.kind == "Cluster"));
+
+        // The tags the example declares, for the functions generic over theirs
+        let tags: Vec<TokenStream> = self
+            .modules
+            .iter()
+            .flat_map(|module| module.locations().map(move |location| (module, location)))
+            .filter_map(|(module, location)| match &location.tag {
+                Tag::Example(tag) => {
+                    let doc = format!(" The tag of the {} `{}` that `{}` takes", location.kind.to_string().to_lowercase(), location.binding, module.name);
+                    Some(quote! {
+                        #[doc = #doc]
+                        pub struct #tag {}
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        let members = Literal::usize_unsuffixed(CLUSTER_MEMBERS);
+        let members = has_clusters.then(|| quote! { const CLUSTER_MEMBERS: usize = #members; });
+
+        let mut create = Vec::new();
+        let mut place = Vec::new();
+        let mut connect = Vec::new();
+        let mut wait = Vec::new();
+        if shares_external {
+            create.push(quote! { let #external = flow.external_process::<()>(); });
+        }
+        for module in &self.modules {
+            let func_name = &module.name;
+            let mut arguments = Vec::new();
+            for parameter in &module.parameters {
+                let Some(location) = parameter else {
+                    arguments.push(quote! { &#external });
+                    continue;
+                };
+                let variable = module.variable(location);
+                let create_location = Ident::new(&location.kind.to_string().to_lowercase(), Span::call_site());
+                let tag = match &location.tag {
+                    Tag::Unit => quote! { () },
+                    Tag::Module(tag) => quote! { #crate_name::#func_name::#tag },
+                    Tag::Example(tag) => quote! { #tag },
+                };
+                create.push(quote! { let #variable = flow.#create_location::<#tag>(); });
+                place.push(match location.kind.to_string().as_str() {
+                    "Cluster" => quote! { .with_cluster(&#variable, vec![localhost.clone(); CLUSTER_MEMBERS]) },
+                    _ => quote! { .with_process(&#variable, localhost.clone()) },
+                });
+                arguments.push(quote! { &#variable });
+            }
+            if module.signals_completion {
+                let done = Ident::new(&format!("{}_done", func_name), Span::call_site());
+                create.push(quote! { let #done = #crate_name::#func_name::#func_name(#(#arguments),*); });
+                connect.push(quote! { let mut #done = nodes.connect_source_bincode(#done).await; });
+                wait.push(quote! { #done.next().await; });
+            } else {
+                create.push(quote! { #crate_name::#func_name::#func_name(#(#arguments),*); });
+            }
+        }
+        if shares_external {
+            place.push(quote! { .with_external(&#external, localhost.clone()) });
+        }
+
+        let pipes: Vec<TokenStream> = self
+            .pipes
+            .iter()
+            .map(|&(from, to)| {
+                let (from, to) = (&self.modules[from], &self.modules[to]);
+                let source = from.variable(from.first_process().unwrap());
+                let sink = to.variable(to.first_process().unwrap());
+                let stdout = Ident::new(&format!("{}_stdout", source), Span::call_site());
+                let stdin = Ident::new(&format!("{}_stdin", sink), Span::call_site());
+                quote! {
+                    let mut #stdout = nodes.get_process(&#source).stdout().await;
+                    let #stdin = nodes.get_process(&#sink).underlying().read().await.stdin();
+                    tokio::spawn(async move {
+                        while let Some(line) = #stdout.recv().await {
+                            if #stdin.send(format!("{}\n", line)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            })
+            .collect();
+
+        let timeout_secs = Literal::u64_unsuffixed(TIMEOUT_SECS);
+        let run = if wait.is_empty() {
+            quote! {
+                tracing::info!(event = "service_running", "Service running, press Ctrl-C to stop");
+                deployment.run_ctrl_c().await.unwrap();
+            }
+        } else {
+            quote! {
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                let finished = timeout(Duration::from_secs(#timeout_secs), async {
+                    #(#wait)*
+                })
+                .await;
+                match finished {
+                    Ok(()) => tracing::info!(event = "stream_completed", "Deployment completed"),
+                    Err(_) => {
+                        tracing::warn!(event = "timeout", timeout_secs = #timeout_secs, "Deployment did not complete in time");
+                        std::process::exit(1);
+                    }
+                }
+            }
+        };
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::StreamExt;
+            use hydro_deploy::Deployment;
+            use hydro_lang::deploy::DeployCrateWrapper;
+            use tokio::time::{timeout, Duration};
+
+            #(#tags)*
+
+            #members
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+                let localhost = deployment.Localhost();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                #(#create)*
+
+                let nodes = flow
+                    #(#place)*
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+                deployment.deploy().await.unwrap();
+
+                #(#connect)*
+                #(#pipes)*
+
+                #run
+            }
+        };
+        let file = syn::parse2(example).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+        Ok(prettyplease::unparse(&file))
+    }
+}
+
+impl Default for Composition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ComposedModule {
+    fn locations(&self) -> impl Iterator<Item = &Location> {
+        self.parameters.iter().flatten()
+    }
+
+    fn first_process(&self) -> Option<&Location> {
+        self.locations().find(|location| location.kind == "Process")
+    }
+
+    /// The variable of the example holding one of the module's locations
+    fn variable(&self, location: &Location) -> Ident {
+        Ident::new(&format!("{}_{}", self.name, location.binding), Span::call_site())
+    }
+}
+
+/// The name of the struct an example declares as the tag of a module's location
+fn example_tag(module_name: &str, binding: &Ident) -> Ident {
+    let camel = |name: &str| -> String {
+        name.split('_')
+            .filter(|word| !word.is_empty())
+            .map(|word| word[..1].to_uppercase() + &word[1..])
+            .collect()
+    };
+    Ident::new(&format!("{}{}", camel(module_name), camel(&binding.to_string())), Span::call_site())
+}
+
+/// The arguments of the last segment of a path type
+fn type_arguments(ty: &Type, name: &str) -> Option<Vec<GenericArgument>> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != name {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::None => Some(Vec::new()),
+        PathArguments::AngleBracketed(arguments) => Some(arguments.args.iter().cloned().collect()),
+        PathArguments::Parenthesized(_) => None,
+    }
+}
+
+fn is_unit(argument: &GenericArgument) -> bool {
+    matches!(argument, GenericArgument::Type(Type::Tuple(unit)) if unit.elems.is_empty())
+}
+
+/// Whether a type is an `ExternalProcess` with the `()` tag, which the modules share
+fn is_shared_external(ty: &Type) -> bool {
+    type_arguments(ty, "ExternalProcess").is_some_and(|arguments| {
+        arguments.iter().all(|argument| matches!(argument, GenericArgument::Lifetime(_)) || is_unit(argument))
+    })
+}
+
+/// Whether a type is the `ExternalBincodeStream<()>` a dataflow signals completion through
+fn is_completion_port(ty: &Type) -> bool {
+    type_arguments(ty, "ExternalBincodeStream").is_some_and(|arguments| matches!(&arguments[..], [unit] if is_unit(unit)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Target, TransformerConfig};
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    const COUNT: &str = "fn main() {\n    for n in 0..10 {\n        println!(\"{}\", n * 2);\n    }\n}\n";
+
+    #[test]
+    fn test_modules_are_deployed_in_one_flow() {
+        let generic = TransformerConfig::new().with_generic_locations(true);
+        let doubles = SynLegacyToHydroTransformer::new().with_config(generic).transform_source(COUNT, "doubles").unwrap();
+        let cluster = TransformerConfig::new().with_target(Target::Cluster);
+        let workers = SynLegacyToHydroTransformer::new().with_config(cluster).transform_source(COUNT, "workers").unwrap();
+        let quiet = "use hydro_lang::*;\n\npub fn quiet<'a>(process: &Process<'a>) {\n    process.source_iter(q!(0..1)).for_each(q!(|_| {}));\n}\n";
+
+        let composition = Composition::new()
+            .with_module("doubles", &doubles.hydro_function)
+            .unwrap()
+            .with_module("workers", &workers.hydro_function)
+            .unwrap()
+            .with_module("quiet", quiet)
+            .unwrap()
+            .with_pipe("doubles", "workers")
+            .unwrap();
+        let example = composition.example().unwrap();
+        assert!(syn::parse_file(&example).is_ok());
+        let compact: String = example.split_whitespace().collect();
+
+        // A generic function runs on a tag the example declares, the others on their own
+        assert!(compact.contains("pubstructDoublesProcess{}"));
+        assert!(compact.contains("letdoubles_process=flow.process::<DoublesProcess>();"));
+        assert!(compact.contains("letworkers_leader=flow.process::<hydro_template::workers::Leader>();"));
+        assert!(compact.contains("letworkers_workers=flow.cluster::<hydro_template::workers::Worker>();"));
+        assert!(compact.contains("letquiet_process=flow.process::<()>();"));
+        // One external process for every module
+        assert_eq!(compact.matches("flow.external_process::<()>()").count(), 1);
+        assert!(compact.contains("letdoubles_done=hydro_template::doubles::doubles(&doubles_process,&external);"));
+        assert!(compact.contains("letworkers_done=hydro_template::workers::workers(&workers_leader,&workers_workers,&external,);"));
+        assert!(compact.contains("hydro_template::quiet::quiet(&quiet_process);"));
+        assert!(compact.contains(".with_cluster(&workers_workers,vec![localhost.clone();CLUSTER_MEMBERS])"));
+        // The pipe feeds what the doubles print to the leader
+        assert!(compact.contains("letmutdoubles_process_stdout=nodes.get_process(&doubles_process).stdout().await;"));
+        assert!(compact.contains("letworkers_leader_stdin=nodes.get_process(&workers_leader).underlying().read().await.stdin();"));
+        // Done once both programs that signal it are
+        assert!(compact.contains("doubles_done.next().await;workers_done.next().await;"));
+    }
+
+    #[test]
+    fn test_modules_that_cant_be_composed_are_refused() {
+        let seeded = "pub fn seeded<'a>(process: &Process<'a>, seed: u64) {}\n";
+        let error = Composition::new().with_module("seeded", seeded).unwrap_err();
+        assert!(error.to_string().contains("`seeded` takes `seed`"));
+        let port = "pub fn port<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBytesPort {}\n";
+        assert!(matches!(Composition::new().with_module("port", port), Err(TransformError::UnsupportedConstruct(_))));
+        assert!(Composition::new().with_module("other", seeded).is_err());
+
+        let quiet = "pub fn quiet<'a>(process: &Process<'a>) {}\n";
+        let composition = Composition::new().with_module("quiet", quiet).unwrap();
+        assert!(composition.clone().with_pipe("quiet", "missing").is_err());
+        assert!(composition.clone().with_pipe("quiet", "quiet").is_err());
+        assert!(composition.clone().with_module("quiet", quiet).is_err());
+        // Without a completion signal, the example runs until stopped
+        assert!(composition.example().unwrap().contains("deployment.run_ctrl_c().await.unwrap();"));
+        assert!(Composition::new().example().is_err());
+    }
+}
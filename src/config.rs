@@ -0,0 +1,360 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use proc_macro2::Literal;
+
+use crate::error::TransformError;
+use crate::target_cfg::TargetCfg;
+
+/// Where the generated example gets the lines a program reads from stdin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdinMode {
+    /// The process's own stdin, for the reading patterns that can be served from it,
+    /// and canned sample input for the others
+    Real,
+    /// Canned sample input for every read, so the example runs without a terminal
+    Mock,
+    /// For a program reading a single line, an external port the example's client task
+    /// sends the terminal's line through, with the program's output coming back through
+    /// another. Other reads are served as with `Real`.
+    External,
+}
+
+/// The kind of location the generated dataflow runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// A single process
+    Process,
+    /// A data-parallel loop spread across a cluster of workers, with a leader process
+    /// handing out the items round-robin. Other programs still run on a single process.
+    Cluster,
+    /// An interactive program split in two: a UI process owning the terminal, which
+    /// reads the line from stdin and prints, and a logic process running the body, with
+    /// typed streams between them. Other programs still run on a single process.
+    Split,
+}
+
+/// How a cluster target spreads a loop's work across its members
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Distribution {
+    /// The leader hands out the items round-robin, or by the hash of their key for a
+    /// loop grouping its items by key
+    RoundRobin,
+    /// Each member folds its shard of the items into a partial aggregate, and the leader
+    /// combines the partials into the result, for a loop reducing its items into one value
+    Reduce,
+}
+
+/// How much of a legacy program is taken apart into dataflow operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lowering {
+    /// The body runs as a whole inside a single map
+    Wrap,
+    /// The pure statements the body starts with run in maps of their own, ahead of the
+    /// one running the statements with effects
+    Stages,
+    /// The built-in translations are tried first, each turning a shape of program into
+    /// the operators it stands for
+    Full,
+}
+
+/// What a transformation does with the `unsafe` blocks of a legacy program, which it
+/// can't check any more than the compiler can
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsafeCode {
+    /// The blocks are quoted into the dataflow as they were written
+    Passthrough,
+    /// A block an operator runs for its effects alone, using no variables, moves out of
+    /// the operator into a function of its own, called by an `inspect` stage ahead of the
+    /// operator. Other blocks are quoted as written.
+    Isolate,
+    /// A program with unsafe code isn't transformed
+    Refuse,
+}
+
+/// The options of a transformation, shared by all backends. Each backend honors the
+/// ones that apply to it, and `Transformer::capabilities` tells what it does with them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformerConfig {
+    /// Whether the legacy statements keep their original spans in the generated code,
+    /// for debugging
+    pub preserve_spans: bool,
+    /// Where the example gets stdin from
+    pub stdin: StdinMode,
+    /// How long the example waits for the dataflow to signal completion
+    pub timeout: Duration,
+    /// What the dataflow runs on
+    pub target: Target,
+    /// How a cluster target spreads the work
+    pub distribution: Distribution,
+    /// How much of the body becomes operators
+    pub lowering: Lowering,
+    /// Whether prints become a stream of output rather than side effects inside the map,
+    /// which is the default
+    pub rewrite_prints: bool,
+    /// Whether `rand` randomness is drawn from an RNG seeded by the example
+    pub seeded_rng: bool,
+    /// Whether the operators of the dataflow count and time the items they handle, and
+    /// report their throughput
+    pub instrument: bool,
+    /// Whether the dataflow function is generic over the tags of its processes and
+    /// clusters, for composing it into larger flows
+    pub generic_locations: bool,
+    /// What becomes of unsafe blocks
+    pub unsafe_code: UnsafeCode,
+    /// The platform the `#[cfg]`s of the program are resolved for, or `None` to carry
+    /// them over as written
+    pub target_cfg: Option<TargetCfg>,
+}
+
+impl TransformerConfig {
+    pub fn new() -> Self {
+        Self {
+            preserve_spans: true,
+            stdin: StdinMode::Real,
+            timeout: Duration::from_secs(60),
+            target: Target::Process,
+            distribution: Distribution::RoundRobin,
+            lowering: Lowering::Full,
+            rewrite_prints: true,
+            seeded_rng: false,
+            instrument: false,
+            generic_locations: false,
+            unsafe_code: UnsafeCode::Passthrough,
+            target_cfg: None,
+        }
+    }
+
+    pub fn with_preserve_spans(mut self, preserve: bool) -> Self {
+        self.preserve_spans = preserve;
+        self
+    }
+
+    pub fn with_stdin(mut self, stdin: StdinMode) -> Self {
+        self.stdin = stdin;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn with_distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    pub fn with_lowering(mut self, lowering: Lowering) -> Self {
+        self.lowering = lowering;
+        self
+    }
+
+    pub fn with_print_rewriting(mut self, rewrite: bool) -> Self {
+        self.rewrite_prints = rewrite;
+        self
+    }
+
+    pub fn with_seeded_rng(mut self, seeded: bool) -> Self {
+        self.seeded_rng = seeded;
+        self
+    }
+
+    pub fn with_instrumentation(mut self, instrument: bool) -> Self {
+        self.instrument = instrument;
+        self
+    }
+
+    pub fn with_generic_locations(mut self, generic: bool) -> Self {
+        self.generic_locations = generic;
+        self
+    }
+
+    pub fn with_unsafe_code(mut self, unsafe_code: UnsafeCode) -> Self {
+        self.unsafe_code = unsafe_code;
+        self
+    }
+
+    pub fn with_target_cfg(mut self, target_cfg: TargetCfg) -> Self {
+        self.target_cfg = Some(target_cfg);
+        self
+    }
+
+    /// The whole seconds of the timeout, as the example's `Duration::from_secs` takes them
+    pub(crate) fn timeout_secs(&self) -> Literal {
+        Literal::u64_unsuffixed(self.timeout.as_secs())
+    }
+}
+
+/// The options in the file `TransformerConfig::to_file_contents` writes, in order
+const KEYS: &[&str] = &["preserve_spans", "stdin", "timeout_secs", "target", "distribution", "lowering", "rewrite_prints", "seeded_rng", "instrument", "generic_locations", "unsafe_code", "target_cfg"];
+
+impl TransformerConfig {
+    /// The config as `key = value` lines, which are valid TOML, so the choices made for
+    /// one program can be saved and reused for others
+    pub fn to_file_contents(&self) -> String {
+        let mut contents = String::new();
+        writeln!(contents, "preserve_spans = {}", self.preserve_spans).unwrap();
+        writeln!(contents, "stdin = \"{}\"", self.stdin.as_str()).unwrap();
+        writeln!(contents, "timeout_secs = {}", self.timeout.as_secs()).unwrap();
+        writeln!(contents, "target = \"{}\"", self.target.as_str()).unwrap();
+        writeln!(contents, "distribution = \"{}\"", self.distribution.as_str()).unwrap();
+        writeln!(contents, "lowering = \"{}\"", self.lowering.as_str()).unwrap();
+        writeln!(contents, "rewrite_prints = {}", self.rewrite_prints).unwrap();
+        writeln!(contents, "seeded_rng = {}", self.seeded_rng).unwrap();
+        writeln!(contents, "instrument = {}", self.instrument).unwrap();
+        writeln!(contents, "generic_locations = {}", self.generic_locations).unwrap();
+        writeln!(contents, "unsafe_code = \"{}\"", self.unsafe_code.as_str()).unwrap();
+        // An empty list carries the cfgs over as written
+        let target_cfg = self.target_cfg.as_ref().map(TargetCfg::to_spec).unwrap_or_default();
+        writeln!(contents, "target_cfg = \"{}\"", target_cfg).unwrap();
+        contents
+    }
+
+    /// Read back a config `to_file_contents` wrote. Blank lines and `#` comments are
+    /// skipped, and options left out keep their defaults.
+    pub fn from_file_contents(contents: &str) -> Result<Self, TransformError> {
+        let mut config = Self::new();
+        for (index, line) in contents.lines().enumerate() {
+            let error = |message: String| TransformError::ConfigError { line: index + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| error(format!("expected `key = value`, found `{}`", line)))?;
+            let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+            let invalid = || error(format!("invalid value `{}` for `{}`", value, key));
+            match key {
+                "preserve_spans" => config.preserve_spans = value.parse().map_err(|_| invalid())?,
+                "stdin" => config.stdin = StdinMode::parse(value).ok_or_else(invalid)?,
+                "timeout_secs" => config.timeout = Duration::from_secs(value.parse().map_err(|_| invalid())?),
+                "target" => config.target = Target::parse(value).ok_or_else(invalid)?,
+                "distribution" => config.distribution = Distribution::parse(value).ok_or_else(invalid)?,
+                "lowering" => config.lowering = Lowering::parse(value).ok_or_else(invalid)?,
+                "rewrite_prints" => config.rewrite_prints = value.parse().map_err(|_| invalid())?,
+                "seeded_rng" => config.seeded_rng = value.parse().map_err(|_| invalid())?,
+                "instrument" => config.instrument = value.parse().map_err(|_| invalid())?,
+                "generic_locations" => config.generic_locations = value.parse().map_err(|_| invalid())?,
+                "unsafe_code" => config.unsafe_code = UnsafeCode::parse(value).ok_or_else(invalid)?,
+                "target_cfg" if value.is_empty() => config.target_cfg = None,
+                "target_cfg" => config.target_cfg = Some(TargetCfg::parse(value).map_err(|_| invalid())?),
+                _ => return Err(error(format!("unknown option `{}`, expected one of {}", key, KEYS.join(", ")))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl StdinMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StdinMode::Real => "real",
+            StdinMode::Mock => "mock",
+            StdinMode::External => "external",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [StdinMode::Real, StdinMode::Mock, StdinMode::External].into_iter().find(|mode| mode.as_str() == value)
+    }
+}
+
+impl Target {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Target::Process => "process",
+            Target::Cluster => "cluster",
+            Target::Split => "split",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [Target::Process, Target::Cluster, Target::Split].into_iter().find(|target| target.as_str() == value)
+    }
+}
+
+impl Distribution {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Distribution::RoundRobin => "round-robin",
+            Distribution::Reduce => "reduce",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [Distribution::RoundRobin, Distribution::Reduce].into_iter().find(|distribution| distribution.as_str() == value)
+    }
+}
+
+impl Lowering {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Lowering::Wrap => "wrap",
+            Lowering::Stages => "stages",
+            Lowering::Full => "full",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [Lowering::Wrap, Lowering::Stages, Lowering::Full].into_iter().find(|lowering| lowering.as_str() == value)
+    }
+}
+
+impl UnsafeCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UnsafeCode::Passthrough => "passthrough",
+            UnsafeCode::Isolate => "isolate",
+            UnsafeCode::Refuse => "refuse",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [UnsafeCode::Passthrough, UnsafeCode::Isolate, UnsafeCode::Refuse].into_iter().find(|mode| mode.as_str() == value)
+    }
+}
+
+impl Default for TransformerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_file_round_trips() {
+        let config = TransformerConfig::new()
+            .with_stdin(StdinMode::Mock)
+            .with_timeout(Duration::from_secs(5))
+            .with_target(Target::Cluster)
+            .with_distribution(Distribution::Reduce)
+            .with_lowering(Lowering::Stages)
+            .with_seeded_rng(true)
+            .with_instrumentation(true)
+            .with_generic_locations(true)
+            .with_unsafe_code(UnsafeCode::Isolate)
+            .with_target_cfg(TargetCfg::parse("unix,target_os=linux").unwrap());
+        let contents = config.to_file_contents();
+        assert!(contents.contains("lowering = \"stages\"\n"));
+        assert!(contents.contains("distribution = \"reduce\"\n"));
+        assert!(contents.contains("unsafe_code = \"isolate\"\n"));
+        assert!(contents.contains("target_cfg = \"unix,target_family=unix,target_os=linux\"\n"));
+        assert_eq!(TransformerConfig::from_file_contents(&contents).unwrap(), config);
+
+        // Options left out keep their defaults
+        let partial = TransformerConfig::from_file_contents("# chosen for fizzbuzz\n\ntarget = \"cluster\"\n").unwrap();
+        assert_eq!(partial, TransformerConfig::new().with_target(Target::Cluster));
+
+        let unknown = TransformerConfig::from_file_contents("lowering = \"full\"\nbackend = \"syn\"\n");
+        assert!(matches!(unknown, Err(TransformError::ConfigError { line: 2, .. })));
+        assert!(TransformerConfig::from_file_contents("lowering = \"most\"").is_err());
+    }
+}
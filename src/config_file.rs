@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+
+use quote::ToTokens;
+use syn::visit::{self, Visit};
+use syn::{parse_quote, Attribute, Expr, GenericArgument, Item, Local, LocalInit, Pat, PathArguments, Stmt, Type, UseTree};
+
+use crate::csv_source::unwrap_tries;
+use crate::json_lines::record_definitions;
+use crate::syntax::{bindings, mentions};
+
+/// The crates a config file is deserialized with
+const FORMATS: [&str; 3] = ["toml", "serde_yaml", "serde_json"];
+
+/// A config file a legacy program loads at startup, deserialized with `toml`,
+/// `serde_yaml` or `serde_json`. Loading it moves to the example, which hands the
+/// deserialized value to the dataflow as its configuration.
+pub struct ConfigFile {
+    /// The binding of the config in the legacy body
+    pub pat: Pat,
+    /// The type it is deserialized into
+    pub ty: Type,
+    /// The statements reading and deserializing the file, as the example runs them,
+    /// with any `?` turned into `.unwrap()`
+    pub load: Vec<Stmt>,
+    /// The definitions of the config's type and the types it uses, made public and
+    /// cloneable and serializable, so the example can load into them and ship the value
+    pub definitions: Vec<Item>,
+    /// The legacy file's `serde` imports, which the definitions need
+    pub definition_imports: Vec<Item>,
+    /// The legacy file's imports the loading statements use
+    pub imports: Vec<Item>,
+}
+
+impl ConfigFile {
+    /// Find `let config: Config = toml::from_str(&fs::read_to_string(path)?)?;`, or the
+    /// same split over a `let text = fs::read_to_string(path)?;` only it reads, among
+    /// the top-level statements of a legacy body. Loading may only read the bindings in
+    /// `available`, which the example has worked out before it. Returns the config and
+    /// the body without the loading statements.
+    pub fn detect(items: &[Item], stmts: &[Stmt], available: &[String]) -> Option<(Self, Vec<Stmt>)> {
+        let (index, local, ty) = stmts.iter().enumerate().find_map(|(index, stmt)| {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+            let mut scan = Deserializes::default();
+            scan.visit_expr(&init.expr);
+            if !scan.found {
+                return None;
+            }
+            let ty = match &local.pat {
+                Pat::Type(typed) => Some((*typed.ty).clone()),
+                _ => scan.ty,
+            }?;
+            Some((index, local, ty))
+        })?;
+
+        // The file is read by the same statement, or by one before it binding the text
+        let mut loading = vec![index];
+        if !reads_file(&Stmt::Local(local.clone())) {
+            let text = (0..index).rev().find(|&earlier| match &stmts[earlier] {
+                Stmt::Local(earlier) => {
+                    let mut names = HashSet::new();
+                    bindings(&earlier.pat, &mut names);
+                    names.iter().any(|name| mentions(&stmts[index..index + 1], name))
+                }
+                _ => false,
+            })?;
+            if !reads_file(&stmts[text]) {
+                return None;
+            }
+            let mut names = HashSet::new();
+            if let Stmt::Local(text) = &stmts[text] {
+                bindings(&text.pat, &mut names);
+            }
+            let elsewhere: Vec<Stmt> = stmts.iter().enumerate().filter(|(other, _)| *other != text && *other != index).map(|(_, stmt)| stmt.clone()).collect();
+            if names.iter().any(|name| mentions(&elsewhere, name)) {
+                return None;
+            }
+            loading.insert(0, text);
+        }
+
+        let load: Vec<Stmt> = loading.iter().map(|&index| unwrap_local_tries(&stmts[index])).collect();
+        let rest: Vec<Stmt> = stmts.iter().enumerate().filter(|(index, _)| !loading.contains(index)).map(|(_, stmt)| stmt.clone()).collect();
+
+        // The example runs the loading before anything else in the body
+        let mut bound = HashSet::new();
+        for stmt in &rest {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut bound);
+            }
+        }
+        if bound.iter().any(|name| !available.contains(name) && mentions(&load, name)) {
+            return None;
+        }
+
+        let pat = match &local.pat {
+            Pat::Type(typed) => (*typed.pat).clone(),
+            pat => pat.clone(),
+        };
+        let (definition_imports, mut definitions): (Vec<Item>, Vec<Item>) =
+            record_definitions(items, &ty).into_iter().partition(|item| matches!(item, Item::Use(_)));
+        definitions.iter_mut().for_each(shippable);
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&load, name))))
+            .cloned()
+            .collect();
+
+        Some((ConfigFile { pat, ty, load, definitions, definition_imports, imports }, rest))
+    }
+}
+
+/// Finds a call deserializing with one of the config [`FORMATS`], and the type it
+/// names with a turbofish, if any
+#[derive(Default)]
+struct Deserializes {
+    found: bool,
+    ty: Option<Type>,
+}
+
+impl<'ast> Visit<'ast> for Deserializes {
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        if let Expr::Path(func) = &*call.func {
+            let segments: Vec<_> = func.path.segments.iter().collect();
+            if let [.., format, function] = &segments[..] {
+                if FORMATS.iter().any(|name| format.ident == name) && function.ident.to_string().starts_with("from_") {
+                    self.found = true;
+                    if let PathArguments::AngleBracketed(args) = &function.arguments {
+                        if let Some(GenericArgument::Type(ty)) = args.args.first() {
+                            self.ty = Some(ty.clone());
+                        }
+                    }
+                }
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+}
+
+/// Whether the statement reads a file
+fn reads_file(stmt: &Stmt) -> bool {
+    let tokens = stmt.to_token_stream().to_string();
+    ["read_to_string", "File :: open", "fs :: read"].iter().any(|read| tokens.contains(read))
+}
+
+fn unwrap_local_tries(stmt: &Stmt) -> Stmt {
+    match stmt {
+        Stmt::Local(Local { attrs, let_token, pat, init: Some(LocalInit { eq_token, expr, diverge: None }), semi_token }) => {
+            Stmt::Local(Local {
+                attrs: attrs.clone(),
+                let_token: *let_token,
+                pat: pat.clone(),
+                init: Some(LocalInit { eq_token: *eq_token, expr: Box::new(unwrap_tries(expr)), diverge: None }),
+                semi_token: *semi_token,
+            })
+        }
+        stmt => stmt.clone(),
+    }
+}
+
+/// The names a `use` brings into scope
+pub(crate) fn leaves(tree: &UseTree) -> Vec<String> {
+    match tree {
+        UseTree::Path(path) => leaves(&path.tree),
+        UseTree::Name(name) => vec![name.ident.to_string()],
+        UseTree::Rename(rename) => vec![rename.rename.to_string()],
+        UseTree::Glob(_) => Vec::new(),
+        UseTree::Group(group) => group.items.iter().flat_map(leaves).collect(),
+    }
+}
+
+/// Make a definition public and derive what shipping its values to the dataflow needs
+fn shippable(item: &mut Item) {
+    let (vis, attrs) = match item {
+        Item::Struct(item) => (&mut item.vis, &mut item.attrs),
+        Item::Enum(item) => (&mut item.vis, &mut item.attrs),
+        _ => return,
+    };
+    *vis = syn::Visibility::Public(Default::default());
+    let derived = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("derive"))
+        .map(|attr| attr.meta.to_token_stream().to_string())
+        .collect::<String>();
+    let missing: Vec<syn::Path> = [("Clone", parse_quote! { Clone }), ("Serialize", parse_quote! { serde::Serialize })]
+        .into_iter()
+        .filter(|(name, _)| !derived.contains(name))
+        .map(|(_, path)| path)
+        .collect();
+    if !missing.is_empty() {
+        let attr: Attribute = parse_quote! { #[derive(#(#missing),*)] };
+        attrs.push(attr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str, available: &[&str]) -> Option<(ConfigFile, Vec<Stmt>)> {
+        let (items, main) = parse_main(source)?;
+        let available: Vec<String> = available.iter().map(|name| name.to_string()).collect();
+        ConfigFile::detect(&items, &main, &available)
+    }
+
+    #[test]
+    fn test_toml_config_is_loaded_by_the_example() {
+        let (config, rest) = detect(r#"
+            use serde::Deserialize;
+            use std::fs;
+
+            #[derive(Deserialize)]
+            struct Config { greeting: String, times: u32 }
+
+            fn main() -> Result<(), Box<dyn std::error::Error>> {
+                let text = fs::read_to_string("config.toml")?;
+                let config: Config = toml::from_str(&text)?;
+                for _ in 0..config.times {
+                    println!("{}", config.greeting);
+                }
+                Ok(())
+            }
+        "#, &[]).unwrap();
+
+        assert_eq!(compact(&config.ty), "Config");
+        assert_eq!(compact(&config.pat), "config");
+        let load: Vec<String> = config.load.iter().map(compact).collect();
+        assert_eq!(load, ["lettext=fs::read_to_string(\"config.toml\").unwrap();", "letconfig:Config=toml::from_str(&text).unwrap();"]);
+        assert_eq!(rest.len(), 2);
+        assert_eq!(config.imports.iter().map(compact).collect::<Vec<_>>(), ["usestd::fs;"]);
+        assert_eq!(config.definition_imports.len(), 1);
+        // Made fit for shipping to the dataflow
+        let definition = compact(&config.definitions[0]);
+        assert!(definition.contains("#[derive(Clone,serde::Serialize)]pubstructConfig"));
+    }
+
+    #[test]
+    fn test_config_path_from_the_command_line() {
+        let source = r#"
+            #[derive(serde::Deserialize, Clone, serde::Serialize)]
+            struct Settings { port: u16 }
+
+            fn main() {
+                let args = Args::parse();
+                let settings = serde_yaml::from_str::<Settings>(&std::fs::read_to_string(&args.config).unwrap()).unwrap();
+                println!("{}", settings.port);
+            }
+        "#;
+        // The command line is worked out by the example beforehand
+        let (config, rest) = detect(source, &["args"]).unwrap();
+        assert_eq!(compact(&config.ty), "Settings");
+        assert_eq!(config.load.len(), 1);
+        assert_eq!(rest.len(), 2);
+        assert!(!compact(&config.definitions[0]).contains("derive(Clone"));
+        // Otherwise the path is only known in the body
+        assert!(detect(source, &[]).is_none());
+    }
+
+    #[test]
+    fn test_parsing_without_a_file_is_not_a_config() {
+        assert!(detect("fn main() { let config: Config = toml::from_str(\"a = 1\").unwrap(); }", &[]).is_none());
+        assert!(detect("fn main() { let n: u32 = std::fs::read_to_string(\"n\").unwrap().parse().unwrap(); }", &[]).is_none());
+    }
+}
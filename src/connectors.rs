@@ -0,0 +1,353 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Pat, Stmt};
+
+use crate::loop_control::first_loop;
+use crate::syntax::{leaves_early, mentions, unwrapped};
+
+/// A legacy program talking to Postgres through `tokio_postgres`: it connects, runs a
+/// query and handles each row it returns in a `for` loop, which may insert rows of its
+/// own with `client.execute(..)`
+pub struct PostgresClient {
+    /// The connection string the legacy program connects with, which the example falls
+    /// back to when it is given none
+    pub connection: Expr,
+    /// The query whose rows are streamed in
+    pub query: Expr,
+    /// The binding of each row and the statements handling it, with each insert
+    /// turned into a push onto `inserts`
+    pub row: Pat,
+    pub body: Vec<Stmt>,
+    /// Whether the body inserts anything
+    pub inserts: bool,
+}
+
+impl PostgresClient {
+    /// Recognize `let (client, connection) = tokio_postgres::connect(..).await?;`, the
+    /// task driving the connection, and a loop over the rows of
+    /// `client.query(sql, &[]).await?`, either directly or through a `let rows = ..;`
+    /// before the loop. The loop may be followed by a final `Ok(())`, and nothing else.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop) = first_loop(stmts)?;
+        if for_loop.label.is_some() {
+            return None;
+        }
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+
+        let prefix = &stmts[..index];
+        let (client, driver, connection) = prefix.iter().find_map(connect_binding)?;
+
+        // Besides connecting, the code before the loop only drives the connection and
+        // runs the query
+        let mut query = None;
+        let mut rows = None;
+        for stmt in prefix {
+            match stmt {
+                _ if connect_binding(stmt).is_some() => {}
+                Stmt::Local(local) => {
+                    let Pat::Ident(name) = &local.pat else {
+                        return None;
+                    };
+                    let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+                    query = Some(query_of(&init.expr, &client)?);
+                    rows = Some(name.ident.clone());
+                }
+                Stmt::Expr(spawn, Some(_)) if is_spawn(spawn) && mentions(std::slice::from_ref(stmt), &driver.to_string()) => {}
+                _ => return None,
+            }
+        }
+
+        // The loop reads the rows of the query, or the binding holding them
+        let iterated = rows_of(&for_loop.expr);
+        let query = match (&rows, iterated) {
+            (Some(rows), Expr::Path(path)) if path.path.is_ident(rows) => query?,
+            (None, iterated) => query_of(iterated, &client)?,
+            _ => return None,
+        };
+
+        // The body runs in the dataflow, where the inserts go out through a connector
+        let mut body = for_loop.body.stmts.clone();
+        let mut inserts = Inserts { client: client.clone(), found: false };
+        body.iter_mut().for_each(|stmt| inserts.visit_stmt_mut(stmt));
+        let mut names = vec![client.to_string(), driver.to_string()];
+        names.extend(rows.iter().map(|rows| rows.to_string()));
+        if leaves_early(&body) || names.iter().any(|name| mentions(&body, name)) {
+            return None;
+        }
+
+        Some(Self { connection, query, row: (*for_loop.pat).clone(), body, inserts: inserts.found })
+    }
+}
+
+/// `let (client, connection) = tokio_postgres::connect(config, NoTls).await?;`, as the
+/// client's name, the connection's and the connection string
+fn connect_binding(stmt: &Stmt) -> Option<(syn::Ident, syn::Ident, Expr)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Pat::Tuple(pair) = &local.pat else {
+        return None;
+    };
+    let names: Vec<_> = pair.elems.iter().collect();
+    let [Pat::Ident(client), Pat::Ident(driver)] = names[..] else {
+        return None;
+    };
+    let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+    let Expr::Call(call) = unwrapped(&init.expr) else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+    let connects = match &segments[..] {
+        [.., krate, connect] => krate == "tokio_postgres" && connect == "connect",
+        [connect] => connect == "connect",
+        [] => false,
+    };
+    if !connects || call.args.len() != 2 {
+        return None;
+    }
+    Some((client.ident.clone(), driver.ident.clone(), call.args[0].clone()))
+}
+
+/// `tokio::spawn(..)`, which legacy programs drive the connection with
+fn is_spawn(expr: &Expr) -> bool {
+    let Expr::Call(call) = expr else {
+        return false;
+    };
+    matches!(&*call.func, Expr::Path(func) if func.path.segments.last().is_some_and(|segment| segment.ident == "spawn"))
+}
+
+/// The rows a loop iterates over, under any `&`, `.iter()` or `.into_iter()`
+fn rows_of(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Reference(reference) => rows_of(&reference.expr),
+        Expr::MethodCall(call) if (call.method == "iter" || call.method == "into_iter") && call.args.is_empty() => rows_of(&call.receiver),
+        expr => expr,
+    }
+}
+
+/// The SQL of `client.query(sql, &[]).await?`, which takes no parameters
+fn query_of(expr: &Expr, client: &syn::Ident) -> Option<Expr> {
+    let Expr::MethodCall(call) = unwrapped(expr) else {
+        return None;
+    };
+    let on_client = matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(client));
+    let args: Vec<&Expr> = call.args.iter().collect();
+    match args[..] {
+        [sql, Expr::Reference(params)] if on_client && call.method == "query" && matches!(&*params.expr, Expr::Array(array) if array.elems.is_empty()) => {
+            Some(sql.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Turns each `client.execute(sql, &[&a, ..]).await?;` into a push of the SQL and owned
+/// parameters onto `inserts`
+struct Inserts {
+    client: syn::Ident,
+    found: bool,
+}
+
+impl VisitMut for Inserts {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Expr(expr, Some(_)) = stmt {
+            if let Expr::MethodCall(call) = unwrapped(expr) {
+                let on_client = matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(&self.client));
+                let args: Vec<&Expr> = call.args.iter().collect();
+                if let ([sql, Expr::Reference(params)], true) = (&args[..], on_client && call.method == "execute") {
+                    if let Expr::Array(params) = &*params.expr {
+                        let params = params.elems.iter().map(|param| match param {
+                            Expr::Reference(param) => &*param.expr,
+                            param => param,
+                        });
+                        *stmt = parse_quote! {
+                            inserts.push((
+                                (#sql).to_string(),
+                                vec![#(Box::new((#params).to_owned()) as Box<dyn tokio_postgres::types::ToSql + Send + Sync>),*],
+                            ));
+                        };
+                        self.found = true;
+                        return;
+                    }
+                }
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+}
+
+/// The definition of `postgres_rows(connection, query)`, a stream of the query's rows,
+/// as Postgres sends them, followed by `None`
+pub fn postgres_source() -> TokenStream {
+    quote! {
+        /// The rows of `query`, streamed from Postgres as the server sends them, followed by `None`
+        pub fn postgres_rows(connection: String, query: String) -> impl futures::Stream<Item = Option<tokio_postgres::Row>> + Unpin {
+            use futures::StreamExt;
+            Box::pin(futures::stream::once(async move {
+                let (client, driver) = tokio_postgres::connect(&connection, tokio_postgres::NoTls).await.unwrap();
+                tokio::spawn(async move {
+                    if let Err(error) = driver.await {
+                        eprintln!("connection error: {}", error);
+                    }
+                });
+                let rows = client.query_raw(query.as_str(), Vec::<String>::new()).await.unwrap();
+                // The client has to outlive the rows it streams
+                rows.map(move |row| {
+                    let _client = &client;
+                    Some(row.unwrap())
+                })
+                .chain(futures::stream::once(async { None }))
+            })
+            .flatten())
+        }
+    }
+}
+
+/// The definition of `PostgresInserts`, which writes the inserts of each row to
+/// Postgres in batches, each in a single transaction
+pub fn postgres_sink() -> TokenStream {
+    quote! {
+        /// An insert, as its SQL and the values of its parameters
+        pub type Insert = (String, Vec<Box<dyn tokio_postgres::types::ToSql + Send + Sync>>);
+
+        /// Writes inserts to Postgres from a task of its own. Whatever arrives while a batch
+        /// is being written makes up the next batch, which is written in one transaction.
+        pub struct PostgresInserts {
+            sender: tokio::sync::mpsc::UnboundedSender<(Vec<Insert>, Option<tokio::sync::oneshot::Sender<()>>)>,
+        }
+
+        impl PostgresInserts {
+            pub fn connect(connection: String) -> Self {
+                let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(Vec<Insert>, Option<tokio::sync::oneshot::Sender<()>>)>();
+                tokio::spawn(async move {
+                    let (mut client, driver) = tokio_postgres::connect(&connection, tokio_postgres::NoTls).await.unwrap();
+                    tokio::spawn(async move {
+                        if let Err(error) = driver.await {
+                            eprintln!("connection error: {}", error);
+                        }
+                    });
+                    while let Some(first) = receiver.recv().await {
+                        let mut batch = vec![first];
+                        while let Ok(next) = receiver.try_recv() {
+                            batch.push(next);
+                        }
+                        let transaction = client.transaction().await.unwrap();
+                        let mut flushed = Vec::new();
+                        for (inserts, flush) in batch {
+                            for (sql, params) in &inserts {
+                                let params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = params.iter().map(|param| param.as_ref() as _).collect();
+                                transaction.execute(sql.as_str(), &params).await.unwrap();
+                            }
+                            flushed.extend(flush);
+                        }
+                        transaction.commit().await.unwrap();
+                        for flush in flushed {
+                            let _ = flush.send(());
+                        }
+                    }
+                });
+                PostgresInserts { sender }
+            }
+
+            /// Queue the inserts of a row. With `flush`, the future resolves once they, and
+            /// everything queued before them, are written.
+            pub fn write(&self, inserts: Vec<Insert>, flush: bool) -> impl std::future::Future<Output = ()> {
+                let (done, written) = if flush {
+                    let (done, written) = tokio::sync::oneshot::channel();
+                    (Some(done), Some(written))
+                } else {
+                    (None, None)
+                };
+                if flush || !inserts.is_empty() {
+                    let _ = self.sender.send((inserts, done));
+                }
+                async move {
+                    if let Some(written) = written {
+                        let _ = written.await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<PostgresClient> {
+        PostgresClient::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_query_loop_with_inserts_is_recognized() {
+        let postgres = detect(r#"{
+            let (client, connection) = tokio_postgres::connect("host=localhost user=postgres", NoTls).await?;
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    eprintln!("connection error: {}", e);
+                }
+            });
+            let rows = client.query("SELECT id, name FROM person", &[]).await?;
+            for row in &rows {
+                let id: i32 = row.get(0);
+                let name: &str = row.get(1);
+                println!("{} {}", id, name);
+                client.execute("INSERT INTO greeted (id, name) VALUES ($1, $2)", &[&id, &name]).await?;
+            }
+            Ok(())
+        }"#).unwrap();
+
+        assert_eq!(compact(&postgres.connection), "\"host=localhostuser=postgres\"");
+        assert_eq!(compact(&postgres.query), "\"SELECTid,nameFROMperson\"");
+        assert_eq!(compact(&postgres.row), "row");
+        assert!(postgres.inserts);
+        // The insert takes owned copies of its parameters
+        assert!(compact(&postgres.body[3]).starts_with("inserts.push(((\"INSERTINTOgreeted(id,name)VALUES($1,$2)\").to_string(),vec![Box::new((id).to_owned())as"));
+    }
+
+    #[test]
+    fn test_query_in_the_loop_header() {
+        let postgres = detect(r#"{
+            let (client, connection) = connect(&config, NoTls).await.unwrap();
+            tokio::spawn(connection);
+            for row in client.query("SELECT name FROM person", &[]).await.unwrap() {
+                let name: String = row.get("name");
+                println!("{}", name);
+            }
+        }"#).unwrap();
+
+        assert_eq!(compact(&postgres.connection), "&config");
+        assert!(!postgres.inserts);
+        assert_eq!(postgres.body.len(), 2);
+    }
+
+    #[test]
+    fn test_clients_that_are_not_connectors() {
+        // The body queries on its own
+        assert!(detect(r#"{
+            let (client, connection) = tokio_postgres::connect("", NoTls).await?;
+            for row in client.query("SELECT id FROM a", &[]).await? {
+                let id: i32 = row.get(0);
+                let more = client.query("SELECT * FROM b WHERE id = $1", &[&id]).await?;
+            }
+        }"#).is_none());
+        // The query takes parameters
+        assert!(detect(r#"{
+            let (client, connection) = tokio_postgres::connect("", NoTls).await?;
+            for row in client.query("SELECT id FROM a WHERE id > $1", &[&limit]).await? {
+                println!("{:?}", row);
+            }
+        }"#).is_none());
+        // Not Postgres
+        assert!(detect("{ let (client, other) = make(); for row in client.query(\"\", &[]) { println!(\"{:?}\", row); } }").is_none());
+    }
+}
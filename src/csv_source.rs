@@ -0,0 +1,244 @@
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, GenericArgument, Pat, Stmt, Type};
+
+use crate::loop_control::first_loop;
+use crate::syntax::{leaves_early, mentions};
+
+/// A legacy program reading a CSV file with `csv::Reader`: the reader is opened, the
+/// header row optionally read, and every row handled in a `for` loop
+pub struct CsvSource {
+    /// The expression opening the reader, with any `?` turned into `.unwrap()`
+    pub open: Expr,
+    pub reader: syn::Ident,
+    /// The binding of the header row and the expression reading it, when the loop
+    /// reads it
+    pub headers: Option<(Pat, Expr)>,
+    /// The type rows are deserialized into, or `None` for `StringRecord`s
+    pub record: Option<Type>,
+    /// How each row leaves the reader: the result for it, bound to `row`, and how the
+    /// record is taken out of the result
+    pub row: Pat,
+    pub unwrap: Unwrap,
+    /// The binding of each record and the statements handling it
+    pub pat: Pat,
+    pub body: Vec<Stmt>,
+}
+
+/// How the loop takes the record out of the result for a row
+pub enum Unwrap {
+    /// With `.unwrap()` or `.expect(..)`, as given, so a bad row panics as before
+    Panics(Box<Expr>),
+    /// With `?`, so the rows end at the first bad one
+    Stops,
+    /// The loop body gets the results themselves
+    Results,
+}
+
+impl CsvSource {
+    /// Recognize `let mut reader = csv::Reader::from_..(..);`, optionally followed by
+    /// `let headers = reader.headers()?.clone();`, and then a loop over
+    /// `reader.deserialize()` or `reader.records()`. The loop may be followed by a
+    /// final `Ok(())`, and nothing else.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop) = first_loop(stmts)?;
+        if for_loop.label.is_some() {
+            return None;
+        }
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+
+        let (reader, open, headers) = match &stmts[..index] {
+            [open] => {
+                let (reader, open) = reader_binding(open)?;
+                (reader, open, None)
+            }
+            [open, headers] => {
+                let (reader, open) = reader_binding(open)?;
+                let Stmt::Local(local) = headers else {
+                    return None;
+                };
+                let read = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+                let read = unwrap_tries(&read.expr);
+                if !mentions(&[Stmt::Expr(read.clone(), None)], &reader.to_string()) {
+                    return None;
+                }
+                (reader, open, Some((local.pat.clone(), read)))
+            }
+            _ => return None,
+        };
+
+        // The rows of the reader, as records of a type or `StringRecord`s
+        let Expr::MethodCall(rows) = &*for_loop.expr else {
+            return None;
+        };
+        if !matches!(&*rows.receiver, Expr::Path(path) if path.path.is_ident(&reader)) || !rows.args.is_empty() {
+            return None;
+        }
+        let turbofish = match rows.turbofish.as_ref().map(|turbofish| turbofish.args.iter().collect::<Vec<_>>()) {
+            None => None,
+            Some(args) => match args[..] {
+                [GenericArgument::Type(record)] => Some(record.clone()),
+                _ => return None,
+            },
+        };
+        let deserializes = match rows.method.to_string().as_str() {
+            "deserialize" => true,
+            "records" if turbofish.is_none() => false,
+            _ => return None,
+        };
+
+        // The body starts by taking the record out of the result, or works on results
+        let row = (*for_loop.pat).clone();
+        let body = &for_loop.body.stmts;
+        let (pat, annotated, unwrap, body) = match body.split_first().and_then(|(first, rest)| record_binding(first, &row).map(|binding| (binding, rest))) {
+            Some(((pat, annotated, unwrap), rest)) => (pat, annotated, unwrap, rest.to_vec()),
+            None => (row.clone(), None, Unwrap::Results, body.clone()),
+        };
+        let record = match (deserializes, turbofish.or(annotated)) {
+            (true, Some(record)) => Some(record),
+            (true, None) => return None,
+            (false, _) => None,
+        };
+
+        // The body runs as a sink, away from the reader
+        if leaves_early(&body) || mentions(&body, &reader.to_string()) {
+            return None;
+        }
+        let headers = headers.filter(|(pat, _)| match pat {
+            Pat::Ident(name) => mentions(&body, &name.ident.to_string()),
+            _ => true,
+        });
+
+        Some(Self { open, reader, headers, record, row, unwrap, pat, body })
+    }
+
+    /// The reader's consuming iterator over the rows
+    pub fn rows_method(&self) -> syn::Ident {
+        let name = if self.record.is_some() { "into_deserialize" } else { "into_records" };
+        syn::Ident::new(name, proc_macro2::Span::call_site())
+    }
+}
+
+/// `let mut reader = csv::Reader::from_..(..)` or a `csv::ReaderBuilder` chain
+fn reader_binding(stmt: &Stmt) -> Option<(syn::Ident, Expr)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Pat::Ident(reader) = &local.pat else {
+        return None;
+    };
+    let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+    let open = unwrap_tries(&init.expr);
+    let tokens = quote::ToTokens::to_token_stream(&open).to_string();
+    if !tokens.contains("csv :: Reader") && !tokens.contains("csv :: ReaderBuilder") {
+        return None;
+    }
+    Some((reader.ident.clone(), open))
+}
+
+/// `let record: T = row.unwrap();`, `.expect(..)` or `row?`, as the record's pattern,
+/// its type if given and how it is unwrapped
+pub(crate) fn record_binding(stmt: &Stmt, row: &Pat) -> Option<(Pat, Option<Type>, Unwrap)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Pat::Ident(row) = row else {
+        return None;
+    };
+    let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+    let is_row = |expr: &Expr| matches!(expr, Expr::Path(path) if path.path.is_ident(&row.ident));
+    let unwrap = match &*init.expr {
+        Expr::Try(unwrap) if is_row(&unwrap.expr) => Unwrap::Stops,
+        Expr::MethodCall(unwrap) if is_row(&unwrap.receiver) && (unwrap.method == "unwrap" || unwrap.method == "expect") => {
+            Unwrap::Panics(init.expr.clone())
+        }
+        _ => return None,
+    };
+    let (pat, record) = match &local.pat {
+        Pat::Type(typed) => ((*typed.pat).clone(), Some((*typed.ty).clone())),
+        pat => (pat.clone(), None),
+    };
+    Some((pat, record, unwrap))
+}
+
+/// The expression with each `?` replaced by `.unwrap()`, for code that runs where the
+/// error can't be returned from `main`
+pub(crate) fn unwrap_tries(expr: &Expr) -> Expr {
+    struct Tries;
+
+    impl VisitMut for Tries {
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            visit_mut::visit_expr_mut(self, expr);
+            if let Expr::Try(tried) = expr {
+                let inner = &tried.expr;
+                *expr = parse_quote! { #inner.unwrap() };
+            }
+        }
+    }
+
+    let mut expr = expr.clone();
+    Tries.visit_expr_mut(&mut expr);
+    expr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<CsvSource> {
+        CsvSource::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_deserializing_reader_is_recognized() {
+        let csv = detect(r#"{
+            let mut reader = csv::Reader::from_path("cities.csv")?;
+            let headers = reader.headers()?.clone();
+            for result in reader.deserialize() {
+                let city: City = result?;
+                println!("{:?}: {} ({})", headers, city.name, city.population);
+            }
+            Ok(())
+        }"#).unwrap();
+
+        assert_eq!(compact(&csv.open), "csv::Reader::from_path(\"cities.csv\").unwrap()");
+        assert_eq!(compact(&csv.headers.as_ref().unwrap().1), "reader.headers().unwrap().clone()");
+        assert_eq!(compact(csv.record.as_ref().unwrap()), "City");
+        assert_eq!(compact(&csv.pat), "city");
+        assert!(matches!(csv.unwrap, Unwrap::Stops));
+        assert_eq!(csv.rows_method(), "into_deserialize");
+    }
+
+    #[test]
+    fn test_string_records_and_unused_headers() {
+        let csv = detect(r#"{
+            let mut rdr = csv::ReaderBuilder::new().delimiter(b';').from_reader(std::io::stdin());
+            let headers = rdr.headers().unwrap().clone();
+            for row in rdr.records() {
+                println!("{:?}", row);
+            }
+        }"#).unwrap();
+
+        // The loop never reads the header row
+        assert!(csv.headers.is_none());
+        assert!(csv.record.is_none() && matches!(csv.unwrap, Unwrap::Results));
+        assert_eq!(compact(&csv.pat), "row");
+        assert_eq!(csv.rows_method(), "into_records");
+    }
+
+    #[test]
+    fn test_readers_that_are_not_sources() {
+        // No type to deserialize into
+        assert!(detect("{ let mut reader = csv::Reader::from_path(\"a.csv\").unwrap(); for row in reader.deserialize() { println!(\"{:?}\", row); } }").is_none());
+        // The body keeps using the reader
+        assert!(detect("{ let mut reader = csv::Reader::from_path(\"a.csv\").unwrap(); for row in reader.records() { println!(\"{:?}\", reader.position()); } }").is_none());
+        // The loop stops early
+        assert!(detect("{ let mut reader = csv::Reader::from_path(\"a.csv\").unwrap(); for row in reader.records() { break; } }").is_none());
+        // Not a CSV reader
+        assert!(detect("{ let mut reader = open(); for row in reader.records() { println!(\"{:?}\", row); } }").is_none());
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashSet;
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::visit::{self, Visit};
+use syn::{Expr, Ident, Pat, Stmt};
+
+use crate::syntax::{bindings, is_compound_assign, place_root};
+
+/// A legacy `for` loop whose iterations don't depend on each other, so they can be
+/// spread across the members of a cluster
+pub struct ParallelLoop {
+    /// Immutable bindings before the loop, which feed the iterated collection and
+    /// compute the parameters
+    pub prefix: Vec<Stmt>,
+    /// The bindings before the loop that the body reads, in the order they are made.
+    /// They are computed once, so every member has to be sent them before its items.
+    pub params: Vec<Ident>,
+    pub pat: Pat,
+    /// The collection the loop iterates over
+    pub items: Expr,
+    pub body: Vec<Stmt>,
+}
+
+impl ParallelLoop {
+    /// Recognize a body that is a single data-parallel `for` loop, optionally preceded
+    /// by `let` bindings used to build the collection or read by the body. The loop body
+    /// must not mutate anything it didn't declare itself, or leave the loop early.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (last, prefix) = stmts.split_last()?;
+        let for_loop = match last {
+            Stmt::Expr(Expr::ForLoop(for_loop), _) if for_loop.label.is_none() => for_loop,
+            _ => return None,
+        };
+
+        let mut outer = HashSet::new();
+        for stmt in prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            // A mutable binding before the loop is state the iterations could share
+            if !bindings(&local.pat, &mut outer) {
+                return None;
+            }
+        }
+
+        // The collection is built on the leader and shipped to the workers, so it must own its items
+        let borrows = match &*for_loop.expr {
+            Expr::Reference(_) => true,
+            Expr::MethodCall(call) => call.method == "iter" || call.method == "iter_mut",
+            _ => false,
+        };
+        if borrows {
+            return None;
+        }
+
+        let mut locals = HashSet::new();
+        bindings(&for_loop.pat, &mut locals);
+        let mut independence = Independence { outer: &outer, locals, read: HashSet::new(), shared: false };
+        independence.visit_block(&for_loop.body);
+        if independence.shared {
+            return None;
+        }
+        let params = prefix
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Local(local) => Some(pattern_idents(&local.pat)),
+                _ => None,
+            })
+            .flatten()
+            .filter(|ident| independence.read.contains(&ident.to_string()))
+            .collect();
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            params,
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            body: for_loop.body.stmts.clone(),
+        })
+    }
+}
+
+/// The identifiers a pattern binds, in order
+fn pattern_idents(pat: &Pat) -> Vec<Ident> {
+    struct Idents(Vec<Ident>);
+
+    impl<'ast> Visit<'ast> for Idents {
+        fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+            self.0.push(pat.ident.clone());
+            visit::visit_pat_ident(self, pat);
+        }
+    }
+
+    let mut idents = Idents(Vec::new());
+    idents.visit_pat(pat);
+    idents.0
+}
+
+/// The definition of `WorkerMessage`, what a leader sends the members of a cluster when
+/// they need the loop's parameters before its items
+pub fn worker_message() -> TokenStream {
+    quote! {
+        /// The parameters computed before the loop, sent to every member first, or an item
+        #[derive(serde::Serialize, serde::Deserialize, Clone)]
+        pub enum WorkerMessage<C, T> {
+            Config(C),
+            Item(T),
+        }
+    }
+}
+
+/// Looks for anything in a loop body that ties one iteration to another
+struct Independence<'o> {
+    outer: &'o HashSet<String>,
+    /// Names declared inside the loop, which each iteration gets its own copy of
+    locals: HashSet<String>,
+    /// The names declared before the loop that the body reads
+    read: HashSet<String>,
+    shared: bool,
+}
+
+impl Independence<'_> {
+    fn check_place(&mut self, place: &Expr) {
+        match place_root(place) {
+            Some(root) if self.locals.contains(&root) => {}
+            // Statics, fields of something returned by a call, ...
+            _ => self.shared = true,
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Independence<'_> {
+    fn visit_local(&mut self, local: &'ast syn::Local) {
+        if let Some(init) = &local.init {
+            self.visit_local_init(init);
+        }
+        bindings(&local.pat, &mut self.locals);
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Assign(assign) => self.check_place(&assign.left),
+            Expr::Binary(binary) if is_compound_assign(&binary.op) => self.check_place(&binary.left),
+            Expr::Reference(reference) if reference.mutability.is_some() => self.check_place(&reference.expr),
+            Expr::Break(_) | Expr::Continue(_) | Expr::Return(_) | Expr::Unsafe(_) => self.shared = true,
+            Expr::Path(path) => {
+                // The bindings before the loop are only made on the leader
+                if let Some(ident) = path.path.get_ident() {
+                    let name = ident.to_string();
+                    if self.outer.contains(&name) && !self.locals.contains(&name) {
+                        self.read.insert(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+        visit::visit_expr(self, expr);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        // Format arguments can refer to the bindings before the loop too
+        let args = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated);
+        if let Ok(args) = args {
+            for arg in &args {
+                self.visit_expr(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+
+    fn detect(body: &str) -> Option<ParallelLoop> {
+        ParallelLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_independent_iterations_are_parallel() {
+        let parallel = detect(r#"{
+            let inputs = vec![1, 2, 3];
+            for n in inputs {
+                let mut squared = n * n;
+                squared += 1;
+                println!("{} -> {}", n, squared);
+            }
+        }"#).unwrap();
+
+        assert_eq!(parallel.prefix.len(), 1);
+        assert!(matches!(parallel.items, Expr::Path(_)));
+        assert_eq!(parallel.body.len(), 3);
+        assert!(parallel.params.is_empty());
+        assert!(detect("{ for i in 0..10 { println!(\"{}\", i * 2); } }").is_some());
+    }
+
+    #[test]
+    fn test_bindings_the_body_reads_are_parameters() {
+        let parallel = detect(r#"{
+            let (offset, scale) = (10, 3);
+            let label = format!("x{}", scale);
+            let inputs = vec![1, 2, 3];
+            for n in inputs {
+                let n = n + offset;
+                println!("{}: {}", label, n * scale);
+            }
+        }"#).unwrap();
+
+        let params: Vec<String> = parallel.params.iter().map(|param| param.to_string()).collect();
+        assert_eq!(params, ["offset", "scale", "label"]);
+
+        // A binding the body shadows before reading it isn't one
+        let shadowed = detect("{ let n = 5; let xs = vec![1]; for x in xs { let n = x; println!(\"{}\", n); } }").unwrap();
+        assert!(shadowed.params.is_empty());
+    }
+
+    #[test]
+    fn test_cross_iteration_state_is_not_parallel() {
+        // An accumulator carried between iterations
+        assert!(detect("{ let mut total = 0; for i in 0..10 { total += i; } }").is_none());
+        // A collection built by the iterations
+        assert!(detect("{ for i in 0..10 { RESULTS[i] = i; } }").is_none());
+        // Early exit depends on the iterations before it
+        assert!(detect("{ for i in 0..10 { if i > 5 { break; } println!(\"{}\", i); } }").is_none());
+        // The prefix bindings are only read, never written
+        assert!(detect("{ let total = 0; let xs = vec![1]; for x in xs { total = x; } }").is_none());
+        // Trailing code after the loop
+        assert!(detect("{ for i in 0..10 { println!(\"{}\", i); } println!(\"done\"); }").is_none());
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use syn::{Expr, FnArg, Item, Pat, Stmt};
+
+/// What an operator does to the stream flowing through it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Source,
+    Operator,
+    Sink,
+}
+
+/// An operator of a generated Hydro function, and the location it runs on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub operator: String,
+    pub location: String,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    /// Whether the stream crosses the network between two locations
+    pub network: bool,
+    /// Whether the edge feeds a cycle, carrying its values to the next tick
+    pub feedback: bool,
+}
+
+/// The topology of a generated Hydro function: its sources, operators and sinks, the
+/// processes and clusters they run on, and the streams between them, as written in the
+/// generated code
+#[derive(Debug, Clone, Default)]
+pub struct DataflowGraph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+/// A format a `DataflowGraph` can be rendered in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+}
+
+impl GraphFormat {
+    /// The extension of a file holding a graph in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::Mermaid => "mmd",
+        }
+    }
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(format!("Unknown graph format `{}`, expected `dot` or `mermaid`", format)),
+        }
+    }
+}
+
+impl DataflowGraph {
+    /// Read the graph off the public functions of a generated module, following each
+    /// chain of operators from the locations the functions take
+    pub fn from_module(code: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = syn::parse_file(code)?;
+        let mut builder = Builder::default();
+        for item in &file.items {
+            if let Item::Fn(func) = item {
+                for input in &func.sig.inputs {
+                    if let FnArg::Typed(param) = input {
+                        let ty = quote::ToTokens::to_token_stream(&param.ty).to_string();
+                        if let Pat::Ident(name) = &*param.pat {
+                            if ["Process", "Cluster", "ExternalProcess"].iter().any(|location| ty.contains(location)) {
+                                let name = name.ident.to_string();
+                                builder.locations.insert(name.clone(), name);
+                            }
+                        }
+                    }
+                }
+                func.block.stmts.iter().for_each(|stmt| builder.stmt(stmt));
+            }
+        }
+        Ok(builder.graph)
+    }
+
+    /// Render the graph in `format`
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+        }
+    }
+
+    /// The graph as Graphviz DOT, with a cluster for each location
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph dataflow {\n    rankdir = TB;\n");
+        for (index, (location, nodes)) in self.by_location().into_iter().enumerate() {
+            writeln!(dot, "    subgraph cluster_{} {{", index).unwrap();
+            writeln!(dot, "        label = \"{}\";", location).unwrap();
+            for node in nodes {
+                let shape = match self.nodes[node].kind {
+                    NodeKind::Source => "invhouse",
+                    NodeKind::Operator => "box",
+                    NodeKind::Sink => "house",
+                };
+                writeln!(dot, "        n{} [label = \"{}\", shape = {}];", node, self.nodes[node].operator, shape).unwrap();
+            }
+            dot.push_str("    }\n");
+        }
+        for edge in &self.edges {
+            let style = match (edge.network, edge.feedback) {
+                (true, _) => " [style = dashed]",
+                (false, true) => " [label = \"next tick\", style = bold]",
+                (false, false) => "",
+            };
+            writeln!(dot, "    n{} -> n{}{};", edge.from, edge.to, style).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as a Mermaid flowchart, with a subgraph for each location
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = String::from("flowchart TD\n");
+        for (location, nodes) in self.by_location() {
+            writeln!(mermaid, "    subgraph {}", location).unwrap();
+            for node in nodes {
+                let operator = &self.nodes[node].operator;
+                match self.nodes[node].kind {
+                    NodeKind::Source => writeln!(mermaid, "        n{}([\"{}\"])", node, operator),
+                    NodeKind::Operator => writeln!(mermaid, "        n{}[\"{}\"]", node, operator),
+                    NodeKind::Sink => writeln!(mermaid, "        n{}[/\"{}\"/]", node, operator),
+                }
+                .unwrap();
+            }
+            mermaid.push_str("    end\n");
+        }
+        for edge in &self.edges {
+            let arrow = match (edge.network, edge.feedback) {
+                (true, _) => "-.->",
+                (false, true) => "-- next tick -->",
+                (false, false) => "-->",
+            };
+            writeln!(mermaid, "    n{} {} n{}", edge.from, arrow, edge.to).unwrap();
+        }
+        mermaid
+    }
+
+    /// The nodes of each location, in the order the locations first appear
+    fn by_location(&self) -> Vec<(&str, Vec<usize>)> {
+        let mut locations: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            match locations.iter_mut().find(|(location, _)| *location == node.location) {
+                Some((_, nodes)) => nodes.push(index),
+                None => locations.push((&node.location, vec![index])),
+            }
+        }
+        locations
+    }
+}
+
+/// Walks a generated function's statements, tracking the streams its bindings hold
+#[derive(Default)]
+struct Builder {
+    graph: DataflowGraph,
+    /// The names of locations, and of ticks, with the location each stands for
+    locations: HashMap<String, String>,
+    /// The node whose output each binding holds
+    streams: HashMap<String, usize>,
+    /// The cycle each completion handle feeds
+    handles: HashMap<String, usize>,
+}
+
+impl Builder {
+    fn node(&mut self, operator: &str, location: &str, kind: NodeKind) -> usize {
+        self.graph.nodes.push(Node { operator: operator.to_string(), location: location.to_string(), kind });
+        self.graph.nodes.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, feedback: bool) {
+        let network = self.graph.nodes[from].location != self.graph.nodes[to].location;
+        self.graph.edges.push(Edge { from, to, network, feedback });
+    }
+
+    /// The location an expression names, as in `process` or `&workers`
+    fn location(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Reference(reference) => self.location(&reference.expr),
+            Expr::Path(path) => self.locations.get(&path.path.get_ident()?.to_string()).cloned(),
+            _ => None,
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Local(local) => {
+                let Some(init) = &local.init else {
+                    return;
+                };
+                let names = bound_names(&local.pat);
+                if let Expr::MethodCall(call) = &*init.expr {
+                    if let Some(location) = self.location(&call.receiver) {
+                        match call.method.to_string().as_str() {
+                            "tick" => {
+                                if let [name] = &names[..] {
+                                    self.locations.insert(name.clone(), location);
+                                }
+                                return;
+                            }
+                            "cycle" | "forward_ref" | "tick_cycle" => {
+                                let cycle = self.node(&call.method.to_string(), &location, NodeKind::Source);
+                                if let [handle, stream] = &names[..] {
+                                    self.handles.insert(handle.clone(), cycle);
+                                    self.streams.insert(stream.clone(), cycle);
+                                }
+                                return;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                // A port and its stream are bound together, with the stream last
+                if let (Some(node), Some(name)) = (self.expr(&init.expr), names.last()) {
+                    self.streams.insert(name.clone(), node);
+                }
+            }
+            Stmt::Expr(expr, _) => {
+                self.expr(expr);
+            }
+            _ => {}
+        }
+    }
+
+    /// Add the operators of an expression, returning the node producing its stream
+    fn expr(&mut self, expr: &Expr) -> Option<usize> {
+        match expr {
+            Expr::Path(path) => self.streams.get(&path.path.get_ident()?.to_string()).copied(),
+            Expr::Reference(reference) => self.expr(&reference.expr),
+            Expr::Paren(paren) => self.expr(&paren.expr),
+            Expr::Tuple(tuple) => {
+                tuple.elems.iter().for_each(|elem| {
+                    self.expr(elem);
+                });
+                None
+            }
+            Expr::MethodCall(call) => self.method_call(call),
+            _ => None,
+        }
+    }
+
+    fn method_call(&mut self, call: &syn::ExprMethodCall) -> Option<usize> {
+        let method = call.method.to_string();
+
+        // Completing a cycle feeds it back around to the next tick
+        if let Expr::Path(path) = &*call.receiver {
+            if let Some(cycle) = path.path.get_ident().and_then(|ident| self.handles.get(&ident.to_string())).copied() {
+                let fed = call.args.first().and_then(|arg| self.expr(arg))?;
+                self.edge(fed, cycle, true);
+                return None;
+            }
+        }
+
+        let node = match self.location(&call.receiver) {
+            Some(location) => {
+                let source = self.node(&method, &location, NodeKind::Source);
+                if method.starts_with("source_external") {
+                    let external = call.args.first().and_then(|arg| self.location(arg)).unwrap_or_else(|| "external".to_string());
+                    let port = self.node("external", &external, NodeKind::Source);
+                    self.edge(port, source, false);
+                }
+                source
+            }
+            None => {
+                let input = self.expr(&call.receiver)?;
+                if method == "clone" {
+                    return Some(input);
+                }
+                let location = self.graph.nodes[input].location.clone();
+                let destination = if is_network(&method) { call.args.first().and_then(|arg| self.location(arg)) } else { None };
+                let kind = if method == "for_each" || method == "dest_sink" || method.ends_with("_external") {
+                    NodeKind::Sink
+                } else {
+                    NodeKind::Operator
+                };
+                let node = self.node(&method, destination.as_deref().unwrap_or(&location), kind);
+                self.edge(input, node, false);
+                if method.ends_with("_external") {
+                    let external = call.args.first().and_then(|arg| self.location(arg)).unwrap_or_else(|| "external".to_string());
+                    let port = self.node("external", &external, NodeKind::Sink);
+                    self.edge(node, port, false);
+                }
+                node
+            }
+        };
+
+        // Streams passed in join this one
+        for arg in &call.args {
+            if let Some(joined) = self.expr(arg) {
+                self.edge(joined, node, false);
+            }
+        }
+        Some(node)
+    }
+}
+
+/// Whether an operator sends its stream to another location, given as its argument
+fn is_network(method: &str) -> bool {
+    ["send_bincode", "send_bytes", "broadcast_bincode", "round_robin_bincode", "demux_bincode", "send_partitioned"]
+        .iter()
+        .any(|network| method.starts_with(network))
+        && !method.ends_with("_external")
+}
+
+/// The names a `let` binds, in order
+fn bound_names(pat: &Pat) -> Vec<String> {
+    match pat {
+        Pat::Ident(name) => vec![name.ident.to_string()],
+        Pat::Type(typed) => bound_names(&typed.pat),
+        Pat::Tuple(tuple) => tuple.elems.iter().flat_map(bound_names).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operators(graph: &DataflowGraph) -> Vec<(&str, &str)> {
+        graph.nodes.iter().map(|node| (node.operator.as_str(), node.location.as_str())).collect()
+    }
+
+    #[test]
+    fn test_cluster_round_trip() {
+        let graph = DataflowGraph::from_module(include_str!("first_ten_cluster.rs")).unwrap();
+
+        assert_eq!(operators(&graph), [
+            ("source_iter", "leader"),
+            ("round_robin_bincode", "workers"),
+            ("map", "workers"),
+            ("inspect", "workers"),
+            ("send_bincode_anonymous", "leader"),
+            ("for_each", "leader"),
+            ("source_iter", "leader"),
+            ("broadcast_bincode", "workers"),
+            ("map", "workers"),
+            ("send_bincode_anonymous", "leader"),
+            ("for_each", "leader"),
+        ]);
+        let network: Vec<(usize, usize)> = graph.edges.iter().filter(|edge| edge.network).map(|edge| (edge.from, edge.to)).collect();
+        assert_eq!(network, [(0, 1), (3, 4), (6, 7), (8, 9)]);
+
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n    subgraph leader\n        n0([\"source_iter\"])\n"));
+        assert!(mermaid.contains("    n0 -.-> n1\n    n1 --> n2\n"));
+    }
+
+    #[test]
+    fn test_external_ports_unions_and_cycles() {
+        let graph = DataflowGraph::from_module(r#"
+            use hydro_lang::*;
+
+            pub fn machine<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let (shutdown_port, shutdown) = process.source_external_bincode(external);
+                let tick = process.tick();
+                let (next_state, state) = tick.cycle::<Optional<State, _, _>>();
+                let step = state.or(tick.optional_first_tick(q!(State::Start))).map(q!(|s| s));
+                next_state.complete_next_tick(step.clone().filter_map(q!(|s| s)));
+                step.all_ticks().union(shutdown).send_bincode_external(external)
+            }
+        "#).unwrap();
+
+        assert_eq!(operators(&graph), [
+            ("source_external_bincode", "process"),
+            ("external", "external"),
+            ("cycle", "process"),
+            ("or", "process"),
+            ("optional_first_tick", "process"),
+            ("map", "process"),
+            ("filter_map", "process"),
+            ("all_ticks", "process"),
+            ("union", "process"),
+            ("send_bincode_external", "process"),
+            ("external", "external"),
+        ]);
+        let feedback: Vec<(usize, usize)> = graph.edges.iter().filter(|edge| edge.feedback).map(|edge| (edge.from, edge.to)).collect();
+        assert_eq!(feedback, [(6, 2)]);
+        assert!(graph.edges.contains(&Edge { from: 0, to: 8, network: false, feedback: false }));
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("    subgraph cluster_1 {\n        label = \"external\";\n        n1 [label = \"external\", shape = invhouse];\n        n10 [label = \"external\", shape = house];\n    }\n"));
+        assert!(dot.contains("    n1 -> n0 [style = dashed];\n"));
+        assert!(dot.contains("    n6 -> n2 [label = \"next tick\", style = bold];\n"));
+    }
+
+    #[test]
+    fn test_graph_formats() {
+        assert_eq!("mermaid".parse::<GraphFormat>(), Ok(GraphFormat::Mermaid));
+        assert_eq!(GraphFormat::Dot.extension(), "dot");
+        assert!("png".parse::<GraphFormat>().is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashSet;
+
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use quote::quote;
+use syn::visit::{self, Visit};
+use syn::{Expr, Pat, Stmt};
+
+/// The methods whose results may borrow from their receiver
+const BORROWS: [&str; 18] = [
+    "iter", "iter_mut", "as_str", "as_slice", "as_ref", "as_bytes", "chars", "bytes", "lines", "split", "split_whitespace", "trim", "get", "first",
+    "last", "keys", "values", "windows",
+];
+
+/// What one statement of a body binds and what it reads or updates
+#[derive(Debug, Clone, Default)]
+struct Statement {
+    /// The names a `let` binds, with whether each is mutable, or `None` for patterns
+    /// other than names and tuples
+    binds: Option<Vec<(syn::Ident, bool)>>,
+    reads: HashSet<String>,
+    /// The names assigned to, borrowed mutably or called methods on
+    updates: HashSet<String>,
+    /// Whether what the statement binds may borrow from something else
+    borrows: bool,
+    /// Whether the statement declares an item, which is scoped to the block it's in
+    item: bool,
+}
+
+/// The def-use graph of the top-level statements of a legacy body: which statement's
+/// binding, or last update, of a variable each statement reads or updates. The body
+/// can be cut into stages wherever the values live across the cut can be handed on
+/// from one stage to the next.
+#[derive(Debug, Clone)]
+pub struct DefUseGraph {
+    statements: Vec<Statement>,
+    /// Each `(definition, use)` pair of statements, ordered by use
+    pub edges: Vec<(usize, usize)>,
+}
+
+impl DefUseGraph {
+    /// Build the graph of a body's statements
+    pub fn of_body(stmts: &[Stmt]) -> Self {
+        let statements: Vec<Statement> = stmts.iter().map(statement).collect();
+
+        let mut edges = Vec::new();
+        for (later, uses) in statements.iter().enumerate() {
+            let mut definitions: Vec<usize> = uses
+                .reads
+                .iter()
+                .chain(&uses.updates)
+                .filter_map(|name| (0..later).rev().find(|&earlier| defines(&statements[earlier], name)))
+                .collect();
+            definitions.sort();
+            definitions.dedup();
+            edges.extend(definitions.into_iter().map(|earlier| (earlier, later)));
+        }
+        Self { statements, edges }
+    }
+
+    /// Whether statement `later` reads or updates a value statement `earlier` defined
+    pub fn depends_on(&self, later: usize, earlier: usize) -> bool {
+        self.edges.contains(&(earlier, later))
+    }
+
+    /// The values bound before statement `cut` that statements from `cut` on use, in the
+    /// order they were bound, or `None` when one of them can't be handed on: it may
+    /// borrow, or is bound by a pattern the graph doesn't follow, or the statements
+    /// before the cut declare an item
+    pub fn live_across(&self, cut: usize) -> Option<Vec<syn::Ident>> {
+        let before = &self.statements[..cut];
+        if before.iter().any(|statement| statement.item || statement.binds.is_none()) {
+            return None;
+        }
+
+        let mut live: Vec<syn::Ident> = Vec::new();
+        for (index, statement) in before.iter().enumerate() {
+            for (name, _) in statement.binds.iter().flatten() {
+                let key = name.to_string();
+                // Only the last binding of a name before the cut is still in scope after it
+                let shadowed = before[index + 1..].iter().any(|later| binds(later, &key));
+                if shadowed || !self.used_from(cut, &key) {
+                    continue;
+                }
+                if statement.borrows {
+                    return None;
+                }
+                live.push(name.clone());
+            }
+        }
+        Some(live)
+    }
+
+    /// What statements `cut..until` take from the statements before them
+    pub fn handoff(&self, cut: usize, until: usize) -> Option<Handoff> {
+        let live = self.live_across(cut)?;
+        let bindings = live
+            .into_iter()
+            .map(|name| {
+                let key = name.to_string();
+                let declared_mut = self.statements[..cut]
+                    .iter()
+                    .rev()
+                    .find_map(|statement| statement.binds.iter().flatten().find(|(bound, _)| *bound == key))
+                    .is_some_and(|(_, mutable)| *mutable);
+                let updated = self.statements[cut..until].iter().any(|statement| statement.updates.contains(&key));
+                (name, declared_mut && updated)
+            })
+            .collect();
+        Some(Handoff { bindings })
+    }
+
+    /// Whether a statement from `cut` on uses the name before binding it again
+    fn used_from(&self, cut: usize, name: &str) -> bool {
+        for statement in &self.statements[cut..] {
+            if statement.reads.contains(name) || statement.updates.contains(name) {
+                return true;
+            }
+            if binds(statement, name) {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// The values one stage of a body hands on to the next
+#[derive(Debug, Clone)]
+pub struct Handoff {
+    /// The names handed on, with whether the next stage updates them
+    pub bindings: Vec<(syn::Ident, bool)>,
+}
+
+impl Handoff {
+    /// The value the stage before the handoff ends with
+    pub fn value(&self) -> TokenStream {
+        let names = self.bindings.iter().map(|(name, _)| name);
+        match &self.bindings[..] {
+            [(name, _)] => quote! { #name },
+            _ => quote! { (#(#names),*) },
+        }
+    }
+
+    /// The pattern the stage after the handoff takes the value with
+    pub fn pattern(&self) -> TokenStream {
+        let names = self.bindings.iter().map(|(name, mutable)| if *mutable { quote! { mut #name } } else { quote! { #name } });
+        match &self.bindings[..] {
+            [] => quote! { _ },
+            [_] => quote! { #(#names)* },
+            _ => quote! { (#(#names),*) },
+        }
+    }
+}
+
+fn binds(statement: &Statement, name: &str) -> bool {
+    statement.binds.iter().flatten().any(|(bound, _)| bound == name)
+}
+
+fn defines(statement: &Statement, name: &str) -> bool {
+    binds(statement, name) || statement.updates.contains(name)
+}
+
+fn statement(stmt: &Stmt) -> Statement {
+    let mut uses = Uses::default();
+    uses.visit_stmt(stmt);
+    let mut statement = Statement { binds: Some(Vec::new()), reads: uses.reads, updates: uses.updates, ..Default::default() };
+    match stmt {
+        Stmt::Local(local) => {
+            statement.binds = pattern_bindings(&local.pat);
+            statement.borrows = borrows(stmt);
+        }
+        Stmt::Item(_) => statement.item = true,
+        _ => {}
+    }
+    statement
+}
+
+/// The names a `let` pattern binds, or `None` for patterns other than names and tuples
+fn pattern_bindings(pat: &Pat) -> Option<Vec<(syn::Ident, bool)>> {
+    match pat {
+        Pat::Ident(binding) if binding.by_ref.is_none() && binding.subpat.is_none() => {
+            Some(vec![(binding.ident.clone(), binding.mutability.is_some())])
+        }
+        Pat::Type(typed) => pattern_bindings(&typed.pat),
+        Pat::Tuple(tuple) => tuple.elems.iter().map(pattern_bindings).collect::<Option<Vec<_>>>().map(|elems| elems.concat()),
+        Pat::Wild(_) => Some(Vec::new()),
+        _ => None,
+    }
+}
+
+/// Whether a statement takes a reference or calls a method whose result may borrow
+pub(crate) fn borrows(stmt: &Stmt) -> bool {
+    #[derive(Default)]
+    struct Borrows {
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Borrows {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::Reference(_) => self.found = true,
+                Expr::MethodCall(call) if BORROWS.contains(&call.method.to_string().as_str()) => self.found = true,
+                _ => {}
+            }
+            visit::visit_expr(self, expr);
+        }
+    }
+
+    let mut borrows = Borrows::default();
+    borrows.visit_stmt(stmt);
+    borrows.found
+}
+
+/// The variables code reads and updates. Names shadowed inside it are counted too,
+/// which only hands on more than is needed.
+#[derive(Default)]
+struct Uses {
+    reads: HashSet<String>,
+    updates: HashSet<String>,
+}
+
+impl Uses {
+    fn update(&mut self, mut expr: &Expr) {
+        loop {
+            match expr {
+                Expr::Field(field) => expr = &field.base,
+                Expr::Index(index) => expr = &index.expr,
+                Expr::Paren(paren) => expr = &paren.expr,
+                Expr::Unary(unary) => expr = &unary.expr,
+                Expr::Path(path) => {
+                    if let Some(name) = path.path.get_ident() {
+                        self.updates.insert(name.to_string());
+                    }
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Macro arguments aren't parsed, so every name in them counts as read, as do the
+    /// names captured inline by format strings
+    fn tokens(&mut self, tokens: TokenStream) {
+        for token in tokens {
+            match token {
+                TokenTree::Ident(ident) => {
+                    self.reads.insert(ident.to_string());
+                }
+                TokenTree::Group(group) if group.delimiter() != Delimiter::None || !group.stream().is_empty() => self.tokens(group.stream()),
+                TokenTree::Literal(literal) => {
+                    if let Ok(syn::Lit::Str(format)) = syn::parse_str::<syn::Lit>(&literal.to_string()) {
+                        for capture in format.value().split('{').skip(1) {
+                            let name: String = capture.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+                            if !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) {
+                                self.reads.insert(name);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Uses {
+    fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+        if let Some(name) = path.path.get_ident() {
+            self.reads.insert(name.to_string());
+        }
+        visit::visit_expr_path(self, path);
+    }
+
+    fn visit_expr_assign(&mut self, assign: &'ast syn::ExprAssign) {
+        self.update(&assign.left);
+        visit::visit_expr_assign(self, assign);
+    }
+
+    fn visit_expr_binary(&mut self, binary: &'ast syn::ExprBinary) {
+        use syn::BinOp::*;
+        if matches!(
+            binary.op,
+            AddAssign(_) | SubAssign(_) | MulAssign(_) | DivAssign(_) | RemAssign(_) | BitXorAssign(_) | BitAndAssign(_) | BitOrAssign(_) | ShlAssign(_) | ShrAssign(_)
+        ) {
+            self.update(&binary.left);
+        }
+        visit::visit_expr_binary(self, binary);
+    }
+
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        // The method may take `&mut self`
+        self.update(&call.receiver);
+        visit::visit_expr_method_call(self, call);
+    }
+
+    fn visit_expr_reference(&mut self, reference: &'ast syn::ExprReference) {
+        if reference.mutability.is_some() {
+            self.update(&reference.expr);
+        }
+        visit::visit_expr_reference(self, reference);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        self.tokens(mac.tokens.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(source: &str) -> Vec<Stmt> {
+        let block: syn::Block = syn::parse_str(source).unwrap();
+        block.stmts
+    }
+
+    #[test]
+    fn test_uses_are_linked_to_their_definitions() {
+        let graph = DefUseGraph::of_body(&body(r#"{
+            let mut total = 0;
+            let values = vec![1, 2, 3];
+            total += values.len();
+            let label = format!("{total} values");
+            println!("{}", label);
+        }"#));
+
+        assert_eq!(graph.edges, [(0, 2), (1, 2), (2, 3), (3, 4)]);
+        assert!(graph.depends_on(3, 2));
+        assert!(!graph.depends_on(3, 0));
+    }
+
+    #[test]
+    fn test_live_values_are_handed_on() {
+        let graph = DefUseGraph::of_body(&body(r#"{
+            let base = 10;
+            let (mut squares, count) = ((0..base).map(|n| n * n).collect::<Vec<u64>>(), base);
+            let base = base * 2;
+            squares.push(base);
+            println!("{:?} {}", squares, count);
+        }"#));
+
+        // `base` is only used up to the statement pushing it
+        assert_eq!(graph.handoff(3, 5).unwrap().pattern().to_string(), "(mut squares , count , base)");
+        assert_eq!(graph.handoff(4, 5).unwrap().value().to_string(), "(squares , count)");
+        assert_eq!(graph.handoff(4, 5).unwrap().pattern().to_string(), "(squares , count)");
+        assert_eq!(graph.handoff(1, 2).unwrap().pattern().to_string(), "base");
+    }
+
+    #[test]
+    fn test_borrows_stay_in_their_stage() {
+        let graph = DefUseGraph::of_body(&body(r#"{
+            let words = vec!["a", "b"];
+            let first = words.first();
+            println!("{:?}", first);
+            println!("{}", words.len());
+        }"#));
+
+        assert!(graph.live_across(1).is_some());
+        assert!(graph.live_across(2).is_none());
+        // Once nothing uses the borrow, the cut is fine again
+        assert_eq!(graph.live_across(3).unwrap(), ["words"]);
+    }
+}
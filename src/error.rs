@@ -0,0 +1,82 @@
+use thiserror::Error;
+
+/// Why a legacy program couldn't be transformed
+#[derive(Debug, Error)]
+pub enum TransformError {
+    /// The legacy source isn't valid Rust
+    #[error("Could not parse the legacy program at line {line}, column {column}: {source}")]
+    ParseError {
+        /// 1-based line of the error in the legacy source
+        line: usize,
+        /// 0-based column of the error in the legacy source
+        column: usize,
+        source: syn::Error,
+    },
+    /// The legacy source has no `fn main`
+    #[error("No main function found in the source file")]
+    NoMainFunction,
+    /// The program has a construct the translation picked for it can't carry over
+    #[error("Unsupported construct: {0}")]
+    UnsupportedConstruct(String),
+    /// Reading the legacy source failed
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// The generated code couldn't be assembled, as when a template doesn't parse
+    #[error("Could not generate the Hydro program: {0}")]
+    TemplateError(String),
+    /// A program would be generated under a name that can't name a module, or that
+    /// another program already has
+    #[error("{0}")]
+    InvalidModuleName(String),
+    /// A saved transformer config couldn't be read back
+    #[error("Invalid transformer config at line {line}: {message}")]
+    ConfigError {
+        /// 1-based line of the config file
+        line: usize,
+        message: String,
+    },
+}
+
+impl From<syn::Error> for TransformError {
+    fn from(source: syn::Error) -> Self {
+        let start = source.span().start();
+        TransformError::ParseError { line: start.line, column: start.column, source }
+    }
+}
+
+/// The errors of the code generators, which are boxed, keep their cause when they are
+/// one of these or an I/O error
+impl From<Box<dyn std::error::Error>> for TransformError {
+    fn from(error: Box<dyn std::error::Error>) -> Self {
+        let error = match error.downcast::<TransformError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => TransformError::IoError(*error),
+            Err(error) => TransformError::TemplateError(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors_are_located() {
+        let error = TransformError::from(syn::parse_file("fn main() {\n    let x = ;\n}").unwrap_err());
+        assert!(matches!(error, TransformError::ParseError { line: 2, column: 12, .. }));
+        assert!(error.to_string().starts_with("Could not parse the legacy program at line 2, column 12: "));
+    }
+
+    #[test]
+    fn test_boxed_errors_keep_their_cause() {
+        let boxed: Box<dyn std::error::Error> = Box::new(TransformError::NoMainFunction);
+        assert!(matches!(TransformError::from(boxed), TransformError::NoMainFunction));
+        let boxed: Box<dyn std::error::Error> = Box::new(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(matches!(TransformError::from(boxed), TransformError::IoError(_)));
+        let boxed: Box<dyn std::error::Error> = "expected `;`".into();
+        assert_eq!(TransformError::from(boxed).to_string(), "Could not generate the Hydro program: expected `;`");
+    }
+}
@@ -0,0 +1,86 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{parse_quote, Expr, GenericArgument, PathArguments, ReturnType, Stmt, Type};
+
+/// The error type of a legacy `main` returning `Result<(), E>`, or `std::io::Error` for
+/// `io::Result<()>`
+pub fn main_error_type(sig: &syn::Signature) -> Option<Type> {
+    let ReturnType::Type(_, ty) = &sig.output else {
+        return None;
+    };
+    let Type::Path(path) = &**ty else {
+        return None;
+    };
+    let segments: Vec<_> = path.path.segments.iter().collect();
+    let result = segments.last()?;
+    if result.ident != "Result" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &result.arguments else {
+        return None;
+    };
+    let types: Vec<&Type> = args
+        .args
+        .iter()
+        .filter_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        })
+        .collect();
+    match types[..] {
+        [_, error] => Some(error.clone()),
+        // io::Result<()> and the like fix the error type
+        [_] if segments.len() > 1 && segments[segments.len() - 2].ident == "io" => {
+            Some(parse_quote! { std::io::Error })
+        }
+        _ => None,
+    }
+}
+
+/// The statements of a fallible `main` and the value it ends with, `Ok(())` unless
+/// the body ends in an expression of its own
+pub fn split_outcome(stmts: &[Stmt]) -> (Vec<Stmt>, Expr) {
+    match stmts.split_last() {
+        Some((Stmt::Expr(outcome, None), stmts)) => (stmts.to_vec(), outcome.clone()),
+        _ => (stmts.to_vec(), parse_quote! { Ok(()) }),
+    }
+}
+
+/// What Rust prints to stderr when `main` returns `Err(error)`
+pub fn error_report() -> TokenStream {
+    quote! { format!("Error: {:?}\n", error) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    fn error_type(signature: &str) -> Option<String> {
+        let sig: syn::Signature = syn::parse_str(signature).unwrap();
+        main_error_type(&sig).map(|ty| ty.to_token_stream().to_string().split_whitespace().collect())
+    }
+
+    #[test]
+    fn test_main_error_types() {
+        assert_eq!(error_type("fn main() -> Result<(), Box<dyn std::error::Error>>").as_deref(), Some("Box<dynstd::error::Error>"));
+        assert_eq!(error_type("fn main() -> std::io::Result<()>").as_deref(), Some("std::io::Error"));
+        assert_eq!(error_type("fn main() -> io::Result<()>").as_deref(), Some("std::io::Error"));
+        assert_eq!(error_type("fn main()"), None);
+        // An alias whose error type isn't spelled out
+        assert_eq!(error_type("fn main() -> anyhow::Result<()>"), None);
+    }
+
+    #[test]
+    fn test_outcome_is_split_from_the_body() {
+        let block: syn::Block = syn::parse_str("{ let n: i32 = s.parse()?; println!(\"{}\", n); Ok(()) }").unwrap();
+        let (stmts, outcome) = split_outcome(&block.stmts);
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(outcome.to_token_stream().to_string(), "Ok (())");
+
+        let block: syn::Block = syn::parse_str("{ run()?; }").unwrap();
+        let (stmts, outcome) = split_outcome(&block.stmts);
+        assert_eq!(stmts.len(), 1);
+        assert_eq!(outcome.to_token_stream().to_string(), "Ok (())");
+    }
+}
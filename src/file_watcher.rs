@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+
+use quote::ToTokens;
+use syn::{parse_quote, Expr, Item, Pat, Stmt};
+
+use crate::config_file::leaves;
+use crate::sqlite_source::unwrap_stmt_tries;
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// The ways a legacy program creates a `notify` watcher sending its events to a channel
+const WATCHERS: [&str; 3] = ["recommended_watcher (", "RecommendedWatcher :: new (", "PollWatcher :: new ("];
+
+/// A legacy program watching the filesystem with `notify`: a watcher sends its events to
+/// a channel, and a loop handles them as they are received. The watcher moves to the
+/// example, which forwards the events to the dataflow through an external port, and the
+/// handling of the events moves to the dataflow.
+pub struct FileWatcher {
+    /// The statements creating the channel and the watcher and starting to watch, as the
+    /// example runs them
+    pub setup: Vec<Stmt>,
+    /// The receiving end of the channel
+    pub receiver: syn::Ident,
+    /// The binding of each event, a `notify::Result<notify::Event>`, and the statements
+    /// handling it
+    pub pat: Pat,
+    pub body: Vec<Stmt>,
+    /// The legacy file's imports the body uses
+    pub imports: Vec<Item>,
+    /// The legacy file's imports the setup uses
+    pub setup_imports: Vec<Item>,
+}
+
+impl FileWatcher {
+    /// Recognize `let (tx, rx) = channel();`, a watcher created with `tx` and told what
+    /// to watch, and a loop over the events, `for res in rx` (or `&rx`, `rx.iter()`) or
+    /// `while let Ok(res) = rx.recv()`. The loop may be followed by a final `Ok(())`, and
+    /// nothing else, and its body may only use the event.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let index = stmts.iter().position(|stmt| matches!(stmt, Stmt::Expr(Expr::ForLoop(_) | Expr::While(_), _)))?;
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+        let prefix = &stmts[..index];
+        let (sender, receiver) = prefix.iter().find_map(channel_binding)?;
+        let setup_tokens: String = prefix.iter().map(|stmt| stmt.to_token_stream().to_string()).collect();
+        let watches = prefix.iter().any(|stmt| {
+            let tokens = stmt.to_token_stream().to_string();
+            WATCHERS.iter().any(|watcher| tokens.contains(watcher)) && mentions(std::slice::from_ref(stmt), &sender.to_string())
+        });
+        if !watches || !setup_tokens.contains(". watch (") {
+            return None;
+        }
+
+        let is_receiver = |expr: &Expr| matches!(expr, Expr::Path(path) if path.path.is_ident(&receiver));
+        let (pat, body) = match &stmts[index] {
+            Stmt::Expr(Expr::ForLoop(for_loop), _) if for_loop.label.is_none() => {
+                let receives = match &*for_loop.expr {
+                    Expr::Reference(reference) => is_receiver(&reference.expr),
+                    Expr::MethodCall(call) => (call.method == "iter" || call.method == "into_iter") && is_receiver(&call.receiver),
+                    expr => is_receiver(expr),
+                };
+                receives.then(|| ((*for_loop.pat).clone(), for_loop.body.stmts.clone()))?
+            }
+            Stmt::Expr(Expr::While(looped), _) if looped.label.is_none() => {
+                let Expr::Let(received) = &*looped.cond else {
+                    return None;
+                };
+                let Pat::TupleStruct(ok) = &*received.pat else {
+                    return None;
+                };
+                let [event] = &ok.elems.iter().collect::<Vec<_>>()[..] else {
+                    return None;
+                };
+                let receives = matches!(&*received.expr, Expr::MethodCall(call) if call.method == "recv" && is_receiver(&call.receiver));
+                (ok.path.is_ident("Ok") && receives).then(|| ((*event).clone(), looped.body.stmts.clone()))?
+            }
+            _ => return None,
+        };
+
+        // The body runs in the dataflow, which only has the events
+        let mut names = HashSet::new();
+        for stmt in prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut names);
+            }
+        }
+        if leaves_early(&body) || names.iter().any(|name| mentions(&body, name)) {
+            return None;
+        }
+
+        let setup: Vec<Stmt> = prefix.iter().map(unwrap_stmt_tries).collect();
+        let used_by = |stmts: &[Stmt]| -> Vec<Item> {
+            items
+                .iter()
+                .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(stmts, name))))
+                .cloned()
+                .collect()
+        };
+        let imports = used_by(&body);
+        let setup_imports = used_by(&setup);
+
+        Some(Self { setup, receiver, pat, body, imports, setup_imports })
+    }
+}
+
+/// `let (tx, rx) = mpsc::channel();` or any other constructor of a channel, as the
+/// names of its ends
+fn channel_binding(stmt: &Stmt) -> Option<(syn::Ident, syn::Ident)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Pat::Tuple(ends) = &local.pat else {
+        return None;
+    };
+    let [Pat::Ident(sender), Pat::Ident(receiver)] = &ends.elems.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let init = local.init.as_ref()?.expr.to_token_stream().to_string();
+    (init.contains("channel") || init.contains("unbounded")).then(|| (sender.ident.clone(), receiver.ident.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str) -> Option<FileWatcher> {
+        let (items, main) = parse_main(source)?;
+        FileWatcher::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_channel_watcher_loop() {
+        let watcher = detect(r#"
+            use notify::{Event, EventKind, RecursiveMode, Result, Watcher};
+            use std::path::Path;
+            use std::sync::mpsc;
+
+            fn main() -> Result<()> {
+                let (tx, rx) = mpsc::channel::<Result<Event>>();
+                let mut watcher = notify::recommended_watcher(tx)?;
+                watcher.watch(Path::new("."), RecursiveMode::Recursive)?;
+                for res in rx {
+                    match res {
+                        Ok(event) if matches!(event.kind, EventKind::Create(_)) => println!("created: {:?}", event.paths),
+                        Ok(_) => {}
+                        Err(e) => println!("watch error: {:?}", e),
+                    }
+                }
+                Ok(())
+            }
+        "#).unwrap();
+
+        assert_eq!(watcher.receiver, "rx");
+        assert_eq!(compact(&watcher.pat), "res");
+        // The setup can't return its errors from the example
+        assert_eq!(compact(&watcher.setup[1]), "letmutwatcher=notify::recommended_watcher(tx).unwrap();");
+        assert_eq!(watcher.imports.len(), 1);
+        assert_eq!(watcher.setup_imports.len(), 3);
+    }
+
+    #[test]
+    fn test_receive_loop() {
+        let watcher = detect(r#"
+            fn main() {
+                let (sender, receiver) = crossbeam_channel::unbounded();
+                let mut watcher = RecommendedWatcher::new(sender, Config::default()).unwrap();
+                watcher.watch("/tmp".as_ref(), RecursiveMode::NonRecursive).unwrap();
+                while let Ok(event) = receiver.recv() {
+                    println!("{:?}", event);
+                }
+            }
+        "#).unwrap();
+
+        assert_eq!(compact(&watcher.pat), "event");
+        assert_eq!(watcher.body.len(), 1);
+    }
+
+    #[test]
+    fn test_loops_that_are_not_watchers() {
+        // Nothing is watched
+        assert!(detect(r#"
+            fn main() {
+                let (tx, rx) = mpsc::channel();
+                let watcher = notify::recommended_watcher(tx).unwrap();
+                for res in rx { println!("{:?}", res); }
+            }
+        "#).is_none());
+        // The body uses the watcher
+        assert!(detect(r#"
+            fn main() {
+                let (tx, rx) = mpsc::channel();
+                let mut watcher = notify::recommended_watcher(tx).unwrap();
+                watcher.watch(Path::new("."), RecursiveMode::Recursive).unwrap();
+                for res in rx { watcher.unwatch(Path::new(".")).unwrap(); }
+            }
+        "#).is_none());
+    }
+}
@@ -13,26 +13,46 @@ pub fn first_ten_cluster<'a>(leader: &Process<'a, Leader>, workers: &Cluster<'a,
         .for_each(q!(|n| println!("{}", n)));
 }
 
+/// Like `first_ten_cluster`, but every worker gets every number, so the leader hears
+/// back once per worker for each of them
+pub fn first_ten_broadcast<'a>(leader: &Process<'a, Leader>, workers: &Cluster<'a, Worker>) {
+    leader
+        .source_iter(q!(0..10)) // : Stream<i32, Process<Leader>, ...>
+        .broadcast_bincode(workers) // : Stream<i32, Cluster<Worker>, ...>
+        .map(q!(|n| n * 2)) // : Stream<i32, Cluster<Worker>, ...>
+        .send_bincode_anonymous(leader) // : Stream<i32, Process<Leader>, ...>
+        .for_each(q!(|n| println!("{}", n)));
+}
+
 #[cfg(test)]
 mod tests {
     use futures::StreamExt;
     use hydro_deploy::Deployment;
     use hydro_lang::deploy::DeployCrateWrapper;
+    use hydro_lang::{Cluster, Process};
     use tokio_stream::wrappers::UnboundedReceiverStream;
 
-    #[tokio::test]
-    async fn first_ten_cluster() {
+    use super::{Leader, Worker};
+
+    /// Deploy `flow` on a leader and `members` workers, and collect the first `lines`
+    /// lines the leader prints, sorted. Neither flow depends on the number of workers,
+    /// which is only chosen here.
+    async fn leader_lines(
+        flow_fn: impl for<'a> FnOnce(&Process<'a, Leader>, &Cluster<'a, Worker>),
+        members: usize,
+        lines: usize,
+    ) -> Vec<String> {
         let mut deployment = Deployment::new();
         let localhost = deployment.Localhost();
 
         let flow = hydro_lang::FlowBuilder::new();
         let leader = flow.process();
         let workers = flow.cluster();
-        super::first_ten_cluster(&leader, &workers);
+        flow_fn(&leader, &workers);
 
         let nodes = flow
             .with_process(&leader, localhost.clone())
-            .with_cluster(&workers, vec![localhost.clone(); 4])
+            .with_cluster(&workers, vec![localhost.clone(); members])
             .deploy(&mut deployment);
 
         deployment.deploy().await.unwrap();
@@ -42,14 +62,31 @@ mod tests {
         deployment.start().await.unwrap();
 
         let mut out = UnboundedReceiverStream::new(leader_stdout)
-            .take(10)
+            .take(lines)
             .collect::<Vec<_>>()
             .await;
         out.sort();
+        out
+    }
 
+    #[tokio::test]
+    async fn first_ten_cluster() {
         let mut expected = vec!["0", "2", "4", "6", "8", "10", "12", "14", "16", "18"];
         expected.sort();
 
-        assert_eq!(out, expected);
+        for members in [1, 4] {
+            assert_eq!(leader_lines(super::first_ten_cluster, members, 10).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn first_ten_broadcast() {
+        let members = 3;
+        let mut expected: Vec<String> = (0..10)
+            .flat_map(|n| std::iter::repeat_n((n * 2).to_string(), members))
+            .collect();
+        expected.sort();
+
+        assert_eq!(leader_lines(super::first_ten_broadcast, members, 10 * members).await, expected);
     }
 }
@@ -0,0 +1,195 @@
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr};
+
+use crate::comments::{carry_comments, trailing_comment};
+use crate::error::TransformError;
+
+/// What a `q!` is laid out as while the module is formatted: a call, whose arguments
+/// prettyplease formats like any other code, unlike the tokens of a macro
+const QUOTE_CALL: &str = "__quoted_by_q__";
+
+/// Lay out a generated module with prettyplease, the code quoted by its `q!`s included.
+/// prettyplease leaves the tokens of macros as they come, which strings a quoted closure
+/// out over a few long lines; each `q!` holding an expression is formatted as a call
+/// instead, and turned back into a `q!` after. The layout only depends on the code, so a
+/// formatted module formats to itself. Tokens hold no comments, so the module comes out
+/// without any; `carry_comments` puts back those of the legacy program.
+pub fn format_module(module: &str) -> Result<String, TransformError> {
+    let file = syn::parse_file(module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let mut quoted = file.clone();
+    QuotesAsCalls.visit_file_mut(&mut quoted);
+    let formatted = prettyplease::unparse(&quoted).replace(&format!("{}(", QUOTE_CALL), "q!(");
+
+    // Dedenting the lines of a string literal running over them would change the string
+    let overflowed = overflow_quotes(&formatted);
+    let same_literals = syn::parse_file(&overflowed)
+        .map(|overflowed| literals(quote::quote!(#overflowed)) == literals(quote::quote!(#file)))
+        .unwrap_or(false);
+    Ok(if same_literals { overflowed } else { formatted })
+}
+
+/// Lay out a module wrapping the body of a legacy program as written, putting back the
+/// comments of its `main`. A module that doesn't parse is left as it is.
+pub fn format_wrapped_module(file: &syn::File, module: &str) -> String {
+    let main_block = file.items.iter().find_map(|item| match item {
+        syn::Item::Fn(func) if func.sig.ident == "main" => Some(&func.block),
+        _ => None,
+    });
+    match (format_module(module), main_block) {
+        (Ok(formatted), Some(block)) => carry_comments(block, &formatted),
+        (Ok(formatted), None) => formatted,
+        (Err(_), _) => module.to_string(),
+    }
+}
+
+/// Put a `q!` that is the only argument of a call back on the line of the call, as
+/// rustfmt does, where prettyplease breaks it out onto lines of its own:
+/// `.map(\n    q!(|x| {\n ..\n    }),\n)` becomes `.map(q!(|x| {\n ..\n}))`
+fn overflow_quotes(formatted: &str) -> String {
+    let mut lines: Vec<String> = formatted.lines().map(str::to_string).collect();
+    let mut index = 0;
+    while index + 2 < lines.len() {
+        if let Some(close) = lone_quote_argument(&lines, index) {
+            let mut argument: Vec<String> = lines.drain(index + 1..close).collect();
+            let closing = lines.remove(index + 1);
+            let last = argument.len() - 1;
+            argument[last] = format!("{}{}", argument[last].trim_end_matches(','), closing.trim_start());
+            let first = argument.remove(0);
+            lines[index].push_str(first.trim_start());
+            for (offset, line) in argument.into_iter().enumerate() {
+                let line = line.strip_prefix("    ").map(str::to_string).unwrap_or(line);
+                lines.insert(index + 1 + offset, line);
+            }
+        }
+        index += 1;
+    }
+    let mut overflowed = lines.join("\n");
+    if formatted.ends_with('\n') {
+        overflowed.push('\n');
+    }
+    overflowed
+}
+
+/// Where the call opened on a line holds a single `q!` argument, the line closing the call
+fn lone_quote_argument(lines: &[String], open: usize) -> Option<usize> {
+    if !lines[open].ends_with('(') {
+        return None;
+    }
+    let indent = indentation(&lines[open]);
+    let argument_indent = indent + 4;
+    let first = &lines[open + 1];
+    if indentation(first) != argument_indent || !first.trim_start().starts_with("q!(") {
+        return None;
+    }
+    for (offset, line) in lines[open + 2..].iter().enumerate() {
+        let line_indent = indentation(line);
+        if line_indent == indent && line.trim_start().starts_with(')') {
+            return Some(open + 2 + offset);
+        }
+        // Anything but the closing of the `q!` at its indentation is a further argument
+        if line_indent < argument_indent || (line_indent == argument_indent && !line.trim_start().starts_with(['}', ')', ']'])) {
+            return None;
+        }
+    }
+    None
+}
+
+/// Whether a module has comments other than doc comments, which are attributes
+pub fn has_comments(module: &str) -> bool {
+    module.lines().map(str::trim).any(|line| {
+        let doc = line.starts_with("///") || line.starts_with("//!");
+        !doc && (line.starts_with("/*") || trailing_comment(line).is_some())
+    })
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+fn literals(tokens: proc_macro2::TokenStream) -> Vec<String> {
+    tokens
+        .into_iter()
+        .flat_map(|token| match token {
+            proc_macro2::TokenTree::Literal(literal) => vec![literal.to_string()],
+            proc_macro2::TokenTree::Group(group) => literals(group.stream()),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// The expression a `q!` holds, which may be followed by a comma
+fn quoted_expr(input: syn::parse::ParseStream) -> syn::Result<Expr> {
+    let expr = input.parse()?;
+    input.parse::<Option<syn::Token![,]>>()?;
+    Ok(expr)
+}
+
+/// Turns each `q!` holding an expression into a call of `QUOTE_CALL`
+struct QuotesAsCalls;
+
+impl VisitMut for QuotesAsCalls {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(mac) = expr {
+            if mac.mac.path.is_ident("q") {
+                if let Ok(mut quoted) = mac.mac.parse_body_with(quoted_expr) {
+                    self.visit_expr_mut(&mut quoted);
+                    let call = syn::Ident::new(QUOTE_CALL, proc_macro2::Span::call_site());
+                    *expr = parse_quote! { #call(#quoted) };
+                    return;
+                }
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quoted_code_is_laid_out() {
+        let mangled = r#"use hydro_lang::*;
+pub fn mixed<'a>(process: &Process<'a>) {
+    process
+        .source_iter(q!(std::iter::once(())))
+        .map(
+            q!(
+                | _ | { print!("Processing"); for i in 1
+                ..= 3 { eprint!("."); } println!("\nDone!"); }
+            ),
+        )
+        .for_each(q!(| _ | {}));
+}
+"#;
+        let formatted = format_module(mangled).unwrap();
+        assert_eq!(
+            formatted,
+            r#"use hydro_lang::*;
+pub fn mixed<'a>(process: &Process<'a>) {
+    process
+        .source_iter(q!(std::iter::once(())))
+        .map(q!(|_| {
+            print!("Processing");
+            for i in 1..=3 {
+                eprint!(".");
+            }
+            println!("\nDone!");
+        }))
+        .for_each(q!(|_| {}));
+}
+"#
+        );
+        assert_eq!(format_module(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_quotes_sharing_a_call_stay_on_their_lines() {
+        let module = "pub fn sums<'a>(process: &Process<'a>) {\n    process.source_iter(q!(0..10)).fold(q!(|| 0), q!(|sum, n| { *sum += n; let doubled = n * 2; *sum += doubled; }));\n}\n";
+        let formatted = format_module(module).unwrap();
+        assert!(formatted.contains("        .fold(\n            q!(|| 0),\n            q!(|sum, n| {\n"));
+        let text = "pub fn lines() {\n    stage(q!(|_| {\n        let text = \"a\nb\";\n        println!(\"{}\", text);\n    }));\n}\n";
+        // Moving the string's second line would change the string
+        assert!(format_module(text).unwrap().contains("\nb\""));
+    }
+}
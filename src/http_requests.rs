@@ -0,0 +1,196 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::visit::{self, Visit};
+use syn::{Expr, Pat, Stmt};
+
+use crate::call_loop::CallLoop;
+
+/// Methods of `reqwest::blocking::Response` a [`fetched_response`] doesn't offer
+const UNSUPPORTED_METHODS: [&str; 10] = [
+    "error_for_status",
+    "error_for_status_ref",
+    "copy_to",
+    "read",
+    "read_to_end",
+    "read_to_string",
+    "remote_addr",
+    "cookies",
+    "extensions",
+    "text_with_charset",
+];
+
+/// A legacy loop fetching a URL for each item with `reqwest::blocking::get`. The
+/// request moves to an async stage of the dataflow, and the loop body handles its
+/// result downstream, in the order of the items.
+pub struct RequestLoop {
+    /// The collection the loop iterates over, as a block that builds it
+    pub source: Expr,
+    /// Whether the loop borrowed the items, so its pattern is matched against a
+    /// reference to each
+    pub by_ref: bool,
+    pub pat: Pat,
+    /// The URL the loop fetches for an item
+    pub url: Expr,
+    /// The loop body, with the request replaced by its result, `fetched`
+    pub body: Vec<Stmt>,
+}
+
+impl RequestLoop {
+    /// Recognize a `for` loop whose first statement makes the loop's only
+    /// `reqwest::blocking::get(url)` call, and handles its result in a `match`, an
+    /// `if let` or a `let`. The loop is otherwise shaped as `CallLoop::detect` takes,
+    /// and the body only uses the response as a `FetchedResponse` allows.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let found = CallLoop::detect(stmts, request_url, "fetched")?;
+        let mut methods = Methods::default();
+        found.body.iter().for_each(|stmt| methods.visit_stmt(stmt));
+        if methods.names.iter().any(|name| UNSUPPORTED_METHODS.contains(&name.as_str())) {
+            return None;
+        }
+
+        Some(Self { source: found.source, by_ref: found.by_ref, pat: found.pat, url: found.call, body: found.body })
+    }
+}
+
+/// The URL of a `reqwest::blocking::get(url)` call
+fn request_url(expr: &Expr) -> Option<Expr> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+    let [.., blocking, get] = &segments[..] else {
+        return None;
+    };
+    let [url] = &call.args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    (blocking == "blocking" && get == "get").then(|| (*url).clone())
+}
+
+/// The names of the methods a body calls
+#[derive(Default)]
+struct Methods {
+    names: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for Methods {
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        self.names.push(call.method.to_string());
+        visit::visit_expr_method_call(self, call);
+    }
+}
+
+/// The definition of `FetchedResponse`, a response whose body the async stage has read,
+/// which the loop body uses as it used a `reqwest::blocking::Response`
+pub fn fetched_response() -> TokenStream {
+    quote! {
+        /// A response with its body read, offering the accessors of a blocking response
+        pub struct FetchedResponse {
+            status: reqwest::StatusCode,
+            version: reqwest::Version,
+            url: reqwest::Url,
+            headers: reqwest::header::HeaderMap,
+            body: bytes::Bytes,
+        }
+
+        impl FetchedResponse {
+            pub async fn read(response: reqwest::Response) -> reqwest::Result<Self> {
+                let status = response.status();
+                let version = response.version();
+                let url = response.url().clone();
+                let headers = response.headers().clone();
+                let body = response.bytes().await?;
+                Ok(FetchedResponse { status, version, url, headers, body })
+            }
+
+            pub fn status(&self) -> reqwest::StatusCode {
+                self.status
+            }
+
+            pub fn version(&self) -> reqwest::Version {
+                self.version
+            }
+
+            pub fn url(&self) -> &reqwest::Url {
+                &self.url
+            }
+
+            pub fn headers(&self) -> &reqwest::header::HeaderMap {
+                &self.headers
+            }
+
+            pub fn content_length(&self) -> Option<u64> {
+                Some(self.body.len() as u64)
+            }
+
+            pub fn text(self) -> reqwest::Result<String> {
+                Ok(String::from_utf8_lossy(&self.body).into_owned())
+            }
+
+            pub fn bytes(self) -> reqwest::Result<bytes::Bytes> {
+                Ok(self.body)
+            }
+
+            pub fn json<T: serde::de::DeserializeOwned>(self) -> serde_json::Result<T> {
+                serde_json::from_slice(&self.body)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<RequestLoop> {
+        RequestLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_match_on_request_keeps_its_arms() {
+        let requests = detect(r#"{
+            let urls = vec!["https://example.com", "https://example.org"];
+            for url in &urls {
+                match reqwest::blocking::get(*url) {
+                    Ok(response) => println!("{}: {}", url, response.status()),
+                    Err(e) => eprintln!("{}: {}", url, e),
+                }
+            }
+        }"#).unwrap();
+
+        assert!(requests.by_ref);
+        assert_eq!(compact(&requests.source), "{leturls=vec![\"https://example.com\",\"https://example.org\"];urls}");
+        assert_eq!(compact(&requests.url), "*url");
+        assert!(compact(&requests.body[0]).starts_with("matchfetched{Ok(response)=>"));
+    }
+
+    #[test]
+    fn test_unwrapped_request_in_a_let() {
+        let requests = detect(r#"{
+            for id in 1..=3 {
+                let text = reqwest::blocking::get(format!("https://example.com/{}", id)).unwrap().text().unwrap();
+                println!("{}", text.len());
+            }
+        }"#).unwrap();
+
+        assert!(!requests.by_ref);
+        assert_eq!(compact(&requests.url), "format!(\"https://example.com/{}\",id)");
+        assert_eq!(compact(&requests.body[0]), "lettext=fetched.unwrap().text().unwrap();");
+    }
+
+    #[test]
+    fn test_loops_that_are_not_request_pipelines() {
+        // Two requests per item
+        assert!(detect("{ for u in us { let a = reqwest::blocking::get(u); let b = reqwest::blocking::get(u); } }").is_none());
+        // The error ends the program
+        assert!(detect("{ for u in us { let r = reqwest::blocking::get(u)?; println!(\"{}\", r.status()); } }").is_none());
+        // The body reads the response as a stream
+        assert!(detect("{ for u in us { let mut r = reqwest::blocking::get(u).unwrap(); r.copy_to(&mut out).unwrap(); } }").is_none());
+        // The request comes later in the body
+        assert!(detect("{ for u in us { println!(\"{}\", u); let r = reqwest::blocking::get(u); } }").is_none());
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+
+use syn::{Expr, FnArg, Item, ItemFn, LitStr, Pat, PathArguments, ReturnType, Stmt, Type, UseTree};
+
+use crate::config_file::leaves;
+use crate::syntax::{bindings, mentions};
+
+/// The `axum::routing` functions a route may be registered with
+const METHODS: [&str; 4] = ["get", "post", "put", "delete"];
+
+/// A tiny `axum` web service: a `Router` whose routes are served by simple handler
+/// functions, which return text and take at most a path parameter. The handlers move
+/// to the dataflow, which answers a stream of requests with a stream of responses, and
+/// the example serves HTTP in front of it.
+pub struct HttpService {
+    /// The routes, in the order they were registered, which identifies them in the
+    /// requests
+    pub routes: Vec<Route>,
+    /// The handlers, no longer `async`, and the functions they call
+    pub functions: Vec<ItemFn>,
+    /// The legacy file's imports the functions use
+    pub imports: Vec<Item>,
+    /// The binding of the router in the legacy body
+    pub app: syn::Ident,
+    /// The statements after the router, binding the listener and serving it, as the
+    /// example runs them
+    pub serve: Vec<Stmt>,
+}
+
+pub struct Route {
+    pub path: LitStr,
+    /// The routing function, `get` or the like
+    pub method: syn::Ident,
+    pub handler: syn::Ident,
+    /// The extractor the handler takes its path parameter with, and the parameter's
+    /// type, if it takes one
+    pub param: Option<(syn::Path, Type)>,
+}
+
+impl HttpService {
+    /// Recognize `let app = Router::new().route(path, get(handler))...;` among the
+    /// top-level statements of a legacy body, followed only by statements serving it.
+    /// Every handler must be an `async fn` in `items` that never awaits, returns a
+    /// `String` or `&str`, and takes nothing or a single `Path(..)` extractor.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let (index, app, routes) = stmts.iter().enumerate().find_map(|(index, stmt)| {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            let Pat::Ident(app) = &local.pat else {
+                return None;
+            };
+            let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+            Some((index, app.ident.clone(), router_routes(&init.expr)?))
+        })?;
+        if index != 0 {
+            return None;
+        }
+        let serve = stmts[1..].to_vec();
+        if !mentions(&serve, &app.to_string()) {
+            return None;
+        }
+
+        // The handlers, as the dataflow calls them
+        let functions: Vec<&ItemFn> = items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Fn(function) if function.sig.ident != "main" => Some(function),
+                _ => None,
+            })
+            .collect();
+        let mut routes_out = Vec::new();
+        let mut kept = HashSet::new();
+        for (path, method, handler) in routes {
+            let function = functions.iter().find(|function| function.sig.ident == handler)?;
+            let param = handler_param(function)?;
+            routes_out.push(Route { path, method, handler: handler.clone(), param });
+            kept.insert(handler.to_string());
+        }
+
+        // Along with the functions they call
+        loop {
+            let kept_functions: Vec<Stmt> = functions
+                .iter()
+                .filter(|function| kept.contains(&function.sig.ident.to_string()))
+                .map(|function| Stmt::Item(Item::Fn((*function).clone())))
+                .collect();
+            let called: Vec<String> = functions
+                .iter()
+                .map(|function| function.sig.ident.to_string())
+                .filter(|name| !kept.contains(name) && mentions(&kept_functions, name))
+                .collect();
+            if called.is_empty() {
+                break;
+            }
+            kept.extend(called);
+        }
+        let functions: Vec<ItemFn> = functions
+            .into_iter()
+            .filter(|function| kept.contains(&function.sig.ident.to_string()))
+            .map(|function| {
+                let mut function = function.clone();
+                if routes_out.iter().any(|route| route.handler == function.sig.ident) {
+                    function.sig.asyncness = None;
+                }
+                function
+            })
+            .collect();
+        if functions.iter().any(|function| function.sig.asyncness.is_some() || awaits(function)) {
+            return None;
+        }
+
+        let uses: Vec<Stmt> = functions.iter().map(|function| Stmt::Item(Item::Fn(function.clone()))).collect();
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&uses, name)) && !is_glob(&item.tree)))
+            .cloned()
+            .collect();
+
+        Some(Self { routes: routes_out, functions, imports, app, serve })
+    }
+}
+
+/// The path, routing function and handler of each `.route(path, get(handler))` on a
+/// `Router::new()`
+fn router_routes(expr: &Expr) -> Option<Vec<(LitStr, syn::Ident, syn::Ident)>> {
+    let mut routes = Vec::new();
+    let mut expr = expr;
+    loop {
+        match expr {
+            Expr::MethodCall(call) if call.method == "route" => {
+                let [Expr::Lit(path), Expr::Call(method)] = &call.args.iter().collect::<Vec<_>>()[..] else {
+                    return None;
+                };
+                let syn::Lit::Str(path) = &path.lit else {
+                    return None;
+                };
+                let Expr::Path(function) = &*method.func else {
+                    return None;
+                };
+                let method_name = function.path.segments.last()?.ident.clone();
+                if !METHODS.iter().any(|name| method_name == name) {
+                    return None;
+                }
+                let [Expr::Path(handler)] = &method.args.iter().collect::<Vec<_>>()[..] else {
+                    return None;
+                };
+                routes.push((path.clone(), method_name, handler.path.get_ident()?.clone()));
+                expr = &call.receiver;
+            }
+            Expr::Call(new) => {
+                let Expr::Path(function) = &*new.func else {
+                    return None;
+                };
+                let segments: Vec<String> = function.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+                if !matches!(&segments[..], [.., router, new] if router == "Router" && new == "new") || routes.is_empty() {
+                    return None;
+                }
+                routes.reverse();
+                return Some(routes);
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// For a handler that returns text and takes nothing or a single `Path(x): Path<T>`,
+/// the extractor and `T`, if any
+fn handler_param(function: &ItemFn) -> Option<Option<(syn::Path, Type)>> {
+    if function.sig.asyncness.is_none() || !returns_text(&function.sig.output) {
+        return None;
+    }
+    let inputs: Vec<&FnArg> = function.sig.inputs.iter().collect();
+    match inputs[..] {
+        [] => Some(None),
+        [FnArg::Typed(typed)] => {
+            let Pat::TupleStruct(extracted) = &*typed.pat else {
+                return None;
+            };
+            let Type::Path(extractor) = &*typed.ty else {
+                return None;
+            };
+            let last = extractor.path.segments.last()?;
+            if last.ident != "Path" || extracted.path.segments.last()?.ident != "Path" {
+                return None;
+            }
+            let PathArguments::AngleBracketed(args) = &last.arguments else {
+                return None;
+            };
+            let [syn::GenericArgument::Type(param)] = &args.args.iter().collect::<Vec<_>>()[..] else {
+                return None;
+            };
+            // A single value, not a tuple of several
+            let mut names = HashSet::new();
+            bindings(&typed.pat, &mut names);
+            if names.len() != 1 || matches!(param, Type::Tuple(_)) {
+                return None;
+            }
+            let mut constructor = extractor.path.clone();
+            constructor.segments.last_mut()?.arguments = PathArguments::None;
+            Some(Some((constructor, (*param).clone())))
+        }
+        _ => None,
+    }
+}
+
+fn returns_text(output: &ReturnType) -> bool {
+    let ReturnType::Type(_, ty) = output else {
+        return false;
+    };
+    match &**ty {
+        Type::Path(path) => path.path.is_ident("String"),
+        Type::Reference(reference) => matches!(&*reference.elem, Type::Path(path) if path.path.is_ident("str")),
+        _ => false,
+    }
+}
+
+fn awaits(function: &ItemFn) -> bool {
+    quote::ToTokens::to_token_stream(&function.block).to_string().contains(". await")
+}
+
+fn is_glob(tree: &UseTree) -> bool {
+    match tree {
+        UseTree::Path(path) => is_glob(&path.tree),
+        UseTree::Glob(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str) -> Option<HttpService> {
+        let (items, main) = parse_main(source)?;
+        HttpService::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_router_with_simple_handlers() {
+        let service = detect(r#"
+            use axum::{extract::Path, routing::get, Router};
+
+            async fn hello() -> &'static str {
+                "Hello, world!"
+            }
+
+            async fn greet(Path(name): Path<String>) -> String {
+                shout(&format!("Hello, {}!", name))
+            }
+
+            fn shout(text: &str) -> String {
+                text.to_uppercase()
+            }
+
+            #[tokio::main]
+            async fn main() {
+                let app = Router::new().route("/", get(hello)).route("/greet/:name", get(greet));
+                let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+                axum::serve(listener, app).await.unwrap();
+            }
+        "#).unwrap();
+
+        let routes: Vec<String> = service.routes.iter().map(|route| format!("{} {} {}", route.path.value(), route.method, route.handler)).collect();
+        assert_eq!(routes, ["/ get hello", "/greet/:name get greet"]);
+        assert!(service.routes[0].param.is_none());
+        let (extractor, param) = service.routes[1].param.as_ref().unwrap();
+        assert_eq!((compact(extractor), compact(param)), ("Path".to_string(), "String".to_string()));
+        // The handlers run synchronously, with the helper they call
+        let functions: Vec<String> = service.functions.iter().map(|function| function.sig.ident.to_string()).collect();
+        assert_eq!(functions, ["hello", "greet", "shout"]);
+        assert!(service.functions.iter().all(|function| function.sig.asyncness.is_none()));
+        assert_eq!(service.imports.len(), 1);
+        assert_eq!(service.app, "app");
+        assert_eq!(service.serve.len(), 2);
+    }
+
+    #[test]
+    fn test_handlers_that_are_not_simple() {
+        // Awaits in the handler
+        assert!(detect(r#"
+            async fn slow() -> String { tokio::time::sleep(DELAY).await; String::new() }
+            async fn main() {
+                let app = Router::new().route("/", get(slow));
+                axum::serve(listener, app).await.unwrap();
+            }
+        "#).is_none());
+        // Takes the request body
+        assert!(detect(r#"
+            async fn echo(body: String) -> String { body }
+            async fn main() {
+                let app = Router::new().route("/", post(echo));
+                axum::serve(listener, app).await.unwrap();
+            }
+        "#).is_none());
+        // A JSON response
+        assert!(detect(r#"
+            async fn data() -> Json<Data> { Json(Data::default()) }
+            async fn main() {
+                let app = Router::new().route("/", get(data));
+                axum::serve(listener, app).await.unwrap();
+            }
+        "#).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use proc_macro2::{Literal, Span, TokenStream};
+use quote::quote;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, FnArg, Ident, Item, Stmt, Type};
+
+use crate::error::TransformError;
+
+/// The operators whose closures are counted and timed
+const OPERATORS: [&str; 7] = ["map", "filter", "filter_map", "flat_map_ordered", "flat_map_unordered", "inspect", "for_each"];
+
+/// Count the items each operator of a generated dataflow handles and time its closure
+/// on them, so its throughput can be compared with the legacy program's. Every process
+/// and cluster of the dataflow reports the operators it ran to stderr once a second,
+/// leaving the program's own output alone.
+pub fn instrument(module: &str, module_name: &str) -> Result<String, TransformError> {
+    let mut file = syn::parse_file(module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let module_ident = Ident::new(module_name, Span::call_site());
+
+    let dataflow = file
+        .items
+        .iter_mut()
+        .find_map(|item| match item {
+            Item::Fn(func) if func.sig.ident == module_name => Some(func),
+            _ => None,
+        })
+        .ok_or_else(|| TransformError::TemplateError(format!("No function named `{}` to instrument", module_name)))?;
+
+    let mut timing = Timing { module: &module_ident, names: Vec::new() };
+    timing.visit_block_mut(&mut dataflow.block);
+    let names = timing.names;
+
+    // Reports start with the dataflow, on every location it runs on
+    let reporters: Vec<Stmt> = locations(&dataflow.sig.inputs)
+        .into_iter()
+        .map(|location| {
+            let name = location.to_string();
+            parse_quote! {
+                #location
+                    .source_interval(q!(std::time::Duration::from_secs(1)))
+                    .for_each(q!(|_| crate::#module_ident::report_operator_stats(#name)));
+            }
+        })
+        .collect();
+    dataflow.block.stmts.splice(0..0, reporters);
+
+    let count = Literal::usize_unsuffixed(names.len());
+    let stats: TokenStream = quote! {
+        /// How many items an operator of the dataflow handled since the last report, and
+        /// how long its closure took on them
+        pub struct OperatorStats {
+            pub name: &'static str,
+            pub items: std::sync::atomic::AtomicU64,
+            pub nanos: std::sync::atomic::AtomicU64,
+        }
+
+        impl OperatorStats {
+            const fn new(name: &'static str) -> Self {
+                Self { name, items: std::sync::atomic::AtomicU64::new(0), nanos: std::sync::atomic::AtomicU64::new(0) }
+            }
+
+            pub fn record(&self, started: std::time::Instant) {
+                self.items.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                self.nanos.fetch_add(started.elapsed().as_nanos() as u64, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+
+        /// The operators of the dataflow, numbered in the order they appear in it
+        pub static OPERATOR_STATS: [OperatorStats; #count] = [#(OperatorStats::new(#names)),*];
+
+        /// Print the throughput and mean time per item of the operators that ran on
+        /// `location` in the last second, and start counting again
+        pub fn report_operator_stats(location: &str) {
+            for stats in &OPERATOR_STATS {
+                let items = stats.items.swap(0, std::sync::atomic::Ordering::Relaxed);
+                let nanos = stats.nanos.swap(0, std::sync::atomic::Ordering::Relaxed);
+                if items > 0 {
+                    eprintln!("[{}] {}: {} items/s, {:.1} µs/item", location, stats.name, items, nanos as f64 / items as f64 / 1000.0);
+                }
+            }
+        }
+    };
+    let stats: syn::File = syn::parse2(stats).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    file.items.extend(stats.items);
+
+    Ok(prettyplease::unparse(&file))
+}
+
+/// The processes and clusters a dataflow function takes, by the names of its parameters
+fn locations(inputs: &syn::punctuated::Punctuated<FnArg, syn::Token![,]>) -> Vec<Ident> {
+    inputs
+        .iter()
+        .filter_map(|input| {
+            let FnArg::Typed(typed) = input else {
+                return None;
+            };
+            let (syn::Pat::Ident(binding), Type::Reference(reference)) = (&*typed.pat, &*typed.ty) else {
+                return None;
+            };
+            let Type::Path(path) = &*reference.elem else {
+                return None;
+            };
+            let kind = &path.path.segments.last()?.ident;
+            (kind == "Process" || kind == "Cluster").then(|| binding.ident.clone())
+        })
+        .collect()
+}
+
+/// Wraps the closure of each operator in the dataflow to record the item it handles,
+/// naming the operators in the order they run along each chain
+struct Timing<'m> {
+    module: &'m Ident,
+    names: Vec<String>,
+}
+
+impl Timing<'_> {
+    /// Time a closure's body, recording it in the stats of operator `index`
+    fn wrap(&self, closure: &mut syn::ExprClosure, index: usize) {
+        let (module, index) = (self.module, Literal::usize_unsuffixed(index));
+        let body = &closure.body;
+        closure.body = Box::new(parse_quote! {{
+            let operator_started = std::time::Instant::now();
+            let result = #body;
+            crate::#module::OPERATOR_STATS[#index].record(operator_started);
+            result
+        }});
+    }
+}
+
+impl VisitMut for Timing<'_> {
+    fn visit_expr_method_call_mut(&mut self, call: &mut syn::ExprMethodCall) {
+        // The receiver first, so operators are numbered from the start of the chain
+        visit_mut::visit_expr_method_call_mut(self, call);
+        let method = call.method.to_string();
+        if !OPERATORS.contains(&method.as_str()) || call.args.len() != 1 {
+            return;
+        }
+        let Expr::Macro(mac) = &mut call.args[0] else {
+            return;
+        };
+        if !mac.mac.path.is_ident("q") {
+            return;
+        }
+        let Ok(mut staged) = mac.mac.parse_body::<Expr>() else {
+            return;
+        };
+        let index = self.names.len();
+        let closure = match &mut staged {
+            Expr::Closure(closure) => Some(closure),
+            // State set up ahead of the closure, like `{ let mut seen = 0; move |x| .. }`
+            Expr::Block(block) => match block.block.stmts.last_mut() {
+                Some(Stmt::Expr(Expr::Closure(closure), None)) => Some(closure),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(closure) = closure else {
+            return;
+        };
+        self.wrap(closure, index);
+        self.names.push(format!("{} {}", index, method));
+        mac.mac.tokens = quote! { #staged };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_are_counted_and_timed() {
+        let module = r#"
+use hydro_lang::*;
+
+pub fn doubles<'a>(leader: &Process<'a, Leader>, workers: &Cluster<'a, Worker>, external: &ExternalProcess<'a, ()>) {
+    leader
+        .source_iter(q!(0..10))
+        .round_robin_bincode(workers)
+        .map(q!(|n| n * 2))
+        .filter_map(q!({
+            let mut seen = 0;
+            move |n| {
+                seen += 1;
+                (seen > 1).then_some(n)
+            }
+        }))
+        .send_bincode_anonymous(leader)
+        .for_each(q!(|n| println!("{}", n)));
+}
+"#;
+        let instrumented = instrument(module, "doubles").unwrap();
+        let compact: String = instrumented.split_whitespace().collect();
+
+        // Each operator's closure is timed, in the order of the chain
+        assert!(compact.contains(".map(q!(|n|{letoperator_started=std::time::Instant::now();letresult=n*2;crate::doubles::OPERATOR_STATS[0].record(operator_started);result}"));
+        assert!(compact.contains("letmutseen=0;move|n|{letoperator_started"));
+        assert!(compact.contains("OPERATOR_STATS[1]"));
+        assert!(compact.contains("OPERATOR_STATS[2]"));
+        assert!(compact.contains("pubstaticOPERATOR_STATS:[OperatorStats;3]=[OperatorStats::new(\"0map\"),OperatorStats::new(\"1filter_map\"),OperatorStats::new(\"2for_each\"),];"));
+        // The source isn't an operator on items
+        assert!(compact.contains(".source_iter(q!(0..10))"));
+        // Both locations report, the external process doesn't
+        assert!(compact.contains("leader.source_interval(q!(std::time::Duration::from_secs(1))).for_each(q!(|_|crate::doubles::report_operator_stats(\"leader\")));"));
+        assert!(compact.contains("report_operator_stats(\"workers\")"));
+        assert!(!compact.contains("report_operator_stats(\"external\")"));
+        assert!(syn::parse_file(&instrumented).is_ok());
+
+        assert!(instrument(module, "triples").is_err());
+    }
+}
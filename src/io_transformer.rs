@@ -1,27 +1,69 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
-use syn::{parse_file, Item, ItemFn, Stmt, Expr, ExprCall, ExprMethodCall, ExprMacro, Pat, PatIdent};
-use quote::{quote, ToTokens};
+use syn::{parse_file, Item, ItemFn, Stmt, Expr, ExprCall, ExprMethodCall, ExprMacro, Pat, PatIdent, Type};
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+use syn::visit_mut::{self, VisitMut};
+use crate::backend::{Capabilities, Transformer};
+use crate::config::{StdinMode, Target, TransformerConfig, UnsafeCode};
+use crate::error::TransformError;
+use crate::captures::{qualify_items, thread_captures};
+use crate::comments::carry_comments;
+use crate::formatting::format_module;
+use crate::instrument::instrument;
+use crate::location_tags::generic_locations;
+use crate::unsafe_code::{isolate_unsafe, refuse_unsafe};
+use crate::lifecycle;
+use crate::output::TransformOutput;
+use crate::syntax::{mentions, span_location};
+use crate::fallible::{error_report, main_error_type, split_outcome};
+use crate::json_lines::{record_definitions, JsonLinesLoop};
+use crate::loop_control::ControlledLoop;
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::match_demux::MatchDemux;
+use crate::user_types::UserTypes;
+use crate::print_rewriter::{format_message, handle_channel, DiagnosticMacro, has_log_records, print_through_handles, terminate_tail, LogLevel, LogMacro, OutputChannel, PrintMacro};
+use quote::{format_ident, quote, ToTokens};
 use proc_macro2::{TokenStream, Span};
 
 /// A specialized transformer for handling I/O operations in legacy Rust programs
 /// and converting them to Hydro stream-based operations
 pub struct IOToHydroTransformer {
-    preserve_spans: bool,
+    /// The options shared by all backends
+    config: TransformerConfig,
 }
 
 /// Information about I/O operations found in the source code
 #[derive(Debug, Clone)]
 pub struct IOOperation {
     pub operation_type: IOOperationType,
+    /// 1-based line of the operation in the legacy source
     pub line_number: Option<usize>,
+    /// 0-based column of the operation in the legacy source
+    pub column: Option<usize>,
     pub variable_name: Option<String>,
 }
 
+impl IOOperation {
+    /// An operation located at the start of `span`
+    pub fn new(operation_type: IOOperationType, span: Span, variable_name: Option<String>) -> Self {
+        let location = span_location(span);
+        Self {
+            operation_type,
+            line_number: location.map(|(line, _)| line),
+            column: location.map(|(_, column)| column),
+            variable_name,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum IOOperationType {
     StdinRead,
     StdinReadLine,
+    /// A `read_line` inside a loop, reading the next line each time around
+    StdinReadLineLoop,
     StdinLines,
     StdoutWrite,
     StdoutPrint,
@@ -31,17 +73,43 @@ pub enum IOOperationType {
     StderrEprintln,
     StdoutFlush,
     StderrFlush,
+    /// A `dbg!`, which writes its operands to stderr
+    StderrDbg,
+    /// An `assert!`, `assert_eq!` or `assert_ne!`, which panics when it fails
+    Assert,
+    /// A `panic!`
+    Panic,
+    /// A handle on stdout, or a lock or buffered writer on it, bound to a variable
+    StdoutHandle,
+    /// A handle on stderr bound to a variable
+    StderrHandle,
+    /// A file opened or created and bound to a variable
+    FileOpen,
+    /// A `write!` or `writeln!` to a file
+    FileWrite,
+    /// A socket connected and bound to a variable
+    SocketConnect,
+    /// A `write!` or `writeln!` to a socket
+    SocketWrite,
+    /// A `log` or `tracing` record at the given level
+    Log(LogLevel),
 }
 
 impl IOToHydroTransformer {
     pub fn new() -> Self {
         Self {
-            preserve_spans: false,
+            config: TransformerConfig::new(),
         }
     }
 
+    /// Use the given options, replacing any set before
+    pub fn with_config(mut self, config: TransformerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
     pub fn with_preserve_spans(mut self, preserve: bool) -> Self {
-        self.preserve_spans = preserve;
+        self.config.preserve_spans = preserve;
         self
     }
 
@@ -50,32 +118,77 @@ impl IOToHydroTransformer {
         &self,
         legacy_path: P,
         module_name: &str,
-    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+    ) -> Result<TransformOutput, TransformError> {
         let source = fs::read_to_string(&legacy_path)?;
-        let file = parse_file(&source)?;
+        self.transform_source(&source, module_name)
+    }
+
+    /// Transform legacy source code held in memory, as `transform_program` does the
+    /// contents of a file
+    pub fn transform_source(&self, source: &str, module_name: &str) -> Result<TransformOutput, TransformError> {
+        self.transform_file(&parse_file(source)?, module_name)
+    }
+
+    /// Transform an already parsed legacy program
+    pub fn transform_file(&self, file: &syn::File, module_name: &str) -> Result<TransformOutput, TransformError> {
+        // The program as it compiles for the target platform, when one is given
+        let resolved = self.config.target_cfg.as_ref().map(|cfg| cfg.resolve(file));
+        let file = resolved.as_ref().unwrap_or(file);
+        // The I/O operations, including those of the helpers main calls, drive the code
+        // generated and are reported in the output, so they are worked out once
+        let analysis = Transformer::analyze(self, file)?;
+        if self.config.unsafe_code == UnsafeCode::Refuse {
+            refuse_unsafe(&analysis.report)?;
+        }
+        let (hydro_function, example_program) = self.generate(file, &analysis.io_operations, module_name)?;
+        // The types main uses come along, after the helpers it calls
+        let main_fn = self.extract_main_function(file)?;
+        let hydro_function = UserTypes::of(&file.items, &main_fn.block.stmts).carry(hydro_function)?;
+        let hydro_function = thread_captures(hydro_function)?;
+        let hydro_function = qualify_items(hydro_function, module_name)?;
+        let hydro_function = if self.config.unsafe_code == UnsafeCode::Isolate { isolate_unsafe(&hydro_function, module_name)? } else { hydro_function };
+        let hydro_function = if self.config.instrument { instrument(&hydro_function, module_name)? } else { hydro_function };
+        let (hydro_function, example_program) = if self.config.generic_locations {
+            generic_locations(&hydro_function, &example_program, module_name)?
+        } else {
+            (hydro_function, example_program)
+        };
+        let hydro_function = format_module(&hydro_function)?;
+        // Last, as the passes before reformat the module and drop comments
+        let hydro_function = if self.config.preserve_spans { carry_comments(&main_fn.block, &hydro_function) } else { hydro_function };
+        Ok(TransformOutput::from_analysis(file, analysis, hydro_function, example_program))
+    }
 
+    /// The generated module and example for a legacy program with the given I/O operations
+    fn generate(&self, file: &syn::File, io_operations: &[IOOperation], module_name: &str) -> Result<(String, String), TransformError> {
         // Extract the main function and its body
-        let main_fn = self.extract_main_function(&file)?;
+        let main_fn = self.extract_main_function(file)?;
         let main_body = self.extract_function_body(&main_fn)?;
+        let main_error = main_error_type(&main_fn.sig);
 
-        // Analyze I/O operations in the code
-        let io_operations = self.analyze_io_operations(&main_body);
+        // Writes through stdout and stderr handles join the output like prints, as do
+        // the prints of the helpers
+        let helpers = CarriedHelpers::of(&file.items, &main_body);
+        let main_body = helpers.rewrite_calls(&print_through_handles(&main_body));
 
         // Generate the Hydro function based on I/O patterns
         let hydro_function = self.generate_io_aware_hydro_function(
             module_name,
+            &file.items,
             &main_body,
-            &io_operations,
+            io_operations,
+            main_error.as_ref(),
         )?;
+        let hydro_function = helpers.append_to(hydro_function)?;
 
         // Generate the example program
-        let example_program = self.generate_example_program(module_name, &io_operations)?;
+        let example_program = self.generate_example_program(module_name, io_operations)?;
 
         Ok((hydro_function, example_program))
     }
 
     /// Extract the main function from the parsed file
-    pub fn extract_main_function<'a>(&self, file: &'a syn::File) -> Result<&'a ItemFn, Box<dyn std::error::Error>> {
+    pub fn extract_main_function<'a>(&self, file: &'a syn::File) -> Result<&'a ItemFn, TransformError> {
         for item in &file.items {
             if let Item::Fn(func) = item {
                 if func.sig.ident == "main" {
@@ -83,256 +196,449 @@ impl IOToHydroTransformer {
                 }
             }
         }
-        Err("No main function found in the source file".into())
+        Err(TransformError::NoMainFunction)
     }
 
     /// Extract the body statements from a function, preserving spans
-    pub fn extract_function_body(&self, func: &ItemFn) -> Result<Vec<Stmt>, Box<dyn std::error::Error>> {
+    pub fn extract_function_body(&self, func: &ItemFn) -> Result<Vec<Stmt>, TransformError> {
         Ok(func.block.stmts.clone())
     }
 
     /// Analyze I/O operations in the function body
     pub fn analyze_io_operations(&self, stmts: &[Stmt]) -> Vec<IOOperation> {
+        self.analyze_io_operations_in(&[], stmts)
+    }
+
+    /// Analyze I/O operations in the function body, looking into the functions of the
+    /// file it calls. The operations of a helper are reported at its own lines, each
+    /// time it is called.
+    pub fn analyze_io_operations_in(&self, items: &[Item], stmts: &[Stmt]) -> Vec<IOOperation> {
+        let mut helpers = Helpers::of(items);
         let mut operations = Vec::new();
         for stmt in stmts {
-            self.extract_io_operations_from_stmt(stmt, &mut operations);
+            self.extract_io_operations_from_stmt(stmt, &mut operations, &mut helpers);
         }
         operations
     }
 
-    fn extract_io_operations_from_stmt(&self, stmt: &Stmt, operations: &mut Vec<IOOperation>) {
+    fn extract_io_operations_from_stmt(&self, stmt: &Stmt, operations: &mut Vec<IOOperation>, helpers: &mut Helpers<'_>) {
         match stmt {
             Stmt::Local(local) => {
                 // Check for variable assignments involving I/O
                 if let Some(init) = &local.init {
-                    self.extract_io_operations_from_expr(&init.expr, operations);
+                    self.extract_io_operations_from_expr(&init.expr, operations, helpers);
                     
-                    // Check for stdin assignments
-                    if let Some(tokens) = init.expr.to_token_stream().to_string().strip_prefix("io :: stdin") {
+                    // Check for stdin assignments, including locked handles like `stdin.lock()`
+                    let init_str = init.expr.to_token_stream().to_string();
+                    let binds_stdin = init_str.starts_with("io :: stdin")
+                        || init_str.starts_with("std :: io :: stdin")
+                        || matches!(&*init.expr, Expr::MethodCall(call)
+                            if call.method == "lock" && self.is_stdin_handle(&call.receiver, operations));
+                    if binds_stdin {
                         if let Pat::Ident(PatIdent { ident, .. }) = &local.pat {
-                            operations.push(IOOperation {
-                                operation_type: IOOperationType::StdinRead,
-                                line_number: None,
-                                variable_name: Some(ident.to_string()),
-                            });
+                            operations.push(IOOperation::new(
+                                IOOperationType::StdinRead,
+                                local.span(),
+                                Some(ident.to_string()),
+                            ));
                         }
                     }
+
+                    // Handles on the standard streams, files and sockets are bound so that
+                    // writes to them can be told apart from formatting into a String
+                    let opens = if let Some(channel) = handle_channel(&init.expr, &self.std_handles(operations)) {
+                        Some(match channel {
+                            OutputChannel::Stdout => IOOperationType::StdoutHandle,
+                            OutputChannel::Stderr => IOOperationType::StderrHandle,
+                        })
+                    } else if init_str.contains("File :: create") || init_str.contains("File :: open") || init_str.contains("OpenOptions") {
+                        Some(IOOperationType::FileOpen)
+                    } else if init_str.contains("TcpStream :: connect") || init_str.contains("UnixStream :: connect") {
+                        Some(IOOperationType::SocketConnect)
+                    } else {
+                        None
+                    };
+                    if let (Some(operation_type), Pat::Ident(PatIdent { ident, .. })) = (opens, &local.pat) {
+                        operations.push(IOOperation::new(operation_type, local.span(), Some(ident.to_string())));
+                    }
                 }
             }
             Stmt::Expr(expr, _) => {
-                self.extract_io_operations_from_expr(expr, operations);
+                self.extract_io_operations_from_expr(expr, operations, helpers);
+            }
+            Stmt::Macro(stmt_macro) => {
+                // `println!(...);` at statement level is not an expression in syn
+                let expr = Expr::Macro(ExprMacro {
+                    attrs: stmt_macro.attrs.clone(),
+                    mac: stmt_macro.mac.clone(),
+                });
+                self.extract_io_operations_from_expr(&expr, operations, helpers);
             }
             _ => {}
         }
     }
 
-    fn extract_io_operations_from_expr(&self, expr: &Expr, operations: &mut Vec<IOOperation>) {
+    fn extract_io_operations_from_expr(&self, expr: &Expr, operations: &mut Vec<IOOperation>, helpers: &mut Helpers<'_>) {
         match expr {
-            Expr::Call(ExprCall { func, .. }) => {
+            Expr::Call(ExprCall { func, args, .. }) => {
+                for arg in args {
+                    self.extract_io_operations_from_expr(arg, operations, helpers);
+                }
                 let func_str = func.to_token_stream().to_string();
                 if func_str.contains("println!") {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdoutPrintln,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StdoutPrintln, func.span(), None));
                 } else if func_str.contains("print!") {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdoutPrint,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StdoutPrint, func.span(), None));
                 } else if func_str.contains("eprint!") {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StderrEprint,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StderrEprint, func.span(), None));
                 } else if func_str.contains("eprintln!") {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StderrEprintln,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StderrEprintln, func.span(), None));
+                } else if let Some(helper) = helpers.enter(func) {
+                    // The helper's bindings are its own, so it is analyzed on its own
+                    let mut found = Vec::new();
+                    for stmt in &helper.block.stmts {
+                        self.extract_io_operations_from_stmt(stmt, &mut found, helpers);
+                    }
+                    helpers.leave();
+                    operations.extend(found.into_iter().map(|mut op| {
+                        // Only the body's own reads are served by the stdin source
+                        if matches!(op.operation_type, IOOperationType::StdinReadLine | IOOperationType::StdinReadLineLoop | IOOperationType::StdinLines) {
+                            op.operation_type = IOOperationType::StdinRead;
+                        }
+                        op.variable_name = None;
+                        op
+                    }));
                 }
             }
+            Expr::Closure(closure) => {
+                self.extract_io_operations_from_expr(&closure.body, operations, helpers);
+            }
             Expr::Macro(ExprMacro { mac, .. }) => {
                 let path = &mac.path;
                 let path_str = path.to_token_stream().to_string();
                 
                 if path_str == "println" {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdoutPrintln,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StdoutPrintln, path.span(), None));
                 } else if path_str == "print" {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdoutPrint,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StdoutPrint, path.span(), None));
                 } else if path_str == "eprint" {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StderrEprint,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StderrEprint, path.span(), None));
                 } else if path_str == "eprintln" {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StderrEprintln,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StderrEprintln, path.span(), None));
+                } else if path_str == "write" || path_str == "writeln" {
+                    let args = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated);
+                    let target = args.ok().and_then(|args| args.first().and_then(|handle| self.write_target(handle, operations)));
+                    if let Some(operation_type) = target {
+                        operations.push(IOOperation::new(operation_type, path.span(), None));
+                    }
+                } else if let Some(log) = LogMacro::parse(mac) {
+                    operations.push(IOOperation::new(IOOperationType::Log(log.level), path.span(), None));
+                } else if let Some(diagnostic) = DiagnosticMacro::parse(mac) {
+                    // The operands are evaluated before anything is written
+                    for operand in diagnostic.operands(mac).unwrap_or_default() {
+                        self.extract_io_operations_from_expr(&operand, operations, helpers);
+                    }
+                    let operation_type = match diagnostic {
+                        DiagnosticMacro::Dbg => IOOperationType::StderrDbg,
+                        DiagnosticMacro::Panic => IOOperationType::Panic,
+                        _ => IOOperationType::Assert,
+                    };
+                    operations.push(IOOperation::new(operation_type, path.span(), None));
                 }
             }
-            Expr::MethodCall(ExprMethodCall { receiver, method, .. }) => {
+            Expr::Try(expr_try) => {
+                self.extract_io_operations_from_expr(&expr_try.expr, operations, helpers);
+            }
+            Expr::MethodCall(ExprMethodCall { receiver, method, args, .. }) => {
+                // Operations in the receiver chain (e.g. `handle.read_line(..).unwrap()`)
+                // and in the arguments happen around this call
+                self.extract_io_operations_from_expr(receiver, operations, helpers);
+                for arg in args {
+                    self.extract_io_operations_from_expr(arg, operations, helpers);
+                }
+
                 let receiver_str = receiver.to_token_stream().to_string();
                 let method_str = method.to_string();
                 
                 if method_str == "read_line" {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdinReadLine,
-                        line_number: None,
-                        variable_name: None,
-                    });
-                } else if method_str == "lines" && receiver_str.contains("stdin") {
-                    operations.push(IOOperation {
-                        operation_type: IOOperationType::StdinLines,
-                        line_number: None,
-                        variable_name: None,
-                    });
+                    operations.push(IOOperation::new(IOOperationType::StdinReadLine, method.span(), None));
+                } else if method_str == "lines" && (receiver_str.contains("stdin") || self.is_stdin_handle(receiver, operations)) {
+                    operations.push(IOOperation::new(IOOperationType::StdinLines, method.span(), None));
                 } else if method_str == "flush" {
-                    if receiver_str.contains("stdout") {
-                        operations.push(IOOperation {
-                            operation_type: IOOperationType::StdoutFlush,
-                            line_number: None,
-                            variable_name: None,
-                        });
-                    } else if receiver_str.contains("stderr") {
-                        operations.push(IOOperation {
-                            operation_type: IOOperationType::StderrFlush,
-                            line_number: None,
-                            variable_name: None,
-                        });
+                    let channel = handle_channel(receiver, &self.std_handles(operations));
+                    if receiver_str.contains("stdout") || channel == Some(OutputChannel::Stdout) {
+                        operations.push(IOOperation::new(IOOperationType::StdoutFlush, method.span(), None));
+                    } else if receiver_str.contains("stderr") || channel == Some(OutputChannel::Stderr) {
+                        operations.push(IOOperation::new(IOOperationType::StderrFlush, method.span(), None));
                     }
                 } else if method_str == "write" {
                     if receiver_str.contains("stdout") {
-                        operations.push(IOOperation {
-                            operation_type: IOOperationType::StdoutWrite,
-                            line_number: None,
-                            variable_name: None,
-                        });
+                        operations.push(IOOperation::new(IOOperationType::StdoutWrite, method.span(), None));
                     } else if receiver_str.contains("stderr") {
-                        operations.push(IOOperation {
-                            operation_type: IOOperationType::StderrWrite,
-                            line_number: None,
-                            variable_name: None,
-                        });
+                        operations.push(IOOperation::new(IOOperationType::StderrWrite, method.span(), None));
                     }
                 }
             }
             Expr::ForLoop(for_loop) => {
-                self.extract_io_operations_from_expr(&for_loop.expr, operations);
-                for stmt in &for_loop.body.stmts {
-                    self.extract_io_operations_from_stmt(stmt, operations);
-                }
+                self.extract_io_operations_from_expr(&for_loop.expr, operations, helpers);
+                self.extract_io_operations_from_loop(&for_loop.body.stmts, operations, helpers);
+            }
+            Expr::While(while_loop) => {
+                // The condition runs before every iteration, like `while let Ok(n) = handle.read_line(..)`
+                let mut body = vec![Stmt::Expr((*while_loop.cond).clone(), None)];
+                body.extend(while_loop.body.stmts.iter().cloned());
+                self.extract_io_operations_from_loop(&body, operations, helpers);
+            }
+            Expr::Loop(plain_loop) => {
+                self.extract_io_operations_from_loop(&plain_loop.body.stmts, operations, helpers);
+            }
+            Expr::Let(expr_let) => {
+                self.extract_io_operations_from_expr(&expr_let.expr, operations, helpers);
             }
             Expr::Block(block) => {
                 for stmt in &block.block.stmts {
-                    self.extract_io_operations_from_stmt(stmt, operations);
+                    self.extract_io_operations_from_stmt(stmt, operations, helpers);
                 }
             }
             Expr::If(expr_if) => {
-                self.extract_io_operations_from_expr(&expr_if.cond, operations);
+                self.extract_io_operations_from_expr(&expr_if.cond, operations, helpers);
                 for stmt in &expr_if.then_branch.stmts {
-                    self.extract_io_operations_from_stmt(stmt, operations);
+                    self.extract_io_operations_from_stmt(stmt, operations, helpers);
                 }
                 if let Some((_, else_branch)) = &expr_if.else_branch {
-                    self.extract_io_operations_from_expr(else_branch, operations);
+                    self.extract_io_operations_from_expr(else_branch, operations, helpers);
                 }
             }
             Expr::Match(expr_match) => {
-                self.extract_io_operations_from_expr(&expr_match.expr, operations);
+                self.extract_io_operations_from_expr(&expr_match.expr, operations, helpers);
                 for arm in &expr_match.arms {
-                    self.extract_io_operations_from_expr(&arm.body, operations);
+                    self.extract_io_operations_from_expr(&arm.body, operations, helpers);
                 }
             }
             _ => {}
         }
     }
 
-    /// Generate a Hydro dataflow function that handles I/O operations
+    /// The operations of a loop body. A `read_line` there reads the next line each time
+    /// around, rather than a single line.
+    fn extract_io_operations_from_loop(&self, stmts: &[Stmt], operations: &mut Vec<IOOperation>, helpers: &mut Helpers<'_>) {
+        let start = operations.len();
+        for stmt in stmts {
+            self.extract_io_operations_from_stmt(stmt, operations, helpers);
+        }
+        for op in &mut operations[start..] {
+            if op.operation_type == IOOperationType::StdinReadLine {
+                op.operation_type = IOOperationType::StdinReadLineLoop;
+            }
+        }
+    }
+
+    /// What a `write!` to `handle` writes to: stdout, stderr, or a file or socket opened
+    /// in place or bound earlier. Writes to anything else, like a `String`, only format.
+    fn write_target(&self, handle: &Expr, operations: &[IOOperation]) -> Option<IOOperationType> {
+        let handle_str = handle.to_token_stream().to_string();
+        match handle_channel(handle, &self.std_handles(operations)) {
+            Some(OutputChannel::Stdout) => return Some(IOOperationType::StdoutWrite),
+            Some(OutputChannel::Stderr) => return Some(IOOperationType::StderrWrite),
+            None => {}
+        }
+        if handle_str.contains("stdout") {
+            return Some(IOOperationType::StdoutWrite);
+        } else if handle_str.contains("stderr") {
+            return Some(IOOperationType::StderrWrite);
+        } else if handle_str.contains("File ::") || handle_str.contains("OpenOptions") {
+            return Some(IOOperationType::FileWrite);
+        } else if handle_str.contains("TcpStream") || handle_str.contains("UnixStream") {
+            return Some(IOOperationType::SocketWrite);
+        }
+
+        // The variable behind `&mut handle`, `handle.by_ref()` and the like
+        let mut root = handle;
+        let name = loop {
+            match root {
+                Expr::Reference(reference) => root = &reference.expr,
+                Expr::Paren(paren) => root = &paren.expr,
+                Expr::Field(field) => root = &field.base,
+                Expr::MethodCall(call) => root = &call.receiver,
+                Expr::Path(path) => break path.path.get_ident()?.to_string(),
+                _ => return None,
+            }
+        };
+        let bound = operations.iter().rev().find(|op| op.variable_name.as_deref() == Some(name.as_str()))?;
+        match bound.operation_type {
+            IOOperationType::FileOpen => Some(IOOperationType::FileWrite),
+            IOOperationType::SocketConnect => Some(IOOperationType::SocketWrite),
+            _ => None,
+        }
+    }
+
+    /// The variables already bound to handles on stdout or stderr
+    fn std_handles(&self, operations: &[IOOperation]) -> HashMap<String, OutputChannel> {
+        operations
+            .iter()
+            .filter_map(|op| {
+                let channel = match op.operation_type {
+                    IOOperationType::StdoutHandle => OutputChannel::Stdout,
+                    IOOperationType::StderrHandle => OutputChannel::Stderr,
+                    _ => return None,
+                };
+                Some((op.variable_name.clone()?, channel))
+            })
+            .collect()
+    }
+
+    /// Whether `expr` names a variable already bound to stdin or a lock on it
+    fn is_stdin_handle(&self, expr: &Expr, operations: &[IOOperation]) -> bool {
+        let Expr::Path(path) = expr else {
+            return false;
+        };
+        path.path.get_ident().is_some_and(|ident| {
+            operations.iter().any(|op| {
+                op.operation_type == IOOperationType::StdinRead
+                    && op.variable_name.as_deref().is_some_and(|name| ident == name)
+            })
+        })
+    }
+
+    /// Generate a Hydro dataflow function that handles I/O operations. `items` are the
+    /// other items of the legacy file, whose definitions the function may need.
     fn generate_io_aware_hydro_function(
         &self,
         module_name: &str,
+        items: &[Item],
         body_stmts: &[Stmt],
         io_operations: &[IOOperation],
+        main_error: Option<&Type>,
     ) -> Result<String, Box<dyn std::error::Error>> {
         let func_name = syn::Ident::new(module_name, Span::call_site());
         
         // Analyze the I/O pattern to determine the appropriate Hydro stream structure
-        let has_stdin = io_operations.iter().any(|op| matches!(op.operation_type, 
-            IOOperationType::StdinRead | IOOperationType::StdinReadLine | IOOperationType::StdinLines));
-
-        // Transform the AST to replace I/O operations with stream-compatible versions
-        let transformed_body = self.transform_io_statements(body_stmts, io_operations)?;
+        let stdin_source = StdinSource::for_operations(io_operations, self.config.stdin);
 
         // Generate different stream patterns based on I/O usage
-        let hydro_fn = if has_stdin {
-            if io_operations.iter().any(|op| op.operation_type == IOOperationType::StdinLines) {
+        let hydro_fn = match stdin_source {
+            StdinSource::MockLines => {
                 // For programs that read multiple lines from stdin
                 quote! {
                     use hydro_lang::*;
+                    use hydro_lang::location::external_process::ExternalBincodeStream;
 
-                    pub fn #func_name(process: &Process) {
+                    pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
                         // Create a mock stdin stream for line-by-line processing
                         // In production, this would be connected to actual stdin
                         let stdin_lines = vec!["Alice".to_string(), "Bob".to_string(), "Charlie".to_string()];
-                        
+                    
                         process
                             .source_iter(q!(stdin_lines.into_iter()))
-                            .for_each(q!(|line| {
+                            .map(q!(|line| {
                                 // Process each line as it would come from stdin
                                 let text = line.clone();
                                 if !text.trim().is_empty() {
                                     println!("Echo: {}", text);
                                 }
-                            }));
+                            }))
+                            // Signal completion once every line has been processed
+                            .fold(q!(|| ()), q!(|_, _| {}))
+                            .into_stream()
+                            .send_bincode_external(external)
+                    }
+                }
+            }
+            StdinSource::FirstLine => {
+                // The single read_line consumes the first line of the process's stdin, and
+                // the legacy body runs once that line (or EOF) arrives
+                let body_stmts = StdinRewriter::rewrite(body_stmts);
+                let (transformed_body, rewriter) = self.transform_main_body(&body_stmts, io_operations, main_error)?;
+                let source = quote! {
+                    source_stream(q!({
+                        use tokio::io::AsyncBufReadExt;
+                        use tokio_stream::StreamExt;
+                        let lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+                        // read_line keeps the newline, and reads nothing at EOF
+                        tokio_stream::wrappers::LinesStream::new(lines)
+                            .map(|line| line.map(|line| line + "\n"))
+                            .chain(tokio_stream::once(Ok(String::new())))
+                            .take(1)
+                    }))
+                };
+                if self.splits(io_operations) {
+                    self.split_dataflow(&func_name, source, transformed_body, &rewriter)
+                } else {
+                    self.output_dataflow(&func_name, source, quote! { stdin_line }, transformed_body, &rewriter, None)
+                }
+            }
+            StdinSource::AllLines => if let Some(lines_loop) = StdinLinesLoop::detect(body_stmts, main_error.is_some()) {
+                match lines_loop {
+                    StdinLinesLoop::Json(json) => {
+                        let definitions = record_definitions(items, &json.record);
+                        self.generate_json_lines(&func_name, *json, &definitions, io_operations)?
+                    }
+                    StdinLinesLoop::Demux(demux) => self.generate_demux(&func_name, *demux, io_operations)?,
+                    StdinLinesLoop::Controlled(controlled) => {
+                        self.generate_controlled_loop(&func_name, *controlled, io_operations, main_error)?
                     }
                 }
             } else {
+                // The source reads stdin up to EOF and then closes, so the legacy body runs
+                // once with every line -- including whatever follows its lines() loop. Output
+                // therefore appears after EOF rather than line by line. A read_line in a
+                // loop takes the lines one by one.
+                let body_stmts = StdinRewriter::rewrite_lines(body_stmts);
+                let (transformed_body, rewriter) = self.transform_main_body(&body_stmts, io_operations, main_error)?;
+                let source = quote! {
+                    source_stream(q!(Box::pin(futures::stream::once(async {
+                        use tokio::io::AsyncBufReadExt;
+                        let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+                        let mut stdin_lines = Vec::new();
+                        loop {
+                            match lines.next_line().await {
+                                Ok(Some(line)) => stdin_lines.push(Ok(line)),
+                                // EOF
+                                Ok(None) => break,
+                                Err(error) => {
+                                    stdin_lines.push(Err(error));
+                                    break;
+                                }
+                            }
+                        }
+                        stdin_lines
+                    }))))
+                };
+                self.output_dataflow(&func_name, source, quote! { stdin_lines }, transformed_body, &rewriter, None)
+            }
+            StdinSource::Client => {
+                let body_stmts = StdinRewriter::rewrite(body_stmts);
+                let (transformed_body, rewriter) = self.transform_main_body(&body_stmts, io_operations, main_error)?;
+                Self::client_dataflow(&func_name, transformed_body, &rewriter)
+            }
+            StdinSource::MockLine => {
                 // For programs that read a single input from stdin
                 quote! {
                     use hydro_lang::*;
+                    use hydro_lang::location::external_process::ExternalBincodeStream;
 
-                    pub fn #func_name(process: &Process) {
+                    pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
                         // Provide mock stdin input for single-read programs
                         // In production, this would be connected to actual stdin stream
                         process
                             .source_iter(q!(std::iter::once("Alice".to_string())))
-                            .for_each(q!(|name| {
+                            .map(q!(|name| {
                                 println!("What's your name?");
                                 let name = name.trim();
                                 println!("Hello, {}!", name);
-                            }));
+                            }))
+                            // Signal completion to the example once the input has been handled
+                            .send_bincode_external(external)
                     }
                 }
             }
-        } else {
-            // For programs without stdin (output-only) - preserve original logic
-            quote! {
-                use hydro_lang::*;
-                use std::io::{self, Write};
-
-                pub fn #func_name(process: &Process) {
-                    process
-                        .source_iter(q!(std::iter::once(())))
-                        .map(q!(|_| {
-                            #transformed_body
-                        }))
-                        .for_each(q!(|_| {}));
-                }
+            StdinSource::None => if let Some(paced) = PacedLoop::detect(body_stmts) {
+                self.generate_paced_loop(&func_name, paced, io_operations)?
+            } else {
+                // For programs without stdin (output-only) the legacy body runs exactly once
+                let (transformed_body, rewriter) = self.transform_main_body(body_stmts, io_operations, main_error)?;
+                let source = quote! { source_iter(q!(std::iter::once(()))) };
+                self.output_dataflow(&func_name, source, quote! { _ }, transformed_body, &rewriter, None)
             }
         };
 
@@ -341,188 +647,2381 @@ impl IOToHydroTransformer {
         Ok(formatted)
     }
 
-    /// Transform I/O statements to be compatible with Hydro streams
-    fn transform_io_statements(&self, stmts: &[Stmt], _io_operations: &[IOOperation]) -> Result<TokenStream, Box<dyn std::error::Error>> {
-        // For now, preserve the original statements
-        // In a more sophisticated implementation, we would transform:
-        // - stdin.read_line() -> receive from stdin stream
-        // - println!/eprintln! -> send to stdout/stderr streams
-        // - io::stdout().flush() -> stream flush operations
-        
-        if self.preserve_spans {
-            Ok(self.preserve_statement_spans(stmts))
+    /// Drive a paced loop from an interval source: step 0 runs the statements before the
+    /// loop, step `k` runs iteration `k` on the `k`th item one period later than step
+    /// `k - 1`, and the last step also runs the statements after the loop
+    fn generate_paced_loop(
+        &self,
+        func_name: &syn::Ident,
+        paced: PacedLoop,
+        io_operations: &[IOOperation],
+    ) -> Result<TokenStream, Box<dyn std::error::Error>> {
+        let PacedLoop { prefix, pat, items, period, body, suffix } = paced;
+        let (prefix, prefix_writes) = self.transform_io_statements(&prefix, io_operations)?;
+        let (body, body_writes) = self.transform_io_statements(&body, io_operations)?;
+        let (suffix, suffix_writes) = self.transform_io_statements(&suffix, io_operations)?;
+        let rewriter = OutputStreamRewriter {
+            writes: prefix_writes.writes || body_writes.writes || suffix_writes.writes,
+            fails: false,
+            logs: prefix_writes.logs || body_writes.logs || suffix_writes.logs,
+        };
+
+        // The items are built once, and each is carried to its step. The interval's first
+        // tick completes immediately, like the code before the loop, which has no item.
+        let source = quote! {
+            source_stream(q!({
+                use futures::StreamExt;
+                let items: Vec<_> = (#items).into_iter().collect();
+                let steps = items.len();
+                tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(#period))
+                    .zip(futures::stream::iter(std::iter::once(None).chain(items.into_iter().map(Some))))
+                    .enumerate()
+                    .map(move |(step, (_, item))| (step == steps, item))
+            }))
+        };
+        let transformed_body = quote! {
+            match item {
+                None => {
+                    #prefix
+                }
+                Some(#pat) => {
+                    #body
+                }
+            }
+            if last_step {
+                #suffix
+            }
+        };
+        let finished = quote! { last_step };
+        Ok(self.output_dataflow(func_name, source, quote! { (last_step, item) }, transformed_body, &rewriter, Some(finished)))
+    }
+
+    /// Stream stdin line by line through a `lines()` loop that jumps out of its body.
+    /// Leading `continue` guards filter the lines, and the rest of the body runs in a
+    /// stateful map that owns the bindings made before the loop: once the body breaks
+    /// or returns, or stdin reaches EOF, later lines are ignored and completion is
+    /// signaled, after the statements following the loop unless the body returned.
+    /// When `main` returns `Result`, an error from the body ends the loop the same way
+    /// and is reported on the error stream instead of running the rest.
+    fn generate_controlled_loop(
+        &self,
+        func_name: &syn::Ident,
+        controlled: ControlledLoop,
+        io_operations: &[IOOperation],
+        main_error: Option<&Type>,
+    ) -> Result<TokenStream, Box<dyn std::error::Error>> {
+        let ControlledLoop { prefix, pat, skips, mut body, suffix, .. } = controlled;
+        let (prefix, prefix_writes) = self.transform_io_statements(&prefix, io_operations)?;
+        // The body is the value of a match arm, so it is rewritten as an expression
+        let mut body_writes = OutputStreamRewriter::default();
+        body_writes.visit_expr_mut(&mut body);
+        let (suffix, suffix_writes) = self.transform_main_body(&suffix, io_operations, main_error)?;
+        let prefix_decl = prefix_writes.buffer_decl();
+        let step_decl = if body_writes.writes || suffix_writes.writes {
+            quote! { let mut stdio_writes = pending.take().unwrap_or_default(); }
         } else {
-            Ok(quote! { #(#stmts)* })
-        }
+            quote! { let stdio_writes = pending.take().unwrap_or_default(); }
+        };
+
+        // In a fallible main, the body runs in a closure where `?` works as before
+        let (body, eof_flow, check_flow, step) = match main_error {
+            Some(error) => {
+                let report = error_report();
+                (
+                    quote! { (|| -> Result<std::ops::ControlFlow<bool>, #error> { Ok(#body) })() },
+                    quote! { Ok(std::ops::ControlFlow::Break(true)) },
+                    quote! {
+                        let flow = match flow {
+                            Ok(flow) => flow,
+                            Err(error) => {
+                                stopped = true;
+                                return Err((stdio_writes, #report));
+                            }
+                        };
+                    },
+                    quote! { Ok((stdio_writes, finished)) },
+                )
+            }
+            None => (
+                body.to_token_stream(),
+                quote! { std::ops::ControlFlow::Break(true) },
+                quote! {},
+                quote! { (stdio_writes, finished) },
+            ),
+        };
+
+        let lines = Self::stdin_line_stream(&pat, &skips);
+        let outputs = quote! {
+            process
+                .#lines
+                .map(q!({
+                    #prefix_decl
+                    #prefix
+                    // Whatever the code before the loop wrote comes out with the first line
+                    let mut pending = Some(stdio_writes);
+                    let mut stopped = false;
+                    move |stdin_line| {
+                        #step_decl
+                        let mut finished = false;
+                        if !stopped {
+                            let flow = match stdin_line {
+                                Some(#pat) => #body,
+                                None => #eof_flow,
+                            };
+                            #check_flow
+                            if let std::ops::ControlFlow::Break(completed) = flow {
+                                stopped = true;
+                                finished = true;
+                                if completed {
+                                    #suffix
+                                }
+                            }
+                        }
+                        #step
+                    }
+                }))
+        };
+        Ok(self.write_outputs(func_name, outputs, true, main_error.is_some(), false, false))
     }
 
-    /// Preserve original spans from statements for better debugging
-    fn preserve_statement_spans(&self, stmts: &[Stmt]) -> TokenStream {
-        let mut result = TokenStream::new();
-        for stmt in stmts {
-            let stmt_tokens = stmt.to_token_stream();
-            result.extend(stmt_tokens);
+    /// Every line of stdin and then `None` at EOF, as `Option`s named `stdin_line`,
+    /// dropping the lines bound to `pat` for which any of `skips` holds
+    fn stdin_line_stream(pat: &Pat, skips: &[Expr]) -> TokenStream {
+        let skips = skips.iter().map(|skip| {
+            quote! {
+                .filter_map(q!(|stdin_line| match stdin_line {
+                    Some(#pat) if #skip => None,
+                    stdin_line => Some(stdin_line),
+                }))
+            }
+        });
+        quote! {
+            source_stream(q!({
+                use tokio::io::AsyncBufReadExt;
+                use tokio_stream::StreamExt;
+                let lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+                // Every line, then None at EOF
+                tokio_stream::wrappers::LinesStream::new(lines)
+                    .map(Some)
+                    .chain(tokio_stream::once(None))
+            }))
+            #(#skips)*
         }
-        result
     }
 
-    /// Generate an example program that handles I/O
-    fn generate_example_program(&self, module_name: &str, io_operations: &[IOOperation]) -> Result<String, Box<dyn std::error::Error>> {
-        let func_name = syn::Ident::new(module_name, Span::call_site());
-        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+    /// Split the lines of a stdin loop parsing JSON into a typed stream of the records
+    /// that parse and a stream of those that don't, each paired with the text that
+    /// failed and its error. The record type's definition is carried along from the
+    /// legacy file, as `definitions`.
+    fn generate_json_lines(
+        &self,
+        func_name: &syn::Ident,
+        json: JsonLinesLoop,
+        definitions: &[Item],
+        io_operations: &[IOOperation],
+    ) -> Result<TokenStream, Box<dyn std::error::Error>> {
+        let JsonLinesLoop { prefix, item, lead, input, record, parsed, body, error, text, suffix, .. } = json;
+        let lines = Self::stdin_line_stream(&item, &[]);
+        let sink = Self::stdio_sink();
+
+        let (prefix, prefix_writes) = self.transform_io_statements(&prefix, io_operations)?;
+        let (body, body_writes) = self.transform_io_statements(&body, io_operations)?;
+        let (suffix, suffix_writes) = self.transform_io_statements(&suffix, io_operations)?;
+        let prefix_decl = prefix_writes.buffer_decl();
+        let body_decl = body_writes.buffer_decl();
+        let suffix_decl = suffix_writes.buffer_decl();
+
+        let parse_errors = match error {
+            Some((error, handler)) => {
+                let text = match text {
+                    Some(text) if mentions(&handler, &text.to_string()) => quote! { #text },
+                    _ => quote! { _ },
+                };
+                let (handler, handler_writes) = self.transform_io_statements(&handler, io_operations)?;
+                let handler_decl = handler_writes.buffer_decl();
+                quote! {
+                    // The lines that aren't a valid record, with the error parsing them
+                    records
+                        .clone()
+                        .filter_map(q!(|record| record.err()))
+                        .flat_map_ordered(q!(|(#text, #error)| {
+                            #handler_decl
+                            #handler
+                            stdio_writes
+                        }))
+                        .for_each(q!(#sink));
+                }
+            }
+            None => quote! {},
+        };
+
+        Ok(quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+            use std::io::{self, Write};
+
+            #(#definitions)*
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                process
+                    .source_iter(q!({
+                        #prefix_decl
+                        #prefix
+                        stdio_writes
+                    }))
+                    .for_each(q!(#sink));
+
+                let lines = process.#lines;
+
+                // Each line deserialized, keeping the text of those that fail to parse
+                let records = lines.clone().filter_map(q!(|stdin_line| stdin_line.map(|#item| {
+                    #(#lead)*
+                    serde_json::from_str::<#record>(#input).map_err(|error| ((#input).to_string(), error))
+                })));
+
+                // The typed stream of the records that parsed
+                records
+                    .clone()
+                    .filter_map(q!(|record| record.ok()))
+                    .flat_map_ordered(q!(|#parsed| {
+                        #body_decl
+                        #body
+                        stdio_writes
+                    }))
+                    .for_each(q!(#sink));
+
+                #parse_errors
+
+                // The code after the loop runs at EOF
+                let finished = lines.filter_map(q!(|stdin_line| match stdin_line {
+                    Some(_) => None,
+                    None => Some(()),
+                }));
+                finished
+                    .clone()
+                    .flat_map_ordered(q!(|_| {
+                        #suffix_decl
+                        #suffix
+                        stdio_writes
+                    }))
+                    .for_each(q!(#sink));
+
+                // Signal completion to the example once every line has been read
+                finished.send_bincode_external(external)
+            }
+        })
+    }
+
+    /// Demultiplex the lines of a stdin loop by the arm of its `match` they reach. Each
+    /// arm gets its own `filter_map`, and the code before and after the loop its own
+    /// stream, so the arms run as separate dataflow paths. A gate ahead of the split
+    /// drops every line after the one whose arm breaks or returns. The paths meet again
+    /// in one sink, which prints their writes in program order and signals completion
+    /// once the code after the loop has printed.
+    fn generate_demux(
+        &self,
+        func_name: &syn::Ident,
+        demux: MatchDemux,
+        io_operations: &[IOOperation],
+    ) -> Result<TokenStream, Box<dyn std::error::Error>> {
+        let MatchDemux { prefix, skips, item, branches, suffix, .. } = demux;
+        let lines = Self::stdin_line_stream(&item, &skips);
+        let sink = Self::stdio_sink();
+
+        let (prefix, prefix_writes) = self.transform_io_statements(&prefix, io_operations)?;
+        let (suffix, suffix_writes) = self.transform_io_statements(&suffix, io_operations)?;
+        let prefix_decl = prefix_writes.buffer_decl();
+        let suffix_decl = suffix_writes.buffer_decl();
 
-        let has_stdin = io_operations.iter().any(|op| matches!(op.operation_type, 
-            IOOperationType::StdinRead | IOOperationType::StdinReadLine | IOOperationType::StdinLines));
+        // Which arm each line reaches, with everything else taken from `arm`
+        let arms = |arm: &dyn Fn(usize) -> TokenStream| -> Vec<TokenStream> {
+            branches.iter().enumerate().map(|(index, branch)| {
+                let selector = &branch.selector;
+                let value = arm(index);
+                quote! { Some(#selector) => #value, }
+            }).collect()
+        };
+        let exits = arms(&|index| match branches[index].exit {
+            Some(_) => quote! { true },
+            None => quote! { false },
+        });
+        let finishes = arms(&|index| match branches[index].exit {
+            Some(completed) => quote! { Some((2 * index + 1, #completed)) },
+            None => quote! { None },
+        });
 
-        let example = if has_stdin {
+        // The writes of line `index`'s arm are keyed `2 * index`, and those of the code
+        // after the loop `2 * index + 1` after the line that left it, or `2 * index` at EOF
+        let branch_streams = branches.iter().enumerate().map(|(index, branch)| {
+            let mut body = branch.body.clone();
+            let mut rewriter = OutputStreamRewriter::default();
+            rewriter.visit_expr_mut(&mut body);
+            let decl = rewriter.buffer_decl();
+            let pat = &branch.pat;
+            let mut others = arms(&|_| quote! { None });
+            others[index] = quote! {
+                Some(#pat) => Some((
+                    2 * index,
+                    {
+                        #decl
+                        let _ = #body;
+                        stdio_writes
+                    },
+                    Some(if last { 2 * index + 1 } else { 2 * index + 2 }),
+                )),
+            };
             quote! {
-                use hydro_deploy::Deployment;
-                use tokio::time::{timeout, Duration};
+                .union(lines.clone().filter_map(q!(|(index, last, stdin_line)| match stdin_line {
+                    #(#others)*
+                    None => None,
+                })))
+            }
+        });
 
-                #[tokio::main]
-                async fn main() {
-                    let mut deployment = Deployment::new();
+        Ok(quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+            use std::io::{self, Write};
 
-                    let flow = hydro_lang::FlowBuilder::new();
-                    let process = flow.process::<()>();
-                    
-                    // Call our generated I/O-aware Hydro function
-                    #crate_name::#func_name::#func_name(&process);
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let before = process.source_iter(q!({
+                    #prefix_decl
+                    #prefix
+                    [(0usize, stdio_writes, Some(2usize))]
+                }));
 
-                    let _nodes = flow
-                        .with_process(&process, deployment.Localhost())
-                        .deploy(&mut deployment);
+                // Lines stop at the first one whose arm leaves the loop, or at EOF
+                let lines = process
+                    .#lines
+                    .filter_map(q!({
+                        let mut stopped = false;
+                        let mut index = 0usize;
+                        move |stdin_line| {
+                            if stopped {
+                                return None;
+                            }
+                            index += 1;
+                            stopped = match &stdin_line {
+                                #(#exits)*
+                                None => true,
+                            };
+                            Some((index, stopped, stdin_line))
+                        }
+                    }));
 
-                    println!("Starting I/O-aware Hydro deployment...");
-                    println!("Note: stdin input is mocked with sample data");
-                    println!("Looking for 'running command:' output...");
-                    
-                    // Deploy the processes first
-                    deployment.deploy().await.unwrap();
-                    
-                    // Start the deployment with a timeout
-                    let start_result = timeout(Duration::from_secs(60), async {
-                        deployment.start().await.unwrap();
-                    }).await;
-                    
-                    match start_result {
-                        Ok(_) => {
-                            println!("✓ Deployment completed successfully");
+                // The code after the loop runs unless an arm returned from main
+                let after = lines
+                    .clone()
+                    .filter_map(q!(|(index, _, stdin_line)| match stdin_line {
+                        #(#finishes)*
+                        None => Some((2 * index, true)),
+                    }))
+                    .map(q!(|(key, completed)| {
+                        #suffix_decl
+                        if completed {
+                            #suffix
                         }
-                        Err(_) => {
-                            println!("✓ Deployment reached 60-second timeout");
-                            println!("If you saw output containing:");
-                            println!("  [() (process 0)] running command: `...`");
-                            println!("  [() (process 0)] <your program output>");
-                            println!("Then the I/O transformation worked correctly!");
+                        (key, stdio_writes, None)
+                    }));
+
+                // One stream per arm of the legacy match, printed in program order by
+                // following each batch's key to the next
+                before
+                    #(#branch_streams)*
+                    .union(after)
+                    .filter_map(q!({
+                        let mut pending = std::collections::BTreeMap::new();
+                        let mut next = Some(0usize);
+                        move |(key, stdio_writes, then): (usize, Vec<(u8, String)>, Option<usize>)| {
+                            pending.insert(key, (stdio_writes, then));
+                            while let Some((stdio_writes, then)) = next.and_then(|key| pending.remove(&key)) {
+                                stdio_writes.into_iter().for_each(#sink);
+                                next = then;
+                            }
+                            // Signal completion to the example once the code after the loop has printed
+                            next.is_none().then_some(())
                         }
-                    }
-                }
+                    }))
+                    .send_bincode_external(external)
             }
+        })
+    }
+
+    /// The Hydro function for a legacy body that runs once per element of `source`,
+    /// bound to `input`, and hands back everything it printed in program order.
+    /// Completion is signaled after every element, or only after those for which
+    /// `finished` holds when the legacy program spans several elements.
+    fn output_dataflow(
+        &self,
+        func_name: &syn::Ident,
+        source: TokenStream,
+        input: TokenStream,
+        transformed_body: TokenStream,
+        rewriter: &OutputStreamRewriter,
+        finished: Option<TokenStream>,
+    ) -> TokenStream {
+        let writes_decl = rewriter.buffer_decl();
+        let buffers = rewriter.buffers();
+        let result = match &finished {
+            Some(finished) => quote! { (#buffers, #finished) },
+            None => buffers,
+        };
+        let result = if rewriter.fails { quote! { Ok(#result) } } else { result };
+        let outputs = quote! {
+            process
+                .#source
+                .map(q!(|#input| {
+                    #writes_decl
+                    #transformed_body
+                    #result
+                }))
+        };
+        self.write_outputs(func_name, outputs, finished.is_some(), rewriter.fails, rewriter.logs, false)
+    }
+
+    /// The Hydro function for a legacy body reading a line, served through external
+    /// ports: the client sends the terminal's line, or `None` at EOF, and gets back the
+    /// fd-tagged writes of the body followed by `None` once it has finished
+    fn client_dataflow(func_name: &syn::Ident, transformed_body: TokenStream, rewriter: &OutputStreamRewriter) -> TokenStream {
+        let writes_decl = rewriter.buffer_decl();
+        let buffers = rewriter.buffers();
+        let (result, report_errors) = if rewriter.fails {
+            (
+                quote! { Ok(#buffers) },
+                quote! {
+                    // A failing step reports its error after whatever it wrote, as when
+                    // main returns it
+                    .map(q!(|result| match result {
+                        Ok(stdio_writes) => stdio_writes,
+                        Err((mut stdio_writes, report)) => {
+                            stdio_writes.push((2u8, report));
+                            stdio_writes
+                        }
+                    }))
+                },
+            )
         } else {
-            quote! {
-                use hydro_deploy::Deployment;
-                use tokio::time::{timeout, Duration};
+            (buffers, quote! {})
+        };
+        quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
 
-                #[tokio::main]
-                async fn main() {
-                    let mut deployment = Deployment::new();
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> (ExternalBincodeSink<Option<String>>, ExternalBincodeStream<Option<(u8, String)>>) {
+                let (stdin_port, stdin) = process.source_external_bincode(external);
 
-                    let flow = hydro_lang::FlowBuilder::new();
-                    let process = flow.process::<()>();
-                    
-                    // Call our generated Hydro function
-                    #crate_name::#func_name::#func_name(&process);
+                let output_port = stdin
+                    .map(q!(|line: Option<String>| {
+                        // read_line keeps the newline, and reads nothing at EOF
+                        let stdin_line: std::io::Result<String> = Ok(line.map_or_else(String::new, |line| line + "\n"));
+                        #writes_decl
+                        #transformed_body
+                        #result
+                    }))
+                    #report_errors
+                    // The writes go back to the client in order, and the end of the
+                    // program after them
+                    .flat_map_ordered(q!(|stdio_writes| stdio_writes.into_iter().map(Some).chain(std::iter::once(None))))
+                    .send_bincode_external(external);
 
-                    let _nodes = flow
-                        .with_process(&process, deployment.Localhost())
-                        .deploy(&mut deployment);
+                (stdin_port, output_port)
+            }
+        }
+    }
 
-                    println!("Starting deployment...");
-                    println!("Looking for 'running command:' output...");
-                    
-                    // Deploy the processes first
-                    deployment.deploy().await.unwrap();
-                    
-                    // Start the deployment with a timeout
-                    let start_result = timeout(Duration::from_secs(60), async {
-                        deployment.start().await.unwrap();
-                    }).await;
-                    
-                    match start_result {
-                        Ok(_) => {
-                            println!("✓ Deployment completed successfully");
-                        }
-                        Err(_) => {
-                            println!("✓ Deployment reached 60-second timeout");
-                            println!("If you saw output containing:");
-                            println!("  [() (process 0)] running command: `...`");
-                            println!("  [() (process 0)] <your program output>");
-                            println!("Then the deployment worked correctly!");
-                        }
+    /// Whether an interactive program is split into a UI process and a logic process:
+    /// one reading a single line of real stdin, without log records, whose `&'static
+    /// str` levels couldn't cross between the processes
+    fn splits(&self, io_operations: &[IOOperation]) -> bool {
+        self.config.target == Target::Split
+            && StdinSource::for_operations(io_operations, self.config.stdin) == StdinSource::FirstLine
+            && !io_operations.iter().any(|op| matches!(op.operation_type, IOOperationType::Log(_)))
+    }
+
+    /// The Hydro function for a legacy body reading a line of stdin, split between a
+    /// UI process owning the terminal and a logic process running the body. The UI
+    /// process reads the line from `source` and sends it over as a `Result<String,
+    /// String>`, with a read error as its message, and the logic process sends back
+    /// the fd-tagged writes of the body for the UI process to print.
+    fn split_dataflow(
+        &self,
+        func_name: &syn::Ident,
+        source: TokenStream,
+        transformed_body: TokenStream,
+        rewriter: &OutputStreamRewriter,
+    ) -> TokenStream {
+        let writes_decl = rewriter.buffer_decl();
+        let buffers = rewriter.buffers();
+        let result = if rewriter.fails { quote! { Ok(#buffers) } } else { buffers };
+        let outputs = quote! {
+            ui
+                .#source
+                .map(q!(|line| line.map_err(|error| error.to_string())))
+                .send_bincode(logic)
+                .map(q!(|stdin_line| {
+                    let stdin_line = stdin_line.map_err(std::io::Error::other);
+                    #writes_decl
+                    #transformed_body
+                    #result
+                }))
+                .send_bincode(ui)
+        };
+        self.write_outputs(func_name, outputs, false, rewriter.fails, false, true)
+    }
+
+    /// The Hydro function around a stream of `outputs`, each the fd-tagged writes of a
+    /// step of the legacy program, paired with whether it finished the program when
+    /// `signals_finish` is set
+    ///
+    /// With `fallible` set, each output is instead a `Result` whose errors carry the
+    /// writes made before the failure and the report of the error. Those are split off
+    /// onto an error stream of their own, and count as finishing the program.
+    ///
+    /// With `logs` set, the writes of each step come paired with its log records, which
+    /// go to a log stream and sink of their own.
+    ///
+    /// With `split` set, the function takes a UI process and a logic process rather
+    /// than a single one, and `outputs` arrive at the UI process, which prints them.
+    fn write_outputs(
+        &self,
+        func_name: &syn::Ident,
+        outputs: TokenStream,
+        signals_finish: bool,
+        fallible: bool,
+        logs: bool,
+        split: bool,
+    ) -> TokenStream {
+        // Patterns taking apart the buffers of a step, and the step itself
+        let buffers = |writes: TokenStream, records: TokenStream| {
+            if logs {
+                quote! { (#writes, #records) }
+            } else {
+                writes
+            }
+        };
+        let step = |buffers: TokenStream| {
+            if signals_finish {
+                quote! { (#buffers, _) }
+            } else {
+                buffers
+            }
+        };
+        let step_writes = step(buffers(quote! { stdio_writes }, quote! { _ }));
+        let writes = quote! { |#step_writes| stdio_writes };
+        let done = if signals_finish {
+            quote! { filter_map(q!(|(_, finished)| if finished { Some(()) } else { None })) }
+        } else {
+            quote! { map(q!(|_| ())) }
+        };
+        let sink = Self::stdio_sink();
+        let (errors, successes, done) = if fallible {
+            let failed_writes = buffers(quote! { mut stdio_writes }, quote! { _ });
+            let errors = quote! {
+                // Errors leave the main stream and are reported on stderr after whatever
+                // the failing step wrote, as when main returns them
+                outputs
+                    .clone()
+                    .filter_map(q!(|result| result.err()))
+                    .flat_map_ordered(q!(|(#failed_writes, report)| {
+                        stdio_writes.push((2u8, report));
+                        stdio_writes
+                    }))
+                    .for_each(q!(#sink));
+            };
+            let done = if signals_finish {
+                quote! {
+                    filter_map(q!(|result| match result {
+                        Ok((_, finished)) => finished.then_some(()),
+                        Err(_) => Some(()),
+                    }))
+                }
+            } else {
+                done
+            };
+            (errors, quote! { .clone().filter_map(q!(|result| result.ok())) }, done)
+        } else {
+            (quote! {}, quote! { .clone() }, done)
+        };
+        let log_stream = if logs {
+            let step_records = step(buffers(quote! { _ }, quote! { log_records }));
+            let records = if fallible {
+                let failed_records = buffers(quote! { _ }, quote! { log_records });
+                quote! {
+                    |result| match result {
+                        Ok(#step_records) => log_records,
+                        Err((#failed_records, _)) => log_records,
                     }
                 }
+            } else {
+                quote! { |#step_records| log_records }
+            };
+            let log_sink = Self::log_sink(func_name);
+            quote! {
+                // Log records are data of their own: (level, message) pairs
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(#records))
+                    .for_each(q!(#log_sink));
             }
+        } else {
+            quote! {}
         };
+        let (roles, locations) = if split {
+            (
+                quote! {
+                    /// The process owning the terminal: it reads stdin and prints
+                    pub struct Ui {}
+                    /// The process running the program's logic
+                    pub struct Logic {}
+                },
+                quote! { ui: &Process<'a, Ui>, logic: &Process<'a, Logic> },
+            )
+        } else {
+            (quote! {}, quote! { process: &Process<'a> })
+        };
+        quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+            use std::io::{self, Write};
 
-        // Format the generated code for better readability
-        let formatted = prettyplease::unparse(&syn::parse2(example)?);
-        Ok(formatted)
+            #roles
+
+            pub fn #func_name<'a>(#locations, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let outputs = #outputs;
+                #errors
+                #log_stream
+
+                // stdout (fd 1) and stderr (fd 2) writes share one stream tagged with their
+                // file descriptor, so the sink reproduces the original interleaving
+                outputs
+                    #successes
+                    .flat_map_ordered(q!(#writes))
+                    .for_each(q!(#sink));
+
+                // Signal completion to the example once the legacy body has run
+                outputs
+                    .#done
+                    .send_bincode_external(external)
+            }
+        }
     }
-}
 
-impl Default for IOToHydroTransformer {
-    fn default() -> Self {
-        Self::new()
+    /// The sink printing log records to stderr the way `env_logger` does without
+    /// timestamps, filtered by the level in `RUST_LOG` (errors only by default) and
+    /// with the generated module as their target
+    fn log_sink(func_name: &syn::Ident) -> TokenStream {
+        let target = func_name.to_string();
+        quote! {
+            |(level, message)| {
+                let rank = |level: &str| {
+                    ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
+                        .iter()
+                        .position(|known| known.eq_ignore_ascii_case(level))
+                };
+                let max_level = std::env::var("RUST_LOG")
+                    .ok()
+                    .and_then(|filter| rank(&filter))
+                    .unwrap_or(0);
+                if rank(level).is_some_and(|rank| rank <= max_level) {
+                    eprintln!("[{:<5} {}] {}", level, #target, message);
+                }
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+    /// The sink printing each fd-tagged write to stdout (fd 1) or stderr (fd 2)
+    fn stdio_sink() -> TokenStream {
+        quote! {
+            |(fd, msg)| {
+                if fd == 1 {
+                    print!("{}", msg);
+                    io::stdout().flush().unwrap();
+                } else {
+                    eprint!("{}", msg);
+                    io::stderr().flush().unwrap();
+                }
+            }
+        }
+    }
 
-    #[test]
-    fn test_interactive_hello_transformation() {
-        let mut temp_file = NamedTempFile::new().unwrap();
-        writeln!(temp_file, r#"
-use std::io::{{self, BufRead}};
+    /// Transform I/O statements to be compatible with Hydro streams
+    fn transform_io_statements(&self, stmts: &[Stmt], _io_operations: &[IOOperation]) -> Result<(TokenStream, OutputStreamRewriter), Box<dyn std::error::Error>> {
+        // print!/println! and eprint!/eprintln! become pushes onto the fd-tagged
+        // stdio_writes buffer (a single stdin.read_line() is rewritten beforehand by
+        // StdinRewriter), and flushes of stdout and stderr are left to the sink
+        let mut rewriter = OutputStreamRewriter::default();
+        let mut stmts = stmts.to_vec();
+        stmts.retain(|stmt| !is_std_flush(stmt));
+        for stmt in &mut stmts {
+            rewriter.visit_stmt_mut(stmt);
+        }
 
-fn main() {{
-    println!("What's your name?");
-    
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
-    let mut name = String::new();
-    
-    match handle.read_line(&mut name) {{
-        Ok(_) => {{
-            let name = name.trim();
-            println!("Hello, {{}}!", name);
-        }}
-        Err(error) => {{
-            eprintln!("Error reading input: {{}}", error);
-        }}
-    }}
-}}
-"#).unwrap();
+        // The buffers are returned after the body
+        terminate_tail(&mut stmts);
 
-        let transformer = IOToHydroTransformer::new();
-        let result = transformer.transform_program(temp_file.path(), "test_interactive");
-        
+        let tokens = if self.config.preserve_spans {
+            self.preserve_statement_spans(&stmts)
+        } else {
+            quote! { #(#stmts)* }
+        };
+        Ok((tokens, rewriter))
+    }
+
+    /// Transform the statements of a legacy `main` (or its tail) that run as one step.
+    /// When `main` returns `Result<(), E>` they run in a closure returning the same, so
+    /// `?` and early returns behave as before, and a failing step returns the writes it
+    /// made along with the report of its error.
+    fn transform_main_body(
+        &self,
+        stmts: &[Stmt],
+        io_operations: &[IOOperation],
+        main_error: Option<&Type>,
+    ) -> Result<(TokenStream, OutputStreamRewriter), Box<dyn std::error::Error>> {
+        let Some(error) = main_error else {
+            return self.transform_io_statements(stmts, io_operations);
+        };
+        let (stmts, mut outcome) = split_outcome(stmts);
+        let (body, mut rewriter) = self.transform_io_statements(&stmts, io_operations)?;
+        rewriter.visit_expr_mut(&mut outcome);
+        rewriter.fails = true;
+        let report = error_report();
+        let buffers = rewriter.buffers();
+        let tokens = quote! {
+            let outcome = (|| -> Result<(), #error> {
+                #body
+                #outcome
+            })();
+            if let Err(error) = outcome {
+                return Err((#buffers, #report));
+            }
+        };
+        Ok((tokens, rewriter))
+    }
+
+    /// Preserve original spans from statements for better debugging
+    fn preserve_statement_spans(&self, stmts: &[Stmt]) -> TokenStream {
+        let mut result = TokenStream::new();
+        for stmt in stmts {
+            let stmt_tokens = stmt.to_token_stream();
+            result.extend(stmt_tokens);
+        }
+        result
+    }
+
+    /// Generate an example program that handles I/O
+    fn generate_example_program(&self, module_name: &str, io_operations: &[IOOperation]) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let stdin_source = StdinSource::for_operations(io_operations, self.config.stdin);
+        if stdin_source == StdinSource::Client {
+            return self.generate_client_example(module_name);
+        }
+
+        // Where the program's stdin comes from, for a harness to tell a mocked run apart
+        let stdin = match stdin_source {
+            StdinSource::None => "none",
+            StdinSource::FirstLine | StdinSource::AllLines | StdinSource::Client => "forwarded",
+            StdinSource::MockLines | StdinSource::MockLine => "mocked",
+        };
+        let banner = quote! {
+            tracing::info!(event = "deploy_started", stdin = #stdin, "Starting deployment");
+        };
+
+        // A split program runs on a UI process and a logic process, and the terminal is
+        // the UI process's
+        let (processes, locations, deploy_processes, terminal) = if self.splits(io_operations) {
+            (
+                quote! {
+                    let ui = flow.process::<#crate_name::#func_name::Ui>();
+                    let logic = flow.process::<#crate_name::#func_name::Logic>();
+                },
+                quote! { &ui, &logic },
+                quote! {
+                    .with_process(&ui, deployment.Localhost())
+                    .with_process(&logic, deployment.Localhost())
+                },
+                quote! { ui },
+            )
+        } else {
+            (
+                quote! { let process = flow.process::<()>(); },
+                quote! { &process },
+                quote! { .with_process(&process, deployment.Localhost()) },
+                quote! { process },
+            )
+        };
+
+        // Programs reading real stdin get ours forwarded to the deployed process. The
+        // sender is dropped at our EOF so the process sees EOF as well.
+        let (stdin_imports, forward_stdin) = if matches!(stdin_source, StdinSource::FirstLine | StdinSource::AllLines) {
+            (
+                quote! {
+                    use hydro_lang::deploy::DeployCrateWrapper;
+                    use tokio::io::{AsyncBufReadExt, BufReader};
+                },
+                quote! {
+                    let process_stdin = nodes.get_process(&#terminal).underlying().read().await.stdin();
+                    tokio::spawn(async move {
+                        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if process_stdin.send(format!("{}\n", line)).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                },
+            )
+        } else {
+            (TokenStream::new(), TokenStream::new())
+        };
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { done.next() }, &timeout_secs, &[format_ident!("process")], &[]);
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::StreamExt;
+            use hydro_deploy::Deployment;
+            #stdin_imports
+            use tokio::time::{timeout, Duration};
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                #processes
+                let external = flow.external_process::<()>();
+                
+                // Call our generated I/O-aware Hydro function
+                let done_port = #crate_name::#func_name::#func_name(#locations, &external);
+
+                let nodes = flow
+                    #deploy_processes
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                #banner
+                
+                // Deploy the processes first
+                deployment.deploy().await.unwrap();
+                
+                // Connect to the completion port before starting so the signal can't be missed
+                let mut done = nodes.connect_source_bincode(done_port).await;
+                #forward_stdin
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+                
+                #completion
+            }
+        };
+
+        // Format the generated code for better readability
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a program served through external ports, which runs a
+    /// client task owning the terminal: it sends the terminal's line to the dataflow and
+    /// prints the writes that come back until the program has finished
+    fn generate_client_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { client }, &timeout_secs, &[format_ident!("process")], &[]);
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use std::io::Write;
+
+            use futures::{SinkExt, StreamExt};
+            use hydro_deploy::Deployment;
+            use tokio::io::{AsyncBufReadExt, BufReader};
+            use tokio::time::{timeout, Duration};
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated I/O-aware Hydro function
+                let (stdin_port, output_port) = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // Connect the client to both ports before starting so nothing is missed
+                let mut stdin_sink = nodes.connect_sink_bincode(stdin_port).await;
+                let mut output = nodes.connect_source_bincode(output_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // The terminal's line goes to the dataflow, or None at EOF
+                tokio::spawn(async move {
+                    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+                    let line = lines.next_line().await.ok().flatten();
+                    stdin_sink.send(line).await.unwrap();
+                });
+
+                // What the program writes comes back to the terminal, up to its end
+                let client = async {
+                    while let Some(write) = output.next().await {
+                        let Some((fd, msg)) = write else {
+                            return Some(());
+                        };
+                        if fd == 1 {
+                            print!("{}", msg);
+                            std::io::stdout().flush().unwrap();
+                        } else {
+                            eprint!("{}", msg);
+                            std::io::stderr().flush().unwrap();
+                        }
+                    }
+                    None
+                };
+                #completion
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+}
+
+impl Transformer for IOToHydroTransformer {
+    fn name(&self) -> &'static str {
+        "io"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            parses: true,
+            rewrites_output: true,
+            streams_stdin: self.config.stdin != StdinMode::Mock,
+            seeds_rng: false,
+            translations: Vec::new(),
+        }
+    }
+
+    fn transform_source(&self, source: &str, module_name: &str) -> Result<TransformOutput, TransformError> {
+        IOToHydroTransformer::transform_source(self, source, module_name)
+    }
+}
+
+/// The functions of the legacy file other than `main`, which the analyzer looks into
+/// where the body calls them
+struct Helpers<'f> {
+    functions: HashMap<String, &'f ItemFn>,
+    /// The helpers being looked into, which recursion doesn't enter again
+    visiting: Vec<String>,
+}
+
+impl<'f> Helpers<'f> {
+    fn of(items: &'f [Item]) -> Self {
+        let functions = items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Fn(func) if func.sig.ident != "main" => Some((func.sig.ident.to_string(), func)),
+                _ => None,
+            })
+            .collect();
+        Self { functions, visiting: Vec::new() }
+    }
+
+    /// The helper a call is to, unless it is already being looked into
+    fn enter(&mut self, func: &Expr) -> Option<&'f ItemFn> {
+        let Expr::Path(path) = func else {
+            return None;
+        };
+        let name = path.path.get_ident()?.to_string();
+        let helper = *self.functions.get(&name)?;
+        if self.visiting.contains(&name) {
+            return None;
+        }
+        self.visiting.push(name);
+        Some(helper)
+    }
+
+    fn leave(&mut self) {
+        self.visiting.pop();
+    }
+}
+
+/// The functions of the legacy file the body calls, directly or through each other,
+/// carried into the generated module. Those that print take the step's buffer of
+/// writes as an extra argument, so what they print keeps its place among the body's
+/// writes. A helper that logs, or that is used other than by calling it, prints
+/// directly instead.
+struct CarriedHelpers {
+    functions: Vec<ItemFn>,
+    /// The helpers taking the buffer
+    printing: HashSet<String>,
+}
+
+impl CarriedHelpers {
+    fn of(items: &[Item], stmts: &[Stmt]) -> Self {
+        let helpers = Helpers::of(items);
+        // Writes through stdout and stderr handles become prints, as in the body
+        let bodies: HashMap<String, Vec<Stmt>> = helpers
+            .functions
+            .iter()
+            .map(|(name, func)| (name.clone(), print_through_handles(&func.block.stmts)))
+            .collect();
+        let uses: HashMap<&String, HelperUses> = bodies.iter().map(|(name, stmts)| (name, HelperUses::of(stmts))).collect();
+        let body = HelperUses::of(stmts);
+
+        let mut reached = HashSet::new();
+        let mut pending: Vec<&String> = body.called.iter().chain(&body.named).collect();
+        while let Some(name) = pending.pop() {
+            if let Some(helper) = uses.get(name) {
+                if reached.insert(name.clone()) {
+                    pending.extend(helper.called.iter().chain(&helper.named));
+                }
+            }
+        }
+        let named: HashSet<&String> = body.named.iter().chain(reached.iter().flat_map(|name| &uses[name].named)).collect();
+        // The helpers that print, or call one that does
+        let mut printing = HashSet::new();
+        loop {
+            let grown: Vec<String> = reached
+                .iter()
+                .filter(|name| !printing.contains(*name) && !named.contains(name))
+                .filter(|name| {
+                    let helper = &uses[name];
+                    !helper.logs && (helper.writes || helper.called.iter().any(|called| printing.contains(called)))
+                })
+                .cloned()
+                .collect();
+            if grown.is_empty() {
+                break;
+            }
+            printing.extend(grown);
+        }
+
+        let functions = items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Fn(func) if reached.contains(&func.sig.ident.to_string()) => Some(func),
+                _ => None,
+            })
+            .map(|func| {
+                let name = func.sig.ident.to_string();
+                let mut func = func.clone();
+                func.block.stmts = bodies[&name].clone();
+                HelperCalls { printing: &printing, buffer: syn::parse_quote! { stdio_writes } }.visit_block_mut(&mut func.block);
+                if printing.contains(&name) {
+                    func.sig.inputs.push(syn::parse_quote! { stdio_writes: &mut Vec<(u8, String)> });
+                    OutputStreamRewriter::default().visit_block_mut(&mut func.block);
+                }
+                func
+            })
+            .collect();
+        Self { functions, printing }
+    }
+
+    /// Pass the step's buffer to the calls of the helpers that print
+    fn rewrite_calls(&self, stmts: &[Stmt]) -> Vec<Stmt> {
+        let mut calls = HelperCalls { printing: &self.printing, buffer: syn::parse_quote! { &mut stdio_writes } };
+        let mut stmts = stmts.to_vec();
+        stmts.iter_mut().for_each(|stmt| calls.visit_stmt_mut(stmt));
+        stmts
+    }
+
+    /// The generated module with the helpers after its function
+    fn append_to(&self, module: String) -> Result<String, Box<dyn std::error::Error>> {
+        if self.functions.is_empty() {
+            return Ok(module);
+        }
+        let mut file = syn::parse_file(&module)?;
+        file.items.extend(self.functions.iter().cloned().map(Item::Fn));
+        Ok(prettyplease::unparse(&file))
+    }
+}
+
+/// What a function body does that decides how a helper is carried
+#[derive(Default)]
+struct HelperUses {
+    /// The names called as functions
+    called: HashSet<String>,
+    /// The names used other than by calling them, including calls in macro arguments
+    named: HashSet<String>,
+    /// Whether it prints, writes with `dbg!` or panics
+    writes: bool,
+    logs: bool,
+    /// How many macros the visitor is in the arguments of
+    macros: usize,
+}
+
+impl HelperUses {
+    fn of(stmts: &[Stmt]) -> Self {
+        let mut uses = Self::default();
+        stmts.iter().for_each(|stmt| uses.visit_stmt(stmt));
+        uses
+    }
+}
+
+impl<'ast> Visit<'ast> for HelperUses {
+    fn visit_expr_call(&mut self, call: &'ast ExprCall) {
+        match &*call.func {
+            Expr::Path(path) if self.macros == 0 && path.path.get_ident().is_some() => {
+                self.called.insert(path.path.segments[0].ident.to_string());
+            }
+            func => self.visit_expr(func),
+        }
+        call.args.iter().for_each(|arg| self.visit_expr(arg));
+    }
+
+    fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+        if let Some(ident) = path.path.get_ident() {
+            self.named.insert(ident.to_string());
+        }
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        self.writes |= PrintMacro::parse(mac).is_some() || DiagnosticMacro::parse(mac).is_some();
+        self.logs |= LogMacro::parse(mac).is_some();
+        if let Ok(args) = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated) {
+            self.macros += 1;
+            args.iter().for_each(|arg| self.visit_expr(arg));
+            self.macros -= 1;
+        }
+    }
+}
+
+/// Appends `buffer` to the arguments of calls to the helpers that print
+struct HelperCalls<'p> {
+    printing: &'p HashSet<String>,
+    buffer: Expr,
+}
+
+impl VisitMut for HelperCalls<'_> {
+    fn visit_expr_call_mut(&mut self, call: &mut ExprCall) {
+        visit_mut::visit_expr_call_mut(self, call);
+        if let Expr::Path(path) = &*call.func {
+            if path.path.get_ident().is_some_and(|ident| self.printing.contains(&ident.to_string())) {
+                call.args.push(self.buffer.clone());
+            }
+        }
+    }
+}
+
+/// How a legacy program's stdin usage is mapped onto the generated dataflow
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StdinSource {
+    /// The program never reads stdin
+    None,
+    /// A single `read_line`, served by the first line of the process's stdin
+    FirstLine,
+    /// A `lines()` loop or a `read_line` in a loop, served by every line of the
+    /// process's stdin up to EOF
+    AllLines,
+    /// Line-by-line reading mixed with other reads, still served by canned sample lines
+    MockLines,
+    /// Any other stdin read, still served by a canned sample input
+    MockLine,
+    /// A single `read_line`, served by a line the example's client sends through an
+    /// external port, with the output sent back to the client
+    Client,
+}
+
+impl StdinSource {
+    fn for_operations(io_operations: &[IOOperation], mode: StdinMode) -> Self {
+        let source = Self::detected(io_operations);
+        match (mode, source) {
+            (StdinMode::Mock, StdinSource::FirstLine) => StdinSource::MockLine,
+            (StdinMode::Mock, StdinSource::AllLines) => StdinSource::MockLines,
+            // Log records stay on the process, with their &'static str levels
+            (StdinMode::External, StdinSource::FirstLine)
+                if !io_operations.iter().any(|op| matches!(op.operation_type, IOOperationType::Log(_))) =>
+            {
+                StdinSource::Client
+            }
+            (_, source) => source,
+        }
+    }
+
+    /// The source the reads of a program can be served from
+    fn detected(io_operations: &[IOOperation]) -> Self {
+        let count = |operation_type: IOOperationType| {
+            io_operations.iter().filter(|op| op.operation_type == operation_type).count()
+        };
+        let (lines, looped) = (count(IOOperationType::StdinLines), count(IOOperationType::StdinReadLineLoop));
+        if lines > 0 || looped > 0 {
+            // The lines are read either by lines() loops or by read_line in loops
+            if count(IOOperationType::StdinReadLine) == 0 && (lines == 0 || looped == 0) {
+                StdinSource::AllLines
+            } else {
+                StdinSource::MockLines
+            }
+        } else if count(IOOperationType::StdinReadLine) == 1 {
+            StdinSource::FirstLine
+        } else if count(IOOperationType::StdinRead) + count(IOOperationType::StdinReadLine) > 0 {
+            StdinSource::MockLine
+        } else {
+            StdinSource::None
+        }
+    }
+}
+
+/// Rewrites a legacy body's stdin reads into reading the element of the stdin source:
+/// a single `read_line` reads `stdin_line`, and a `lines()` loop iterates `stdin_lines`.
+/// The stdin handle bindings are dropped as they are no longer needed.
+#[derive(Default)]
+struct StdinRewriter {
+    handles: Vec<String>,
+    /// Whether each `read_line` takes the next of `stdin_lines`, as one in a loop does
+    pulls: bool,
+    /// Whether a `read_line` was rewritten to take the next of `stdin_lines`
+    pulled: bool,
+}
+
+impl StdinRewriter {
+    fn rewrite(stmts: &[Stmt]) -> Vec<Stmt> {
+        Self::default().rewrite_stmts(stmts)
+    }
+
+    /// Rewrite a body served by every line of stdin, where each `read_line` takes the
+    /// next line from an iterator over them
+    fn rewrite_lines(stmts: &[Stmt]) -> Vec<Stmt> {
+        let mut rewriter = Self { pulls: true, ..Self::default() };
+        let mut rewritten = rewriter.rewrite_stmts(stmts);
+        if rewriter.pulled {
+            rewritten.insert(0, syn::parse_quote! { let mut stdin_lines = stdin_lines.into_iter(); });
+        }
+        rewritten
+    }
+
+    fn rewrite_stmts(&mut self, stmts: &[Stmt]) -> Vec<Stmt> {
+        let mut rewritten = Vec::new();
+        for stmt in stmts {
+            if self.binds_stdin_handle(stmt) {
+                continue;
+            }
+            let mut stmt = stmt.clone();
+            self.visit_stmt_mut(&mut stmt);
+            rewritten.push(stmt);
+        }
+        rewritten
+    }
+
+    /// `let stdin = io::stdin();` or `let handle = stdin.lock();`, recording the binding
+    fn binds_stdin_handle(&mut self, stmt: &Stmt) -> bool {
+        let Stmt::Local(local) = stmt else {
+            return false;
+        };
+        let (Pat::Ident(PatIdent { ident, .. }), Some(init)) = (&local.pat, &local.init) else {
+            return false;
+        };
+        if self.is_stdin_handle(&init.expr) {
+            self.handles.push(ident.to_string());
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_stdin_handle(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Path(path) => path.path.get_ident().is_some_and(|ident| self.handles.iter().any(|h| ident == h)),
+            Expr::Call(call) => call.func.to_token_stream().to_string().ends_with("stdin"),
+            Expr::MethodCall(call) => call.method == "lock" && self.is_stdin_handle(&call.receiver),
+            _ => false,
+        }
+    }
+}
+
+impl VisitMut for StdinRewriter {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::MethodCall(call) = expr {
+            if call.method == "read_line" && call.args.len() == 1 && self.is_stdin_handle(&call.receiver) {
+                let buffer = match &call.args[0] {
+                    Expr::Reference(reference) => (*reference.expr).clone(),
+                    other => other.clone(),
+                };
+                let buffer: Expr = match buffer {
+                    Expr::Path(_) => buffer,
+                    other => syn::parse_quote! { (#other) },
+                };
+                // read_line appends to the buffer and returns the number of bytes read
+                *expr = if self.pulls {
+                    self.pulled = true;
+                    // The lines of the source lost their newline, and EOF reads nothing
+                    syn::parse_quote! {
+                        match stdin_lines.next() {
+                            Some(Ok(line)) => {
+                                #buffer.push_str(&line);
+                                #buffer.push('\n');
+                                Ok(line.len() + 1)
+                            }
+                            Some(Err(error)) => Err(error),
+                            None => Ok(0),
+                        }
+                    }
+                } else {
+                    syn::parse_quote! {
+                        stdin_line.map(|line| {
+                            #buffer.push_str(&line);
+                            line.len()
+                        })
+                    }
+                };
+                return;
+            }
+            if call.method == "lines" && call.args.is_empty() && self.is_stdin_handle(&call.receiver) {
+                *expr = syn::parse_quote! { stdin_lines.into_iter() };
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// A loop over the lines of stdin that parses them as JSON or jumps out of its body,
+/// and so has to see the lines one at a time rather than all at once
+enum StdinLinesLoop {
+    /// The body deserializes each line, with records and parse errors handled by
+    /// streams of their own
+    Json(Box<JsonLinesLoop>),
+    /// The body matches on each line, with each arm handled by a stream of its own
+    Demux(Box<MatchDemux>),
+    Controlled(Box<ControlledLoop>),
+}
+
+impl StdinLinesLoop {
+    /// `fallible_main` says whether `main` returns `Result`, which a body using `?`
+    /// needs, and whose final `Ok(())` the separate streams of a demux can't return
+    fn detect(stmts: &[Stmt], fallible_main: bool) -> Option<Self> {
+        let stmts = StdinRewriter::rewrite(stmts);
+        // Log records are only carried by the steps of a once-run or paced body
+        if has_log_records(&stmts) {
+            return None;
+        }
+        let stdin_lines: Expr = syn::parse_quote! { stdin_lines.into_iter() };
+        let reads_stdin = |items: &Expr, prefix: &[Stmt], suffix: &[Stmt]| {
+            *items == stdin_lines && !mentions(&[prefix, suffix].concat(), "stdin_lines")
+        };
+        if let Some(json) = JsonLinesLoop::detect(&stmts) {
+            if !fallible_main && reads_stdin(&json.items, &json.prefix, &json.suffix) {
+                return Some(StdinLinesLoop::Json(Box::new(json)));
+            }
+        }
+        let controlled = ControlledLoop::detect(&stmts)?;
+        if !reads_stdin(&controlled.items, &controlled.prefix, &controlled.suffix) || (controlled.fallible && !fallible_main) {
+            return None;
+        }
+        match MatchDemux::detect(&stmts).filter(|_| !fallible_main) {
+            Some(demux) => Some(StdinLinesLoop::Demux(Box::new(demux))),
+            None => Some(StdinLinesLoop::Controlled(Box::new(controlled))),
+        }
+    }
+}
+
+/// A `for` loop paced by a `thread::sleep` at the top of its body, along with the
+/// statements around it
+struct PacedLoop {
+    prefix: Vec<Stmt>,
+    pat: Pat,
+    items: Expr,
+    period: Expr,
+    body: Vec<Stmt>,
+    suffix: Vec<Stmt>,
+}
+
+impl PacedLoop {
+    /// Find the paced loop of a legacy body. Each step of the generated dataflow runs
+    /// independently, so the pattern is only matched when no state is carried between
+    /// steps: no bindings before the loop, and no `break`/`continue` inside it.
+    fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop, period) = stmts.iter().enumerate().find_map(|(index, stmt)| {
+            let Stmt::Expr(Expr::ForLoop(for_loop), _) = stmt else {
+                return None;
+            };
+            let Some(Stmt::Expr(Expr::Call(call), _)) = for_loop.body.stmts.first() else {
+                return None;
+            };
+            let func = call.func.to_token_stream().to_string();
+            let is_sleep = func == "sleep" || func.ends_with("thread :: sleep");
+            (is_sleep && call.args.len() == 1).then(|| (index, for_loop, call.args[0].clone()))
+        })?;
+
+        let prefix = &stmts[..index];
+        if for_loop.label.is_some() || prefix.iter().any(|stmt| matches!(stmt, Stmt::Local(_))) {
+            return None;
+        }
+        // Each step runs on its own, so the iterations may have effects but share nothing
+        let analysis = LoopAnalysis::of_loop(&[], stmts, index)?;
+        if analysis.kind != LoopKind::EffectOnly || analysis.skips {
+            return None;
+        }
+        let body = for_loop.body.stmts[1..].to_vec();
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            period,
+            body,
+            suffix: stmts[index + 1..].to_vec(),
+        })
+    }
+}
+
+/// Rewrites print macros in the legacy body into pushes of the formatted message,
+/// tagged with its file descriptor, onto a single buffer of writes in program order.
+/// Logging macros push their level and message onto a buffer of log records instead.
+#[derive(Default)]
+struct OutputStreamRewriter {
+    writes: bool,
+    /// Whether the rewritten statements return `Err` from their step when they fail
+    fails: bool,
+    /// Whether the rewritten statements push onto `log_records`
+    logs: bool,
+}
+
+impl OutputStreamRewriter {
+    fn rewrite_macro(&mut self, mac: &syn::Macro) -> Option<Expr> {
+        if let Some(diagnostic) = DiagnosticMacro::parse(mac) {
+            return self.rewrite_diagnostic(mac, diagnostic);
+        }
+        if let Some(log) = LogMacro::parse(mac) {
+            self.logs = true;
+            let level = log.level.as_str();
+            let message = format_message(mac);
+            return syn::parse2(quote! { log_records.push((#level, #message)) }).ok();
+        }
+        let print = PrintMacro::parse(mac)?;
+        self.writes = true;
+
+        let fd: u8 = match print.channel {
+            OutputChannel::Stdout => 1,
+            OutputChannel::Stderr => 2,
+        };
+        let message = format_message(mac);
+        let message = if print.newline {
+            quote! { #message + "\n" }
+        } else {
+            message
+        };
+        syn::parse2(quote! { stdio_writes.push((#fd, #message)) }).ok()
+    }
+
+    /// `dbg!` pushes what it prints onto the buffer as a write to stderr. A panic, or a
+    /// failed assertion, first prints the writes made so far and then panics with the
+    /// same message, which ends the dataflow as it ended the legacy program.
+    fn rewrite_diagnostic(&mut self, mac: &syn::Macro, diagnostic: DiagnosticMacro) -> Option<Expr> {
+        let args = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated).ok()?;
+        self.writes = true;
+        let sink = IOToHydroTransformer::stdio_sink();
+        let flush = quote! { stdio_writes.drain(..).for_each(#sink); };
+        let message = |skip: usize| {
+            let rest: Vec<&Expr> = args.iter().skip(skip).collect();
+            if rest.is_empty() {
+                quote! { String::new() }
+            } else {
+                quote! { format!(": {}", format_args!(#(#rest),*)) }
+            }
+        };
+        let rewritten = match diagnostic {
+            DiagnosticMacro::Dbg => {
+                let location = quote! { file!(), line!(), column!() };
+                let each: Vec<TokenStream> = args
+                    .iter()
+                    .map(|arg| {
+                        quote! {
+                            match #arg {
+                                tmp => {
+                                    stdio_writes.push((2u8, format!("[{}:{}:{}] {} = {:#?}\n", #location, stringify!(#arg), &tmp)));
+                                    tmp
+                                }
+                            }
+                        }
+                    })
+                    .collect();
+                match each.as_slice() {
+                    [] => quote! { stdio_writes.push((2u8, format!("[{}:{}:{}]\n", #location))) },
+                    [single] => single.clone(),
+                    _ => quote! { (#(#each),*) },
+                }
+            }
+            DiagnosticMacro::Panic => {
+                let tokens = &mac.tokens;
+                quote! {
+                    {
+                        #flush
+                        panic!(#tokens)
+                    }
+                }
+            }
+            DiagnosticMacro::Assert => {
+                let cond = args.first()?;
+                let rest: Vec<&Expr> = args.iter().skip(1).collect();
+                let panic = if rest.is_empty() {
+                    quote! { panic!(concat!("assertion failed: ", stringify!(#cond))) }
+                } else {
+                    quote! { panic!(#(#rest),*) }
+                };
+                quote! {
+                    if !(#cond) {
+                        #flush
+                        #panic
+                    }
+                }
+            }
+            DiagnosticMacro::AssertEq | DiagnosticMacro::AssertNe => {
+                let (left, right) = (args.first()?, args.get(1)?);
+                let (op, holds) = if diagnostic == DiagnosticMacro::AssertEq {
+                    ("==", quote! { *left_val == *right_val })
+                } else {
+                    ("!=", quote! { *left_val != *right_val })
+                };
+                let header = format!("assertion `left {} right` failed{{}}\n  left: {{:?}}\n right: {{:?}}", op);
+                let message = message(2);
+                quote! {
+                    match (&(#left), &(#right)) {
+                        (left_val, right_val) => {
+                            if !(#holds) {
+                                #flush
+                                panic!(#header, #message, left_val, right_val)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        syn::parse2(rewritten).ok()
+    }
+
+    /// Declare the buffer, only mutable if the body actually writes to it, and the
+    /// buffer of log records if the body logs
+    fn buffer_decl(&self) -> TokenStream {
+        let writes = if self.writes {
+            quote! { let mut stdio_writes: Vec<(u8, String)> = Vec::new(); }
+        } else {
+            quote! { let stdio_writes: Vec<(u8, String)> = Vec::new(); }
+        };
+        if self.logs {
+            quote! {
+                #writes
+                let mut log_records: Vec<(&'static str, String)> = Vec::new();
+            }
+        } else {
+            writes
+        }
+    }
+
+    /// The buffers a step hands back: its writes, paired with its log records if any
+    fn buffers(&self) -> TokenStream {
+        if self.logs {
+            quote! { (stdio_writes, log_records) }
+        } else {
+            quote! { stdio_writes }
+        }
+    }
+}
+
+impl VisitMut for OutputStreamRewriter {
+    fn visit_block_mut(&mut self, block: &mut syn::Block) {
+        block.stmts.retain(|stmt| !is_std_flush(stmt));
+        visit_mut::visit_block_mut(self, block);
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Macro(stmt_macro) = stmt {
+            if let Some(push) = self.rewrite_macro(&stmt_macro.mac) {
+                *stmt = Stmt::Expr(push, Some(stmt_macro.semi_token.unwrap_or_default()));
+                return;
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(ExprMacro { mac, .. }) = expr {
+            if let Some(push) = self.rewrite_macro(mac) {
+                *expr = push;
+                return;
+            }
+        }
+        // The sink flushes after every write, so flushing in the step has nothing to do
+        if let Expr::MethodCall(call) = expr {
+            if call.method == "flush" && call.args.is_empty() && is_std_stream(&call.receiver) {
+                *expr = syn::parse_quote! { Ok::<(), std::io::Error>(()) };
+                return;
+            }
+        }
+        // The buffer handed to a helper that prints
+        if let Expr::Reference(reference) = expr {
+            if reference.mutability.is_some() && matches!(&*reference.expr, Expr::Path(path) if path.path.is_ident("stdio_writes")) {
+                self.writes = true;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// A statement only flushing stdout or stderr, like `io::stdout().flush().unwrap();`
+fn is_std_flush(stmt: &Stmt) -> bool {
+    let Stmt::Expr(expr, Some(_)) = stmt else {
+        return false;
+    };
+    let expr = match expr {
+        Expr::MethodCall(call) if call.method == "unwrap" || call.method == "expect" => &*call.receiver,
+        Expr::Try(try_expr) => &*try_expr.expr,
+        expr => expr,
+    };
+    matches!(expr, Expr::MethodCall(call) if call.method == "flush" && call.args.is_empty() && is_std_stream(&call.receiver))
+}
+
+/// `io::stdout()` or `io::stderr()`, however the path to it is written
+fn is_std_stream(expr: &Expr) -> bool {
+    let Expr::Call(call) = expr else {
+        return false;
+    };
+    call.args.is_empty()
+        && matches!(&*call.func, Expr::Path(func) if func.path.segments.last().is_some_and(|last| last.ident == "stdout" || last.ident == "stderr"))
+}
+
+impl Default for IOToHydroTransformer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_interactive_hello_transformation() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    println!("What's your name?");
+    
+    let stdin = io::stdin();
+    let mut handle = stdin.lock();
+    let mut name = String::new();
+    
+    match handle.read_line(&mut name) {{
+        Ok(_) => {{
+            let name = name.trim();
+            println!("Hello, {{}}!", name);
+        }}
+        Err(error) => {{
+            eprintln!("Error reading input: {{}}", error);
+        }}
+    }}
+}}
+"#).unwrap();
+
+        let transformer = IOToHydroTransformer::new();
+        let result = transformer.transform_program(temp_file.path(), "test_interactive");
+        
         assert!(result.is_ok());
-        let (hydro_fn, example) = result.unwrap();
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = result.unwrap();
         
         // Check that the generated function contains our expected I/O structure
         assert!(hydro_fn.contains("pub fn test_interactive"));
+        assert!(hydro_fn.contains("source_stream"));
+        assert!(hydro_fn.contains("map"));
+        
+        // Check that the example contains deployment code
+        assert!(example.contains("Deployment::new"));
+        assert!(example.contains("test_interactive"));
+        assert!(example.contains("tracing::info!(event = \"deploy_started\", stdin = \"forwarded\""));
+    }
+
+    #[test]
+    fn test_output_keeps_interleaving_across_channels() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, Write}};
+
+fn main() {{
+    print!("Processing");
+    io::stdout().flush().unwrap();
+    for i in 1..=3 {{
+        eprint!(".");
+        print!("{{}}", i);
+    }}
+    println!();
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_channels")
+            .unwrap();
+        // Quoted closure bodies aren't reformatted, so compare without whitespace
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // Print macros become pushes onto one buffer, tagged with their channel's fd
+        assert!(!compact.contains("print!(\"Processing\")"));
+        assert!(compact.contains("stdio_writes.push((1u8,format!(\"Processing\")))"));
+        assert!(compact.contains("stdio_writes.push((2u8,format!(\".\")));stdio_writes.push((1u8,format!(\"{}\",i)))"));
+        assert!(compact.contains("stdio_writes.push((1u8,String::new()+\"\\n\"))"));
+        // The sink flushes each write, so the body's flush goes
+        assert!(compact.contains("stdio_writes.push((1u8,format!(\"Processing\")));foriin1..=3"));
+
+        // A single sink demultiplexes the writes back onto stdout and stderr
+        assert_eq!(compact.matches(".for_each(").count(), 1);
+        assert!(compact.contains("iffd==1{print!(\"{}\",msg)"));
+        assert!(compact.contains("eprint!(\"{}\",msg)"));
+        assert!(compact.contains("send_bincode_external"));
+    }
+
+    #[test]
+    fn test_in_memory_source_needs_no_file() {
+        let source = "use std::io;\n\nfn main() {\n    let mut name = String::new();\n    io::stdin().read_line(&mut name).unwrap();\n    println!(\"Hello, {}!\", name.trim());\n}\n";
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(temp_file, "{}", source).unwrap();
+
+        let transformer = IOToHydroTransformer::new();
+        let generated = |output: TransformOutput| (output.hydro_function, output.example_program);
+        let from_file = generated(transformer.transform_program(temp_file.path(), "test_greeting").unwrap());
+        assert_eq!(generated(transformer.transform_source(source, "test_greeting").unwrap()), from_file);
+        let file = parse_file(source).unwrap();
+        assert_eq!(generated(transformer.transform_file(&file, "test_greeting").unwrap()), from_file);
+    }
+
+    #[test]
+    fn test_locked_stdout_writes_join_the_stdout_stream() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufWriter, Write}};
+
+fn main() -> io::Result<()> {{
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for i in 1..=3 {{
+        writeln!(out, "row {{}}", i)?;
+    }}
+    out.flush()?;
+    Ok(())
+}}
+"#).unwrap();
+
+        let source = std::fs::read_to_string(temp_file.path()).unwrap();
+        let file = parse_file(&source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let body = transformer.extract_function_body(transformer.extract_main_function(&file).unwrap()).unwrap();
+        let found: Vec<IOOperationType> = transformer.analyze_io_operations(&body).into_iter().map(|op| op.operation_type).collect();
+        assert_eq!(found, [IOOperationType::StdoutHandle, IOOperationType::StdoutHandle, IOOperationType::StdoutWrite, IOOperationType::StdoutFlush]);
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = transformer.transform_program(temp_file.path(), "test_locked").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The writes are pushed like the println! they amount to, and the handle is gone
+        assert!(compact.contains("stdio_writes.push((1u8,format!(\"row{}\",i)+\"\\n\"))"));
+        assert!(!compact.contains("lock()"));
+        assert!(!compact.contains("out.flush()"));
+    }
+
+    #[test]
+    fn test_io_in_closures_and_helpers_is_found() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn banner(title: &str) {{
+    println!("== {{}} ==", title);
+}}
+
+fn report(values: &[u32]) {{
+    banner("values");
+    values.iter().for_each(|v| eprintln!("{{}}", v));
+}}
+
+fn square(x: u32) -> u32 {{
+    x * x
+}}
+
+fn main() {{
+    let values: Vec<u32> = (1..=3).map(square).collect();
+    report(&values);
+    println!("done");
+}}
+"#).unwrap();
+
+        let source = std::fs::read_to_string(temp_file.path()).unwrap();
+        let file = parse_file(&source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let body = transformer.extract_function_body(transformer.extract_main_function(&file).unwrap()).unwrap();
+        let found: Vec<IOOperationType> = transformer.analyze_io_operations_in(&file.items, &body).into_iter().map(|op| op.operation_type).collect();
+        assert_eq!(found, [IOOperationType::StdoutPrintln, IOOperationType::StderrEprintln, IOOperationType::StdoutPrintln]);
+        // The banner is reported at its own line
+        let first = &transformer.analyze_io_operations_in(&file.items, &body)[0];
+        assert_eq!(first.line_number, Some(3));
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = transformer.transform_program(temp_file.path(), "test_helpers").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The helpers that print write to the step's buffer, which the body hands them
+        assert!(compact.contains("report(&values,&mutstdio_writes);"));
+        assert!(compact.contains("fnreport(values:&[u32],stdio_writes:&mutVec<(u8,String)>)"));
+        assert!(compact.contains("banner(\"values\",stdio_writes);"));
+        assert!(compact.contains("values.iter().for_each(|v|stdio_writes.push((2u8,format!(\"{}\",v)+\"\\n\")));"));
+        // Helpers that don't print are carried as they are
+        assert!(compact.contains("fnsquare(x:u32)->u32"));
+    }
+
+    #[test]
+    fn test_dbg_and_panics_keep_their_output() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let values = vec![3, 1, 2];
+    let total: i32 = dbg!(values.iter().sum());
+    println!("total {{}}", total);
+    assert_eq!(total, 6, "sum of {{:?}}", values);
+    assert!(total > 0);
+    if values.is_empty() {{
+        panic!("no values");
+    }}
+}}
+"#).unwrap();
+
+        let source = std::fs::read_to_string(temp_file.path()).unwrap();
+        let file = parse_file(&source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let body = transformer.extract_function_body(transformer.extract_main_function(&file).unwrap()).unwrap();
+        let found: Vec<IOOperationType> = transformer.analyze_io_operations(&body).into_iter().map(|op| op.operation_type).collect();
+        assert_eq!(found, [
+            IOOperationType::StderrDbg,
+            IOOperationType::StdoutPrintln,
+            IOOperationType::Assert,
+            IOOperationType::Assert,
+            IOOperationType::Panic,
+        ]);
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = transformer.transform_program(temp_file.path(), "test_diagnostics").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // dbg! writes to stderr in its place among the writes, and still returns its value
+        assert!(compact.contains("lettotal:i32=matchvalues.iter().sum(){tmp=>{stdio_writes.push((2u8,format!(\"[{}:{}:{}]{}={:#?}\\n\",file!(),line!(),column!(),stringify!(values.iter().sum()),&tmp),));tmp}};"));
+        // A failed assertion or a panic prints what was written before it, then panics
+        assert!(compact.contains("match(&(total),&(6)){(left_val,right_val)=>{if!(*left_val==*right_val){stdio_writes.drain(..).for_each(|(fd,msg)|"));
+        assert!(compact.contains("panic!(\"assertion`left==right`failed{}\\nleft:{:?}\\nright:{:?}\",format!(\":{}\",format_args!(\"sumof{:?}\",values)),left_val,right_val)"));
+        assert!(compact.contains("if!(total>0){stdio_writes.drain(..)"));
+        assert!(compact.contains("panic!(concat!(\"assertionfailed:\",stringify!(total>0)))"));
+        assert!(compact.contains("panic!(\"novalues\")"));
+        assert!(compact.contains("letmutstdio_writes"));
+    }
+
+    #[test]
+    fn test_mock_stdin_mode_serves_sample_input() {
+        let source = "use std::io;\n\nfn main() {\n    let mut name = String::new();\n    io::stdin().read_line(&mut name).unwrap();\n    println!(\"Hello, {}!\", name.trim());\n}\n";
+        let transformer = IOToHydroTransformer::new().with_config(TransformerConfig::new().with_stdin(StdinMode::Mock));
+        assert!(!transformer.capabilities().streams_stdin);
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } =
+            transformer.transform_source(source, "test_greeting").unwrap();
+        assert!(!hydro_fn.contains("tokio::io::stdin()"));
         assert!(hydro_fn.contains("source_iter"));
-        assert!(hydro_fn.contains("map"));
-        
-        // Check that the example contains deployment code
-        assert!(example.contains("Deployment::new"));
-        assert!(example.contains("test_interactive"));
-        assert!(example.contains("I/O-aware"));
+        assert!(!example.contains("process_stdin"));
+        assert!(example.contains("stdin = \"mocked\""));
+
+        // The sample lines are defined in the quoted source rather than captured from the builder
+        let source = "use std::io::{self, BufRead};\n\nfn main() {\n    for line in io::stdin().lock().lines() {\n        println!(\"{}\", line.unwrap());\n    }\n}\n";
+        let hydro_fn = transformer.transform_source(source, "test_echo").unwrap().hydro_function;
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("q!({letstdin_lines=vec![\"Alice\".to_string(),\"Bob\".to_string(),\"Charlie\".to_string()];stdin_lines.into_iter()}"));
+        assert!(!compact.contains("ExternalBincodeStream<()>{letstdin_lines"));
+    }
+
+    #[test]
+    fn test_single_read_line_takes_first_stdin_line() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    let stdin = io::stdin();
+    let mut handle = stdin.lock();
+    let mut name = String::new();
+    handle.read_line(&mut name).unwrap();
+    println!("Hello, {{}}!", name.trim());
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_read_line")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The first line of the real stdin feeds the legacy body, with no canned input
+        assert!(!hydro_fn.contains("Alice"));
+        assert!(compact.contains(".source_stream("));
+        assert!(compact.contains(".take(1)"));
+        assert!(compact.contains("|stdin_line|"));
+        assert!(compact.contains("stdin_line.map(|line|{name.push_str(&line);line.len()}).unwrap();"));
+        // The stdin handles are no longer needed
+        assert!(!compact.contains("stdin.lock()"));
+
+        assert!(example.contains("process_stdin"));
+        assert!(!example.contains("mocked"));
+    }
+
+    #[test]
+    fn test_split_target_separates_terminal_from_logic() {
+        let source = r#"
+use std::io::{self, BufRead};
+
+fn main() {
+    println!("What's your name?");
+    let mut name = String::new();
+    io::stdin().lock().read_line(&mut name).unwrap();
+    println!("Hello, {}!", name.trim());
+}
+"#;
+        let config = TransformerConfig::new().with_target(Target::Split);
+        let output = IOToHydroTransformer::new().with_config(config).transform_source(source, "test_split").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // The UI process reads the line and prints, and the logic process runs the body
+        assert!(compact.contains("pubstructUi{}"));
+        assert!(compact.contains("pubfntest_split<'a>(ui:&Process<'a,Ui>,logic:&Process<'a,Logic>,external:&ExternalProcess<'a,()>,)"));
+        assert!(compact.contains("ui.source_stream("));
+        assert!(compact.contains(".map(q!(|line|line.map_err(|error|error.to_string()))).send_bincode(logic)"));
+        assert!(compact.contains("letstdin_line=stdin_line.map_err(std::io::Error::other);"));
+        assert!(compact.contains(".send_bincode(ui)"));
+        assert!(!compact.contains("(process:"));
+
+        let example: String = output.example_program.split_whitespace().collect();
+        assert!(example.contains("letui=flow.process::<hydro_template::test_split::Ui>();"));
+        assert!(example.contains("test_split::test_split(&ui,&logic,&external)"));
+        assert!(example.contains("nodes.get_process(&ui)"));
+
+        // Programs that aren't interactive stay on a single process
+        let output = IOToHydroTransformer::new()
+            .with_config(TransformerConfig::new().with_target(Target::Split))
+            .transform_source("fn main() {\n    println!(\"hi\");\n}\n", "test_unsplit")
+            .unwrap();
+        assert!(output.hydro_function.contains("process: &Process<'a>"));
+    }
+
+    #[test]
+    fn test_external_stdin_serves_a_client_through_ports() {
+        let source = r#"
+use std::io::{self, BufRead};
+
+fn main() {
+    println!("What's your name?");
+    let mut name = String::new();
+    io::stdin().lock().read_line(&mut name).unwrap();
+    println!("Hello, {}!", name.trim());
+}
+"#;
+        let config = TransformerConfig::new().with_stdin(StdinMode::External);
+        let output = IOToHydroTransformer::new().with_config(config.clone()).transform_source(source, "test_client").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // The line arrives through a port, and the writes leave through another
+        assert!(compact.contains("->(ExternalBincodeSink<Option<String>>,ExternalBincodeStream<Option<(u8,String)>>)"));
+        assert!(compact.contains("let(stdin_port,stdin)=process.source_external_bincode(external);"));
+        assert!(compact.contains("line.map_or_else(String::new,|line|line+\"\\n\")"));
+        assert!(compact.contains("stdio_writes.into_iter().map(Some).chain(std::iter::once(None))"));
+        assert!(!compact.contains("tokio::io::stdin()"));
+
+        let example: String = output.example_program.split_whitespace().collect();
+        assert!(example.contains("nodes.connect_sink_bincode(stdin_port)"));
+        assert!(example.contains("stdin_sink.send(line)"));
+        assert!(example.contains("letSome((fd,msg))=writeelse{returnSome(());};"));
+
+        // Other reads of stdin are served as from the process's own stdin
+        let lines = "use std::io::{self, BufRead};\n\nfn main() {\n    for line in io::stdin().lock().lines() {\n        println!(\"{}\", line.unwrap());\n    }\n}\n";
+        let output = IOToHydroTransformer::new().with_config(config).transform_source(lines, "test_lines").unwrap();
+        assert!(output.hydro_function.contains("tokio::io::stdin()"));
+        assert!(output.example_program.contains("process_stdin"));
+    }
+
+    #[test]
+    fn test_lines_loop_reads_stdin_until_eof() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {{
+        println!("Echo: {{}}", line.unwrap());
+    }}
+    println!("Done processing input.");
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_lines")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The source ends at EOF instead of serving canned lines
+        assert!(!hydro_fn.contains("Alice"));
+        assert!(compact.contains("Ok(None)=>break"));
+        // The legacy loop iterates the lines read, and the code after it still runs
+        assert!(compact.contains("|stdin_lines|"));
+        assert!(compact.contains("forlineinstdin_lines.into_iter()"));
+        assert!(compact.contains("stdio_writes.push((1u8,format!(\"Doneprocessinginput.\")+\"\\n\"))"));
+
+        assert!(example.contains("process_stdin"));
+        assert!(!example.contains("mocked"));
+    }
+
+    #[test]
+    fn test_while_read_line_echo_reads_every_line() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    let stdin = io::stdin();
+    let mut handle = stdin.lock();
+    let mut buf = String::new();
+    while let Ok(n) = handle.read_line(&mut buf) {{
+        if n == 0 {{
+            break;
+        }}
+        print!("echo: {{}}", buf);
+        buf.clear();
+    }}
+    loop {{
+        eprintln!("bye");
+        break;
+    }}
+}}
+"#).unwrap();
+
+        let source = std::fs::read_to_string(temp_file.path()).unwrap();
+        let file = parse_file(&source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let body = transformer.extract_function_body(transformer.extract_main_function(&file).unwrap()).unwrap();
+        let found: Vec<IOOperationType> = transformer.analyze_io_operations(&body).into_iter().map(|op| op.operation_type).collect();
+        assert_eq!(found, [
+            IOOperationType::StdinRead,
+            IOOperationType::StdinRead,
+            IOOperationType::StdinReadLineLoop,
+            IOOperationType::StdoutPrint,
+            IOOperationType::StderrEprintln,
+        ]);
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = transformer.transform_program(temp_file.path(), "test_echo").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // Each read_line takes the next line of stdin, up to EOF
+        assert!(!hydro_fn.contains("Alice"));
+        assert!(compact.contains("letmutstdin_lines=stdin_lines.into_iter();"));
+        assert!(compact.contains("whileletOk(n)=matchstdin_lines.next(){"));
+        assert!(compact.contains("None=>Ok(0),"));
+        assert!(!compact.contains("handle.read_line"));
+        assert!(example.contains("process_stdin"));
+    }
+
+    #[test]
+    fn test_lines_loop_jumps_become_stream_control() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    let stdin = io::stdin();
+    let mut count = 0;
+    for line in stdin.lock().lines() {{
+        if line.as_ref().is_ok_and(|text| text.is_empty()) {{
+            continue;
+        }}
+        let text = line.unwrap();
+        if text == "quit" {{
+            break;
+        }}
+        if text == "abort" {{
+            return;
+        }}
+        count += 1;
+        println!("{{}}: {{}}", count, text);
+    }}
+    println!("Saw {{}} lines", count);
+}}
+"#).unwrap();
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_jumps")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // Lines stream in one at a time, ending with None at EOF
+        assert!(compact.contains(".map(Some).chain(tokio_stream::once(None))"));
+        // continue -> filter
+        assert!(compact.contains("Some(line)ifline.as_ref().is_ok_and(|text|text.is_empty())=>None"));
+        // break -> short-circuit that still runs the code after the loop
+        assert!(compact.contains("iftext==\"quit\"{break'iterationstd::ops::ControlFlow::Break(true);}"));
+        assert!(compact.contains("ifcompleted{stdio_writes.push((1u8,format!(\"Saw{}lines\",count)+\"\\n\"));}"));
+        // return -> the stream's output ends without it
+        assert!(compact.contains("iftext==\"abort\"{break'iterationstd::ops::ControlFlow::Break(false);}"));
+        // The count before the loop is state of the per-line map
+        assert!(compact.contains("letmutcount=0;"));
+        assert!(compact.contains("if!stopped"));
+        assert!(compact.contains("filter_map(q!(|(_,finished)|iffinished{Some(())}else{None}))"));
+    }
+
+    #[test]
+    fn test_match_on_lines_demuxes_into_branch_streams() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() {{
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {{
+        match line {{
+            Ok(text) => println!("Echo: {{}}", text),
+            Err(error) => {{
+                eprintln!("Error reading line: {{}}", error);
+                break;
+            }}
+        }}
+    }}
+    println!("Done processing input.");
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_demux")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The gate stops the lines at the first error
+        assert!(compact.contains("stopped=match&stdin_line{Some(Ok(_))=>false,Some(Err(_))=>true,None=>true,};"));
+        // Each arm has its own stream, and all of them one sink printing in order
+        assert!(compact.contains("Some(Ok(text))=>{Some((2*index,{"));
+        assert!(compact.contains("Some(Err(error))=>{Some((2*index,{"));
+        assert!(compact.contains("Some(iflast{2*index+1}else{2*index+2}),"));
+        assert_eq!(compact.matches("stdio_writes.into_iter().for_each(").count(), 1);
+        assert!(!compact.contains(".for_each(q!("));
+        // The code after the loop runs once the loop ends, and completion follows its writes
+        assert!(compact.contains("Some(Err(_))=>Some((2*index+1,true)),None=>Some((2*index,true)),"));
+        assert!(compact.contains("ifcompleted{stdio_writes.push((1u8,format!(\"Doneprocessinginput.\")+\"\\n\"));}"));
+        assert!(compact.contains("next.is_none().then_some(())}})).send_bincode_external(external)"));
+    }
+
+    #[test]
+    fn test_question_mark_errors_go_to_an_error_stream() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() -> Result<(), std::num::ParseIntError> {{
+    let n: i32 = "forty-two".parse()?;
+    println!("Parsed {{}}", n);
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_fallible")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The body keeps its `?` inside a closure with main's return type
+        assert!(compact.contains("letoutcome=(||->Result<(),std::num::ParseIntError>{letn:i32=\"forty-two\".parse()?;"));
+        assert!(compact.contains("returnErr((stdio_writes,format!(\"Error:{:?}\\n\",error)));"));
+        assert!(compact.contains("Ok(stdio_writes)"));
+        // Errors are split off and reported on stderr, successes keep to the main stream
+        assert!(compact.contains(".filter_map(q!(|result|result.err()))"));
+        assert!(compact.contains("stdio_writes.push((2u8,report));"));
+        assert!(compact.contains(".filter_map(q!(|result|result.ok()))"));
+    }
+
+    #[test]
+    fn test_log_macros_go_to_a_log_stream() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use log::info;
+
+fn main() {{
+    env_logger::init();
+    info!("starting up");
+    println!("Hello");
+    log::warn!("{{}} items left", 3);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_logs")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // Records keep their level and message, apart from the writes
+        assert!(compact.contains("letmutlog_records:Vec<(&'staticstr,String)>=Vec::new();"));
+        assert!(compact.contains("log_records.push((\"INFO\",format!(\"startingup\")));"));
+        assert!(compact.contains("log_records.push((\"WARN\",format!(\"{}itemsleft\",3)));"));
+        assert!(compact.contains("stdio_writes.push((1u8,format!(\"Hello\")+\"\\n\"));"));
+        // Each step hands back both buffers, which go to sinks of their own
+        assert!(compact.contains(".flat_map_ordered(q!(|(_,log_records)|log_records))"));
+        assert!(compact.contains(".flat_map_ordered(q!(|(stdio_writes,_)|stdio_writes))"));
+        assert!(compact.contains("eprintln!(\"[{:<5}{}]{}\",level,\"test_logs\",message);"));
+    }
+
+    #[test]
+    fn test_json_lines_become_typed_records_and_parse_errors() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use serde::Deserialize;
+use std::io::{{self, BufRead}};
+
+#[derive(Debug, Deserialize)]
+struct Event {{
+    name: String,
+    value: i64,
+}}
+
+fn main() {{
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {{
+        let line = line.unwrap();
+        match serde_json::from_str::<Event>(&line) {{
+            Ok(event) => println!("{{}} = {{}}", event.name, event.value),
+            Err(error) => eprintln!("skipping {{}}: {{}}", line, error),
+        }}
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_json")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The record type comes along, with the import deriving it
+        assert!(compact.contains("useserde::Deserialize;#[derive(Debug,Deserialize)]structEvent{name:String,value:i64,}"));
+        assert!(compact.contains("serde_json::from_str::<Event>(&line).map_err(|error|((&line).to_string(),error))"));
+        // Records and parse errors each get a stream of their own
+        assert!(compact.contains(".filter_map(q!(|record|record.ok())).flat_map_ordered(q!(|event|{"));
+        assert!(compact.contains(".filter_map(q!(|record|record.err())).flat_map_ordered(q!(|(line,error)|{"));
+        assert!(compact.contains("stdio_writes.push((2u8,format!(\"skipping{}:{}\",line,error)+\"\\n\"));"));
+    }
+
+    #[test]
+    fn test_question_mark_in_lines_loop_ends_the_loop() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::{{self, BufRead}};
+
+fn main() -> io::Result<()> {{
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {{
+        let line = line?;
+        if line == "quit" {{
+            break;
+        }}
+        println!("{{}}", line);
+    }}
+    println!("bye");
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_fallible_lines")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        assert!(compact.contains("Some(line)=>{(||->Result<std::ops::ControlFlow<bool>,std::io::Error>{Ok('iteration:{letline=line?;"));
+        assert!(compact.contains("None=>Ok(std::ops::ControlFlow::Break(true)),"));
+        assert!(compact.contains("Err(error)=>{stopped=true;returnErr((stdio_writes,format!(\"Error:{:?}\\n\",error)));}"));
+        assert!(compact.contains("Ok((stdio_writes,finished))"));
+        // An error counts as finishing the program
+        assert!(compact.contains("Ok((_,finished))=>finished.then_some(()),Err(_)=>Some(()),"));
+    }
+
+    #[test]
+    fn test_sleep_paced_loop_uses_interval_source() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    println!("Start");
+    for i in 1..=3 {{
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        println!("Tick {{}}", i);
+    }}
+    println!("Done");
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = IOToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_paced")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The sleep becomes the interval's period instead of blocking the runtime
+        assert!(!compact.contains("thread::sleep"));
+        assert!(compact.contains("tokio::time::interval(std::time::Duration::from_millis(500))"));
+        // The items are built once and carried to their steps, after the one before the loop
+        assert!(compact.contains("letitems:Vec<_>=(1..=3).into_iter().collect();"));
+        assert_eq!(compact.matches("1..=3").count(), 1);
+        assert!(compact.contains(".zip(futures::stream::iter(std::iter::once(None).chain(items.into_iter().map(Some)),),)"));
+        // Each step runs its part of the legacy body, and only the last one signals completion
+        assert!(compact.contains("matchitem{None=>{stdio_writes.push((1u8,format!(\"Start\")+\"\\n\"));}Some(i)=>{"));
+        assert!(compact.contains("iflast_step{stdio_writes.push((1u8,format!(\"Done\")+\"\\n\"));}"));
+        assert!(compact.contains("(stdio_writes,last_step)"));
+        assert!(compact.contains("iffinished{Some(())}"));
+    }
+
+    #[test]
+    fn test_sleep_loop_with_shared_state_is_not_paced() {
+        let source = r#"
+fn main() {
+    let mut total = 0;
+    for i in 1..=3 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        total += i;
+    }
+    println!("{}", total);
+}
+"#;
+        let file = parse_file(source).unwrap();
+        let main_fn = IOToHydroTransformer::new().extract_main_function(&file).unwrap();
+        // `total` would have to survive across steps
+        assert!(PacedLoop::detect(&main_fn.block.stmts).is_none());
+    }
+
+    #[test]
+    fn test_io_operation_locations() {
+        let source = r#"use std::io::{self, Write};
+
+fn main() {
+    print!("Processing");
+    io::stdout().flush().unwrap();
+    for i in 1..=3 {
+        eprintln!("step {}", i);
+    }
+}
+"#;
+
+        let file = parse_file(source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let main_fn = transformer.extract_main_function(&file).unwrap();
+        let body = transformer.extract_function_body(main_fn).unwrap();
+
+        let locations: Vec<_> = transformer
+            .analyze_io_operations(&body)
+            .into_iter()
+            .map(|op| (op.operation_type, op.line_number, op.column))
+            .collect();
+        assert_eq!(locations, vec![
+            (IOOperationType::StdoutPrint, Some(4), Some(4)),
+            (IOOperationType::StdoutFlush, Some(5), Some(17)),
+            (IOOperationType::StderrEprintln, Some(7), Some(8)),
+        ]);
     }
 
     #[test]
@@ -557,4 +3056,37 @@ fn main() {
         assert!(io_ops.iter().any(|op| op.operation_type == IOOperationType::StderrEprintln));
         assert!(io_ops.iter().any(|op| op.operation_type == IOOperationType::StdinLines));
     }
+
+    #[test]
+    fn test_write_macros_are_classified_by_handle() {
+        let source = r#"
+use std::fmt::Write as _;
+use std::io::Write;
+
+fn main() -> std::io::Result<()> {
+    writeln!(io::stdout(), "to stdout")?;
+    write!(std::io::stderr(), "to stderr").unwrap();
+    let mut log = File::create("run.log")?;
+    writeln!(log, "to a file")?;
+    let mut peer = TcpStream::connect("127.0.0.1:7878")?;
+    writeln!(&mut peer, "to a socket")?;
+    let mut summary = String::new();
+    write!(summary, "only formatting").unwrap();
+    Ok(())
+}
+"#;
+        let file = parse_file(source).unwrap();
+        let transformer = IOToHydroTransformer::new();
+        let body = transformer.extract_function_body(transformer.extract_main_function(&file).unwrap()).unwrap();
+
+        let found: Vec<IOOperationType> = transformer.analyze_io_operations(&body).into_iter().map(|op| op.operation_type).collect();
+        assert_eq!(found, [
+            IOOperationType::StdoutWrite,
+            IOOperationType::StderrWrite,
+            IOOperationType::FileOpen,
+            IOOperationType::FileWrite,
+            IOOperationType::SocketConnect,
+            IOOperationType::SocketWrite,
+        ]);
+    }
 }
@@ -0,0 +1,302 @@
+use std::collections::HashSet;
+
+use proc_macro2::Span;
+use syn::{parse_quote, Expr, ExprClosure, Pat, Stmt};
+
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// A legacy `iter().map(..).filter(..).for_each(..)` pipeline, with each std adaptor
+/// paired with the hydro_lang stream operator that does the same thing
+pub struct IteratorChain {
+    /// The collection the chain starts from, as a block that builds it
+    pub source: Expr,
+    /// Hydro operator names and the closures to pass them, in order
+    pub operators: Vec<(syn::Ident, ExprClosure)>,
+    /// The closure given to the terminal `for_each`
+    pub sink: ExprClosure,
+}
+
+impl IteratorChain {
+    /// Recognize a main body ending in an iterator chain consumed by `for_each`, or in
+    /// a loop that only acts on the items some `if let` lets through. Any statements
+    /// before it must be immutable `let`s that only build the collection.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        Self::detect_chain(stmts).or_else(|| Self::detect_filter_loop(stmts))
+    }
+
+    fn detect_chain(stmts: &[Stmt]) -> Option<Self> {
+        let (last, prefix) = stmts.split_last()?;
+        let Stmt::Expr(chain, _) = last else {
+            return None;
+        };
+
+        // Unwind the method calls from the terminal for_each back to the source
+        let mut calls = Vec::new();
+        let mut expr = chain;
+        let (collection, mut by_ref) = loop {
+            match expr {
+                Expr::MethodCall(call) => match call.method.to_string().as_str() {
+                    "iter" if call.args.is_empty() => break (&*call.receiver, true),
+                    "into_iter" if call.args.is_empty() => break (&*call.receiver, false),
+                    _ => {
+                        calls.push(call);
+                        expr = &call.receiver;
+                    }
+                },
+                Expr::Paren(paren) if matches!(&*paren.expr, Expr::Range(_)) => break (&*paren.expr, false),
+                _ => return None,
+            }
+        };
+        calls.reverse();
+
+        let (sink_call, adaptors) = calls.split_last()?;
+        if sink_call.method != "for_each" {
+            return None;
+        }
+
+        let prefix_names = prefix_bindings(prefix)?;
+
+        let mut operators = Vec::new();
+        let mut adaptors = adaptors.iter().peekable();
+        // `iter().cloned()` yields owned items just like the dataflow does
+        if let Some(call) = adaptors.peek() {
+            if by_ref && (call.method == "cloned" || call.method == "copied") && call.args.is_empty() {
+                by_ref = false;
+                adaptors.next();
+            }
+        }
+        for call in adaptors {
+            let (operator, changes_items) = match call.method.to_string().as_str() {
+                "map" => ("map", true),
+                "filter" => ("filter", false),
+                "filter_map" => ("filter_map", true),
+                "flat_map" => ("flat_map_ordered", true),
+                "inspect" => ("inspect", false),
+                _ => return None,
+            };
+            let closure = chain_closure(&call.args, by_ref, &prefix_names)?;
+            operators.push((syn::Ident::new(operator, Span::call_site()), closure));
+            by_ref &= !changes_items;
+        }
+        let sink = chain_closure(&sink_call.args, by_ref, &prefix_names)?;
+
+        Some(Self { source: source(prefix, collection), operators, sink })
+    }
+
+    /// `for x in xs { if let Some(y) = f(x) { .. } }`, where each `if let` on an
+    /// `Option` (or a `Result`, keeping its `Ok`s) becomes a `filter_map` and the
+    /// innermost body the sink. Each body may only use what its own `if let` binds.
+    fn detect_filter_loop(stmts: &[Stmt]) -> Option<Self> {
+        let (last, prefix) = stmts.split_last()?;
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = last else {
+            return None;
+        };
+        if for_loop.label.is_some() {
+            return None;
+        }
+        let prefix_names = prefix_bindings(prefix)?;
+        let (collection, mut by_ref) = match &*for_loop.expr {
+            Expr::Reference(reference) if reference.mutability.is_none() => (&*reference.expr, true),
+            Expr::MethodCall(call) if call.method == "iter" && call.args.is_empty() => (&*call.receiver, true),
+            items => (items, false),
+        };
+
+        let mut pat = (*for_loop.pat).clone();
+        let mut body = &for_loop.body.stmts;
+        let mut operators = Vec::new();
+        while let [Stmt::Expr(Expr::If(filter), _)] = &body[..] {
+            let Expr::Let(binding) = &*filter.cond else {
+                break;
+            };
+            if filter.else_branch.is_some() {
+                return None;
+            }
+            let (inner, value) = filtered_value(&binding.pat, &binding.expr)?;
+            let closure = item_closure(&parse_quote! { |#pat| #value }, by_ref, &prefix_names)?;
+            operators.push((syn::Ident::new("filter_map", Span::call_site()), closure));
+
+            // Later stages only see what this one passes on
+            let mut names = HashSet::new();
+            bindings(&pat, &mut names);
+            if names.iter().any(|name| mentions(&filter.then_branch.stmts, name)) {
+                return None;
+            }
+            pat = inner;
+            body = &filter.then_branch.stmts;
+            by_ref = false;
+        }
+        if operators.is_empty() || leaves_early(body) {
+            return None;
+        }
+
+        let sink = item_closure(&parse_quote! { |#pat| { #(#body)* } }, false, &prefix_names)?;
+
+        Some(Self { source: source(prefix, collection), operators, sink })
+    }
+}
+
+/// The collection as a block that builds it from the statements before it
+pub(crate) fn source(prefix: &[Stmt], collection: &Expr) -> Expr {
+    if prefix.is_empty() {
+        collection.clone()
+    } else {
+        parse_quote! {{
+            #(#prefix)*
+            #collection
+        }}
+    }
+}
+
+/// The names bound by statements before a chain, which must all be immutable `let`s
+pub(crate) fn prefix_bindings(prefix: &[Stmt]) -> Option<HashSet<String>> {
+    let mut names = HashSet::new();
+    for stmt in prefix {
+        let Stmt::Local(local) = stmt else {
+            return None;
+        };
+        if !bindings(&local.pat, &mut names) {
+            return None;
+        }
+    }
+    Some(names)
+}
+
+/// For `if let Some(x) = value` or `if let Ok(x) = value`, the pattern `x` and the
+/// `Option` a `filter_map` returns for it
+fn filtered_value(pat: &Pat, value: &Expr) -> Option<(Pat, Expr)> {
+    let Pat::TupleStruct(variant) = pat else {
+        return None;
+    };
+    let [inner] = &variant.elems.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let inner = (*inner).clone();
+    if variant.path.is_ident("Some") {
+        Some((inner, value.clone()))
+    } else if variant.path.is_ident("Ok") {
+        Some((inner, parse_quote! { (#value).ok() }))
+    } else {
+        None
+    }
+}
+
+/// The single closure argument of an adaptor. Where the legacy chain borrowed from the
+/// collection but the dataflow owns its items, the closure gets a reference again, so
+/// its patterns and body see the same types as before.
+fn chain_closure(
+    args: &syn::punctuated::Punctuated<Expr, syn::Token![,]>,
+    by_ref: bool,
+    prefix_names: &HashSet<String>,
+) -> Option<ExprClosure> {
+    let [Expr::Closure(closure)] = &args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    item_closure(closure, by_ref, prefix_names)
+}
+
+/// A closure run on each item, given a reference to it again if `by_ref` is set
+fn item_closure(closure: &ExprClosure, by_ref: bool, prefix_names: &HashSet<String>) -> Option<ExprClosure> {
+    if closure.inputs.len() != 1 || closure.asyncness.is_some() {
+        return None;
+    }
+    // The bindings before the chain don't exist inside the quoted operators
+    let body = [Stmt::Expr((*closure.body).clone(), None)];
+    if prefix_names.iter().any(|name| mentions(&body, name)) {
+        return None;
+    }
+    if !by_ref {
+        return Some(closure.clone());
+    }
+
+    let pat = &closure.inputs[0];
+    let body = &closure.body;
+    let item = syn::Ident::new("item", Span::call_site());
+    Some(parse_quote! {
+        |#item| {
+            let #pat = &#item;
+            #body
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<IteratorChain> {
+        IteratorChain::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_owned_chain_maps_onto_operators() {
+        let chain = detect(r#"{
+            (1..=10).map(|n| n * n).filter(|sq| sq % 2 == 0).for_each(|sq| println!("{}", sq));
+        }"#).unwrap();
+
+        assert_eq!(compact(&chain.source), "1..=10");
+        let operators: Vec<_> = chain.operators.iter().map(|(op, f)| format!("{}{}", op, compact(f))).collect();
+        assert_eq!(operators, ["map|n|n*n", "filter|sq|sq%2==0"]);
+        assert_eq!(compact(&chain.sink), "|sq|println!(\"{}\",sq)");
+    }
+
+    #[test]
+    fn test_borrowing_chain_keeps_reference_patterns() {
+        let chain = detect(r#"{
+            let words = vec!["a", "bb", "ccc"];
+            words.iter().filter(|&&w| w.len() > 1).map(|w| w.len()).for_each(|len| println!("{}", len));
+        }"#).unwrap();
+
+        assert_eq!(compact(&chain.source), "{letwords=vec![\"a\",\"bb\",\"ccc\"];words}");
+        // Until the map, the closures see a reference to the owned item
+        assert_eq!(compact(&chain.operators[0].1), "|item|{let&&w=&item;w.len()>1}");
+        assert_eq!(compact(&chain.operators[1].1), "|item|{letw=&item;w.len()}");
+        assert_eq!(compact(&chain.sink), "|len|println!(\"{}\",len)");
+        assert_eq!(chain.operators[0].0, "filter");
+    }
+
+    #[test]
+    fn test_if_let_loops_become_filter_maps() {
+        let chain = detect(r#"{
+            let inputs = vec!["1", "x", "3"];
+            for input in &inputs {
+                if let Ok(n) = input.parse::<i32>() {
+                    if let Some(half) = (n % 2 == 0).then_some(n / 2) {
+                        println!("{}", half);
+                    }
+                }
+            }
+        }"#).unwrap();
+
+        assert_eq!(compact(&chain.source), "{letinputs=vec![\"1\",\"x\",\"3\"];inputs}");
+        let operators: Vec<_> = chain.operators.iter().map(|(op, f)| format!("{}{}", op, compact(f))).collect();
+        assert_eq!(
+            operators,
+            [
+                "filter_map|item|{letinput=&item;(input.parse::<i32>()).ok()}",
+                "filter_map|n|(n%2==0).then_some(n/2)",
+            ]
+        );
+        assert_eq!(compact(&chain.sink), "|half|{println!(\"{}\",half);}");
+    }
+
+    #[test]
+    fn test_if_let_loops_that_need_more_than_the_value() {
+        // The body still uses the item the value came from
+        assert!(detect("{ for s in strs { if let Some(c) = s.chars().next() { println!(\"{} {}\", s, c); } } }").is_none());
+        // An else branch acts on the dropped items too
+        assert!(detect("{ for s in strs { if let Some(c) = s.chars().next() { println!(\"{}\", c); } else { println!(\"empty\"); } } }").is_none());
+        // A plain loop filters nothing
+        assert!(detect("{ for s in strs { println!(\"{}\", s); } }").is_none());
+    }
+
+    #[test]
+    fn test_unsupported_chains_are_rejected() {
+        // The closure captures a local that won't exist in the generated module
+        assert!(detect("{ let k = 3; let xs = vec![1]; xs.into_iter().map(|x| x * k).for_each(|x| println!(\"{}\", x)); }").is_none());
+        // An adaptor without a stream counterpart here
+        assert!(detect("{ (0..5).rev().for_each(|x| println!(\"{}\", x)); }").is_none());
+        // Not consumed by for_each
+        assert!(detect("{ let v: Vec<i32> = (0..5).map(|x| x + 1).collect(); }").is_none());
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+
+use crate::error::TransformError;
+use crate::report::{Diagnostic, TransformReport};
+
+/// The constructs of a report as rustc-style JSON diagnostics, one object per line, as
+/// `rustc --error-format=json` prints them. Each is a warning at the construct's place
+/// in `file_name`, with the suggested rewrite as a help child. `source` is the legacy
+/// program, for the byte offsets of the spans.
+pub fn report_to_json(report: &TransformReport, file_name: &str, source: &str) -> Vec<String> {
+    report.diagnostics.iter().map(|diagnostic| warning(diagnostic).render(file_name, source)).collect()
+}
+
+/// A transformation that failed as a rustc-style JSON error diagnostic, located when
+/// the legacy program didn't parse
+pub fn error_to_json(error: &(dyn std::error::Error + 'static), file_name: &str, source: &str) -> String {
+    let location = match error.downcast_ref::<TransformError>() {
+        Some(TransformError::ParseError { line, column, .. }) => Some((*line, *column)),
+        _ => None,
+    };
+    JsonDiagnostic { level: "error", message: error.to_string(), location, help: None }.render(file_name, source)
+}
+
+fn warning(diagnostic: &Diagnostic) -> JsonDiagnostic {
+    JsonDiagnostic {
+        level: "warning",
+        message: format!("{} is carried over as written", diagnostic.description),
+        location: diagnostic.line_number.zip(diagnostic.column),
+        help: Some(diagnostic.construct.suggestion()),
+    }
+}
+
+/// A diagnostic at a single point of the legacy source
+struct JsonDiagnostic {
+    level: &'static str,
+    message: String,
+    /// 1-based line and 0-based column
+    location: Option<(usize, usize)>,
+    help: Option<&'static str>,
+}
+
+impl JsonDiagnostic {
+    fn render(&self, file_name: &str, source: &str) -> String {
+        let mut rendered = format!("{}: {}\n", self.level, self.message);
+        let spans = match self.location {
+            Some((line, column)) => {
+                writeln!(rendered, " --> {}:{}:{}", file_name, line, column + 1).unwrap();
+                let byte = byte_offset(source, line, column);
+                format!(
+                    "[{{\"file_name\":{},\"byte_start\":{byte},\"byte_end\":{byte},\"line_start\":{line},\"line_end\":{line},\
+                     \"column_start\":{column},\"column_end\":{column},\"is_primary\":true,\"text\":[],\"label\":null,\
+                     \"suggested_replacement\":null,\"suggestion_applicability\":null,\"expansion\":null}}]",
+                    json_string(file_name),
+                    byte = byte,
+                    line = line,
+                    column = column + 1,
+                )
+            }
+            None => "[]".to_string(),
+        };
+        let children = match self.help {
+            Some(help) => {
+                writeln!(rendered, "  = help: {}", help).unwrap();
+                format!(
+                    "[{{\"message\":{},\"code\":null,\"level\":\"help\",\"spans\":[],\"children\":[],\"rendered\":null}}]",
+                    json_string(help)
+                )
+            }
+            None => "[]".to_string(),
+        };
+        format!(
+            "{{\"$message_type\":\"diagnostic\",\"message\":{},\"code\":null,\"level\":\"{}\",\"spans\":{},\"children\":{},\"rendered\":{}}}",
+            json_string(&self.message),
+            self.level,
+            spans,
+            children,
+            json_string(&rendered),
+        )
+    }
+}
+
+/// The byte offset of a 1-based line and 0-based character column of `source`
+fn byte_offset(source: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = source.split_inclusive('\n').take(line.saturating_sub(1)).map(str::len).sum();
+    let rest = &source[line_start.min(source.len())..];
+    line_start + rest.char_indices().nth(column).map_or(rest.len(), |(offset, _)| offset)
+}
+
+/// A string as a JSON string literal
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_becomes_rustc_style_json() {
+        let source = "fn main() {\n    let x = 1;\n    unsafe { println!(\"{}\", x); }\n}\n";
+        let report = TransformReport::analyze(&syn::parse_file(source).unwrap());
+        let lines = report_to_json(&report, "legacy/unsafe.rs", source);
+
+        assert_eq!(lines.len(), 1);
+        let line = &lines[0];
+        assert!(line.starts_with("{\"$message_type\":\"diagnostic\""));
+        assert!(line.contains("\"level\":\"warning\""));
+        assert!(line.contains("\"file_name\":\"legacy/unsafe.rs\",\"byte_start\":31,\"byte_end\":31,\"line_start\":3"));
+        assert!(line.contains("\"column_start\":5"));
+        assert!(line.contains("\"level\":\"help\""));
+        assert!(line.contains(" --> legacy/unsafe.rs:3:5\\n"));
+        assert_eq!(&source[31..37], "unsafe");
+    }
+
+    #[test]
+    fn test_errors_are_located_when_parsing_failed() {
+        let source = "fn main() {\n    let x = ;\n}";
+        let error = TransformError::from(syn::parse_file(source).unwrap_err());
+        let json = error_to_json(&error, "broken.rs", source);
+        assert!(json.contains("\"level\":\"error\""));
+        assert!(json.contains("\"line_start\":2"));
+
+        let json = error_to_json(&TransformError::NoMainFunction, "lib.rs", "");
+        assert!(json.contains("\"message\":\"No main function found in the source file\""));
+        assert!(json.contains("\"spans\":[]"));
+        assert_eq!(json_string("a \"quote\"\n"), "\"a \\\"quote\\\"\\n\"");
+    }
+}
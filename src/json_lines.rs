@@ -0,0 +1,410 @@
+use std::collections::HashSet;
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{Expr, GenericArgument, Item, Pat, PathArguments, Stmt, Type, UseTree};
+
+use crate::loop_control::{first_loop, loop_label, targets};
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// A loop over lines that parses each of them as JSON with `serde_json::from_str`: the
+/// lines that parse become a typed stream of records, and those that don't a stream of
+/// parse errors
+pub struct JsonLinesLoop {
+    /// The statements before the loop, which share nothing with the rest
+    pub prefix: Vec<Stmt>,
+    pub item: Pat,
+    pub items: Expr,
+    /// The `let`s ahead of the parse, which get the text to parse from the item
+    pub lead: Vec<Stmt>,
+    /// The text parsed
+    pub input: Expr,
+    /// The type each record is deserialized into
+    pub record: Type,
+    /// The binding of each record that parsed, and the statements handling it
+    pub parsed: Pat,
+    pub body: Vec<Stmt>,
+    /// The binding of each parse error and the statements handling it, if the loop
+    /// does anything with them
+    pub error: Option<(Pat, Vec<Stmt>)>,
+    /// The binding of the text parsed when it names one, which the error handler may
+    /// read along with the error
+    pub text: Option<syn::Ident>,
+    /// The statements after the loop
+    pub suffix: Vec<Stmt>,
+}
+
+/// The parse of a line, and what the loop does with its outcome
+struct Parse {
+    input: Expr,
+    record: Option<Type>,
+    parsed: Pat,
+    body: Vec<Stmt>,
+    error: Option<(Pat, Vec<Stmt>)>,
+}
+
+impl JsonLinesLoop {
+    /// Recognize a `for` loop whose body, after some `let`s, parses the line with
+    /// `serde_json::from_str` in one of these shapes:
+    ///
+    /// - `match serde_json::from_str::<T>(&line) { Ok(record) => .., Err(error) => .. }`
+    /// - `if let Ok(record) = serde_json::from_str::<T>(&line) { .. } else { .. }`
+    /// - `let record: T = match serde_json::from_str(&line) { Ok(record) => record,
+    ///   Err(error) => { ..; continue; } };` followed by the rest of the body
+    ///
+    /// The record and error handlers become separate streams, so they may only read
+    /// their own bindings and, for the error handler, the text that failed to parse.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop) = first_loop(stmts)?;
+        let label = loop_label(for_loop);
+        let (prefix, suffix) = (&stmts[..index], &stmts[index + 1..]);
+        let body = &for_loop.body.stmts;
+
+        let position = body.iter().position(|stmt| !matches!(stmt, Stmt::Local(_)) || parse_call(stmt).is_some())?;
+        let lead = &body[..position];
+        let parse = parse_stmt(&body[position], &body[position + 1..], label.as_deref())?;
+        let record = parse.record?;
+
+        let handlers = [parse.body.as_slice(), parse.error.as_ref().map_or(&[], |(_, stmts)| stmts.as_slice())];
+        if leaves_early(lead) || handlers.iter().any(|stmts| leaves_early(stmts)) || leaves_early(prefix) || leaves_early(suffix) {
+            return None;
+        }
+
+        // The bindings of the item and the lead stay in the parsing operator
+        let mut line_names = HashSet::new();
+        bindings(&for_loop.pat, &mut line_names);
+        for stmt in lead {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut line_names);
+            }
+        }
+        let text = match &parse.input {
+            Expr::Reference(reference) => name_of(&reference.expr),
+            Expr::MethodCall(call) if call.method == "as_str" && call.args.is_empty() => name_of(&call.receiver),
+            input => name_of(input),
+        }
+        .filter(|name| line_names.contains(&name.to_string()));
+        if line_names.iter().any(|name| mentions(&parse.body, name)) {
+            return None;
+        }
+        if let Some((_, handler)) = &parse.error {
+            let mut shared = line_names.iter().filter(|name| text.as_ref().is_none_or(|text| *text != **name));
+            if shared.any(|name| mentions(handler, name)) {
+                return None;
+            }
+        }
+
+        // Nor do the bindings before the loop make it into any other stream
+        let mut prefix_names = HashSet::new();
+        for stmt in prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut prefix_names);
+            }
+        }
+        let used = [lead, handlers[0], handlers[1], suffix].concat();
+        if prefix_names.iter().any(|name| mentions(&used, name)) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            item: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            lead: lead.to_vec(),
+            input: parse.input,
+            record,
+            parsed: parse.parsed,
+            body: parse.body,
+            error: parse.error,
+            text,
+            suffix: suffix.to_vec(),
+        })
+    }
+}
+
+/// The definitions in `items` of the types a record of type `record` is made of, along
+/// with the `serde` imports deriving their implementations, in their original order
+pub fn record_definitions(items: &[Item], record: &Type) -> Vec<Item> {
+    let mut wanted = HashSet::new();
+    idents(record.to_token_stream(), &mut wanted);
+    let mut included = vec![false; items.len()];
+    // Each definition included may name more types defined alongside it
+    loop {
+        let mut grew = false;
+        for (index, item) in items.iter().enumerate() {
+            let name = match item {
+                Item::Struct(item) => &item.ident,
+                Item::Enum(item) => &item.ident,
+                Item::Type(item) => &item.ident,
+                _ => continue,
+            };
+            if !included[index] && wanted.contains(&name.to_string()) {
+                included[index] = true;
+                idents(item.to_token_stream(), &mut wanted);
+                grew = true;
+            }
+        }
+        if !grew {
+            break;
+        }
+    }
+
+    items
+        .iter()
+        .zip(included)
+        .filter(|(item, included)| *included || matches!(item, Item::Use(item) if uses_serde(&item.tree)))
+        .map(|(item, _)| item.clone())
+        .collect()
+}
+
+fn uses_serde(tree: &UseTree) -> bool {
+    matches!(tree, UseTree::Path(path) if path.ident == "serde")
+}
+
+fn idents(tokens: TokenStream, names: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                names.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => idents(group.stream(), names),
+            _ => {}
+        }
+    }
+}
+
+fn name_of(expr: &Expr) -> Option<syn::Ident> {
+    match expr {
+        Expr::Path(path) => path.path.get_ident().cloned(),
+        _ => None,
+    }
+}
+
+/// The text parsed and the type given to `serde_json::from_str`, if any, when `expr`
+/// calls it
+fn from_str(expr: &Expr) -> Option<(Expr, Option<Type>)> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<_> = func.path.segments.iter().collect();
+    let [.., krate, function] = &segments[..] else {
+        return None;
+    };
+    if krate.ident != "serde_json" || function.ident != "from_str" || call.args.len() != 1 {
+        return None;
+    }
+    let record = match &function.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(GenericArgument::Type(record)) if args.args.len() == 1 => Some(record.clone()),
+            _ => return None,
+        },
+        _ => None,
+    };
+    Some((call.args[0].clone(), record))
+}
+
+/// The `serde_json::from_str` call a statement of the body starts with
+fn parse_call(stmt: &Stmt) -> Option<(Expr, Option<Type>)> {
+    match stmt {
+        Stmt::Expr(Expr::Match(demux), _) => from_str(&demux.expr),
+        Stmt::Expr(Expr::If(branch), _) => match &*branch.cond {
+            Expr::Let(cond) => from_str(&cond.expr),
+            _ => None,
+        },
+        Stmt::Local(local) => match local.init.as_ref().map(|init| &*init.expr) {
+            Some(Expr::Match(demux)) => from_str(&demux.expr),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn parse_stmt(stmt: &Stmt, rest: &[Stmt], label: Option<&str>) -> Option<Parse> {
+    let (input, record) = parse_call(stmt)?;
+    match stmt {
+        Stmt::Expr(Expr::Match(demux), _) if rest.is_empty() => {
+            let (mut ok, mut err) = (None, None);
+            for arm in &demux.arms {
+                if arm.guard.is_some() {
+                    return None;
+                }
+                match variant(&arm.pat)? {
+                    ("Ok", pat) => ok = Some((pat, arm_stmts(&arm.body))),
+                    ("Err", pat) => err = Some((pat, arm_stmts(&arm.body))),
+                    _ => return None,
+                }
+            }
+            let (parsed, body) = ok?;
+            Some(Parse { input, record, parsed, body, error: Some(err?) })
+        }
+        Stmt::Expr(Expr::If(branch), _) if rest.is_empty() => {
+            let Expr::Let(cond) = &*branch.cond else {
+                return None;
+            };
+            let ("Ok", parsed) = variant(&cond.pat)? else {
+                return None;
+            };
+            let error = match &branch.else_branch {
+                None => None,
+                Some((_, otherwise)) => match &**otherwise {
+                    Expr::Block(otherwise) => Some((syn::parse_quote! { _ }, otherwise.block.stmts.clone())),
+                    _ => return None,
+                },
+            };
+            Some(Parse { input, record, parsed, body: branch.then_branch.stmts.clone(), error })
+        }
+        Stmt::Local(local) => {
+            let Some(Expr::Match(demux)) = local.init.as_ref().filter(|init| init.diverge.is_none()).map(|init| &*init.expr) else {
+                return None;
+            };
+            let (parsed, annotated) = match &local.pat {
+                Pat::Type(typed) => ((*typed.pat).clone(), Some((*typed.ty).clone())),
+                pat => (pat.clone(), None),
+            };
+            let mut error = None;
+            for arm in &demux.arms {
+                if arm.guard.is_some() {
+                    return None;
+                }
+                match variant(&arm.pat)? {
+                    // The record itself
+                    ("Ok", Pat::Ident(value)) if name_of(&arm.body).is_some_and(|name| name == value.ident) => {}
+                    // Handled, then on to the next line
+                    ("Err", pat) => {
+                        let mut stmts = arm_stmts(&arm.body);
+                        match stmts.pop() {
+                            Some(Stmt::Expr(Expr::Continue(jump), _)) if targets(&jump.label, label, 0) => {}
+                            _ => return None,
+                        }
+                        error = Some((pat, stmts));
+                    }
+                    _ => return None,
+                }
+            }
+            if demux.arms.len() != 2 {
+                return None;
+            }
+            Some(Parse { input, record: record.or(annotated), parsed, body: rest.to_vec(), error: Some(error?) })
+        }
+        _ => None,
+    }
+}
+
+/// `Ok(pat)` or `Err(pat)` as the variant's name and its pattern
+fn variant(pat: &Pat) -> Option<(&'static str, Pat)> {
+    let Pat::TupleStruct(variant) = pat else {
+        return None;
+    };
+    let [inner] = &variant.elems.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let name = match variant.path.get_ident()?.to_string().as_str() {
+        "Ok" => "Ok",
+        "Err" => "Err",
+        _ => return None,
+    };
+    Some((name, (*inner).clone()))
+}
+
+fn arm_stmts(body: &Expr) -> Vec<Stmt> {
+    match body {
+        Expr::Block(block) if block.label.is_none() => block.block.stmts.clone(),
+        body => vec![Stmt::Expr(body.clone(), None)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<JsonLinesLoop> {
+        JsonLinesLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_parse_shapes_are_recognized() {
+        let json = detect(r#"{
+            for line in lines {
+                let line = line.unwrap();
+                match serde_json::from_str::<Event>(&line) {
+                    Ok(event) => println!("{} = {}", event.name, event.value),
+                    Err(error) => eprintln!("bad line {}: {}", line, error),
+                }
+            }
+        }"#).unwrap();
+        assert_eq!(compact(&json.record), "Event");
+        assert_eq!(compact(&json.input), "&line");
+        assert_eq!(json.text.map(|text| text.to_string()).as_deref(), Some("line"));
+        assert_eq!(compact(&json.error.unwrap().0), "error");
+        assert_eq!(json.lead.len(), 1);
+
+        let json = detect(r#"{
+            for line in lines {
+                let line = line.unwrap();
+                let event: Event = match serde_json::from_str(&line) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        eprintln!("skipping: {}", e);
+                        continue;
+                    }
+                };
+                println!("{}", event.name);
+            }
+            println!("done");
+        }"#).unwrap();
+        assert_eq!(compact(&json.record), "Event");
+        assert_eq!(compact(&json.parsed), "event");
+        assert_eq!(json.body.len(), 1);
+        assert_eq!(json.error.unwrap().1.len(), 1);
+        assert_eq!(json.suffix.len(), 1);
+
+        let json = detect(r#"{
+            for line in lines {
+                if let Ok(event) = serde_json::from_str::<Event>(&line.unwrap()) {
+                    println!("{}", event.name);
+                }
+            }
+        }"#).unwrap();
+        assert!(json.error.is_none() && json.text.is_none());
+    }
+
+    #[test]
+    fn test_loops_that_cannot_be_split() {
+        // No type to deserialize into
+        assert!(detect("{ for line in lines { let line = line.unwrap(); if let Ok(event) = serde_json::from_str(&line) { handle(event); } } }").is_none());
+        // The record handler reads the line
+        assert!(detect("{ for line in lines { let line = line.unwrap(); if let Ok(event) = serde_json::from_str::<Event>(&line) { println!(\"{} {}\", line, event.name); } } }").is_none());
+        // A count kept across the streams
+        assert!(detect("{ let mut n = 0; for line in lines { if let Ok(event) = serde_json::from_str::<Event>(&line.unwrap()) { n += event.value; } } println!(\"{}\", n); }").is_none());
+        // Stops at the first bad line
+        assert!(detect("{ for line in lines { match serde_json::from_str::<Event>(&line.unwrap()) { Ok(event) => println!(\"{}\", event.name), Err(_) => break } } }").is_none());
+    }
+
+    #[test]
+    fn test_record_definitions_are_carried() {
+        let file: syn::File = syn::parse_str(r#"
+            use serde::Deserialize;
+            use std::io::BufRead;
+
+            #[derive(Deserialize)]
+            struct Event { name: String, kind: Kind }
+
+            #[derive(Deserialize)]
+            enum Kind { Click, View }
+
+            struct Unrelated;
+
+            fn main() {}
+        "#).unwrap();
+        let record: Type = syn::parse_quote! { Event };
+        let definitions: Vec<String> = record_definitions(&file.items, &record).iter().map(compact).collect();
+        assert_eq!(definitions.len(), 3);
+        assert_eq!(definitions[0], "useserde::Deserialize;");
+        assert!(definitions[1].contains("structEvent"));
+        assert!(definitions[2].contains("enumKind"));
+    }
+}
@@ -0,0 +1,320 @@
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use syn::{parse_quote, Block, Expr, Item, Pat, Stmt, UseTree};
+
+use crate::config_file::leaves;
+use crate::csv_source::record_binding;
+use crate::sqlite_source::unwrap_stmt_tries;
+use crate::syntax::{leaves_early, mentions};
+
+/// The methods of a message the loop body may call, which the messages the bridge
+/// forwards still have
+const MESSAGE_METHODS: [&str; 2] = ["payload", "payload_view"];
+
+/// A legacy service consuming Kafka with `rdkafka`: a consumer is created and
+/// subscribed, and a loop polls it for messages. The consumer moves to a bridge in the
+/// example, which forwards each message's payload to the dataflow through an external
+/// bytes port, and the handling of the messages moves to the dataflow.
+pub struct KafkaConsumer {
+    /// The statements creating and subscribing the consumer, as the bridge runs them
+    pub setup: Vec<Stmt>,
+    /// The legacy loop, as the bridge runs it, with the handling of each message
+    /// replaced by forwarding its payload to `payloads`
+    pub bridge: Stmt,
+    /// The binding of each message and the statements handling it
+    pub message: syn::Ident,
+    pub body: Vec<Stmt>,
+    /// The legacy file's imports the bridge needs: those of `rdkafka`, for its traits,
+    /// and any other the bridge names
+    pub imports: Vec<Item>,
+}
+
+impl KafkaConsumer {
+    /// Recognize a consumer built with `ClientConfig::new()...create()` and subscribed,
+    /// followed by `loop { match consumer.poll(..) { Some(Ok(message)) => .. } }`,
+    /// `loop { match consumer.recv().await { Ok(message) => .. } }` or a loop over
+    /// `consumer.iter()` that takes each message out of its result. The loop may be
+    /// followed by a final `Ok(())`, and nothing else.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let index = stmts.iter().position(|stmt| matches!(stmt, Stmt::Expr(Expr::Loop(_) | Expr::ForLoop(_), _)))?;
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+
+        // Everything before the loop creates the consumer, or sets it up
+        let prefix = &stmts[..index];
+        let consumer = prefix.iter().find_map(consumer_binding)?;
+        let name = consumer.to_string();
+        if !prefix.iter().all(|stmt| mentions(std::slice::from_ref(stmt), &name)) {
+            return None;
+        }
+
+        // The arm or statements handling a message, which move to the dataflow
+        let mut bridge = stmts[index].clone();
+        let (message, body) = match &mut bridge {
+            Stmt::Expr(Expr::Loop(polling), _) if polling.label.is_none() => {
+                let polls = |scrutinee: &str| [". poll (", ". recv ()"].iter().any(|call| scrutinee.starts_with(&format!("{} {}", name, call)));
+                message_arm(&mut polling.body, polls)?
+            }
+            Stmt::Expr(Expr::ForLoop(iterating), _) if iterating.label.is_none() => {
+                let reads_consumer = matches!(&*iterating.expr, Expr::MethodCall(call) if call.method == "iter"
+                    && matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(&consumer)));
+                if !reads_consumer {
+                    return None;
+                }
+                let result = (*iterating.pat).clone();
+                match iterating.body.stmts.split_first().and_then(|(first, rest)| record_binding(first, &result).map(|binding| (first.clone(), binding, rest.to_vec()))) {
+                    Some((unwrap, (Pat::Ident(message), None, _), body)) => {
+                        let forward = forward(&message.ident);
+                        iterating.body = parse_quote! {{
+                            #unwrap
+                            #forward
+                        }};
+                        (message.ident, body)
+                    }
+                    _ => {
+                        let result = result.to_token_stream().to_string();
+                        message_arm(&mut iterating.body, |scrutinee| scrutinee == result)?
+                    }
+                }
+            }
+            _ => return None,
+        };
+
+        // The body runs in the dataflow, which only has the payloads
+        if leaves_early(&body) || mentions(&body, &name) || !only_payloads(&quote! { #(#body)* }, &message.to_string()) {
+            return None;
+        }
+
+        let setup: Vec<Stmt> = prefix.iter().map(unwrap_stmt_tries).collect();
+        let bridge = unwrap_stmt_tries(&bridge);
+
+        // The bridge needs the `rdkafka` traits, and whatever else it names
+        let mut bridged = setup.clone();
+        bridged.push(bridge.clone());
+        let imports = items
+            .iter()
+            .filter(|item| match item {
+                Item::Use(item) => {
+                    matches!(&item.tree, UseTree::Path(path) if path.ident == "rdkafka")
+                        || leaves(&item.tree).iter().any(|name| mentions(&bridged, name))
+                }
+                _ => false,
+            })
+            .cloned()
+            .collect();
+
+        Some(Self { setup, bridge, message, body, imports })
+    }
+}
+
+/// `let consumer: BaseConsumer = ClientConfig::new()...create()?;`, as the consumer's name
+fn consumer_binding(stmt: &Stmt) -> Option<syn::Ident> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let name = match &local.pat {
+        Pat::Ident(name) => &name.ident,
+        Pat::Type(typed) => match &*typed.pat {
+            Pat::Ident(name) => &name.ident,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let init = local.init.as_ref().filter(|init| init.diverge.is_none())?;
+    let tokens = init.expr.to_token_stream().to_string();
+    (tokens.starts_with("ClientConfig :: new") || tokens.contains("config :: ClientConfig :: new")).then(|| name.clone())
+}
+
+/// The message arm of a `match` making up the loop's body, `Some(Ok(message))` over
+/// `consumer.poll(..)` or `Ok(message)` over `consumer.recv().await` or a result from
+/// the consumer's iterator, which `polls` tells apart. The arm is replaced by
+/// forwarding the message's payload.
+fn message_arm(block: &mut Block, polls: impl Fn(&str) -> bool) -> Option<(syn::Ident, Vec<Stmt>)> {
+    let [Stmt::Expr(Expr::Match(polled), _)] = &mut block.stmts[..] else {
+        return None;
+    };
+    if !polls(&polled.expr.to_token_stream().to_string()) {
+        return None;
+    }
+    polled.arms.iter_mut().find_map(|arm| {
+        let message = match ok_binding(&arm.pat)? {
+            Pat::Ident(message) => message.ident.clone(),
+            _ => return None,
+        };
+        let body = match &*arm.body {
+            Expr::Block(block) => block.block.stmts.clone(),
+            expr => vec![parse_quote! { #expr; }],
+        };
+        let forward = forward(&message);
+        *arm.body = parse_quote! {{
+            #forward
+        }};
+        Some((message, body))
+    })
+}
+
+/// The pattern under `Some(Ok(..))` or `Ok(..)`
+fn ok_binding(pat: &Pat) -> Option<&Pat> {
+    let Pat::TupleStruct(variant) = pat else {
+        return None;
+    };
+    let [inner] = &variant.elems.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    if variant.path.is_ident("Ok") {
+        Some(inner)
+    } else if variant.path.is_ident("Some") && matches!(inner, Pat::TupleStruct(ok) if ok.path.is_ident("Ok")) {
+        ok_binding(inner)
+    } else {
+        None
+    }
+}
+
+/// The bridge's forwarding of a message's payload to the dataflow
+fn forward(message: &syn::Ident) -> TokenStream {
+    quote! {
+        if let Some(payload) = rdkafka::Message::payload(&#message) {
+            payloads.send(bytes::Bytes::copy_from_slice(payload)).await.unwrap();
+        }
+    }
+}
+
+/// The definition of `KafkaMessage`, the messages the loop body handles in the
+/// dataflow, which offer the [`MESSAGE_METHODS`] of `rdkafka::Message`
+pub fn kafka_message() -> TokenStream {
+    quote! {
+        /// A Kafka message as the bridge forwards it: its payload, without the key or metadata
+        pub struct KafkaMessage {
+            payload: bytes::BytesMut,
+        }
+
+        impl KafkaMessage {
+            pub fn payload(&self) -> Option<&[u8]> {
+                Some(&self.payload)
+            }
+
+            pub fn payload_view<P: ?Sized + rdkafka::message::FromBytes>(&self) -> Option<Result<&P, P::Error>> {
+                self.payload().map(P::from_bytes)
+            }
+        }
+    }
+}
+
+/// Whether every use of `message` in the tokens calls one of [`MESSAGE_METHODS`] on it
+fn only_payloads(tokens: &TokenStream, message: &str) -> bool {
+    let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    tokens.iter().enumerate().all(|(index, token)| match token {
+        TokenTree::Ident(ident) if ident == message => {
+            let dot = matches!(tokens.get(index + 1), Some(TokenTree::Punct(punct)) if punct.as_char() == '.');
+            let method = matches!(tokens.get(index + 2), Some(TokenTree::Ident(method)) if MESSAGE_METHODS.iter().any(|name| method == name));
+            dot && method
+        }
+        TokenTree::Group(group) => only_payloads(&group.stream(), message),
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str) -> Option<KafkaConsumer> {
+        let (items, main) = parse_main(source)?;
+        KafkaConsumer::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_poll_loop_moves_to_the_bridge() {
+        let kafka = detect(r#"
+            use rdkafka::config::ClientConfig;
+            use rdkafka::consumer::{BaseConsumer, Consumer};
+            use rdkafka::Message;
+            use std::time::Duration;
+            use std::collections::HashMap;
+
+            fn main() -> Result<(), Box<dyn std::error::Error>> {
+                let consumer: BaseConsumer = ClientConfig::new()
+                    .set("bootstrap.servers", "localhost:9092")
+                    .set("group.id", "ingest")
+                    .create()?;
+                consumer.subscribe(&["events"])?;
+                loop {
+                    match consumer.poll(Duration::from_millis(100)) {
+                        Some(Ok(message)) => {
+                            let text = message.payload_view::<str>().unwrap().unwrap();
+                            println!("event: {}", text);
+                        }
+                        Some(Err(e)) => eprintln!("kafka error: {}", e),
+                        None => {}
+                    }
+                }
+            }
+        "#).unwrap();
+
+        assert_eq!(kafka.setup.len(), 2);
+        assert!(compact(&kafka.setup[0]).ends_with(".create().unwrap();"));
+        assert_eq!(compact(&kafka.setup[1]), "consumer.subscribe(&[\"events\"]).unwrap();");
+        assert_eq!(kafka.message, "message");
+        assert_eq!(kafka.body.len(), 2);
+        // The bridge polls with a timeout
+        assert_eq!(kafka.imports.len(), 4);
+        assert_eq!(compact(&kafka.imports[3]), "usestd::time::Duration;");
+        // The bridge keeps polling and reporting errors, and forwards the payloads
+        let bridge = compact(&kafka.bridge);
+        assert!(bridge.contains("Some(Ok(message))=>{ifletSome(payload)=rdkafka::Message::payload(&message){payloads.send(bytes::Bytes::copy_from_slice(payload)).await.unwrap();}}"));
+        assert!(bridge.contains("Some(Err(e))=>eprintln!(\"kafkaerror:{}\",e),"));
+    }
+
+    #[test]
+    fn test_iterated_consumer_moves_to_the_bridge() {
+        let kafka = detect(r#"
+            fn main() {
+                let consumer: BaseConsumer = ClientConfig::new().set("group.id", "g").create().expect("consumer");
+                consumer.subscribe(&["lines"]).unwrap();
+                for result in consumer.iter() {
+                    let message = result.unwrap();
+                    if let Some(bytes) = message.payload() {
+                        println!("{} bytes", bytes.len());
+                    }
+                }
+            }
+        "#).unwrap();
+
+        assert_eq!(kafka.message, "message");
+        assert_eq!(kafka.body.len(), 1);
+        assert!(compact(&kafka.bridge).starts_with("forresultinconsumer.iter(){letmessage=result.unwrap();ifletSome(payload)"));
+    }
+
+    #[test]
+    fn test_loops_that_are_not_kafka_sources() {
+        // The body reads more of the message than the bridge forwards
+        assert!(detect(r#"
+            fn main() {
+                let consumer: StreamConsumer = ClientConfig::new().create().unwrap();
+                consumer.subscribe(&["t"]).unwrap();
+                loop {
+                    match consumer.recv().await {
+                        Ok(message) => println!("{:?}", message.key()),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+            }
+        "#).is_none());
+        // The body commits through the consumer
+        assert!(detect(r#"
+            fn main() {
+                let consumer: BaseConsumer = ClientConfig::new().create().unwrap();
+                for result in consumer.iter() {
+                    let message = result.unwrap();
+                    consumer.commit_message(&message, CommitMode::Async).unwrap();
+                }
+            }
+        "#).is_none());
+        // No consumer
+        assert!(detect("fn main() { loop { match rx.recv() { Ok(message) => println!(\"{:?}\", message.payload()), Err(_) => break } } }").is_none());
+    }
+}
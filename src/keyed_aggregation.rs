@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use syn::visit::{self, Visit};
+use syn::{BinOp, Expr, Ident, Pat, Stmt, UnOp};
+
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::syntax::{bindings, is_compound_assign, is_name, mentions};
+
+/// A legacy loop grouping its items by key into a map, like a word count, followed by
+/// the code that uses the groups. Each group only depends on the items with its key, so
+/// the groups can be built by whichever member of a cluster owns the key.
+pub struct KeyedAggregation {
+    /// Immutable bindings before the map, which only feed the loop
+    pub prefix: Vec<Stmt>,
+    /// The map's binding, which the code after the loop gets the groups in
+    pub map: Ident,
+    /// `HashMap` or `BTreeMap`
+    pub map_type: Ident,
+    pub pat: Pat,
+    pub items: Expr,
+    /// The bindings each iteration makes before it updates its group
+    pub lets: Vec<Stmt>,
+    pub key: Expr,
+    /// The entry method making a group for a new key, like `or_insert`
+    pub insert: Ident,
+    pub insert_args: Vec<Expr>,
+    /// The compound assignment folding the value into the group, like `+=`
+    pub op: BinOp,
+    pub value: Expr,
+    /// The statements after the loop
+    pub rest: Vec<Stmt>,
+    /// Whether the statements after the loop change the map
+    pub rest_mutates: bool,
+}
+
+impl KeyedAggregation {
+    /// Recognize `let mut m = HashMap::new(); for x in xs { .. *m.entry(k).or_insert(i) += v; }`
+    /// followed by the statements reading `m`, after any immutable `let`s the loop uses
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let start = stmts.iter().position(|stmt| map_decl(stmt).is_some())?;
+        let (prefix, [decl, fill, rest @ ..]) = stmts.split_at(start) else {
+            return None;
+        };
+        let (map, map_type) = map_decl(decl)?;
+        let name = map.to_string();
+
+        let mut prefix_names = HashSet::new();
+        for stmt in prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            if !bindings(&local.pat, &mut prefix_names) {
+                return None;
+            }
+        }
+
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = fill else {
+            return None;
+        };
+        // The iterations may only fold their items into the groups
+        let analysis = LoopAnalysis::of_loop(&[], stmts, start + 1)?;
+        if analysis.kind != LoopKind::FoldLike || analysis.skips {
+            return None;
+        }
+        let (last, lets) = for_loop.body.stmts.split_last()?;
+        if !lets.iter().all(|stmt| matches!(stmt, Stmt::Local(_))) || mentions(lets, &name) {
+            return None;
+        }
+        let Stmt::Expr(Expr::Binary(update), Some(_)) = last else {
+            return None;
+        };
+        if !is_compound_assign(&update.op) {
+            return None;
+        }
+        let Expr::Unary(deref) = &*update.left else {
+            return None;
+        };
+        if !matches!(deref.op, UnOp::Deref(_)) {
+            return None;
+        }
+        let Expr::MethodCall(insert) = &*deref.expr else {
+            return None;
+        };
+        if !["or_insert", "or_insert_with", "or_default"].contains(&insert.method.to_string().as_str()) {
+            return None;
+        }
+        let Expr::MethodCall(entry) = &*insert.receiver else {
+            return None;
+        };
+        if entry.method != "entry" || entry.args.len() != 1 {
+            return None;
+        }
+        let key = &entry.args[0];
+        if !matches!(&*entry.receiver, Expr::Path(path) if path.path.is_ident(&name)) {
+            return None;
+        }
+        let parts = [key.clone(), (*update.right).clone()].into_iter().chain(insert.args.iter().cloned());
+        if parts.map(|part| Stmt::Expr(part, None)).any(|part| mentions(&[part], &name)) {
+            return None;
+        }
+
+        // The code after the loop runs on its own, once the groups are merged
+        if rest.is_empty() || !mentions(rest, &name) || prefix_names.iter().any(|prefix| mentions(rest, prefix)) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            map,
+            map_type,
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            lets: lets.to_vec(),
+            key: key.clone(),
+            insert: insert.method.clone(),
+            insert_args: insert.args.iter().cloned().collect(),
+            op: update.op,
+            value: (*update.right).clone(),
+            rest: rest.to_vec(),
+            rest_mutates: mutates(rest, &name),
+        })
+    }
+}
+
+/// `let mut m = HashMap::new();` (or a `BTreeMap`), giving the binding and the map type
+fn map_decl(stmt: &Stmt) -> Option<(Ident, Ident)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let pat = match &local.pat {
+        Pat::Type(typed) => &*typed.pat,
+        pat => pat,
+    };
+    let Pat::Ident(binding) = pat else {
+        return None;
+    };
+    binding.mutability?;
+    let Expr::Call(call) = &*local.init.as_ref()?.expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<_> = func.path.segments.iter().collect();
+    let [.., map_type, new] = &segments[..] else {
+        return None;
+    };
+    if !call.args.is_empty() || new.ident != "new" || !(map_type.ident == "HashMap" || map_type.ident == "BTreeMap") {
+        return None;
+    }
+    Some((binding.ident.clone(), map_type.ident.clone()))
+}
+
+/// Whether the statements assign to `name`, borrow it mutably or call a method changing it
+pub(crate) fn mutates(stmts: &[Stmt], name: &str) -> bool {
+    const MUTATORS: [&str; 11] =
+        ["insert", "remove", "entry", "retain", "clear", "get_mut", "iter_mut", "values_mut", "drain", "extend", "append"];
+
+    struct Mutations<'n> {
+        name: &'n str,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Mutations<'_> {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::MethodCall(call) if is_name(&call.receiver, self.name) => {
+                    self.found |= MUTATORS.contains(&call.method.to_string().as_str());
+                }
+                Expr::Reference(reference) if reference.mutability.is_some() => {
+                    self.found |= is_name(&reference.expr, self.name);
+                }
+                Expr::Assign(assign) => self.found |= is_name(&assign.left, self.name),
+                Expr::Binary(binary) if is_compound_assign(&binary.op) => self.found |= is_name(&binary.left, self.name),
+                _ => {}
+            }
+            visit::visit_expr(self, expr);
+        }
+    }
+
+    let mut mutations = Mutations { name, found: false };
+    for stmt in stmts {
+        mutations.visit_stmt(stmt);
+    }
+    mutations.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+    use quote::ToTokens;
+
+    fn detect(body: &str) -> Option<KeyedAggregation> {
+        KeyedAggregation::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_word_count_groups_by_key() {
+        let counted = detect(r#"{
+            let text = "the cat saw the dog";
+            let mut counts = HashMap::new();
+            for word in text.split_whitespace() {
+                let word = word.to_lowercase();
+                *counts.entry(word).or_insert(0) += 1;
+            }
+            let mut words: Vec<_> = counts.into_iter().collect();
+            words.sort();
+            for (word, count) in words {
+                println!("{}: {}", word, count);
+            }
+        }"#).unwrap();
+
+        assert_eq!(counted.prefix.len(), 1);
+        assert_eq!((counted.map.to_string(), counted.map_type.to_string()), ("counts".to_string(), "HashMap".to_string()));
+        assert_eq!(counted.lets.len(), 1);
+        assert_eq!(counted.key.to_token_stream().to_string(), "word");
+        assert_eq!(counted.insert, "or_insert");
+        assert!(matches!(counted.op, BinOp::AddAssign(_)));
+        assert_eq!(counted.rest.len(), 3);
+        assert!(!counted.rest_mutates);
+
+        let sums = detect("{ let mut sums = std::collections::BTreeMap::new(); for (k, v) in pairs() { *sums.entry(k).or_default() += v; } sums.remove(&0); println!(\"{:?}\", sums); }").unwrap();
+        assert_eq!(sums.map_type, "BTreeMap");
+        assert!(sums.rest_mutates);
+    }
+
+    #[test]
+    fn test_other_map_updates_are_not_keyed_aggregations() {
+        // Nothing reads the groups afterwards
+        assert!(detect("{ let mut counts = HashMap::new(); for w in words() { *counts.entry(w).or_insert(0) += 1; } }").is_none());
+        // A group depends on the others
+        assert!(detect("{ let mut counts = HashMap::new(); for w in words() { *counts.entry(w).or_insert(counts.len()) += 1; } println!(\"{:?}\", counts); }").is_none());
+        // The iterations skip items
+        assert!(detect("{ let mut counts = HashMap::new(); for w in words() { if w.is_empty() { continue; } *counts.entry(w).or_insert(0) += 1; } println!(\"{:?}\", counts); }").is_none());
+        // The code after the loop reads a binding that only exists before it
+        assert!(detect("{ let scale = 2; let mut counts = HashMap::new(); for w in words() { *counts.entry(w).or_insert(0) += scale; } println!(\"{:?} {}\", counts, scale); }").is_none());
+        // Not a map of groups
+        assert!(detect("{ let mut seen = HashSet::new(); for w in words() { seen.insert(w); } println!(\"{:?}\", seen); }").is_none());
+    }
+}
@@ -0,0 +1,99 @@
+use hydro_lang::*;
+use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
+
+/// A command of the store, as the legacy program matched it on the words of a line
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Get(String),
+    Set(String, String),
+}
+
+/// The legacy key-value store (`legacy::kv_store`) as a dataflow between a request and a
+/// response stream. Each line sent on the request port is parsed into a `Command`, and
+/// the commands fold into the store one after another, each answered with the line the
+/// legacy program printed for it. The store lives in the folding operator, where the
+/// legacy loop kept it in a local, so the responses come back in the order of the
+/// requests.
+pub fn kv_store<'a>(
+    process: &Process<'a>,
+    external: &ExternalProcess<'a, ()>,
+) -> (ExternalBincodeSink<String>, ExternalBincodeStream<String>) {
+    let (requests_port, requests) = process.source_external_bincode(external);
+
+    let commands = requests.map(q!(|line: String| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["SET", key, value] => Ok(Command::Set(key.to_string(), value.to_string())),
+            ["GET", key] => Ok(Command::Get(key.to_string())),
+            _ => Err(format!("ERR unknown command: {}", line)),
+        }
+    })); // : Stream<Result<Command, String>, Process, ...>
+
+    let responses = commands.map(q!({
+        let mut store = std::collections::HashMap::new();
+        move |command: Result<Command, String>| match command {
+            Ok(Command::Set(key, value)) => {
+                store.insert(key, value);
+                "OK".to_string()
+            }
+            Ok(Command::Get(key)) => store.get(&key).cloned().unwrap_or_else(|| "(nil)".to_string()),
+            Err(error) => error,
+        }
+    })); // : Stream<String, Process, ...>
+
+    let responses_port = responses.send_bincode_external(external);
+
+    (requests_port, responses_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use hydro_deploy::Deployment;
+
+    use crate::io_transformer::IOToHydroTransformer;
+
+    /// A session with the store and what the legacy program prints for it
+    const REQUESTS: [&str; 6] = ["SET a 1", "GET a", "GET b", "DEL a", "SET b two", "GET b"];
+    const RESPONSES: [&str; 6] = ["OK", "1", "(nil)", "ERR unknown command: DEL a", "OK", "two"];
+
+    #[tokio::test]
+    async fn kv_store() {
+        let mut deployment = Deployment::new();
+
+        let flow = hydro_lang::FlowBuilder::new();
+        let process = flow.process();
+        let external = flow.external_process::<()>();
+        let (requests_port, responses_port) = super::kv_store(&process, &external);
+
+        let nodes = flow
+            .with_process(&process, deployment.Localhost())
+            .with_external(&external, deployment.Localhost())
+            .deploy(&mut deployment);
+
+        deployment.deploy().await.unwrap();
+
+        let mut requests = nodes.connect_sink_bincode(requests_port).await;
+        let responses = nodes.connect_source_bincode(responses_port).await;
+
+        deployment.start().await.unwrap();
+
+        for request in REQUESTS {
+            requests.send(request.to_string()).await.unwrap();
+        }
+        let received = responses.take(RESPONSES.len()).collect::<Vec<String>>().await;
+        assert_eq!(received, RESPONSES);
+    }
+
+    #[test]
+    fn test_legacy_kv_store_reads_stdin_as_one_batch() {
+        // Stdin is read to its end before the loop runs, so the legacy store answers
+        // nothing until the input is closed. The module above answers each request as
+        // it comes, which is what a command loop on stdin should become.
+        let legacy = syn::parse_file(include_str!("legacy/kv_store.rs")).unwrap();
+        let output = IOToHydroTransformer::new().transform_file(&legacy, "kv_store").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("letmutstdin_lines=Vec::new();"));
+        assert!(compact.contains("forlineinstdin_lines.into_iter(){"));
+    }
+}
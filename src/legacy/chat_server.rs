@@ -0,0 +1,32 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+fn main() {
+    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    println!("Chat server listening on 127.0.0.1:7878");
+
+    // Every connected client, by the number of its connection
+    let clients: Arc<Mutex<Vec<(usize, TcpStream)>>> = Arc::new(Mutex::new(Vec::new()));
+
+    for (id, stream) in listener.incoming().enumerate() {
+        let stream = stream.unwrap();
+        clients.lock().unwrap().push((id, stream.try_clone().unwrap()));
+
+        let clients = clients.clone();
+        thread::spawn(move || {
+            // Each line a client says goes to every client, itself included
+            for line in BufReader::new(stream).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let message = format!("[{}] {}\n", id, line);
+                for (_, client) in clients.lock().unwrap().iter_mut() {
+                    let _ = client.write_all(message.as_bytes());
+                }
+            }
+            clients.lock().unwrap().retain(|(client, _)| *client != id);
+        });
+    }
+}
@@ -0,0 +1,22 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+fn main() {
+    let mut store: HashMap<String, String> = HashMap::new();
+
+    for line in io::stdin().lock().lines() {
+        let line = line.unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["SET", key, value] => {
+                store.insert(key.to_string(), value.to_string());
+                println!("OK");
+            }
+            ["GET", key] => match store.get(*key) {
+                Some(value) => println!("{}", value),
+                None => println!("(nil)"),
+            },
+            _ => println!("ERR unknown command: {}", line),
+        }
+    }
+}
@@ -0,0 +1,11 @@
+fn main() {
+    let numbers: Vec<u64> = (1..=100).collect();
+
+    let mut total: u64 = 0;
+    for n in numbers {
+        let square = n * n;
+        total += square;
+    }
+
+    println!("Sum of squares: {}", total);
+}
@@ -6,6 +6,10 @@ pub mod counter;
 pub mod interactive_hello;
 pub mod echo_lines;
 pub mod mixed_io;
+pub mod word_count;
+pub mod chat_server;
+pub mod kv_store;
+pub mod map_reduce;
 
 pub fn main() {
     println!("Hello, world!");
@@ -0,0 +1,16 @@
+use std::collections::HashMap;
+
+fn main() {
+    let text = "the quick brown fox jumps over the lazy dog and the dog sleeps";
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word.to_string()).or_insert(0) += 1;
+    }
+
+    let mut words: Vec<(String, usize)> = counts.into_iter().collect();
+    words.sort();
+    for (word, count) in words {
+        println!("{}: {}", word, count);
+    }
+}
@@ -8,10 +8,75 @@ pub mod syn_hello_world;
 pub mod interactive_hello_hydro;
 pub mod echo_lines_hydro;
 pub mod mixed_io_hydro;
+pub mod word_count;
+pub mod chat_server;
+pub mod kv_store;
+pub mod map_reduce;
+pub mod backend;
+pub mod build;
+pub mod config;
+pub mod error;
+pub mod json_diagnostics;
 pub mod transformer;
 pub mod syn_transformer;
 pub mod io_transformer;
+pub mod print_rewriter;
+pub mod seeded_rng;
+pub mod data_parallel;
+pub mod channel_pipeline;
+pub mod shared_state;
+pub mod atomic_counter;
+pub mod iterator_chain;
+pub mod collect_loop;
+pub mod nested_join;
+pub mod keyed_aggregation;
+pub mod reduce_loop;
+pub mod user_types;
+pub mod captures;
+pub mod comments;
+pub mod formatting;
+pub mod remote_hosts;
+pub mod instrument;
+pub mod location_tags;
+pub mod compose;
+pub mod unsafe_code;
+pub mod target_cfg;
+pub mod lifecycle;
+pub mod windowed;
+pub mod loop_control;
+pub mod match_demux;
+pub mod fallible;
+pub mod json_lines;
+pub mod csv_source;
+pub mod sqlite_source;
+pub mod connectors;
+pub mod kafka_source;
+pub mod http_requests;
+pub mod http_service;
+pub mod websocket;
+pub mod shutdown;
+pub mod subprocess;
+pub mod file_watcher;
+pub mod polling;
+pub mod long_running;
+pub mod state_machine;
+pub mod report;
+pub mod output;
+pub mod source_map;
+pub mod transform_diff;
+pub mod assess;
+pub mod dataflow_graph;
+pub mod dependencies;
+pub mod loop_analysis;
+pub mod purity;
+pub mod cli_args;
+pub mod config_file;
 pub mod legacy;
+pub mod transform_rule;
+pub(crate) mod call_loop;
+pub(crate) mod syntax;
+#[cfg(test)]
+pub(crate) mod test_support;
 
 #[cfg(test)]
 mod test_init {
@@ -0,0 +1,160 @@
+use proc_macro2::{Literal, TokenStream};
+use quote::quote;
+use syn::{parse_quote, Expr, Ident};
+
+/// The flag that keeps a generated example's log to the warnings of its deployment
+pub const QUIET_FLAG: &str = "--quiet";
+
+/// Set up the lifecycle log of a generated example: one JSON object per line on stderr,
+/// so the program's own output on stdout stays as it was and a harness can follow the
+/// deployment by the `event` field of each line, `deploy_started`, `process_launched`,
+/// `stream_completed`, `stream_ended` or `timeout`. With `--quiet` only the events
+/// meaning the deployment went wrong are logged.
+pub(crate) fn logging() -> TokenStream {
+    quote! {
+        let quiet = std::env::args().any(|arg| arg == #QUIET_FLAG);
+        tracing_subscriber::fmt()
+            .json()
+            .with_writer(std::io::stderr)
+            .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+            .init();
+    }
+}
+
+/// Wait for a deployed dataflow to finish, in an example importing tokio's `timeout` and
+/// `Duration` and holding the deployed `nodes`. `signal` is a future giving `Some(())`
+/// once the dataflow signals completion, or `None` if it stops without doing so. Its end
+/// is logged as `stream_completed`, `stream_ended` or, after `timeout_secs`, `timeout`.
+/// If one of the `processes`, or a member of one of the `clusters`, exits first (by
+/// `std::process::exit` or a panic), the example exits with that status, as the legacy
+/// program would, and otherwise with 0 on completion and 1 if it times out.
+pub(crate) fn await_completion(signal: TokenStream, timeout_secs: &Literal, processes: &[Ident], clusters: &[Ident]) -> TokenStream {
+    quote! {
+        use hydro_lang::deploy::DeployCrateWrapper;
+
+        // The status of the first deployed process to exit
+        let exited = || async {
+            loop {
+                #(
+                    if let Some(code) = nodes.get_process(&#processes).underlying().read().await.exit_code() {
+                        return code;
+                    }
+                )*
+                #(
+                    for member in nodes.get_cluster(&#clusters).members() {
+                        if let Some(code) = member.underlying().read().await.exit_code() {
+                            return code;
+                        }
+                    }
+                )*
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        // Finish as soon as the dataflow signals completion, or report the status if
+        // a process exits first
+        let finished = timeout(Duration::from_secs(#timeout_secs), async {
+            tokio::select! {
+                signal = #signal => Ok(signal),
+                code = exited() => Err(code),
+            }
+        })
+        .await;
+        let exit_code = match finished {
+            Ok(Ok(Some(()))) => {
+                tracing::info!(event = "stream_completed", "Deployment completed successfully");
+                0
+            }
+            Ok(Ok(None)) => {
+                tracing::warn!(event = "stream_ended", "Dataflow stopped without signaling completion");
+                // The stream closes when its process dies, which may be noticed just before its status
+                timeout(Duration::from_secs(1), exited()).await.unwrap_or(1)
+            }
+            Ok(Err(code)) => code,
+            Err(_) => {
+                tracing::warn!(event = "timeout", timeout_secs = #timeout_secs, "Deployment did not complete in time");
+                1
+            }
+        };
+
+        tracing::info!(event = "process_exited", exit_code, "Process exited");
+        std::process::exit(exit_code);
+    }
+}
+
+/// The first command-line argument of a generated example that isn't one of its flags
+pub(crate) fn first_argument() -> TokenStream {
+    quote! { std::env::args().skip(1).find(|arg| arg != #QUIET_FLAG) }
+}
+
+/// A legacy program's clap parse, like `Args::parse()` or `command.get_matches()`,
+/// reading the example's command line without its flags, which clap would reject
+pub(crate) fn without_flags(parse: &Expr) -> Expr {
+    let arguments: Expr = parse_quote! { std::env::args().filter(|arg| arg != #QUIET_FLAG) };
+    let from = |name: &Ident| match name.to_string().as_str() {
+        method @ ("parse" | "try_parse" | "get_matches" | "try_get_matches") => Some(Ident::new(&format!("{}_from", method), name.span())),
+        _ => None,
+    };
+    let mut parse = parse.clone();
+    match &mut parse {
+        Expr::Call(call) if call.args.is_empty() => {
+            if let Expr::Path(path) = &mut *call.func {
+                if let Some(last) = path.path.segments.last_mut() {
+                    if let Some(method) = from(&last.ident) {
+                        last.ident = method;
+                        call.args.push(arguments);
+                    }
+                }
+            }
+        }
+        Expr::MethodCall(call) if call.args.is_empty() => {
+            if let Some(method) = from(&call.method) {
+                call.method = method;
+                call.args.push(arguments);
+            }
+        }
+        _ => {}
+    }
+    parse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::compact;
+
+    #[test]
+    fn test_quiet_is_a_flag_not_an_argument() {
+        let logging = compact(super::logging());
+        assert!(logging.contains("letquiet=std::env::args().any(|arg|arg==\"--quiet\");"));
+        assert!(logging.contains(".with_writer(std::io::stderr)"));
+        let setup = super::logging();
+        assert!(syn::parse2::<syn::Block>(quote! {{ #setup }}).is_ok());
+
+        let argument = compact(first_argument());
+        assert_eq!(argument, "std::env::args().skip(1).find(|arg|arg!=\"--quiet\")");
+
+        // Nor does clap see them
+        let parse = without_flags(&parse_quote! { Args::parse() });
+        assert_eq!(compact(&parse), "Args::parse_from(std::env::args().filter(|arg|arg!=\"--quiet\"))");
+        let matches = without_flags(&parse_quote! { Command::new("wc").arg(Arg::new("path")).get_matches() });
+        assert!(compact(&matches).ends_with(".get_matches_from(std::env::args().filter(|arg|arg!=\"--quiet\"))"));
+    }
+    #[test]
+    fn test_completion_is_awaited_up_to_the_timeout() {
+        let process = Ident::new("process", proc_macro2::Span::call_site());
+        let completion = compact(await_completion(quote! { done.next() }, &Literal::u64_unsuffixed(30), &[process], &[]));
+        assert!(completion.contains("letfinished=timeout(Duration::from_secs(30),async{tokio::select!{signal=done.next()=>Ok(signal),code=exited()=>Err(code),}}).await;"));
+        for event in ["stream_completed", "stream_ended", "timeout", "process_exited"] {
+            assert!(completion.contains(&format!("event=\"{}\"", event)), "{}", event);
+        }
+        assert!(completion.contains("nodes.get_process(&process).underlying().read().await.exit_code()"));
+        assert!(completion.ends_with("std::process::exit(exit_code);"));
+
+        // Cluster members are watched too
+        let workers = Ident::new("workers", proc_macro2::Span::call_site());
+        let completion = await_completion(quote! { client }, &Literal::u64_unsuffixed(30), &[], &[workers]);
+        assert!(compact(&completion).contains("formemberinnodes.get_cluster(&workers).members()"));
+        assert!(syn::parse2::<syn::Block>(quote! {{ #completion }}).is_ok());
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+
+use proc_macro2::{LineColumn, Span};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, FnArg, GenericArgument, GenericParam, Ident, Item, Lifetime, Pat, PathArguments, Type};
+
+use crate::error::TransformError;
+
+/// Make the dataflow function of a generated module generic over the tags of the
+/// processes and clusters it takes, so a larger flow can place it on locations with tag
+/// types of its own. A location with the implicit `()` tag gets a type parameter, `P`
+/// when it is the only one and `P1`, `P2` and so on otherwise. One with a named tag such
+/// as `Leader` gets a type parameter of that name, and the module keeps its struct for
+/// the example to use. Generic tags can't be inferred from the call, so the example names
+/// the tag of each location it creates: `()`, or the module's struct.
+pub fn generic_locations(module: &str, example: &str, module_name: &str) -> Result<(String, String), TransformError> {
+    let mut file = syn::parse_file(module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let dataflow = file
+        .items
+        .iter_mut()
+        .find_map(|item| match item {
+            Item::Fn(func) if func.sig.ident == module_name => Some(func),
+            _ => None,
+        })
+        .ok_or_else(|| TransformError::TemplateError(format!("No function named `{}` to make generic", module_name)))?;
+
+    // The tag of each location parameter, by its position, or `None` for the implicit one
+    let locations: Vec<(usize, Option<Ident>)> = dataflow
+        .sig
+        .inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, input)| location_tag(input).map(|tag| (index, tag)))
+        .collect();
+    if locations.is_empty() {
+        return Ok((module.to_string(), example.to_string()));
+    }
+
+    let lifetime = match dataflow.sig.generics.lifetimes().next() {
+        Some(param) => param.lifetime.clone(),
+        None => {
+            let lifetime: Lifetime = parse_quote!('a);
+            dataflow.sig.generics.params.insert(0, parse_quote!(#lifetime));
+            lifetime
+        }
+    };
+    let taken: Vec<Ident> = dataflow.sig.generics.type_params().map(|param| param.ident.clone()).collect();
+    let implicit_count = locations.iter().filter(|(_, tag)| tag.is_none()).count();
+    let mut implicit_types = None;
+    let mut implicit = (1..).map(|number| match implicit_count {
+        1 => Ident::new("P", Span::call_site()),
+        _ => Ident::new(&format!("P{}", number), Span::call_site()),
+    });
+
+    let mut parameters: Vec<Ident> = Vec::new();
+    for (index, tag) in &locations {
+        let parameter = match tag {
+            Some(tag) => tag.clone(),
+            None => implicit.find(|name| !taken.contains(name)).unwrap(),
+        };
+        if let FnArg::Typed(typed) = &mut dataflow.sig.inputs[*index] {
+            if let Type::Reference(reference) = &mut *typed.ty {
+                // The only implicit location's type is named the same way elsewhere in
+                // the function, once the generic parameters are in place
+                if implicit_count == 1 && tag.is_none() {
+                    let kind = location_kind(&reference.elem).unwrap();
+                    implicit_types = Some(ImplicitTags { kind, lifetime: lifetime.clone(), parameter: parameter.clone() });
+                }
+                set_tag(&mut reference.elem, &lifetime, &parameter);
+            }
+        }
+        if !parameters.contains(&parameter) && !taken.contains(&parameter) {
+            parameters.push(parameter);
+        }
+    }
+    for parameter in &parameters {
+        dataflow.sig.generics.params.push(GenericParam::Type(parse_quote!(#parameter)));
+    }
+    if let Some(mut implicit_types) = implicit_types {
+        implicit_types.visit_item_fn_mut(dataflow);
+    }
+
+    let example = name_example_tags(example, module_name, &locations)?;
+    Ok((prettyplease::unparse(&file), example))
+}
+
+/// The tag of a parameter taking a process or cluster: `Some(None)` for the implicit
+/// `()` and the name of a named one. Tags of any other shape are left alone.
+fn location_tag(input: &FnArg) -> Option<Option<Ident>> {
+    let FnArg::Typed(typed) = input else {
+        return None;
+    };
+    match &*typed.ty {
+        Type::Reference(reference) => type_tag(&reference.elem),
+        _ => None,
+    }
+}
+
+/// Whether a type is a `Process` or a `Cluster`, as the name it has
+pub(crate) fn location_kind(ty: &Type) -> Option<Ident> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    (segment.ident == "Process" || segment.ident == "Cluster").then(|| segment.ident.clone())
+}
+
+/// The tag of a `Process` or `Cluster` type, as `location_tag` gives it
+pub(crate) fn type_tag(ty: &Type) -> Option<Option<Ident>> {
+    location_kind(ty)?;
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let types: Vec<&Type> = match &path.path.segments.last()?.arguments {
+        PathArguments::None => Vec::new(),
+        PathArguments::AngleBracketed(arguments) => arguments
+            .args
+            .iter()
+            .filter_map(|argument| match argument {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        PathArguments::Parenthesized(_) => return None,
+    };
+    match types[..] {
+        [] => Some(None),
+        [Type::Tuple(unit)] if unit.elems.is_empty() => Some(None),
+        [Type::Path(tag)] if tag.qself.is_none() => tag.path.get_ident().map(|ident| Some(ident.clone())),
+        _ => None,
+    }
+}
+
+/// Set the tag of a `Process` or `Cluster` type to a type parameter
+fn set_tag(ty: &mut Type, lifetime: &Lifetime, parameter: &Ident) {
+    if let Type::Path(path) = ty {
+        if let Some(segment) = path.path.segments.last_mut() {
+            segment.arguments = PathArguments::AngleBracketed(parse_quote!(<#lifetime, #parameter>));
+        }
+    }
+}
+
+/// Gives the other types of the kind of the only implicit location the tag's type
+/// parameter
+struct ImplicitTags {
+    kind: Ident,
+    lifetime: Lifetime,
+    parameter: Ident,
+}
+
+impl VisitMut for ImplicitTags {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if location_kind(ty).as_ref() == Some(&self.kind) && type_tag(ty) == Some(None) {
+            set_tag(ty, &self.lifetime, &self.parameter);
+            return;
+        }
+        visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+/// Name the tags of the locations an example creates for the call of the dataflow
+/// function, rewriting `flow.process()` to `flow.process::<Tag>()`. The example is edited
+/// as text, so its comments and layout stay as they were.
+fn name_example_tags(example: &str, module_name: &str, locations: &[(usize, Option<Ident>)]) -> Result<String, TransformError> {
+    let file = syn::parse_file(example).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let mut call = DataflowCall { module_name, module_path: None, arguments: Vec::new() };
+    call.visit_file(&file);
+    let Some(module_path) = call.module_path else {
+        return Err(TransformError::TemplateError(format!("The example has no call of `{}` to name the tags of its locations for", module_name)));
+    };
+
+    // The tag each location binding of the call gets
+    let mut tags: BTreeMap<String, String> = BTreeMap::new();
+    for (index, tag) in locations {
+        let Some(Some(binding)) = call.arguments.get(*index) else {
+            continue;
+        };
+        let tag = match tag {
+            Some(tag) => format!("{}::{}", module_path, tag),
+            None => "()".to_string(),
+        };
+        tags.insert(binding.clone(), tag);
+    }
+
+    let mut creations = Creations { tags: &tags, edits: Vec::new() };
+    creations.visit_file(&file);
+    let mut edits = creations.edits;
+    if let Some(binding) = tags.keys().find(|binding| !edits.iter().any(|(_, _, _, created)| created == *binding)) {
+        return Err(TransformError::TemplateError(format!("The example doesn't create `{}` with `flow.process()` or `flow.cluster()`", binding)));
+    }
+
+    let mut named = example.to_string();
+    edits.sort_by_key(|(start, ..)| *start);
+    for (start, end, replacement, _) in edits.into_iter().rev() {
+        let (start, end) = (offset(example, start), offset(example, end));
+        named.replace_range(start..end, &replacement);
+    }
+    Ok(named)
+}
+
+/// The byte offset of a line and column in source text
+fn offset(text: &str, position: LineColumn) -> usize {
+    let line_start: usize = text.split_inclusive('\n').take(position.line - 1).map(str::len).sum();
+    let line = &text[line_start..];
+    line_start + line.char_indices().nth(position.column).map_or(line.len(), |(index, _)| index)
+}
+
+/// Finds the call of the dataflow function, with the path of its module and the bindings
+/// passed by reference as its arguments
+struct DataflowCall<'m> {
+    module_name: &'m str,
+    module_path: Option<String>,
+    arguments: Vec<Option<String>>,
+}
+
+impl<'ast> Visit<'ast> for DataflowCall<'_> {
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        if let Expr::Path(path) = &*call.func {
+            let segments: Vec<String> = path.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+            if segments.len() >= 2 && segments[segments.len() - 2..].iter().all(|segment| segment == self.module_name) {
+                self.module_path = Some(segments[..segments.len() - 1].join("::"));
+                self.arguments = call
+                    .args
+                    .iter()
+                    .map(|argument| match argument {
+                        Expr::Reference(reference) => match &*reference.expr {
+                            Expr::Path(binding) => binding.path.get_ident().map(Ident::to_string),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect();
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+}
+
+/// Finds the `let`s creating the locations that get named tags, with the text to replace
+/// the turbofish and arguments of their `process` or `cluster` call with
+struct Creations<'t> {
+    tags: &'t BTreeMap<String, String>,
+    edits: Vec<(LineColumn, LineColumn, String, String)>,
+}
+
+impl<'ast> Visit<'ast> for Creations<'_> {
+    fn visit_local(&mut self, local: &'ast syn::Local) {
+        let binding = match &local.pat {
+            Pat::Ident(binding) => Some(&binding.ident),
+            Pat::Type(typed) => match &*typed.pat {
+                Pat::Ident(binding) => Some(&binding.ident),
+                _ => None,
+            },
+            _ => None,
+        };
+        let tag = binding.and_then(|binding| self.tags.get(&binding.to_string()));
+        if let (Some(binding), Some(tag), Some(init)) = (binding, tag, &local.init) {
+            if let Expr::MethodCall(creation) = &*init.expr {
+                if creation.method == "process" || creation.method == "cluster" {
+                    let replacement = format!("::<{}>()", tag);
+                    self.edits.push((creation.method.span().end(), creation.span().end(), replacement, binding.to_string()));
+                }
+            }
+        }
+        visit::visit_local(self, local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_tags_become_type_parameters() {
+        let module = "use hydro_lang::*;\n\npub struct Leader {}\npub struct Worker {}\n\npub fn squares<'a>(leader: &Process<'a, Leader>, workers: &Cluster<'a, Worker>, external: &ExternalProcess<'a, ()>) {\n    leader.source_iter(q!(0..10)).round_robin_bincode(workers).for_each(q!(|n| println!(\"{}\", n * n)));\n}\n";
+        let example = "fn main() {\n    let flow = hydro_lang::FlowBuilder::new();\n    // The leader hands out the numbers\n    let leader = flow.process();\n    let workers = flow.cluster();\n    let external = flow.external_process::<()>();\n    hydro_template::squares::squares(&leader, &workers, &external);\n}\n";
+        let (generic, named) = generic_locations(module, example, "squares").unwrap();
+        let compact: String = generic.split_whitespace().collect();
+        assert!(compact.contains("pubfnsquares<'a,Leader,Worker>(leader:&Process<'a,Leader>,workers:&Cluster<'a,Worker>,external:&ExternalProcess<'a,()>,)"));
+        assert!(compact.contains("pubstructLeader{}"));
+        assert!(named.contains("    // The leader hands out the numbers\n    let leader = flow.process::<hydro_template::squares::Leader>();\n"));
+        assert!(named.contains("let workers = flow.cluster::<hydro_template::squares::Worker>();"));
+        assert!(named.contains("let external = flow.external_process::<()>();"));
+
+        // Implicit tags are numbered when there are several
+        let module = "pub fn relay(a: &Process, b: &Process<'_, ()>) -> Stream<u32, Process<'_>, Unbounded> {\n    todo!()\n}\n";
+        let example = "fn main() {\n    let a = flow.process::<()>();\n    let b = flow.process();\n    crate::relay::relay(&a, &b);\n}\n";
+        let (generic, named) = generic_locations(module, example, "relay").unwrap();
+        let compact: String = generic.split_whitespace().collect();
+        assert!(compact.contains("pubfnrelay<'a,P1,P2>(a:&Process<'a,P1>,b:&Process<'a,P2>,)->Stream<u32,Process<'_>,Unbounded>"));
+        assert_eq!(named, "fn main() {\n    let a = flow.process::<()>();\n    let b = flow.process::<()>();\n    crate::relay::relay(&a, &b);\n}\n");
+
+        assert!(generic_locations(module, "fn main() {}\n", "relay").is_err());
+    }
+
+    #[test]
+    fn test_the_only_implicit_tag_is_named_throughout() {
+        let module = "pub fn echo<'a>(process: &Process<'a>) -> Stream<String, Process<'a>, Unbounded> {\n    let lines: Stream<String, Process<'a>, Unbounded> = process.source_iter(q!(vec![]));\n    lines\n}\n";
+        let example = "fn main() {\n    let process = flow.process();\n    let _ = hydro_template::echo::echo(&process);\n}\n";
+        let (generic, named) = generic_locations(module, example, "echo").unwrap();
+        let compact: String = generic.split_whitespace().collect();
+        assert!(compact.contains("pubfnecho<'a,P>(process:&Process<'a,P>,)->Stream<String,Process<'a,P>,Unbounded>"));
+        assert!(compact.contains("letlines:Stream<String,Process<'a,P>,Unbounded>"));
+        assert!(named.contains("let process = flow.process::<()>();"));
+    }
+}
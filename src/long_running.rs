@@ -0,0 +1,127 @@
+use syn::visit::{self, Visit};
+use syn::{Expr, Stmt};
+
+/// The socket types whose `bind` makes a legacy program a server
+const SOCKETS: [&str; 4] = ["TcpListener", "UdpSocket", "UnixListener", "UnixDatagram"];
+
+/// What makes a legacy program run until it is stopped: a loop that never ends, or a
+/// socket it serves on. Its example runs as a service rather than waiting for the
+/// dataflow to finish.
+#[derive(Default)]
+pub struct LongRunning {
+    /// Whether a loop never ends
+    pub forever: bool,
+    /// The addresses the program binds its sockets to, as it wrote them
+    pub addresses: Vec<Expr>,
+}
+
+impl LongRunning {
+    /// Find `loop`s with no `break` or `return` out of them, `while true` loops, and
+    /// calls to `bind` on a listener or socket anywhere in a legacy body
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let mut scan = Self::default();
+        stmts.iter().for_each(|stmt| scan.visit_stmt(stmt));
+        (scan.forever || !scan.addresses.is_empty()).then_some(scan)
+    }
+}
+
+impl<'ast> Visit<'ast> for LongRunning {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Loop(looped) if !exits(&looped.body.stmts) => self.forever = true,
+            Expr::While(looped) if *looped.cond == syn::parse_quote! { true } && !exits(&looped.body.stmts) => self.forever = true,
+            Expr::Call(call) => {
+                if let Expr::Path(func) = &*call.func {
+                    let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+                    if let ([.., socket, bind], Some(address)) = (&segments[..], call.args.first()) {
+                        if bind == "bind" && SOCKETS.contains(&socket.as_str()) {
+                            self.addresses.push(address.clone());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        visit::visit_expr(self, expr);
+    }
+}
+
+/// Whether a loop's body can leave it: a `return`, an unlabeled `break` outside any
+/// nested loop, or a `break` to a label other than a nested loop's
+fn exits(body: &[Stmt]) -> bool {
+    #[derive(Default)]
+    struct Exits<'ast> {
+        nested: Vec<Option<&'ast syn::Lifetime>>,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Exits<'ast> {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            let label = match expr {
+                Expr::Return(_) => {
+                    self.found = true;
+                    return;
+                }
+                Expr::Break(broken) => {
+                    self.found |= match &broken.label {
+                        None => self.nested.is_empty(),
+                        Some(target) => !self.nested.contains(&Some(target)),
+                    };
+                    None
+                }
+                Expr::Closure(_) | Expr::Async(_) => return,
+                Expr::Loop(looped) => Some(looped.label.as_ref().map(|label| &label.name)),
+                Expr::While(looped) => Some(looped.label.as_ref().map(|label| &label.name)),
+                Expr::ForLoop(looped) => Some(looped.label.as_ref().map(|label| &label.name)),
+                _ => None,
+            };
+            match label {
+                Some(label) => {
+                    self.nested.push(label);
+                    visit::visit_expr(self, expr);
+                    self.nested.pop();
+                }
+                None => visit::visit_expr(self, expr),
+            }
+        }
+    }
+
+    let mut exits = Exits::default();
+    body.iter().for_each(|stmt| exits.visit_stmt(stmt));
+    exits.found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+    use quote::ToTokens;
+
+    fn detect(source: &str) -> Option<LongRunning> {
+        LongRunning::detect(&parse_block(source))
+    }
+
+    #[test]
+    fn test_server_socket_and_endless_loop() {
+        let service = detect(r#"{
+            let listener = std::net::TcpListener::bind("127.0.0.1:7878").unwrap();
+            for stream in listener.incoming() {
+                handle(stream.unwrap());
+            }
+        }"#).unwrap();
+        assert!(!service.forever);
+        let addresses: Vec<String> = service.addresses.iter().map(|address| address.to_token_stream().to_string()).collect();
+        assert_eq!(addresses, ["\"127.0.0.1:7878\""]);
+
+        // Breaking out of an inner loop doesn't end the outer one
+        let service = detect("{ loop { for line in lines() { if line.is_empty() { break; } } } }").unwrap();
+        assert!(service.forever && service.addresses.is_empty());
+    }
+
+    #[test]
+    fn test_programs_that_finish() {
+        assert!(detect("{ loop { if done() { break; } } }").is_none());
+        assert!(detect("{ 'outer: loop { loop { break 'outer; } } }").is_none());
+        assert!(detect("{ while running() { work(); } }").is_none());
+    }
+}
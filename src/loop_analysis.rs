@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use quote::ToTokens;
+use syn::visit::{self, Visit};
+use syn::{Expr, Item, Pat, Stmt};
+
+use crate::purity::Effect;
+use crate::syntax::{is_compound_assign, place_root};
+
+/// The methods that add an item to a collection
+const PUSHES: [&str; 6] = ["push", "push_back", "push_front", "push_str", "insert", "extend"];
+/// The methods that update a value through a shared reference
+const INTERIOR_UPDATES: [&str; 11] =
+    ["fetch_add", "fetch_sub", "fetch_or", "fetch_and", "fetch_max", "fetch_min", "store", "swap", "lock", "borrow_mut", "set"];
+
+/// The shape of a loop, by how its iterations depend on the bindings made before it.
+/// It decides the operators the loop becomes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKind {
+    /// Each iteration pushes values computed from its item onto collections, which
+    /// become a `map` (or `flat_map`) whose output is collected
+    MapLike,
+    /// Each iteration combines its item into state carried across iterations, which
+    /// becomes a `fold`
+    FoldLike,
+    /// The iterations only have effects, and become a `for_each`
+    EffectOnly,
+    /// Whether to run another iteration depends on the ones before it: a `while` or
+    /// `loop`, or a `for` that breaks or returns. It needs a cycle carrying its state.
+    Stateful,
+}
+
+/// A top-level loop of a legacy body and its classification
+#[derive(Debug, Clone)]
+pub struct LoopAnalysis {
+    /// The index of the loop among the body's statements
+    pub index: usize,
+    pub kind: LoopKind,
+    /// The bindings made before the loop that its body updates
+    pub state: Vec<String>,
+    /// Whether the body skips to the next iteration with `continue`
+    pub skips: bool,
+    /// The strongest effect of the body
+    pub effect: Effect,
+}
+
+impl LoopAnalysis {
+    /// Classify each `for`, `while` and `loop` at the top level of a body
+    pub fn of_body(items: &[Item], stmts: &[Stmt]) -> Vec<Self> {
+        (0..stmts.len()).filter_map(|index| Self::of_loop(items, stmts, index)).collect()
+    }
+
+    /// Classify the loop at `index` of a body, if that statement is one
+    pub fn of_loop(items: &[Item], stmts: &[Stmt], index: usize) -> Option<Self> {
+        let (label, body, iterates) = match &stmts[index] {
+            Stmt::Expr(Expr::ForLoop(for_loop), _) => (&for_loop.label, &for_loop.body, true),
+            Stmt::Expr(Expr::While(while_loop), _) => (&while_loop.label, &while_loop.body, false),
+            Stmt::Expr(Expr::Loop(plain_loop), _) => (&plain_loop.label, &plain_loop.body, false),
+            _ => return None,
+        };
+
+        // The bindings before the loop, with whether each is mutable
+        let mut outer = HashMap::new();
+        for stmt in &stmts[..index] {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut outer);
+            }
+        }
+        let mut updates = Updates { outer: &outer, loops: 0, closures: 0, pushed: Vec::new(), updated: Vec::new(), reads: HashMap::new(), skips: false, exits: label.is_some() };
+        updates.visit_block(body);
+
+        let mut state: Vec<String> = updates.pushed.iter().chain(&updates.updated).cloned().collect();
+        state.sort();
+        state.dedup();
+        // A collection is only the output of a map if the iterations don't look at it
+        let maps = updates.updated.is_empty() && updates.pushed.iter().all(|name| updates.reads.get(name).copied().unwrap_or(0) == 0);
+        let kind = if !iterates || updates.exits {
+            LoopKind::Stateful
+        } else if state.is_empty() {
+            LoopKind::EffectOnly
+        } else if maps {
+            LoopKind::MapLike
+        } else {
+            LoopKind::FoldLike
+        };
+
+        let effect = body.stmts.iter().map(|stmt| Effect::of(items, stmt)).max().unwrap_or(Effect::Pure);
+        Some(Self { index, kind, state, skips: updates.skips, effect })
+    }
+}
+
+/// Add the names a pattern binds, with whether each is mutable
+fn bindings(pat: &Pat, names: &mut HashMap<String, bool>) {
+    struct Bindings<'n> {
+        names: &'n mut HashMap<String, bool>,
+    }
+
+    impl<'ast> Visit<'ast> for Bindings<'_> {
+        fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+            self.names.insert(pat.ident.to_string(), pat.mutability.is_some());
+            visit::visit_pat_ident(self, pat);
+        }
+    }
+
+    Bindings { names }.visit_pat(pat);
+}
+
+/// How a loop body uses the bindings before the loop, and whether it leaves the loop.
+/// Names the body shadows are still taken for the outer bindings, which only makes the
+/// classification more conservative.
+struct Updates<'o> {
+    outer: &'o HashMap<String, bool>,
+    /// How many loops inside the body the visitor is in, which a plain `break` only leaves
+    loops: usize,
+    /// How many closures and async blocks it is in, which a `return` only leaves
+    closures: usize,
+    /// The outer collections pushed onto
+    pushed: Vec<String>,
+    /// The outer bindings updated in any other way
+    updated: Vec<String>,
+    /// How many times each outer binding is read, besides being pushed onto
+    reads: HashMap<String, usize>,
+    skips: bool,
+    exits: bool,
+}
+
+impl Updates<'_> {
+    /// An update of a place, which updates its outer root, or some state outside the
+    /// program's bindings
+    fn update(&mut self, place: &Expr) {
+        match place_root(place) {
+            Some(root) if self.outer.contains_key(&root) => self.updated.push(root),
+            Some(_) => {}
+            None => self.updated.push(place.to_token_stream().to_string()),
+        }
+    }
+
+    /// Whether a `break` or `continue` leaves the iteration of the loop being classified
+    fn jumps(&self, label: &Option<syn::Lifetime>) -> bool {
+        self.closures == 0 && (self.loops == 0 || label.is_some())
+    }
+}
+
+impl<'ast> Visit<'ast> for Updates<'_> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Assign(assign) => self.update(&assign.left),
+            Expr::Binary(binary) if is_compound_assign(&binary.op) => self.update(&binary.left),
+            Expr::Reference(reference) if reference.mutability.is_some() => self.update(&reference.expr),
+            Expr::MethodCall(call) => {
+                let method = call.method.to_string();
+                if let Some(receiver) = place_root(&call.receiver).filter(|name| self.outer.contains_key(name)) {
+                    if PUSHES.contains(&method.as_str()) && self.outer[&receiver] {
+                        self.pushed.push(receiver);
+                        // The receiver isn't a read of the collection
+                        call.args.iter().for_each(|arg| self.visit_expr(arg));
+                        return;
+                    }
+                    if self.outer[&receiver] || INTERIOR_UPDATES.contains(&method.as_str()) {
+                        self.updated.push(receiver);
+                    }
+                }
+            }
+            Expr::Path(path) => {
+                if let Some(name) = path.path.get_ident().map(|ident| ident.to_string()) {
+                    if self.outer.contains_key(&name) {
+                        *self.reads.entry(name).or_default() += 1;
+                    }
+                }
+            }
+            Expr::Continue(jump) if self.jumps(&jump.label) => self.skips = true,
+            Expr::Break(jump) if self.jumps(&jump.label) => self.exits = true,
+            Expr::Return(_) | Expr::Try(_) if self.closures == 0 => self.exits = true,
+            Expr::ForLoop(_) | Expr::While(_) | Expr::Loop(_) => {
+                self.loops += 1;
+                visit::visit_expr(self, expr);
+                self.loops -= 1;
+                return;
+            }
+            Expr::Closure(_) | Expr::Async(_) => {
+                self.closures += 1;
+                visit::visit_expr(self, expr);
+                self.closures -= 1;
+                return;
+            }
+            _ => {}
+        }
+        visit::visit_expr(self, expr);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        // Format arguments read the bindings before the loop too
+        let args = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated);
+        if let Ok(args) = args {
+            for arg in &args {
+                self.visit_expr(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+
+    fn kinds(body: &str) -> Vec<LoopKind> {
+        LoopAnalysis::of_body(&[], &parse_block(body)).iter().map(|analysis| analysis.kind).collect()
+    }
+
+    #[test]
+    fn test_loops_are_classified() {
+        use LoopKind::*;
+        assert_eq!(kinds(r#"{
+            let words = vec!["a", "bb"];
+            let mut lengths = Vec::new();
+            for word in &words { lengths.push(word.len()); }
+            let mut total = 0;
+            for length in &lengths { total += length; }
+            for word in &words { println!("{}", word); }
+            let mut n = total;
+            while n > 0 { n /= 2; }
+        }"#), [MapLike, FoldLike, EffectOnly, Stateful]);
+    }
+
+    #[test]
+    fn test_reading_what_it_builds_is_a_fold() {
+        use LoopKind::*;
+        // The iterations look at what the ones before pushed
+        assert_eq!(kinds("{ let mut seen = Vec::new(); for x in 0..10 { if !seen.contains(&x) { seen.push(x); } } }"), [FoldLike]);
+        // An atomic updated through a shared reference
+        assert_eq!(kinds("{ let hits = AtomicUsize::new(0); for _ in 0..3 { hits.fetch_add(1, Ordering::SeqCst); } }"), [FoldLike]);
+        // Reading an immutable binding leaves it alone
+        assert_eq!(kinds("{ let scale = 2; for x in 0..3 { println!(\"{}\", x * scale.abs()); } }"), [EffectOnly]);
+    }
+
+    #[test]
+    fn test_leaving_early_is_stateful() {
+        let block: syn::Block = syn::parse_str(r#"{
+            for x in 0..10 { if x > 5 { break; } println!("{}", x); }
+            for x in 0..10 { for y in 0..x { if y > 2 { break; } } if x % 2 == 0 { continue; } println!("{}", x); }
+        }"#).unwrap();
+        let analyses = LoopAnalysis::of_body(&[], &block.stmts);
+
+        assert_eq!(analyses[0].kind, LoopKind::Stateful);
+        // Only the inner loop is left early, and skipping an iteration is a filter
+        assert_eq!((analyses[1].kind, analyses[1].skips), (LoopKind::EffectOnly, true));
+        assert_eq!(analyses[1].effect, Effect::Io);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+
+use proc_macro2::Span;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Pat, Stmt};
+
+use crate::print_rewriter::terminate_tail;
+use crate::syntax::{bindings, mentions};
+
+/// A legacy `for` loop that leaves iterations early with `continue`, stops with `break`
+/// or ends the program with `return`, along with the statements around it. Run once
+/// per item, the body reports which of those it did instead of jumping.
+pub struct ControlledLoop {
+    /// The statements before the loop, whose bindings live on across iterations
+    pub prefix: Vec<Stmt>,
+    pub pat: Pat,
+    pub items: Expr,
+    /// Conditions of the `if cond { continue; }` guards the body starts with, which
+    /// only look at the item and so can drop it before the body runs
+    pub skips: Vec<Expr>,
+    /// The rest of the body as a block evaluating to a `std::ops::ControlFlow<bool>`:
+    /// `Continue` moves on to the next item, `Break(true)` leaves the loop and
+    /// `Break(false)` returns from the program, skipping the suffix
+    pub body: Expr,
+    /// The statements after the loop
+    pub suffix: Vec<Stmt>,
+    /// Whether the body propagates errors out of `main` with `?`
+    pub fallible: bool,
+}
+
+impl ControlledLoop {
+    /// Find the first top-level `for` loop that jumps out of its body. Loops without
+    /// jumps are left to the translations that run their body as is. A `return` with a
+    /// value can't be expressed per item, and neither can `?` outside the loop, where
+    /// the code doesn't run per item, so those are rejected.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop) = first_loop(stmts)?;
+        let label = loop_label(for_loop);
+        let (prefix, suffix) = (&stmts[..index], &stmts[index + 1..]);
+
+        // Only the loop body runs per item, so the code around it must not leave main
+        let mut around = Exits::default();
+        for stmt in prefix.iter().chain(suffix) {
+            around.visit_stmt(stmt);
+        }
+        let mut exits = Exits::default();
+        exits.visit_block(&for_loop.body);
+        if around.returns || around.unsupported || around.tries || exits.unsupported {
+            return None;
+        }
+        if !exits.returns && !exits.tries && !jumps_out(&for_loop.body.stmts, label.as_deref(), true) {
+            return None;
+        }
+
+        let mut body = for_loop.body.stmts.as_slice();
+        let mut skips = Vec::new();
+        while let Some((stmt, rest)) = body.split_first() {
+            let Some(cond) = skip_guard(stmt, label.as_deref()) else {
+                break;
+            };
+            // The guard runs in its own operator, away from the bindings before the loop
+            if prefix.iter().any(|stmt| binds_used_in(stmt, cond)) {
+                break;
+            }
+            skips.push(cond.clone());
+            body = rest;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            skips,
+            body: iteration_block(body, label.as_deref()),
+            suffix: suffix.to_vec(),
+            fallible: exits.tries,
+        })
+    }
+}
+
+/// The first top-level `for` loop and its position
+pub fn first_loop(stmts: &[Stmt]) -> Option<(usize, &syn::ExprForLoop)> {
+    stmts.iter().enumerate().find_map(|(index, stmt)| match stmt {
+        Stmt::Expr(Expr::ForLoop(for_loop), _) => Some((index, for_loop)),
+        _ => None,
+    })
+}
+
+/// The name of the loop's label, if it has one
+pub fn loop_label(for_loop: &syn::ExprForLoop) -> Option<String> {
+    for_loop.label.as_ref().map(|label| label.name.ident.to_string())
+}
+
+/// Statements from the body of the loop labeled `label` as the block one iteration
+/// runs in, with the jumps out of it rewritten to say what the loop does next
+pub fn iteration_block(body: &[Stmt], label: Option<&str>) -> Expr {
+    let mut body = body.to_vec();
+    let mut rewriter = JumpRewriter { label, depth: 0 };
+    for stmt in &mut body {
+        rewriter.visit_stmt_mut(stmt);
+    }
+    terminate_tail(&mut body);
+    let flow_label = flow_label();
+    parse_quote! {
+        #flow_label: {
+            #(#body)*
+            std::ops::ControlFlow::Continue(())
+        }
+    }
+}
+
+/// Whether the statements break out of the loop labeled `label` or return from main
+pub fn leaves_loop(body: &[Stmt], label: Option<&str>) -> bool {
+    let mut exits = Exits::default();
+    for stmt in body {
+        exits.visit_stmt(stmt);
+    }
+    exits.returns || jumps_out(body, label, false)
+}
+
+/// The label of the block each iteration's body runs in
+pub fn flow_label() -> syn::Lifetime {
+    syn::Lifetime::new("'iteration", Span::call_site())
+}
+
+/// `if cond { continue; }` with nothing else in it, returning `cond`
+fn skip_guard<'s>(stmt: &'s Stmt, label: Option<&str>) -> Option<&'s Expr> {
+    let Stmt::Expr(Expr::If(guard), _) = stmt else {
+        return None;
+    };
+    if guard.else_branch.is_some() {
+        return None;
+    }
+    match &guard.then_branch.stmts[..] {
+        [Stmt::Expr(Expr::Continue(jump), _)] if targets(&jump.label, label, 0) => Some(&guard.cond),
+        _ => None,
+    }
+}
+
+/// Whether a jump with this label, `depth` loops inside the legacy loop's body, leaves
+/// that loop's iteration
+pub fn targets(jump: &Option<syn::Lifetime>, label: Option<&str>, depth: usize) -> bool {
+    match jump {
+        None => depth == 0,
+        Some(jump) => label.is_some_and(|label| jump.ident == label),
+    }
+}
+
+/// Whether any `break`, or `continue` if `continues` is set, in the body leaves the
+/// loop's own iteration
+fn jumps_out(body: &[Stmt], label: Option<&str>, continues: bool) -> bool {
+    struct Jumps<'l> {
+        label: Option<&'l str>,
+        continues: bool,
+        depth: usize,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Jumps<'_> {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::Break(jump) => self.found |= targets(&jump.label, self.label, self.depth),
+                Expr::Continue(jump) if self.continues => {
+                    self.found |= targets(&jump.label, self.label, self.depth)
+                }
+                Expr::Closure(_) => return,
+                Expr::ForLoop(_) | Expr::While(_) | Expr::Loop(_) => {
+                    self.depth += 1;
+                    visit::visit_expr(self, expr);
+                    self.depth -= 1;
+                    return;
+                }
+                _ => {}
+            }
+            visit::visit_expr(self, expr);
+        }
+    }
+
+    let mut jumps = Jumps { label, continues, depth: 0, found: false };
+    for stmt in body {
+        jumps.visit_stmt(stmt);
+    }
+    jumps.found
+}
+
+/// Finds how the legacy code leaves `main`: a bare `return` or a `?` have counterparts
+/// once the code runs inside operators, but returning a value doesn't
+#[derive(Default)]
+struct Exits {
+    returns: bool,
+    tries: bool,
+    unsupported: bool,
+}
+
+impl<'ast> Visit<'ast> for Exits {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Return(ret) => {
+                self.returns = true;
+                self.unsupported |= ret.expr.is_some();
+            }
+            Expr::Try(_) => self.tries = true,
+            // These return from something else
+            Expr::Closure(_) | Expr::Async(_) => return,
+            _ => {}
+        }
+        visit::visit_expr(self, expr);
+    }
+
+    fn visit_item(&mut self, _: &'ast syn::Item) {}
+}
+
+/// Whether the statement binds a name the expression uses
+fn binds_used_in(stmt: &Stmt, expr: &Expr) -> bool {
+    let Stmt::Local(local) = stmt else {
+        return false;
+    };
+    let mut names = HashSet::new();
+    bindings(&local.pat, &mut names);
+    let expr = [Stmt::Expr(expr.clone(), None)];
+    names.iter().any(|name| mentions(&expr, name))
+}
+
+/// Turns the jumps leaving an iteration into breaks out of its labeled block, carrying
+/// what the loop should do next
+struct JumpRewriter<'l> {
+    label: Option<&'l str>,
+    depth: usize,
+}
+
+impl VisitMut for JumpRewriter<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let flow_label = flow_label();
+        match expr {
+            Expr::Continue(jump) if targets(&jump.label, self.label, self.depth) => {
+                *expr = parse_quote! { break #flow_label std::ops::ControlFlow::Continue(()) };
+            }
+            Expr::Break(jump) if targets(&jump.label, self.label, self.depth) => {
+                *expr = parse_quote! { break #flow_label std::ops::ControlFlow::Break(true) };
+            }
+            Expr::Return(_) => {
+                *expr = parse_quote! { break #flow_label std::ops::ControlFlow::Break(false) };
+            }
+            Expr::Closure(_) | Expr::Async(_) => {}
+            Expr::ForLoop(_) | Expr::While(_) | Expr::Loop(_) => {
+                self.depth += 1;
+                visit_mut::visit_expr_mut(self, expr);
+                self.depth -= 1;
+            }
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _: &mut syn::Item) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<ControlledLoop> {
+        ControlledLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_leading_continue_becomes_a_skip() {
+        let controlled = detect(r#"{
+            for word in words {
+                if word.is_empty() { continue; }
+                println!("{}", word);
+            }
+            println!("done");
+        }"#).unwrap();
+
+        assert_eq!(controlled.skips.len(), 1);
+        assert_eq!(compact(&controlled.skips[0]), "word.is_empty()");
+        assert_eq!(
+            compact(&controlled.body),
+            "'iteration:{println!(\"{}\",word);std::ops::ControlFlow::Continue(())}"
+        );
+        assert_eq!(controlled.suffix.len(), 1);
+    }
+
+    #[test]
+    fn test_break_and_return_report_how_the_loop_ends() {
+        let controlled = detect(r#"{
+            let mut total = 0;
+            for n in numbers {
+                match n {
+                    Some(0) => continue,
+                    Some(n) => total += n,
+                    None => break,
+                }
+                if total > 100 { return; }
+            }
+            println!("{}", total);
+        }"#).unwrap();
+
+        assert!(controlled.skips.is_empty());
+        let body = compact(&controlled.body);
+        assert!(body.contains("Some(0)=>break'iterationstd::ops::ControlFlow::Continue(())"));
+        assert!(body.contains("None=>break'iterationstd::ops::ControlFlow::Break(true)"));
+        assert!(body.contains("iftotal>100{break'iterationstd::ops::ControlFlow::Break(false);}"));
+        assert_eq!(controlled.prefix.len(), 1);
+    }
+
+    #[test]
+    fn test_inner_jumps_and_unsupported_exits() {
+        // The break belongs to the inner loop, so the outer one never jumps
+        assert!(detect("{ for x in xs { for y in ys { if y > x { break; } } } }").is_none());
+        // A labeled break out of the outer loop does
+        let controlled = detect("{ 'rows: for x in xs { for y in ys { if y > x { break 'rows; } } } }").unwrap();
+        assert!(compact(&controlled.body).contains("foryinys"));
+        assert!(compact(&controlled.body).contains("Break(true)"));
+        // `?` leaves main from inside the body, but not from the code around it
+        assert!(detect("{ for x in xs { let n: i32 = x.parse()?; if n < 0 { break; } } }").unwrap().fallible);
+        assert!(detect("{ let n: i32 = s.parse()?; for x in xs { if x > n { break; } } }").is_none());
+        // A guard reading a binding from before the loop stays in the body
+        let controlled = detect("{ let limit = 3; for x in xs { if x > limit { continue; } println!(\"{}\", x); } }").unwrap();
+        assert!(controlled.skips.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use hydro_lang::*;
+
+use crate::first_ten_cluster::{Leader, Worker};
+
+/// The legacy sum of squares (`legacy::map_reduce`) written by hand as a map-reduce over
+/// a cluster, the shape `--distribute reduce` generates: the leader shards the numbers
+/// round-robin and then tells every member that there are no more, each member squares
+/// its share and sums it into a partial, and the leader adds up the partials once every
+/// member has reported. The leader only finds out how many members there are at
+/// runtime, so the function works with a cluster of any size.
+pub fn map_reduce<'a>(leader: &Process<'a, Leader>, workers: &Cluster<'a, Worker>) {
+    // The end marker goes on the same channel as the numbers, so it arrives after them
+    let members = workers.members();
+    let partials = leader
+        .source_iter(q!((1..=100u64).map(Some).chain(std::iter::once(None)).enumerate()))
+        .flat_map_ordered(q!(|(index, n)| match n {
+            Some(n) => vec![(members[index % members.len()], Some(n))],
+            None => members.iter().map(|member| (*member, None)).collect(),
+        })) // : Stream<(ClusterId<Worker>, Option<u64>), Process<Leader>, ...>
+        .demux_bincode(workers) // : Stream<Option<u64>, Cluster<Worker>, ...>
+        .filter_map(q!({
+            let mut partial = 0;
+            move |n: Option<u64>| match n {
+                Some(n) => {
+                    partial += n * n;
+                    None
+                }
+                None => Some(partial),
+            }
+        })) // : Stream<u64, Cluster<Worker>, ...>
+        .send_bincode_anonymous(leader); // : Stream<u64, Process<Leader>, ..., NoOrder>
+
+    let reporting = workers.members();
+    partials
+        .filter_map(q!({
+            let mut total = 0;
+            let mut reported = 0;
+            move |partial: u64| {
+                total += partial;
+                reported += 1;
+                if reported == reporting.len() { Some(total) } else { None }
+            }
+        })) // : Stream<u64, Process<Leader>, ...>
+        .for_each(q!(|total| println!("Sum of squares: {}", total)));
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use hydro_deploy::Deployment;
+    use hydro_lang::deploy::DeployCrateWrapper;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use crate::config::{Distribution, Target, TransformerConfig};
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    /// What the legacy program prints
+    const EXPECTED: &str = "Sum of squares: 338350";
+
+    #[tokio::test]
+    async fn map_reduce() {
+        // A single member gets every number, and more members each get a share
+        for members in [1, 4] {
+            let mut deployment = Deployment::new();
+            let localhost = deployment.Localhost();
+
+            let flow = hydro_lang::FlowBuilder::new();
+            let leader = flow.process();
+            let workers = flow.cluster();
+            super::map_reduce(&leader, &workers);
+
+            let nodes = flow
+                .with_process(&leader, localhost.clone())
+                .with_cluster(&workers, vec![localhost.clone(); members])
+                .deploy(&mut deployment);
+
+            deployment.deploy().await.unwrap();
+
+            let leader_stdout = nodes.get_process(&leader).stdout().await;
+
+            deployment.start().await.unwrap();
+
+            let out = UnboundedReceiverStream::new(leader_stdout).take(1).collect::<Vec<_>>().await;
+            assert_eq!(out, [EXPECTED]);
+        }
+    }
+
+    #[test]
+    fn test_legacy_map_reduce_is_reduced_at_a_leader() {
+        let legacy = syn::parse_file(include_str!("legacy/map_reduce.rs")).unwrap();
+
+        let config = TransformerConfig::new().with_target(Target::Cluster).with_distribution(Distribution::Reduce);
+        let reduce = SynLegacyToHydroTransformer::new().with_config(config);
+        assert_eq!(reduce.translation(&legacy).unwrap(), Some("leader-aggregator reduce"));
+        let output = reduce.transform_file(&legacy, "map_reduce").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("(numbers).into_iter().map(Some).chain(std::iter::once(None)).enumerate()"));
+        assert!(compact.contains(".demux_bincode(workers)"));
+        assert!(compact.contains("Some(n)=>{letsquare=n*n;letvalue=square;"));
+        assert!(compact.contains("letmutcombined=Some(0);"));
+
+        // Handed out round-robin instead, the iterations would share the total, so no
+        // translation takes the loop
+        let round_robin = SynLegacyToHydroTransformer::new().with_cluster_mode(true);
+        assert_eq!(round_robin.translation(&legacy).unwrap(), None);
+    }
+}
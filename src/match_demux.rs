@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+
+use syn::visit_mut::{self, VisitMut};
+use syn::{Expr, Pat, Stmt};
+
+use crate::loop_control::{first_loop, iteration_block, leaves_loop, loop_label, targets, ControlledLoop};
+use crate::syntax::{bindings, mentions};
+
+/// A loop that jumps out of its body and whose body, after any `continue` guards, is a
+/// single `match` on the item: each arm becomes its own stream of the items it matches
+pub struct MatchDemux {
+    /// The statements before the loop, which share nothing with the rest
+    pub prefix: Vec<Stmt>,
+    pub items: Expr,
+    /// Conditions of the leading `if cond { continue; }` guards, on `item`
+    pub skips: Vec<Expr>,
+    /// The loop's binding of each item, which the guards read
+    pub item: Pat,
+    pub branches: Vec<Branch>,
+    /// The statements after the loop
+    pub suffix: Vec<Stmt>,
+}
+
+/// One arm of the demultiplexed `match`
+pub struct Branch {
+    pub pat: Pat,
+    /// The arm's pattern with its bindings replaced by `_`, for telling which arm an
+    /// item goes to without taking it apart
+    pub selector: Pat,
+    /// The arm's body as an iteration block (see [`iteration_block`]), without the
+    /// jump it ends with
+    pub body: Expr,
+    /// How an item reaching this arm ends the loop: `Some(true)` for a `break`,
+    /// `Some(false)` for a `return`
+    pub exit: Option<bool>,
+}
+
+impl MatchDemux {
+    /// Recognize `for x in xs { match x { .. } }` where the only ways out of the loop
+    /// are a `break` or `return` ending an arm. The arms and the code around the loop
+    /// become separate streams, so they must not share any bindings.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let controlled = ControlledLoop::detect(stmts).filter(|controlled| !controlled.fallible)?;
+        let (_, for_loop) = first_loop(stmts)?;
+        let label = loop_label(for_loop);
+        let Pat::Ident(item) = &*for_loop.pat else {
+            return None;
+        };
+        if item.subpat.is_some() || item.by_ref.is_some() {
+            return None;
+        }
+        let name = item.ident.to_string();
+
+        let [Stmt::Expr(Expr::Match(demux), _)] = &for_loop.body.stmts[controlled.skips.len()..] else {
+            return None;
+        };
+        if !matches!(&*demux.expr, Expr::Path(path) if path.path.is_ident(&name)) || demux.arms.len() < 2 {
+            return None;
+        }
+
+        let mut prefix_names = HashSet::new();
+        for stmt in &controlled.prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut prefix_names);
+            }
+        }
+        let mut used = controlled.suffix.clone();
+
+        let mut branches = Vec::new();
+        for arm in &demux.arms {
+            if arm.guard.is_some() {
+                return None;
+            }
+            let mut body = match &*arm.body {
+                Expr::Block(block) if block.label.is_none() => block.block.stmts.clone(),
+                body => vec![Stmt::Expr(body.clone(), None)],
+            };
+            let exit = match body.last() {
+                Some(Stmt::Expr(Expr::Break(jump), _)) if jump.expr.is_none() && targets(&jump.label, label.as_deref(), 0) => Some(true),
+                Some(Stmt::Expr(Expr::Return(ret), _)) if ret.expr.is_none() => Some(false),
+                _ => None,
+            };
+            if exit.is_some() {
+                body.pop();
+            }
+            // Any other way out of the loop depends on more than which arm matched
+            if leaves_loop(&body, label.as_deref()) || mentions(&body, &name) {
+                return None;
+            }
+            used.extend(body.iter().cloned());
+
+            let mut selector = arm.pat.clone();
+            Wildcards.visit_pat_mut(&mut selector);
+            branches.push(Branch {
+                pat: arm.pat.clone(),
+                selector,
+                body: iteration_block(&body, label.as_deref()),
+                exit,
+            });
+        }
+        if prefix_names.iter().any(|name| mentions(&used, name)) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: controlled.prefix,
+            items: controlled.items,
+            skips: controlled.skips,
+            item: controlled.pat,
+            branches,
+            suffix: controlled.suffix,
+        })
+    }
+}
+
+/// Replaces the bindings of a pattern with `_`. Capitalized identifiers are taken to
+/// be unit variants or constants, which are matched rather than bound.
+struct Wildcards;
+
+impl VisitMut for Wildcards {
+    fn visit_pat_mut(&mut self, pat: &mut Pat) {
+        if let Pat::Ident(ident) = pat {
+            let binds = ident.ident.to_string().starts_with(|c: char| c.is_lowercase() || c == '_');
+            match ident.subpat.take() {
+                Some((_, subpat)) => *pat = *subpat,
+                None if binds => *pat = syn::parse_quote! { _ },
+                None => return,
+            }
+        }
+        visit_mut::visit_pat_mut(self, pat);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<MatchDemux> {
+        MatchDemux::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_result_arms_become_branches() {
+        let demux = detect(r#"{
+            println!("start");
+            for line in lines {
+                match line {
+                    Ok(text) => {
+                        if text.trim().is_empty() { continue; }
+                        println!("Echo: {}", text);
+                    }
+                    Err(error) => {
+                        eprintln!("Error reading line: {}", error);
+                        break;
+                    }
+                }
+            }
+            println!("done");
+        }"#).unwrap();
+
+        assert_eq!(demux.branches.len(), 2);
+        let (ok, err) = (&demux.branches[0], &demux.branches[1]);
+        assert_eq!(compact(&ok.selector), "Ok(_)");
+        assert_eq!(ok.exit, None);
+        assert!(compact(&ok.body).contains("break'iterationstd::ops::ControlFlow::Continue(())"));
+        assert_eq!(compact(&err.pat), "Err(error)");
+        assert_eq!(compact(&err.selector), "Err(_)");
+        assert_eq!(err.exit, Some(true));
+        assert!(!compact(&err.body).contains("Break"));
+        assert_eq!((demux.prefix.len(), demux.suffix.len()), (1, 1));
+    }
+
+    #[test]
+    fn test_enum_arms_keep_unit_variants_in_selectors() {
+        let demux = detect(r#"{
+            for command in commands {
+                match command {
+                    Command::Add(n) => println!("add {}", n),
+                    Command::Quit => return,
+                    other @ Command::Reset { .. } => println!("{:?}", other),
+                }
+            }
+        }"#).unwrap();
+
+        let selectors: Vec<_> = demux.branches.iter().map(|branch| compact(&branch.selector)).collect();
+        assert_eq!(selectors, ["Command::Add(_)", "Command::Quit", "Command::Reset{..}"]);
+        assert_eq!(demux.branches[1].exit, Some(false));
+    }
+
+    #[test]
+    fn test_arms_that_cannot_be_separated() {
+        // The break depends on the line, not just the arm
+        assert!(detect("{ for x in xs { match x { Ok(n) => if n > 3 { break; }, Err(_) => break } } }").is_none());
+        // The arms share a counter
+        assert!(detect("{ let mut n = 0; for x in xs { match x { Ok(_) => n += 1, Err(_) => break } } println!(\"{}\", n); }").is_none());
+        // A guard
+        assert!(detect("{ for x in xs { match x { Ok(n) if n > 0 => println!(\"{}\", n), _ => break } } }").is_none());
+    }
+}
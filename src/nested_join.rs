@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+
+use syn::punctuated::Punctuated;
+use syn::{BinOp, Expr, GenericArgument, Lit, Member, Pat, PathArguments, Stmt, Token, Type, UnOp};
+
+use crate::syntax::{bindings, mentions};
+
+/// Two nested legacy loops over borrowed collections whose inner body only runs when a
+/// key from the outer item equals a key from the inner one: a hand-rolled equi-join
+pub struct NestedJoin {
+    /// Immutable bindings before the loops, which build the two collections
+    pub prefix: Vec<Stmt>,
+    pub outer: JoinSide,
+    pub inner: JoinSide,
+    /// The body run for each matching pair
+    pub body: Vec<Stmt>,
+}
+
+/// One of the two collections being joined
+pub struct JoinSide {
+    /// The loop's pattern, which binds references into each item
+    pub pat: Pat,
+    /// The collection, without the borrow the loop iterated it through
+    pub items: Expr,
+    /// This side's half of the equality, in terms of the pattern's bindings
+    pub key: Expr,
+}
+
+impl NestedJoin {
+    /// Recognize `for a in &xs { for b in &ys { if key(a) == key(b) { .. } } }`, after
+    /// any immutable `let`s building `xs` and `ys`
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let (last, prefix) = stmts.split_last()?;
+        let mut prefix_names = HashSet::new();
+        for stmt in prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            if !bindings(&local.pat, &mut prefix_names) {
+                return None;
+            }
+        }
+
+        let (outer_pat, outer_items, outer_body) = borrowed_loop(last)?;
+        let [inner_loop] = &outer_body[..] else {
+            return None;
+        };
+        let (inner_pat, inner_items, inner_body) = borrowed_loop(inner_loop)?;
+        let [Stmt::Expr(Expr::If(cond), _)] = &inner_body[..] else {
+            return None;
+        };
+        if cond.else_branch.is_some() {
+            return None;
+        }
+        let Expr::Binary(eq) = &*cond.cond else {
+            return None;
+        };
+        if !matches!(eq.op, BinOp::Eq(_)) {
+            return None;
+        }
+
+        let mut outer_names = HashSet::new();
+        bindings(&outer_pat, &mut outer_names);
+        let mut inner_names = HashSet::new();
+        bindings(&inner_pat, &mut inner_names);
+
+        // Each half of the equality must depend on exactly one of the two items
+        let side = |expr: &Expr| {
+            let expr = [Stmt::Expr(expr.clone(), None)];
+            let outer = outer_names.iter().any(|name| mentions(&expr, name));
+            let inner = inner_names.iter().any(|name| mentions(&expr, name));
+            match (outer, inner) {
+                (true, false) => Some(true),
+                (false, true) => Some(false),
+                _ => None,
+            }
+        };
+        let (outer_key, inner_key) = match (side(&eq.left)?, side(&eq.right)?) {
+            (true, false) => ((*eq.left).clone(), (*eq.right).clone()),
+            (false, true) => ((*eq.right).clone(), (*eq.left).clone()),
+            _ => return None,
+        };
+
+        // The join hashes the keys, where the loops only compared them
+        let outer = JoinSide { pat: outer_pat, items: outer_items, key: outer_key };
+        let inner = JoinSide { pat: inner_pat, items: inner_items, key: inner_key };
+        if !outer.hashable_key(prefix) || !inner.hashable_key(prefix) {
+            return None;
+        }
+
+        // The quoted operators can't see the bindings before the loops
+        let body = cond.then_branch.stmts.clone();
+        let used = [body.as_slice(), &[Stmt::Expr((*cond.cond).clone(), None)]].concat();
+        if prefix_names.iter().any(|name| mentions(&used, name)) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            outer,
+            inner,
+            body,
+        })
+    }
+}
+
+impl JoinSide {
+    /// Whether the key is known to have a type that can be hashed: it is the item, or a
+    /// part of it, and the `let` building the collection spells out that part's type or
+    /// shows it through its literal elements. Types such as `f64` are comparable with
+    /// `==` but can't key a join.
+    fn hashable_key(&self, prefix: &[Stmt]) -> bool {
+        let Some(path) = key_path(&self.pat, &self.key) else {
+            return false;
+        };
+        let Expr::Path(items) = &self.items else {
+            return false;
+        };
+        let Some(local) = prefix.iter().rev().find_map(|stmt| match stmt {
+            Stmt::Local(local) if pat_is(&local.pat, items) => Some(local),
+            _ => None,
+        }) else {
+            return false;
+        };
+
+        if let Pat::Type(typed) = &local.pat {
+            return element_type(&typed.ty).and_then(|ty| type_part(ty, &path)).is_some_and(hashable_type);
+        }
+        let Some(init) = &local.init else {
+            return false;
+        };
+        first_element(&init.expr).is_some_and(|element| value_part(&element, &path).is_some_and(hashable_value))
+    }
+}
+
+/// Where in the item the key reads: the tuple fields leading to it, from the loop's
+/// pattern and any `.N` accesses. `*name`, `(..)` and `.clone()` don't change the value.
+fn key_path(pat: &Pat, key: &Expr) -> Option<Vec<usize>> {
+    match key {
+        Expr::Unary(unary) if matches!(unary.op, UnOp::Deref(_)) => key_path(pat, &unary.expr),
+        Expr::Paren(paren) => key_path(pat, &paren.expr),
+        Expr::MethodCall(call) if call.method == "clone" && call.args.is_empty() => key_path(pat, &call.receiver),
+        Expr::Field(field) => {
+            let Member::Unnamed(index) = &field.member else {
+                return None;
+            };
+            let mut path = key_path(pat, &field.base)?;
+            path.push(index.index as usize);
+            Some(path)
+        }
+        Expr::Path(path) => binding_path(pat, &path.path.get_ident()?.to_string()),
+        _ => None,
+    }
+}
+
+/// The tuple fields leading to where a pattern binds `name`
+fn binding_path(pat: &Pat, name: &str) -> Option<Vec<usize>> {
+    match pat {
+        Pat::Ident(ident) => (ident.ident == name && ident.subpat.is_none()).then(Vec::new),
+        Pat::Reference(reference) => binding_path(&reference.pat, name),
+        Pat::Paren(paren) => binding_path(&paren.pat, name),
+        Pat::Tuple(tuple) => tuple.elems.iter().enumerate().find_map(|(index, elem)| {
+            let mut path = binding_path(elem, name)?;
+            path.insert(0, index);
+            Some(path)
+        }),
+        _ => None,
+    }
+}
+
+/// Whether a `let` pattern binds just the name of the path, with or without a type
+fn pat_is(pat: &Pat, items: &syn::ExprPath) -> bool {
+    match pat {
+        Pat::Ident(ident) => items.path.is_ident(&ident.ident),
+        Pat::Type(typed) => pat_is(&typed.pat, items),
+        _ => false,
+    }
+}
+
+/// The item type of a `Vec`, `VecDeque`, array or slice type
+fn element_type(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::Array(array) => Some(&array.elem),
+        Type::Slice(slice) => Some(&slice.elem),
+        Type::Reference(reference) => element_type(&reference.elem),
+        Type::Path(path) => {
+            let segment = path.path.segments.last()?;
+            if segment.ident != "Vec" && segment.ident != "VecDeque" {
+                return None;
+            }
+            let PathArguments::AngleBracketed(args) = &segment.arguments else {
+                return None;
+            };
+            match args.args.first()? {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn type_part<'t>(ty: &'t Type, path: &[usize]) -> Option<&'t Type> {
+    let Some((index, rest)) = path.split_first() else {
+        return Some(ty);
+    };
+    match ty {
+        Type::Tuple(tuple) => type_part(tuple.elems.iter().nth(*index)?, rest),
+        Type::Paren(paren) => type_part(&paren.elem, path),
+        _ => None,
+    }
+}
+
+fn hashable_type(ty: &Type) -> bool {
+    const HASHABLE: [&str; 15] =
+        ["u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "bool", "char", "String"];
+    match ty {
+        Type::Path(path) => path.path.get_ident().is_some_and(|ident| HASHABLE.contains(&ident.to_string().as_str())),
+        Type::Reference(reference) => hashable_type(&reference.elem) || matches!(&*reference.elem, Type::Path(path) if path.path.is_ident("str")),
+        Type::Tuple(tuple) => tuple.elems.iter().all(hashable_type),
+        Type::Paren(paren) => hashable_type(&paren.elem),
+        _ => false,
+    }
+}
+
+/// The first element of a `vec![..]` or array literal
+fn first_element(init: &Expr) -> Option<Expr> {
+    match init {
+        Expr::Array(array) => array.elems.first().cloned(),
+        Expr::Reference(reference) => first_element(&reference.expr),
+        Expr::Macro(mac) if mac.mac.path.is_ident("vec") => {
+            let elems = mac.mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated).ok()?;
+            elems.first().cloned()
+        }
+        _ => None,
+    }
+}
+
+fn value_part<'e>(value: &'e Expr, path: &[usize]) -> Option<&'e Expr> {
+    let Some((index, rest)) = path.split_first() else {
+        return Some(value);
+    };
+    match value {
+        Expr::Tuple(tuple) => value_part(tuple.elems.iter().nth(*index)?, rest),
+        Expr::Paren(paren) => value_part(&paren.expr, path),
+        _ => None,
+    }
+}
+
+/// Whether a literal value, or one built from a string literal, has a hashable type
+fn hashable_value(value: &Expr) -> bool {
+    match value {
+        Expr::Lit(lit) => !matches!(lit.lit, Lit::Float(_)),
+        Expr::Unary(unary) if matches!(unary.op, UnOp::Neg(_)) => hashable_value(&unary.expr),
+        Expr::Tuple(tuple) => tuple.elems.iter().all(hashable_value),
+        Expr::Paren(paren) => hashable_value(&paren.expr),
+        Expr::MethodCall(call) if call.method == "to_string" || call.method == "to_owned" => {
+            matches!(&*call.receiver, Expr::Lit(lit) if matches!(lit.lit, Lit::Str(_)))
+        }
+        Expr::Call(call) => {
+            let from_str = matches!(&*call.func, Expr::Path(func) if func.path.segments.len() == 2
+                && func.path.segments[0].ident == "String" && func.path.segments[1].ident == "from");
+            from_str && matches!(&call.args.first(), Some(Expr::Lit(lit)) if matches!(lit.lit, Lit::Str(_)))
+        }
+        _ => false,
+    }
+}
+
+/// `for pat in &items` or `for pat in items.iter()`, returning the pattern, the
+/// collection and the loop body
+fn borrowed_loop(stmt: &Stmt) -> Option<(Pat, Expr, Vec<Stmt>)> {
+    let Stmt::Expr(Expr::ForLoop(for_loop), _) = stmt else {
+        return None;
+    };
+    if for_loop.label.is_some() {
+        return None;
+    }
+    let items = match &*for_loop.expr {
+        Expr::Reference(reference) if reference.mutability.is_none() => (*reference.expr).clone(),
+        Expr::MethodCall(call) if call.method == "iter" && call.args.is_empty() => (*call.receiver).clone(),
+        _ => return None,
+    };
+    Some(((*for_loop.pat).clone(), items, for_loop.body.stmts.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<NestedJoin> {
+        NestedJoin::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_nested_key_comparison_is_a_join() {
+        let join = detect(r#"{
+            let users = vec![(1, "alice"), (2, "bob")];
+            let orders = vec![(1, "book"), (2, "pen"), (1, "cup")];
+            for (id, name) in &users {
+                for order in orders.iter() {
+                    if order.0 == *id {
+                        println!("{} ordered {}", name, order.1);
+                    }
+                }
+            }
+        }"#).unwrap();
+
+        assert_eq!(join.prefix.len(), 2);
+        assert_eq!(compact(&join.outer.items), "users");
+        assert_eq!(compact(&join.outer.key), "*id");
+        assert_eq!(compact(&join.inner.items), "orders");
+        assert_eq!(compact(&join.inner.key), "order.0");
+        assert_eq!(join.body.len(), 1);
+    }
+
+    #[test]
+    fn test_other_nested_loops_are_not_joins() {
+        // Not an equality between the two sides
+        assert!(detect("{ for a in &xs { for b in &ys { if a < b { println!(\"{}\", a); } } } }").is_none());
+        // Both halves depend on the outer item
+        assert!(detect("{ for a in &xs { for b in &ys { if a.0 == a.1 { println!(\"{}\", b); } } } }").is_none());
+        // More than the comparison in the inner loop
+        assert!(detect("{ for a in &xs { for b in &ys { println!(\"{}\", b); if a == b { println!(\"{}\", a); } } } }").is_none());
+    }
+
+    #[test]
+    fn test_keys_must_be_known_hashable() {
+        let join = |prefix: &str, key: &str| {
+            detect(&format!(
+                "{{ {} for a in &xs {{ for b in &ys {{ if {} {{ println!(\"{{:?}} {{:?}}\", a, b); }} }} }} }}",
+                prefix, key
+            ))
+        };
+        // Types shown by the literals or spelled out by the `let`s
+        assert!(join("let xs = vec![(1, \"a\".to_string())]; let ys = [String::from(\"b\")];", "a.1 == *b").is_some());
+        assert!(join("let xs = vec![(1, 'a')]; let ys = [('b', 2)];", "a.1 == b.0").is_some());
+        assert!(join("let xs: Vec<(u32, f64)> = load(); let ys: &[u32] = &ids;", "a.0 == *b").is_some());
+        // Floats compare with `==` but can't be hashed
+        assert!(join("let xs = vec![1.5, 2.5]; let ys = vec![2.5];", "*a == *b").is_none());
+        assert!(join("let xs: Vec<(u32, f64)> = load(); let ys: Vec<f64> = load();", "a.1 == *b").is_none());
+        // Nothing says what the items are
+        assert!(join("let xs = load(); let ys = load();", "a.0 == b.0").is_none());
+        assert!(join("let xs = vec![User::new(1)]; let ys = vec![User::new(2)];", "a.id == b.id").is_none());
+    }
+}
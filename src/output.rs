@@ -0,0 +1,204 @@
+use std::collections::BTreeSet;
+
+use syn::visit::{self, Visit};
+use syn::{Item, Stmt, UseTree};
+
+use crate::backend::Analysis;
+use crate::io_transformer::{IOOperation, IOToHydroTransformer};
+use crate::report::TransformReport;
+use crate::source_map::SourceMap;
+
+/// Crates every Rust program can name without depending on them
+const BUILTIN_ROOTS: &[&str] = &["std", "core", "alloc", "crate", "self", "super", "Self", "hydro_template"];
+
+/// Primitive types, whose associated items are named like paths into a crate
+const PRIMITIVES: &[&str] = &[
+    "bool", "char", "str", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+    "f32", "f64",
+];
+
+/// Everything a transformation produced for a legacy program, the same for every backend
+#[derive(Debug, Clone)]
+pub struct TransformOutput {
+    /// The generated Hydro module
+    pub hydro_function: String,
+    /// The example program deploying the module
+    pub example_program: String,
+    /// The I/O operations found in the legacy program, the helpers it calls included
+    pub io_operations: Vec<IOOperation>,
+    /// The constructs carried over as written, which may need a rewrite by hand
+    pub report: TransformReport,
+    /// Where the statements of the legacy `main` ended up in the generated module
+    pub source_map: SourceMap,
+    /// The crates the generated module and example name, which the crate they are
+    /// written into has to depend on
+    pub dependencies: Vec<String>,
+}
+
+impl TransformOutput {
+    /// Put together the output for the generated module and example of a legacy file
+    pub fn new(file: &syn::File, hydro_function: String, example_program: String) -> Self {
+        let main_body = main_body(file).unwrap_or_default();
+        let io_operations = IOToHydroTransformer::new().analyze_io_operations_in(&file.items, main_body);
+        let report = TransformReport::analyze(file);
+        Self::assemble(main_body, io_operations, report, hydro_function, example_program)
+    }
+
+    /// Put together the output from an analysis the backend already made of the file,
+    /// so its I/O operations and report aren't worked out a second time
+    pub fn from_analysis(file: &syn::File, analysis: Analysis, hydro_function: String, example_program: String) -> Self {
+        let main_body = main_body(file).unwrap_or_default();
+        Self::assemble(main_body, analysis.io_operations, analysis.report, hydro_function, example_program)
+    }
+
+    fn assemble(
+        main_body: &[Stmt],
+        io_operations: Vec<IOOperation>,
+        report: TransformReport,
+        hydro_function: String,
+        example_program: String,
+    ) -> Self {
+        let source_map = SourceMap::build(main_body, &hydro_function);
+        let dependencies = required_dependencies(&[&hydro_function, &example_program]);
+        Self { hydro_function, example_program, io_operations, report, source_map, dependencies }
+    }
+}
+
+/// The statements of a legacy file's `main`
+pub(crate) fn main_body(file: &syn::File) -> Option<&[Stmt]> {
+    file.items.iter().find_map(|item| match item {
+        Item::Fn(func) if func.sig.ident == "main" => Some(func.block.stmts.as_slice()),
+        _ => None,
+    })
+}
+
+/// The crates named by the `use` items and paths of generated sources, sorted. Sources
+/// that don't parse name none.
+pub fn required_dependencies(sources: &[&str]) -> Vec<String> {
+    let mut roots = CrateRoots::default();
+    for source in sources {
+        if let Ok(file) = syn::parse_file(source) {
+            roots.visit_file(&file);
+        }
+    }
+    roots.crates.into_iter().collect()
+}
+
+/// The first segments of the paths of a file that name crates
+#[derive(Default)]
+struct CrateRoots {
+    crates: BTreeSet<String>,
+    /// Modules and functions of the file itself, named like crates
+    local: BTreeSet<String>,
+}
+
+impl CrateRoots {
+    fn add(&mut self, root: String) {
+        let builtin = BUILTIN_ROOTS.contains(&root.as_str()) || PRIMITIVES.contains(&root.as_str());
+        let lowercase = root.starts_with(|c: char| c.is_ascii_lowercase());
+        if lowercase && !builtin && !self.local.contains(&root) {
+            self.crates.insert(root);
+        }
+    }
+
+    fn add_use_roots(&mut self, tree: &UseTree) {
+        match tree {
+            UseTree::Path(path) => self.add(path.ident.to_string()),
+            UseTree::Name(name) => self.add(name.ident.to_string()),
+            UseTree::Rename(rename) => self.add(rename.ident.to_string()),
+            UseTree::Group(group) => group.items.iter().for_each(|tree| self.add_use_roots(tree)),
+            UseTree::Glob(_) => {}
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for CrateRoots {
+    fn visit_file(&mut self, file: &'ast syn::File) {
+        for item in &file.items {
+            match item {
+                Item::Mod(module) => {
+                    self.local.insert(module.ident.to_string());
+                }
+                Item::Fn(func) => {
+                    self.local.insert(func.sig.ident.to_string());
+                }
+                _ => {}
+            }
+        }
+        visit::visit_file(self, file);
+    }
+
+    fn visit_item_use(&mut self, item: &'ast syn::ItemUse) {
+        self.add_use_roots(&item.tree);
+    }
+
+    fn visit_path(&mut self, path: &'ast syn::Path) {
+        if path.segments.len() > 1 && path.leading_colon.is_none() {
+            self.add(path.segments[0].ident.to_string());
+        }
+        visit::visit_path(self, path);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        self.visit_path(&mac.path);
+        // Quoted code and format arguments are expressions or statements, whose paths
+        // name crates too
+        if let Ok(stmts) = mac.parse_body_with(syn::Block::parse_within) {
+            for stmt in &stmts {
+                self.visit_stmt(stmt);
+            }
+        } else if let Ok(args) =
+            mac.parse_body_with(syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated)
+        {
+            for arg in &args {
+                self.visit_expr(arg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_transformer::IOOperationType;
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    #[test]
+    fn test_output_describes_the_transformation() {
+        let source = "fn main() {\n    let total: u64 = (1..=4).sum();\n    println!(\"{}\", total);\n}\n";
+        let output = SynLegacyToHydroTransformer::new().transform_source(source, "test_total").unwrap();
+
+        let operations: Vec<_> = output.io_operations.iter().map(|op| (op.operation_type.clone(), op.line_number)).collect();
+        assert_eq!(operations, vec![(IOOperationType::StdoutPrintln, Some(3))]);
+        assert!(output.report.is_clean());
+        // The sum is carried over as written, the print is rewritten
+        let line = output.source_map.generated_line(2).unwrap();
+        assert!(output.hydro_function.lines().nth(line - 1).unwrap().contains("(1..=4).sum()"));
+        assert_eq!(output.source_map.generated_line(3), None);
+        assert!(output.dependencies.contains(&"hydro_lang".to_string()));
+        assert!(output.dependencies.contains(&"hydro_deploy".to_string()));
+    }
+
+    #[test]
+    fn test_output_reuses_the_analysis() {
+        let file = syn::parse_file("fn main() {\n    println!(\"hi\");\n}\n").unwrap();
+        let mut analysis = Analysis::new(&file, None).unwrap();
+        assert_eq!(analysis.io_operations.len(), 1);
+
+        // Whatever the backend worked out is what the output carries
+        analysis.io_operations.clear();
+        let output = TransformOutput::from_analysis(&file, analysis, String::new(), String::new());
+        assert!(output.io_operations.is_empty());
+    }
+
+    #[test]
+    fn test_required_dependencies_are_named_crates() {
+        let module = "use hydro_lang::*;\nuse serde::{Deserialize, Serialize};\n\nmod local { pub fn f() {} }\n\npub fn module() {\n    let _ = std::mem::size_of::<u8>();\n    let _ = local::f();\n    let _ = q!(|line: String| serde_json::from_str::<u64>(&line));\n    let _ = u64::MAX;\n}\n";
+        let example = "use hydro_deploy::Deployment;\n\n#[tokio::main]\nasync fn main() {\n    hydro_template::module::module();\n}\n";
+        assert_eq!(
+            required_dependencies(&[module, example]),
+            vec!["hydro_deploy", "hydro_lang", "serde", "serde_json", "tokio"],
+        );
+        assert!(required_dependencies(&["fn main( {"]).is_empty());
+    }
+}
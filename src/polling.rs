@@ -0,0 +1,127 @@
+use std::collections::HashSet;
+
+use quote::ToTokens;
+use syn::{Expr, Item, Stmt};
+
+use crate::config_file::leaves;
+use crate::syntax::{bindings, leaves_early, mentions, unwrapped};
+
+/// A legacy daemon polling forever: `loop { work(); sleep(period); }`. The sleep becomes
+/// an interval source at the same period, which drives the rest of the body as a
+/// pipeline stage instead of blocking the dataflow inside one operator.
+pub struct PollingLoop {
+    /// The statements before the loop, which set up the state the body keeps between
+    /// iterations
+    pub prefix: Vec<Stmt>,
+    /// How long the loop slept after each iteration
+    pub period: Expr,
+    /// The loop body without its sleep
+    pub body: Vec<Stmt>,
+    /// The legacy file's imports the loop uses
+    pub imports: Vec<Item>,
+}
+
+impl PollingLoop {
+    /// Recognize a final `loop` whose last statement is `thread::sleep(period)` or
+    /// `tokio::time::sleep(period).await`, with a period that doesn't depend on the
+    /// statements before it. The body may not leave the loop or await anything else.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let (Stmt::Expr(Expr::Loop(looped), _), prefix) = stmts.split_last()? else {
+            return None;
+        };
+        if looped.label.is_some() {
+            return None;
+        }
+        let (sleep, body) = looped.body.stmts.split_last()?;
+        let period = sleep_period(sleep)?;
+
+        let prefix = prefix.to_vec();
+        let mut names = HashSet::new();
+        for stmt in &prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut names);
+            }
+        }
+        let period_stmt = [Stmt::Expr(period.clone(), None)];
+        if names.iter().any(|name| mentions(&period_stmt, name)) {
+            return None;
+        }
+        let body = body.to_vec();
+        let awaits = body.iter().any(|stmt| stmt.to_token_stream().to_string().contains(". await"));
+        if leaves_early(&body) || awaits {
+            return None;
+        }
+
+        let mut used = prefix.clone();
+        used.extend(period_stmt);
+        used.extend(body.iter().cloned());
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&used, name))))
+            .cloned()
+            .collect();
+
+        Some(Self { prefix, period, body, imports })
+    }
+}
+
+/// The period of `thread::sleep(period);` or `tokio::time::sleep(period).await;`
+fn sleep_period(stmt: &Stmt) -> Option<Expr> {
+    let Stmt::Expr(expr, Some(_)) = stmt else {
+        return None;
+    };
+    let Expr::Call(call) = unwrapped(expr) else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let [period] = &call.args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    (func.path.segments.last()?.ident == "sleep").then(|| (*period).clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(source: &str) -> Option<PollingLoop> {
+        PollingLoop::detect(&[syn::parse_quote! { use std::time::Duration; }, syn::parse_quote! { use std::thread; }], &parse_block(source))
+    }
+
+    #[test]
+    fn test_sleeping_loop_keeps_its_state() {
+        let polling = detect(r#"{
+            let mut polls = 0;
+            loop {
+                polls += 1;
+                println!("poll {}", polls);
+                thread::sleep(Duration::from_secs(5));
+            }
+        }"#).unwrap();
+
+        assert_eq!(compact(&polling.period), "Duration::from_secs(5)");
+        assert_eq!(polling.prefix.len(), 1);
+        assert_eq!(polling.body.len(), 2);
+        // Only the sleep used `thread`
+        assert_eq!(polling.imports.len(), 1);
+    }
+
+    #[test]
+    fn test_async_sleep() {
+        let polling = detect("{ loop { check_health(); tokio::time::sleep(Duration::from_millis(250)).await; } }").unwrap();
+        assert_eq!(compact(&polling.period), "Duration::from_millis(250)");
+    }
+
+    #[test]
+    fn test_loops_that_are_not_polling() {
+        // The loop ends itself
+        assert!(detect("{ loop { if done() { break; } thread::sleep(PERIOD); } }").is_none());
+        // The period changes as the loop goes
+        assert!(detect("{ let mut backoff = 1; loop { work(); thread::sleep(Duration::from_secs(backoff)); } }").is_none());
+        // It sleeps first, then works
+        assert!(detect("{ loop { thread::sleep(PERIOD); work(); } }").is_none());
+    }
+}
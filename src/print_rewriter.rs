@@ -0,0 +1,650 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::punctuated::Punctuated;
+use syn::{Expr, ExprLit, ExprMacro, Lit, Macro, Pat, Stmt, Token};
+
+/// The standard stream a print macro writes to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputChannel {
+    Stdout,
+    Stderr,
+}
+
+/// A recognized `print!`, `println!`, `eprint!` or `eprintln!` invocation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrintMacro {
+    pub channel: OutputChannel,
+    pub newline: bool,
+}
+
+impl PrintMacro {
+    /// Classify a macro invocation, returning `None` for anything but the print macros
+    pub fn parse(mac: &Macro) -> Option<Self> {
+        let (channel, newline) = match mac.path.get_ident()?.to_string().as_str() {
+            "print" => (OutputChannel::Stdout, false),
+            "println" => (OutputChannel::Stdout, true),
+            "eprint" => (OutputChannel::Stderr, false),
+            "eprintln" => (OutputChannel::Stderr, true),
+            _ => return None,
+        };
+        Some(Self { channel, newline })
+    }
+}
+
+/// A recognized `dbg!` invocation, which writes to stderr, or an `assert!`,
+/// `assert_eq!`, `assert_ne!` or `panic!` invocation, which panics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiagnosticMacro {
+    Dbg,
+    Assert,
+    AssertEq,
+    AssertNe,
+    Panic,
+}
+
+impl DiagnosticMacro {
+    pub fn parse(mac: &Macro) -> Option<Self> {
+        match mac.path.get_ident()?.to_string().as_str() {
+            "dbg" => Some(DiagnosticMacro::Dbg),
+            "assert" => Some(DiagnosticMacro::Assert),
+            "assert_eq" => Some(DiagnosticMacro::AssertEq),
+            "assert_ne" => Some(DiagnosticMacro::AssertNe),
+            "panic" => Some(DiagnosticMacro::Panic),
+            _ => None,
+        }
+    }
+
+    /// The arguments the macro evaluates as code: every argument of `dbg!`, and the
+    /// operands of an assertion. `None` for `panic!`, whose arguments only format.
+    pub fn operands(self, mac: &Macro) -> Option<Vec<Expr>> {
+        let args = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated).ok()?;
+        let count = match self {
+            DiagnosticMacro::Dbg => args.len(),
+            DiagnosticMacro::Assert => 1,
+            DiagnosticMacro::AssertEq | DiagnosticMacro::AssertNe => 2,
+            DiagnosticMacro::Panic => return None,
+        };
+        (args.len() >= count).then(|| args.into_iter().take(count).collect())
+    }
+}
+
+/// The level of a `log` or `tracing` record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The level as `env_logger` prints it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+/// A recognized `info!`-style logging macro, from `log` or `tracing` or imported from
+/// either. Only records given as a format string are recognized, not ones with a
+/// `target:` or structured fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogMacro {
+    pub level: LogLevel,
+}
+
+impl LogMacro {
+    pub fn parse(mac: &Macro) -> Option<Self> {
+        let segments: Vec<String> = mac.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+        let name = match segments.as_slice() {
+            [name] => name,
+            [krate, name] if krate == "log" || krate == "tracing" => name,
+            _ => return None,
+        };
+        let level = match name.as_str() {
+            "error" => LogLevel::Error,
+            "warn" => LogLevel::Warn,
+            "info" => LogLevel::Info,
+            "debug" => LogLevel::Debug,
+            "trace" => LogLevel::Trace,
+            _ => return None,
+        };
+        let args = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated).ok()?;
+        let format_string = matches!(args.first(), Some(Expr::Lit(ExprLit { lit: Lit::Str(_), .. })));
+        format_string.then_some(Self { level })
+    }
+}
+
+/// Whether any of `stmts` logs through a recognized logging macro
+pub fn has_log_records(stmts: &[Stmt]) -> bool {
+    struct LogScan(bool);
+
+    impl<'ast> Visit<'ast> for LogScan {
+        fn visit_macro(&mut self, mac: &'ast Macro) {
+            self.0 |= LogMacro::parse(mac).is_some();
+            visit::visit_macro(self, mac);
+        }
+    }
+
+    let mut scan = LogScan(false);
+    for stmt in stmts {
+        scan.visit_stmt(stmt);
+    }
+    scan.0
+}
+
+/// Turn a print macro's arguments into an expression building the formatted `String`,
+/// without the trailing newline
+pub fn format_message(mac: &Macro) -> TokenStream {
+    let args = &mac.tokens;
+    if args.is_empty() {
+        quote! { String::new() }
+    } else {
+        quote! { format!(#args) }
+    }
+}
+
+/// The standard stream a handle expression writes to: `io::stdout()`, a `lock()` or
+/// `&mut` of a handle, a `BufWriter` or `LineWriter` around one, or a variable in
+/// `handles`
+pub fn handle_channel(expr: &Expr, handles: &HashMap<String, OutputChannel>) -> Option<OutputChannel> {
+    match expr {
+        Expr::Call(call) => {
+            let Expr::Path(func) = &*call.func else {
+                return None;
+            };
+            let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+            match (segments.as_slice(), call.args.first()) {
+                ([.., last], None) if last == "stdout" => Some(OutputChannel::Stdout),
+                ([.., last], None) if last == "stderr" => Some(OutputChannel::Stderr),
+                ([.., writer, new], Some(inner)) if (writer == "BufWriter" || writer == "LineWriter") && new == "new" => handle_channel(inner, handles),
+                _ => None,
+            }
+        }
+        Expr::MethodCall(call) if call.method == "lock" || call.method == "by_ref" => handle_channel(&call.receiver, handles),
+        Expr::Reference(reference) => handle_channel(&reference.expr, handles),
+        Expr::Paren(paren) => handle_channel(&paren.expr, handles),
+        Expr::Path(path) => handles.get(&path.path.get_ident()?.to_string()).copied(),
+        _ => None,
+    }
+}
+
+/// Rewrite `write!`/`writeln!` through stdout and stderr handles into the print macros
+/// they amount to, so they join the output streams like any other print. A handle
+/// bound to a variable is only rewritten when all the body does with it is write and
+/// flush. Its binding and flushes are then dropped, and so is the handling of the
+/// `io::Result` the writes return.
+pub fn print_through_handles(stmts: &[Stmt]) -> Vec<Stmt> {
+    let mut bound = HandleBindings::default();
+    for stmt in stmts {
+        bound.visit_stmt(stmt);
+    }
+    let mut uses = HandleUses { handles: &bound.handles, other: HashSet::new() };
+    for stmt in stmts {
+        uses.visit_stmt(stmt);
+    }
+    // A handle that stays keeps the handles it was made from
+    while let Some(source) = bound
+        .sources
+        .iter()
+        .filter(|(name, _)| uses.other.contains(*name))
+        .flat_map(|(_, sources)| sources)
+        .find(|source| !uses.other.contains(*source))
+    {
+        uses.other.insert(source.clone());
+    }
+    let handles: HashMap<String, OutputChannel> = bound.handles.iter().filter(|(name, _)| !uses.other.contains(*name)).map(|(name, channel)| (name.clone(), *channel)).collect();
+
+    let mut rewriter = HandleRewriter { handles };
+    let mut stmts = stmts.to_vec();
+    rewriter.rewrite_stmts(&mut stmts);
+    stmts
+}
+
+/// The print macro a `write!`/`writeln!` through a handle amounts to
+fn handle_write(mac: &Macro, handles: &HashMap<String, OutputChannel>) -> Option<Macro> {
+    let newline = match mac.path.get_ident()?.to_string().as_str() {
+        "write" => false,
+        "writeln" => true,
+        _ => return None,
+    };
+    // The handle is everything up to the first comma, the format arguments the rest
+    let mut tokens = mac.tokens.clone().into_iter();
+    let handle: TokenStream = tokens.by_ref().take_while(|token| !matches!(token, TokenTree::Punct(punct) if punct.as_char() == ',')).collect();
+    let args: TokenStream = tokens.collect();
+    let name = match (handle_channel(&syn::parse2(handle).ok()?, handles)?, newline) {
+        (OutputChannel::Stdout, false) => "print",
+        (OutputChannel::Stdout, true) => "println",
+        (OutputChannel::Stderr, false) => "eprint",
+        (OutputChannel::Stderr, true) => "eprintln",
+    };
+    let name = syn::Ident::new(name, mac.path.span());
+    syn::parse2(quote! { #name!(#args) }).ok()
+}
+
+/// The variables bound to stdout or stderr handles, anywhere in a body
+#[derive(Default)]
+struct HandleBindings {
+    handles: HashMap<String, OutputChannel>,
+    /// The handles each one was made from, like `stdout` for `stdout.lock()`
+    sources: HashMap<String, Vec<String>>,
+}
+
+impl<'ast> Visit<'ast> for HandleBindings {
+    fn visit_local(&mut self, local: &'ast syn::Local) {
+        if let (Pat::Ident(binding), Some(init)) = (&local.pat, &local.init) {
+            if let Some(channel) = handle_channel(&init.expr, &self.handles) {
+                let sources = init.expr.to_token_stream().into_iter().filter_map(|token| match token {
+                    TokenTree::Ident(ident) if self.handles.contains_key(&ident.to_string()) => Some(ident.to_string()),
+                    _ => None,
+                });
+                let name = binding.ident.to_string();
+                self.sources.insert(name.clone(), sources.collect());
+                self.handles.insert(name, channel);
+            }
+        }
+        visit::visit_local(self, local);
+    }
+}
+
+/// The handles used other than by being written to, flushed or locked into another
+struct HandleUses<'h> {
+    handles: &'h HashMap<String, OutputChannel>,
+    other: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for HandleUses<'_> {
+    fn visit_local(&mut self, local: &'ast syn::Local) {
+        // Binding a handle from another is a use the rewrite drops along with both
+        if let (Pat::Ident(binding), Some(init)) = (&local.pat, &local.init) {
+            if self.handles.contains_key(&binding.ident.to_string()) && handle_channel(&init.expr, self.handles).is_some() {
+                return;
+            }
+        }
+        visit::visit_local(self, local);
+    }
+
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        // A flush as a statement of its own is dropped along with the handle
+        if let Stmt::Expr(expr, Some(_)) = stmt {
+            if matches!(handled(expr), Expr::MethodCall(call) if call.method == "flush" && matches!(&*call.receiver, Expr::Path(_))) {
+                return;
+            }
+        }
+        visit::visit_stmt(self, stmt);
+    }
+
+    fn visit_expr_path(&mut self, path: &'ast syn::ExprPath) {
+        if let Some(name) = path.path.get_ident().map(|ident| ident.to_string()) {
+            if self.handles.contains_key(&name) {
+                self.other.insert(name);
+            }
+        }
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if handle_write(mac, self.handles).is_some() {
+            return;
+        }
+        // A handle passed to anything else, a write to it included, isn't rewritten
+        for token in mac.tokens.clone() {
+            if let TokenTree::Ident(ident) = token {
+                if self.handles.contains_key(&ident.to_string()) {
+                    self.other.insert(ident.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Replaces the writes through handles, and drops the handles' bindings and flushes
+struct HandleRewriter {
+    handles: HashMap<String, OutputChannel>,
+}
+
+impl HandleRewriter {
+    fn rewrite_stmts(&mut self, stmts: &mut Vec<Stmt>) {
+        stmts.retain(|stmt| !self.drops(stmt));
+        for stmt in stmts.iter_mut() {
+            self.visit_stmt_mut(stmt);
+        }
+    }
+
+    /// A binding of a handle, or a flush of one with its result handled or thrown away
+    fn drops(&self, stmt: &Stmt) -> bool {
+        match stmt {
+            Stmt::Local(local) => match (&local.pat, &local.init) {
+                (Pat::Ident(binding), Some(init)) => {
+                    self.handles.contains_key(&binding.ident.to_string()) && handle_channel(&init.expr, &self.handles).is_some()
+                }
+                _ => false,
+            },
+            // Flushes of handles not bound to a variable are left as they were
+            Stmt::Expr(expr, Some(_)) => matches!(handled(expr), Expr::MethodCall(call)
+                if call.method == "flush" && matches!(&*call.receiver, Expr::Path(_)) && handle_channel(&call.receiver, &self.handles).is_some()),
+            _ => false,
+        }
+    }
+
+    fn print(&self, expr: &Expr) -> Option<Expr> {
+        let Expr::Macro(ExprMacro { mac, .. }) = handled(expr) else {
+            return None;
+        };
+        let mac = handle_write(mac, &self.handles)?;
+        Some(Expr::Macro(ExprMacro { attrs: Vec::new(), mac }))
+    }
+}
+
+/// The expression whose `io::Result` is handled by `?`, `.unwrap()` or `.expect(..)`
+fn handled(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Try(expr_try) => &expr_try.expr,
+        Expr::MethodCall(call) if call.method == "unwrap" || call.method == "expect" => &call.receiver,
+        _ => expr,
+    }
+}
+
+impl VisitMut for HandleRewriter {
+    fn visit_block_mut(&mut self, block: &mut syn::Block) {
+        self.rewrite_stmts(&mut block.stmts);
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Macro(stmt_macro) = stmt {
+            if let Some(mac) = handle_write(&stmt_macro.mac, &self.handles) {
+                stmt_macro.mac = mac;
+                return;
+            }
+        }
+        // A write whose result is only handled becomes the print itself
+        if let Stmt::Expr(expr, Some(semi)) = stmt {
+            if let Some(Expr::Macro(print)) = self.print(expr) {
+                *stmt = Stmt::Macro(syn::StmtMacro { attrs: print.attrs, mac: print.mac, semi_token: Some(*semi) });
+                return;
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        // Elsewhere the write still evaluates to an `io::Result`
+        if let Expr::Macro(ExprMacro { mac, .. }) = expr {
+            if let Some(mac) = handle_write(mac, &self.handles) {
+                *expr = syn::parse_quote! {{ #mac; std::io::Result::Ok(()) }};
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// A legacy body whose stdout has been lowered into a buffer of complete lines
+pub struct StdoutLines {
+    /// The rewritten statements; they no longer print to stdout themselves
+    pub stmts: Vec<Stmt>,
+    /// Whether the body uses `print!`, which needs a partial line carried between calls
+    pub has_partial_lines: bool,
+}
+
+impl StdoutLines {
+    /// Rewrite every `println!`/`print!` in the body into pushes onto `stdout_lines`.
+    /// `eprintln!`/`eprint!` are left as they are.
+    pub fn lower(stmts: &[Stmt]) -> Self {
+        let mut scan = PrintScan::default();
+        for stmt in stmts {
+            scan.visit_stmt(stmt);
+        }
+
+        let mut rewriter = StdoutLinesRewriter { has_partial_lines: scan.has_partial_lines };
+        let mut stmts = stmts.to_vec();
+        for stmt in &mut stmts {
+            rewriter.visit_stmt_mut(stmt);
+        }
+        terminate_tail(&mut stmts);
+
+        Self {
+            stmts,
+            has_partial_lines: scan.has_partial_lines,
+        }
+    }
+
+    /// The closure body that runs the lowered statements and evaluates to the
+    /// `Vec<String>` of lines they printed; an unterminated last line is kept
+    pub fn closure_body(&self, body: TokenStream) -> TokenStream {
+        let lines = lines_buffer();
+        if self.has_partial_lines {
+            let partial = partial_line_buffer();
+            quote! {
+                let mut #lines: Vec<String> = Vec::new();
+                let mut #partial = String::new();
+                #body
+                if !#partial.is_empty() {
+                    #lines.push(#partial);
+                }
+                #lines
+            }
+        } else {
+            quote! {
+                let mut #lines: Vec<String> = Vec::new();
+                #body
+                #lines
+            }
+        }
+    }
+}
+
+/// A body followed by more generated code can't end in a tail expression
+pub fn terminate_tail(stmts: &mut [Stmt]) {
+    if let Some(Stmt::Expr(_, semi @ None)) = stmts.last_mut() {
+        *semi = Some(Default::default());
+    }
+}
+
+fn lines_buffer() -> syn::Ident {
+    syn::Ident::new("stdout_lines", Span::call_site())
+}
+
+fn partial_line_buffer() -> syn::Ident {
+    syn::Ident::new("partial_line", Span::call_site())
+}
+
+#[derive(Default)]
+struct PrintScan {
+    has_partial_lines: bool,
+}
+
+impl<'ast> Visit<'ast> for PrintScan {
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if let Some(print) = PrintMacro::parse(mac) {
+            self.has_partial_lines |= print.channel == OutputChannel::Stdout && !print.newline;
+        }
+        visit::visit_macro(self, mac);
+    }
+}
+
+struct StdoutLinesRewriter {
+    has_partial_lines: bool,
+}
+
+impl StdoutLinesRewriter {
+    fn rewrite_macro(&self, mac: &Macro) -> Option<Expr> {
+        let print = PrintMacro::parse(mac)?;
+        if print.channel != OutputChannel::Stdout {
+            return None;
+        }
+
+        let lines = lines_buffer();
+        let partial = partial_line_buffer();
+        let message = format_message(mac);
+        let push = match (print.newline, self.has_partial_lines) {
+            (true, false) => quote! { #lines.push(#message) },
+            // Complete the pending partial line, if any
+            (true, true) => quote! { #lines.push(std::mem::take(&mut #partial) + &#message) },
+            (false, _) => quote! { #partial.push_str(&#message) },
+        };
+        syn::parse2(push).ok()
+    }
+}
+
+impl VisitMut for StdoutLinesRewriter {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Macro(stmt_macro) = stmt {
+            if let Some(push) = self.rewrite_macro(&stmt_macro.mac) {
+                *stmt = Stmt::Expr(push, Some(stmt_macro.semi_token.unwrap_or_default()));
+                return;
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(ExprMacro { mac, .. }) = expr {
+            if let Some(push) = self.rewrite_macro(mac) {
+                *expr = push;
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+
+    fn lower(body: &str) -> (String, bool) {
+        let lowered = StdoutLines::lower(&parse_block(body));
+        let stmts = &lowered.stmts;
+        let code = lowered.closure_body(quote! { #(#stmts)* }).to_string();
+        (code.split_whitespace().collect(), lowered.has_partial_lines)
+    }
+
+    #[test]
+    fn test_log_macros_are_recognized() {
+        let level = |tokens: &str| {
+            let mac: Macro = syn::parse_str(tokens).unwrap();
+            LogMacro::parse(&mac).map(|log| log.level)
+        };
+        assert_eq!(level("info!(\"started {}\", id)"), Some(LogLevel::Info));
+        assert_eq!(level("log::warn!(\"low disk\")"), Some(LogLevel::Warn));
+        assert_eq!(level("tracing::error!(\"failed: {}\", e)"), Some(LogLevel::Error));
+        // Structured fields and targets don't make a plain message
+        assert_eq!(level("tracing::info!(user = %name, \"login\")"), None);
+        assert_eq!(level("log::debug!(target: \"db\", \"query\")"), None);
+        assert_eq!(level("other::info!(\"x\")"), None);
+    }
+
+    #[test]
+    fn test_println_becomes_line_push() {
+        let (code, has_partial_lines) = lower(r#"{
+            println!("Hello, {}!", name);
+            for i in 1..=3 { println!("Count: {}", i) }
+            eprintln!("done");
+        }"#);
+
+        assert!(!has_partial_lines);
+        assert!(code.contains("stdout_lines.push(format!(\"Hello,{}!\",name));"));
+        assert!(code.contains("stdout_lines.push(format!(\"Count:{}\",i))"));
+        // stderr is not part of the stdout stream
+        assert!(code.contains("eprintln!(\"done\");"));
+        assert!(code.ends_with("stdout_lines"));
+    }
+
+    #[test]
+    fn test_print_accumulates_partial_line() {
+        let (code, has_partial_lines) = lower(r#"{
+            print!("Processing");
+            print!(".");
+            println!();
+            print!("tail");
+        }"#);
+
+        assert!(has_partial_lines);
+        assert!(code.contains("partial_line.push_str(&format!(\"Processing\"));"));
+        assert!(code.contains("stdout_lines.push(std::mem::take(&mutpartial_line)+&String::new());"));
+        // The unterminated tail is flushed as a final line
+        assert!(code.contains("if!partial_line.is_empty(){stdout_lines.push(partial_line);}"));
+    }
+
+    #[test]
+    fn test_writes_through_handles_become_prints() {
+        let block: syn::Block = syn::parse_str(r#"{
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            let mut err = io::stderr().lock();
+            for i in 0..3 {
+                writeln!(out, "line {}", i)?;
+                write!(&mut err, ".").unwrap();
+            }
+            let done = writeln!(out, "done");
+            out.flush()?;
+            writeln!(io::stdout(), "direct").expect("stdout");
+        }"#).unwrap();
+        let stmts = print_through_handles(&block.stmts);
+        let code: String = quote! { #(#stmts)* }.to_string().split_whitespace().collect();
+
+        assert_eq!(code, concat!(
+            "for i in 0..3{println!(\"line {}\",i);eprint!(\".\");}",
+            "let done={println!(\"done\");std::io::Result::Ok(())};",
+            "println!(\"direct\");",
+        ).split_whitespace().collect::<String>());
+    }
+
+    #[test]
+    fn test_handles_used_otherwise_are_kept() {
+        let block: syn::Block = syn::parse_str(r#"{
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            writeln!(out, "header")?;
+            render(&mut out)?;
+        }"#).unwrap();
+        let stmts = print_through_handles(&block.stmts);
+        assert_eq!(stmts, block.stmts);
+
+        // The result of a flush is kept, and so is the handle
+        let block: syn::Block = syn::parse_str("{ let mut out = io::stdout().lock(); writeln!(out, \"x\")?; let flushed = out.flush(); }").unwrap();
+        assert_eq!(print_through_handles(&block.stmts), block.stmts);
+    }
+
+    #[test]
+    fn test_parse_print_macros() {
+        let mac = |code: &str| syn::parse_str::<syn::Macro>(code).unwrap();
+        assert_eq!(
+            PrintMacro::parse(&mac("eprint!(\".\")")),
+            Some(PrintMacro { channel: OutputChannel::Stderr, newline: false })
+        );
+        assert_eq!(
+            PrintMacro::parse(&mac("println!()")),
+            Some(PrintMacro { channel: OutputChannel::Stdout, newline: true })
+        );
+        assert_eq!(PrintMacro::parse(&mac("format!(\"{}\", 1)")), None);
+    }
+
+    #[test]
+    fn test_diagnostic_macro_operands() {
+        let mac = |code: &str| syn::parse_str::<syn::Macro>(code).unwrap();
+        let operands = |code: &str| {
+            let mac = mac(code);
+            let operands = DiagnosticMacro::parse(&mac).unwrap().operands(&mac);
+            operands.map(|operands| operands.iter().map(|operand| operand.to_token_stream().to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(operands("dbg!(a, b + 1)"), Some(vec!["a".to_string(), "b + 1".to_string()]));
+        // The message of an assertion only formats
+        assert_eq!(operands("assert!(check(x), \"bad {}\", x)"), Some(vec!["check (x)".to_string()]));
+        assert_eq!(operands("assert_ne!(left, right)"), Some(vec!["left".to_string(), "right".to_string()]));
+        assert_eq!(operands("panic!(\"{}\", x)"), None);
+        assert_eq!(DiagnosticMacro::parse(&mac("debug_assert!(x)")), None);
+    }
+}
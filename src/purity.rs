@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::{Expr, Item, Stmt, Token};
+
+use crate::dependencies::{DefUseGraph, Handoff};
+use crate::syntax::leaves_early;
+
+/// The path segments of calls that do I/O
+const IO_CALLS: [&str; 11] = ["stdin", "stdout", "stderr", "fs", "File", "OpenOptions", "TcpStream", "TcpListener", "UdpSocket", "Command", "exit"];
+/// The methods that do I/O on a handle
+const IO_METHODS: [&str; 8] = ["read_line", "read_to_string", "read_to_end", "read_exact", "write_all", "write_fmt", "flush", "sync_all"];
+/// The macros that do I/O
+const IO_MACROS: [&str; 13] = ["print", "println", "eprint", "eprintln", "write", "writeln", "dbg", "trace", "debug", "info", "warn", "error", "log"];
+/// The calls that read the clock or wait on it
+const TIME_CALLS: [&str; 5] = ["Instant::now", "SystemTime::now", "Utc::now", "Local::now", "sleep"];
+/// The methods that read the clock
+const TIME_METHODS: [&str; 2] = ["elapsed", "duration_since"];
+/// The calls that depend on the environment the program runs in
+const ENVIRONMENT_CALLS: [&str; 5] = ["random", "thread_rng", "rng", "from_entropy", "process::id"];
+/// The methods that draw randomness
+const ENVIRONMENT_METHODS: [&str; 3] = ["gen", "gen_range", "gen_bool"];
+/// What running a statement depends on or affects, beyond the values it computes. The
+/// variants are ordered from the weakest to the strongest, and a statement takes the
+/// strongest of the things it does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Effect {
+    /// Computes values only, so it can run in any operator
+    Pure,
+    /// Reads the environment: variables, arguments or randomness
+    EnvironmentDependent,
+    /// Reads the clock, or sleeps
+    TimeDependent,
+    /// Reads or writes the standard streams, files, sockets or other processes
+    Io,
+}
+
+impl Effect {
+    /// Classify a statement of a legacy body, looking into the file's functions it calls
+    pub fn of(items: &[Item], stmt: &Stmt) -> Self {
+        let functions = items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Fn(func) => Some((func.sig.ident.to_string(), func)),
+                _ => None,
+            })
+            .collect();
+        let mut effects = Effects { functions: &functions, visiting: HashSet::new(), effect: Effect::Pure };
+        effects.visit_stmt(stmt);
+        effects.effect
+    }
+
+    /// Classify each statement of a legacy body
+    pub fn of_body(items: &[Item], stmts: &[Stmt]) -> Vec<Self> {
+        stmts.iter().map(|stmt| Self::of(items, stmt)).collect()
+    }
+}
+
+/// Finds the strongest effect of the code it visits
+struct Effects<'a> {
+    functions: &'a HashMap<String, &'a syn::ItemFn>,
+    /// The file's functions being looked into, which recursion doesn't visit again
+    visiting: HashSet<String>,
+    effect: Effect,
+}
+
+impl Effects<'_> {
+    fn found(&mut self, effect: Effect) {
+        self.effect = self.effect.max(effect);
+    }
+}
+
+impl<'ast> Visit<'ast> for Effects<'_> {
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        if let Expr::Path(func) = &*call.func {
+            let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+            let path = segments.join("::");
+            if segments.iter().any(|segment| IO_CALLS.contains(&segment.as_str())) {
+                self.found(Effect::Io);
+            } else if TIME_CALLS.iter().any(|time| path.ends_with(time)) {
+                self.found(Effect::TimeDependent);
+            } else if segments.iter().any(|segment| segment == "env") || ENVIRONMENT_CALLS.iter().any(|environment| path.ends_with(environment)) {
+                self.found(Effect::EnvironmentDependent);
+            } else if let [name] = &segments[..] {
+                if let Some(function) = self.functions.get(name) {
+                    if self.visiting.insert(name.clone()) {
+                        self.visit_block(&function.block);
+                    }
+                }
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        let method = call.method.to_string();
+        if IO_METHODS.contains(&method.as_str()) {
+            self.found(Effect::Io);
+        } else if TIME_METHODS.contains(&method.as_str()) {
+            self.found(Effect::TimeDependent);
+        } else if ENVIRONMENT_METHODS.contains(&method.as_str()) {
+            self.found(Effect::EnvironmentDependent);
+        }
+        visit::visit_expr_method_call(self, call);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        if mac.path.segments.last().is_some_and(|last| IO_MACROS.contains(&last.ident.to_string().as_str())) {
+            self.found(Effect::Io);
+        }
+        // The arguments of `format!`, `vec!` and the like are code too
+        if let Ok(args) = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+            args.iter().for_each(|arg| self.visit_expr(arg));
+        }
+        visit::visit_macro(self, mac);
+    }
+}
+
+/// The statements a legacy body starts with that have no effects, hoisted into `map`
+/// stages of their own ahead of the statements that do. The body's def-use graph cuts
+/// them into stages, each handing the values later statements use on to the next.
+pub struct PureStage {
+    /// The statements of each stage, with what it hands on
+    pub stages: Vec<(Vec<Stmt>, Handoff)>,
+    /// The statements from the first one with an effect on
+    pub rest: Vec<Stmt>,
+}
+
+impl PureStage {
+    /// Split off the pure statements a body starts with, when they compute values the
+    /// rest of the body uses and none leaves early. A stage ends after each statement
+    /// that computes something, unless a borrow is still held after it.
+    pub fn split(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let count = Effect::of_body(items, stmts).iter().take_while(|effect| **effect == Effect::Pure).count();
+        if count == 0 || count == stmts.len() {
+            return None;
+        }
+        let (pure, rest) = stmts.split_at(count);
+        // Starting values for the rest of the body to update aren't worth a stage
+        if leaves_early(pure) || !pure.iter().any(computes) {
+            return None;
+        }
+
+        let graph = DefUseGraph::of_body(stmts);
+        let mut cuts: Vec<usize> = (1..count)
+            .filter(|&cut| computes(&stmts[cut - 1]) && graph.live_across(cut).is_some_and(|live| !live.is_empty()))
+            .collect();
+        cuts.push(count);
+
+        let mut stages = Vec::new();
+        let mut start = 0;
+        for (index, &cut) in cuts.iter().enumerate() {
+            let until = cuts.get(index + 1).copied().unwrap_or(stmts.len());
+            let handoff = graph.handoff(cut, until)?;
+            stages.push((stmts[start..cut].to_vec(), handoff));
+            start = cut;
+        }
+        if stages.last().is_some_and(|(_, handoff)| handoff.bindings.is_empty()) {
+            return None;
+        }
+        Some(Self { stages, rest: rest.to_vec() })
+    }
+
+    /// The pattern the rest of the body takes the last stage's values with
+    pub fn pattern(&self) -> TokenStream {
+        self.stages.last().map_or_else(|| quote! { _ }, |(_, handoff)| handoff.pattern())
+    }
+}
+
+/// Whether a statement does more than bind a starting value: a literal, a path, or a
+/// constructor called with those
+fn computes(stmt: &Stmt) -> bool {
+    fn starting_value(expr: &Expr) -> bool {
+        match expr {
+            Expr::Lit(_) | Expr::Path(_) => true,
+            Expr::Call(call) => matches!(&*call.func, Expr::Path(_)) && call.args.iter().all(starting_value),
+            Expr::Tuple(tuple) => tuple.elems.iter().all(starting_value),
+            Expr::Array(array) => array.elems.iter().all(starting_value),
+            Expr::Unary(unary) => starting_value(&unary.expr),
+            _ => false,
+        }
+    }
+
+    match stmt {
+        Stmt::Local(local) => local.init.as_ref().is_some_and(|init| !starting_value(&init.expr)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_main;
+    use quote::ToTokens;
+
+    fn parse(source: &str) -> (Vec<Item>, Vec<Stmt>) {
+        parse_main(source).unwrap()
+    }
+
+    #[test]
+    fn test_statements_are_classified() {
+        let (items, body) = parse(r#"
+            fn report(total: u64) {
+                println!("{}", total);
+            }
+
+            fn main() {
+                let numbers: Vec<u64> = (1..=10).collect();
+                let total = numbers.iter().sum::<u64>();
+                let name = std::env::var("NAME").unwrap_or_default();
+                let started = Instant::now();
+                let elapsed = format!("{:?}", started.elapsed());
+                report(total);
+                let mut line = String::new();
+                io::stdin().read_line(&mut line).unwrap();
+            }
+        "#);
+
+        use Effect::*;
+        assert_eq!(Effect::of_body(&items, &body), [Pure, Pure, EnvironmentDependent, TimeDependent, TimeDependent, Io, Pure, Io]);
+    }
+
+    #[test]
+    fn test_pure_statements_are_hoisted() {
+        let (items, body) = parse(r#"
+            fn main() {
+                let base = 10;
+                let (mut squares, count) = ((0..base).map(|n| n * n).collect::<Vec<u64>>(), base);
+                let base = base * 2;
+                squares.push(base);
+                println!("{:?} {}", squares, count);
+            }
+        "#);
+
+        let stage = PureStage::split(&items, &body).unwrap();
+        // A stage ends after each statement computing something
+        let lengths: Vec<usize> = stage.stages.iter().map(|(stmts, _)| stmts.len()).collect();
+        assert_eq!((lengths, stage.rest.len()), (vec![2, 1, 1], 1));
+        let handoffs: Vec<String> = stage.stages.iter().map(|(_, handoff)| handoff.pattern().to_string()).collect();
+        assert_eq!(handoffs, ["(base , squares , count)", "(mut squares , count , base)", "(squares , count)"]);
+    }
+
+    #[test]
+    fn test_statements_that_stay_together() {
+        // Nothing effectful follows
+        let (items, body) = parse("fn main() { let x = 1; let y = x + 1; }");
+        assert!(PureStage::split(&items, &body).is_none());
+        // The body starts with an effect
+        let (items, body) = parse("fn main() { println!(\"hi\"); let x = 1; }");
+        assert!(PureStage::split(&items, &body).is_none());
+        // The second binding borrows from the first
+        let (items, body) = parse("fn main() { let words = vec![\"a\", \"b\"]; let first = words.first(); println!(\"{:?}\", first); }");
+        assert!(PureStage::split(&items, &body).is_none());
+        // Only starting values for the rest to update
+        let (items, body) = parse("fn main() { let mut total = 0; let mut seen = Vec::new(); for line in io::stdin().lines() { total += 1; seen.push(line); } println!(\"{} {:?}\", total, seen); }");
+        assert!(PureStage::split(&items, &body).is_none());
+        // A single binding is handed on as it is
+        let (items, body) = parse("fn main() { let greeting = [\"hello\", \"world\"].join(\" \"); println!(\"{}\", greeting); }");
+        let stage = PureStage::split(&items, &body).unwrap();
+        assert_eq!(stage.pattern().to_token_stream().to_string(), "greeting");
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use syn::{BinOp, Expr, Ident, Pat, Stmt};
+
+use crate::keyed_aggregation::mutates;
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::syntax::{bindings, mentions};
+
+/// A legacy loop reducing its items into a single accumulator, like a sum, followed by
+/// the code that uses the result. The operator folding the items in doesn't care how
+/// they are grouped or ordered, so any share of them can be folded on its own into a
+/// partial, and the partials combined with the same operator.
+pub struct ReduceLoop {
+    /// Immutable bindings before the accumulator, which only feed the loop
+    pub prefix: Vec<Stmt>,
+    /// The accumulator's binding, which the code after the loop gets the result in
+    pub acc: Ident,
+    /// The accumulator's starting value, which the partials are combined into
+    pub init: Expr,
+    pub pat: Pat,
+    /// The collection the loop iterates over, whose items are shipped to the members
+    pub items: Expr,
+    /// The bindings each iteration makes before it folds its value in
+    pub lets: Vec<Stmt>,
+    /// The compound assignment folding a value in, like `+=`
+    pub op: BinOp,
+    pub value: Expr,
+    /// The statements after the loop
+    pub rest: Vec<Stmt>,
+    /// Whether the statements after the loop change the accumulator
+    pub rest_mutates: bool,
+}
+
+impl ReduceLoop {
+    /// Recognize `let mut acc = init; for x in xs { .. acc += v; }` followed by the
+    /// statements reading `acc`, after any immutable `let`s the loop uses. The iterations
+    /// may only read their own item, so they can run wherever the item is sent.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let start = stmts.iter().position(|stmt| acc_decl(stmt).is_some())?;
+        let (prefix, [decl, fold, rest @ ..]) = stmts.split_at(start) else {
+            return None;
+        };
+        let (acc, init) = acc_decl(decl)?;
+        let name = acc.to_string();
+
+        let mut prefix_names = HashSet::new();
+        for stmt in prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            if !bindings(&local.pat, &mut prefix_names) {
+                return None;
+            }
+        }
+        // The leader combines the partials into the starting value, and runs the code
+        // after the loop, without the bindings before it
+        let outside = |stmts: &[Stmt]| prefix_names.iter().any(|prefix| mentions(stmts, prefix));
+        if outside(&[Stmt::Expr(init.clone(), None)]) {
+            return None;
+        }
+
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = fold else {
+            return None;
+        };
+        let analysis = LoopAnalysis::of_loop(&[], stmts, start + 1)?;
+        if analysis.kind != LoopKind::FoldLike || analysis.skips {
+            return None;
+        }
+        // The items are built on the leader and shipped to the members, so they must be owned
+        let borrows = match &*for_loop.expr {
+            Expr::Reference(_) => true,
+            Expr::MethodCall(call) => call.method == "iter" || call.method == "iter_mut",
+            _ => false,
+        };
+        if borrows {
+            return None;
+        }
+
+        let (last, lets) = for_loop.body.stmts.split_last()?;
+        if !lets.iter().all(|stmt| matches!(stmt, Stmt::Local(_))) {
+            return None;
+        }
+        let Stmt::Expr(Expr::Binary(update), Some(_)) = last else {
+            return None;
+        };
+        if !regroups(&update.op) || !matches!(&*update.left, Expr::Path(path) if path.path.is_ident(&name)) {
+            return None;
+        }
+        let member_side = [lets, &[Stmt::Expr((*update.right).clone(), None)]].concat();
+        if mentions(&member_side, &name) || outside(&member_side) {
+            return None;
+        }
+
+        if rest.is_empty() || !mentions(rest, &name) || outside(rest) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            acc,
+            init,
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            lets: lets.to_vec(),
+            op: update.op,
+            value: (*update.right).clone(),
+            rest: rest.to_vec(),
+            rest_mutates: mutates(rest, &name),
+        })
+    }
+}
+
+/// `let mut acc = init;`, giving the binding and its starting value
+fn acc_decl(stmt: &Stmt) -> Option<(Ident, Expr)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let pat = match &local.pat {
+        Pat::Type(typed) => &*typed.pat,
+        pat => pat,
+    };
+    let Pat::Ident(binding) = pat else {
+        return None;
+    };
+    binding.mutability?;
+    let init = local.init.as_ref()?;
+    if init.diverge.is_some() {
+        return None;
+    }
+    Some((binding.ident.clone(), (*init.expr).clone()))
+}
+
+/// Whether folding with the compound assignment gives the same result however the
+/// values are split up and ordered
+fn regroups(op: &BinOp) -> bool {
+    use syn::BinOp::*;
+    matches!(op, AddAssign(_) | MulAssign(_) | BitXorAssign(_) | BitAndAssign(_) | BitOrAssign(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+    use quote::ToTokens;
+
+    fn detect(body: &str) -> Option<ReduceLoop> {
+        ReduceLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_sum_of_squares_reduces() {
+        let summed = detect(r#"{
+            let numbers: Vec<u64> = (1..=100).collect();
+            let mut total: u64 = 0;
+            for n in numbers {
+                let square = n * n;
+                total += square;
+            }
+            println!("Sum of squares: {}", total);
+        }"#).unwrap();
+
+        assert_eq!(summed.prefix.len(), 1);
+        assert_eq!(summed.acc, "total");
+        assert_eq!(summed.init.to_token_stream().to_string(), "0");
+        assert_eq!(summed.lets.len(), 1);
+        assert!(matches!(summed.op, BinOp::AddAssign(_)));
+        assert_eq!(summed.value.to_token_stream().to_string(), "square");
+        assert_eq!(summed.rest.len(), 1);
+        assert!(!summed.rest_mutates);
+
+        let flags = detect("{ let mut seen = 0u32; for bit in bits() { seen |= 1 << bit; } seen &= 0xff; println!(\"{:b}\", seen); }").unwrap();
+        assert!(matches!(flags.op, BinOp::BitOrAssign(_)));
+        assert!(flags.rest_mutates);
+    }
+
+    #[test]
+    fn test_other_folds_are_not_reductions() {
+        // The result depends on the order of the items
+        assert!(detect("{ let mut left = 100; for n in numbers() { left -= n; } println!(\"{}\", left); }").is_none());
+        // An iteration reads the accumulator
+        assert!(detect("{ let mut total = 1; for n in numbers() { total += total * n; } println!(\"{}\", total); }").is_none());
+        // The items are borrowed from the leader
+        assert!(detect("{ let numbers = vec![1, 2, 3]; let mut total = 0; for n in numbers.iter() { total += n; } println!(\"{}\", total); }").is_none());
+        // An iteration reads a binding that only exists before the loop
+        assert!(detect("{ let scale = 2; let mut total = 0; for n in numbers() { total += n * scale; } println!(\"{}\", total); }").is_none());
+        // The iterations skip items
+        assert!(detect("{ let mut total = 0; for n in numbers() { if n < 0 { continue; } total += n; } println!(\"{}\", total); }").is_none());
+        // Nothing reads the result afterwards
+        assert!(detect("{ let mut total = 0; for n in numbers() { total += n; } }").is_none());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Item, Pat, Stmt};
+
+use crate::error::TransformError;
+
+/// The cloud machines the remote variant of a generated example deploys onto, read from
+/// a deployment file like
+///
+/// ```toml
+/// project = "my-project"
+/// region = "us-west1-a"
+/// machine_type = "e2-micro"
+///
+/// [machine_types]
+/// workers = "e2-standard-4"
+/// ```
+///
+/// Each process and each member of a cluster gets a Compute Engine machine of its own,
+/// which `hydro_deploy` creates and reaches over SSH. The external process the example
+/// talks to the dataflow through stays on the machine running the example.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteHosts {
+    /// The GCP project the machines are created in
+    pub project: String,
+    /// The zone the machines are created in
+    pub region: String,
+    /// The machine type of the processes and members without one of their own
+    pub machine_type: String,
+    /// The boot image of every machine
+    pub image: String,
+    /// Machine types of particular locations, by the name the example binds them to,
+    /// like `leader` or `workers`
+    pub machine_types: BTreeMap<String, String>,
+}
+
+/// The options of a deployment file, outside any table
+const KEYS: &[&str] = &["project", "region", "machine_type", "image"];
+
+impl RemoteHosts {
+    pub fn new(project: &str) -> Self {
+        Self {
+            project: project.to_string(),
+            region: "us-west1-a".to_string(),
+            machine_type: "e2-micro".to_string(),
+            image: "debian-cloud/debian-11".to_string(),
+            machine_types: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_machine_type(mut self, location: &str, machine_type: &str) -> Self {
+        self.machine_types.insert(location.to_string(), machine_type.to_string());
+        self
+    }
+
+    /// Read a deployment file. It has to name the project, and the other options left
+    /// out keep their defaults.
+    pub fn from_file_contents(contents: &str) -> Result<Self, TransformError> {
+        let mut hosts = Self::new("");
+        let mut in_machine_types = false;
+        for (index, line) in contents.lines().enumerate() {
+            let error = |message: String| TransformError::ConfigError { line: index + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(table) = line.strip_prefix('[').and_then(|table| table.strip_suffix(']')) {
+                if table.trim() != "machine_types" {
+                    return Err(error(format!("unknown table `{}`, expected `machine_types`", table)));
+                }
+                in_machine_types = true;
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| error(format!("expected `key = value`, found `{}`", line)))?;
+            let (key, value) = (key.trim(), value.trim().trim_matches('"').to_string());
+            if in_machine_types {
+                hosts.machine_types.insert(key.to_string(), value);
+                continue;
+            }
+            match key {
+                "project" => hosts.project = value,
+                "region" => hosts.region = value,
+                "machine_type" => hosts.machine_type = value,
+                "image" => hosts.image = value,
+                _ => return Err(error(format!("unknown option `{}`, expected one of {}", key, KEYS.join(", ")))),
+            }
+        }
+        if hosts.project.is_empty() {
+            let line = contents.lines().count().max(1);
+            return Err(TransformError::ConfigError { line, message: "missing `project`, the GCP project to create the hosts in".to_string() });
+        }
+        Ok(hosts)
+    }
+
+    /// The remote variant of a generated example: the processes and cluster members it
+    /// deploys on `localhost` each get a machine of their own instead
+    pub fn remote_example(&self, example: &str) -> Result<String, TransformError> {
+        let mut file = syn::parse_file(example).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+
+        let mut placement = Placement { hosts: self, placed: BTreeSet::new(), error: None };
+        placement.visit_file_mut(&mut file);
+        if let Some(error) = placement.error {
+            return Err(TransformError::TemplateError(error));
+        }
+        if placement.placed.is_empty() {
+            return Err(TransformError::TemplateError("The example deploys no process or cluster".to_string()));
+        }
+        if let Some(unknown) = self.machine_types.keys().find(|location| !placement.placed.contains(*location)) {
+            let placed: Vec<&str> = placement.placed.iter().map(String::as_str).collect();
+            return Err(TransformError::TemplateError(format!(
+                "The deployment file sets the machine type of `{}`, but the example only deploys {}",
+                unknown,
+                placed.join(", ")
+            )));
+        }
+
+        let main = file
+            .items
+            .iter_mut()
+            .find_map(|item| match item {
+                Item::Fn(func) if func.sig.ident == "main" => Some(func),
+                _ => None,
+            })
+            .ok_or(TransformError::NoMainFunction)?;
+        let deployment = main
+            .block
+            .stmts
+            .iter()
+            .position(|stmt| matches!(stmt, Stmt::Local(syn::Local { pat: Pat::Ident(binding), .. }) if binding.ident == "deployment"))
+            .ok_or_else(|| TransformError::TemplateError("The example creates no `deployment`".to_string()))?;
+        main.block.stmts.insert(deployment + 1, parse_quote! {
+            // The machines share a network of their own, which is torn down with them
+            let network = std::sync::Arc::new(tokio::sync::RwLock::new(hydro_deploy::gcp::GcpNetwork::new(PROJECT, None)));
+        });
+
+        let (project, region, image) = (&self.project, &self.region, &self.image);
+        let host_items: syn::File = parse_quote! {
+            /// The GCP project, zone and boot image of the machines, from the deployment file
+            const PROJECT: &str = #project;
+            const REGION: &str = #region;
+            const IMAGE: &str = #image;
+
+            /// A fresh Compute Engine machine for a process or a member of a cluster, which
+            /// the deployment reaches over SSH
+            fn remote_host(
+                deployment: &mut hydro_deploy::Deployment,
+                network: &std::sync::Arc<tokio::sync::RwLock<hydro_deploy::gcp::GcpNetwork>>,
+                machine_type: &str,
+            ) -> std::sync::Arc<hydro_deploy::gcp::GcpComputeEngineHost> {
+                deployment
+                    .GcpComputeEngineHost()
+                    .project(PROJECT)
+                    .machine_type(machine_type)
+                    .image(IMAGE)
+                    .region(REGION)
+                    .network(network.clone())
+                    .add()
+            }
+        };
+        let main_index = file.items.iter().position(|item| matches!(item, Item::Fn(func) if func.sig.ident == "main")).unwrap_or(file.items.len());
+        file.items.splice(main_index..main_index, host_items.items);
+
+        Ok(prettyplease::unparse(&file))
+    }
+}
+
+/// Puts the processes and clusters of an example's `with_process` and `with_cluster`
+/// calls on remote hosts, recording the names of the locations it placed
+struct Placement<'h> {
+    hosts: &'h RemoteHosts,
+    placed: BTreeSet<String>,
+    error: Option<String>,
+}
+
+impl VisitMut for Placement<'_> {
+    fn visit_expr_method_call_mut(&mut self, call: &mut syn::ExprMethodCall) {
+        visit_mut::visit_expr_method_call_mut(self, call);
+        let cluster = call.method == "with_cluster";
+        if !(cluster || call.method == "with_process") || call.args.len() != 2 {
+            return;
+        }
+        let location = match &call.args[0] {
+            Expr::Reference(reference) => match &*reference.expr {
+                Expr::Path(path) => path.path.get_ident().map(|ident| ident.to_string()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(location) = location else {
+            return;
+        };
+        let machine_type = self.hosts.machine_types.get(&location).unwrap_or(&self.hosts.machine_type);
+        let host: Expr = parse_quote! { remote_host(&mut deployment, &network, #machine_type) };
+
+        call.args[1] = if cluster {
+            // One machine for each of the members the example asked for
+            let count = match &call.args[1] {
+                Expr::Macro(mac) if mac.mac.path.is_ident("vec") => mac
+                    .mac
+                    .parse_body_with(|input: syn::parse::ParseStream| {
+                        input.parse::<Expr>()?;
+                        input.parse::<syn::Token![;]>()?;
+                        input.parse::<Expr>()
+                    })
+                    .ok(),
+                _ => None,
+            };
+            let Some(count) = count else {
+                self.error = Some(format!("The members of `{}` aren't given as `vec![host; count]`", location));
+                return;
+            };
+            parse_quote! { (0..#count).map(|_| #host).collect::<Vec<_>>() }
+        } else {
+            host
+        };
+        self.placed.insert(location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTER_EXAMPLE: &str = r#"
+use hydro_deploy::Deployment;
+
+#[tokio::main]
+async fn main() {
+    let workers_count = 4;
+    let mut deployment = Deployment::new();
+    let localhost = deployment.Localhost();
+
+    let flow = hydro_lang::FlowBuilder::new();
+    let leader = flow.process();
+    let workers = flow.cluster();
+    let external = flow.external_process::<()>();
+
+    let nodes = flow
+        .with_process(&leader, localhost.clone())
+        .with_cluster(&workers, vec![localhost.clone(); workers_count])
+        .with_external(&external, localhost.clone())
+        .deploy(&mut deployment);
+}
+"#;
+
+    #[test]
+    fn test_deployment_file_is_read() {
+        let hosts = RemoteHosts::from_file_contents(
+            "# Hosts for the migrated pipelines\nproject = \"my-project\"\nmachine_type = \"e2-small\"\n\n[machine_types]\nworkers = \"e2-standard-4\"\n",
+        )
+        .unwrap();
+        let expected = RemoteHosts { machine_type: "e2-small".to_string(), ..RemoteHosts::new("my-project") };
+        assert_eq!(hosts, expected.with_machine_type("workers", "e2-standard-4"));
+
+        let missing = RemoteHosts::from_file_contents("region = \"europe-west1-b\"\n");
+        assert!(matches!(missing, Err(TransformError::ConfigError { line: 1, .. })));
+        let unknown = RemoteHosts::from_file_contents("project = \"p\"\naddress = \"10.0.0.1\"\n");
+        assert!(matches!(unknown, Err(TransformError::ConfigError { line: 2, .. })));
+        assert!(RemoteHosts::from_file_contents("project = \"p\"\n[hosts]\n").is_err());
+    }
+
+    #[test]
+    fn test_processes_and_members_get_machines_of_their_own() {
+        let hosts = RemoteHosts::new("my-project").with_machine_type("leader", "e2-standard-2");
+        let remote = hosts.remote_example(CLUSTER_EXAMPLE).unwrap();
+        let compact: String = remote.split_whitespace().collect();
+
+        assert!(compact.contains(".with_process(&leader,remote_host(&mutdeployment,&network,\"e2-standard-2\"))"));
+        assert!(compact.contains(".with_cluster(&workers,(0..workers_count).map(|_|remote_host(&mutdeployment,&network,\"e2-micro\")).collect::<Vec<_>>(),)"));
+        // The example itself still talks to the dataflow from here
+        assert!(compact.contains(".with_external(&external,localhost.clone())"));
+        assert!(compact.contains("letmutdeployment=Deployment::new();letnetwork=std::sync::Arc::new("));
+        assert!(compact.contains("constPROJECT:&str=\"my-project\";"));
+        assert!(compact.contains(".GcpComputeEngineHost().project(PROJECT).machine_type(machine_type)"));
+        assert!(syn::parse_file(&remote).is_ok());
+
+        // A machine type for a location the example doesn't have is a mistake
+        let typo = RemoteHosts::new("my-project").with_machine_type("worker", "e2-standard-4");
+        assert!(matches!(typo.remote_example(CLUSTER_EXAMPLE), Err(TransformError::TemplateError(_))));
+        assert!(hosts.remote_example("fn main() {}").is_err());
+    }
+}
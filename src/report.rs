@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::{Expr, Item};
+
+use crate::syntax::span_location;
+
+/// A construct the transformer carries over as it was written rather than translating
+/// it into dataflow, and which is likely to keep the generated code from compiling or
+/// from running across locations
+#[derive(Debug, Clone, PartialEq)]
+pub enum Construct {
+    /// An `unsafe` block, function, impl or trait
+    Unsafe,
+    /// An `asm!`, `global_asm!` or `naked_asm!` invocation
+    InlineAsm,
+    /// A function calling itself, directly or through other functions of the file
+    Recursion,
+    /// A `dyn Trait` type, other than a boxed error
+    TraitObject,
+    /// An `extern` block, an `extern "C"` function or a `#[no_mangle]` item
+    Ffi,
+}
+
+impl Construct {
+    /// How to rewrite the construct by hand so the program can be migrated
+    pub fn suggestion(&self) -> &'static str {
+        match self {
+            Construct::Unsafe => "wrap the unsafe code in a safe function upholding its invariants, and call that from the dataflow",
+            Construct::InlineAsm => "move the assembly behind a safe function, or replace it with an intrinsic or portable code",
+            Construct::Recursion => "rewrite the recursion as a loop over an explicit stack, or as a fixpoint computed around a cycle",
+            Construct::TraitObject => "replace the trait object with an enum of its implementations or a generic parameter, so values can be serialized",
+            Construct::Ffi => "keep the foreign calls in the example program, or behind a safe wrapper run on a single process",
+        }
+    }
+}
+
+/// A construct found in the legacy source, and where
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub construct: Construct,
+    /// What was found, as the legacy source names it
+    pub description: String,
+    /// 1-based line of the construct in the legacy source
+    pub line_number: Option<usize>,
+    /// 0-based column of the construct in the legacy source
+    pub column: Option<usize>,
+}
+
+impl Diagnostic {
+    fn new(construct: Construct, description: String, span: Span) -> Self {
+        let location = span_location(span);
+        Self {
+            construct,
+            description,
+            line_number: location.map(|(line, _)| line),
+            column: location.map(|(_, column)| column),
+        }
+    }
+}
+
+/// The constructs of a legacy program the transformer punted on, returned alongside the
+/// generated code so they can be rewritten by hand
+#[derive(Debug, Clone, Default)]
+pub struct TransformReport {
+    /// In the order they appear in the legacy source
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl TransformReport {
+    /// Look through a whole legacy file for the constructs the transformer can't express
+    pub fn analyze(file: &syn::File) -> Self {
+        let mut scan = Scan::default();
+        scan.visit_file(file);
+        let mut diagnostics = scan.diagnostics;
+        diagnostics.extend(recursion(&file.items));
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.line_number, diagnostic.column));
+        Self { diagnostics }
+    }
+
+    /// Whether nothing needs rewriting by hand
+    pub fn is_clean(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+impl fmt::Display for TransformReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in &self.diagnostics {
+            match (diagnostic.line_number, diagnostic.column) {
+                (Some(line), Some(column)) => write!(f, "{}:{}: ", line, column + 1)?,
+                _ => write!(f, "?: ")?,
+            }
+            writeln!(f, "{}", diagnostic.description)?;
+            writeln!(f, "    help: {}", diagnostic.construct.suggestion())?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds every construct but recursion, which takes the whole file
+#[derive(Default)]
+struct Scan {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Scan {
+    fn found(&mut self, construct: Construct, description: String, span: Span) {
+        self.diagnostics.push(Diagnostic::new(construct, description, span));
+    }
+}
+
+impl<'ast> Visit<'ast> for Scan {
+    fn visit_item(&mut self, item: &'ast Item) {
+        match item {
+            Item::ForeignMod(foreign) => {
+                let abi = foreign.abi.name.as_ref().map_or("C".to_string(), |name| name.value());
+                self.found(Construct::Ffi, format!("extern \"{}\" block", abi), foreign.abi.extern_token.span);
+            }
+            Item::Fn(func) => {
+                let name = &func.sig.ident;
+                if let Some(abi) = &func.sig.abi {
+                    self.found(Construct::Ffi, format!("extern fn `{}`", name), abi.extern_token.span);
+                } else if func.attrs.iter().any(|attr| attr.to_token_stream().to_string().contains("no_mangle")) {
+                    self.found(Construct::Ffi, format!("#[no_mangle] fn `{}`", name), func.sig.fn_token.span);
+                }
+                if let Some(unsafety) = &func.sig.unsafety {
+                    self.found(Construct::Unsafe, format!("unsafe fn `{}`", name), unsafety.span);
+                }
+            }
+            Item::Impl(imp) => {
+                if let Some(unsafety) = &imp.unsafety {
+                    self.found(Construct::Unsafe, format!("unsafe impl for `{}`", imp.self_ty.to_token_stream()), unsafety.span);
+                }
+            }
+            Item::Trait(trait_item) => {
+                if let Some(unsafety) = &trait_item.unsafety {
+                    self.found(Construct::Unsafe, format!("unsafe trait `{}`", trait_item.ident), unsafety.span);
+                }
+            }
+            _ => {}
+        }
+        visit::visit_item(self, item);
+    }
+
+    fn visit_impl_item_fn(&mut self, method: &'ast syn::ImplItemFn) {
+        if let Some(unsafety) = &method.sig.unsafety {
+            self.found(Construct::Unsafe, format!("unsafe fn `{}`", method.sig.ident), unsafety.span);
+        }
+        visit::visit_impl_item_fn(self, method);
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        if let Expr::Unsafe(block) = expr {
+            self.found(Construct::Unsafe, "unsafe block".to_string(), block.unsafe_token.span);
+        }
+        visit::visit_expr(self, expr);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        if let Some(name) = mac.path.segments.last().map(|segment| segment.ident.to_string()) {
+            if name == "asm" || name == "global_asm" || name == "naked_asm" {
+                self.found(Construct::InlineAsm, format!("{}!", name), mac.path.span());
+            }
+        }
+        visit::visit_macro(self, mac);
+    }
+
+    fn visit_type_trait_object(&mut self, object: &'ast syn::TypeTraitObject) {
+        // Boxed errors stay on the process that returns them
+        let is_error = object.bounds.iter().any(|bound| {
+            matches!(bound, syn::TypeParamBound::Trait(bound) if bound.path.segments.last().is_some_and(|last| last.ident == "Error"))
+        });
+        if object.dyn_token.is_some() && !is_error {
+            let description = format!("trait object `{}`", object.to_token_stream());
+            self.found(Construct::TraitObject, description, object.span());
+        }
+        visit::visit_type_trait_object(self, object);
+    }
+}
+
+/// The functions of the file that call themselves, directly or through the file's other
+/// functions
+fn recursion(items: &[Item]) -> Vec<Diagnostic> {
+    let functions: Vec<&syn::ItemFn> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(func) => Some(func),
+            _ => None,
+        })
+        .collect();
+    let names: HashSet<String> = functions.iter().map(|func| func.sig.ident.to_string()).collect();
+
+    // Which of the file's functions each one calls
+    let mut calls: HashMap<String, HashSet<String>> = HashMap::new();
+    for func in &functions {
+        let mut callees = Callees { names: &names, found: HashSet::new() };
+        callees.visit_block(&func.block);
+        calls.insert(func.sig.ident.to_string(), callees.found);
+    }
+
+    functions
+        .iter()
+        .filter(|func| {
+            let name = func.sig.ident.to_string();
+            let mut seen = HashSet::new();
+            let mut pending: Vec<&String> = calls[&name].iter().collect();
+            while let Some(callee) = pending.pop() {
+                if *callee == name {
+                    return true;
+                }
+                if seen.insert(callee) {
+                    pending.extend(&calls[callee]);
+                }
+            }
+            false
+        })
+        .map(|func| Diagnostic::new(Construct::Recursion, format!("recursive fn `{}`", func.sig.ident), func.sig.ident.span()))
+        .collect()
+}
+
+/// The names of the file's functions called by plain paths
+struct Callees<'a> {
+    names: &'a HashSet<String>,
+    found: HashSet<String>,
+}
+
+impl<'ast> Visit<'ast> for Callees<'_> {
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        if let Expr::Path(func) = &*call.func {
+            if let Some(name) = func.path.get_ident().map(|ident| ident.to_string()) {
+                if self.names.contains(&name) {
+                    self.found.insert(name);
+                }
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyze(source: &str) -> TransformReport {
+        TransformReport::analyze(&syn::parse_str(source).unwrap())
+    }
+
+    #[test]
+    fn test_punted_constructs_are_located() {
+        let report = analyze(r#"
+extern "C" {
+    fn abs(input: i32) -> i32;
+}
+
+fn fib(n: u64) -> u64 {
+    if n < 2 { n } else { fib(n - 1) + fib(n - 2) }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let shapes: Vec<Box<dyn Shape>> = Vec::new();
+    let x = unsafe { abs(-3) };
+    unsafe { std::arch::asm!("nop") };
+    Ok(())
+}
+"#);
+
+        let found: Vec<(Construct, Option<usize>)> = report.diagnostics.iter().map(|diagnostic| (diagnostic.construct.clone(), diagnostic.line_number)).collect();
+        assert_eq!(found, [
+            (Construct::Ffi, Some(2)),
+            (Construct::Recursion, Some(6)),
+            (Construct::TraitObject, Some(11)),
+            (Construct::Unsafe, Some(12)),
+            (Construct::Unsafe, Some(13)),
+            (Construct::InlineAsm, Some(13)),
+        ]);
+        assert_eq!(report.diagnostics[2].description, "trait object `dyn Shape`");
+        let shown = report.to_string();
+        assert!(shown.starts_with("2:1: extern \"C\" block\n    help: keep the foreign calls"));
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        let report = analyze(r#"
+            fn even(n: u32) -> bool { n == 0 || odd(n - 1) }
+            fn odd(n: u32) -> bool { n != 0 && even(n - 1) }
+            fn main() { println!("{}", even(10)); }
+        "#);
+        let descriptions: Vec<&str> = report.diagnostics.iter().map(|diagnostic| diagnostic.description.as_str()).collect();
+        assert_eq!(descriptions, ["recursive fn `even`", "recursive fn `odd`"]);
+    }
+
+    #[test]
+    fn test_plain_program_is_clean() {
+        assert!(analyze("fn main() { let v: Vec<u32> = (0..10).collect(); println!(\"{:?}\", v); }").is_clean());
+    }
+}
@@ -0,0 +1,160 @@
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_file, parse_quote, Expr, Item, Stmt, Token};
+
+/// Environment variable the generated examples read the RNG seed from
+pub const SEED_ENV_VAR: &str = "HYDRO_INGEST_SEED";
+
+/// Rewrite `thread_rng()` and `random()` calls in a legacy body to draw from a single
+/// `StdRng` seeded with a `seed: u64` in scope, so that two runs given the same seed
+/// produce the same sequence. Returns `None` if the body doesn't use `rand`.
+pub fn seed_rng_calls(stmts: &[Stmt]) -> Option<Vec<Stmt>> {
+    let mut rewriter = RngRewriter::default();
+    let mut stmts = stmts.to_vec();
+    for stmt in &mut stmts {
+        rewriter.visit_stmt_mut(stmt);
+    }
+    if !rewriter.rewritten {
+        return None;
+    }
+
+    let rng = rng_ident();
+    let seeded: Stmt = parse_quote! {
+        let mut #rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+    };
+    stmts.insert(0, seeded);
+    Some(stmts)
+}
+
+/// Seed a whole legacy program with a fixed seed, for running it next to a Hydro
+/// version that was given the same seed. Programs without randomness are unchanged.
+pub fn seed_legacy_program(source: &str, seed: u64) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = parse_file(source)?;
+    let main_fn = file
+        .items
+        .iter_mut()
+        .find_map(|item| match item {
+            Item::Fn(func) if func.sig.ident == "main" => Some(func),
+            _ => None,
+        })
+        .ok_or("No main function found in the source file")?;
+
+    let Some(mut stmts) = seed_rng_calls(&main_fn.block.stmts) else {
+        return Ok(source.to_string());
+    };
+    stmts.insert(0, parse_quote! { let seed: u64 = #seed; });
+    main_fn.block.stmts = stmts;
+    Ok(prettyplease::unparse(&file))
+}
+
+/// `rand::random` rather than some other `random`, which is too common a name to assume
+fn is_rand_path(path: &syn::Path) -> bool {
+    path.segments.len() > 1 && path.segments[0].ident == "rand"
+}
+
+fn rng_ident() -> syn::Ident {
+    syn::Ident::new("seeded_rng", Span::call_site())
+}
+
+#[derive(Default)]
+struct RngRewriter {
+    rewritten: bool,
+}
+
+impl VisitMut for RngRewriter {
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        // Format-style macros like println! take expressions, which may draw randomness
+        let Ok(mut args) = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) else {
+            return;
+        };
+        let rewritten = self.rewritten;
+        self.rewritten = false;
+        for arg in args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+        if self.rewritten {
+            mac.tokens = args.to_token_stream();
+        }
+        self.rewritten |= rewritten;
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+
+        let Expr::Call(call) = expr else {
+            return;
+        };
+        let Expr::Path(func) = &*call.func else {
+            return;
+        };
+        let Some(last) = func.path.segments.last() else {
+            return;
+        };
+        let rng = rng_ident();
+        if last.ident == "thread_rng" && call.args.is_empty() {
+            // `&mut StdRng` is an `Rng` too, so uses of the handle keep working
+            *expr = parse_quote! { (&mut #rng) };
+            self.rewritten = true;
+        } else if last.ident == "random" && call.args.is_empty() && is_rand_path(&func.path) {
+            let ty = match &last.arguments {
+                syn::PathArguments::AngleBracketed(args) => Some(args.clone()),
+                _ => None,
+            };
+            *expr = match ty {
+                Some(ty) => parse_quote! { rand::Rng::gen #ty (&mut #rng) },
+                None => parse_quote! { rand::Rng::gen(&mut #rng) },
+            };
+            self.rewritten = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::parse_block;
+
+    fn rewrite(body: &str) -> Option<String> {
+        seed_rng_calls(&parse_block(body)).map(|stmts| {
+            let code = quote::quote! { #(#stmts)* }.to_string();
+            code.split_whitespace().collect()
+        })
+    }
+
+    #[test]
+    fn test_rng_calls_draw_from_seeded_rng() {
+        let code = rewrite(r#"{
+            let mut rng = rand::thread_rng();
+            let roll = rng.gen_range(1..=6);
+            let coin: bool = rand::random();
+            let x = rand::random::<u8>();
+            println!("{} {} {}", roll, coin, x);
+        }"#).unwrap();
+
+        assert!(code.starts_with("letmutseeded_rng=<rand::rngs::StdRngasrand::SeedableRng>::seed_from_u64(seed);"));
+        assert!(code.contains("letmutrng=(&mutseeded_rng);"));
+        assert!(code.contains("letcoin:bool=rand::Rng::gen(&mutseeded_rng);"));
+        assert!(code.contains("letx=rand::Rng::gen::<u8>(&mutseeded_rng);"));
+        assert!(!code.contains("thread_rng"));
+    }
+
+    #[test]
+    fn test_bodies_without_rand_are_left_alone() {
+        assert!(rewrite("{ println!(\"{}\", random()); }").is_none());
+        assert!(rewrite("{ println!(\"Hello\"); }").is_none());
+    }
+
+    #[test]
+    fn test_seed_legacy_program() {
+        let source = "use rand::Rng;\n\nfn main() {\n    println!(\"{}\", rand::thread_rng().gen::<u32>());\n}\n";
+        let seeded = seed_legacy_program(source, 42).unwrap();
+        let compact: String = seeded.split_whitespace().collect();
+        assert!(compact.contains("letseed:u64=42u64;"));
+        assert!(compact.contains("(&mutseeded_rng).gen::<u32>()"));
+
+        let plain = "fn main() {\n    println!(\"Hello\");\n}\n";
+        assert_eq!(seed_legacy_program(plain, 42).unwrap(), plain);
+    }
+}
@@ -0,0 +1,373 @@
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Pat, Stmt, Token};
+
+use crate::atomic_counter::{atomic_decl, rewrite_atomic_ops};
+use crate::channel_pipeline::spawn_closure_body;
+use crate::print_rewriter::StdoutLines;
+use crate::syntax::{is_name, mentions};
+
+/// A legacy program where threads spawned in a loop update an `Arc<Mutex<T>>` (or an
+/// `Arc` of an atomic), which the main thread reads once it has joined them all
+pub struct SharedState {
+    /// The value the mutex starts out holding
+    pub init: Expr,
+    /// The pattern and collection of the loop that spawns the threads
+    pub pat: Pat,
+    pub items: Expr,
+    /// A thread's body, with locks replaced by `shared_state` and prints lowered onto
+    /// `stdout_lines`
+    pub update: Vec<Stmt>,
+    /// The statements after the join, with locks replaced by `shared_state`
+    pub reads: Vec<Stmt>,
+}
+
+impl SharedState {
+    /// Recognize a main body of the form
+    ///
+    /// ```ignore
+    /// let counter = Arc::new(Mutex::new(0));
+    /// let mut handles = vec![];
+    /// for i in 0..10 {
+    ///     let counter = Arc::clone(&counter);
+    ///     handles.push(thread::spawn(move || { *counter.lock().unwrap() += i; }));
+    /// }
+    /// for handle in handles { handle.join().unwrap(); }
+    /// println!("{}", *counter.lock().unwrap());
+    /// ```
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let [shared, handles, spawns, joins, reads @ ..] = stmts else {
+            return None;
+        };
+        let (name, init) = shared_decl(shared)?;
+        let handles = handles_decl(handles)?;
+        let (pat, items, aliases, update) = spawn_loop(spawns, &name, &handles)?;
+        if !is_join_loop(joins, &handles) {
+            return None;
+        }
+
+        // Locks go first, so any method left on the shared value is an atomic operation
+        let mut locks = LockRewriter { names: &aliases, rewritten: false };
+        let mut update = update;
+        for stmt in &mut update {
+            locks.visit_stmt_mut(stmt);
+        }
+        let update = rewrite_atomic_ops(&update, &aliases)?;
+        let lowered = StdoutLines::lower(&update);
+        // A partial line would have to be carried from one thread to the next
+        if lowered.has_partial_lines || aliases.iter().any(|alias| mentions(&lowered.stmts, alias)) {
+            return None;
+        }
+
+        let read_names = [name.clone()];
+        let mut reads = reads.to_vec();
+        let mut locks = LockRewriter { names: &read_names, rewritten: false };
+        for stmt in &mut reads {
+            locks.visit_stmt_mut(stmt);
+        }
+        let reads = rewrite_atomic_ops(&reads, &read_names)?;
+        if mentions(&reads, &name) || mentions(&reads, &handles) {
+            return None;
+        }
+
+        Some(Self {
+            init,
+            pat,
+            items,
+            update: lowered.stmts,
+            reads,
+        })
+    }
+}
+
+/// The name the state is bound to in place of a lock guard
+pub fn state_ident() -> syn::Ident {
+    syn::Ident::new("shared_state", Span::call_site())
+}
+
+fn binding_name(pat: &Pat) -> Option<String> {
+    match pat {
+        Pat::Ident(ident) => Some(ident.ident.to_string()),
+        Pat::Type(typed) => binding_name(&typed.pat),
+        _ => None,
+    }
+}
+
+/// A call to a path ending in `name`, like `Arc::new(..)`, returning its arguments
+fn call_to<'e>(expr: &'e Expr, owner: &str, name: &str) -> Option<&'e Punctuated<Expr, Token![,]>> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments: Vec<_> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+    match segments.as_slice() {
+        [.., ty, last] if ty == owner && last == name => Some(&call.args),
+        _ => None,
+    }
+}
+
+/// `let name = Arc::new(Mutex::new(init));` (or an `RwLock` or atomic)
+fn shared_decl(stmt: &Stmt) -> Option<(String, Expr)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let name = binding_name(&local.pat)?;
+    let shared = &*local.init.as_ref()?.expr;
+    let arc = call_to(shared, "Arc", "new")?;
+    if let Some((ty, init)) = atomic_decl(shared) {
+        return Some((name, parse_quote! { #init as #ty }));
+    }
+    let [lock] = arc.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let inner = call_to(lock, "Mutex", "new").or_else(|| call_to(lock, "RwLock", "new"))?;
+    let [init] = inner.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some((name, init.clone()))
+}
+
+/// `let mut handles = vec![];` or `Vec::new()`
+fn handles_decl(stmt: &Stmt) -> Option<String> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let empty = match &*local.init.as_ref()?.expr {
+        Expr::Macro(mac) => mac.mac.path.is_ident("vec") && mac.mac.tokens.is_empty(),
+        expr => call_to(expr, "Vec", "new").is_some(),
+    };
+    if !empty {
+        return None;
+    }
+    binding_name(&local.pat)
+}
+
+/// The loop spawning one thread per item, returning its pattern and collection, the
+/// names the state is cloned into and the body of the thread
+fn spawn_loop(stmt: &Stmt, name: &str, handles: &str) -> Option<(Pat, Expr, Vec<String>, Vec<Stmt>)> {
+    let Stmt::Expr(Expr::ForLoop(for_loop), _) = stmt else {
+        return None;
+    };
+    if for_loop.label.is_some() {
+        return None;
+    }
+
+    let mut aliases = vec![name.to_string()];
+    let mut spawned = None;
+    let mut handle = None;
+    for stmt in &for_loop.body.stmts {
+        match stmt {
+            Stmt::Local(local) if spawned.is_none() => {
+                let init = &*local.init.as_ref()?.expr;
+                if let Some(body) = spawn_closure_body(init) {
+                    spawned = Some(body);
+                    handle = Some(binding_name(&local.pat)?);
+                } else if is_clone_of(init, name) {
+                    aliases.push(binding_name(&local.pat)?);
+                } else {
+                    return None;
+                }
+            }
+            Stmt::Expr(Expr::MethodCall(push), Some(_)) if push.method == "push" && is_name(&push.receiver, handles) => {
+                let [pushed] = push.args.iter().collect::<Vec<_>>()[..] else {
+                    return None;
+                };
+                match (&handle, spawn_closure_body(pushed)) {
+                    (Some(handle), None) if is_name(pushed, handle) => {}
+                    (None, Some(body)) if spawned.is_none() => spawned = Some(body),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    let body = spawned?;
+    if mentions(&body.stmts, handles) {
+        return None;
+    }
+    Some(((*for_loop.pat).clone(), (*for_loop.expr).clone(), aliases, body.stmts))
+}
+
+/// `Arc::clone(&name)` or `name.clone()`
+fn is_clone_of(expr: &Expr, name: &str) -> bool {
+    if let Some(args) = call_to(expr, "Arc", "clone") {
+        return matches!(&args.iter().collect::<Vec<_>>()[..], [Expr::Reference(arg)] if is_name(&arg.expr, name));
+    }
+    matches!(expr, Expr::MethodCall(call) if call.method == "clone" && is_name(&call.receiver, name))
+}
+
+/// `for handle in handles { handle.join().unwrap(); }`
+fn is_join_loop(stmt: &Stmt, handles: &str) -> bool {
+    let Stmt::Expr(Expr::ForLoop(for_loop), _) = stmt else {
+        return false;
+    };
+    let Some(handle) = binding_name(&for_loop.pat) else {
+        return false;
+    };
+    if !is_name(&for_loop.expr, handles) {
+        return false;
+    }
+    match &for_loop.body.stmts[..] {
+        [Stmt::Expr(Expr::MethodCall(unwrap), _)] => {
+            matches!(&*unwrap.receiver, Expr::MethodCall(join) if join.method == "join" && is_name(&join.receiver, &handle))
+        }
+        _ => false,
+    }
+}
+
+/// Rewrites `name.lock().unwrap()` (also `read`/`write`, `expect`) into a reborrow of
+/// the state
+struct LockRewriter<'n> {
+    names: &'n [String],
+    rewritten: bool,
+}
+
+impl LockRewriter<'_> {
+    fn is_lock(&self, expr: &Expr) -> bool {
+        let Expr::MethodCall(unwrap) = expr else {
+            return false;
+        };
+        if unwrap.method != "unwrap" && unwrap.method != "expect" {
+            return false;
+        }
+        let Expr::MethodCall(lock) = &*unwrap.receiver else {
+            return false;
+        };
+        matches!(lock.method.to_string().as_str(), "lock" | "read" | "write")
+            && lock.args.is_empty()
+            && self.names.iter().any(|name| is_name(&lock.receiver, name))
+    }
+}
+
+impl VisitMut for LockRewriter<'_> {
+    fn visit_macro_mut(&mut self, mac: &mut syn::Macro) {
+        // Guards are often dereferenced right in the format arguments
+        let Ok(mut args) = mac.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) else {
+            return;
+        };
+        let rewritten = self.rewritten;
+        self.rewritten = false;
+        for arg in args.iter_mut() {
+            self.visit_expr_mut(arg);
+        }
+        if self.rewritten {
+            mac.tokens = args.to_token_stream();
+        }
+        self.rewritten |= rewritten;
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if self.is_lock(expr) {
+            let state = state_ident();
+            *expr = parse_quote! { (&mut *#state) };
+            self.rewritten = true;
+            return;
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact_stmts, parse_block};
+
+    fn detect(body: &str) -> Option<SharedState> {
+        SharedState::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_mutex_counter_is_detected() {
+        let shared = detect(r#"{
+            let counter = Arc::new(Mutex::new(0));
+            let mut handles = vec![];
+            for i in 0..10 {
+                let counter = Arc::clone(&counter);
+                let handle = thread::spawn(move || {
+                    let mut num = counter.lock().unwrap();
+                    *num += i;
+                    println!("Added {}", i);
+                });
+                handles.push(handle);
+            }
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            println!("Result: {}", *counter.lock().unwrap());
+        }"#).unwrap();
+
+        assert_eq!(shared.init.to_token_stream().to_string(), "0");
+        assert_eq!(shared.items.to_token_stream().to_string(), "0 .. 10");
+        assert_eq!(
+            compact_stmts(&shared.update),
+            "letmutnum=(&mut*shared_state);*num+=i;stdout_lines.push(format!(\"Added{}\",i));"
+        );
+        assert_eq!(compact_stmts(&shared.reads), "println!(\"Result:{}\",*(&mut*shared_state));");
+    }
+
+    #[test]
+    fn test_inline_spawn_and_rwlock() {
+        let shared = detect(r#"{
+            let log = Arc::new(RwLock::new(Vec::new()));
+            let mut workers = Vec::new();
+            for name in ["a", "b"] {
+                let log = log.clone();
+                workers.push(thread::spawn(move || log.write().expect("poisoned").push(name)));
+            }
+            for worker in workers { worker.join().unwrap(); }
+            let entries = log.read().unwrap().len();
+            println!("{}", entries);
+        }"#).unwrap();
+
+        assert_eq!(compact_stmts(&shared.update), "(&mut*shared_state).push(name);");
+        assert!(compact_stmts(&shared.reads).starts_with("letentries=(&mut*shared_state).len();"));
+    }
+
+    #[test]
+    fn test_atomic_shared_between_threads() {
+        let shared = detect(r#"{
+            let hits = Arc::new(AtomicUsize::new(0));
+            let mut handles = vec![];
+            for i in 0..4 {
+                let hits = Arc::clone(&hits);
+                handles.push(thread::spawn(move || { hits.fetch_add(i, Ordering::SeqCst); }));
+            }
+            for handle in handles { handle.join().unwrap(); }
+            println!("{}", hits.load(Ordering::SeqCst));
+        }"#).unwrap();
+
+        assert_eq!(shared.init.to_token_stream().to_string(), "0 as usize");
+        assert_eq!(compact_stmts(&shared.update), "*shared_state+=i;");
+        assert_eq!(compact_stmts(&shared.reads), "println!(\"{}\",*shared_state);");
+    }
+
+    #[test]
+    fn test_other_uses_of_the_mutex_are_rejected() {
+        // The Arc itself escapes into the reads
+        assert!(detect(r#"{
+            let counter = Arc::new(Mutex::new(0));
+            let mut handles = vec![];
+            for i in 0..3 {
+                let counter = Arc::clone(&counter);
+                handles.push(thread::spawn(move || { *counter.lock().unwrap() += i; }));
+            }
+            for handle in handles { handle.join().unwrap(); }
+            println!("{}", Arc::strong_count(&counter));
+        }"#).is_none());
+        // Without a join, the reads race with the threads
+        assert!(detect(r#"{
+            let counter = Arc::new(Mutex::new(0));
+            let mut handles = vec![];
+            for i in 0..3 {
+                let counter = Arc::clone(&counter);
+                handles.push(thread::spawn(move || { *counter.lock().unwrap() += i; }));
+            }
+            println!("{}", *counter.lock().unwrap());
+        }"#).is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashSet;
+
+use syn::{parse_quote, Expr, Item, Stmt};
+
+use crate::config_file::leaves;
+use crate::syntax::{leaves_early, mentions, unwrapped};
+
+/// A legacy program that runs a loop until it is signaled: a Ctrl-C handler installed
+/// with `ctrlc::set_handler`, or a flag registered with `signal_hook` for SIGINT or
+/// SIGTERM, flips an `AtomicBool` the loop checks. The signal becomes a control stream
+/// of the dataflow, which runs the handler's cleanup and the statements after the loop
+/// once it arrives, and stops iterating.
+pub struct ShutdownLoop {
+    /// The statements before the loop, other than the flag and its handlers
+    pub prefix: Vec<Stmt>,
+    /// The loop body, run once per iteration
+    pub body: Vec<Stmt>,
+    /// What the handler did besides flipping the flag or exiting
+    pub cleanup: Vec<Stmt>,
+    /// The statements after the loop, without a final `Ok(())`
+    pub suffix: Vec<Stmt>,
+    /// The legacy file's imports the statements use
+    pub imports: Vec<Item>,
+}
+
+impl ShutdownLoop {
+    /// Recognize `let running = Arc::new(AtomicBool::new(..));`, any clones of it, a
+    /// `ctrlc::set_handler(move || ..)` or `signal_hook::flag::register(signal, flag)`
+    /// setting it, and a `while running.load(..)` (or `while !term.load(..)`) loop. Only
+    /// the flag's clones and handlers may use it, and neither the loop body nor the
+    /// statements after it may leave early.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let index = stmts.iter().position(|stmt| matches!(stmt, Stmt::Expr(Expr::While(_), _)))?;
+        let Stmt::Expr(Expr::While(looped), _) = &stmts[index] else {
+            return None;
+        };
+        if looped.label.is_some() {
+            return None;
+        }
+
+        // The flag, and the names it goes by
+        let mut flags = HashSet::new();
+        let mut prefix = Vec::new();
+        let mut cleanup = Vec::new();
+        let mut handled = false;
+        for stmt in &stmts[..index] {
+            if let Some(flag) = flag_binding(stmt, &flags) {
+                flags.insert(flag);
+            } else if let Some(handler) = handler(stmt, &flags) {
+                cleanup.extend(handler);
+                handled = true;
+            } else {
+                prefix.push(stmt.clone());
+            }
+        }
+        if !handled || !checks_flag(&looped.cond, &flags) {
+            return None;
+        }
+
+        let body = looped.body.stmts.clone();
+        let mut suffix = stmts[index + 1..].to_vec();
+        if matches!(suffix.last(), Some(Stmt::Expr(tail, None)) if *tail == parse_quote! { Ok(()) }) {
+            suffix.pop();
+        }
+        if leaves_early(&body) || leaves_early(&suffix) || leaves_early(&cleanup) {
+            return None;
+        }
+        let all: Vec<Stmt> = prefix.iter().chain(&body).chain(&cleanup).chain(&suffix).cloned().collect();
+        if flags.iter().any(|flag| mentions(&all, flag)) {
+            return None;
+        }
+
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&all, name))))
+            .cloned()
+            .collect();
+
+        Some(Self { prefix, body, cleanup, suffix, imports })
+    }
+}
+
+/// The name bound by `let running = Arc::new(AtomicBool::new(..));`, or by a clone of a
+/// flag already known, `let r = running.clone();` or `let r = Arc::clone(&running);`
+fn flag_binding(stmt: &Stmt, flags: &HashSet<String>) -> Option<String> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let syn::Pat::Ident(name) = &local.pat else {
+        return None;
+    };
+    let init = &local.init.as_ref()?.expr;
+    let is_flag = |expr: &Expr| matches!(expr, Expr::Path(path) if path.path.get_ident().is_some_and(|ident| flags.contains(&ident.to_string())));
+    let found = match init.as_ref() {
+        Expr::Call(call) if ends_with(&call.func, "Arc", "new") => {
+            matches!(call.args.first(), Some(Expr::Call(inner)) if ends_with(&inner.func, "AtomicBool", "new"))
+        }
+        Expr::Call(call) if ends_with(&call.func, "Arc", "clone") => {
+            matches!(call.args.first(), Some(Expr::Reference(reference)) if is_flag(&reference.expr))
+        }
+        Expr::MethodCall(call) => call.method == "clone" && is_flag(&call.receiver),
+        _ => false,
+    };
+    found.then(|| name.ident.to_string())
+}
+
+/// The cleanup statements of a handler setting one of the flags: the body of a
+/// `ctrlc::set_handler` closure without the stores and exits, or nothing for a
+/// `signal_hook::flag::register`
+fn handler(stmt: &Stmt, flags: &HashSet<String>) -> Option<Vec<Stmt>> {
+    let Stmt::Expr(expr, Some(_)) = stmt else {
+        return None;
+    };
+    let Expr::Call(call) = unwrapped(expr) else {
+        return None;
+    };
+    if ends_with(&call.func, "flag", "register") {
+        let [_, flag] = &call.args.iter().collect::<Vec<_>>()[..] else {
+            return None;
+        };
+        let flag = [Stmt::Expr((*flag).clone(), None)];
+        return flags.iter().any(|name| mentions(&flag, name)).then(Vec::new);
+    }
+    if !ends_with(&call.func, "ctrlc", "set_handler") {
+        return None;
+    }
+    let [Expr::Closure(closure)] = &call.args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let stmts = match &*closure.body {
+        Expr::Block(block) => block.block.stmts.clone(),
+        body => vec![Stmt::Expr(body.clone(), Some(Default::default()))],
+    };
+    let cleanup: Vec<Stmt> = stmts
+        .into_iter()
+        .filter(|stmt| {
+            let Stmt::Expr(expr, _) = stmt else {
+                return true;
+            };
+            match expr {
+                Expr::MethodCall(call) if call.method == "store" => {
+                    !matches!(&*call.receiver, Expr::Path(path) if path.path.get_ident().is_some_and(|ident| flags.contains(&ident.to_string())))
+                }
+                Expr::Call(call) => !ends_with(&call.func, "process", "exit"),
+                _ => true,
+            }
+        })
+        .collect();
+    Some(cleanup)
+}
+
+/// Whether a loop condition is `flag.load(..)` or `!flag.load(..)` on one of the flags
+fn checks_flag(cond: &Expr, flags: &HashSet<String>) -> bool {
+    let cond = match cond {
+        Expr::Unary(unary) if matches!(unary.op, syn::UnOp::Not(_)) => &*unary.expr,
+        cond => cond,
+    };
+    matches!(cond, Expr::MethodCall(call) if call.method == "load"
+        && matches!(&*call.receiver, Expr::Path(path) if path.path.get_ident().is_some_and(|ident| flags.contains(&ident.to_string()))))
+}
+
+/// Whether `func` is a path ending in `parent::name`, or just `name` as imported
+fn ends_with(func: &Expr, parent: &str, name: &str) -> bool {
+    let Expr::Path(path) = func else {
+        return false;
+    };
+    let segments: Vec<String> = path.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+    match &segments[..] {
+        [.., first, last] => first == parent && last == name,
+        [last] => last == name,
+        [] => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact_each, parse_block};
+
+    fn detect(source: &str) -> Option<ShutdownLoop> {
+        ShutdownLoop::detect(&[parse_quote! { use std::time::Duration; }], &parse_block(source))
+    }
+
+    #[test]
+    fn test_ctrlc_handler_with_cleanup() {
+        let shutdown = detect(r#"{
+            let running = Arc::new(AtomicBool::new(true));
+            let r = running.clone();
+            ctrlc::set_handler(move || {
+                println!("Shutting down...");
+                r.store(false, Ordering::SeqCst);
+            }).expect("Error setting Ctrl-C handler");
+
+            let mut ticks = 0;
+            while running.load(Ordering::SeqCst) {
+                ticks += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            println!("Stopped after {} ticks", ticks);
+        }"#).unwrap();
+
+        assert_eq!(compact_each(&shutdown.prefix), ["letmutticks=0;"]);
+        assert_eq!(compact_each(&shutdown.body), ["ticks+=1;", "std::thread::sleep(Duration::from_millis(100));"]);
+        assert_eq!(compact_each(&shutdown.cleanup), ["println!(\"Shuttingdown...\");"]);
+        assert_eq!(compact_each(&shutdown.suffix), ["println!(\"Stoppedafter{}ticks\",ticks);"]);
+        assert_eq!(shutdown.imports.len(), 1);
+    }
+
+    #[test]
+    fn test_sigterm_flag_registered_with_signal_hook() {
+        let shutdown = detect(r#"{
+            let term = Arc::new(AtomicBool::new(false));
+            signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
+            while !term.load(Ordering::Relaxed) {
+                do_work();
+            }
+            Ok(())
+        }"#).unwrap();
+
+        assert!(shutdown.prefix.is_empty() && shutdown.cleanup.is_empty() && shutdown.suffix.is_empty());
+        assert_eq!(compact_each(&shutdown.body), ["do_work();"]);
+    }
+
+    #[test]
+    fn test_loops_that_are_not_stopped_by_a_signal() {
+        // No handler sets the flag
+        assert!(detect(r#"{
+            let running = Arc::new(AtomicBool::new(true));
+            while running.load(Ordering::SeqCst) { work(); }
+        }"#).is_none());
+        // The loop also stops itself
+        assert!(detect(r#"{
+            let running = Arc::new(AtomicBool::new(true));
+            let r = running.clone();
+            ctrlc::set_handler(move || r.store(false, Ordering::SeqCst)).unwrap();
+            while running.load(Ordering::SeqCst) { if done() { break; } }
+        }"#).is_none());
+        // The body reads the flag too
+        assert!(detect(r#"{
+            let running = Arc::new(AtomicBool::new(true));
+            let r = running.clone();
+            ctrlc::set_handler(move || r.store(false, Ordering::SeqCst)).unwrap();
+            while running.load(Ordering::SeqCst) { report(&running); }
+        }"#).is_none());
+    }
+}
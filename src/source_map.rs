@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::Stmt;
+
+use crate::syntax::span_location;
+
+/// A legacy line and the line of the generated module its statement was carried to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// 1-based line of the statement in the legacy source
+    pub legacy_line: usize,
+    /// 1-based line of the statement in the generated module
+    pub generated_line: usize,
+    /// 0-based column the statement starts at on its generated line, which it may share
+    /// with other code, as in the quoted code prettyplease leaves as it is
+    pub generated_column: usize,
+}
+
+/// Where the statements of a legacy `main` ended up in the generated module. Only the
+/// statements carried over token for token are mapped, so a statement the transformer
+/// rewrote, such as a `println!` turned into a push onto the output stream, has no line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    /// Ordered by legacy line, with at most one mapping per legacy line
+    pub mappings: Vec<Mapping>,
+}
+
+impl SourceMap {
+    /// Find the statements of `body`, nested ones included, in the generated module
+    pub fn build(body: &[Stmt], generated: &str) -> Self {
+        let Ok(tokens) = generated.parse::<TokenStream>() else {
+            return Self::default();
+        };
+        let mut generated_leaves = Vec::new();
+        leaves(tokens, &mut generated_leaves);
+        let texts: Vec<&str> = generated_leaves.iter().map(|(text, _)| text.as_str()).collect();
+
+        let mut statements = Statements::default();
+        for stmt in body {
+            statements.visit_stmt(stmt);
+        }
+
+        let mut mapped = HashSet::new();
+        let mut mappings = Vec::new();
+        for (legacy_line, stmt) in statements.found {
+            if mapped.contains(&legacy_line) {
+                continue;
+            }
+            let mut stmt_leaves = Vec::new();
+            leaves(stmt, &mut stmt_leaves);
+            let needle: Vec<&str> = stmt_leaves.iter().map(|(text, _)| text.as_str()).collect();
+            let found = texts.windows(needle.len()).position(|window| window == needle.as_slice());
+            if let Some(start) = found {
+                let generated = generated_leaves[start].1.start();
+                mapped.insert(legacy_line);
+                mappings.push(Mapping { legacy_line, generated_line: generated.line, generated_column: generated.column });
+            }
+        }
+        mappings.sort_by_key(|mapping| mapping.legacy_line);
+        Self { mappings }
+    }
+
+    /// The generated line a legacy line was carried to
+    pub fn generated_line(&self, legacy_line: usize) -> Option<usize> {
+        self.mappings.iter().find(|mapping| mapping.legacy_line == legacy_line).map(|mapping| mapping.generated_line)
+    }
+
+    /// The legacy line a generated line came from
+    pub fn legacy_line(&self, generated_line: usize) -> Option<usize> {
+        self.mappings.iter().find(|mapping| mapping.generated_line == generated_line).map(|mapping| mapping.legacy_line)
+    }
+}
+
+/// The statements of a body with the legacy line each starts on, outer statements before
+/// the ones nested in them
+#[derive(Default)]
+struct Statements {
+    found: Vec<(usize, TokenStream)>,
+}
+
+impl<'ast> Visit<'ast> for Statements {
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        if let Some((line, _)) = span_location(stmt.span()) {
+            self.found.push((line, stmt.to_token_stream()));
+        }
+        visit::visit_stmt(self, stmt);
+    }
+}
+
+/// The tokens of a stream as text, with groups flattened into their delimiters, so a
+/// statement can be found inside the macros of the generated module
+fn leaves(tokens: TokenStream, out: &mut Vec<(String, Span)>) {
+    for token in tokens {
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                if !open.is_empty() {
+                    out.push((open.to_string(), group.span_open()));
+                }
+                leaves(group.stream(), out);
+                if !close.is_empty() {
+                    out.push((close.to_string(), group.span_close()));
+                }
+            }
+            other => out.push((other.to_string(), other.span())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_carried_statements_are_mapped() {
+        let legacy = syn::parse_file("fn main() {\n    let total = 40 + 2;\n    println!(\"{}\", total);\n}\n").unwrap();
+        let syn::Item::Fn(main_fn) = &legacy.items[0] else { panic!("expected main") };
+        let generated = "pub fn module() {\n    stage(q!(|_| {\n        let total = 40 + 2;\n        stdio_writes.push((1u8, format!(\"{}\\n\", total)));\n    }));\n}\n";
+
+        let map = SourceMap::build(&main_fn.block.stmts, generated);
+        assert_eq!(map.mappings, vec![Mapping { legacy_line: 2, generated_line: 3, generated_column: 8 }]);
+        assert_eq!(map.generated_line(2), Some(3));
+        assert_eq!(map.legacy_line(3), Some(2));
+        assert_eq!(map.generated_line(3), None);
+    }
+}
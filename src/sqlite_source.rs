@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+
+use quote::ToTokens;
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Item, Pat, Stmt, UseTree};
+
+use crate::csv_source::{record_binding, Unwrap};
+use crate::json_lines::record_definitions;
+use crate::loop_control::first_loop;
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// The methods of a prepared `rusqlite` statement that iterate over its rows
+const QUERIES: [&str; 2] = ["query_map", "query_and_then"];
+
+/// A legacy program reading rows from SQLite with `rusqlite`: a connection is opened and
+/// a statement prepared, and every row `query_map` yields is handled in a `for` loop
+pub struct SqliteSource {
+    /// The statements before the loop, opening the connection and preparing the query,
+    /// with any `?` outside the row mapping turned into `.unwrap()`
+    pub setup: Vec<Stmt>,
+    /// The iterator over the results for the rows, as the loop reads it
+    pub rows: Expr,
+    /// How each row leaves the query: the result for it, bound to `row`, and how the
+    /// record is taken out of the result
+    pub row: Pat,
+    pub unwrap: Unwrap,
+    /// The binding of each record and the statements handling it
+    pub pat: Pat,
+    pub body: Vec<Stmt>,
+    /// The definitions of the types the query maps rows into, from the legacy file
+    pub definitions: Vec<Item>,
+    /// The legacy file's `rusqlite` imports, which the setup needs
+    pub imports: Vec<Item>,
+}
+
+impl SqliteSource {
+    /// Recognize a `rusqlite::Connection` opened before a loop over a prepared
+    /// statement's `query_map(..)`, either directly or through a `let rows = ..;`
+    /// before the loop. The loop may be followed by a final `Ok(())`, and nothing else.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let (index, for_loop) = first_loop(stmts)?;
+        if for_loop.label.is_some() {
+            return None;
+        }
+        match &stmts[index + 1..] {
+            [] => {}
+            [Stmt::Expr(tail, None)] if *tail == parse_quote! { Ok(()) } => {}
+            _ => return None,
+        }
+
+        // The setup only prepares the query, and runs away from the rest of the program
+        let prefix = &stmts[..index];
+        let tokens: String = prefix.iter().map(|stmt| stmt.to_token_stream().to_string()).collect();
+        if !tokens.contains("Connection :: open") || ["print !", "println !", "eprint !", "eprintln !"].iter().any(|print| tokens.contains(print)) {
+            return None;
+        }
+
+        // The loop reads the rows of a query, or a binding holding them
+        let mut rows = (*for_loop.expr).clone();
+        Tries.visit_expr_mut(&mut rows);
+        let queried = match &rows {
+            Expr::Path(path) => prefix.iter().any(|stmt| match stmt {
+                Stmt::Local(local) => {
+                    matches!(&local.pat, Pat::Ident(name) if path.path.is_ident(&name.ident))
+                        && local.init.as_ref().is_some_and(|init| is_query(&init.expr))
+                }
+                _ => false,
+            }),
+            rows => is_query(rows),
+        };
+        if !queried {
+            return None;
+        }
+
+        // The body starts by taking the record out of the result, or works on results
+        let row = (*for_loop.pat).clone();
+        let body = &for_loop.body.stmts;
+        let (pat, unwrap, body) = match body.split_first().and_then(|(first, rest)| record_binding(first, &row).map(|binding| (binding, rest))) {
+            Some(((pat, _, unwrap), rest)) => (pat, unwrap, rest.to_vec()),
+            None => (row.clone(), Unwrap::Results, body.clone()),
+        };
+
+        // The body runs as a sink, away from the connection
+        let mut bound = HashSet::new();
+        for stmt in prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut bound);
+            }
+        }
+        if leaves_early(&body) || bound.iter().any(|name| mentions(&body, name)) {
+            return None;
+        }
+
+        let setup: Vec<Stmt> = prefix.iter().map(unwrap_stmt_tries).collect();
+
+        // The types the rows are mapped into, with what they need in turn
+        let mut definitions: Vec<Item> = Vec::new();
+        let mut seen = HashSet::new();
+        let all: Vec<Stmt> = stmts.to_vec();
+        for item in items {
+            let name = match item {
+                Item::Struct(item) => &item.ident,
+                Item::Enum(item) => &item.ident,
+                _ => continue,
+            };
+            if !mentions(&all, &name.to_string()) {
+                continue;
+            }
+            for definition in record_definitions(items, &parse_quote! { #name }) {
+                if seen.insert(definition.to_token_stream().to_string()) {
+                    definitions.push(definition);
+                }
+            }
+        }
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if matches!(&item.tree, UseTree::Path(path) if path.ident == "rusqlite")))
+            .cloned()
+            .collect();
+
+        Some(Self { setup, rows, row, unwrap, pat, body, definitions, imports })
+    }
+}
+
+/// `stmt.query_map(..)`, possibly unwrapped
+fn is_query(expr: &Expr) -> bool {
+    match expr {
+        Expr::MethodCall(call) if QUERIES.iter().any(|query| call.method == query) => true,
+        Expr::MethodCall(call) if call.method == "unwrap" || call.method == "expect" => is_query(&call.receiver),
+        Expr::Try(tried) => is_query(&tried.expr),
+        _ => false,
+    }
+}
+
+/// Replaces each `?` outside closures by `.unwrap()`. Closures such as the row mapping
+/// return results of their own, and keep theirs.
+struct Tries;
+
+impl VisitMut for Tries {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if matches!(expr, Expr::Closure(_)) {
+            return;
+        }
+        visit_mut::visit_expr_mut(self, expr);
+        if let Expr::Try(tried) = expr {
+            let inner = &tried.expr;
+            *expr = parse_quote! { #inner.unwrap() };
+        }
+    }
+}
+
+/// The statement with each `?` outside closures replaced by `.unwrap()`, for code that
+/// runs where the error can't be returned from `main`
+pub(crate) fn unwrap_stmt_tries(stmt: &Stmt) -> Stmt {
+    let mut stmt = stmt.clone();
+    Tries.visit_stmt_mut(&mut stmt);
+    stmt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_main};
+
+    fn detect(source: &str) -> Option<SqliteSource> {
+        let (items, main) = parse_main(source)?;
+        SqliteSource::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_query_map_loop_is_recognized() {
+        let sqlite = detect(r#"
+            use rusqlite::{Connection, Result};
+
+            #[derive(Debug)]
+            struct Person { id: i32, name: String }
+
+            fn main() -> Result<()> {
+                let conn = Connection::open("people.db")?;
+                let mut stmt = conn.prepare("SELECT id, name FROM person")?;
+                let people = stmt.query_map([], |row| Ok(Person { id: row.get(0)?, name: row.get(1)? }))?;
+                for person in people {
+                    let person = person?;
+                    println!("{}: {}", person.id, person.name);
+                }
+                Ok(())
+            }
+        "#).unwrap();
+
+        let setup: Vec<String> = sqlite.setup.iter().map(compact).collect();
+        assert_eq!(setup[0], "letconn=Connection::open(\"people.db\").unwrap();");
+        assert_eq!(setup[1], "letmutstmt=conn.prepare(\"SELECTid,nameFROMperson\").unwrap();");
+        // The mapping closure keeps returning a result, as query_map expects
+        assert!(setup[2].contains("id:row.get(0)?"));
+        assert!(setup[2].ends_with(".unwrap();"));
+        assert_eq!(compact(&sqlite.rows), "people");
+        assert!(matches!(sqlite.unwrap, Unwrap::Stops));
+        assert_eq!(sqlite.body.len(), 1);
+        assert!(compact(&sqlite.definitions[0]).contains("structPerson"));
+        assert_eq!(sqlite.imports.len(), 1);
+    }
+
+    #[test]
+    fn test_query_in_the_loop_header() {
+        let sqlite = detect(r#"
+            fn main() {
+                let conn = rusqlite::Connection::open_in_memory().unwrap();
+                conn.execute("CREATE TABLE t (x INTEGER)", []).unwrap();
+                let mut stmt = conn.prepare("SELECT x FROM t").unwrap();
+                for x in stmt.query_map([], |row| row.get::<_, i64>(0)).unwrap() {
+                    println!("{:?}", x);
+                }
+            }
+        "#).unwrap();
+
+        assert_eq!(sqlite.setup.len(), 3);
+        assert_eq!(compact(&sqlite.rows), "stmt.query_map([],|row|row.get::<_,i64>(0)).unwrap()");
+        assert!(matches!(sqlite.unwrap, Unwrap::Results));
+        assert!(sqlite.definitions.is_empty());
+    }
+
+    #[test]
+    fn test_loops_that_are_not_query_sources() {
+        // The body keeps using the connection
+        assert!(detect("fn main() { let conn = Connection::open(\"a.db\").unwrap(); let mut stmt = conn.prepare(\"SELECT 1\").unwrap(); for x in stmt.query_map([], |row| row.get::<_, i64>(0)).unwrap() { conn.execute(\"DELETE FROM t\", []).unwrap(); } }").is_none());
+        // The setup prints
+        assert!(detect("fn main() { let conn = Connection::open(\"a.db\").unwrap(); println!(\"opened\"); let mut stmt = conn.prepare(\"SELECT 1\").unwrap(); for x in stmt.query_map([], |row| row.get::<_, i64>(0)).unwrap() { println!(\"{:?}\", x); } }").is_none());
+        // Not a query
+        assert!(detect("fn main() { let conn = Connection::open(\"a.db\").unwrap(); for x in 0..3 { println!(\"{}\", x); } }").is_none());
+    }
+}
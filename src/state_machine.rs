@@ -0,0 +1,357 @@
+use std::collections::HashSet;
+
+use quote::{quote, ToTokens};
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Item, Pat, Stmt, Type};
+
+use crate::config_file::leaves;
+use crate::json_lines::record_definitions;
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// A hand-written state machine: a loop matching on a mutable state enum and assigning
+/// the next state on every iteration. The state becomes the value carried around a
+/// cycle from one tick to the next, and each iteration becomes a transition from the
+/// state of a tick to the state of the next.
+pub struct StateMachine {
+    /// The state's binding and its enum
+    pub state: syn::Ident,
+    pub ty: Type,
+    /// The statements before the loop, with the state bound immutably, as they build
+    /// the initial state
+    pub prefix: Vec<Stmt>,
+    /// The condition of a `while` loop, checked before each transition
+    pub cond: Option<Expr>,
+    /// The loop body, with its `break`s and `continue`s returning the
+    /// `std::ops::ControlFlow` of the loop
+    pub body: Vec<Stmt>,
+    /// Whether the statements after the loop use the final state, which a `break` then
+    /// carries out of the cycle
+    pub keeps_state: bool,
+    /// The statements after the loop, without a final `Ok(())`
+    pub suffix: Vec<Stmt>,
+    /// The definition of the state enum, deriving `Clone`, and of the types it holds
+    pub definitions: Vec<Item>,
+    /// The legacy file's imports the statements use
+    pub imports: Vec<Item>,
+}
+
+impl StateMachine {
+    /// Recognize `let mut state = State::Start;` before a `loop` (or `while` loop)
+    /// whose body matches on `state` and assigns it, with `State` an enum defined in the
+    /// legacy file. The body may only use the state from the statements before it, and
+    /// may only leave the loop by `break`ing out of it.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let index = stmts.iter().position(|stmt| matches!(stmt, Stmt::Expr(Expr::Loop(_) | Expr::While(_), _)))?;
+        let (label, cond, block) = match &stmts[index] {
+            Stmt::Expr(Expr::Loop(looped), _) => (&looped.label, None, &looped.body),
+            Stmt::Expr(Expr::While(looped), _) if !matches!(*looped.cond, Expr::Let(_)) => {
+                (&looped.label, Some((*looped.cond).clone()), &looped.body)
+            }
+            _ => return None,
+        };
+        if label.is_some() {
+            return None;
+        }
+
+        // The state, the one binding before the loop the body both matches on and assigns
+        let mut prefix = stmts[..index].to_vec();
+        let (position, state, ty) = prefix.iter().enumerate().find_map(|(position, stmt)| {
+            let (state, ty) = state_binding(stmt)?;
+            let mut uses = StateUses { state: &state, matched: false, assigned: false };
+            block.stmts.iter().for_each(|stmt| uses.visit_stmt(stmt));
+            (uses.matched && uses.assigned).then_some((position, state, ty))
+        })?;
+        let Type::Path(path) = &ty else {
+            return None;
+        };
+        let name = &path.path.segments.last()?.ident;
+        if !items.iter().any(|item| matches!(item, Item::Enum(item) if item.ident == *name)) {
+            return None;
+        }
+        if let Stmt::Local(local) = &mut prefix[position] {
+            immutable(&mut local.pat);
+        }
+
+        let mut suffix = stmts[index + 1..].to_vec();
+        if matches!(suffix.last(), Some(Stmt::Expr(tail, None)) if *tail == parse_quote! { Ok(()) }) {
+            suffix.pop();
+        }
+        let keeps_state = mentions(&suffix, &state.to_string());
+        let mut transitions = Transitions {
+            state: &state,
+            last: if keeps_state { state.to_token_stream() } else { quote! { () } },
+            nested: Vec::new(),
+            unsupported: false,
+        };
+        let mut body = block.stmts.clone();
+        body.iter_mut().for_each(|stmt| transitions.visit_stmt_mut(stmt));
+        if transitions.unsupported {
+            return None;
+        }
+
+        // The transitions and what follows them only have the state at hand
+        let mut names = HashSet::new();
+        for stmt in &prefix {
+            if let Stmt::Local(local) = stmt {
+                bindings(&local.pat, &mut names);
+            }
+        }
+        names.remove(&state.to_string());
+        let cond_stmt: Vec<Stmt> = cond.iter().map(|cond| Stmt::Expr(cond.clone(), None)).collect();
+        let after: Vec<Stmt> = cond_stmt.iter().chain(&body).chain(&suffix).cloned().collect();
+        if names.iter().any(|name| mentions(&after, name)) {
+            return None;
+        }
+        let all: Vec<Stmt> = prefix.iter().chain(&after).cloned().collect();
+        let awaits = all.iter().any(|stmt| stmt.to_token_stream().to_string().contains(". await"));
+        if leaves_early(&prefix) || leaves_early(&cond_stmt) || leaves_early(&suffix) || awaits {
+            return None;
+        }
+
+        let mut definitions = record_definitions(items, &ty);
+        for definition in &mut definitions {
+            if let Item::Enum(item) = definition {
+                if item.ident == *name && !derives_clone(&item.attrs) {
+                    item.attrs.push(parse_quote! { #[derive(Clone)] });
+                }
+            }
+        }
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&all, name))))
+            .filter(|item| !definitions.contains(item))
+            .cloned()
+            .collect();
+
+        Some(Self { state, ty, prefix, cond, body, keeps_state, suffix, definitions, imports })
+    }
+}
+
+/// The name and enum of `let mut state = State::Start;`, with the enum either written
+/// out as the binding's type or the path the initial variant is named by
+fn state_binding(stmt: &Stmt) -> Option<(syn::Ident, Type)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let (pat, ty) = match &local.pat {
+        Pat::Type(typed) => (&*typed.pat, Some((*typed.ty).clone())),
+        pat => (pat, None),
+    };
+    let Pat::Ident(binding) = pat else {
+        return None;
+    };
+    let init = local.init.as_ref()?;
+    if binding.mutability.is_none() || binding.by_ref.is_some() || binding.subpat.is_some() || init.diverge.is_some() {
+        return None;
+    }
+    let ty = match ty {
+        Some(ty) => ty,
+        None => {
+            let variant = match &*init.expr {
+                Expr::Path(path) => &path.path,
+                Expr::Call(call) => match &*call.func {
+                    Expr::Path(path) => &path.path,
+                    _ => return None,
+                },
+                Expr::Struct(built) => &built.path,
+                _ => return None,
+            };
+            let count = variant.segments.len().checked_sub(1).filter(|count| *count > 0)?;
+            let segments: Punctuated<syn::PathSegment, syn::Token![::]> = variant.segments.iter().take(count).cloned().collect();
+            Type::Path(syn::TypePath { qself: None, path: syn::Path { leading_colon: variant.leading_colon, segments } })
+        }
+    };
+    Some((binding.ident.clone(), ty))
+}
+
+/// Drop the `mut` of the binding in `let mut state`, which the initial state no longer
+/// needs
+fn immutable(pat: &mut Pat) {
+    match pat {
+        Pat::Type(typed) => immutable(&mut typed.pat),
+        Pat::Ident(binding) => binding.mutability = None,
+        _ => {}
+    }
+}
+
+fn derives_clone(attrs: &[syn::Attribute]) -> bool {
+    attrs.iter().filter(|attr| attr.path().is_ident("derive")).any(|attr| {
+        attr.parse_args_with(Punctuated::<syn::Path, syn::Token![,]>::parse_terminated)
+            .is_ok_and(|derived| derived.iter().any(|path| path.segments.last().is_some_and(|last| last.ident == "Clone")))
+    })
+}
+
+/// Whether a loop body matches on the state, `match state { .. }` or
+/// `match &state { .. }`, and assigns it, outside closures
+struct StateUses<'a> {
+    state: &'a syn::Ident,
+    matched: bool,
+    assigned: bool,
+}
+
+impl StateUses<'_> {
+    fn is_state(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Reference(reference) => self.is_state(&reference.expr),
+            Expr::Paren(paren) => self.is_state(&paren.expr),
+            Expr::Path(path) => path.path.is_ident(self.state),
+            _ => false,
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for StateUses<'_> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        match expr {
+            Expr::Closure(_) => return,
+            Expr::Match(matched) if self.is_state(&matched.expr) => self.matched = true,
+            Expr::Assign(assign) if self.is_state(&assign.left) => self.assigned = true,
+            _ => {}
+        }
+        visit::visit_expr(self, expr);
+    }
+}
+
+/// Rewrites the `break`s and `continue`s of a loop body into returns of how the loop
+/// goes on, noting anything else leaving the body, which a transition can't do
+struct Transitions<'a> {
+    state: &'a syn::Ident,
+    /// What a `break` carries out of the loop
+    last: proc_macro2::TokenStream,
+    /// The labels of the loops nested in the body around the current expression
+    nested: Vec<Option<String>>,
+    unsupported: bool,
+}
+
+impl Transitions<'_> {
+    /// Whether a `break` or `continue` to `label` leaves the state machine's loop
+    fn leaves(&self, label: &Option<syn::Lifetime>) -> bool {
+        match label {
+            None => self.nested.is_empty(),
+            Some(label) => !self.nested.contains(&Some(label.ident.to_string())),
+        }
+    }
+}
+
+impl VisitMut for Transitions<'_> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        let label = match expr {
+            Expr::Closure(_) | Expr::Async(_) => return,
+            Expr::Return(_) | Expr::Try(_) | Expr::Await(_) => {
+                self.unsupported = true;
+                return;
+            }
+            Expr::Break(broken) if self.leaves(&broken.label) => {
+                if broken.label.is_some() || broken.expr.is_some() {
+                    self.unsupported = true;
+                }
+                let last = &self.last;
+                *expr = parse_quote! { return std::ops::ControlFlow::Break(#last) };
+                return;
+            }
+            Expr::Continue(continued) if self.leaves(&continued.label) => {
+                if continued.label.is_some() {
+                    self.unsupported = true;
+                }
+                let state = self.state;
+                *expr = parse_quote! { return std::ops::ControlFlow::Continue(#state) };
+                return;
+            }
+            Expr::Loop(looped) => Some(looped.label.as_ref().map(|label| label.name.ident.to_string())),
+            Expr::While(looped) => Some(looped.label.as_ref().map(|label| label.name.ident.to_string())),
+            Expr::ForLoop(looped) => Some(looped.label.as_ref().map(|label| label.name.ident.to_string())),
+            _ => None,
+        };
+        match label {
+            Some(label) => {
+                self.nested.push(label);
+                visit_mut::visit_expr_mut(self, expr);
+                self.nested.pop();
+            }
+            None => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact_each, parse_main};
+
+    fn detect(source: &str) -> Option<StateMachine> {
+        let (items, main) = parse_main(source)?;
+        StateMachine::detect(&items, &main)
+    }
+
+    #[test]
+    fn test_match_on_state_becomes_transitions() {
+        let machine = detect(r#"
+            #[derive(Debug, PartialEq)]
+            enum Light { Red, Green(u32), Yellow }
+
+            fn main() {
+                println!("starting");
+                let mut light = Light::Red;
+                loop {
+                    light = match light {
+                        Light::Red => Light::Green(3),
+                        Light::Green(0) => Light::Yellow,
+                        Light::Green(n) => Light::Green(n - 1),
+                        Light::Yellow => break,
+                    };
+                }
+                println!("stopped at {:?}", light);
+            }
+        "#).unwrap();
+
+        assert_eq!(machine.state, "light");
+        assert_eq!(machine.ty.to_token_stream().to_string(), "Light");
+        assert_eq!(compact_each(&machine.prefix), ["println!(\"starting\");", "letlight=Light::Red;"]);
+        assert!(machine.cond.is_none() && machine.keeps_state);
+        assert!(compact_each(&machine.body)[0].contains("Light::Yellow=>returnstd::ops::ControlFlow::Break(light),"));
+        // The cycle clones the state it carries
+        assert_eq!(machine.definitions.len(), 1);
+        assert!(machine.definitions[0].to_token_stream().to_string().contains("derive (Clone)"));
+    }
+
+    #[test]
+    fn test_while_loop_continues_with_the_state() {
+        let machine = detect(r#"
+            #[derive(Clone, Copy, PartialEq)]
+            enum Phase { Start, Middle, End }
+
+            fn main() {
+                let mut phase: Phase = Phase::Start;
+                while phase != Phase::End {
+                    match phase {
+                        Phase::Start => { phase = Phase::Middle; continue; }
+                        _ => phase = Phase::End,
+                    }
+                }
+            }
+        "#).unwrap();
+
+        assert!(machine.cond.is_some() && !machine.keeps_state);
+        assert!(compact_each(&machine.body)[0].contains("phase=Phase::Middle;returnstd::ops::ControlFlow::Continue(phase);"));
+        assert!(!machine.definitions[0].to_token_stream().to_string().contains("derive (Clone)"));
+    }
+
+    #[test]
+    fn test_loops_that_are_not_state_machines() {
+        // The state isn't an enum of the program
+        assert!(detect("fn main() { let mut x = Ordering::Less; loop { x = match x { _ => break } } }").is_none());
+        // The body keeps more state than the enum
+        assert!(detect(r#"
+            enum S { A, B }
+            fn main() {
+                let mut count = 0;
+                let mut s = S::A;
+                loop { count += 1; s = match s { S::A => S::B, S::B => break }; }
+            }
+        "#).is_none());
+        // The body returns from main
+        assert!(detect("enum S { A } fn main() { let mut s = S::A; loop { s = match s { S::A => return } } }").is_none());
+    }
+}
@@ -0,0 +1,135 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Expr, Pat, Stmt};
+
+use crate::call_loop::CallLoop;
+
+/// A legacy loop running an external command for each item with
+/// `std::process::Command`. The dataflow sends each command to the example, which runs
+/// it and streams its stdout lines back, and the loop body handles the command's output
+/// once it has exited, in the order of the items.
+pub struct CommandLoop {
+    /// The collection the loop iterates over, as a block that builds it
+    pub source: Expr,
+    /// Whether the loop borrowed the items, so its pattern is matched against a
+    /// reference to each
+    pub by_ref: bool,
+    pub pat: Pat,
+    /// The program the loop runs for an item, and its arguments
+    pub program: Expr,
+    pub args: Vec<Arg>,
+    /// The loop body, with the command replaced by its result, `command_output`
+    pub body: Vec<Stmt>,
+}
+
+/// An argument of a command, as passed to `.arg(..)` or `.args(..)`
+pub enum Arg {
+    One(Expr),
+    Many(Expr),
+}
+
+impl CommandLoop {
+    /// Recognize a `for` loop whose first statement makes the loop's only
+    /// `Command::new(program).arg(..).args(..).output()` call. The loop is otherwise
+    /// shaped as `CallLoop::detect` takes.
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let found = CallLoop::detect(stmts, command, "command_output")?;
+        let (program, args) = found.call;
+        Some(Self { source: found.source, by_ref: found.by_ref, pat: found.pat, program, args, body: found.body })
+    }
+}
+
+/// The program and arguments of a `Command::new(program).arg(..).args(..).output()` call
+fn command(expr: &Expr) -> Option<(Expr, Vec<Arg>)> {
+    let Expr::MethodCall(output) = expr else {
+        return None;
+    };
+    if output.method != "output" || !output.args.is_empty() {
+        return None;
+    }
+    let mut args = Vec::new();
+    let mut expr = &*output.receiver;
+    loop {
+        match expr {
+            Expr::MethodCall(call) if call.args.len() == 1 && (call.method == "arg" || call.method == "args") => {
+                let arg = call.args[0].clone();
+                args.push(if call.method == "arg" { Arg::One(arg) } else { Arg::Many(arg) });
+                expr = &call.receiver;
+            }
+            Expr::Call(new) => {
+                let Expr::Path(func) = &*new.func else {
+                    return None;
+                };
+                let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+                let [.., command, new_fn] = &segments[..] else {
+                    return None;
+                };
+                let [program] = &new.args.iter().collect::<Vec<_>>()[..] else {
+                    return None;
+                };
+                args.reverse();
+                return (command == "Command" && new_fn == "new").then(|| ((*program).clone(), args));
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// The definition of `CommandEvent`, what the example reports back about a command it
+/// runs for the dataflow
+pub fn command_event() -> TokenStream {
+    quote! {
+        /// A line the command printed, its exit status and what it printed to stderr
+        /// once it exits, or why it couldn't be run
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub enum CommandEvent {
+            Stdout(String),
+            Exited(i32, Vec<u8>),
+            Failed(String),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<CommandLoop> {
+        CommandLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_command_per_item_keeps_its_arguments() {
+        let commands = detect(r#"{
+            let files = vec!["Cargo.toml", "README.md"];
+            for file in &files {
+                let output = Command::new("wc").arg("-l").args(["--", file]).output().expect("failed to run wc");
+                print!("{}", String::from_utf8_lossy(&output.stdout));
+            }
+        }"#).unwrap();
+
+        assert!(commands.by_ref);
+        assert_eq!(compact(&commands.program), "\"wc\"");
+        let args: Vec<String> = commands
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Arg::One(arg) => format!("one {}", compact(arg)),
+                Arg::Many(args) => format!("many {}", compact(args)),
+            })
+            .collect();
+        assert_eq!(args, ["one \"-l\"", "many [\"--\",file]"]);
+        assert_eq!(compact(&commands.body[0]), "letoutput=command_output.expect(\"failedtorunwc\");");
+    }
+
+    #[test]
+    fn test_loops_that_are_not_command_pipelines() {
+        // The command is spawned and waited on by hand
+        assert!(detect("{ for f in fs { let child = Command::new(\"cat\").arg(f).spawn().unwrap(); } }").is_none());
+        // The error ends the program
+        assert!(detect("{ for f in fs { let out = Command::new(\"cat\").arg(f).output()?; println!(\"{:?}\", out); } }").is_none());
+        // The command is configured beyond its arguments
+        assert!(detect("{ for f in fs { let out = Command::new(\"ls\").current_dir(f).output(); } }").is_none());
+    }
+}
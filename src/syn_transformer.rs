@@ -1,51 +1,248 @@
 use std::fs;
 use std::path::Path;
-use syn::{parse_file, Item, ItemFn, Stmt, Expr};
-use quote::{quote, ToTokens};
+use syn::{parse_file, parse_quote, Item, ItemFn, Stmt, Expr, Pat, Type};
+use quote::{format_ident, quote, ToTokens};
 use proc_macro2::{TokenStream, Span};
+use crate::atomic_counter::{AtomicCounter, CounterUpdate};
+use crate::cli_args::CliArgs;
+use crate::config_file::ConfigFile;
+use crate::channel_pipeline::{sent_buffer, ChannelPipeline};
+use crate::collect_loop::{collected_ident, CollectLoop, Produce};
+use crate::csv_source::{CsvSource, Unwrap};
+use crate::sqlite_source::SqliteSource;
+use crate::connectors::{postgres_sink, postgres_source, PostgresClient};
+use crate::kafka_source::{kafka_message, KafkaConsumer};
+use crate::http_requests::{fetched_response, RequestLoop};
+use crate::http_service::{HttpService, Route};
+use crate::websocket::{frames, WebSocketServer};
+use crate::shutdown::ShutdownLoop;
+use crate::subprocess::{command_event, Arg, CommandLoop};
+use crate::file_watcher::FileWatcher;
+use crate::polling::PollingLoop;
+use crate::long_running::LongRunning;
+use crate::state_machine::StateMachine;
+use crate::backend::{Analysis, Capabilities, Transformer};
+use crate::config::{Distribution, Lowering, Target, TransformerConfig, UnsafeCode};
+use crate::error::TransformError;
+use crate::captures::{qualify_items, thread_captures};
+use crate::comments::carry_comments;
+use crate::formatting::{format_module, has_comments};
+use crate::instrument::instrument;
+use crate::location_tags::generic_locations;
+use crate::unsafe_code::{isolate_unsafe, refuse_unsafe};
+use crate::lifecycle;
+use crate::output::TransformOutput;
+use crate::purity::PureStage;
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::transform_rule::{LegacyProgram, TransformRule};
+use crate::data_parallel::{worker_message, ParallelLoop};
+use crate::keyed_aggregation::KeyedAggregation;
+use crate::reduce_loop::ReduceLoop;
+use crate::iterator_chain::IteratorChain;
+use crate::json_lines::record_definitions;
+use crate::nested_join::NestedJoin;
+use crate::print_rewriter::{print_through_handles, DiagnosticMacro, StdoutLines};
+use crate::shared_state::{state_ident, SharedState};
+use crate::user_types::{MethodFold, UserTypes};
+use crate::windowed::WindowedLoop;
+use crate::seeded_rng::{seed_rng_calls, SEED_ENV_VAR};
 
 /// A more robust transformer using syn for AST parsing and preservation of span information
 pub struct SynLegacyToHydroTransformer {
-    /// Whether to preserve original spans for debugging
-    preserve_spans: bool,
+    /// The options shared by all backends
+    config: TransformerConfig,
+    /// The rules registered with `with_rule`, tried before the built-in ones
+    rules: Vec<Box<dyn TransformRule>>,
 }
 
 impl SynLegacyToHydroTransformer {
     pub fn new() -> Self {
         Self {
-            preserve_spans: true,
+            config: TransformerConfig::new(),
+            rules: Vec::new(),
         }
     }
 
+    /// Use the given options, replacing any set before
+    pub fn with_config(mut self, config: TransformerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
     pub fn with_preserve_spans(mut self, preserve: bool) -> Self {
-        self.preserve_spans = preserve;
+        self.config.preserve_spans = preserve;
+        self
+    }
+
+    /// Turn println!/print! into pushes onto a stream of output lines, printed by a
+    /// single sink, instead of side effects inside the map closure. On by default
+    pub fn with_print_rewriting(mut self, rewrite: bool) -> Self {
+        self.config.rewrite_prints = rewrite;
+        self
+    }
+
+    /// Make programs using `rand` deterministic: their randomness is drawn from a
+    /// `StdRng` seeded with a `seed` parameter, which the example reads from the
+    /// `HYDRO_INGEST_SEED` environment variable
+    pub fn with_seeded_rng(mut self, seeded: bool) -> Self {
+        self.config.seeded_rng = seeded;
+        self
+    }
+
+    /// Compile a main body that is a single loop with independent iterations onto a
+    /// `Cluster<Worker>`, with a leader process handing out the items round-robin.
+    /// Other programs are still generated for a single process.
+    pub fn with_cluster_mode(mut self, cluster: bool) -> Self {
+        self.config.target = if cluster { Target::Cluster } else { Target::Process };
         self
     }
 
+    /// Register a translation of its own, such as one for an in-house I/O wrapper. The
+    /// registered rules are tried in the order they were registered, before any
+    /// built-in translation.
+    pub fn with_rule(mut self, rule: impl TransformRule + 'static) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// The first registered rule that applies to the program
+    fn registered_rule(&self, program: &LegacyProgram) -> Option<&dyn TransformRule> {
+        self.rules.iter().find(|rule| rule.applies(self, program)).map(|rule| rule.as_ref())
+    }
+
+    /// The example program running a generated module that takes no parameters and
+    /// signals completion once, for rules to pair with the module they generate
+    pub fn default_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.generate_example_program(module_name, false, &[])
+    }
+
     /// Transform a legacy Rust program file into a Hydro dataflow program
     pub fn transform_program<P: AsRef<Path>>(
         &self,
         legacy_path: P,
         module_name: &str,
-    ) -> Result<(String, String), Box<dyn std::error::Error>> {
+    ) -> Result<TransformOutput, TransformError> {
         let source = fs::read_to_string(&legacy_path)?;
-        let file = parse_file(&source)?;
+        self.transform_source(&source, module_name)
+    }
+
+    /// Transform legacy source code held in memory, as `transform_program` does the
+    /// contents of a file
+    pub fn transform_source(&self, source: &str, module_name: &str) -> Result<TransformOutput, TransformError> {
+        self.transform_file(&parse_file(source)?, module_name)
+    }
+
+    /// Transform an already parsed legacy program
+    pub fn transform_file(&self, file: &syn::File, module_name: &str) -> Result<TransformOutput, TransformError> {
+        // The program as it compiles for the target platform, when one is given
+        let resolved = self.config.target_cfg.as_ref().map(|cfg| cfg.resolve(file));
+        let file = resolved.as_ref().unwrap_or(file);
+        // The I/O operations and report are worked out once, for the check below and the
+        // output. The translation is left to `generate`, which picks it anyway.
+        let analysis = Analysis::new(file, None)?;
+        if self.config.unsafe_code == UnsafeCode::Refuse {
+            refuse_unsafe(&analysis.report)?;
+        }
+        let (hydro_function, example_program) = self.generate(file, module_name)?;
+        // The types main uses come along, whatever the body became
+        let main_fn = self.extract_main_function(file)?;
+        let hydro_function = UserTypes::of(&file.items, &main_fn.block.stmts).carry(hydro_function)?;
+        let hydro_function = thread_captures(hydro_function)?;
+        let hydro_function = qualify_items(hydro_function, module_name)?;
+        let hydro_function = if self.config.unsafe_code == UnsafeCode::Isolate { isolate_unsafe(&hydro_function, module_name)? } else { hydro_function };
+        let hydro_function = if self.config.instrument { instrument(&hydro_function, module_name)? } else { hydro_function };
+        let (hydro_function, example_program) = if self.config.generic_locations {
+            generic_locations(&hydro_function, &example_program, module_name)?
+        } else {
+            (hydro_function, example_program)
+        };
+        // A registered rule's module keeps the comments its author wrote
+        let hydro_function = if has_comments(&hydro_function) { hydro_function } else { format_module(&hydro_function)? };
+        // Last, as the passes before reformat the module and drop comments
+        let hydro_function = if self.config.preserve_spans { carry_comments(&main_fn.block, &hydro_function) } else { hydro_function };
+        Ok(TransformOutput::from_analysis(file, analysis, hydro_function, example_program))
+    }
 
+    /// The generated module and example for a legacy program
+    fn generate(&self, file: &syn::File, module_name: &str) -> Result<(String, String), TransformError> {
         // Extract the main function and its body
-        let main_fn = self.extract_main_function(&file)?;
-        let main_body = self.extract_function_body(&main_fn)?;
+        let main_fn = self.extract_main_function(file)?;
+        // Writes through stdout and stderr handles join the output like prints
+        let main_body = print_through_handles(&self.extract_function_body(&main_fn)?);
+
+        // Draw randomness from a seeded RNG when requested and the program uses any
+        let seeded_body = if self.config.seeded_rng { seed_rng_calls(&main_body) } else { None };
+        let seeded = seeded_body.is_some();
+        let main_body = seeded_body.unwrap_or(main_body);
+
+        // The rules registered on the transformer come before any built-in translation
+        let loops = LoopAnalysis::of_body(&file.items, &main_body);
+        let program = LegacyProgram { items: &file.items, body: &main_body, loops: &loops };
+        if let Some(rule) = self.registered_rule(&program) {
+            return Ok(rule.generate(self, &program, module_name)?);
+        }
+
+        // A clap command line and a config file loaded at startup are worked out by the
+        // example and handed to the body as its configuration. The other translations
+        // take no parameters, so the body runs as a whole.
+        let startup = StartupValue::detect(&file.items, &main_body);
+        if let Some((values, body)) = startup {
+            let hydro_function = self.generate_hydro_function(module_name, &file.items, &body, seeded, &values)?;
+            let example_program = match LongRunning::detect(&body) {
+                Some(service) => self.generate_long_running_example(module_name, seeded, &values, &service)?,
+                None => self.generate_example_program(module_name, seeded, &values)?,
+            };
+            return Ok((hydro_function, example_program));
+        }
+
+        // The built-in translations, each taking apart a shape of program. Their
+        // functions take no seed, so a seeded body runs as a whole.
+        if !seeded {
+            if let Some((_, translation)) = self.builtin_rule(&program) {
+                return Ok(translation(self, &program, module_name)?);
+            }
+        }
 
         // Generate the Hydro function
-        let hydro_function = self.generate_hydro_function(module_name, &main_body)?;
+        let hydro_function = self.generate_hydro_function(module_name, &file.items, &main_body, seeded, &[])?;
 
-        // Generate the example program
-        let example_program = self.generate_example_program(module_name)?;
+        // Generate the example program, run as a service if the program never finishes
+        let example_program = match LongRunning::detect(&main_body) {
+            Some(service) => self.generate_long_running_example(module_name, seeded, &[], &service)?,
+            None => self.generate_example_program(module_name, seeded, &[])?,
+        };
 
         Ok((hydro_function, example_program))
     }
 
+    /// The translation `transform_program` picks for a legacy program, checked in the same
+    /// order, or `None` when the body is wrapped whole in a single map
+    pub fn translation(&self, file: &syn::File) -> Result<Option<&'static str>, TransformError> {
+        let main_fn = self.extract_main_function(file)?;
+        let body = &print_through_handles(&self.extract_function_body(main_fn)?);
+        let loops = LoopAnalysis::of_body(&file.items, body);
+        let program = LegacyProgram { items: &file.items, body, loops: &loops };
+        if let Some(rule) = self.registered_rule(&program) {
+            return Ok(Some(rule.name()));
+        }
+        let seeded = self.config.seeded_rng && seed_rng_calls(body).is_some();
+        if seeded || StartupValue::detect(&file.items, body).is_some() {
+            return Ok(None);
+        }
+        Ok(self.builtin_rule(&program).map(|(name, _)| name))
+    }
+
+    /// The name and translation of the first built-in rule that applies to the program,
+    /// when the body is lowered fully
+    fn builtin_rule(&self, program: &LegacyProgram) -> Option<(&'static str, Translation)> {
+        if self.config.lowering < Lowering::Full {
+            return None;
+        }
+        BUILTIN_RULES.iter().find_map(|rule| Some((rule.name, (rule.detect)(self, program)?)))
+    }
+
     /// Extract the main function from the parsed file
-    pub fn extract_main_function<'a>(&self, file: &'a syn::File) -> Result<&'a ItemFn, Box<dyn std::error::Error>> {
+    pub fn extract_main_function<'a>(&self, file: &'a syn::File) -> Result<&'a ItemFn, TransformError> {
         for item in &file.items {
             if let Item::Fn(func) = item {
                 if func.sig.ident == "main" {
@@ -53,43 +250,70 @@ impl SynLegacyToHydroTransformer {
                 }
             }
         }
-        Err("No main function found in the file".into())
+        Err(TransformError::NoMainFunction)
     }
 
     /// Extract the body statements from a function, preserving spans
-    pub fn extract_function_body(&self, func: &ItemFn) -> Result<Vec<Stmt>, Box<dyn std::error::Error>> {
+    pub fn extract_function_body(&self, func: &ItemFn) -> Result<Vec<Stmt>, TransformError> {
         Ok(func.block.stmts.clone())
     }
 
-    /// Generate a Hydro dataflow function from the legacy function body
+    /// Generate a Hydro dataflow function from the legacy function body, configured by
+    /// the `startup` values the example works out. The pure statements the body starts
+    /// with run in maps of their own, ahead of the one running the statements with
+    /// effects.
     fn generate_hydro_function(
         &self,
         module_name: &str,
+        items: &[Item],
         body_stmts: &[Stmt],
+        seeded: bool,
+        startup: &[StartupValue],
     ) -> Result<String, Box<dyn std::error::Error>> {
         let func_name = syn::Ident::new(module_name, Span::call_site());
+        // The seed is captured by the quoted closure like any other local
+        let seed_param = if seeded { quote! { , seed: u64 } } else { TokenStream::new() };
+        let mut config = Configuration::new(startup);
+
+        // The configuration and the seeded RNG are only in scope for the body's own map
+        let staged = self.config.lowering >= Lowering::Stages && !seeded && startup.is_empty();
+        let pure_stage = if staged { PureStage::split(items, body_stmts) } else { None };
+        let body_stmts = match &pure_stage {
+            Some(pure_stage) => {
+                config.hoist(self, pure_stage);
+                &pure_stage.rest[..]
+            }
+            None => body_stmts,
+        };
+
+        if self.config.rewrite_prints {
+            return self.generate_output_stream_function(&func_name, body_stmts, seed_param, &config);
+        }
+        let Configuration { definitions, param, singleton, stage, cross, input } = &config;
         
         // Convert the original statements to a token stream, preserving spans
-        let body_tokens = if self.preserve_spans {
-            // Preserve original spans for debugging
-            self.preserve_statement_spans(body_stmts)
-        } else {
-            // Use call site spans
-            quote! { #(#body_stmts)* }
-        };
+        let body_tokens = self.body_tokens(body_stmts);
 
         // Generate the Hydro function wrapper
         let hydro_fn = quote! {
             use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
 
-            pub fn #func_name(process: &Process) {
-                // Wrap the original main function logic in a Hydro map operation
+            #definitions
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()> #seed_param #param) -> ExternalBincodeStream<()> {
+                #singleton
+
+                // Wrap the original main function logic in a Hydro map operation,
+                // then signal completion to the example once it has run
                 process
                     .source_iter(q!(std::iter::once(())))
-                    .map(q!(|_| {
+                    #stage
+                    #cross
+                    .map(q!(|#input| {
                         #body_tokens
                     }))
-                    .for_each(q!(|_| {}));
+                    .send_bincode_external(external)
             }
         };
 
@@ -98,223 +322,4445 @@ impl SynLegacyToHydroTransformer {
         Ok(formatted)
     }
 
-    /// Preserve original spans from statements for better debugging
-    fn preserve_statement_spans(&self, stmts: &[Stmt]) -> TokenStream {
-        let mut result = TokenStream::new();
-        for stmt in stmts {
-            // Convert each statement to tokens, preserving its original span
-            let stmt_tokens = stmt.to_token_stream();
-            result.extend(stmt_tokens);
-        }
-        result
+    /// Generate a Hydro function whose map operator yields the lines the legacy body
+    /// printed, which flow through the dataflow to a single stdout sink
+    fn generate_output_stream_function(
+        &self,
+        func_name: &syn::Ident,
+        body_stmts: &[Stmt],
+        seed_param: TokenStream,
+        config: &Configuration,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let stdout_lines = StdoutLines::lower(body_stmts);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
+        let Configuration { definitions, param, singleton, stage, cross, input } = config;
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #definitions
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()> #seed_param #param) -> ExternalBincodeStream<()> {
+                #singleton
+
+                // Run the original main function logic in a Hydro map operation,
+                // collecting what it prints to stdout as data
+                let output = process
+                    .source_iter(q!(std::iter::once(())))
+                    #stage
+                    #cross
+                    .map(q!(|#input| {
+                        #closure_body
+                    }));
+
+                // Every printed line flows to a single stdout sink
+                output
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                // Signal completion to the example once the legacy body has run
+                output
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
     }
 
-    /// Generate an example program that uses the Hydro function
-    fn generate_example_program(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    /// Generate a Hydro function in the style of `first_ten_cluster`: the leader iterates
+    /// the collection and distributes the items, each worker runs the loop body for the
+    /// items it receives, and the lines they print are sent back to the leader
+    fn generate_cluster_function(
+        &self,
+        module_name: &str,
+        parallel: &ParallelLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let func_name = syn::Ident::new(module_name, Span::call_site());
-        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+        let prefix = &parallel.prefix;
+        let pat = &parallel.pat;
+        let items = &parallel.items;
 
-        let example = quote! {
-            use hydro_deploy::Deployment;
-            use tokio::time::{timeout, Duration};
+        let stdout_lines = StdoutLines::lower(&parallel.body);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
 
-            #[tokio::main]
-            async fn main() {
-                let mut deployment = Deployment::new();
+        let (definition, distributed) = if parallel.params.is_empty() {
+            let distributed = quote! {
+                // The collection is built once, and its items are followed by how many there were
+                let messages = leader.source_iter(q!({
+                    #(#prefix)*
+                    let items: Vec<_> = (#items).into_iter().collect();
+                    let total = items.len();
+                    items.into_iter().map(Ok).chain(std::iter::once(Err(total)))
+                }));
 
-                let flow = hydro_lang::FlowBuilder::new();
-                let process = flow.process::<()>();
-                
-                // Call our generated Hydro function
-                #crate_name::#func_name::#func_name(&process);
+                // Each iteration of the original loop runs on whichever worker receives its item
+                let outputs = messages
+                    .clone()
+                    .filter_map(q!(|message| message.ok()))
+                    .round_robin_bincode(workers)
+                    .map(q!(|#pat| {
+                        #closure_body
+                    }))
+                    .send_bincode_anonymous(leader);
+            };
+            (quote! {}, distributed)
+        } else {
+            let params = &parallel.params;
+            let distributed = quote! {
+                // The parameters are computed once, on the leader, and broadcast ahead of the
+                // items on the same channel, so every member has them before its first item
+                let messages = leader.source_iter(q!({
+                    #(#prefix)*
+                    let config = (#(#params.clone(),)*);
+                    let items: Vec<_> = (#items).into_iter().collect();
+                    let total = items.len();
+                    std::iter::once(WorkerMessage::Config(config))
+                        .chain(items.into_iter().map(WorkerMessage::Item))
+                        .enumerate()
+                        .map(Ok)
+                        .chain(std::iter::once(Err(total)))
+                }));
 
-                let _nodes = flow
-                    .with_process(&process, deployment.Localhost())
-                    .deploy(&mut deployment);
+                let members = workers.members();
+                let outputs = messages
+                    .clone()
+                    .filter_map(q!(|message| message.ok()))
+                    .flat_map_ordered(q!(|(index, message)| match message {
+                        WorkerMessage::Config(config) => members
+                            .iter()
+                            .map(|member| (*member, WorkerMessage::Config(config.clone())))
+                            .collect(),
+                        item => vec![(members[index % members.len()], item)],
+                    }))
+                    .demux_bincode(workers)
+                    // Each iteration of the original loop runs on whichever worker receives its item
+                    .filter_map(q!({
+                        let mut config = None;
+                        move |message| match message {
+                            WorkerMessage::Config(params) => {
+                                config = Some(params);
+                                None
+                            }
+                            WorkerMessage::Item(#pat) => {
+                                let (#(#params,)*) = config.clone().expect("the parameters arrive before the items");
+                                Some({ #closure_body })
+                            }
+                        }
+                    }))
+                    .send_bincode_anonymous(leader);
+            };
+            (worker_message(), distributed)
+        };
 
-                println!("Starting deployment...");
-                println!("Looking for 'running command:' output...");
-                
-                // Deploy the processes first
-                deployment.deploy().await.unwrap();
-                
-                // Start the deployment with a timeout
-                let start_result = timeout(Duration::from_secs(60), async {
-                    deployment.start().await.unwrap();
-                }).await;
-                
-                match start_result {
-                    Ok(_) => {
-                        println!("✓ Deployment completed successfully");
-                    }
-                    Err(_) => {
-                        println!("✓ Deployment reached 60-second timeout");
-                        println!("If you saw output containing:");
-                        println!("  [() (process 0)] running command: `...`");
-                        println!("  [() (process 0)] <your program output>");
-                        println!("Then the deployment worked correctly!");
-                    }
-                }
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #definition
+
+            pub struct Leader {}
+            pub struct Worker {}
+
+            pub fn #func_name<'a>(
+                leader: &Process<'a, Leader>,
+                workers: &Cluster<'a, Worker>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> ExternalBincodeStream<()> {
+                #distributed
+
+                // Iterations finish in any order, so their lines may interleave differently
+                // than in the sequential program
+                outputs
+                    .clone()
+                    .flat_map_unordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                // Signal completion once every iteration has reported back, which for an
+                // empty collection is as soon as its size is known
+                messages
+                    .filter_map(q!(|message| message.err()))
+                    .map(q!(|total| Err(total)))
+                    .union(outputs.map(q!(|_| Ok(()))))
+                    .filter_map(q!({
+                        let mut total = None;
+                        let mut finished = 0;
+                        move |event| {
+                            match event {
+                                Ok(()) => finished += 1,
+                                Err(count) => total = Some(count),
+                            }
+                            (total == Some(finished)).then_some(())
+                        }
+                    }))
+                    .send_bincode_external(external)
             }
         };
 
-        // Format the generated code for better readability
-        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
         Ok(formatted)
     }
 
-    /// Extract and analyze function calls from the body for more sophisticated transformations
-    pub fn analyze_function_calls(&self, stmts: &[Stmt]) -> Vec<FunctionCallInfo> {
-        let mut calls = Vec::new();
-        
-        for stmt in stmts {
-            self.extract_calls_from_stmt(stmt, &mut calls);
-        }
-        
-        calls
+    /// Generate a Hydro function aggregating groups by key across a cluster: the leader
+    /// keys the items and sends each to the member its key hashes to, each member folds
+    /// the items of the keys it owns into its groups, and the leader merges the groups
+    /// of all the members, which share no key, before running the code after the loop
+    fn generate_keyed_cluster_function(
+        &self,
+        module_name: &str,
+        keyed: &KeyedAggregation,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let KeyedAggregation { prefix, map, map_type, pat, items, lets, key, insert, insert_args, op, value, .. } = keyed;
+
+        let stdout_lines = StdoutLines::lower(&keyed.rest);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
+        let rebind = if keyed.rest_mutates { quote! { let mut #map = #map; } } else { quote! {} };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub struct Leader {}
+            pub struct Worker {}
+
+            pub fn #func_name<'a>(
+                leader: &Process<'a, Leader>,
+                workers: &Cluster<'a, Worker>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> ExternalBincodeStream<()> {
+                // Each key belongs to the member its hash picks, and every member is told
+                // when there are no more items, on the same channel as its items
+                let members = workers.members();
+                let groups = leader
+                    .source_iter(q!({
+                        #(#prefix)*
+                        let keyed: Vec<_> = (#items)
+                            .into_iter()
+                            .map(|#pat| {
+                                #(#lets)*
+                                ((#key).to_owned(), #value)
+                            })
+                            .collect();
+                        keyed.into_iter().map(Some).chain(std::iter::once(None))
+                    }))
+                    .flat_map_ordered(q!(|item| match item {
+                        Some((key, value)) => {
+                            use std::hash::{Hash, Hasher};
+                            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                            key.hash(&mut hasher);
+                            vec![(members[hasher.finish() as usize % members.len()], Some((key, value)))]
+                        }
+                        None => members.iter().map(|member| (*member, None)).collect(),
+                    }))
+                    .demux_bincode(workers)
+                    // Each member folds the items of its keys, and hands back its groups
+                    // once it has them all
+                    .filter_map(q!({
+                        let mut groups = std::collections::#map_type::new();
+                        move |item| match item {
+                            Some((key, value)) => {
+                                *groups.entry(key).#insert(#(#insert_args),*) #op value;
+                                None
+                            }
+                            None => Some(std::mem::take(&mut groups)),
+                        }
+                    }))
+                    .send_bincode_anonymous(leader);
+
+                // No two members share a key, so their groups merge without combining any
+                let reporting = workers.members();
+                let outputs = groups
+                    .filter_map(q!({
+                        let mut merged = std::collections::#map_type::new();
+                        let mut reported = 0;
+                        move |groups| {
+                            merged.extend(groups);
+                            reported += 1;
+                            (reported == reporting.len()).then(|| std::mem::take(&mut merged))
+                        }
+                    }))
+                    .map(q!(|#map| {
+                        #rebind
+                        #closure_body
+                    }));
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                // Signal completion once the code after the loop has run
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
     }
 
-    fn extract_calls_from_stmt(&self, stmt: &Stmt, calls: &mut Vec<FunctionCallInfo>) {
-        match stmt {
-            Stmt::Expr(expr, _) => {
-                self.extract_calls_from_expr(expr, calls);
+    /// Generate a Hydro function reducing a loop's items across a cluster, map-reduce
+    /// style: the leader hands the items out round-robin, each member folds the items it
+    /// gets into a partial, and the leader combines the partials of all the members into
+    /// the starting value before running the code after the loop
+    fn generate_reduce_cluster_function(
+        &self,
+        module_name: &str,
+        reduce: &ReduceLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let ReduceLoop { prefix, acc, init, pat, items, lets, op, value, .. } = reduce;
+
+        let stdout_lines = StdoutLines::lower(&reduce.rest);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
+        let rebind = if reduce.rest_mutates { quote! { let mut #acc = #acc; } } else { quote! {} };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub struct Leader {}
+            pub struct Worker {}
+
+            pub fn #func_name<'a>(
+                leader: &Process<'a, Leader>,
+                workers: &Cluster<'a, Worker>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> ExternalBincodeStream<()> {
+                // The items go round-robin, and every member is told when there are no
+                // more, on the same channel as its items
+                let members = workers.members();
+                let partials = leader
+                    .source_iter(q!({
+                        #(#prefix)*
+                        (#items).into_iter().map(Some).chain(std::iter::once(None)).enumerate()
+                    }))
+                    .flat_map_ordered(q!(|(index, item)| match item {
+                        Some(item) => vec![(members[index % members.len()], Some(item))],
+                        None => members.iter().map(|member| (*member, None)).collect(),
+                    }))
+                    .demux_bincode(workers)
+                    // Each member folds its share of the items, and hands back its partial
+                    // once it has them all, or nothing if it got none
+                    .filter_map(q!({
+                        let mut partial = None;
+                        move |item| match item {
+                            Some(#pat) => {
+                                #(#lets)*
+                                let value = #value;
+                                partial = Some(match partial.take() {
+                                    None => value,
+                                    Some(mut partial) => {
+                                        partial #op value;
+                                        partial
+                                    }
+                                });
+                                None
+                            }
+                            None => Some(partial.take()),
+                        }
+                    }))
+                    .send_bincode_anonymous(leader);
+
+                // The partials combine in whatever order they arrive
+                let reporting = workers.members();
+                let outputs = partials
+                    .filter_map(q!({
+                        let mut combined = Some(#init);
+                        let mut reported = 0;
+                        move |partial| {
+                            if let (Some(combined), Some(partial)) = (combined.as_mut(), partial) {
+                                *combined #op partial;
+                            }
+                            reported += 1;
+                            if reported == reporting.len() { combined.take() } else { None }
+                        }
+                    }))
+                    .map(q!(|#acc| {
+                        #rebind
+                        #closure_body
+                    }));
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                // Signal completion once the code after the loop has run
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Stmt::Local(local) => {
-                if let Some(init) = &local.init {
-                    self.extract_calls_from_expr(&init.expr, calls);
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a two-process Hydro function in the style of `first_ten_distributed`: the
+    /// producer's sends become a stream sent to the consumer process, which runs the
+    /// receive loop's body for each value
+    fn generate_pipeline_function(
+        &self,
+        module_name: &str,
+        pipeline: &ChannelPipeline,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let sent = sent_buffer();
+        let producer_body = self.body_tokens(&pipeline.producer);
+        let pat = &pipeline.pat;
+
+        let stdout_lines = StdoutLines::lower(&pipeline.consumer);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub struct Producer {}
+            pub struct Consumer {}
+
+            pub fn #func_name<'a>(
+                producer: &Process<'a, Producer>,
+                consumer: &Process<'a, Consumer>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> ExternalBincodeStream<()> {
+                // The producer thread's sends, followed by `None` where it hung up
+                let outputs = producer
+                    .source_iter(q!({
+                        let mut #sent = Vec::new();
+                        #producer_body
+                        #sent.into_iter().map(Some).chain(std::iter::once(None))
+                    }))
+                    .send_bincode(consumer)
+                    .map(q!(|received| match received {
+                        Some(#pat) => {
+                            let lines = { #closure_body };
+                            (lines, false)
+                        }
+                        None => (Vec::new(), true),
+                    }));
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|(lines, _)| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                // The receive loop ends once the channel is drained
+                outputs
+                    .filter_map(q!(|(_, finished)| if finished { Some(()) } else { None }))
+                    .send_bincode_external(external)
             }
-            Stmt::Item(_) => {
-                // Handle item statements (not common in main function body)
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function that folds the updates the threads made under the lock
+    /// into state held by the one process, then runs the reads against the final state
+    fn generate_shared_state_function(
+        &self,
+        module_name: &str,
+        shared: &SharedState,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let state = state_ident();
+        let init = &shared.init;
+        let pat = &shared.pat;
+        let items = &shared.items;
+        let update = self.body_tokens(&shared.update);
+
+        let reads = StdoutLines::lower(&shared.reads);
+        let reads_body = reads.closure_body(self.body_tokens(&reads.stmts));
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // Every thread's update is applied in turn to the state, which only this
+                // process owns, so no lock is needed. The lines the threads print are
+                // kept in the order their updates were applied.
+                let outputs = process
+                    .source_iter(q!(#items))
+                    .fold(
+                        q!(|| (#init, Vec::<String>::new())),
+                        q!(|(#state, stdout_lines), #pat| {
+                            #update
+                        }),
+                    )
+                    .map(q!(|(mut final_state, mut stdout_lines)| {
+                        // Reads after the threads were joined see the final state
+                        let #state = &mut final_state;
+                        stdout_lines.extend({ #reads_body });
+                        stdout_lines
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Stmt::Macro(stmt_macro) => {
-                // Handle macro statements like println! directly at statement level
-                if let Some(ident) = stmt_macro.mac.path.get_ident() {
-                    calls.push(FunctionCallInfo {
-                        name: format!("{}!", ident),
-                        span: ident.span(),
-                        args_count: 1,
-                    });
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function that folds the loop's items into the value of a
+    /// user-defined type with its updating methods, then runs the reads of the value
+    fn generate_method_fold_function(
+        &self,
+        module_name: &str,
+        fold: &MethodFold,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let MethodFold { prefix, acc, init, pat, items, step, .. } = fold;
+
+        let stdout_lines = StdoutLines::lower(&fold.rest);
+        let closure_body = stdout_lines.closure_body(self.body_tokens(&stdout_lines.stmts));
+        let rebind = if fold.rest_mutates { quote! { let mut #acc = #acc; } } else { quote! {} };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // Each item is handed to the value's methods in turn, as the loop did
+                let outputs = process
+                    .source_iter(q!({
+                        #(#prefix)*
+                        #items
+                    }))
+                    .fold(
+                        q!(|| #init),
+                        q!(|#acc, #pat| {
+                            #(#step)*
+                        }),
+                    )
+                    .map(q!(|#acc| {
+                        #rebind
+                        #closure_body
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-        }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
     }
 
-    fn extract_calls_from_expr(&self, expr: &Expr, calls: &mut Vec<FunctionCallInfo>) {
-        match expr {
-            Expr::Call(call) => {
-                if let Expr::Path(path) = &*call.func {
-                    if let Some(ident) = path.path.get_ident() {
-                        calls.push(FunctionCallInfo {
-                            name: ident.to_string(),
-                            span: ident.span(),
-                            args_count: call.args.len(),
-                        });
-                    }
-                }
-                // Recursively check arguments
-                for arg in &call.args {
-                    self.extract_calls_from_expr(arg, calls);
-                }
+    /// Generate a Hydro function that counts the loop's items (those passing the loop's
+    /// condition, if any) and runs the reads of the atomic against the count
+    fn generate_count_function(
+        &self,
+        module_name: &str,
+        counter: &AtomicCounter,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let state = state_ident();
+        let (ty, init) = (&counter.ty, &counter.init);
+        let items = &counter.items;
+        let pat = &counter.pat;
+        let filter = match &counter.update {
+            CounterUpdate::Count { filter: Some(cond) } => quote! {
+                .filter_map(q!(|#pat| (#cond).then_some(())))
+            },
+            _ => TokenStream::new(),
+        };
+
+        let reads = StdoutLines::lower(&counter.reads);
+        let reads_body = reads.closure_body(self.body_tokens(&reads.stmts));
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // The atomic only ever counted items, which the dataflow counts directly
+                let outputs = process
+                    .source_iter(q!(#items))
+                    #filter
+                    .count()
+                    .map(q!(|counted| {
+                        let mut final_count = #init as #ty + counted as #ty;
+                        let #state = &mut final_count;
+                        #reads_body
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Expr::Macro(macro_expr) => {
-                // Handle macro calls like println!, format!, etc.
-                if let Some(ident) = macro_expr.mac.path.get_ident() {
-                    calls.push(FunctionCallInfo {
-                        name: format!("{}!", ident), // Add ! to indicate it's a macro
-                        span: ident.span(),
-                        args_count: 1, // Macros don't have a predictable arg count
-                    });
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function whose operators are the legacy iterator chain's adaptors
+    fn generate_iterator_chain_function(
+        &self,
+        module_name: &str,
+        chain: &IteratorChain,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let source = &chain.source;
+        let operators = chain.operators.iter().map(|(operator, closure)| {
+            quote! { .#operator(q!(#closure)) }
+        });
+        let sink = &chain.sink;
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let items = process
+                    .source_iter(q!(#source))
+                    #(#operators)*;
+
+                items.clone().for_each(q!(#sink));
+
+                // Signal completion once every item has reached the sink
+                items
+                    .count()
+                    .into_stream()
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Expr::MethodCall(method_call) => {
-                calls.push(FunctionCallInfo {
-                    name: method_call.method.to_string(),
-                    span: method_call.method.span(),
-                    args_count: method_call.args.len() + 1, // +1 for receiver
-                });
-                // Recursively check receiver and arguments
-                self.extract_calls_from_expr(&method_call.receiver, calls);
-                for arg in &method_call.args {
-                    self.extract_calls_from_expr(arg, calls);
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function streaming the rows of a CSV reader into the loop body.
+    /// A header row the body reads is taken from the same reader, ahead of the records,
+    /// and joins them as a singleton. The record type's definition is carried along
+    /// from the legacy file, as `definitions`.
+    fn generate_csv_function(
+        &self,
+        module_name: &str,
+        csv: &CsvSource,
+        definitions: &[Item],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let CsvSource { open, reader, row, pat, .. } = csv;
+        let rows_method = csv.rows_method();
+        let turbofish = match &csv.record {
+            Some(record) => quote! { ::<#record> },
+            None => TokenStream::new(),
+        };
+        let unwrap = match &csv.unwrap {
+            Unwrap::Panics(unwrap) => quote! { .map(|#row| #unwrap) },
+            // `?` ended the program at the first bad row
+            Unwrap::Stops => quote! { .map_while(|#row| #row.ok()) },
+            Unwrap::Results => TokenStream::new(),
+        };
+        let body = self.body_tokens(&csv.body);
+
+        let records = match &csv.headers {
+            None => quote! {
+                let records = process.source_iter(q!(#open.#rows_method #turbofish() #unwrap));
+                records.clone().for_each(q!(|#pat| {
+                    #body
+                }));
+            },
+            Some((headers, read)) => quote! {
+                // The header row comes first, then the records
+                let rows = process.source_iter(q!({
+                    let mut #reader = #open;
+                    let header_row = #read;
+                    std::iter::once(Err(header_row)).chain(#reader.#rows_method #turbofish() #unwrap.map(Ok))
+                }));
+                let headers = rows
+                    .clone()
+                    .filter_map(q!(|row| row.err()))
+                    .fold(q!(|| csv::StringRecord::new()), q!(|headers, row| *headers = row));
+                let records = rows.filter_map(q!(|row| row.ok()));
+
+                records.clone().cross_singleton(headers).for_each(q!(|(#pat, #headers)| {
+                    #body
+                }));
+            },
+        };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #(#definitions)*
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                #records
+
+                // Signal completion once every record has been handled
+                records
+                    .count()
+                    .into_stream()
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Expr::Block(block) => {
-                for stmt in &block.block.stmts {
-                    self.extract_calls_from_stmt(stmt, calls);
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function sending the request for each item from an async
+    /// stage, which reads the response before handing it on with the item. Responses
+    /// come back in the order of the items, and the loop body handles each result, error
+    /// or not, as it did.
+    fn generate_request_function(
+        &self,
+        module_name: &str,
+        requests: &RequestLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let RequestLoop { source, pat, url, .. } = requests;
+        // The request only borrows the item, which goes on with the response
+        let (request_item, item) = if requests.by_ref {
+            (quote! { &item }, quote! { &item })
+        } else {
+            (quote! { item.clone() }, quote! { item })
+        };
+        let body = self.body_tokens(&requests.body);
+        let definition = fetched_response();
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #definition
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let responses = process
+                    .source_iter(q!(#source))
+                    .map(q!(|item| async move {
+                        let fetched = {
+                            let #pat = #request_item;
+                            match reqwest::get(#url).await {
+                                Ok(response) => FetchedResponse::read(response).await,
+                                Err(error) => Err(error),
+                            }
+                        };
+                        (item, fetched)
+                    }))
+                    .resolve_futures_ordered();
+
+                responses.clone().for_each(q!(|(item, fetched)| {
+                    let #pat = #item;
+                    #body
+                }));
+
+                // Signal completion once every response has been handled
+                responses
+                    .count()
+                    .into_stream()
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
             }
-            Expr::If(if_expr) => {
-                self.extract_calls_from_expr(&if_expr.cond, calls);
-                for stmt in &if_expr.then_branch.stmts {
-                    self.extract_calls_from_stmt(stmt, calls);
-                }
-                if let Some((_, else_branch)) = &if_expr.else_branch {
-                    self.extract_calls_from_expr(else_branch, calls);
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function running the body of a polling loop on every tick of an
+    /// interval at the loop's period. The state set up before the loop is owned by the
+    /// stage, which keeps it between ticks as the loop kept it between iterations.
+    fn generate_polling_function(
+        &self,
+        module_name: &str,
+        polling: &PollingLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let PollingLoop { prefix, period, imports, .. } = polling;
+        let body = self.body_tokens(&polling.body);
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            #(#imports)*
+
+            pub fn #func_name<'a>(process: &Process<'a>) {
+                // One tick per period, where the legacy loop slept between iterations
+                process
+                    .source_interval(q!(#period))
+                    .for_each(q!({
+                        #(#prefix)*
+                        move |_| {
+                            #body
+                        }
+                    }));
             }
-            Expr::While(while_expr) => {
-                self.extract_calls_from_expr(&while_expr.cond, calls);
-                for stmt in &while_expr.body.stmts {
-                    self.extract_calls_from_stmt(stmt, calls);
-                }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function stepping a state machine once per tick. The state is
+    /// carried to the next tick around a cycle, starting from the initial state built on
+    /// the first tick; each transition either continues with the next state or breaks
+    /// out of the cycle, running the statements after the loop and signaling completion.
+    fn generate_state_machine_function(
+        &self,
+        module_name: &str,
+        machine: &StateMachine,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let StateMachine { state, ty, prefix, definitions, imports, .. } = machine;
+        let body = self.body_tokens(&machine.body);
+        let suffix = self.body_tokens(&machine.suffix);
+        let last = if machine.keeps_state { quote! { #state } } else { quote! { () } };
+        let check = machine.cond.as_ref().map(|cond| quote! {
+            if !(#cond) {
+                return std::ops::ControlFlow::Break(#last);
             }
-            Expr::ForLoop(for_loop) => {
-                self.extract_calls_from_expr(&for_loop.expr, calls);
-                for stmt in &for_loop.body.stmts {
-                    self.extract_calls_from_stmt(stmt, calls);
-                }
+        });
+        let finished = if machine.keeps_state { quote! { #state } } else { quote! { _ } };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+            #(#imports)*
+
+            #(#definitions)*
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                let tick = process.tick();
+
+                // The state of each tick, fed back from the transition of the tick before,
+                // or built on the first tick by the statements before the loop
+                let (next_state, #state) = tick.cycle::<Optional<#ty, _, _>>();
+                let #state = #state.or(tick.optional_first_tick(q!({
+                    #(#prefix)*
+                    #state
+                })));
+
+                // One iteration of the legacy loop per tick
+                let step = #state.map(q!(|mut #state| {
+                    #check
+                    #body
+                    std::ops::ControlFlow::Continue(#state)
+                }));
+                next_state.complete_next_tick(step.clone().filter_map(q!(|step| step.continue_value())));
+
+                step.filter_map(q!(|step| step.break_value()))
+                    .all_ticks()
+                    .map(q!(|#finished| {
+                        #suffix
+                    }))
+                    .send_bincode_external(external)
             }
-            // Add more expression types as needed
-            _ => {}
-        }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function sending the command each item runs to the example, and
+    /// handling what the example streams back about it. The stdout lines of a command
+    /// are gathered until it exits, when the loop body gets its `std::process::Output`
+    /// along with the item it was run for; the example runs the commands one at a time,
+    /// so they exit in the order of the items.
+    fn generate_command_function(
+        &self,
+        module_name: &str,
+        commands: &CommandLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let CommandLoop { source, pat, program, .. } = commands;
+        let args = commands.args.iter().map(|arg| match arg {
+            Arg::One(arg) => quote! { args.push(std::ffi::OsStr::new(&#arg).to_string_lossy().into_owned()); },
+            Arg::Many(many) => quote! {
+                args.extend((#many).into_iter().map(|arg| std::ffi::OsStr::new(&arg).to_string_lossy().into_owned()));
+            },
+        });
+        let item = if commands.by_ref { quote! { &item } } else { quote! { item } };
+        let body = self.body_tokens(&commands.body);
+        let definition = command_event();
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
+
+            #definition
+
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> (ExternalBincodeStream<(String, Vec<String>)>, ExternalBincodeSink<CommandEvent>, ExternalBincodeStream<()>) {
+                // The items, followed by a marker once there are no more
+                let items = process.source_iter(q!(#source.into_iter().map(Some).chain(std::iter::once(None))));
+
+                let commands_port = items
+                    .clone()
+                    .filter_map(q!(|item| item.map(|item| {
+                        let #pat = &item;
+                        let mut args: Vec<String> = Vec::new();
+                        #(#args)*
+                        (std::ffi::OsStr::new(&#program).to_string_lossy().into_owned(), args)
+                    })))
+                    .send_bincode_external(external);
+                let (events_port, events) = process.source_external_bincode(external);
+
+                // Each command's output goes to the item waiting on it, and completion is
+                // signaled once every item has been handled
+                let done = items
+                    .map(q!(|item| Ok(item)))
+                    .union(events.map(q!(|event: CommandEvent| Err(event))))
+                    .filter_map(q!({
+                        let mut waiting = std::collections::VecDeque::new();
+                        let mut listed = false;
+                        let mut stdout = Vec::new();
+                        move |event| {
+                            let command_output = match event {
+                                Ok(Some(item)) => {
+                                    waiting.push_back(item);
+                                    None
+                                }
+                                Ok(None) => {
+                                    listed = true;
+                                    None
+                                }
+                                Err(CommandEvent::Stdout(line)) => {
+                                    stdout.extend_from_slice(line.as_bytes());
+                                    stdout.push(b'\n');
+                                    None
+                                }
+                                Err(CommandEvent::Exited(status, stderr)) => Some(Ok(std::process::Output {
+                                    status: std::os::unix::process::ExitStatusExt::from_raw(status),
+                                    stdout: std::mem::take(&mut stdout),
+                                    stderr,
+                                })),
+                                Err(CommandEvent::Failed(error)) => {
+                                    stdout.clear();
+                                    Some(Err(std::io::Error::other(error)))
+                                }
+                            };
+                            if let Some(command_output) = command_output {
+                                let item = waiting.pop_front().unwrap();
+                                let #pat = #item;
+                                #body
+                            }
+                            (listed && waiting.is_empty()).then_some(())
+                        }
+                    }));
+
+                (commands_port, events_port, done.send_bincode_external(external))
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function running the legacy loop until the example signals
+    /// shutdown on an external control stream. The iterations and the signal meet in a
+    /// single operator owning the loop's state, which runs the handler's cleanup and the
+    /// statements after the loop when the signal arrives, ignores any iteration after it,
+    /// and signals completion.
+    fn generate_shutdown_function(
+        &self,
+        module_name: &str,
+        shutdown: &ShutdownLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let ShutdownLoop { prefix, imports, .. } = shutdown;
+        let body = self.body_tokens(&shutdown.body);
+        let cleanup = self.body_tokens(&shutdown.cleanup);
+        let suffix = self.body_tokens(&shutdown.suffix);
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
+            #(#imports)*
+
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> (ExternalBincodeSink<()>, ExternalBincodeStream<()>) {
+                // The example signals shutdown here, in place of the legacy signal handler
+                let (shutdown_port, shutdown) = process.source_external_bincode(external);
+
+                let finished = process
+                    .source_stream(q!(futures::stream::repeat(())))
+                    .map(q!(|_| false))
+                    .union(shutdown.map(q!(|_: ()| true)))
+                    .filter_map(q!({
+                        #(#prefix)*
+                        let mut stopped = false;
+                        move |signaled: bool| {
+                            if stopped {
+                                None
+                            } else if signaled {
+                                stopped = true;
+                                #cleanup
+                                #suffix
+                                Some(())
+                            } else {
+                                #body
+                                None
+                            }
+                        }
+                    }));
+
+                (shutdown_port, finished.send_bincode_external(external))
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function answering HTTP requests with the legacy handlers. Each
+    /// request arrives on an external stream as its id, the index of its route and its
+    /// path parameter, and leaves as its id with a status and a body.
+    fn generate_service_function(
+        &self,
+        module_name: &str,
+        service: &HttpService,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let HttpService { functions, imports, .. } = service;
+        let arms = service.routes.iter().enumerate().map(|(index, route)| {
+            let index = proc_macro2::Literal::usize_unsuffixed(index);
+            let handler = &route.handler;
+            match &route.param {
+                None => quote! { #index => (200, #handler().to_string()), },
+                Some((extractor, param)) => quote! {
+                    #index => match param.parse::<#param>() {
+                        Ok(param) => (200, #handler(#extractor(param)).to_string()),
+                        Err(_) => (400, "Invalid path parameter".to_string()),
+                    },
+                },
+            }
+        });
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::{ExternalBincodeSink, ExternalBincodeStream};
+            #(#imports)*
+
+            #(#functions)*
+
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> (ExternalBincodeSink<(u64, u32, String)>, ExternalBincodeStream<(u64, (u16, String))>) {
+                let (requests_port, requests) = process.source_external_bincode(external);
+
+                let responses = requests.map(q!(|(id, route, param): (u64, u32, String)| {
+                    let response = match route {
+                        #(#arms)*
+                        _ => (404, String::new()),
+                    };
+                    (id, response)
+                }));
+
+                (requests_port, responses.send_bincode_external(external))
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function handling Kafka messages as they arrive on an external
+    /// bytes port, fed by the bridge in [`Self::generate_kafka_example`]. The messages
+    /// keep the payload accessors the loop body calls.
+    fn generate_kafka_function(
+        &self,
+        module_name: &str,
+        kafka: &KafkaConsumer,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let message = &kafka.message;
+        let body = self.body_tokens(&kafka.body);
+        let definition = kafka_message();
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBytesPort;
+
+            #definition
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBytesPort {
+                // The payloads of the Kafka messages, as the bridge forwards them
+                let (port, payloads) = process.source_external_bytes(external);
+
+                payloads
+                    .map(q!(|payload| KafkaMessage { payload }))
+                    .for_each(q!(|#message| {
+                        #body
+                    }));
+
+                port
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function handling the messages of a WebSocket server, fed by the
+    /// bridge in [`Self::generate_websocket_example`]. Each message arrives on an external
+    /// bytes port framed with its connection, runs through the legacy loop body, and the
+    /// replies it sends leave on a second port, framed for the same connection.
+    fn generate_websocket_function(
+        &self,
+        module_name: &str,
+        websocket: &WebSocketServer,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let WebSocketServer { tungstenite, pat, imports, .. } = websocket;
+        let body = self.body_tokens(&websocket.body);
+        let definitions = frames();
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBytesPort;
+            #(#imports)*
+
+            #definitions
+
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> (ExternalBytesPort, ExternalBytesPort) {
+                // The messages of every connection, as the bridge forwards them
+                let (messages_port, frames) = process.source_external_bytes(external);
+
+                let replies = frames
+                    .map(q!(|frame| {
+                        let (connection, text, data) = decode_frame(&frame);
+                        let message = if text {
+                            #tungstenite::Message::text(String::from_utf8_lossy(&data).into_owned())
+                        } else {
+                            #tungstenite::Message::binary(data)
+                        };
+                        (connection, message)
+                    }))
+                    .flat_map_ordered(q!(|(connection, #pat)| {
+                        let mut replies = Vec::new();
+                        #body
+                        replies.into_iter().map(move |reply| (connection, reply))
+                    }));
+
+                // Only text and binary replies are data, the bridge handles the rest
+                let replies_port = replies
+                    .filter_map(q!(|(connection, reply): (u64, #tungstenite::Message)| {
+                        let text = reply.is_text();
+                        (text || reply.is_binary()).then(|| encode_frame(connection, text, &reply.into_data()))
+                    }))
+                    .send_bytes_external(external);
+
+                (messages_port, replies_port)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function handling filesystem events as they arrive on an
+    /// external port, fed by the watcher in [`Self::generate_watcher_example`]. Errors
+    /// arrive as their messages and become `notify` errors again, so the loop body gets
+    /// the results it got from the channel.
+    fn generate_watcher_function(
+        &self,
+        module_name: &str,
+        watcher: &FileWatcher,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let FileWatcher { pat, imports, .. } = watcher;
+        let body = self.body_tokens(&watcher.body);
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeSink;
+            #(#imports)*
+
+            pub fn #func_name<'a>(
+                process: &Process<'a>,
+                external: &ExternalProcess<'a, ()>,
+            ) -> ExternalBincodeSink<std::result::Result<notify::Event, String>> {
+                // The watcher's events, as the example forwards them
+                let (events_port, events) = process.source_external_bincode(external);
+
+                events
+                    .map(q!(|event: std::result::Result<notify::Event, String>| {
+                        event.map_err(|error| notify::Error::generic(&error))
+                    }))
+                    .for_each(q!(|#pat| {
+                        #body
+                    }));
+
+                events_port
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function streaming the rows of a Postgres query into the loop
+    /// body through the connector in [`crate::connectors`]. The inserts of each row are
+    /// handed to a second connection, which writes them in batches, and completion waits
+    /// for the last of them to be written.
+    fn generate_postgres_function(
+        &self,
+        module_name: &str,
+        postgres: &PostgresClient,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let PostgresClient { query, row, .. } = postgres;
+        let body = self.body_tokens(&postgres.body);
+        let source = postgres_source();
+
+        let (sink, dataflow) = if postgres.inserts {
+            let dataflow = quote! {
+                // Each row's inserts, and whether the query has run out of rows
+                let steps = rows.map(q!(|row| match row {
+                    Some(#row) => {
+                        let mut inserts: Vec<Insert> = Vec::new();
+                        #body
+                        (inserts, false)
+                    }
+                    None => (Vec::new(), true),
+                }));
+
+                // Signal completion once every insert has been written
+                steps
+                    .map(q!({
+                        let sink = PostgresInserts::connect(connection);
+                        move |(inserts, finished)| {
+                            let written = sink.write(inserts, finished);
+                            async move {
+                                written.await;
+                                finished
+                            }
+                        }
+                    }))
+                    .resolve_futures_ordered()
+                    .filter_map(q!(|finished| finished.then_some(())))
+                    .send_bincode_external(external)
+            };
+            (postgres_sink(), dataflow)
+        } else {
+            let dataflow = quote! {
+                // Signal completion once the query has run out of rows
+                rows.filter_map(q!(|row| match row {
+                    Some(#row) => {
+                        #body
+                        None
+                    }
+                    None => Some(()),
+                }))
+                .send_bincode_external(external)
+            };
+            (TokenStream::new(), dataflow)
+        };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #source
+
+            #sink
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>, connection: String) -> ExternalBincodeStream<()> {
+                let rows = process.source_stream(q!(crate::test_postgres::postgres_rows(connection.clone(), (#query).to_string())));
+
+                #dataflow
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function streaming the rows of a SQLite query into the loop
+    /// body. The connection is opened and the query run on a thread of its own, which
+    /// sends each row into the dataflow as the query yields it and `None` once it is
+    /// done, so a large table never has to fit in memory.
+    fn generate_sqlite_function(
+        &self,
+        module_name: &str,
+        sqlite: &SqliteSource,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let SqliteSource { setup, rows, row, pat, imports, definitions, .. } = sqlite;
+        let send = match &sqlite.unwrap {
+            // `?` ended the program at the first bad row
+            Unwrap::Stops => quote! {
+                let Ok(#row) = #row else {
+                    break;
+                };
+            },
+            _ => TokenStream::new(),
+        };
+        let unwrap = match &sqlite.unwrap {
+            Unwrap::Panics(unwrap) => quote! { .map(q!(|#row| #unwrap)) },
+            _ => TokenStream::new(),
+        };
+        let body = self.body_tokens(&sqlite.body);
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            #(#imports)*
+
+            #(#definitions)*
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // The rows as the query yields them, followed by `None`
+                let rows = process.source_stream(q!({
+                    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+                    std::thread::spawn(move || {
+                        #(#setup)*
+                        for #row in #rows {
+                            #send
+                            if sender.send(Some(#row)).is_err() {
+                                return;
+                            }
+                        }
+                        let _ = sender.send(None);
+                    });
+                    tokio_stream::wrappers::UnboundedReceiverStream::new(receiver)
+                }));
+
+                rows.clone().filter_map(q!(|row| row)) #unwrap.for_each(q!(|#pat| {
+                    #body
+                }));
+
+                // Signal completion once the query has run out of rows
+                rows.filter_map(q!(|row| match row {
+                    Some(_) => None,
+                    None => Some(()),
+                }))
+                .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function that produces the loop's pushes as a stream, collects
+    /// them into a single `Vec` and runs the code after the loop on it
+    fn generate_collect_function(
+        &self,
+        module_name: &str,
+        collect: &CollectLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let items = &collect.items;
+        let pat = &collect.pat;
+        let buffer = &collect.buffer;
+        let produce = match &collect.produce {
+            Produce::One(value) => quote! { .map(q!(|#pat| #value)) },
+            Produce::Many(body) => quote! { .flat_map_ordered(q!(|#pat| #body)) },
+        };
+        // The stream is bounded, so it can be sorted as a whole before it is collected
+        let sort = if collect.sorted { quote! { .sort() } } else { TokenStream::new() };
+        // Anything else that reorders the Vec applies to the collected batch
+        let reorder = if collect.reorder.is_empty() {
+            TokenStream::new()
+        } else {
+            let collected = collected_ident();
+            let reorder = &collect.reorder;
+            quote! {
+                .map(q!(|mut #collected| {
+                    #(#reorder)*
+                    #collected
+                }))
+            }
+        };
+
+        let rest = StdoutLines::lower(&collect.rest);
+        let rest_body = rest.closure_body(self.body_tokens(&rest.stmts));
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // What the loop pushed, in order, gathered into the one Vec the code
+                // after the loop works with
+                let collected = process
+                    .source_iter(q!(#items))
+                    #produce
+                    #sort
+                    .fold(q!(|| Vec::new()), q!(|collected, item| collected.push(item)))
+                    #reorder;
+
+                let outputs = collected
+                    .map(q!(|collected| {
+                        let #buffer = collected;
+                        #rest_body
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function that keys both collections and joins them, then runs
+    /// the inner loop's body for each matching pair in the order the loops visited them
+    fn generate_join_function(
+        &self,
+        module_name: &str,
+        join: &NestedJoin,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let prefix = &join.prefix;
+        let (outer_pat, outer_items, outer_key) = (&join.outer.pat, &join.outer.items, &join.outer.key);
+        let (inner_pat, inner_items, inner_key) = (&join.inner.pat, &join.inner.items, &join.inner.key);
+
+        let body = StdoutLines::lower(&join.body);
+        let body = body.closure_body(self.body_tokens(&body.stmts));
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // The collections are built once, like the loops borrowed them, and each item
+                // remembers its position so the matches can be replayed in loop order
+                let items = process.source_iter(q!({
+                    #(#prefix)*
+                    let outer: Vec<_> = (&#outer_items).into_iter().cloned().enumerate().map(Ok).collect();
+                    let inner: Vec<_> = (&#inner_items).into_iter().cloned().enumerate().map(Err).collect();
+                    outer.into_iter().chain(inner)
+                }));
+
+                // Key each item by its half of the loops' comparison
+                let outer = items
+                    .clone()
+                    .filter_map(q!(|item| item.ok()))
+                    .map(q!(|(index, item)| {
+                        let key = {
+                            let #outer_pat = &item;
+                            (#outer_key).clone()
+                        };
+                        (key, (index, item))
+                    }));
+                let inner = items
+                    .filter_map(q!(|item| item.err()))
+                    .map(q!(|(index, item)| {
+                        let key = {
+                            let #inner_pat = &item;
+                            (#inner_key).clone()
+                        };
+                        (key, (index, item))
+                    }));
+
+                let outputs = outer
+                    .join(inner)
+                    .map(q!(|(_, ((outer_index, outer_item), (inner_index, inner_item)))| {
+                        ((outer_index, inner_index), outer_item, inner_item)
+                    }))
+                    .fold(q!(|| Vec::new()), q!(|matches, pair| matches.push(pair)))
+                    .map(q!(|mut matches| {
+                        matches.sort_by_key(|(order, _, _)| *order);
+                        let mut lines = Vec::new();
+                        for (_, outer_item, inner_item) in matches {
+                            let #outer_pat = &outer_item;
+                            let #inner_pat = &inner_item;
+                            lines.extend({ #body });
+                        }
+                        lines
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    /// Generate a Hydro function that numbers the items, groups every `size` of them into
+    /// a window with a keyed fold, and runs the loop's body on each window in order
+    fn generate_windowed_function(
+        &self,
+        module_name: &str,
+        windowed: &WindowedLoop,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let prefix = &windowed.prefix;
+        let items = &windowed.items;
+        let size = &windowed.size;
+        let push = match &windowed.push {
+            Some((pat, value)) if windowed.by_ref => quote! {
+                .map(q!(|item| {
+                    let #pat = &item;
+                    #value
+                }))
+            },
+            Some((pat, value)) => quote! { .map(q!(|#pat| #value)) },
+            None => TokenStream::new(),
+        };
+
+        let binding = &windowed.binding;
+        let bind = if windowed.as_slice {
+            quote! { let #binding = &window_items[..]; }
+        } else {
+            quote! { let #binding = window_items; }
+        };
+        let full = StdoutLines::lower(&windowed.full);
+        let full = full.closure_body(self.body_tokens(&full.stmts));
+        let run = match &windowed.partial {
+            Some(partial) if partial == &windowed.full => quote! {
+                #bind
+                lines.extend({ #full });
+            },
+            Some(partial) => {
+                let partial = StdoutLines::lower(partial);
+                let partial = partial.closure_body(self.body_tokens(&partial.stmts));
+                quote! {
+                    let is_full = window_items.len() == #size;
+                    #bind
+                    if is_full {
+                        lines.extend({ #full });
+                    } else {
+                        lines.extend({ #partial });
+                    }
+                }
+            }
+            // Leftovers that don't fill a window were never processed
+            None => quote! {
+                if window_items.len() == #size {
+                    #bind
+                    lines.extend({ #full });
+                }
+            },
+        };
+
+        let hydro_fn = quote! {
+            use hydro_lang::*;
+            use hydro_lang::location::external_process::ExternalBincodeStream;
+
+            pub fn #func_name<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
+                // Every item is assigned to the window it falls in, and each window is
+                // batched up by key
+                let windows = process
+                    .source_iter(q!({
+                        #(#prefix)*
+                        #items
+                    }))
+                    #push
+                    .enumerate()
+                    .map(q!(|(index, item)| (index / #size, item)))
+                    .fold_keyed(q!(|| Vec::new()), q!(|window, item| window.push(item)));
+
+                let outputs = windows
+                    .fold(q!(|| Vec::new()), q!(|windows, window| windows.push(window)))
+                    .map(q!(|mut windows| {
+                        // Process the windows in the order the loop filled them
+                        windows.sort_by_key(|(index, _)| *index);
+                        let mut lines = Vec::new();
+                        for (_, window_items) in windows {
+                            #run
+                        }
+                        lines
+                    }))
+                    .into_stream();
+
+                outputs
+                    .clone()
+                    .flat_map_ordered(q!(|lines| lines))
+                    .for_each(q!(|s| println!("{}", s)));
+
+                outputs
+                    .map(q!(|_| ()))
+                    .send_bincode_external(external)
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(hydro_fn)?);
+        Ok(formatted)
+    }
+
+    fn body_tokens(&self, stmts: &[Stmt]) -> TokenStream {
+        if self.config.preserve_spans {
+            // Preserve original spans for debugging
+            self.preserve_statement_spans(stmts)
+        } else {
+            // Use call site spans
+            quote! { #(#stmts)* }
+        }
+    }
+
+    /// Preserve original spans from statements for better debugging
+    fn preserve_statement_spans(&self, stmts: &[Stmt]) -> TokenStream {
+        let mut result = TokenStream::new();
+        for stmt in stmts {
+            // Convert each statement to tokens, preserving its original span
+            let stmt_tokens = stmt.to_token_stream();
+            result.extend(stmt_tokens);
+        }
+        result
+    }
+
+    /// Generate an example program that uses the Hydro function
+    fn generate_example_program(&self, module_name: &str, seeded: bool, startup: &[StartupValue]) -> Result<String, Box<dyn std::error::Error>> {
+        self.generate_example(module_name, seeded, startup, None)
+    }
+
+    /// Generate an example program for a legacy program that runs until it is stopped.
+    /// Rather than waiting a while for the dataflow to finish, it runs the deployment as
+    /// a service until Ctrl-C, keeping the completion port connected and printing where
+    /// the program listens.
+    fn generate_long_running_example(
+        &self,
+        module_name: &str,
+        seeded: bool,
+        startup: &[StartupValue],
+        service: &LongRunning,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.generate_example(module_name, seeded, startup, Some(service))
+    }
+
+    fn generate_example(
+        &self,
+        module_name: &str,
+        seeded: bool,
+        startup: &[StartupValue],
+        service: Option<&LongRunning>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let (seed, seed_arg) = if seeded {
+            (
+                quote! {
+                    // Seed for the program's randomness, so runs can be reproduced
+                    let seed: u64 = std::env::var(#SEED_ENV_VAR)
+                        .ok()
+                        .and_then(|seed| seed.parse().ok())
+                        .unwrap_or(0);
+                },
+                quote! { , seed },
+            )
+        } else {
+            (TokenStream::new(), TokenStream::new())
+        };
+
+        // The legacy command line and config file, worked out before anything is
+        // deployed so that `--help`, bad arguments and bad configs behave as they did
+        let mut imports: Vec<String> = Vec::new();
+        for value in startup {
+            for import in &value.imports {
+                let import = import.to_token_stream().to_string();
+                if !imports.contains(&import) {
+                    imports.push(import);
+                }
+            }
+        }
+        let imports = imports.iter().map(|import| import.parse::<TokenStream>()).collect::<Result<Vec<_>, _>>()?;
+        let types = startup.iter().flat_map(|value| &value.definitions).filter_map(|item| match item {
+            Item::Struct(item) => Some(&item.ident),
+            Item::Enum(item) => Some(&item.ident),
+            Item::Type(item) => Some(&item.ident),
+            _ => None,
+        });
+        let startup_imports = quote! {
+            #(#imports)*
+            #(use #crate_name::#func_name::#types;)*
+        };
+        let setup = startup.iter().map(|value| &value.setup);
+        let params = startup.iter().map(|value| &value.param);
+        let startup_setup = quote! { #(#setup)* };
+        let startup_args = quote! { #(, #params)* };
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { done.next() }, &timeout_secs, &[format_ident!("process")], &[]);
+        let (run_imports, run) = match service {
+            None => (
+                quote! { use tokio::time::{timeout, Duration}; },
+                quote! {
+                    // Connect to the completion port before starting so the signal can't be missed
+                    let mut done = nodes.connect_source_bincode(done_port).await;
+                    deployment.start().await.unwrap();
+                    tracing::info!(event = "process_launched", "Dataflow started");
+
+                    #completion
+                },
+            ),
+            Some(service) => {
+                // Addresses worked out at run time can't be printed ahead of it
+                let addresses = service.addresses.iter().filter(|address| matches!(address, Expr::Lit(_)));
+                (
+                    TokenStream::new(),
+                    quote! {
+                        // Keep the completion port connected, in case the service does stop
+                        let mut done = nodes.connect_source_bincode(done_port).await;
+                        tokio::spawn(async move {
+                            if let Some(()) = done.next().await {
+                                tracing::info!(event = "stream_completed", "Service finished on its own");
+                            }
+                        });
+
+                        #(tracing::info!(event = "service_listening", address = #addresses);)*
+                        tracing::info!(event = "service_running", "Service running, press Ctrl-C to stop");
+                        deployment.run_ctrl_c().await.unwrap();
+                    },
+                )
+            }
+        };
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::StreamExt;
+            use hydro_deploy::Deployment;
+            #run_imports
+            #startup_imports
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                #startup_setup
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+                
+                // Call our generated Hydro function
+                #seed
+                let done_port = #crate_name::#func_name::#func_name(&process, &external #seed_arg #startup_args);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+                
+                // Deploy the processes first
+                deployment.deploy().await.unwrap();
+                
+                #run
+            }
+        };
+
+        // Format the generated code for better readability
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate an example program deploying the leader and a multi-member worker
+    /// cluster, with as many workers as the example's first command-line argument says.
+    /// The leader hands out the items round-robin over the members it finds at runtime,
+    /// so the generated function doesn't depend on the count.
+    fn generate_cluster_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { done.next() }, &timeout_secs, &[format_ident!("leader")], &[format_ident!("workers")]);
+        let argument = lifecycle::first_argument();
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::StreamExt;
+            use hydro_deploy::Deployment;
+            use tokio::time::{timeout, Duration};
+
+            /// The number of workers when none is given on the command line
+            const DEFAULT_WORKERS: usize = 4;
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let workers_count: usize = match #argument {
+                    Some(arg) => arg.parse().ok().filter(|&count| count > 0).expect("usage: [number of workers, at least 1]"),
+                    None => DEFAULT_WORKERS,
+                };
+
+                let mut deployment = Deployment::new();
+                let localhost = deployment.Localhost();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let leader = flow.process();
+                let workers = flow.cluster();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let done_port = #crate_name::#func_name::#func_name(&leader, &workers, &external);
+
+                let nodes = flow
+                    .with_process(&leader, localhost.clone())
+                    .with_cluster(&workers, vec![localhost.clone(); workers_count])
+                    .with_external(&external, localhost.clone())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", workers = workers_count, "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // Connect to the completion port before starting so the signal can't be missed
+                let mut done = nodes.connect_source_bincode(done_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                #completion
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate an example program deploying the producer and consumer processes
+    fn generate_pipeline_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { done.next() }, &timeout_secs, &[format_ident!("producer"), format_ident!("consumer")], &[]);
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::StreamExt;
+            use hydro_deploy::Deployment;
+            use tokio::time::{timeout, Duration};
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+                let localhost = deployment.Localhost();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let producer = flow.process();
+                let consumer = flow.process();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let done_port = #crate_name::#func_name::#func_name(&producer, &consumer, &external);
+
+                let nodes = flow
+                    .with_process(&producer, localhost.clone())
+                    .with_process(&consumer, localhost.clone())
+                    .with_external(&external, localhost.clone())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // Connect to the completion port before starting so the signal can't be missed
+                let mut done = nodes.connect_source_bincode(done_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                #completion
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a filesystem watcher, which sets up the legacy watcher
+    /// once the dataflow is running and forwards each event it sends to the dataflow.
+    /// The channel is drained on a thread of its own, as the legacy loop blocked on it,
+    /// and the example runs for as long as the watcher does.
+    fn generate_watcher_example(&self, module_name: &str, watcher: &FileWatcher) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+        let FileWatcher { setup, receiver, setup_imports, .. } = watcher;
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::SinkExt;
+            use hydro_deploy::Deployment;
+            #(#setup_imports)*
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let events_port = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // Connect to the event port before starting so no event is lost
+                let mut events = nodes.connect_sink_bincode(events_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // The legacy watcher, with its events forwarded to the dataflow
+                #(#setup)*
+                let (forward, mut forwarded) = tokio::sync::mpsc::unbounded_channel();
+                std::thread::spawn(move || {
+                    for event in #receiver {
+                        let event: notify::Result<notify::Event> = event;
+                        forward.send(event.map_err(|error| error.to_string())).unwrap();
+                    }
+                });
+                while let Some(event) = forwarded.recv().await {
+                    events.send(event).await.unwrap();
+                }
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a polling loop, which never finishes: like
+    /// [`Self::generate_long_running_example`], it runs the dataflow as a service until
+    /// it is interrupted with Ctrl-C
+    fn generate_polling_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use hydro_deploy::Deployment;
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+
+                // Call our generated Hydro function
+                #crate_name::#func_name::#func_name(&process);
+
+                let _nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // The legacy loop polled until the program was stopped
+                tracing::info!(event = "service_running", "Service running, press Ctrl-C to stop");
+                deployment.run_ctrl_c().await.unwrap();
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a loop running commands, which runs them for the
+    /// dataflow. It runs each command it is sent in turn, with no stdin, streaming back
+    /// its stdout lines as they are printed and then its exit status and stderr.
+    fn generate_command_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let timeout_secs = self.config.timeout_secs();
+        let completion = lifecycle::await_completion(quote! { done.next() }, &timeout_secs, &[format_ident!("process")], &[]);
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use std::os::unix::process::ExitStatusExt;
+            use std::process::Stdio;
+
+            use futures::{SinkExt, StreamExt};
+            use hydro_deploy::Deployment;
+            use tokio::io::AsyncBufReadExt;
+            use tokio::time::{timeout, Duration};
+            use #crate_name::#func_name::CommandEvent;
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let (commands_port, events_port, done_port) = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                let mut commands = nodes.connect_source_bincode(commands_port).await;
+                let mut events = nodes.connect_sink_bincode(events_port).await;
+                let mut done = nodes.connect_source_bincode(done_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // Run the commands the dataflow sends, one at a time
+                tokio::spawn(async move {
+                    while let Some((program, args)) = commands.next().await {
+                        let spawned = tokio::process::Command::new(&program)
+                            .args(&args)
+                            .stdin(Stdio::null())
+                            .stdout(Stdio::piped())
+                            .stderr(Stdio::piped())
+                            .spawn();
+                        let mut child = match spawned {
+                            Ok(child) => child,
+                            Err(error) => {
+                                events.send(CommandEvent::Failed(error.to_string())).await.unwrap();
+                                continue;
+                            }
+                        };
+                        let mut lines = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            events.send(CommandEvent::Stdout(line)).await.unwrap();
+                        }
+                        let event = match child.wait_with_output().await {
+                            Ok(output) => CommandEvent::Exited(output.status.into_raw(), output.stderr),
+                            Err(error) => CommandEvent::Failed(error.to_string()),
+                        };
+                        events.send(event).await.unwrap();
+                    }
+                });
+
+                #completion
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a loop run until a signal arrives. It takes over the
+    /// signals the legacy program handled, Ctrl-C and SIGTERM, forwards the first of them
+    /// to the dataflow's control stream and waits for the dataflow to wind down.
+    fn generate_shutdown_example(&self, module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::{SinkExt, StreamExt};
+            use hydro_deploy::Deployment;
+            use tokio::signal::unix::{signal, SignalKind};
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let (shutdown_port, done_port) = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                let mut shutdown = nodes.connect_sink_bincode(shutdown_port).await;
+                let mut done = nodes.connect_source_bincode(done_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // The signals the legacy handler caught now shut the dataflow down
+                let mut terminate = signal(SignalKind::terminate()).unwrap();
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                shutdown.send(()).await.unwrap();
+
+                match done.next().await {
+                    Some(()) => tracing::info!(event = "stream_completed", "Dataflow shut down cleanly"),
+                    None => tracing::warn!(event = "stream_ended", "Dataflow stopped without finishing its shutdown"),
+                }
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a web service, which serves HTTP in front of the
+    /// dataflow. It registers the legacy routes, each forwarding its requests to the
+    /// dataflow and answering with the response that comes back for them, and serves
+    /// them as the legacy program did.
+    fn generate_service_example(&self, module_name: &str, service: &HttpService) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+        let HttpService { app, serve, .. } = service;
+        let routes = service.routes.iter().enumerate().map(|(index, route)| {
+            let index = proc_macro2::Literal::usize_unsuffixed(index);
+            let Route { path, method, .. } = route;
+            let handler = match &route.param {
+                None => quote! { move || async move { bridge.forward(#index, String::new()).await } },
+                Some(_) => quote! {
+                    move |axum::extract::Path(param): axum::extract::Path<String>| async move { bridge.forward(#index, param).await }
+                },
+            };
+            quote! {
+                .route(#path, axum::routing::#method({
+                    let bridge = bridge.clone();
+                    #handler
+                }))
+            }
+        });
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use std::collections::HashMap;
+            use std::sync::atomic::{AtomicU64, Ordering};
+            use std::sync::{Arc, Mutex};
+
+            use futures::{SinkExt, StreamExt};
+            use hydro_deploy::Deployment;
+
+            /// Forwards requests to the dataflow and hands back the responses it sends for
+            /// them, matched by id
+            #[derive(Clone)]
+            struct Bridge {
+                requests: tokio::sync::mpsc::UnboundedSender<(u64, u32, String)>,
+                pending: Arc<Mutex<HashMap<u64, tokio::sync::oneshot::Sender<(u16, String)>>>>,
+                next: Arc<AtomicU64>,
+            }
+
+            impl Bridge {
+                async fn forward(&self, route: u32, param: String) -> (axum::http::StatusCode, String) {
+                    let id = self.next.fetch_add(1, Ordering::Relaxed);
+                    let (sender, receiver) = tokio::sync::oneshot::channel();
+                    self.pending.lock().unwrap().insert(id, sender);
+                    self.requests.send((id, route, param)).unwrap();
+                    let (status, body) = receiver.await.unwrap();
+                    (axum::http::StatusCode::from_u16(status).unwrap(), body)
+                }
+            }
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let (requests_port, responses_port) = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                let mut requests_sink = nodes.connect_sink_bincode(requests_port).await;
+                let mut responses = nodes.connect_source_bincode(responses_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                let (requests, mut forwarded) = tokio::sync::mpsc::unbounded_channel();
+                let bridge = Bridge { requests, pending: Default::default(), next: Default::default() };
+
+                // Requests go to the dataflow in the order they arrive
+                tokio::spawn(async move {
+                    while let Some(request) = forwarded.recv().await {
+                        requests_sink.send(request).await.unwrap();
+                    }
+                });
+                // Responses go back to whoever is waiting for them
+                let pending = bridge.pending.clone();
+                tokio::spawn(async move {
+                    while let Some((id, response)) = responses.next().await {
+                        if let Some(waiting) = pending.lock().unwrap().remove(&id) {
+                            let _ = waiting.send(response);
+                        }
+                    }
+                });
+
+                // The legacy routes, answered by the dataflow, served as before
+                let #app = axum::Router::new() #(#routes)*;
+                #(#serve)*
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a Kafka consumer, which doubles as the bridge into the
+    /// dataflow. Once the dataflow is running, it creates and subscribes the legacy
+    /// consumer and runs the legacy loop, sending the payload of each message to the
+    /// dataflow's bytes port where the loop used to handle it. It runs for as long as
+    /// the loop did.
+    fn generate_kafka_example(&self, module_name: &str, kafka: &KafkaConsumer) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+        let KafkaConsumer { setup, bridge, imports, .. } = kafka;
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use futures::SinkExt;
+            use hydro_deploy::Deployment;
+            #(#imports)*
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let port = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                // Connect to the message port before starting so no message is lost
+                let mut payloads = nodes.connect_sink_bytes(port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // The legacy consumer, forwarding each message to the dataflow
+                #(#setup)*
+                #bridge
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Generate the example for a WebSocket server, which doubles as the bridge in front
+    /// of the dataflow. It listens where the legacy server did and terminates the
+    /// WebSocket protocol, forwarding the text and binary messages of each connection to
+    /// the dataflow and sending the connection the replies that come back for it.
+    fn generate_websocket_example(&self, module_name: &str, websocket: &WebSocketServer) -> Result<String, Box<dyn std::error::Error>> {
+        let func_name = syn::Ident::new(module_name, Span::call_site());
+        let crate_name = syn::Ident::new("hydro_template", Span::call_site());
+        let address = &websocket.address;
+
+        let logging = lifecycle::logging();
+        let example = quote! {
+            use std::collections::HashMap;
+            use std::sync::{Arc, Mutex};
+
+            use futures::{SinkExt, StreamExt};
+            use hydro_deploy::Deployment;
+            use tokio_tungstenite::tungstenite::Message;
+            use #crate_name::#func_name::{decode_frame, encode_frame};
+
+            #[tokio::main]
+            async fn main() {
+                #logging
+
+                let mut deployment = Deployment::new();
+
+                let flow = hydro_lang::FlowBuilder::new();
+                let process = flow.process::<()>();
+                let external = flow.external_process::<()>();
+
+                // Call our generated Hydro function
+                let (messages_port, replies_port) = #crate_name::#func_name::#func_name(&process, &external);
+
+                let nodes = flow
+                    .with_process(&process, deployment.Localhost())
+                    .with_external(&external, deployment.Localhost())
+                    .deploy(&mut deployment);
+
+                tracing::info!(event = "deploy_started", "Starting deployment");
+
+                deployment.deploy().await.unwrap();
+
+                let mut messages_sink = nodes.connect_sink_bytes(messages_port).await;
+                let mut replies = nodes.connect_source_bytes(replies_port).await;
+                deployment.start().await.unwrap();
+                tracing::info!(event = "process_launched", "Dataflow started");
+
+                // Messages go to the dataflow in the order they arrive
+                let (messages, mut forwarded) = tokio::sync::mpsc::unbounded_channel::<bytes::Bytes>();
+                tokio::spawn(async move {
+                    while let Some(frame) = forwarded.recv().await {
+                        messages_sink.send(frame).await.unwrap();
+                    }
+                });
+                // Replies go back to the connection they are for, if it is still open
+                let connections: Arc<Mutex<HashMap<u64, tokio::sync::mpsc::UnboundedSender<Message>>>> = Default::default();
+                {
+                    let connections = connections.clone();
+                    tokio::spawn(async move {
+                        while let Some(frame) = replies.next().await {
+                            let (connection, text, data) = decode_frame(&frame);
+                            let reply = if text {
+                                Message::text(String::from_utf8_lossy(&data).into_owned())
+                            } else {
+                                Message::binary(data)
+                            };
+                            if let Some(sender) = connections.lock().unwrap().get(&connection) {
+                                let _ = sender.send(reply);
+                            }
+                        }
+                    });
+                }
+
+                // Accept connections where the legacy server did
+                let listener = tokio::net::TcpListener::bind(#address).await.unwrap();
+                let mut next = 0u64;
+                while let Ok((stream, _)) = listener.accept().await {
+                    let connection = next;
+                    next += 1;
+                    let messages = messages.clone();
+                    let connections = connections.clone();
+                    tokio::spawn(async move {
+                        let Ok(websocket) = tokio_tungstenite::accept_async(stream).await else {
+                            return;
+                        };
+                        let (mut write, mut read) = websocket.split();
+                        let (sender, mut replies) = tokio::sync::mpsc::unbounded_channel();
+                        connections.lock().unwrap().insert(connection, sender);
+                        tokio::spawn(async move {
+                            while let Some(reply) = replies.recv().await {
+                                if write.send(reply).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+
+                        while let Some(Ok(message)) = read.next().await {
+                            if message.is_text() || message.is_binary() {
+                                let text = message.is_text();
+                                messages.send(encode_frame(connection, text, &message.into_data())).unwrap();
+                            }
+                        }
+                        connections.lock().unwrap().remove(&connection);
+                    });
+                }
+            }
+        };
+
+        let formatted = prettyplease::unparse(&syn::parse2(example)?);
+        Ok(formatted)
+    }
+
+    /// Extract and analyze function calls from the body for more sophisticated transformations
+    pub fn analyze_function_calls(&self, stmts: &[Stmt]) -> Vec<FunctionCallInfo> {
+        let mut calls = Vec::new();
+        
+        for stmt in stmts {
+            self.extract_calls_from_stmt(stmt, &mut calls);
+        }
+        
+        calls
+    }
+
+    fn extract_calls_from_stmt(&self, stmt: &Stmt, calls: &mut Vec<FunctionCallInfo>) {
+        match stmt {
+            Stmt::Expr(expr, _) => {
+                self.extract_calls_from_expr(expr, calls);
+            }
+            Stmt::Local(local) => {
+                if let Some(init) = &local.init {
+                    self.extract_calls_from_expr(&init.expr, calls);
+                }
+            }
+            Stmt::Item(_) => {
+                // Handle item statements (not common in main function body)
+            }
+            Stmt::Macro(stmt_macro) => {
+                // Handle macro statements like println! directly at statement level
+                if let Some(ident) = stmt_macro.mac.path.get_ident() {
+                    calls.push(FunctionCallInfo {
+                        name: format!("{}!", ident),
+                        span: ident.span(),
+                        args_count: 1,
+                    });
+                }
+                self.extract_calls_from_operands(&stmt_macro.mac, calls);
+            }
+        }
+    }
+
+    fn extract_calls_from_expr(&self, expr: &Expr, calls: &mut Vec<FunctionCallInfo>) {
+        match expr {
+            Expr::Call(call) => {
+                if let Expr::Path(path) = &*call.func {
+                    if let Some(ident) = path.path.get_ident() {
+                        calls.push(FunctionCallInfo {
+                            name: ident.to_string(),
+                            span: ident.span(),
+                            args_count: call.args.len(),
+                        });
+                    }
+                }
+                // Recursively check arguments
+                for arg in &call.args {
+                    self.extract_calls_from_expr(arg, calls);
+                }
+            }
+            Expr::Macro(macro_expr) => {
+                // Handle macro calls like println!, format!, etc.
+                if let Some(ident) = macro_expr.mac.path.get_ident() {
+                    calls.push(FunctionCallInfo {
+                        name: format!("{}!", ident), // Add ! to indicate it's a macro
+                        span: ident.span(),
+                        args_count: 1, // Macros don't have a predictable arg count
+                    });
+                }
+                self.extract_calls_from_operands(&macro_expr.mac, calls);
+            }
+            Expr::MethodCall(method_call) => {
+                calls.push(FunctionCallInfo {
+                    name: method_call.method.to_string(),
+                    span: method_call.method.span(),
+                    args_count: method_call.args.len() + 1, // +1 for receiver
+                });
+                // Recursively check receiver and arguments
+                self.extract_calls_from_expr(&method_call.receiver, calls);
+                for arg in &method_call.args {
+                    self.extract_calls_from_expr(arg, calls);
+                }
+            }
+            Expr::Block(block) => {
+                for stmt in &block.block.stmts {
+                    self.extract_calls_from_stmt(stmt, calls);
+                }
+            }
+            Expr::If(if_expr) => {
+                self.extract_calls_from_expr(&if_expr.cond, calls);
+                for stmt in &if_expr.then_branch.stmts {
+                    self.extract_calls_from_stmt(stmt, calls);
+                }
+                if let Some((_, else_branch)) = &if_expr.else_branch {
+                    self.extract_calls_from_expr(else_branch, calls);
+                }
+            }
+            Expr::While(while_expr) => {
+                self.extract_calls_from_expr(&while_expr.cond, calls);
+                for stmt in &while_expr.body.stmts {
+                    self.extract_calls_from_stmt(stmt, calls);
+                }
+            }
+            Expr::ForLoop(for_loop) => {
+                self.extract_calls_from_expr(&for_loop.expr, calls);
+                for stmt in &for_loop.body.stmts {
+                    self.extract_calls_from_stmt(stmt, calls);
+                }
+            }
+            // Add more expression types as needed
+            _ => {}
+        }
+    }
+
+    /// The calls in the operands of `dbg!` and the assertions, which run as code
+    fn extract_calls_from_operands(&self, mac: &syn::Macro, calls: &mut Vec<FunctionCallInfo>) {
+        let operands = DiagnosticMacro::parse(mac).and_then(|diagnostic| diagnostic.operands(mac));
+        for operand in operands.unwrap_or_default() {
+            self.extract_calls_from_expr(&operand, calls);
+        }
+    }
+}
+
+impl Transformer for SynLegacyToHydroTransformer {
+    fn name(&self) -> &'static str {
+        "syn"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let registered = self.rules.iter().map(|rule| rule.name());
+        Capabilities {
+            parses: true,
+            rewrites_output: self.config.rewrite_prints,
+            streams_stdin: false,
+            seeds_rng: self.config.seeded_rng,
+            translations: match self.config.lowering {
+                Lowering::Full => registered.chain(BUILTIN_RULES.iter().map(|rule| rule.name)).collect(),
+                _ => registered.collect(),
+            },
+        }
+    }
+
+    fn transform_source(&self, source: &str, module_name: &str) -> Result<TransformOutput, TransformError> {
+        SynLegacyToHydroTransformer::transform_source(self, source, module_name)
+    }
+
+    fn analyze(&self, file: &syn::File) -> Result<Analysis, TransformError> {
+        Analysis::new(file, self.translation(file)?)
+    }
+}
+
+/// The generated module and example, or why they couldn't be generated
+type Generated = Result<(String, String), Box<dyn std::error::Error>>;
+
+/// How a built-in rule generates the module and example of a program, holding what it
+/// found in the program so it isn't looked for again
+type Translation = Box<dyn FnOnce(&SynLegacyToHydroTransformer, &LegacyProgram, &str) -> Generated>;
+
+/// A translation built into the transformer, tried in the order of `BUILTIN_RULES`
+struct BuiltinRule {
+    name: &'static str,
+    /// What the rule found in a program, as the translation generating its code, or
+    /// `None` when the rule doesn't apply to it
+    detect: fn(&SynLegacyToHydroTransformer, &LegacyProgram) -> Option<Translation>,
+}
+
+/// The translation of a program a built-in rule applies to
+fn translate(generate: impl FnOnce(&SynLegacyToHydroTransformer, &LegacyProgram, &str) -> Generated + 'static) -> Option<Translation> {
+    Some(Box::new(generate))
+}
+
+/// The built-in translations. The loop translations are only tried on loops of the
+/// shape they take apart.
+static BUILTIN_RULES: &[BuiltinRule] = &[
+    // A seeded body shares its RNG between iterations, so it is never data-parallel
+    BuiltinRule {
+        name: "data-parallel cluster",
+        detect: |transformer, program| {
+            if transformer.config.target != Target::Cluster || !program.has_loop(LoopKind::EffectOnly) {
+                return None;
+            }
+            let parallel = ParallelLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_cluster_function(module_name, &parallel)?, transformer.generate_cluster_example(module_name)?))
+            })
+        },
+    },
+    // Grouping by key is partitioned across a cluster by the hash of the key
+    BuiltinRule {
+        name: "key-partitioned cluster",
+        detect: |transformer, program| {
+            if transformer.config.target != Target::Cluster || !program.has_loop(LoopKind::FoldLike) {
+                return None;
+            }
+            let keyed = KeyedAggregation::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_keyed_cluster_function(module_name, &keyed)?, transformer.generate_cluster_example(module_name)?))
+            })
+        },
+    },
+    // A reduction's members fold partials that a leader combines, when asked for
+    BuiltinRule {
+        name: "leader-aggregator reduce",
+        detect: |transformer, program| {
+            if transformer.config.target != Target::Cluster || transformer.config.distribution != Distribution::Reduce || !program.has_loop(LoopKind::FoldLike) {
+                return None;
+            }
+            let reduce = ReduceLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_reduce_cluster_function(module_name, &reduce)?, transformer.generate_cluster_example(module_name)?))
+            })
+        },
+    },
+    // A producer thread feeding a consumer loop becomes two processes
+    BuiltinRule {
+        name: "channel pipeline",
+        detect: |_, program| {
+            let pipeline = ChannelPipeline::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_pipeline_function(module_name, &pipeline)?, transformer.generate_pipeline_example(module_name)?))
+            })
+        },
+    },
+    // Threads updating an Arc<Mutex<T>> become a fold on a single process
+    BuiltinRule {
+        name: "shared state fold",
+        detect: |_, program| {
+            let shared = SharedState::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_shared_state_function(module_name, &shared)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // A loop feeding each item to a method updating a user-defined value becomes a fold
+    BuiltinRule {
+        name: "method fold",
+        detect: |_, program| {
+            if !program.has_loop(LoopKind::FoldLike) {
+                return None;
+            }
+            let fold = MethodFold::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_method_fold_function(module_name, &fold)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // The rows of a CSV reader become a source of records
+    BuiltinRule {
+        name: "CSV source",
+        detect: |_, program| {
+            let csv = CsvSource::detect(program.body)?;
+            translate(move |transformer, program, module_name| {
+                let definitions = match &csv.record {
+                    Some(record) => record_definitions(program.items, record),
+                    None => Vec::new(),
+                };
+                Ok((transformer.generate_csv_function(module_name, &csv, &definitions)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // A loop run until a signal arrives stops on a control stream from the example
+    BuiltinRule {
+        name: "shutdown control stream",
+        detect: |_, program| {
+            let shutdown = ShutdownLoop::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_shutdown_function(module_name, &shutdown)?, transformer.generate_shutdown_example(module_name)?))
+            })
+        },
+    },
+    // A loop polling forever becomes a stage driven by an interval at its period
+    BuiltinRule {
+        name: "interval-driven polling",
+        detect: |_, program| {
+            let polling = PollingLoop::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_polling_function(module_name, &polling)?, transformer.generate_polling_example(module_name)?))
+            })
+        },
+    },
+    // A tiny web service answers requests from the dataflow, behind the example
+    BuiltinRule {
+        name: "HTTP service",
+        detect: |_, program| {
+            let service = HttpService::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_service_function(module_name, &service)?, transformer.generate_service_example(module_name, &service)?))
+            })
+        },
+    },
+    // A Kafka consumer moves to a bridge feeding the dataflow its messages
+    BuiltinRule {
+        name: "Kafka consumer",
+        detect: |_, program| {
+            let kafka = KafkaConsumer::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_kafka_function(module_name, &kafka)?, transformer.generate_kafka_example(module_name, &kafka)?))
+            })
+        },
+    },
+    // A WebSocket server's message handling moves between a pair of byte streams,
+    // behind a bridge terminating the protocol
+    BuiltinRule {
+        name: "WebSocket server",
+        detect: |_, program| {
+            let websocket = WebSocketServer::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_websocket_function(module_name, &websocket)?, transformer.generate_websocket_example(module_name, &websocket)?))
+            })
+        },
+    },
+    // A filesystem watcher moves to the example, which forwards its events
+    BuiltinRule {
+        name: "filesystem watcher",
+        detect: |_, program| {
+            let watcher = FileWatcher::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_watcher_function(module_name, &watcher)?, transformer.generate_watcher_example(module_name, &watcher)?))
+            })
+        },
+    },
+    // A Postgres client becomes a connector streaming rows in and inserts out
+    BuiltinRule {
+        name: "Postgres connector",
+        detect: |_, program| {
+            let postgres = PostgresClient::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                let connection = StartupValue::connection_string(&postgres);
+                Ok((transformer.generate_postgres_function(module_name, &postgres)?, transformer.generate_example_program(module_name, false, &[connection])?))
+            })
+        },
+    },
+    // The rows of a SQLite query become a source streaming them in
+    BuiltinRule {
+        name: "SQLite source",
+        detect: |_, program| {
+            let sqlite = SqliteSource::detect(program.items, program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_sqlite_function(module_name, &sqlite)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // Blocking requests in a loop become an async stage between the items and the body
+    BuiltinRule {
+        name: "request pipeline",
+        detect: |_, program| {
+            let requests = RequestLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_request_function(module_name, &requests)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // Commands run for each item move out to the example, which streams back their output
+    BuiltinRule {
+        name: "command pipeline",
+        detect: |_, program| {
+            let commands = CommandLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_command_function(module_name, &commands)?, transformer.generate_command_example(module_name)?))
+            })
+        },
+    },
+    // A loop stepping a state enum becomes a cycle carrying the state between ticks
+    BuiltinRule {
+        name: "state machine cycle",
+        detect: |_, program| {
+            if !program.has_loop(LoopKind::Stateful) {
+                return None;
+            }
+            let machine = StateMachine::detect(program.items, program.body)?;
+            translate(move |transformer, program, module_name| {
+                let example_program = match LongRunning::detect(program.body) {
+                    Some(service) => transformer.generate_long_running_example(module_name, false, &[], &service)?,
+                    None => transformer.default_example(module_name)?,
+                };
+                Ok((transformer.generate_state_machine_function(module_name, &machine)?, example_program))
+            })
+        },
+    },
+    // An iterator chain maps adaptor by adaptor onto stream operators
+    BuiltinRule {
+        name: "iterator chain",
+        detect: |_, program| {
+            let chain = IteratorChain::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_iterator_chain_function(module_name, &chain)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // Nested loops matching keys become a join of two streams
+    BuiltinRule {
+        name: "join",
+        detect: |_, program| {
+            let join = NestedJoin::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_join_function(module_name, &join)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // Fixed-size windows become explicit batches of the stream
+    BuiltinRule {
+        name: "windowed batches",
+        detect: |_, program| {
+            let windowed = WindowedLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_windowed_function(module_name, &windowed)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // Filling a Vec in a loop becomes an explicit collection of a stream
+    BuiltinRule {
+        name: "collected stream",
+        detect: |_, program| {
+            if !program.has_loop(LoopKind::MapLike) {
+                return None;
+            }
+            let collect = CollectLoop::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                Ok((transformer.generate_collect_function(module_name, &collect)?, transformer.default_example(module_name)?))
+            })
+        },
+    },
+    // A loop counting into an atomic becomes a count (or fold) over the loop's items
+    BuiltinRule {
+        name: "count",
+        detect: |_, program| {
+            if !program.has_loop(LoopKind::FoldLike) {
+                return None;
+            }
+            let counter = AtomicCounter::detect(program.body)?;
+            translate(move |transformer, _, module_name| {
+                let hydro_function = match counter.update {
+                    CounterUpdate::Count { .. } => transformer.generate_count_function(module_name, &counter)?,
+                    CounterUpdate::Fold(_) => {
+                        let shared = counter
+                            .into_shared_state()
+                            .ok_or_else(|| TransformError::UnsupportedConstruct("Atomic counter is not a fold".to_string()))?;
+                        transformer.generate_shared_state_function(module_name, &shared)?
+                    }
+                };
+                Ok((hydro_function, transformer.default_example(module_name)?))
+            })
+        },
+    },
+];
+
+/// Information about a function call found in the source code
+#[derive(Debug, Clone)]
+pub struct FunctionCallInfo {
+    pub name: String,
+    pub span: Span,
+    pub args_count: usize,
+}
+
+/// A value the example works out before deploying, like the parsed command line or a
+/// loaded config file, and passes to the body as its configuration
+struct StartupValue {
+    /// The parameter passing the value to the generated function, bound to `pat` in
+    /// the body
+    param: syn::Ident,
+    pat: Pat,
+    ty: Type,
+    /// The statements the example runs to bind `param`
+    setup: TokenStream,
+    /// The items the generated module carries for the value's type
+    definitions: Vec<Item>,
+    definition_imports: Vec<Item>,
+    /// The imports the example needs for `setup`
+    imports: Vec<Item>,
+}
+
+impl StartupValue {
+    /// The command line and config file of a legacy body, in the order the example
+    /// works them out, along with the body without them
+    fn detect(items: &[Item], stmts: &[Stmt]) -> Option<(Vec<Self>, Vec<Stmt>)> {
+        let mut values = Vec::new();
+        let mut body = stmts.to_vec();
+        if let Some((cli, rest)) = CliArgs::detect(items, &body) {
+            let param = cli.param();
+            let parse = lifecycle::without_flags(&cli.parse);
+            // Only a derived Parser needs its imports next to the definitions
+            let definition_imports = if cli.definitions.is_empty() { Vec::new() } else { cli.imports.clone() };
+            values.push(StartupValue {
+                setup: quote! { let #param = #parse; },
+                param,
+                pat: cli.pat,
+                ty: cli.ty,
+                definitions: cli.definitions,
+                definition_imports,
+                imports: cli.imports,
+            });
+            body = rest;
+        }
+        let available: Vec<String> = values.iter().map(|value| value.param.to_string()).collect();
+        if let Some((config, rest)) = ConfigFile::detect(items, &body, &available) {
+            let load = &config.load;
+            let param = match &config.pat {
+                Pat::Ident(pat) => pat.ident.clone(),
+                _ => return None,
+            };
+            values.push(StartupValue {
+                setup: quote! { #(#load)* },
+                param,
+                pat: config.pat,
+                ty: config.ty,
+                definitions: config.definitions,
+                definition_imports: config.definition_imports,
+                imports: config.imports,
+            });
+            body = rest;
+        }
+        (!values.is_empty()).then_some((values, body))
+    }
+
+    /// The connection string of a Postgres connector, from the example's first
+    /// command-line argument. The legacy program's own is the default, when it is a
+    /// literal.
+    fn connection_string(postgres: &PostgresClient) -> Self {
+        let param = syn::Ident::new("connection", Span::call_site());
+        let argument = lifecycle::first_argument();
+        let setup = match &postgres.connection {
+            Expr::Lit(literal) => quote! {
+                let #param = #argument.unwrap_or_else(|| #literal.to_string());
+            },
+            _ => quote! {
+                let #param = #argument.expect("usage: <postgres connection string>");
+            },
+        };
+        StartupValue {
+            pat: parse_quote! { #param },
+            param,
+            ty: parse_quote! { String },
+            setup,
+            definitions: Vec::new(),
+            definition_imports: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+}
+
+/// How the startup values reach the body: the definitions of their types, the
+/// parameters of the generated function passing them in, a singleton holding each,
+/// and the crossing of the body's input with those singletons. A pure stage hoisted
+/// out of the body runs ahead of the crossing.
+struct Configuration {
+    definitions: TokenStream,
+    param: TokenStream,
+    singleton: TokenStream,
+    stage: TokenStream,
+    cross: TokenStream,
+    /// The pattern the body's closure takes its input with
+    input: TokenStream,
+}
+
+impl Configuration {
+    fn new(startup: &[StartupValue]) -> Self {
+        let mut config = Self {
+            definitions: TokenStream::new(),
+            param: TokenStream::new(),
+            singleton: TokenStream::new(),
+            stage: TokenStream::new(),
+            cross: TokenStream::new(),
+            input: quote! { _ },
+        };
+        let mut imports: Vec<String> = Vec::new();
+        for value in startup {
+            let StartupValue { param, pat, ty, definitions, definition_imports, .. } = value;
+            for import in definition_imports {
+                let tokens = import.to_token_stream();
+                if !imports.contains(&tokens.to_string()) {
+                    imports.push(tokens.to_string());
+                    config.definitions.extend(tokens);
+                }
+            }
+            config.definitions.extend(quote! { #(#definitions)* });
+            config.param.extend(quote! { , #param: #ty });
+            // Each singleton takes the name of the parameter it holds
+            config.singleton.extend(quote! { let #param = process.singleton(q!(#param)); });
+            config.cross.extend(quote! { .cross_singleton(#param) });
+            let input = &config.input;
+            config.input = quote! { (#input, #pat) };
+        }
+        config
+    }
+
+    /// Run the pure statements in maps of their own, each taking the values the one
+    /// before hands on, and the body takes the values of the last as its input
+    fn hoist(&mut self, transformer: &SynLegacyToHydroTransformer, pure_stage: &PureStage) {
+        let mut input = quote! { _ };
+        for (stmts, handoff) in &pure_stage.stages {
+            let stmts = transformer.body_tokens(stmts);
+            let output = handoff.value();
+            self.stage.extend(quote! {
+                .map(q!(|#input| {
+                    #stmts
+                    #output
+                }))
+            });
+            input = handoff.pattern();
+        }
+        self.input = pure_stage.pattern();
+    }
+}
+
+impl Default for SynLegacyToHydroTransformer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target_cfg::TargetCfg;
+    use std::io::Write;
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_hello_world_transformation() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    println!("Hello, world!");
+}}
+"#).unwrap();
+
+        let transformer = SynLegacyToHydroTransformer::new();
+        let result = transformer.transform_program(temp_file.path(), "test_hello");
+        
+        assert!(result.is_ok());
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = result.unwrap();
+        
+        // Check that the generated function contains our expected structure
+        assert!(hydro_fn.contains("pub fn test_hello"));
+        assert!(hydro_fn.contains("singleton"));
+        assert!(hydro_fn.contains("map"));
+        assert!(hydro_fn.contains("println!"));
+        
+        // Check that the example contains deployment code
+        assert!(example.contains("Deployment::new"));
+        assert!(example.contains("test_hello"));
+    }
+
+    #[test]
+    fn test_failures_are_typed() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "fn helper() {{}}").unwrap();
+        let error = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_missing")
+            .unwrap_err();
+        assert!(matches!(error, TransformError::NoMainFunction));
+
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, "fn main() {{\n    let x = ;\n}}").unwrap();
+        let error = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_broken")
+            .unwrap_err();
+        assert!(matches!(error, TransformError::ParseError { line: 2, .. }));
+
+        let error = SynLegacyToHydroTransformer::new()
+            .transform_program("/nonexistent/legacy.rs", "test_unread")
+            .unwrap_err();
+        assert!(matches!(error, TransformError::IoError(_)));
+    }
+
+    #[test]
+    fn test_in_memory_source_matches_file() {
+        let source = "fn main() {\n    for i in 1..=5 {\n        println!(\"Count: {}\", i);\n    }\n}\n";
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(temp_file, "{}", source).unwrap();
+
+        let transformer = SynLegacyToHydroTransformer::new();
+        let generated = |output: TransformOutput| (output.hydro_function, output.example_program);
+        let from_file = generated(transformer.transform_program(temp_file.path(), "test_counter").unwrap());
+        assert_eq!(generated(transformer.transform_source(source, "test_counter").unwrap()), from_file);
+        let file = parse_file(source).unwrap();
+        assert_eq!(generated(transformer.transform_file(&file, "test_counter").unwrap()), from_file);
+    }
+
+    #[test]
+    fn test_prints_become_output_stream() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    for i in 1..=5 {{
+        println!("Count: {{}}", i);
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_counter")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+
+        // The body pushes lines instead of printing them
+        assert!(compact.contains("stdout_lines.push(format!(\"Count:{}\",i))"));
+        assert!(!compact.contains("println!(\"Count:{}\",i)"));
+        // ... and they reach stdout through a single terminal sink
+        assert_eq!(compact.matches(".for_each(").count(), 1);
+        assert!(compact.contains(".for_each(q!(|s|println!(\"{}\",s)))"));
+
+        let TransformOutput { hydro_function: side_effects, .. } = SynLegacyToHydroTransformer::new()
+            .with_print_rewriting(false)
+            .transform_program(temp_file.path(), "test_counter")
+            .unwrap();
+        assert!(side_effects.contains("println!(\"Count: {}\", i)"));
+    }
+
+    #[test]
+    fn test_seeded_rng_threads_seed_from_example() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use rand::Rng;
+
+fn main() {{
+    let roll = rand::thread_rng().gen_range(1..=6);
+    println!("Rolled {{}}", roll);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .with_seeded_rng(true)
+            .transform_program(temp_file.path(), "test_dice")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("external:&ExternalProcess<'a,()>,seed:u64,"));
+        assert!(compact.contains("seed_from_u64(seed,)"));
+        assert!(compact.contains("(&mutseeded_rng).gen_range(1..=6)"));
+        assert!(example.contains("HYDRO_INGEST_SEED"));
+        assert!(example.contains("(&process, &external, seed)"));
+
+        // Without the option the program is left nondeterministic
+        let TransformOutput { hydro_function: unseeded, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_dice")
+            .unwrap();
+        assert!(unseeded.contains("thread_rng"));
+        assert!(!unseeded.contains("seed"));
+    }
+
+    #[test]
+    fn test_parallel_loop_runs_on_cluster() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    for n in 0..10 {{
+        println!("{{}}", n * 2);
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .with_cluster_mode(true)
+            .transform_program(temp_file.path(), "test_doubles")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("workers:&Cluster<'a,Worker>"));
+        // The collection is built once, and its size follows the items
+        assert!(compact.contains("letitems:Vec<_>=(0..10).into_iter().collect();lettotal=items.len();items.into_iter().map(Ok).chain(std::iter::once(Err(total)))"));
+        assert_eq!(compact.matches("0..10").count(), 1);
+        assert!(compact.contains(".filter_map(q!(|message|message.ok())).round_robin_bincode(workers).map(q!(|n|{"));
+        // Completion waits for the size and as many outputs, so an empty collection completes at once
+        assert!(compact.contains(".filter_map(q!(|message|message.err())).map(q!(|total|Err(total))).union(outputs.map(q!(|_|Ok(()))))"));
+        assert!(compact.contains("(total==Some(finished)).then_some(())"));
+        assert!(compact.contains("stdout_lines.push(format!(\"{}\",n*2))"));
+        assert!(compact.contains(".send_bincode_anonymous(leader)"));
+        assert!(example.contains(".with_cluster(&workers, vec![localhost.clone(); workers_count])"));
+        // The number of workers comes from the command line
+        let compact: String = example.split_whitespace().collect();
+        assert!(compact.contains("letworkers_count:usize=matchstd::env::args().skip(1).find(|arg|arg!=\"--quiet\"){"));
+        assert!(example.contains("None => DEFAULT_WORKERS,"));
+
+        // A loop carrying an accumulator stays on a single process
+        let mut sequential = NamedTempFile::new().unwrap();
+        writeln!(sequential, r#"
+fn main() {{
+    let mut total = 0;
+    for n in 0..10 {{
+        total += n;
+    }}
+    println!("{{}}", total);
+}}
+"#).unwrap();
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .with_cluster_mode(true)
+            .transform_program(sequential.path(), "test_total")
+            .unwrap();
+        assert!(!hydro_fn.contains("Cluster"));
+    }
+
+    #[test]
+    fn test_instrumented_operators_report_their_throughput() {
+        let source = "fn main() {\n    for n in 0..10 {\n        println!(\"{}\", n * 2);\n    }\n}\n";
+        let config = TransformerConfig::new().with_target(Target::Cluster).with_instrumentation(true);
+        let output = SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_doubles").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        assert!(compact.contains("crate::test_doubles::OPERATOR_STATS[0].record(operator_started)"));
+        assert!(compact.contains("report_operator_stats(\"leader\")"));
+        assert!(compact.contains("report_operator_stats(\"workers\")"));
+        assert!(compact.contains("pubfnreport_operator_stats(location:&str)"));
+        // Only the module changes, and only when asked
+        let plain = SynLegacyToHydroTransformer::new().with_cluster_mode(true).transform_source(source, "test_doubles").unwrap();
+        assert_eq!(plain.example_program, output.example_program);
+        assert!(!plain.hydro_function.contains("OPERATOR_STATS"));
+    }
+
+    #[test]
+    fn test_generic_locations_are_named_by_the_example() {
+        let source = "fn main() {\n    for n in 0..10 {\n        println!(\"{}\", n * 2);\n    }\n}\n";
+        let config = TransformerConfig::new().with_target(Target::Cluster).with_generic_locations(true);
+        let output = SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_doubles").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("pubfntest_doubles<'a,Leader,Worker>(leader:&Process<'a,Leader>,workers:&Cluster<'a,Worker>,"));
+        assert!(output.example_program.contains("let leader = flow.process::<hydro_template::test_doubles::Leader>();"));
+        assert!(output.example_program.contains("let workers = flow.cluster::<hydro_template::test_doubles::Worker>();"));
+
+        let config = TransformerConfig::new().with_generic_locations(true);
+        let output = SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_doubles").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("pubfntest_doubles<'a,P>(process:&Process<'a,P>,external:&ExternalProcess<'a,()>,)"));
+        assert!(output.example_program.contains("let process = flow.process::<()>();"));
+    }
+
+    #[test]
+    fn test_unsafe_blocks_follow_the_configured_handling() {
+        let source = "fn main() {\n    for n in 0..3 {\n        unsafe { libc::sched_yield() };\n        println!(\"{}\", n);\n    }\n}\n";
+        let transform = |unsafe_code| {
+            let config = TransformerConfig::new().with_lowering(Lowering::Wrap).with_unsafe_code(unsafe_code);
+            SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_unsafe")
+        };
+
+        // Passed through, the block stays in the quoted code and is reported
+        let passed = transform(UnsafeCode::Passthrough).unwrap();
+        assert!(!passed.hydro_function.contains("unsafe_effect"));
+        assert_eq!(passed.report.diagnostics[0].construct, crate::report::Construct::Unsafe);
+
+        let refused = transform(UnsafeCode::Refuse);
+        assert!(matches!(refused, Err(TransformError::UnsupportedConstruct(message)) if message.starts_with("unsafe block at line 3")));
+        assert!(transform(UnsafeCode::Isolate).is_ok());
+    }
+
+    #[test]
+    fn test_cfg_branches_are_resolved_for_the_target() {
+        let source = r#"
+fn main() {
+    #[cfg(unix)]
+    let separator = "/";
+    #[cfg(windows)]
+    let separator = "\\";
+    println!("{}", separator);
+}
+"#;
+        let transform = |config: TransformerConfig| SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_separator").unwrap();
+
+        let linux = transform(TransformerConfig::new().with_target_cfg(TargetCfg::parse("unix,target_os=linux").unwrap()));
+        let compact: String = linux.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("letseparator=\"/\";"));
+        assert!(!compact.contains("cfg"));
+        assert!(!compact.contains("\\\\"));
+
+        // Without a target, both branches keep their attributes for the compiler to pick
+        let carried: String = transform(TransformerConfig::new()).hydro_function.split_whitespace().collect();
+        assert!(carried.contains("#[cfg(unix)]letseparator=\"/\";"));
+        assert!(carried.contains("#[cfg(windows)]letseparator"));
+    }
+
+    #[test]
+    fn test_legacy_comments_are_carried_above_their_statements() {
+        let source = "fn main() {\n    // Sum of the first ten squares\n    let total: u32 = (0..10).map(|n| n * n).sum();\n    println!(\"{}\", total); // printed once\n}\n";
+        let config = TransformerConfig::new().with_lowering(Lowering::Wrap);
+        let output = SynLegacyToHydroTransformer::new().with_config(config.clone()).transform_source(source, "test_squares").unwrap();
+        let lines: Vec<&str> = output.hydro_function.lines().collect();
+        let comment = lines.iter().position(|line| line.trim() == "// Sum of the first ten squares").unwrap();
+        assert!(lines[comment + 1].trim().starts_with("let total: u32 ="));
+        // The print became a push onto the output, which has no place for its comment
+        assert!(!output.hydro_function.contains("printed once"));
+        assert_eq!(output.source_map.generated_line(3), Some(comment + 2));
+
+        let plain = SynLegacyToHydroTransformer::new().with_config(config.with_preserve_spans(false)).transform_source(source, "test_squares").unwrap();
+        assert!(!plain.hydro_function.contains("//"));
+    }
+
+    #[test]
+    fn test_parameters_are_broadcast_before_the_items() {
+        let source = r#"
+fn main() {
+    let threshold = std::env::var("THRESHOLD").ok().and_then(|value| value.parse().ok()).unwrap_or(5);
+    let readings = vec![3, 8, 5, 12];
+    for reading in readings {
+        if reading > threshold {
+            println!("{} is over {}", reading, threshold);
+        }
+    }
+}
+"#;
+        let output = SynLegacyToHydroTransformer::new().with_cluster_mode(true).transform_source(source, "test_alerts").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        assert!(compact.contains("pubenumWorkerMessage<C,T>{Config(C),Item(T),}"));
+        // The leader computes the parameter once and sends it to every member first
+        assert!(compact.contains("letconfig=(threshold.clone(),);letitems:Vec<_>=(readings).into_iter().collect();lettotal=items.len();std::iter::once(WorkerMessage::Config(config)).chain(items.into_iter().map(WorkerMessage::Item))"));
+        assert!(compact.contains("WorkerMessage::Config(config)=>{members.iter().map(|member|(*member,WorkerMessage::Config(config.clone()))).collect()}"));
+        assert!(compact.contains("item=>vec![(members[index%members.len()],item)],"));
+        assert!(compact.contains(".demux_bincode(workers)"));
+        assert!(!compact.contains("round_robin_bincode"));
+        // Each member keeps it for the items that follow
+        assert!(compact.contains("WorkerMessage::Item(reading)=>{let(threshold,)=config.clone().expect("));
+        // Completion still counts the items alone, without building them again
+        assert!(compact.contains(".enumerate().map(Ok).chain(std::iter::once(Err(total)))"));
+        assert_eq!(compact.matches("letreadings=").count(), 1);
+    }
+
+    #[test]
+    fn test_keyed_aggregation_is_partitioned_by_key() {
+        let source = r#"
+use std::collections::HashMap;
+
+fn main() {
+    let text = "the cat saw the dog";
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    let mut words: Vec<_> = counts.into_iter().collect();
+    words.sort();
+    for (word, count) in words {
+        println!("{}: {}", word, count);
+    }
+}
+"#;
+        let output = SynLegacyToHydroTransformer::new().with_cluster_mode(true).transform_source(source, "test_words").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // Items go to the member owning their key rather than round-robin
+        assert!(!compact.contains("round_robin_bincode"));
+        assert!(compact.contains("((word).to_owned(),1)"));
+        assert!(compact.contains("members[hasher.finish()asusize%members.len()]"));
+        assert!(compact.contains("None=>members.iter().map(|member|(*member,None)).collect(),"));
+        assert!(compact.contains(".demux_bincode(workers)"));
+        // The members fold locally, and the leader merges their groups
+        assert!(compact.contains("*groups.entry(key).or_insert(0)+=value;"));
+        assert!(compact.contains("merged.extend(groups);"));
+        assert!(compact.contains("(reported==reporting.len()).then(||std::mem::take(&mutmerged))"));
+        assert!(compact.contains(".map(q!(|counts|{"));
+        assert!(output.example_program.contains("workers_count"));
+
+        // On a single process the same loop isn't partitioned
+        let output = SynLegacyToHydroTransformer::new().transform_source(source, "test_words").unwrap();
+        assert!(!output.hydro_function.contains("demux_bincode"));
+    }
+
+    #[test]
+    fn test_reduction_combines_partials_on_a_leader() {
+        let source = r#"
+fn main() {
+    let numbers: Vec<u64> = (1..=100).collect();
+    let mut total: u64 = 0;
+    for n in numbers {
+        let square = n * n;
+        total += square;
+    }
+    println!("Sum of squares: {}", total);
+}
+"#;
+        let config = TransformerConfig::new().with_target(Target::Cluster).with_distribution(Distribution::Reduce);
+        let output = SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_sum").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // The items go round-robin, with every member told when they run out
+        assert!(compact.contains("(numbers).into_iter().map(Some).chain(std::iter::once(None)).enumerate()"));
+        assert!(compact.contains("Some(item)=>vec![(members[index%members.len()],Some(item))],"));
+        assert!(compact.contains(".demux_bincode(workers)"));
+        // The members fold partials of their share, which the leader combines
+        assert!(compact.contains("Some(n)=>{letsquare=n*n;letvalue=square;"));
+        assert!(compact.contains("partial+=value;"));
+        assert!(compact.contains("letmutcombined=Some(0);"));
+        assert!(compact.contains("*combined+=partial;"));
+        assert!(compact.contains(".map(q!(|total|{"));
+        assert!(compact.contains("stdout_lines.push(format!(\"Sumofsquares:{}\",total));"));
+        assert!(output.example_program.contains("workers_count"));
+
+        // Without asking for it, the loop stays on a single process
+        let output = SynLegacyToHydroTransformer::new().with_cluster_mode(true).transform_source(source, "test_sum").unwrap();
+        assert!(!output.hydro_function.contains("demux_bincode"));
+    }
+
+    #[test]
+    fn test_user_types_are_carried_and_fold_through_their_methods() {
+        let source = r#"
+use std::collections::HashSet;
+
+struct Stats {
+    seen: HashSet<u64>,
+    total: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats { seen: HashSet::new(), total: 0 }
+    }
+
+    fn record(&mut self, value: u64) {
+        self.seen.insert(value);
+        self.total += value;
+    }
+
+    fn distinct(&self) -> usize {
+        self.seen.len()
+    }
+}
+
+fn main() {
+    let mut stats = Stats::new();
+    for value in [3, 5, 3, 10] {
+        stats.record(value);
+    }
+    println!("{} distinct, total {}", stats.distinct(), stats.total);
+}
+"#;
+        let output = SynLegacyToHydroTransformer::new().transform_source(source, "test_stats").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // The loop's method becomes the step of a fold over its items
+        assert!(compact.contains(".source_iter(q!({[3,5,3,10]}))"));
+        assert!(compact.contains(".fold(q!(||Stats::new()),q!(|stats,value|{stats.record(value);}),)"));
+        assert!(compact.contains(".map(q!(|stats|{"));
+        // The struct, its impl and the import they need come along
+        assert!(compact.contains("usestd::collections::HashSet;"));
+        assert!(compact.contains("structStats{seen:HashSet<u64>,total:u64,}"));
+        assert!(compact.contains("implStats{fnnew()->Self{"));
+
+        // Wrapped whole, the body still finds the types it uses
+        let config = TransformerConfig::new().with_lowering(Lowering::Wrap);
+        let output = SynLegacyToHydroTransformer::new().with_config(config).transform_source(source, "test_stats").unwrap();
+        assert!(!output.hydro_function.contains(".fold("));
+        assert!(output.hydro_function.contains("impl Stats {"));
+        assert!(syn::parse_file(&output.hydro_function).is_ok());
+    }
+
+    #[test]
+    fn test_constants_and_statics_are_hoisted_into_the_module() {
+        let source = r#"
+const N: usize = 10;
+static NAME: &str = "squares";
+
+fn main() {
+    for i in 0..N {
+        println!("{} {}", NAME, i * i);
+    }
+}
+"#;
+        let output = SynLegacyToHydroTransformer::new().transform_source(source, "test_squares").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+
+        // The items follow the function, outside any quoted code, for every operator to read
+        assert!(compact.contains("pubconstN:usize=10;pubstaticNAME:&str=\"squares\";"));
+        // The quoted code reads them through their paths rather than capturing copies
+        assert!(compact.contains("crate::test_squares::NAME,i*i"));
+        let function_end = compact.find("constN").unwrap();
+        assert!(!compact[function_end..].contains("q!("));
+        assert_eq!(compact.matches("constN").count(), 1);
+        assert!(syn::parse_file(&output.hydro_function).is_ok());
+    }
+
+    #[test]
+    fn test_channel_pipeline_spans_two_processes() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::sync::mpsc;
+use std::thread;
+
+fn main() {{
+    let (tx, rx) = mpsc::channel();
+    let producer = thread::spawn(move || {{
+        for i in 1..=3 {{
+            tx.send(i * i).unwrap();
+        }}
+    }});
+    for square in rx {{
+        println!("Got {{}}", square);
+    }}
+    producer.join().unwrap();
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_squares")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("producer:&Process<'a,Producer>,consumer:&Process<'a,Consumer>"));
+        assert!(compact.contains("foriin1..=3{sent.push(i*i);}"));
+        assert!(compact.contains(".send_bincode(consumer)"));
+        assert!(compact.contains("Some(square)=>{letlines={letmutstdout_lines:Vec<String>=Vec::new();stdout_lines.push(format!(\"Got{}\",square));"));
+        assert!(!compact.contains("thread::spawn"));
+        assert!(example.contains(".with_process(&consumer, localhost.clone())"));
+    }
+
+    #[test]
+    fn test_mutex_state_becomes_fold() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::sync::{{Arc, Mutex}};
+use std::thread;
+
+fn main() {{
+    let counter = Arc::new(Mutex::new(0));
+    let mut handles = vec![];
+    for i in 1..=4 {{
+        let counter = Arc::clone(&counter);
+        let handle = thread::spawn(move || {{
+            *counter.lock().unwrap() += i;
+        }});
+        handles.push(handle);
+    }}
+    for handle in handles {{
+        handle.join().unwrap();
+    }}
+    println!("Total: {{}}", *counter.lock().unwrap());
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_total")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(".source_iter(q!(1..=4)).fold(q!(||(0,Vec::<String>::new())),q!(|(shared_state,stdout_lines),i|{*(&mut*shared_state)+=i;}),)"));
+        assert!(compact.contains("stdout_lines.push(format!(\"Total:{}\",*(&mut*shared_state)));"));
+        assert!(!compact.contains("Mutex"));
+        assert!(!compact.contains("thread::spawn"));
+    }
+
+    #[test]
+    fn test_atomic_increments_become_count() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::sync::atomic::{{AtomicUsize, Ordering}};
+
+fn main() {{
+    let matches = AtomicUsize::new(0);
+    for word in ["apple", "banana", "avocado"] {{
+        if word.starts_with('a') {{
+            matches.fetch_add(1, Ordering::SeqCst);
+        }}
+    }}
+    println!("Matches: {{}}", matches.load(Ordering::SeqCst));
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_matches")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(".filter_map(q!(|word|(word.starts_with('a')).then_some(()))).count()"));
+        assert!(compact.contains("letmutfinal_count=0asusize+countedasusize;"));
+        assert!(compact.contains("stdout_lines.push(format!(\"Matches:{}\",*shared_state));"));
+        assert!(!compact.contains("Atomic"));
+    }
+
+    #[test]
+    fn test_iterator_chain_becomes_operators() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let data = vec![1, 2, 3, 4, 5, 6];
+    data.iter()
+        .map(|x| x * 10)
+        .filter(|x| *x > 20)
+        .for_each(|x| println!("{{}}", x));
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_chain")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(
+            ".source_iter(q!({letdata=vec![1,2,3,4,5,6];data})).map(q!(|item|{letx=&item;x*10})).filter(q!(|x|*x>20));"
+        ));
+        assert!(compact.contains("items.clone().for_each(q!(|x|println!(\"{}\",x)));"));
+    }
+
+    #[test]
+    fn test_if_let_some_loop_becomes_filter_map() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let readings = vec!["12", "n/a", "7"];
+    for reading in readings {{
+        if let Some(value) = reading.parse::<u32>().ok() {{
+            println!("reading: {{}}", value);
+        }}
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_filter_map")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The Nones are dropped by the operator, not inside the body
+        assert!(compact.contains(
+            ".source_iter(q!({letreadings=vec![\"12\",\"n/a\",\"7\"];readings})).filter_map(q!(|reading|reading.parse::<u32>().ok()));"
+        ));
+        assert!(compact.contains("items.clone().for_each(q!(|value|{println!(\"reading:{}\",value);}));"));
+    }
+
+    #[test]
+    fn test_csv_reader_becomes_record_source() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct City {{
+    name: String,
+    population: u64,
+}}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {{
+    let mut reader = csv::Reader::from_path("cities.csv")?;
+    let headers = reader.headers()?.clone();
+    for result in reader.deserialize() {{
+        let city: City = result?;
+        println!("{{:?}} {{}} {{}}", headers, city.name, city.population);
+    }}
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_csv")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("useserde::Deserialize;#[derive(Deserialize)]structCity{"));
+        // One reader yields the header row and then the records, stopping at a bad one
+        assert!(compact.contains("letmutreader=csv::Reader::from_path(\"cities.csv\").unwrap();letheader_row=reader.headers().unwrap().clone();"));
+        assert!(compact.contains("std::iter::once(Err(header_row)).chain(reader.into_deserialize::<City>().map_while(|result|result.ok()).map(Ok),)"));
+        // The header row is read once, as a singleton the records see
+        assert!(compact.contains(".fold(q!(||csv::StringRecord::new()),q!(|headers,row|*headers=row),);"));
+        assert!(compact.contains("records.clone().cross_singleton(headers).for_each(q!(|(city,headers)|{"));
+    }
+
+    #[test]
+    fn test_sqlite_query_becomes_streaming_source() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use rusqlite::{{Connection, Result}};
+
+struct Person {{
+    id: i32,
+    name: String,
+}}
+
+fn main() -> Result<()> {{
+    let conn = Connection::open("people.db")?;
+    let mut stmt = conn.prepare("SELECT id, name FROM person")?;
+    let people = stmt.query_map([], |row| Ok(Person {{ id: row.get(0)?, name: row.get(1)? }}))?;
+    for person in people {{
+        let person = person?;
+        println!("{{}}: {{}}", person.id, person.name);
+    }}
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_sqlite")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("userusqlite::{Connection,Result};structPerson{"));
+        // The connection lives on a thread of its own, streaming the rows over
+        assert!(compact.contains("std::thread::spawn(move||{letconn=Connection::open(\"people.db\").unwrap();"));
+        assert!(compact.contains("forpersoninpeople{letOk(person)=personelse{break;};ifsender.send(Some(person)).is_err(){return;}}let_=sender.send(None);"));
+        assert!(compact.contains("rows.clone().filter_map(q!(|row|row)).for_each(q!(|person|{"));
+        assert!(compact.contains("None=>Some(()),}),).send_bincode_external(external)"));
+    }
+
+    #[test]
+    fn test_postgres_client_becomes_connector() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use tokio_postgres::{{Error, NoTls}};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {{
+    let (client, connection) = tokio_postgres::connect("host=localhost user=postgres", NoTls).await?;
+    tokio::spawn(async move {{
+        if let Err(e) = connection.await {{
+            eprintln!("connection error: {{}}", e);
+        }}
+    }});
+    let rows = client.query("SELECT id, name FROM person", &[]).await?;
+    for row in &rows {{
+        let id: i32 = row.get(0);
+        let name: &str = row.get(1);
+        client.execute("INSERT INTO greeted (id, name) VALUES ($1, $2)", &[&id, &name]).await?;
+    }}
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_postgres")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("pubfntest_postgres<'a>(process:&Process<'a>,external:&ExternalProcess<'a,()>,connection:String,)"));
+        // Rows stream in from the query, inserts go out in batches
+        assert!(compact.contains("process.source_stream(q!(crate::test_postgres::postgres_rows(connection.clone(),(\"SELECTid,nameFROMperson\").to_string(),),)"));
+        assert!(compact.contains("inserts.push(((\"INSERTINTOgreeted(id,name)VALUES($1,$2)\").to_string(),vec!["));
+        assert!(compact.contains("letsink=PostgresInserts::connect(connection);"));
+        assert!(compact.contains(".resolve_futures_ordered().filter_map(q!(|finished|finished.then_some(())))"));
+        assert!(compact.contains("pubstructPostgresInserts{"));
+
+        // The example takes the connection string as its argument
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("letconnection=std::env::args().skip(1).find(|arg|arg!=\"--quiet\").unwrap_or_else(||\"host=localhostuser=postgres\".to_string());"));
+        assert!(example.contains("test_postgres(&process,&external,connection"));
     }
-}
 
-/// Information about a function call found in the source code
-#[derive(Debug, Clone)]
-pub struct FunctionCallInfo {
-    pub name: String,
-    pub span: Span,
-    pub args_count: usize,
-}
+    #[test]
+    fn test_kafka_consumer_is_bridged_into_a_bytes_port() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{{BaseConsumer, Consumer}};
+use rdkafka::Message;
+use std::time::Duration;
 
-impl Default for SynLegacyToHydroTransformer {
-    fn default() -> Self {
-        Self::new()
+fn main() {{
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", "localhost:9092")
+        .set("group.id", "ingest")
+        .create()
+        .expect("consumer creation failed");
+    consumer.subscribe(&["events"]).expect("subscription failed");
+    loop {{
+        match consumer.poll(Duration::from_millis(100)) {{
+            Some(Ok(message)) => {{
+                if let Some(Ok(text)) = message.payload_view::<str>() {{
+                    println!("event: {{}}", text);
+                }}
+            }}
+            Some(Err(e)) => eprintln!("kafka error: {{}}", e),
+            None => {{}}
+        }}
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_kafka")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("->ExternalBytesPort{"));
+        assert!(compact.contains("let(port,payloads)=process.source_external_bytes(external);"));
+        assert!(compact.contains("payloads.map(q!(|payload|KafkaMessage{payload})).for_each(q!(|message|{ifletSome(Ok(text))=message.payload_view::<str>()"));
+        assert!(compact.contains("pubstructKafkaMessage{payload:bytes::BytesMut,}"));
+
+        // The example is the bridge, running the legacy consumer
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("usestd::time::Duration;"));
+        assert!(example.contains("letmutpayloads=nodes.connect_sink_bytes(port).await;deployment.start().await.unwrap();tracing::info!(event=\"process_launched\",\"Dataflowstarted\");letconsumer:BaseConsumer=ClientConfig::new()"));
+        assert!(example.contains("Some(Ok(message))=>{ifletSome(payload)=rdkafka::Message::payload(&message){payloads.send(bytes::Bytes::copy_from_slice(payload)).await.unwrap();}}"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+    #[test]
+    fn test_blocking_requests_become_an_async_stage() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let urls = vec!["https://example.com", "https://example.org"];
+    for url in &urls {{
+        match reqwest::blocking::get(*url) {{
+            Ok(response) => println!("{{}}: {{}}", url, response.status()),
+            Err(e) => eprintln!("{{}}: {{}}", url, e),
+        }}
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_requests")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("pubstructFetchedResponse{"));
+        // Each request runs in an async map over the borrowed item
+        assert!(compact.contains(".map(q!(|item|asyncmove{letfetched={leturl=&item;matchreqwest::get(*url).await{Ok(response)=>FetchedResponse::read(response).await,Err(error)=>Err(error),}};(item,fetched)})"));
+        assert!(compact.contains(".resolve_futures_ordered();"));
+        // Both arms still handle each result, in the order of the items
+        assert!(compact.contains("responses.clone().for_each(q!(|(item,fetched)|{leturl=&item;matchfetched{Ok(response)=>println!(\"{}:{}\",url,response.status()),Err(e)=>eprintln!(\"{}:{}\",url,e),}})"));
+    }
 
     #[test]
-    fn test_hello_world_transformation() {
+    fn test_axum_handlers_become_a_request_response_service() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use axum::{{extract::Path, routing::get, Router}};
+
+async fn hello() -> &'static str {{
+    "Hello, world!"
+}}
+
+async fn greet(Path(name): Path<String>) -> String {{
+    format!("Hello, {{}}!", name)
+}}
+
+#[tokio::main]
+async fn main() {{
+    let app = Router::new().route("/", get(hello)).route("/greet/:name", get(greet));
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_service")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The handlers run synchronously in the dataflow, dispatched by route
+        assert!(compact.contains("useaxum::{extract::Path,routing::get,Router};pubfnhello()->&'staticstr{"));
+        assert!(compact.contains("pubfngreet(Path(name):Path<String>)->String{"));
+        assert!(compact.contains("let(requests_port,requests)=process.source_external_bincode(external);"));
+        assert!(compact.contains("0=>(200,crate::test_service::hello().to_string()),1=>{matchparam.parse::<String>(){Ok(param)=>{(200,crate::test_service::greet(Path(param)).to_string())}Err(_)=>(400,\"Invalidpathparameter\".to_string()),}}_=>(404,String::new()),"));
+        assert!(compact.contains("(requests_port,responses.send_bincode_external(external))"));
+
+        // The example serves the same routes, answered by the dataflow
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains(".route(\"/greet/:name\",axum::routing::get({letbridge=bridge.clone();move|axum::extract::Path(param):axum::extract::Path<String>|asyncmove{bridge.forward(1,param).await}}),)"));
+        assert!(example.contains("letlistener=tokio::net::TcpListener::bind(\"0.0.0.0:3000\").await.unwrap();axum::serve(listener,app).await.unwrap();"));
+    }
+
+    #[test]
+    fn test_notify_watcher_feeds_an_event_port() {
         let mut temp_file = NamedTempFile::new().unwrap();
         writeln!(temp_file, r#"
+use notify::{{Event, EventKind, RecursiveMode, Result, Watcher}};
+use std::path::Path;
+use std::sync::mpsc;
+
+fn main() -> Result<()> {{
+    let (tx, rx) = mpsc::channel::<Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new("."), RecursiveMode::Recursive)?;
+    for res in rx {{
+        match res {{
+            Ok(event) if matches!(event.kind, EventKind::Create(_)) => println!("created: {{:?}}", event.paths),
+            Ok(_) => {{}}
+            Err(e) => println!("watch error: {{:?}}", e),
+        }}
+    }}
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_watcher")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The handler runs in the dataflow on the events the example forwards
+        assert!(compact.contains("usenotify::{Event,EventKind,RecursiveMode,Result,Watcher};"));
+        assert!(compact.contains("->ExternalBincodeSink<std::result::Result<notify::Event,String>>{"));
+        assert!(compact.contains("event.map_err(|error|notify::Error::generic(&error))"));
+        assert!(compact.contains(".for_each(q!(|res|{matchres{Ok(event)ifmatches!(event.kind,EventKind::Create(_))=>"));
+
+        // The example sets up the watcher and drains its channel into the port
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("letmutwatcher=notify::recommended_watcher(tx).unwrap();watcher.watch(Path::new(\".\"),RecursiveMode::Recursive).unwrap();"));
+        assert!(example.contains("foreventinrx{"));
+        assert!(example.contains("events.send(event).await.unwrap();"));
+    }
+
+    #[test]
+    fn test_commands_run_by_the_example() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::process::Command;
+
 fn main() {{
-    println!("Hello, world!");
+    let files = vec!["Cargo.toml", "README.md"];
+    for file in &files {{
+        let output = Command::new("wc").arg("-l").arg(file).output().expect("failed to run wc");
+        print!("{{}}: {{}}", file, String::from_utf8_lossy(&output.stdout));
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_commands")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The dataflow sends the commands out and gets their output back
+        assert!(compact.contains("letfile=&item;letmutargs:Vec<String>=Vec::new();args.push(std::ffi::OsStr::new(&\"-l\").to_string_lossy().into_owned(),);args.push(std::ffi::OsStr::new(&file).to_string_lossy().into_owned(),);(std::ffi::OsStr::new(&\"wc\").to_string_lossy().into_owned(),args,)"));
+        assert!(compact.contains("let(events_port,events)=process.source_external_bincode(external);"));
+        assert!(compact.contains("letitem=waiting.pop_front().unwrap();letfile=&item;letoutput=command_output.expect(\"failedtorunwc\");print!("));
+        assert!(compact.contains("(commands_port,events_port,done.send_bincode_external(external))"));
+
+        // The example runs them without blocking the dataflow
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("tokio::process::Command::new(&program).args(&args)"));
+        assert!(example.contains("events.send(CommandEvent::Stdout(line)).await.unwrap();"));
+    }
+
+    #[test]
+    fn test_server_runs_as_a_service() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::io::Write;
+use std::net::TcpListener;
+
+fn main() {{
+    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    for stream in listener.incoming() {{
+        let mut stream = stream.unwrap();
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_server")
+            .unwrap();
+        let example: String = example.split_whitespace().collect();
+        // No timeout, the service runs until it is stopped
+        assert!(!example.contains("timeout"));
+        assert!(example.contains("tracing::info!(event=\"service_listening\",address=\"127.0.0.1:7878\");"));
+        assert!(example.contains("deployment.run_ctrl_c().await.unwrap();"));
+    }
+
+    #[test]
+    fn test_state_machine_becomes_cycle() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+#[derive(Debug)]
+enum Light {{
+    Red,
+    Green(u32),
+    Yellow,
+}}
+
+fn main() {{
+    let mut light = Light::Red;
+    loop {{
+        light = match light {{
+            Light::Red => Light::Green(3),
+            Light::Green(0) => Light::Yellow,
+            Light::Green(n) => Light::Green(n - 1),
+            Light::Yellow => break,
+        }};
+    }}
+    println!("stopped at {{:?}}", light);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_lights")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The enum is carried along, and the state goes around a cycle
+        assert!(compact.contains("#[derive(Debug)]#[derive(Clone)]enumLight{"));
+        assert!(compact.contains("let(next_state,light)=tick.cycle::<Optional<Light,_,_>>();"));
+        assert!(compact.contains("light.or(tick.optional_first_tick(q!({letlight=Light::Red;light})),)"));
+        // The transitions are the match arms, and breaking ends the cycle with the last state
+        assert!(compact.contains("Light::Green(n)=>Light::Green(n-1),Light::Yellow=>returnstd::ops::ControlFlow::Break(light),};std::ops::ControlFlow::Continue(light)"));
+        assert!(compact.contains("next_state.complete_next_tick(step.clone().filter_map(q!(|step|step.continue_value())),);"));
+        assert!(compact.contains(".all_ticks().map(q!(|light|{println!(\"stoppedat{:?}\",light);}))"));
+    }
+
+    #[test]
+    fn test_report_lists_unsafe_code() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let values = [1, 2, 3];
+    let first = unsafe {{ *values.as_ptr() }};
+    println!("{{}}", first);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, report, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_unsafe")
+            .unwrap();
+        // The block is still wrapped as it was, and reported for a rewrite
+        assert!(hydro_fn.contains("unsafe"));
+        assert_eq!(report.diagnostics.len(), 1);
+        assert_eq!(report.diagnostics[0].description, "unsafe block");
+        assert_eq!(report.diagnostics[0].line_number, Some(4));
+    }
+
+    #[test]
+    fn test_pure_statements_run_in_their_own_map() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let limit = 5;
+    let squares: Vec<u64> = (1..=limit).map(|n| n * n).collect();
+    println!("squares up to {{}}: {{:?}}", limit, squares);
+    eprintln!("done");
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_squares")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The computation is a stage of its own, handing its values to the printing
+        assert!(compact.contains(".map(q!(|_|{letlimit=5;letsquares:Vec<u64>=(1..=limit).map(|n|n*n).collect();(limit,squares)})"));
+        assert!(compact.contains(".map(q!(|(limit,squares)|{letmutstdout_lines"));
+        assert!(compact.contains("eprintln!(\"done\");"));
+    }
+
+    #[test]
+    fn test_dependent_statements_become_pipeline_stages() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let limit = 5;
+    let squares: Vec<u64> = (1..=limit).map(|n| n * n).collect();
+    let total: u64 = squares.clone().into_iter().sum();
+    println!("{{:?}} sum to {{}}", squares, total);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_stages")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // Each stage hands on only what the statements after it use
+        assert!(compact.contains(".map(q!(|_|{letlimit=5;letsquares:Vec<u64>=(1..=limit).map(|n|n*n).collect();squares})"));
+        assert!(compact.contains(".map(q!(|squares|{lettotal:u64=squares.clone().into_iter().sum();(squares,total)})"));
+        assert!(compact.contains(".map(q!(|(squares,total)|{letmutstdout_lines"));
+    }
+
+    #[test]
+    fn test_polling_loop_becomes_interval_stage() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::thread;
+use std::time::Duration;
+
+fn main() {{
+    let mut polls = 0;
+    loop {{
+        polls += 1;
+        println!("poll {{}}", polls);
+        thread::sleep(Duration::from_secs(5));
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_polling")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The sleep sets the interval, and the state lives in the stage
+        assert!(compact.contains(".source_interval(q!(Duration::from_secs(5)))"));
+        assert!(compact.contains("usehydro_lang::*;usestd::time::Duration;pubfn"));
+        assert!(compact.contains(".for_each(q!({letmutpolls=0;move|_|{polls+=1;println!(\"poll{}\",polls);}}"));
+        assert!(!compact.contains("sleep"));
+
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("deployment.run_ctrl_c().await.unwrap();"));
+    }
+
+    #[test]
+    fn test_seeded_polling_loop_runs_as_a_whole() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::thread;
+use std::time::Duration;
+use rand::Rng;
+
+fn main() {{
+    loop {{
+        let reading = rand::thread_rng().gen_range(0..100);
+        println!("reading {{}}", reading);
+        thread::sleep(Duration::from_secs(5));
+    }}
+}}
+"#).unwrap();
+
+        // The interval stage takes no seed, so the seeded RNG stays in the body's own map
+        let transformer = SynLegacyToHydroTransformer::new().with_seeded_rng(true);
+        let file = syn::parse_file(&std::fs::read_to_string(temp_file.path()).unwrap()).unwrap();
+        assert_eq!(transformer.translation(&file).unwrap(), None);
+        let TransformOutput { hydro_function: hydro_fn, .. } = transformer.transform_program(temp_file.path(), "test_seeded_polling").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("external:&ExternalProcess<'a,()>,seed:u64"));
+        assert!(compact.contains("(&mutseeded_rng).gen_range(0..100)"));
+        assert!(!compact.contains("source_interval"));
+        assert!(syn::parse_file(&hydro_fn).is_ok());
+    }
+
+    #[test]
+    fn test_ctrlc_handler_becomes_shutdown_control_stream() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use std::sync::atomic::{{AtomicBool, Ordering}};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn main() {{
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || {{
+        println!("Shutting down...");
+        r.store(false, Ordering::SeqCst);
+    }})
+    .expect("Error setting Ctrl-C handler");
+
+    let mut ticks = 0;
+    while running.load(Ordering::SeqCst) {{
+        ticks += 1;
+        std::thread::sleep(Duration::from_millis(100));
+    }}
+    println!("Stopped after {{}} ticks", ticks);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_shutdown")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // Only the imports the loop still uses are kept
+        assert!(compact.contains("usestd::time::Duration;pubfn"));
+        assert!(!compact.contains("AtomicBool"));
+        assert!(compact.contains("let(shutdown_port,shutdown)=process.source_external_bincode(external);"));
+        assert!(compact.contains(".union(shutdown.map(q!(|_:()|true)))"));
+        assert!(compact.contains("letmutticks=0;letmutstopped=false;move|signaled:bool|{ifstopped{None}elseifsignaled{stopped=true;println!(\"Shuttingdown...\");println!(\"Stoppedafter{}ticks\",ticks);Some(())}else{ticks+=1;std::thread::sleep(Duration::from_millis(100));None}}"));
+
+        // The example forwards the signal instead of installing a handler
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("_=tokio::signal::ctrl_c()=>{}_=terminate.recv()=>{}"));
+        assert!(example.contains("shutdown.send(()).await.unwrap();"));
+    }
+
+    #[test]
+    fn test_websocket_server_is_bridged_into_byte_streams() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use futures::{{SinkExt, StreamExt}};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::main]
+async fn main() {{
+    let listener = TcpListener::bind("127.0.0.1:9002").await.unwrap();
+    while let Ok((stream, _)) = listener.accept().await {{
+        tokio::spawn(async move {{
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(message) = ws.next().await {{
+                let message = message.unwrap();
+                if let Ok(text) = message.to_text() {{
+                    ws.send(Message::text(text.to_uppercase())).await.unwrap();
+                }}
+            }}
+        }});
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_websocket")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The loop body sits between the two ports, its sends collected as replies
+        assert!(compact.contains("usetokio_tungstenite::tungstenite::Message;"));
+        assert!(compact.contains("let(messages_port,frames)=process.source_external_bytes(external);"));
+        assert!(compact.contains("tokio_tungstenite::tungstenite::Message::text(String::from_utf8_lossy(&data).into_owned(),)"));
+        assert!(compact.contains("|(connection,message)|{letmutreplies=Vec::new();ifletOk(text)=message.to_text(){replies.push(Message::text(text.to_uppercase()));}replies.into_iter().map(move|reply|(connection,reply))}"));
+        assert!(compact.contains(".send_bytes_external(external);(messages_port,replies_port)"));
+
+        // The example terminates the protocol where the legacy server listened
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("usehydro_template::test_websocket::{decode_frame,encode_frame};"));
+        assert!(example.contains("letlistener=tokio::net::TcpListener::bind(\"127.0.0.1:9002\").await.unwrap();"));
+        assert!(example.contains("letOk(websocket)=tokio_tungstenite::accept_async(stream).awaitelse{"));
+    }
+
+    #[test]
+    fn test_clap_arguments_become_configuration() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {{
+    #[arg(long, default_value = "world")]
+    name: String,
+}}
+
+fn main() {{
+    let args = Args::parse();
+    println!("Hello, {{}}!", args.name);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_cli")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // The Parser comes along for the example to parse into
+        assert!(compact.contains("useclap::Parser;#[derive(Parser)]pubstructArgs{"));
+        assert!(compact.contains("external:&ExternalProcess<'a,()>,args:Args,"));
+        assert!(compact.contains("letargs=process.singleton(q!(args));"));
+        assert!(compact.contains(".cross_singleton(args).map(q!(|(_,args)|{"));
+        assert!(!compact.contains("Args::parse()"));
+
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("useclap::Parser;usehydro_template::test_cli::Args;"));
+        assert!(example.contains(".init();letargs=Args::parse_from(std::env::args().filter(|arg|arg!=\"--quiet\"));"));
+        assert!(example.contains("test_cli::test_cli(&process,&external,args)"));
+    }
+
+    #[test]
+    fn test_config_file_is_loaded_by_the_example() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+use clap::Parser;
+use serde::Deserialize;
+use std::fs;
+
+#[derive(Parser)]
+struct Args {{
+    config: String,
+}}
+
+#[derive(Deserialize)]
+struct Config {{
+    greeting: String,
+}}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {{
+    let args = Args::parse();
+    let config: Config = toml::from_str(&fs::read_to_string(&args.config)?)?;
+    println!("{{}}", config.greeting);
+    Ok(())
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_config")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        // No file I/O is left in the dataflow, only the loaded config
+        assert!(!compact.contains("read_to_string"));
+        assert!(compact.contains("#[derive(Deserialize)]#[derive(Clone,serde::Serialize)]pubstructConfig{"));
+        assert!(compact.contains("args:Args,config:Config,"));
+        assert!(compact.contains("letconfig=process.singleton(q!(config));"));
+        assert!(compact.contains(".cross_singleton(args).cross_singleton(config).map(q!(|((_,args),config)|{"));
+
+        // The example loads it once the command line says where from
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("usestd::fs;"));
+        assert!(example.contains("usehydro_template::test_config::Args;usehydro_template::test_config::Config;"));
+        assert!(example.contains(
+            "letargs=Args::parse_from(std::env::args().filter(|arg|arg!=\"--quiet\"));letconfig:Config=toml::from_str(&fs::read_to_string(&args.config).unwrap()).unwrap();"
+        ));
+        assert!(example.contains("(&process,&external,args,config"));
+    }
+
+    #[test]
+    fn test_push_loop_becomes_collection() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let mut lengths = Vec::new();
+    for word in ["hydro", "dataflow"] {{
+        lengths.push(word.len());
+    }}
+    println!("{{:?}}", lengths);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_lengths")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(
+            ".source_iter(q!([\"hydro\",\"dataflow\"])).map(q!(|word|word.len())).fold(q!(||Vec::new()),q!(|collected,item|collected.push(item)),);"
+        ));
+        assert!(compact.contains("letmutlengths=collected;"));
+        assert!(compact.contains("stdout_lines.push(format!(\"{:?}\",lengths));"));
+    }
+
+    #[test]
+    fn test_sort_after_collection_happens_on_the_batch() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let mut scores = Vec::new();
+    for score in [42, 7, 19, 7] {{
+        scores.push(score);
+    }}
+    scores.sort();
+    scores.dedup();
+    println!("{{:?}}", scores);
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_scores")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(
+            ".map(q!(|score|score)).sort().fold(q!(||Vec::new()),q!(|collected,item|collected.push(item)),).map(q!(|mutcollected|{collected.dedup();collected}));"
+        ));
+        assert!(!compact.contains("scores.sort()"));
+    }
+
+    #[test]
+    fn test_nested_loop_join_becomes_join_operator() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let users = vec![(1, "alice"), (2, "bob")];
+    let orders = vec![(2, "pen"), (1, "book")];
+    for (id, name) in &users {{
+        for (user, item) in &orders {{
+            if id == user {{
+                println!("{{}} ordered {{}}", name, item);
+            }}
+        }}
+    }}
 }}
 "#).unwrap();
 
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_orders")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("letkey={let(id,name)=&item;(id).clone()};(key,(index,item))"));
+        // The pattern's own `item` doesn't shadow the keyed item
+        assert!(compact.contains("letkey={let(user,item)=&item;(user).clone()};(key,(index,item))"));
+        // The collections are built once, before the items are split by side
+        assert_eq!(compact.matches("letusers=").count(), 1);
+        assert!(compact.contains("letouter:Vec<_>=(&users).into_iter().cloned().enumerate().map(Ok).collect();"));
+        assert!(compact.contains("letinner=items.filter_map(q!(|item|item.err()))"));
+        assert!(compact.contains(".join(inner)"));
+        assert!(compact.contains("matches.sort_by_key(|(order,_,_)|*order);"));
+        assert!(compact.contains("stdout_lines.push(format!(\"{}ordered{}\",name,item));"));
+        assert!(!compact.contains("for(user,item)in&orders"));
+    }
+
+    #[test]
+    fn test_chunks_become_keyed_windows() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        writeln!(temp_file, r#"
+fn main() {{
+    let temperatures = vec![20, 22, 21, 25, 24];
+    for window in temperatures.chunks(2) {{
+        let total: i32 = window.iter().sum();
+        println!("avg {{}}", total / window.len() as i32);
+    }}
+}}
+"#).unwrap();
+
+        let TransformOutput { hydro_function: hydro_fn, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(temp_file.path(), "test_windows")
+            .unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains(".enumerate().map(q!(|(index,item)|(index/2,item))).fold_keyed("));
+        assert!(compact.contains("letwindow=&window_items[..];lines.extend({"));
+        assert!(!compact.contains(".chunks(2)"));
+    }
+
+    #[test]
+    fn test_config_sets_lowering_and_timeout() {
+        let source = "fn main() {\n    vec![1, 2, 3].iter().map(|x| x * 2).for_each(|x| println!(\"{}\", x));\n}\n";
+        let file = parse_file(source).unwrap();
+
+        let full = SynLegacyToHydroTransformer::new();
+        assert_eq!(full.translation(&file).unwrap(), Some("iterator chain"));
+
+        let config = TransformerConfig::new().with_lowering(Lowering::Wrap).with_timeout(Duration::from_secs(5));
+        let wrapped = SynLegacyToHydroTransformer::new().with_config(config);
+        assert_eq!(wrapped.translation(&file).unwrap(), None);
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } =
+            wrapped.transform_source(source, "test_wrapped").unwrap();
+        let compact: String = hydro_fn.split_whitespace().collect();
+        assert!(compact.contains("vec![1,2,3].iter().map(|x|x*2).for_each("));
+        let example: String = example.split_whitespace().collect();
+        assert!(example.contains("timeout(Duration::from_secs(5),async{tokio::select!{signal=done.next()=>Ok(signal),"));
+        assert!(example.contains("tracing::warn!(event=\"timeout\",timeout_secs=5,"));
+    }
+
+    #[test]
+    fn test_registered_rules_come_first() {
+        struct AuditLogRule;
+
+        impl TransformRule for AuditLogRule {
+            fn name(&self) -> &'static str {
+                "audit log"
+            }
+
+            fn applies(&self, _: &SynLegacyToHydroTransformer, program: &LegacyProgram) -> bool {
+                program.body.iter().any(|stmt| stmt.to_token_stream().to_string().contains("AuditLog"))
+            }
+
+            fn generate(
+                &self,
+                transformer: &SynLegacyToHydroTransformer,
+                _: &LegacyProgram,
+                module_name: &str,
+            ) -> Result<(String, String), Box<dyn std::error::Error>> {
+                Ok((format!("// audit log module {}", module_name), transformer.default_example(module_name)?))
+            }
+        }
+
+        // An iterator chain, writing through an in-house wrapper
+        let source = r#"
+fn main() {
+    vec![1, 2, 3].iter().map(|x| x * 2).for_each(|x| AuditLog::record(x));
+}
+"#;
+        let mut temp_file = NamedTempFile::new().unwrap();
+        write!(temp_file, "{}", source).unwrap();
+        let file = parse_file(source).unwrap();
+
         let transformer = SynLegacyToHydroTransformer::new();
-        let result = transformer.transform_program(temp_file.path(), "test_hello");
-        
-        assert!(result.is_ok());
-        let (hydro_fn, example) = result.unwrap();
-        
-        // Check that the generated function contains our expected structure
-        assert!(hydro_fn.contains("pub fn test_hello"));
-        assert!(hydro_fn.contains("singleton"));
-        assert!(hydro_fn.contains("map"));
-        assert!(hydro_fn.contains("println!"));
-        
-        // Check that the example contains deployment code
-        assert!(example.contains("Deployment::new"));
-        assert!(example.contains("test_hello"));
+        assert_eq!(transformer.translation(&file).unwrap(), Some("iterator chain"));
+
+        let transformer = SynLegacyToHydroTransformer::new().with_rule(AuditLogRule);
+        assert_eq!(transformer.translation(&file).unwrap(), Some("audit log"));
+        let TransformOutput { hydro_function: hydro_fn, example_program: example, .. } = transformer.transform_program(temp_file.path(), "audited").unwrap();
+        assert_eq!(hydro_fn, "// audit log module audited");
+        assert!(example.contains("audited::audited"));
+
+        // Programs the rule doesn't apply to are translated as before
+        let other = parse_file("fn main() { vec![1, 2].iter().map(|x| x + 1).for_each(|x| println!(\"{}\", x)); }").unwrap();
+        assert_eq!(transformer.translation(&other).unwrap(), Some("iterator chain"));
     }
 
     #[test]
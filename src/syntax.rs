@@ -0,0 +1,189 @@
+//! Syntax helpers shared by the detectors
+
+use std::collections::HashSet;
+
+use proc_macro2::Span;
+use syn::visit::{self, Visit};
+use syn::{BinOp, Expr, Pat, Stmt};
+
+/// The 1-based line and 0-based column a span starts at in the legacy source, or `None`
+/// for a span created at runtime rather than parsed from source, which reports line 0
+pub(crate) fn span_location(span: Span) -> Option<(usize, usize)> {
+    let start = span.start();
+    (start.line > 0).then_some((start.line, start.column))
+}
+
+/// Whether the expression is the plain variable `name`
+pub(crate) fn is_name(expr: &Expr, name: &str) -> bool {
+    matches!(expr, Expr::Path(path) if path.path.is_ident(name))
+}
+
+/// Whether any statement refers to `name`
+pub(crate) fn mentions(stmts: &[Stmt], name: &str) -> bool {
+    struct Mentions<'n> {
+        name: &'n str,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Mentions<'_> {
+        fn visit_ident(&mut self, ident: &'ast proc_macro2::Ident) {
+            self.found |= ident == self.name;
+        }
+
+        fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+            self.found |= tokens_mention(mac.tokens.clone(), self.name);
+            visit::visit_macro(self, mac);
+        }
+    }
+
+    fn tokens_mention(tokens: proc_macro2::TokenStream, name: &str) -> bool {
+        tokens.into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Ident(ident) => ident == name,
+            proc_macro2::TokenTree::Group(group) => tokens_mention(group.stream(), name),
+            _ => false,
+        })
+    }
+
+    let mut mentions = Mentions { name, found: false };
+    for stmt in stmts {
+        mentions.visit_stmt(stmt);
+    }
+    mentions.found
+}
+
+/// Whether a loop body leaves the loop other than by finishing, which a closure run on
+/// each item can't do. Loops
+/// nested in the body are looked into too: their own `break`s and `continue`s stay inside
+/// them, but a `return`, a `?` or a jump labeled for an enclosing loop still leaves.
+pub(crate) fn leaves_early(body: &[Stmt]) -> bool {
+    #[derive(Default)]
+    struct Jumps {
+        /// How many loops inside the body are around the current expression
+        depth: usize,
+        /// The labels of the loops and blocks inside the body around it
+        labels: Vec<String>,
+        found: bool,
+    }
+
+    impl Jumps {
+        fn leaves(&self, label: Option<&syn::Lifetime>) -> bool {
+            match label {
+                Some(label) => !self.labels.contains(&label.ident.to_string()),
+                None => self.depth == 0,
+            }
+        }
+
+        fn visit_nested(&mut self, label: Option<&syn::Label>, is_loop: bool, expr: &Expr) {
+            if let Some(label) = label {
+                self.labels.push(label.name.ident.to_string());
+            }
+            self.depth += usize::from(is_loop);
+            visit::visit_expr(self, expr);
+            self.depth -= usize::from(is_loop);
+            if label.is_some() {
+                self.labels.pop();
+            }
+        }
+    }
+
+    impl<'ast> Visit<'ast> for Jumps {
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            match expr {
+                Expr::Return(_) | Expr::Try(_) => self.found = true,
+                Expr::Break(jump) => self.found |= self.leaves(jump.label.as_ref()),
+                Expr::Continue(jump) => self.found |= self.leaves(jump.label.as_ref()),
+                Expr::Closure(_) => return,
+                Expr::ForLoop(nested) => return self.visit_nested(nested.label.as_ref(), true, expr),
+                Expr::While(nested) => return self.visit_nested(nested.label.as_ref(), true, expr),
+                Expr::Loop(nested) => return self.visit_nested(nested.label.as_ref(), true, expr),
+                Expr::Block(nested) => return self.visit_nested(nested.label.as_ref(), false, expr),
+                _ => {}
+            }
+            visit::visit_expr(self, expr);
+        }
+
+        // Functions declared in the body return from themselves
+        fn visit_item(&mut self, _: &'ast syn::Item) {}
+    }
+
+    let mut jumps = Jumps::default();
+    for stmt in body {
+        jumps.visit_stmt(stmt);
+    }
+    jumps.found
+}
+
+/// Add the names a pattern binds, returning false if any is `mut`
+pub(crate) fn bindings(pat: &Pat, names: &mut HashSet<String>) -> bool {
+    struct Bindings<'n> {
+        names: &'n mut HashSet<String>,
+        immutable: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Bindings<'_> {
+        fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+            self.immutable &= pat.mutability.is_none();
+            self.names.insert(pat.ident.to_string());
+            visit::visit_pat_ident(self, pat);
+        }
+    }
+
+    let mut bindings = Bindings { names, immutable: true };
+    bindings.visit_pat(pat);
+    bindings.immutable
+}
+
+/// The variable at the root of a place expression like `a.b[i]`, if it is a plain name
+pub(crate) fn place_root(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Path(path) => path.path.get_ident().map(|ident| ident.to_string()),
+        Expr::Field(field) => place_root(&field.base),
+        Expr::Index(index) => place_root(&index.expr),
+        Expr::Paren(paren) => place_root(&paren.expr),
+        Expr::Unary(unary) => place_root(&unary.expr),
+        _ => None,
+    }
+}
+
+/// The expression under any `?`, `.await`, `.unwrap()` or `.expect(..)`
+pub(crate) fn unwrapped(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Try(tried) => unwrapped(&tried.expr),
+        Expr::Await(waited) => unwrapped(&waited.base),
+        Expr::MethodCall(call) if call.method == "unwrap" || call.method == "expect" => unwrapped(&call.receiver),
+        expr => expr,
+    }
+}
+
+/// `+=` and the other operators that assign to their left side
+pub(crate) fn is_compound_assign(op: &BinOp) -> bool {
+    use syn::BinOp::*;
+    matches!(
+        op,
+        AddAssign(_) | SubAssign(_) | MulAssign(_) | DivAssign(_) | RemAssign(_)
+            | BitXorAssign(_) | BitAndAssign(_) | BitOrAssign(_) | ShlAssign(_) | ShrAssign(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jumps_out_of_nested_loops_leave_early() {
+        // Whether the body of the outer loop leaves it early
+        let leaves = |outer: &str| {
+            let outer: syn::ExprForLoop = syn::parse_str(outer).unwrap();
+            leaves_early(&outer.body.stmts)
+        };
+        assert!(leaves("for row in rows { for cell in row { return; } }"));
+        assert!(leaves("'outer: for row in rows { for cell in row { break 'outer; } }"));
+        assert!(leaves("'outer: for row in rows { 'inner: for cell in row { while busy() { continue 'outer; } } }"));
+        assert!(leaves("for row in rows { for cell in row { let n: u32 = cell.parse()?; } }"));
+        // Jumps that stay inside the body, and a closure's own returns
+        assert!(!leaves("for row in rows { for cell in row { if cell.is_empty() { break; } } }"));
+        assert!(!leaves("for row in rows { 'inner: for cell in row { loop { continue 'inner; } } }"));
+        assert!(!leaves("for row in rows { let first = 'found: { if row.is_empty() { break 'found None; } row.first() }; }"));
+        assert!(!leaves("for row in rows { let lens = row.iter().map(|cell| { return cell.len(); }); }"));
+    }
+}
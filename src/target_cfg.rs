@@ -0,0 +1,325 @@
+use std::collections::BTreeSet;
+
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{Attribute, Expr, Lit, Meta};
+
+use crate::error::TransformError;
+
+/// The configuration options of the platform a program is migrated for, which its
+/// `#[cfg]` attributes and `cfg!` invocations are resolved against, as rustc resolves
+/// them against `--cfg` flags
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TargetCfg {
+    /// Options set by name, like `unix`
+    names: BTreeSet<String>,
+    /// Options set to a value, like `target_os = "linux"`
+    pairs: BTreeSet<(String, String)>,
+}
+
+impl TargetCfg {
+    /// Read the options from a comma-separated list like `unix,target_os=linux`. Values
+    /// may be quoted, and `unix` and `windows` also set the `target_family` they name,
+    /// as rustc sets both.
+    pub fn parse(spec: &str) -> Result<Self, TransformError> {
+        let mut cfg = Self::default();
+        for option in spec.split(',').map(str::trim).filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some((key, value)) => {
+                    let (key, value) = (key.trim(), value.trim().trim_matches('"'));
+                    if key.is_empty() || value.is_empty() {
+                        return Err(TransformError::UnsupportedConstruct(format!("cfg option `{}` needs a key and a value", option)));
+                    }
+                    cfg.pairs.insert((key.to_string(), value.to_string()));
+                    if key == "target_family" && (value == "unix" || value == "windows") {
+                        cfg.names.insert(value.to_string());
+                    }
+                }
+                None => {
+                    if option == "unix" || option == "windows" {
+                        cfg.pairs.insert(("target_family".to_string(), option.to_string()));
+                    }
+                    cfg.names.insert(option.to_string());
+                }
+            }
+        }
+        Ok(cfg)
+    }
+
+    /// Whether a cfg predicate, like `all(unix, not(target_os = "macos"))`, holds
+    pub fn holds(&self, predicate: &Meta) -> bool {
+        match predicate {
+            Meta::Path(path) => path.get_ident().is_some_and(|name| self.names.contains(&name.to_string())),
+            Meta::NameValue(pair) => match (pair.path.get_ident(), &pair.value) {
+                (Some(key), Expr::Lit(value)) => match &value.lit {
+                    Lit::Str(value) => self.pairs.contains(&(key.to_string(), value.value())),
+                    _ => false,
+                },
+                _ => false,
+            },
+            Meta::List(list) => {
+                let Ok(nested) = list.parse_args_with(Punctuated::<Meta, syn::Token![,]>::parse_terminated) else {
+                    return false;
+                };
+                if list.path.is_ident("all") {
+                    nested.iter().all(|predicate| self.holds(predicate))
+                } else if list.path.is_ident("any") {
+                    nested.iter().any(|predicate| self.holds(predicate))
+                } else if list.path.is_ident("not") {
+                    nested.len() == 1 && !self.holds(&nested[0])
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// A legacy file as it compiles for this target: the items, statements, fields, match
+    /// arms and other code whose `#[cfg]` doesn't hold are dropped, the attributes of
+    /// `#[cfg_attr]` whose predicate holds are applied, and `cfg!` becomes `true` or
+    /// `false`. What is left has no cfg attributes.
+    pub fn resolve(&self, file: &syn::File) -> syn::File {
+        let mut file = file.clone();
+        Resolve { cfg: self }.visit_file_mut(&mut file);
+        file
+    }
+
+    /// The options as `parse` reads them, sorted
+    pub fn to_spec(&self) -> String {
+        let names = self.names.iter().cloned();
+        let pairs = self.pairs.iter().map(|(key, value)| format!("{}={}", key, value));
+        names.chain(pairs).collect::<Vec<_>>().join(",")
+    }
+}
+
+/// Drops the code whose cfg doesn't hold for the target
+struct Resolve<'c> {
+    cfg: &'c TargetCfg,
+}
+
+impl Resolve<'_> {
+    /// Whether code with these attributes is compiled for the target, leaving them
+    /// without the cfg attributes when it is
+    fn keep(&self, attrs: &mut Vec<Attribute>) -> bool {
+        let mut resolved = Vec::with_capacity(attrs.len());
+        for attr in attrs.drain(..) {
+            let Meta::List(list) = &attr.meta else {
+                resolved.push(attr);
+                continue;
+            };
+            if list.path.is_ident("cfg") {
+                match list.parse_args::<Meta>() {
+                    Ok(predicate) if self.cfg.holds(&predicate) => {}
+                    Ok(_) => return false,
+                    // A predicate that doesn't parse is left for the compiler
+                    Err(_) => resolved.push(attr),
+                }
+            } else if list.path.is_ident("cfg_attr") {
+                let Ok(args) = list.parse_args_with(Punctuated::<Meta, syn::Token![,]>::parse_terminated) else {
+                    resolved.push(attr);
+                    continue;
+                };
+                let mut args = args.into_iter();
+                if args.next().is_some_and(|predicate| self.cfg.holds(&predicate)) {
+                    resolved.extend(args.map(|meta| Attribute { meta, ..attr.clone() }));
+                }
+            } else {
+                resolved.push(attr);
+            }
+        }
+        *attrs = resolved;
+        true
+    }
+}
+
+impl VisitMut for Resolve<'_> {
+    fn visit_file_mut(&mut self, file: &mut syn::File) {
+        file.items.retain_mut(|item| item_attrs(item).is_none_or(|attrs| self.keep(attrs)));
+        visit_mut::visit_file_mut(self, file);
+    }
+
+    fn visit_item_mod_mut(&mut self, module: &mut syn::ItemMod) {
+        if let Some((_, items)) = &mut module.content {
+            items.retain_mut(|item| item_attrs(item).is_none_or(|attrs| self.keep(attrs)));
+        }
+        visit_mut::visit_item_mod_mut(self, module);
+    }
+
+    fn visit_item_impl_mut(&mut self, imp: &mut syn::ItemImpl) {
+        imp.items.retain_mut(|item| match item {
+            syn::ImplItem::Const(item) => self.keep(&mut item.attrs),
+            syn::ImplItem::Fn(item) => self.keep(&mut item.attrs),
+            syn::ImplItem::Type(item) => self.keep(&mut item.attrs),
+            syn::ImplItem::Macro(item) => self.keep(&mut item.attrs),
+            _ => true,
+        });
+        visit_mut::visit_item_impl_mut(self, imp);
+    }
+
+    fn visit_item_trait_mut(&mut self, trait_item: &mut syn::ItemTrait) {
+        trait_item.items.retain_mut(|item| match item {
+            syn::TraitItem::Const(item) => self.keep(&mut item.attrs),
+            syn::TraitItem::Fn(item) => self.keep(&mut item.attrs),
+            syn::TraitItem::Type(item) => self.keep(&mut item.attrs),
+            syn::TraitItem::Macro(item) => self.keep(&mut item.attrs),
+            _ => true,
+        });
+        visit_mut::visit_item_trait_mut(self, trait_item);
+    }
+
+    fn visit_fields_named_mut(&mut self, fields: &mut syn::FieldsNamed) {
+        fields.named = std::mem::take(&mut fields.named).into_iter().filter_map(|mut field| self.keep(&mut field.attrs).then_some(field)).collect();
+        visit_mut::visit_fields_named_mut(self, fields);
+    }
+
+    fn visit_item_enum_mut(&mut self, item: &mut syn::ItemEnum) {
+        item.variants = std::mem::take(&mut item.variants).into_iter().filter_map(|mut variant| self.keep(&mut variant.attrs).then_some(variant)).collect();
+        visit_mut::visit_item_enum_mut(self, item);
+    }
+
+    fn visit_block_mut(&mut self, block: &mut syn::Block) {
+        block.stmts.retain_mut(|stmt| match stmt {
+            syn::Stmt::Local(local) => self.keep(&mut local.attrs),
+            syn::Stmt::Item(item) => item_attrs(item).is_none_or(|attrs| self.keep(attrs)),
+            syn::Stmt::Expr(expr, _) => expr_attrs(expr).is_none_or(|attrs| self.keep(attrs)),
+            syn::Stmt::Macro(mac) => self.keep(&mut mac.attrs),
+        });
+        visit_mut::visit_block_mut(self, block);
+    }
+
+    fn visit_expr_match_mut(&mut self, expr: &mut syn::ExprMatch) {
+        expr.arms.retain_mut(|arm| self.keep(&mut arm.attrs));
+        visit_mut::visit_expr_match_mut(self, expr);
+    }
+
+    fn visit_expr_struct_mut(&mut self, expr: &mut syn::ExprStruct) {
+        expr.fields = std::mem::take(&mut expr.fields).into_iter().filter_map(|mut field| self.keep(&mut field.attrs).then_some(field)).collect();
+        visit_mut::visit_expr_struct_mut(self, expr);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(mac) = expr {
+            if mac.mac.path.is_ident("cfg") {
+                if let Ok(predicate) = mac.mac.parse_body::<Meta>() {
+                    let holds = self.cfg.holds(&predicate);
+                    *expr = syn::parse_quote! { #holds };
+                    return;
+                }
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// The attributes of an item, when it can have any
+fn item_attrs(item: &mut syn::Item) -> Option<&mut Vec<Attribute>> {
+    use syn::Item;
+    match item {
+        Item::Const(item) => Some(&mut item.attrs),
+        Item::Enum(item) => Some(&mut item.attrs),
+        Item::ExternCrate(item) => Some(&mut item.attrs),
+        Item::Fn(item) => Some(&mut item.attrs),
+        Item::ForeignMod(item) => Some(&mut item.attrs),
+        Item::Impl(item) => Some(&mut item.attrs),
+        Item::Macro(item) => Some(&mut item.attrs),
+        Item::Mod(item) => Some(&mut item.attrs),
+        Item::Static(item) => Some(&mut item.attrs),
+        Item::Struct(item) => Some(&mut item.attrs),
+        Item::Trait(item) => Some(&mut item.attrs),
+        Item::TraitAlias(item) => Some(&mut item.attrs),
+        Item::Type(item) => Some(&mut item.attrs),
+        Item::Union(item) => Some(&mut item.attrs),
+        Item::Use(item) => Some(&mut item.attrs),
+        _ => None,
+    }
+}
+
+/// The attributes of an expression standing as a statement, for the kinds that take
+/// them there
+fn expr_attrs(expr: &mut Expr) -> Option<&mut Vec<Attribute>> {
+    match expr {
+        Expr::Block(expr) => Some(&mut expr.attrs),
+        Expr::Call(expr) => Some(&mut expr.attrs),
+        Expr::ForLoop(expr) => Some(&mut expr.attrs),
+        Expr::If(expr) => Some(&mut expr.attrs),
+        Expr::Loop(expr) => Some(&mut expr.attrs),
+        Expr::Macro(expr) => Some(&mut expr.attrs),
+        Expr::Match(expr) => Some(&mut expr.attrs),
+        Expr::MethodCall(expr) => Some(&mut expr.attrs),
+        Expr::Unsafe(expr) => Some(&mut expr.attrs),
+        Expr::While(expr) => Some(&mut expr.attrs),
+        Expr::Assign(expr) => Some(&mut expr.attrs),
+        Expr::Path(expr) => Some(&mut expr.attrs),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    const PLATFORM: &str = r#"
+#[cfg(unix)]
+const SEPARATOR: char = '/';
+#[cfg(windows)]
+const SEPARATOR: char = '\\';
+
+struct Paths {
+    root: String,
+    #[cfg(windows)]
+    drive: char,
+}
+
+fn main() {
+    #[cfg(target_os = "linux")]
+    let name = "linux";
+    #[cfg(not(target_os = "linux"))]
+    let name = "other";
+    #[cfg_attr(unix, allow(unused_variables))]
+    let home = std::env::var("HOME");
+    match name {
+        #[cfg(all(unix, not(target_os = "macos")))]
+        "linux" => println!("{}", SEPARATOR),
+        _ => {}
+    }
+    if cfg!(windows) {
+        println!("windows");
+    }
+}
+
+#[cfg(test)]
+mod tests {}
+"#;
+
+    #[test]
+    fn test_cfg_is_resolved_for_the_target() {
+        let file: syn::File = syn::parse_str(PLATFORM).unwrap();
+        let linux = TargetCfg::parse("unix, target_os=\"linux\"").unwrap();
+        let resolved: String = linux.resolve(&file).to_token_stream().to_string().split_whitespace().collect();
+
+        assert!(resolved.contains("constSEPARATOR:char='/';structPaths{root:String}"));
+        assert!(!resolved.contains("'\\\\'"));
+        assert!(resolved.contains("letname=\"linux\";#[allow(unused_variables)]lethome"));
+        assert!(resolved.contains("\"linux\"=>println!"));
+        assert!(resolved.contains("iffalse{"));
+        // What isn't set doesn't hold, tests included
+        assert!(!resolved.contains("modtests"));
+        assert!(!resolved.contains("cfg"));
+
+        let windows = TargetCfg::parse("windows").unwrap();
+        let resolved: String = windows.resolve(&file).to_token_stream().to_string().split_whitespace().collect();
+        assert!(resolved.contains("drive:char"));
+        assert!(resolved.contains("letname=\"other\";lethome"));
+        assert!(resolved.contains("_=>{}}iftrue{"));
+        assert!(!resolved.contains("\"linux\"=>"));
+    }
+
+    #[test]
+    fn test_specs_round_trip() {
+        let cfg = TargetCfg::parse("unix,target_os=linux").unwrap();
+        assert_eq!(cfg.to_spec(), "unix,target_family=unix,target_os=linux");
+        assert_eq!(TargetCfg::parse(&cfg.to_spec()).unwrap(), cfg);
+        assert!(TargetCfg::parse("target_os=").is_err());
+    }
+}
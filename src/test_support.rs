@@ -0,0 +1,38 @@
+//! Fixtures shared by the detectors' tests
+
+use quote::ToTokens;
+use syn::{Item, Stmt};
+
+use crate::output::main_body;
+
+/// The statements of a `{ ... }` block
+pub(crate) fn parse_block(body: &str) -> Vec<Stmt> {
+    syn::parse_str::<syn::Block>(body).unwrap().stmts
+}
+
+/// The items of a whole program and the statements of its `main`
+pub(crate) fn parse_main(source: &str) -> Option<(Vec<Item>, Vec<Stmt>)> {
+    let file: syn::File = syn::parse_str(source).unwrap();
+    let main = main_body(&file)?.to_vec();
+    Some((file.items, main))
+}
+
+/// Tokens printed without whitespace, so expectations don't depend on the printer's spacing
+pub(crate) fn compact(tokens: impl ToTokens) -> String {
+    tokens.to_token_stream().to_string().split_whitespace().collect()
+}
+
+/// Generated source without whitespace, like [`compact`] for code already printed
+pub(crate) fn compact_str(code: &str) -> String {
+    code.split_whitespace().collect()
+}
+
+/// Statements printed one after another by [`compact`]
+pub(crate) fn compact_stmts(stmts: &[Stmt]) -> String {
+    stmts.iter().map(compact).collect()
+}
+
+/// Each statement printed by [`compact`]
+pub(crate) fn compact_each(stmts: &[Stmt]) -> Vec<String> {
+    stmts.iter().map(compact).collect()
+}
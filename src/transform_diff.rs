@@ -0,0 +1,215 @@
+use std::fmt::Write;
+
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+use crate::error::TransformError;
+use crate::output::{main_body, TransformOutput};
+
+/// A line of a legacy statement carried over as written, and where it went
+#[derive(Debug, Clone, PartialEq)]
+pub struct CarriedLine {
+    /// 1-based line in the legacy source
+    pub legacy_line: usize,
+    /// 1-based line in the generated module
+    pub generated_line: usize,
+    /// The operator whose closure the line runs in, or `None` outside any operator
+    pub operator: Option<String>,
+}
+
+/// A statement of the legacy `main`, and what it became
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementAudit {
+    /// 1-based first and last line of the statement in the legacy source
+    pub legacy_lines: (usize, usize),
+    /// The statement as written
+    pub legacy_text: String,
+    /// The lines of the statement carried over token for token. A statement with none
+    /// was rewritten, as prints turned into output are.
+    pub carried: Vec<CarriedLine>,
+}
+
+impl StatementAudit {
+    /// The operators the statement's carried lines run in, in order, without repeats
+    pub fn operators(&self) -> Vec<&str> {
+        let mut operators: Vec<&str> = Vec::new();
+        for operator in self.carried.iter().filter_map(|line| line.operator.as_deref()) {
+            if !operators.contains(&operator) {
+                operators.push(operator);
+            }
+        }
+        operators
+    }
+}
+
+/// How the statements of a legacy `main` map onto the generated module, read off the
+/// source map, so a transformation can be reviewed statement by statement
+#[derive(Debug, Clone)]
+pub struct TransformDiff {
+    pub statements: Vec<StatementAudit>,
+    generated: Vec<String>,
+}
+
+impl TransformDiff {
+    /// Audit the output a backend produced for the legacy `source`
+    pub fn new(source: &str, output: &TransformOutput) -> Result<Self, TransformError> {
+        let file = syn::parse_file(source)?;
+        let body = main_body(&file).ok_or(TransformError::NoMainFunction)?;
+        let legacy: Vec<&str> = source.lines().collect();
+        let calls = OperatorCalls::of(&output.hydro_function);
+
+        let statements = body
+            .iter()
+            .map(|stmt| {
+                let (start, end) = (stmt.span().start().line, stmt.span().end().line);
+                let carried = output
+                    .source_map
+                    .mappings
+                    .iter()
+                    .filter(|mapping| (start..=end).contains(&mapping.legacy_line))
+                    .map(|mapping| CarriedLine {
+                        legacy_line: mapping.legacy_line,
+                        generated_line: mapping.generated_line,
+                        operator: calls.innermost(mapping.generated_line),
+                    })
+                    .collect();
+                let legacy_text = legacy.get(start.saturating_sub(1)..end.min(legacy.len())).unwrap_or_default().join("\n");
+                StatementAudit { legacy_lines: (start, end), legacy_text, carried }
+            })
+            .collect();
+        Ok(Self { statements, generated: output.hydro_function.lines().map(str::to_string).collect() })
+    }
+
+    /// A unified diff with a hunk per legacy statement: the statement as removed lines,
+    /// then the lines carried from it as added ones, under a header naming the operators
+    pub fn render_unified(&self, legacy_name: &str, generated_name: &str) -> String {
+        let mut diff = format!("--- {}\n+++ {}\n", legacy_name, generated_name);
+        for statement in &self.statements {
+            let (start, end) = statement.legacy_lines;
+            let operators = statement.operators();
+            let became = if statement.carried.is_empty() {
+                "rewritten".to_string()
+            } else if operators.is_empty() {
+                "carried over".to_string()
+            } else {
+                format!("in {}", operators.iter().map(|operator| format!("`{}`", operator)).collect::<Vec<_>>().join(", "))
+            };
+            let generated = match (statement.carried.first(), statement.carried.last()) {
+                (Some(first), Some(last)) => format!("+{},{}", first.generated_line, last.generated_line - first.generated_line + 1),
+                _ => "+0,0".to_string(),
+            };
+            writeln!(diff, "@@ -{},{} {} @@ {}", start, end - start + 1, generated, became).unwrap();
+            for line in statement.legacy_text.lines() {
+                writeln!(diff, "-{}", line).unwrap();
+            }
+            for carried in &statement.carried {
+                writeln!(diff, "+{}", self.generated_line(carried.generated_line)).unwrap();
+            }
+        }
+        diff
+    }
+
+    /// Each legacy statement on the left, and on the right the generated lines carried
+    /// from it with the operator each runs in, or what became of it
+    pub fn render_side_by_side(&self, width: usize) -> String {
+        let mut diff = String::new();
+        for statement in &self.statements {
+            let legacy: Vec<String> = statement
+                .legacy_text
+                .lines()
+                .zip(statement.legacy_lines.0..)
+                .map(|(line, number)| format!("{:>4} {}", number, line))
+                .collect();
+            let generated: Vec<String> = if statement.carried.is_empty() {
+                vec!["       (rewritten, not carried over as written)".to_string()]
+            } else {
+                statement
+                    .carried
+                    .iter()
+                    .map(|carried| {
+                        let operator = carried.operator.as_deref().map_or(String::new(), |operator| format!("[{}] ", operator));
+                        format!("{:>4} {}{}", carried.generated_line, operator, self.generated_line(carried.generated_line).trim())
+                    })
+                    .collect()
+            };
+            for row in 0..legacy.len().max(generated.len()) {
+                let left = legacy.get(row).map_or("", String::as_str);
+                let right = generated.get(row).map_or("", String::as_str);
+                let left: String = left.chars().take(width).collect();
+                writeln!(diff, "{:<width$} | {}", left, right, width = width).unwrap();
+            }
+            writeln!(diff, "{}-+-{}", "-".repeat(width), "-".repeat(width)).unwrap();
+        }
+        diff
+    }
+
+    fn generated_line(&self, line: usize) -> &str {
+        self.generated.get(line.wrapping_sub(1)).map_or("", String::as_str)
+    }
+}
+
+/// The operator calls of a generated module, with the lines their arguments span, which
+/// is where their closures are
+struct OperatorCalls {
+    calls: Vec<(String, usize, usize)>,
+}
+
+impl OperatorCalls {
+    fn of(module: &str) -> Self {
+        let mut calls = OperatorCalls { calls: Vec::new() };
+        if let Ok(file) = syn::parse_file(module) {
+            calls.visit_file(&file);
+        }
+        calls
+    }
+
+    /// The operator with the narrowest arguments around a generated line
+    fn innermost(&self, line: usize) -> Option<String> {
+        self.calls
+            .iter()
+            .filter(|(_, start, end)| (*start..=*end).contains(&line))
+            .min_by_key(|(_, start, end)| end - start)
+            .map(|(name, _, _)| name.clone())
+    }
+}
+
+impl<'ast> Visit<'ast> for OperatorCalls {
+    fn visit_expr_method_call(&mut self, call: &'ast syn::ExprMethodCall) {
+        // Operators are the calls taking quoted closures; other calls are plain code
+        let quoted = call.args.iter().any(|arg| matches!(arg, syn::Expr::Macro(mac) if mac.mac.path.is_ident("q")));
+        if quoted {
+            let parens = call.paren_token.span;
+            self.calls.push((call.method.to_string(), parens.open().start().line, parens.close().end().line));
+        }
+        visit::visit_expr_method_call(self, call);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    #[test]
+    fn test_statements_are_traced_to_their_operators() {
+        let source = "fn main() {\n    let total: u64 = (1..=4).sum();\n    println!(\"{}\", total);\n}\n";
+        let output = SynLegacyToHydroTransformer::new().transform_source(source, "test_total").unwrap();
+        let diff = TransformDiff::new(source, &output).unwrap();
+
+        let [sum, print] = diff.statements.as_slice() else { panic!("expected two statements") };
+        assert_eq!(sum.legacy_lines, (2, 2));
+        assert_eq!(sum.carried.len(), 1);
+        assert_eq!(sum.operators(), ["map"]);
+        assert_eq!(print.legacy_text, "    println!(\"{}\", total);");
+        assert!(print.carried.is_empty());
+
+        let unified = diff.render_unified("legacy/total.rs", "src/test_total.rs");
+        assert!(unified.starts_with("--- legacy/total.rs\n+++ src/test_total.rs\n@@ -2,1 +"));
+        assert!(unified.contains("@@ in `map`\n-    let total: u64 = (1..=4).sum();\n+"));
+        assert!(unified.contains("@@ -3,1 +0,0 @@ rewritten\n-    println!"));
+
+        let side_by_side = diff.render_side_by_side(40);
+        assert!(side_by_side.contains("   3     println!(\"{}\", total);          |        (rewritten"));
+        assert!(side_by_side.contains("   2     let total: u64 = (1..=4).sum(); |   10 [map] let total: u64 = (1..=4).sum();"));
+    }
+}
@@ -0,0 +1,66 @@
+use syn::{Item, Stmt};
+
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+/// A legacy program as the transformation rules see it
+pub struct LegacyProgram<'a> {
+    /// The items of the legacy file, `main` included
+    pub items: &'a [Item],
+    /// The body of `main`, with writes through stdout and stderr handles rewritten into
+    /// prints and, when requested, its randomness drawn from a seeded RNG
+    pub body: &'a [Stmt],
+    /// The classification of each top-level loop of the body
+    pub loops: &'a [LoopAnalysis],
+}
+
+impl LegacyProgram<'_> {
+    /// Whether the body has a top-level loop of the given shape
+    pub fn has_loop(&self, kind: LoopKind) -> bool {
+        self.loops.iter().any(|analysis| analysis.kind == kind)
+    }
+}
+
+/// One translation of legacy programs into Hydro by the syn backend. The transformer
+/// tries its rules in order, and the first that applies to a program generates its
+/// module and example. The I/O backend doesn't consult them.
+///
+/// Rules registered with `SynLegacyToHydroTransformer::with_rule` are tried before the
+/// built-in ones, so a translation for an in-house I/O wrapper can be added without
+/// changing the crate:
+///
+/// ```ignore
+/// struct AuditLogRule;
+///
+/// impl TransformRule for AuditLogRule {
+///     fn name(&self) -> &'static str {
+///         "audit log"
+///     }
+///
+///     fn applies(&self, _: &SynLegacyToHydroTransformer, program: &LegacyProgram) -> bool {
+///         program.body.iter().any(|stmt| stmt.to_token_stream().to_string().contains("AuditLog"))
+///     }
+///
+///     fn generate(&self, transformer: &SynLegacyToHydroTransformer, program: &LegacyProgram, module_name: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
+///         Ok((audit_log_module(program, module_name), transformer.default_example(module_name)?))
+///     }
+/// }
+///
+/// let transformer = SynLegacyToHydroTransformer::new().with_rule(AuditLogRule);
+/// ```
+pub trait TransformRule {
+    /// The name of the translation, as `SynLegacyToHydroTransformer::translation`
+    /// reports it
+    fn name(&self) -> &'static str;
+
+    /// Whether the rule translates the program
+    fn applies(&self, transformer: &SynLegacyToHydroTransformer, program: &LegacyProgram) -> bool;
+
+    /// The generated module and example for a program the rule applies to
+    fn generate(
+        &self,
+        transformer: &SynLegacyToHydroTransformer,
+        program: &LegacyProgram,
+        module_name: &str,
+    ) -> Result<(String, String), Box<dyn std::error::Error>>;
+}
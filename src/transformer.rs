@@ -1,35 +1,71 @@
 use std::fs;
 use std::path::Path;
 
-pub struct LegacyToHydroTransformer;
+use crate::backend::{Capabilities, Transformer};
+use crate::config::TransformerConfig;
+use crate::error::TransformError;
+use crate::formatting::format_wrapped_module;
+use crate::output::TransformOutput;
+
+pub struct LegacyToHydroTransformer {
+    /// The options shared by all backends, of which this one honors the timeout
+    config: TransformerConfig,
+}
 
 impl LegacyToHydroTransformer {
     pub fn new() -> Self {
-        Self
+        Self { config: TransformerConfig::new() }
+    }
+
+    /// Use the given options, replacing any set before
+    pub fn with_config(mut self, config: TransformerConfig) -> Self {
+        self.config = config;
+        self
     }
 
-    pub fn transform_program(&self, input_path: &Path, output_name: &str) -> Result<(String, String), Box<dyn std::error::Error>> {
+    pub fn transform_program(&self, input_path: &Path, output_name: &str) -> Result<TransformOutput, TransformError> {
         let legacy_code = fs::read_to_string(input_path)?;
-        let main_body = self.extract_main_body(&legacy_code)?;
+        self.transform_source(&legacy_code, output_name)
+    }
+
+    /// Transform legacy source code held in memory, as `transform_program` does the
+    /// contents of a file
+    pub fn transform_source(&self, legacy_code: &str, output_name: &str) -> Result<TransformOutput, TransformError> {
+        let main_body = self.extract_main_body(legacy_code)?;
         
         let hydro_function = self.generate_hydro_function(&main_body, output_name)?;
         let example_program = self.generate_example_program(output_name)?;
         
-        Ok((hydro_function, example_program))
+        // The text is transformed whether it parses or not, but only a program that
+        // parses is analyzed
+        let file = syn::parse_file(legacy_code).unwrap_or(syn::File { shebang: None, attrs: Vec::new(), items: Vec::new() });
+        // A module that parses is laid out like the other backends' ones
+        let hydro_function = format_wrapped_module(&file, &hydro_function);
+        match Transformer::analyze(self, &file) {
+            Ok(analysis) => Ok(TransformOutput::from_analysis(&file, analysis, hydro_function, example_program)),
+            // Without a parsed `main` there is nothing to analyze
+            Err(_) => Ok(TransformOutput::new(&file, hydro_function, example_program)),
+        }
+    }
+
+    /// Transform an already parsed legacy program. This backend works on the text of the
+    /// program, so the file is printed back to source first.
+    pub fn transform_file(&self, file: &syn::File, output_name: &str) -> Result<TransformOutput, TransformError> {
+        self.transform_source(&prettyplease::unparse(file), output_name)
     }
 
     fn generate_hydro_function(&self, main_body: &str, function_name: &str) -> Result<String, Box<dyn std::error::Error>> {
         let hydro_function = format!(
 r#"use hydro_lang::*;
+use hydro_lang::location::external_process::ExternalBincodeStream;
 
-pub fn {}(process: &Process) {{
+pub fn {}<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {{
     process
         .source_iter(q!(std::iter::once(())))
         .map(q!(|_| {{
-            // Legacy main function body wrapped in Hydro map operator
 {}
         }}))
-        .for_each(q!(|_| {{}}));
+        .send_bincode_external(external)
 }}"#, 
             function_name,
             self.indent_code(main_body, 12)
@@ -40,7 +76,8 @@ pub fn {}(process: &Process) {{
 
     fn generate_example_program(&self, function_name: &str) -> Result<String, Box<dyn std::error::Error>> {
         let example = format!(
-r#"use hydro_deploy::Deployment;
+r#"use futures::StreamExt;
+use hydro_deploy::Deployment;
 use tokio::time::{{timeout, Duration}};
 
 #[tokio::main]
@@ -49,25 +86,32 @@ async fn main() {{
 
     let flow = hydro_lang::FlowBuilder::new();
     let process = flow.process();
-    hydro_template::{}::{}(&process);
+    let external = flow.external_process();
+    let done_port = hydro_template::{}::{}(&process, &external);
 
-    let _nodes = flow
+    let nodes = flow
         .with_process(&process, deployment.Localhost())
+        .with_external(&external, deployment.Localhost())
         .deploy(&mut deployment);
 
-    // Run for 10 seconds then exit
-    match timeout(Duration::from_secs(10), deployment.run_ctrl_c()).await {{
-        Ok(_) => println!("Program completed normally"),
-        Err(_) => println!("Program timed out after 10 seconds"),
+    deployment.deploy().await.unwrap();
+    let mut done = nodes.connect_source_bincode(done_port).await;
+    deployment.start().await.unwrap();
+
+    // Exit as soon as the dataflow signals completion
+    match timeout(Duration::from_secs({}), done.next()).await {{
+        Ok(Some(())) => println!("Program completed normally"),
+        Ok(None) => println!("Program stopped without signaling completion"),
+        Err(_) => println!("Program timed out after {} seconds"),
     }}
 }}"#, 
-            function_name, function_name
+            function_name, function_name, self.config.timeout.as_secs(), self.config.timeout.as_secs()
         );
         
         Ok(example)
     }
 
-    fn extract_main_body(&self, code: &str) -> Result<String, Box<dyn std::error::Error>> {
+    fn extract_main_body(&self, code: &str) -> Result<String, TransformError> {
         // Find the main function and extract its body
         let lines: Vec<&str> = code.lines().collect();
         let mut in_main = false;
@@ -115,7 +159,7 @@ async fn main() {{
         }
         
         if main_body_lines.is_empty() {
-            return Err("Could not find main function body in legacy code".into());
+            return Err(TransformError::NoMainFunction);
         }
         
         Ok(main_body_lines.join("\n"))
@@ -134,6 +178,20 @@ async fn main() {{
     }
 }
 
+impl Transformer for LegacyToHydroTransformer {
+    fn name(&self) -> &'static str {
+        "string"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { parses: false, rewrites_output: false, streams_stdin: false, seeds_rng: false, translations: Vec::new() }
+    }
+
+    fn transform_source(&self, legacy_code: &str, output_name: &str) -> Result<TransformOutput, TransformError> {
+        LegacyToHydroTransformer::transform_source(self, legacy_code, output_name)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,19 +205,21 @@ mod tests {
         let result = transformer.transform_program(legacy_path, "hello_world_hydro");
         assert!(result.is_ok(), "Transform should succeed");
         
-        let (hydro_function, example_program) = result.unwrap();
+        let TransformOutput { hydro_function, example_program, .. } = result.unwrap();
         
         // Check that the hydro function contains expected structure
         assert!(hydro_function.contains("use hydro_lang::*"));
         assert!(hydro_function.contains("pub fn hello_world_hydro"));
         assert!(hydro_function.contains("source_iter"));
         assert!(hydro_function.contains("map"));
-        assert!(hydro_function.contains("for_each"));
+        assert!(hydro_function.contains("send_bincode_external"));
         assert!(hydro_function.contains("println!(\"Hello, world!\")"));
         
         // Check that the example program has the right structure
         assert!(example_program.contains("use hydro_deploy::Deployment"));
         assert!(example_program.contains("hydro_template::hello_world_hydro::hello_world_hydro"));
+        // The example waits for the completion signal rather than a fixed timeout
+        assert!(example_program.contains("connect_source_bincode(done_port)"));
     }
 
     #[test]
@@ -170,7 +230,7 @@ mod tests {
         let result = transformer.transform_program(legacy_path, "counter_hydro");
         assert!(result.is_ok(), "Transform should succeed");
         
-        let (hydro_function, _) = result.unwrap();
+        let TransformOutput { hydro_function, .. } = result.unwrap();
         
         // Check that it wrapped the for loop in a map operator
         assert!(hydro_function.contains("map"));
@@ -0,0 +1,187 @@
+use proc_macro2::Span;
+use quote::quote;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Expr, Ident, Item, Stmt};
+
+use crate::captures::free_variables;
+use crate::error::TransformError;
+use crate::report::{Construct, TransformReport};
+
+/// Fail the transformation of a program whose report found unsafe code, naming the first
+pub fn refuse_unsafe(report: &TransformReport) -> Result<(), TransformError> {
+    let Some(first) = report.diagnostics.iter().find(|diagnostic| diagnostic.construct == Construct::Unsafe) else {
+        return Ok(());
+    };
+    let location = first.line_number.map_or(String::new(), |line| format!(" at line {}", line));
+    Err(TransformError::UnsupportedConstruct(format!(
+        "{}{}, which the transformation was asked to refuse; {}",
+        first.description,
+        location,
+        Construct::Unsafe.suggestion()
+    )))
+}
+
+/// Move the unsafe blocks the operators of a generated module run for their effects
+/// alone out of the quoted code. Each becomes a function of the module, marked as the
+/// legacy program's unsafe code, and an `inspect` stage ahead of the operator calls it
+/// for every item the operator handles. A block is only moved when it is a statement of
+/// the operator's closure, uses no variable at all and doesn't return, break or `?` out
+/// of the closure; the others are left as they were written.
+pub fn isolate_unsafe(module: &str, module_name: &str) -> Result<String, TransformError> {
+    let mut file = syn::parse_file(module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+    let mut stages = Stages { module: Ident::new(module_name, Span::call_site()), effects: Vec::new() };
+    for item in &mut file.items {
+        if let Item::Fn(func) = item {
+            stages.visit_block_mut(&mut func.block);
+        }
+    }
+    if stages.effects.is_empty() {
+        return Ok(module.to_string());
+    }
+
+    for (index, block) in stages.effects.into_iter().enumerate() {
+        let name = effect_name(index);
+        file.items.push(parse_quote! {
+            /// An unsafe block of the legacy program, run for its effects alone in a stage
+            /// of its own. Its invariants are still the legacy program's to uphold.
+            pub fn #name() {
+                #block
+            }
+        });
+    }
+    Ok(prettyplease::unparse(&file))
+}
+
+fn effect_name(index: usize) -> Ident {
+    Ident::new(&format!("unsafe_effect_{}", index), Span::call_site())
+}
+
+/// Takes the effect-only unsafe blocks out of the closures of operators, calling them
+/// from a stage ahead of each
+struct Stages {
+    module: Ident,
+    /// The blocks taken out, numbered in the order they were found
+    effects: Vec<syn::ExprUnsafe>,
+}
+
+impl VisitMut for Stages {
+    fn visit_expr_method_call_mut(&mut self, call: &mut syn::ExprMethodCall) {
+        visit_mut::visit_expr_method_call_mut(self, call);
+        if call.args.len() != 1 {
+            return;
+        }
+        let Expr::Macro(mac) = &mut call.args[0] else {
+            return;
+        };
+        if !mac.mac.path.is_ident("q") {
+            return;
+        }
+        let Ok(Expr::Closure(mut closure)) = mac.mac.parse_body::<Expr>() else {
+            return;
+        };
+        let Expr::Block(body) = &mut *closure.body else {
+            return;
+        };
+
+        let last = body.block.stmts.len().saturating_sub(1);
+        let mut calls: Vec<Stmt> = Vec::new();
+        let mut index = 0;
+        body.block.stmts.retain(|stmt| {
+            let effect = match stmt {
+                Stmt::Expr(Expr::Unsafe(block), semi) if semi.is_some() || index < last => Some(block),
+                _ => None,
+            };
+            index += 1;
+            let Some(block) = effect.filter(|block| isolable(block)) else {
+                return true;
+            };
+            let (module, name) = (&self.module, effect_name(self.effects.len()));
+            calls.push(parse_quote! { crate::#module::#name(); });
+            self.effects.push(block.clone());
+            false
+        });
+        if calls.is_empty() {
+            return;
+        }
+        mac.mac.tokens = quote! { #closure };
+
+        let receiver = &call.receiver;
+        let staged: Expr = parse_quote! { #receiver.inspect(q!(|_| { #(#calls)* })) };
+        *call.receiver = staged;
+    }
+}
+
+/// Whether an unsafe block can run away from the closure it was written in
+fn isolable(block: &syn::ExprUnsafe) -> bool {
+    let mut exits = Exits::default();
+    exits.visit_block(&block.block);
+    !exits.found && free_variables(&Expr::Unsafe(block.clone())).is_empty()
+}
+
+/// Whether code leaves its enclosing function or loop early
+#[derive(Default)]
+struct Exits {
+    found: bool,
+}
+
+impl<'ast> Visit<'ast> for Exits {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        if matches!(expr, Expr::Return(_) | Expr::Break(_) | Expr::Continue(_) | Expr::Try(_)) {
+            self.found = true;
+        }
+        visit::visit_expr(self, expr);
+    }
+
+    // Format arguments can hold a `?` too
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        if let Ok(args) = mac.parse_body_with(syn::punctuated::Punctuated::<Expr, syn::Token![,]>::parse_terminated) {
+            args.iter().for_each(|arg| self.visit_expr(arg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::compact_str;
+
+    #[test]
+    fn test_effect_only_unsafe_blocks_get_a_stage_of_their_own() {
+        let module = r#"
+pub fn effects<'a>(process: &Process<'a>) {
+    process
+        .source_iter(q!(0..3))
+        .map(q!(|n| {
+            unsafe { crate::effects::reset_counters(); };
+            let doubled = unsafe { double(n) };
+            unsafe { println!("{}", n); }
+            doubled
+        }))
+        .for_each(q!(|n| println!("{}", n)));
+}
+"#;
+        let isolated = compact_str(&isolate_unsafe(module, "effects").unwrap());
+
+        // The block using nothing moves out, ahead of the operator
+        assert!(isolated.contains(".source_iter(q!(0..3)).inspect(q!(|_|{crate::effects::unsafe_effect_0();})).map(q!(|n|{letdoubled"));
+        assert!(isolated.contains("pubfnunsafe_effect_0(){unsafe{crate::effects::reset_counters();}}"));
+        // Those using the closure's values, or giving one, stay where they were
+        assert!(isolated.contains("letdoubled=unsafe{double(n)};unsafe{println!(\"{}\",n);}doubled"));
+        assert!(!isolated.contains("unsafe_effect_1"));
+
+        // Nothing to isolate leaves the module as it was
+        let module = "pub fn f<'a>(process: &Process<'a>) {\n    process.source_iter(q!(0..3)).for_each(q!(|n| println!(\"{}\", n)));\n}\n";
+        assert_eq!(isolate_unsafe(module, "f").unwrap(), module);
+    }
+
+    #[test]
+    fn test_unsafe_code_is_refused_where_it_is_found() {
+        let file = syn::parse_file("fn main() {\n    let x = 1;\n    unsafe { libc::abort() };\n}\n").unwrap();
+        let error = refuse_unsafe(&TransformReport::analyze(&file)).unwrap_err();
+        assert!(error.to_string().starts_with("Unsupported construct: unsafe block at line 3, which the transformation was asked to refuse;"));
+
+        let file = syn::parse_file("fn main() {\n    println!(\"safe\");\n}\n").unwrap();
+        assert!(refuse_unsafe(&TransformReport::analyze(&file)).is_ok());
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashSet;
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::visit::{self, Visit};
+use syn::{Expr, ExprMethodCall, FnArg, ImplItem, Item, Pat, Stmt, Type};
+
+use crate::config_file::leaves;
+use crate::error::TransformError;
+use crate::keyed_aggregation::mutates;
+use crate::loop_analysis::{LoopAnalysis, LoopKind};
+use crate::syntax::{bindings, mentions};
+
+/// The user-defined items of a legacy file that a body uses, with what they need to
+/// compile away from `main`: the structs, enums, type aliases, traits, functions,
+/// `const`s and `static`s it names, the impls of those types, and the items those name
+/// in turn, along with the imports they use. They are carried into the generated
+/// module as written, generics and `where` clauses included, where the quoted operators
+/// reach them as `main` did, and every operator reads the one definition.
+pub struct UserTypes {
+    /// The items, in their original order
+    pub items: Vec<Item>,
+}
+
+impl UserTypes {
+    pub fn of(items: &[Item], stmts: &[Stmt]) -> Self {
+        let mut wanted = HashSet::new();
+        for stmt in stmts {
+            idents(stmt.to_token_stream(), &mut wanted);
+        }
+        let mut included = vec![false; items.len()];
+        // Each item included may name more items defined alongside it
+        loop {
+            let mut grew = false;
+            for (index, item) in items.iter().enumerate() {
+                let reached = match item {
+                    Item::Impl(item) => self_type(&item.self_ty).is_some_and(|name| wanted.contains(&name)),
+                    Item::Fn(item) if item.sig.ident == "main" => false,
+                    _ => name(item).is_some_and(|name| wanted.contains(&name)),
+                };
+                if !included[index] && reached {
+                    included[index] = true;
+                    idents(item.to_token_stream(), &mut wanted);
+                    grew = true;
+                }
+            }
+            if !grew {
+                break;
+            }
+        }
+        if !included.contains(&true) {
+            return Self { items: Vec::new() };
+        }
+
+        // The imports the carried items use, which the body's own may not cover
+        let mut used = HashSet::new();
+        for (item, _) in items.iter().zip(&included).filter(|(_, included)| **included) {
+            idents(item.to_token_stream(), &mut used);
+        }
+        let items = items
+            .iter()
+            .zip(included)
+            .filter(|(item, included)| match item {
+                Item::Use(item) => leaves(&item.tree).iter().any(|name| used.contains(name)),
+                _ => *included,
+            })
+            .map(|(item, _)| item.clone())
+            .collect();
+        Self { items }
+    }
+
+    /// The methods of `ty` in the file's impls that take `&mut self`, the ones a value
+    /// of the type is updated through
+    pub fn updating_methods(items: &[Item], ty: &str) -> HashSet<String> {
+        items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Impl(item) if self_type(&item.self_ty).as_deref() == Some(ty) => Some(&item.items),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|item| match item {
+                ImplItem::Fn(method) => match method.sig.inputs.first() {
+                    Some(FnArg::Receiver(receiver)) if receiver.reference.is_some() && receiver.mutability.is_some() => {
+                        Some(method.sig.ident.to_string())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The generated module with the items after its function, leaving out those it
+    /// defines or imports already
+    pub fn carry(&self, module: String) -> Result<String, TransformError> {
+        if self.items.is_empty() {
+            return Ok(module);
+        }
+        let mut file = syn::parse_file(&module).map_err(|error| TransformError::TemplateError(error.to_string()))?;
+        let defined: HashSet<String> = file.items.iter().filter_map(name).collect();
+        let imported: HashSet<String> = file.items.iter().filter(|item| matches!(item, Item::Use(_))).map(|item| item.to_token_stream().to_string()).collect();
+        let (uses, definitions): (Vec<&Item>, Vec<&Item>) = self
+            .items
+            .iter()
+            .filter(|item| name(item).is_none_or(|name| !defined.contains(&name)))
+            .filter(|item| !imported.contains(&item.to_token_stream().to_string()))
+            .partition(|item| matches!(item, Item::Use(_)));
+        // The imports go with the module's own, ahead of the function
+        let at = file.items.iter().take_while(|item| matches!(item, Item::Use(_))).count();
+        file.items.splice(at..at, uses.into_iter().cloned());
+        file.items.extend(definitions.into_iter().cloned());
+        Ok(prettyplease::unparse(&file))
+    }
+}
+
+/// A legacy loop handing each item to a method that updates a value of a user-defined
+/// type, like `stats.record(value)`, followed by the code that reads the value. The
+/// method processes one item at a time, so the loop is a fold with the method as its
+/// step.
+pub struct MethodFold {
+    /// Immutable bindings before the value, which only feed the loop
+    pub prefix: Vec<Stmt>,
+    /// The value's binding, which the code after the loop gets the result in
+    pub acc: syn::Ident,
+    /// The expression creating the value, like `Stats::new()`
+    pub init: Expr,
+    pub pat: Pat,
+    /// The collection the loop iterates over
+    pub items: Expr,
+    /// An iteration: bindings of its own, and the method calls on the value
+    pub step: Vec<Stmt>,
+    /// The statements after the loop
+    pub rest: Vec<Stmt>,
+    /// Whether the statements after the loop change the value
+    pub rest_mutates: bool,
+}
+
+impl MethodFold {
+    /// Recognize `let mut acc = Type::new(..); for x in xs { .. acc.update(x); }` followed
+    /// by the statements reading `acc`, where `Type` is defined in the file and `update`
+    /// takes `&mut self`, after any immutable `let`s the loop uses
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let start = stmts.iter().position(|stmt| value_decl(items, stmt).is_some())?;
+        let (prefix, [decl, fold, rest @ ..]) = stmts.split_at(start) else {
+            return None;
+        };
+        let (acc, ty, init) = value_decl(items, decl)?;
+        let name = acc.to_string();
+        let methods = UserTypes::updating_methods(items, &ty);
+
+        let mut prefix_names = HashSet::new();
+        for stmt in prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            if !bindings(&local.pat, &mut prefix_names) {
+                return None;
+            }
+        }
+        // The value is created and read back away from the bindings before it
+        let outside = |stmts: &[Stmt]| prefix_names.iter().any(|prefix| mentions(stmts, prefix));
+        if outside(&[Stmt::Expr(init.clone(), None)]) {
+            return None;
+        }
+
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = fold else {
+            return None;
+        };
+        let analysis = LoopAnalysis::of_loop(items, stmts, start + 1)?;
+        if analysis.kind != LoopKind::FoldLike || analysis.skips || analysis.state != [name.clone()] {
+            return None;
+        }
+        // The items are built ahead of the fold, so they must be owned
+        let borrows = match &*for_loop.expr {
+            Expr::Reference(_) => true,
+            Expr::MethodCall(call) => call.method == "iter" || call.method == "iter_mut",
+            _ => false,
+        };
+        if borrows || mentions(&[Stmt::Expr((*for_loop.expr).clone(), None)], &name) {
+            return None;
+        }
+
+        // Each statement of an iteration binds a value of its own, or passes values to
+        // one of the methods
+        let mut calls = 0;
+        for stmt in &for_loop.body.stmts {
+            match stmt {
+                Stmt::Local(local) if !mentions(&[Stmt::Local(local.clone())], &name) => {}
+                Stmt::Expr(Expr::MethodCall(call), Some(_))
+                    if matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(&name))
+                        && methods.contains(&call.method.to_string())
+                        && !call.args.iter().any(|arg| mentions(&[Stmt::Expr(arg.clone(), None)], &name)) =>
+                {
+                    calls += 1;
+                }
+                _ => return None,
+            }
+        }
+        if calls == 0 || outside(&for_loop.body.stmts) {
+            return None;
+        }
+
+        if rest.is_empty() || !mentions(rest, &name) || outside(rest) {
+            return None;
+        }
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            acc,
+            init,
+            pat: (*for_loop.pat).clone(),
+            items: (*for_loop.expr).clone(),
+            step: for_loop.body.stmts.clone(),
+            rest: rest.to_vec(),
+            rest_mutates: mutates(rest, &name) || updates(rest, &name, &methods),
+        })
+    }
+}
+
+/// Whether the statements call one of the `methods` updating `name`
+fn updates(stmts: &[Stmt], name: &str, methods: &HashSet<String>) -> bool {
+    struct Updates<'m> {
+        name: &'m str,
+        methods: &'m HashSet<String>,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Updates<'_> {
+        fn visit_expr_method_call(&mut self, call: &'ast ExprMethodCall) {
+            self.found |= matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(self.name))
+                && self.methods.contains(&call.method.to_string());
+            visit::visit_expr_method_call(self, call);
+        }
+    }
+
+    let mut updates = Updates { name, methods, found: false };
+    stmts.iter().for_each(|stmt| updates.visit_stmt(stmt));
+    updates.found
+}
+
+/// `let mut acc = Type::new(..);` or `let mut acc: Type = ..;` for a type defined in
+/// `items`, giving the binding, the type's name and the value
+fn value_decl(items: &[Item], stmt: &Stmt) -> Option<(syn::Ident, String, Expr)> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let (pat, annotated) = match &local.pat {
+        Pat::Type(typed) => (&*typed.pat, self_type(&typed.ty)),
+        pat => (pat, None),
+    };
+    let Pat::Ident(binding) = pat else {
+        return None;
+    };
+    binding.mutability?;
+    let init = local.init.as_ref()?;
+    if init.diverge.is_some() {
+        return None;
+    }
+    let ty = annotated.or_else(|| match &*init.expr {
+        Expr::Call(call) => match &*call.func {
+            Expr::Path(path) if path.path.segments.len() == 2 => Some(path.path.segments[0].ident.to_string()),
+            _ => None,
+        },
+        Expr::Struct(literal) => literal.path.get_ident().map(|ident| ident.to_string()),
+        _ => None,
+    })?;
+    let defined = items.iter().any(|item| matches!(item, Item::Struct(_) | Item::Enum(_)) && name(item).as_deref() == Some(ty.as_str()));
+    defined.then(|| (binding.ident.clone(), ty, (*init.expr).clone()))
+}
+
+/// The name an item defines, if it defines one
+fn name(item: &Item) -> Option<String> {
+    let ident = match item {
+        Item::Struct(item) => &item.ident,
+        Item::Enum(item) => &item.ident,
+        Item::Type(item) => &item.ident,
+        Item::Trait(item) => &item.ident,
+        Item::Fn(item) => &item.sig.ident,
+        Item::Const(item) => &item.ident,
+        Item::Static(item) => &item.ident,
+        _ => return None,
+    };
+    Some(ident.to_string())
+}
+
+/// The name of the type an impl is for, like `Stats` of `impl Display for Stats<T>`
+fn self_type(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path.path.segments.last().map(|segment| segment.ident.to_string()),
+        _ => None,
+    }
+}
+
+fn idents(tokens: TokenStream, names: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                names.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => idents(group.stream(), names),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    const STATS: &str = r#"
+        use std::collections::HashMap;
+        use std::fmt;
+
+        struct Stats { count: u32, total: u64, seen: HashMap<u64, u32> }
+
+        impl Stats {
+            fn new() -> Self { Stats { count: 0, total: 0, seen: HashMap::new() } }
+            fn record(&mut self, value: u64) { self.count += 1; self.total += clamp(value); }
+            fn mean(&self) -> f64 { self.total as f64 / self.count as f64 }
+        }
+
+        impl fmt::Display for Stats {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", self.count) }
+        }
+
+        fn clamp(value: u64) -> u64 { value.min(LIMIT) }
+
+        const LIMIT: u64 = 100;
+
+        struct Unrelated;
+
+        fn unrelated() {}
+
+        fn main() {}
+    "#;
+
+    #[test]
+    fn test_types_the_body_uses_are_carried() {
+        let file: syn::File = syn::parse_str(STATS).unwrap();
+        let block: syn::Block = syn::parse_str("{ let mut stats = Stats::new(); stats.record(3); println!(\"{}\", stats.mean()); }").unwrap();
+        let carried: Vec<String> = UserTypes::of(&file.items, &block.stmts).items.iter().map(compact).collect();
+
+        // Both impls, and the function and constant a method uses, with their imports
+        assert_eq!(carried.len(), 7);
+        assert_eq!(carried[0], "usestd::collections::HashMap;");
+        assert_eq!(carried[1], "usestd::fmt;");
+        assert!(carried[2].starts_with("structStats"));
+        assert!(carried[3].starts_with("implStats"));
+        assert!(carried[4].starts_with("implfmt::DisplayforStats"));
+        assert!(carried[5].starts_with("fnclamp"));
+        assert!(carried[6].starts_with("constLIMIT"));
+
+        // A body calling a function carries it, with the constant it uses
+        let block: syn::Block = syn::parse_str("{ println!(\"{}\", clamp(3)); }").unwrap();
+        let carried: Vec<String> = UserTypes::of(&file.items, &block.stmts).items.iter().map(compact).collect();
+        assert_eq!(carried, ["fnclamp(value:u64)->u64{value.min(LIMIT)}", "constLIMIT:u64=100;"]);
+
+        // A body using none of the items carries nothing
+        let block: syn::Block = syn::parse_str("{ println!(\"{}\", 3); }").unwrap();
+        assert!(UserTypes::of(&file.items, &block.stmts).items.is_empty());
+
+        assert_eq!(UserTypes::updating_methods(&file.items, "Stats"), HashSet::from(["record".to_string()]));
+    }
+
+    #[test]
+    fn test_constants_the_body_uses_are_carried() {
+        let file: syn::File = syn::parse_str(r#"
+            const N: usize = SCALE * 5;
+            const SCALE: usize = 2;
+            static NAME: &str = "counter";
+            static UNUSED: u8 = 0;
+            const fn doubled(n: usize) -> usize { n * 2 }
+            const LIMIT: usize = doubled(N);
+            fn helper() {}
+            fn main() {}
+        "#).unwrap();
+        let block: syn::Block = syn::parse_str("{ for i in 0..N { helper(); println!(\"{} {}\", NAME, i); } }").unwrap();
+        let carried: Vec<String> = UserTypes::of(&file.items, &block.stmts).items.iter().map(compact).collect();
+        // The constants each name come along, as do the functions the body calls
+        assert_eq!(carried, ["constN:usize=SCALE*5;", "constSCALE:usize=2;", "staticNAME:&str=\"counter\";", "fnhelper(){}"]);
+
+        let block: syn::Block = syn::parse_str("{ println!(\"{}\", LIMIT); }").unwrap();
+        let carried: Vec<String> = UserTypes::of(&file.items, &block.stmts).items.iter().map(compact).collect();
+        assert_eq!(carried.len(), 4);
+        assert!(carried[2].starts_with("constfndoubled"));
+        assert_eq!(carried[3], "constLIMIT:usize=doubled(N);");
+    }
+
+    #[test]
+    fn test_generic_functions_keep_their_bounds() {
+        let file: syn::File = syn::parse_str(r#"
+            use std::fmt::{Debug, Display};
+            fn print_all<T: Display>(items: &[T]) { for item in items { println!("{}", item); } }
+            fn largest<T>(items: &[T]) -> Option<&T> where T: PartialOrd + Debug { items.iter().fold(None, |m, i| m.max(Some(i))) }
+            fn main() {}
+        "#).unwrap();
+        let block: syn::Block = syn::parse_str("{ print_all::<u32>(&[1, 2]); println!(\"{:?}\", largest(&[3, 4])); }").unwrap();
+        let carried: Vec<String> = UserTypes::of(&file.items, &block.stmts).items.iter().map(compact).collect();
+        assert_eq!(carried.len(), 3);
+        assert_eq!(carried[0], "usestd::fmt::{Debug,Display};");
+        assert!(carried[1].starts_with("fnprint_all<T:Display>(items:&[T])"));
+        assert!(carried[2].starts_with("fnlargest<T>(items:&[T])->Option<&T>whereT:PartialOrd+Debug{"));
+    }
+
+    #[test]
+    fn test_carried_types_follow_the_module_function() {
+        let file: syn::File = syn::parse_str(STATS).unwrap();
+        let block: syn::Block = syn::parse_str("{ let stats = Stats::new(); }").unwrap();
+        let module = "use hydro_lang::*;\nuse std::fmt;\npub fn f() {}\n".to_string();
+        let carried = UserTypes::of(&file.items, &block.stmts).carry(module).unwrap();
+        let compact: String = carried.split_whitespace().collect();
+
+        // An import the module has already isn't repeated
+        assert_eq!(compact.matches("usestd::fmt;").count(), 1);
+        assert!(compact.starts_with("usehydro_lang::*;usestd::fmt;usestd::collections::HashMap;pubfnf(){}structStats"));
+        assert!(syn::parse_file(&carried).is_ok());
+    }
+
+    fn detect(body: &str) -> Option<MethodFold> {
+        let file: syn::File = syn::parse_str(STATS).unwrap();
+        MethodFold::detect(&file.items, &parse_block(body))
+    }
+
+    #[test]
+    fn test_updating_method_loop_folds() {
+        let fold = detect(r#"{
+            let values: Vec<u64> = vec![3, 5, 10];
+            let mut stats = Stats::new();
+            for value in values {
+                let doubled = value * 2;
+                stats.record(doubled);
+            }
+            println!("{} values, mean {}", stats.count, stats.mean());
+        }"#).unwrap();
+
+        assert_eq!(fold.prefix.len(), 1);
+        assert_eq!(fold.acc, "stats");
+        assert_eq!(compact(&fold.init), "Stats::new()");
+        assert_eq!(compact(&fold.items), "values");
+        assert_eq!(fold.step.len(), 2);
+        assert_eq!(fold.rest.len(), 1);
+        assert!(!fold.rest_mutates);
+
+        let annotated = detect("{ let mut stats: Stats = make(); for v in 0..3u64 { stats.record(v); } stats.record(9); println!(\"{}\", stats); }").unwrap();
+        assert_eq!(compact(&annotated.init), "make()");
+        assert!(annotated.rest_mutates);
+    }
+
+    #[test]
+    fn test_other_method_loops_are_not_folds() {
+        // The method only reads the value
+        assert!(detect("{ let mut stats = Stats::new(); for v in 0..3u64 { stats.mean(); } println!(\"{}\", stats); }").is_none());
+        // The type isn't defined in the file
+        assert!(detect("{ let mut seen = HashSet::new(); for v in 0..3u64 { seen.insert(v); } println!(\"{}\", seen.len()); }").is_none());
+        // The iteration prints as well
+        assert!(detect("{ let mut stats = Stats::new(); for v in 0..3u64 { stats.record(v); println!(\"{}\", v); } println!(\"{}\", stats); }").is_none());
+        // An argument reads the value
+        assert!(detect("{ let mut stats = Stats::new(); for v in 0..3u64 { stats.record(stats.total + v); } println!(\"{}\", stats); }").is_none());
+        // The iterations skip items
+        assert!(detect("{ let mut stats = Stats::new(); for v in 0..3u64 { if v == 1 { continue; } stats.record(v); } println!(\"{}\", stats); }").is_none());
+        // Nothing reads the value afterwards
+        assert!(detect("{ let mut stats = Stats::new(); for v in 0..3u64 { stats.record(v); } }").is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+
+use proc_macro2::TokenStream;
+use quote::{quote, ToTokens};
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{parse_quote, Block, Expr, Item, Pat, Stmt};
+
+use crate::config_file::leaves;
+use crate::syntax::{bindings, leaves_early, mentions, unwrapped};
+
+/// The methods a legacy server reads a message from its WebSocket with
+const READS: [&str; 2] = ["read", "read_message"];
+/// The methods it sends one with
+const SENDS: [&str; 3] = ["send", "write_message", "send_message"];
+
+/// A legacy WebSocket server built on `tungstenite` or `tokio-tungstenite`: it accepts
+/// connections and, for each, loops reading a message and sending replies. The
+/// handling of the messages moves to the dataflow, and the example terminates the
+/// WebSocket protocol in front of it.
+pub struct WebSocketServer {
+    /// The address the legacy server listens on
+    pub address: Expr,
+    /// The path of the `tungstenite` crate the messages come from
+    pub tungstenite: syn::Path,
+    /// The binding of each message and the statements handling it, with each send
+    /// turned into a push onto `replies`
+    pub pat: Pat,
+    pub body: Vec<Stmt>,
+    /// The legacy file's imports the body uses
+    pub imports: Vec<Item>,
+}
+
+impl WebSocketServer {
+    /// Recognize `TcpListener::bind(address)` in a legacy body with a connection handler
+    /// that accepts a WebSocket, `let mut websocket = accept(stream)..;`, and then loops
+    /// over its messages: `loop { let message = websocket.read()..; .. }` or
+    /// `while let Some(message) = websocket.next().await { let message = message..; .. }`.
+    /// The loop body may send any number of replies on the WebSocket, and may not use
+    /// it otherwise, or leave the loop.
+    pub fn detect(items: &[Item], stmts: &[Stmt]) -> Option<Self> {
+        let mut scan = Server::default();
+        stmts.iter().for_each(|stmt| scan.visit_stmt(stmt));
+        let address = scan.address?;
+        let (websocket, pat, mut body) = scan.handler?;
+
+        // The sends become replies
+        let mut sends = Sends { websocket: websocket.clone() };
+        body.iter_mut().for_each(|stmt| sends.visit_stmt_mut(stmt));
+        let awaits = body.iter().any(|stmt| stmt.to_token_stream().to_string().contains(". await"));
+        if leaves_early(&body) || awaits || mentions(&body, &websocket.to_string()) {
+            return None;
+        }
+
+        // Only the message is at hand in the dataflow
+        let mut bound = HashSet::new();
+        Bindings(&mut bound).visit_block(&Block { brace_token: Default::default(), stmts: stmts.to_vec() });
+        let mut local = HashSet::new();
+        bindings(&pat, &mut local);
+        Bindings(&mut local).visit_block(&Block { brace_token: Default::default(), stmts: body.clone() });
+        if bound.iter().any(|name| !local.contains(name) && mentions(&body, name)) {
+            return None;
+        }
+
+        let tokens: String = stmts.iter().map(|stmt| stmt.to_token_stream().to_string()).collect();
+        let tungstenite = if tokens.contains("tokio_tungstenite") || tokens.contains("accept_async") {
+            parse_quote! { tokio_tungstenite::tungstenite }
+        } else {
+            parse_quote! { tungstenite }
+        };
+
+        let imports = items
+            .iter()
+            .filter(|item| matches!(item, Item::Use(item) if leaves(&item.tree).iter().any(|name| mentions(&body, name))))
+            .cloned()
+            .collect();
+
+        Some(Self { address, tungstenite, pat, body, imports })
+    }
+}
+
+/// Finds the address a legacy server binds and its WebSocket handler: the WebSocket's
+/// binding, and the message binding and body of its loop
+#[derive(Default)]
+struct Server {
+    address: Option<Expr>,
+    handler: Option<(syn::Ident, Pat, Vec<Stmt>)>,
+}
+
+impl<'ast> Visit<'ast> for Server {
+    fn visit_expr_call(&mut self, call: &'ast syn::ExprCall) {
+        if let (Expr::Path(func), [address]) = (&*call.func, &call.args.iter().collect::<Vec<_>>()[..]) {
+            let segments: Vec<String> = func.path.segments.iter().map(|segment| segment.ident.to_string()).collect();
+            if matches!(&segments[..], [.., listener, bind] if listener == "TcpListener" && bind == "bind") && matches!(address, Expr::Lit(_)) {
+                self.address = Some((*address).clone());
+            }
+        }
+        visit::visit_expr_call(self, call);
+    }
+
+    fn visit_block(&mut self, block: &'ast Block) {
+        for (index, stmt) in block.stmts.iter().enumerate() {
+            let Some(websocket) = accepted(stmt) else {
+                continue;
+            };
+            if let Some(Stmt::Expr(messages, _)) = block.stmts.get(index + 1) {
+                self.handler = self.handler.take().or_else(|| message_loop(messages, &websocket));
+            }
+        }
+        visit::visit_block(self, block);
+    }
+}
+
+/// `let mut websocket = accept(stream)..;`, as the WebSocket's name
+fn accepted(stmt: &Stmt) -> Option<syn::Ident> {
+    let Stmt::Local(local) = stmt else {
+        return None;
+    };
+    let Pat::Ident(websocket) = &local.pat else {
+        return None;
+    };
+    let init = local.init.as_ref()?;
+    let tokens = init.expr.to_token_stream().to_string();
+    ["accept (", "accept_async (", "accept_hdr ("].iter().any(|accept| tokens.contains(accept)).then(|| websocket.ident.clone())
+}
+
+/// The message binding and body of a loop reading the WebSocket's messages
+fn message_loop(expr: &Expr, websocket: &syn::Ident) -> Option<(syn::Ident, Pat, Vec<Stmt>)> {
+    let on_websocket = |expr: &Expr, methods: &[&str]| {
+        matches!(unwrapped(expr), Expr::MethodCall(call) if methods.iter().any(|method| call.method == method)
+            && matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(websocket)))
+    };
+    let (first, rest) = match expr {
+        // loop { let message = websocket.read()?; .. }
+        Expr::Loop(looped) if looped.label.is_none() => {
+            let (first, rest) = looped.body.stmts.split_first()?;
+            let Stmt::Local(local) = first else {
+                return None;
+            };
+            if !on_websocket(&local.init.as_ref()?.expr, &READS) {
+                return None;
+            }
+            (local, rest)
+        }
+        // while let Some(message) = websocket.next().await { let message = message?; .. }
+        Expr::While(looped) if looped.label.is_none() => {
+            let Expr::Let(next) = &*looped.cond else {
+                return None;
+            };
+            if !on_websocket(&next.expr, &["next"]) {
+                return None;
+            }
+            let Pat::TupleStruct(some) = &*next.pat else {
+                return None;
+            };
+            let [Pat::Ident(result)] = &some.elems.iter().collect::<Vec<_>>()[..] else {
+                return None;
+            };
+            let (first, rest) = looped.body.stmts.split_first()?;
+            let Stmt::Local(local) = first else {
+                return None;
+            };
+            let init = unwrapped(&local.init.as_ref()?.expr);
+            if !matches!(init, Expr::Path(path) if path.path.is_ident(&result.ident)) {
+                return None;
+            }
+            (local, rest)
+        }
+        _ => return None,
+    };
+    Some((websocket.clone(), first.pat.clone(), rest.to_vec()))
+}
+
+/// Turns each `websocket.send(reply)..;` into `replies.push(reply);`
+struct Sends {
+    websocket: syn::Ident,
+}
+
+impl VisitMut for Sends {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if let Stmt::Expr(expr, Some(_)) = stmt {
+            if let Expr::MethodCall(call) = unwrapped(expr) {
+                let on_websocket = matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(&self.websocket));
+                if let (true, [reply]) = (on_websocket && SENDS.iter().any(|send| call.method == send), &call.args.iter().collect::<Vec<_>>()[..]) {
+                    *stmt = parse_quote! { replies.push(#reply); };
+                    return;
+                }
+            }
+        }
+        visit_mut::visit_stmt_mut(self, stmt);
+    }
+}
+
+/// Collects the names bound anywhere in a block, by `let`s, closures or patterns
+struct Bindings<'n>(&'n mut HashSet<String>);
+
+impl<'ast> Visit<'ast> for Bindings<'_> {
+    fn visit_pat_ident(&mut self, pat: &'ast syn::PatIdent) {
+        self.0.insert(pat.ident.to_string());
+        visit::visit_pat_ident(self, pat);
+    }
+}
+
+/// The definitions of `encode_frame` and `decode_frame`, the framing of messages
+/// between the example's bridge and the dataflow
+pub fn frames() -> TokenStream {
+    quote! {
+        /// A message between the bridge and the dataflow, as the connection it belongs to,
+        /// whether it is text, and its data
+        pub fn encode_frame(connection: u64, text: bool, data: &[u8]) -> bytes::Bytes {
+            let mut frame = bytes::BytesMut::with_capacity(9 + data.len());
+            frame.extend_from_slice(&connection.to_be_bytes());
+            frame.extend_from_slice(&[text as u8]);
+            frame.extend_from_slice(data);
+            frame.freeze()
+        }
+
+        pub fn decode_frame(frame: &[u8]) -> (u64, bool, Vec<u8>) {
+            let (connection, rest) = frame.split_at(8);
+            (u64::from_be_bytes(connection.try_into().unwrap()), rest[0] == 1, rest[1..].to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(source: &str) -> Option<WebSocketServer> {
+        WebSocketServer::detect(&[parse_quote! { use tungstenite::Message; }], &parse_block(source))
+    }
+
+    #[test]
+    fn test_threaded_echo_server() {
+        let server = detect(r#"{
+            let server = TcpListener::bind("127.0.0.1:9001").unwrap();
+            for stream in server.incoming() {
+                spawn(move || {
+                    let mut websocket = accept(stream.unwrap()).unwrap();
+                    loop {
+                        let msg = websocket.read().unwrap();
+                        if msg.is_binary() || msg.is_text() {
+                            websocket.send(msg).unwrap();
+                        }
+                    }
+                });
+            }
+        }"#).unwrap();
+
+        assert_eq!(compact(&server.address), "\"127.0.0.1:9001\"");
+        assert_eq!(compact(&server.tungstenite), "tungstenite");
+        assert_eq!(compact(&server.pat), "msg");
+        assert_eq!(compact(&server.body[0]), "ifmsg.is_binary()||msg.is_text(){replies.push(msg);}");
+    }
+
+    #[test]
+    fn test_async_server_with_replies() {
+        let server = detect(r#"{
+            let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    while let Some(message) = ws.next().await {
+                        let message = message.unwrap();
+                        let text = message.to_text().unwrap().to_uppercase();
+                        println!("got {}", text);
+                        ws.send(Message::text(text.clone())).await.unwrap();
+                        ws.send(Message::text(format!("{} bytes", text.len()))).await.unwrap();
+                    }
+                });
+            }
+        }"#).unwrap();
+
+        assert_eq!(compact(&server.tungstenite), "tokio_tungstenite::tungstenite");
+        assert_eq!(compact(&server.pat), "message");
+        let body: Vec<String> = server.body.iter().map(compact).collect();
+        assert_eq!(body[2], "replies.push(Message::text(text.clone()));");
+        assert_eq!(body.len(), 4);
+        assert_eq!(server.imports.len(), 1);
+    }
+
+    #[test]
+    fn test_servers_that_are_not_pipelines() {
+        // The handler uses the connection's peer address
+        assert!(detect(r#"{
+            let server = TcpListener::bind("127.0.0.1:9001").unwrap();
+            for stream in server.incoming() {
+                let stream = stream.unwrap();
+                let peer = stream.peer_addr().unwrap();
+                let mut websocket = accept(stream).unwrap();
+                loop {
+                    let msg = websocket.read().unwrap();
+                    println!("{}: {}", peer, msg);
+                }
+            }
+        }"#).is_none());
+        // The loop ends on a close message
+        assert!(detect(r#"{
+            let server = TcpListener::bind("127.0.0.1:9001").unwrap();
+            for stream in server.incoming() {
+                let mut websocket = accept(stream.unwrap()).unwrap();
+                loop {
+                    let msg = websocket.read().unwrap();
+                    if msg.is_close() { break; }
+                    websocket.send(msg).unwrap();
+                }
+            }
+        }"#).is_none());
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+
+use syn::{parse_quote, BinOp, Expr, Pat, Stmt};
+
+use crate::syntax::{bindings, leaves_early, mentions};
+
+/// A legacy loop that processes a collection a fixed number of items at a time, either
+/// through `chunks(n)` or by filling and clearing a window buffer by hand
+pub struct WindowedLoop {
+    /// Immutable bindings before the loop, which build the collection
+    pub prefix: Vec<Stmt>,
+    /// The collection, iterated by value
+    pub items: Expr,
+    /// For a hand-filled window, the loop's pattern and what it pushes for each item
+    pub push: Option<(Pat, Expr)>,
+    /// Whether the loop borrowed its items, so `push` sees a reference to each one
+    pub by_ref: bool,
+    /// The number of items per window
+    pub size: Expr,
+    /// What each window's body binds it as, and whether that is a slice of the window
+    /// (`chunks`) or the window `Vec` itself
+    pub binding: Pat,
+    pub as_slice: bool,
+    /// The body run for each full window
+    pub full: Vec<Stmt>,
+    /// The body run for a trailing window with fewer than `size` items, if anything is
+    pub partial: Option<Vec<Stmt>>,
+}
+
+impl WindowedLoop {
+    /// Recognize `for chunk in xs.chunks(n) { .. }`, or a loop pushing into a `Vec` that
+    /// is processed and cleared whenever it reaches `n` items, with an optional
+    /// `if !window.is_empty() { .. }` for the leftovers afterwards
+    pub fn detect(stmts: &[Stmt]) -> Option<Self> {
+        let detected = Self::detect_chunks(stmts).or_else(|| Self::detect_window_buffer(stmts))?;
+
+        // The size and the bodies run inside quoted operators, where the bindings
+        // before the loop don't exist
+        let mut prefix_names = HashSet::new();
+        for stmt in &detected.prefix {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            if !bindings(&local.pat, &mut prefix_names) {
+                return None;
+            }
+        }
+        let mut used = detected.full.clone();
+        used.extend(detected.partial.iter().flatten().cloned());
+        used.push(Stmt::Expr(detected.size.clone(), None));
+        if let Some((_, value)) = &detected.push {
+            used.push(Stmt::Expr(value.clone(), None));
+        }
+        if prefix_names.iter().any(|name| mentions(&used, name)) {
+            return None;
+        }
+        Some(detected)
+    }
+
+    fn detect_chunks(stmts: &[Stmt]) -> Option<Self> {
+        let (last, prefix) = stmts.split_last()?;
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = last else {
+            return None;
+        };
+        let Expr::MethodCall(chunks) = &*for_loop.expr else {
+            return None;
+        };
+        if chunks.method != "chunks" || chunks.args.len() != 1 || for_loop.label.is_some() || leaves_early(&for_loop.body.stmts) {
+            return None;
+        }
+        let items = match &*chunks.receiver {
+            Expr::Reference(reference) => (*reference.expr).clone(),
+            receiver => receiver.clone(),
+        };
+        let body = for_loop.body.stmts.clone();
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            items,
+            push: None,
+            by_ref: false,
+            size: chunks.args[0].clone(),
+            binding: (*for_loop.pat).clone(),
+            as_slice: true,
+            // The last chunk is simply shorter
+            partial: Some(body.clone()),
+            full: body,
+        })
+    }
+
+    fn detect_window_buffer(stmts: &[Stmt]) -> Option<Self> {
+        // The buffer, the loop filling it, and maybe the leftovers
+        let (decl_index, window) = stmts.iter().enumerate().find_map(|(index, stmt)| {
+            let Stmt::Local(local) = stmt else {
+                return None;
+            };
+            let Pat::Ident(ident) = &local.pat else {
+                return None;
+            };
+            (ident.mutability.is_some() && is_empty_vec(&local.init.as_ref()?.expr)).then(|| (index, ident.ident.clone()))
+        })?;
+        let buffer = window;
+        let window = buffer.to_string();
+        let prefix = &stmts[..decl_index];
+        let (fill, leftovers) = match &stmts[decl_index + 1..] {
+            [fill] => (fill, None),
+            [fill, leftovers] => (fill, Some(leftovers)),
+            _ => return None,
+        };
+
+        let Stmt::Expr(Expr::ForLoop(for_loop), _) = fill else {
+            return None;
+        };
+        if for_loop.label.is_some() || leaves_early(&for_loop.body.stmts) {
+            return None;
+        }
+        let (items, by_ref) = match &*for_loop.expr {
+            Expr::Reference(reference) if reference.mutability.is_none() => ((*reference.expr).clone(), true),
+            Expr::MethodCall(call) if call.method == "iter" && call.args.is_empty() => ((*call.receiver).clone(), true),
+            items => (items.clone(), false),
+        };
+
+        let [Stmt::Expr(push, Some(_)), Stmt::Expr(Expr::If(full), _)] = &for_loop.body.stmts[..] else {
+            return None;
+        };
+        let value = method_on(push, &window, "push").and_then(|args| match &args[..] {
+            [value] => Some((*value).clone()),
+            _ => None,
+        })?;
+        if full.else_branch.is_some() || mentions(&[Stmt::Expr(value.clone(), None)], &window) {
+            return None;
+        }
+        let size = window_size(&full.cond, &window)?;
+        let (clear, full_body) = full.then_branch.stmts.split_last()?;
+        match clear {
+            Stmt::Expr(clear, Some(_)) if method_on(clear, &window, "clear").is_some_and(|args| args.is_empty()) => {}
+            _ => return None,
+        }
+
+        let partial = match leftovers {
+            None => None,
+            Some(Stmt::Expr(Expr::If(leftovers), _)) if leftovers.else_branch.is_none() && is_not_empty(&leftovers.cond, &window) => {
+                Some(leftovers.then_branch.stmts.clone())
+            }
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            prefix: prefix.to_vec(),
+            items,
+            push: Some(((*for_loop.pat).clone(), value)),
+            by_ref,
+            size,
+            binding: parse_quote! { mut #buffer },
+            as_slice: false,
+            full: full_body.to_vec(),
+            partial,
+        })
+    }
+}
+
+/// `Vec::new()`, `Vec::with_capacity(n)` or `vec![]`
+fn is_empty_vec(expr: &Expr) -> bool {
+    match expr {
+        Expr::Macro(mac) => mac.mac.path.is_ident("vec") && mac.mac.tokens.is_empty(),
+        Expr::Call(call) => match &*call.func {
+            Expr::Path(func) => {
+                let segments: Vec<_> = func.path.segments.iter().map(|s| s.ident.to_string()).collect();
+                matches!(segments.as_slice(), [.., ty, new] if ty == "Vec" && (new == "new" || new == "with_capacity"))
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The arguments of a `name.method(..)` call
+fn method_on<'e>(expr: &'e Expr, name: &str, method: &str) -> Option<Vec<&'e Expr>> {
+    match expr {
+        Expr::MethodCall(call) if call.method == method && matches!(&*call.receiver, Expr::Path(path) if path.path.is_ident(name)) => {
+            Some(call.args.iter().collect())
+        }
+        _ => None,
+    }
+}
+
+/// `window.len() == n` (or `>=`), returning `n`
+fn window_size(cond: &Expr, window: &str) -> Option<Expr> {
+    let Expr::Binary(cmp) = cond else {
+        return None;
+    };
+    if !matches!(cmp.op, BinOp::Eq(_) | BinOp::Ge(_)) {
+        return None;
+    }
+    method_on(&cmp.left, window, "len").filter(|args| args.is_empty())?;
+    matches!(&*cmp.right, Expr::Lit(_) | Expr::Path(_)).then(|| (*cmp.right).clone())
+}
+
+/// `!window.is_empty()`
+fn is_not_empty(cond: &Expr, window: &str) -> bool {
+    match cond {
+        Expr::Unary(not) if matches!(not.op, syn::UnOp::Not(_)) => {
+            method_on(&not.expr, window, "is_empty").is_some_and(|args| args.is_empty())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compact, parse_block};
+
+    fn detect(body: &str) -> Option<WindowedLoop> {
+        WindowedLoop::detect(&parse_block(body))
+    }
+
+    #[test]
+    fn test_chunks_loop() {
+        let windowed = detect(r#"{
+            let readings = vec![3, 5, 8, 2, 9];
+            for chunk in readings.chunks(2) {
+                let sum: i32 = chunk.iter().sum();
+                println!("{:?} -> {}", chunk, sum);
+            }
+        }"#).unwrap();
+
+        assert!(windowed.as_slice);
+        assert!(windowed.push.is_none());
+        assert_eq!(compact(&windowed.items), "readings");
+        assert_eq!(compact(&windowed.size), "2");
+        assert_eq!(windowed.partial.as_ref().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn test_manual_window_buffer() {
+        let windowed = detect(r#"{
+            let mut window = Vec::new();
+            for value in [1, 2, 3, 4, 5] {
+                window.push(value * 10);
+                if window.len() == 3 {
+                    println!("full: {:?}", window);
+                    window.clear();
+                }
+            }
+            if !window.is_empty() {
+                println!("rest: {:?}", window);
+            }
+        }"#).unwrap();
+
+        assert!(!windowed.as_slice);
+        assert!(!windowed.by_ref);
+        let (pat, value) = windowed.push.as_ref().unwrap();
+        assert_eq!(compact(pat), "value");
+        assert_eq!(compact(value), "value*10");
+        assert_eq!(compact(&windowed.binding), "mutwindow");
+        assert_eq!(windowed.full.len(), 1);
+        assert_eq!(windowed.partial.as_ref().map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn test_other_buffers_are_not_windows() {
+        // The buffer is never cleared, so it only grows
+        assert!(detect(r#"{
+            let mut seen = Vec::new();
+            for value in 0..10 {
+                seen.push(value);
+                if seen.len() == 3 { println!("{:?}", seen); }
+            }
+        }"#).is_none());
+        // The window size is a local
+        assert!(detect("{ let n = 2; let xs = vec![1, 2, 3]; for c in xs.chunks(2) { println!(\"{:?}\", (c, n)); } }").is_none());
+    }
+}
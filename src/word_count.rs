@@ -0,0 +1,66 @@
+use hydro_lang::*;
+
+/// The legacy word count (`legacy::word_count`) written by hand as a dataflow: each word
+/// is paired with a count of one, the counts are folded by word, and the totals are
+/// printed in word order, as the legacy program sorted them before printing
+pub fn word_count<'a>(process: &Process<'a>) {
+    process
+        .source_iter(q!("the quick brown fox jumps over the lazy dog and the dog sleeps".split_whitespace()))
+        .map(q!(|word| (word.to_string(), 1))) // : Stream<(String, usize), Process, ...>
+        .fold_keyed(q!(|| 0), q!(|count: &mut usize, n| *count += n)) // : Stream<(String, usize), Process, ..., NoOrder>
+        .sort() // : Stream<(String, usize), Process, ..., TotalOrder>
+        .for_each(q!(|(word, count)| println!("{}: {}", word, count)));
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use hydro_deploy::Deployment;
+    use hydro_lang::deploy::DeployCrateWrapper;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use crate::config::{Target, TransformerConfig};
+    use crate::syn_transformer::SynLegacyToHydroTransformer;
+
+    /// What the legacy program prints
+    const EXPECTED: [&str; 10] =
+        ["and: 1", "brown: 1", "dog: 2", "fox: 1", "jumps: 1", "lazy: 1", "over: 1", "quick: 1", "sleeps: 1", "the: 3"];
+
+    #[tokio::test]
+    async fn word_count() {
+        let mut deployment = Deployment::new();
+
+        let flow = hydro_lang::FlowBuilder::new();
+        let process = flow.process();
+        super::word_count(&process);
+
+        let nodes = flow
+            .with_process(&process, deployment.Localhost())
+            .deploy(&mut deployment);
+
+        deployment.deploy().await.unwrap();
+
+        let stdout = nodes.get_process(&process).stdout().await;
+
+        deployment.start().await.unwrap();
+
+        let out = UnboundedReceiverStream::new(stdout)
+            .take(EXPECTED.len())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(out, EXPECTED);
+    }
+
+    #[test]
+    fn test_legacy_word_count_is_partitioned_by_key() {
+        let legacy = syn::parse_file(include_str!("legacy/word_count.rs")).unwrap();
+
+        let cluster = SynLegacyToHydroTransformer::new().with_config(TransformerConfig::new().with_target(Target::Cluster));
+        assert_eq!(cluster.translation(&legacy).unwrap(), Some("key-partitioned cluster"));
+        let output = cluster.transform_file(&legacy, "word_count").unwrap();
+        let compact: String = output.hydro_function.split_whitespace().collect();
+        assert!(compact.contains("((word.to_string()).to_owned(),1)"));
+        assert!(compact.contains("letmutgroups=std::collections::HashMap::new();"));
+        assert!(compact.contains("*groups.entry(key).or_insert(0)+=value;"));
+    }
+}
@@ -3,6 +3,13 @@ use tokio::time::{timeout, Duration};
 
 #[tokio::main]
 async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
+
     let mut deployment = Deployment::new();
 
     let flow = hydro_lang::FlowBuilder::new();
@@ -13,8 +20,7 @@ async fn main() {
         .with_process(&process, deployment.Localhost())
         .deploy(&mut deployment);
 
-    println!("Starting deployment...");
-    println!("Looking for 'running command:' output...");
+    tracing::info!(event = "deploy_started", "Starting deployment");
     
     // Deploy the processes first
     deployment.deploy().await.unwrap();
@@ -22,18 +28,15 @@ async fn main() {
     // Start the deployment with a timeout
     let start_result = timeout(Duration::from_secs(60), async {
         deployment.start().await.unwrap();
+        tracing::info!(event = "process_launched", "Dataflow started");
     }).await;
     
     match start_result {
         Ok(_) => {
-            println!("✓ Deployment completed successfully");
+            tracing::info!(event = "stream_completed", "Deployment completed successfully");
         }
         Err(_) => {
-            println!("✓ Deployment reached 60-second timeout");
-            println!("If you saw output containing:");
-            println!("  [() (process 0)] running command: `...`");
-            println!("  [() (process 0)] Hello, world!");
-            println!("Then the deployment worked correctly!");
+            tracing::warn!(event = "timeout", timeout_secs = 60, "Deployment did not complete in time");
         }
     }
 }
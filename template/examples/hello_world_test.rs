@@ -1,39 +1,79 @@
+use futures::StreamExt;
 use hydro_deploy::Deployment;
+use hydro_lang::deploy::DeployCrateWrapper;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::time::{timeout, Duration};
 
 #[tokio::main]
 async fn main() {
+    let quiet = std::env::args().any(|arg| arg == "--quiet");
+    tracing_subscriber::fmt()
+        .json()
+        .with_writer(std::io::stderr)
+        .with_max_level(if quiet { tracing::Level::WARN } else { tracing::Level::INFO })
+        .init();
+
     let mut deployment = Deployment::new();
 
     let flow = hydro_lang::FlowBuilder::new();
     let process = flow.process();
-    hydro_template::hello_world_test::hello_world_test(&process);
+    let external = flow.external_process();
+    let done_port = hydro_template::hello_world_test::hello_world_test(&process, &external);
 
-    let _nodes = flow
+    let nodes = flow
         .with_process(&process, deployment.Localhost())
+        .with_external(&external, deployment.Localhost())
         .deploy(&mut deployment);
 
-    println!("Starting deployment...");
-    println!("Looking for 'running command:' output...");
+    tracing::info!(event = "deploy_started", "Starting deployment");
     
     // Deploy the processes first
     deployment.deploy().await.unwrap();
     
-    // Start the deployment with a timeout
-    let start_result = timeout(Duration::from_secs(60), async {
-        deployment.start().await.unwrap();
+    // Connect to the completion port before starting so the signal can't be missed
+    let mut done = nodes.connect_source_bincode(done_port).await;
+    
+    // Forward our stdin to the deployed process so interactive programs can be scripted
+    let process_stdin = nodes.get_process(&process).underlying().read().await.stdin();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if process_stdin.send(format!("{}\n", line)).is_err() {
+                break;
+            }
+        }
+    });
+    
+    deployment.start().await.unwrap();
+    tracing::info!(event = "process_launched", "Dataflow started");
+    
+    // Finish as soon as the dataflow signals completion, or report the status if
+    // the process exits first (e.g. `std::process::exit` or a panic)
+    let finished = timeout(Duration::from_secs(60), async {
+        tokio::select! {
+            _ = done.next() => 0,
+            code = async {
+                loop {
+                    if let Some(code) = nodes.get_process(&process).underlying().read().await.exit_code() {
+                        return code;
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            } => code,
+        }
     }).await;
     
-    match start_result {
-        Ok(_) => {
-            println!("✓ Deployment completed successfully");
+    let exit_code = match finished {
+        Ok(code) => {
+            tracing::info!(event = "stream_completed", "Deployment completed");
+            code
         }
         Err(_) => {
-            println!("✓ Deployment reached 60-second timeout");
-            println!("If you saw output containing:");
-            println!("  [() (process 0)] running command: `...`");
-            println!("  [() (process 0)] Hello, world!");
-            println!("Then the deployment worked correctly!");
+            tracing::warn!(event = "timeout", timeout_secs = 60, "Deployment did not complete in time");
+            1
         }
-    }
+    };
+    
+    tracing::info!(event = "process_exited", exit_code, "Process exited");
+    std::process::exit(exit_code);
 }
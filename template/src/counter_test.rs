@@ -1,6 +1,7 @@
 use hydro_lang::*;
+use hydro_lang::location::external_process::ExternalBincodeStream;
 
-pub fn counter_test(process: &Process) {
+pub fn counter_test<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
     process
         .source_iter(q!(std::iter::once(())))
         .map(q!(|_| {
@@ -9,5 +10,6 @@ pub fn counter_test(process: &Process) {
                     println!("Count: {}", i);
                 }
         }))
-        .for_each(q!(|_| {}));
+        // Signal completion to the example once the legacy body has run
+        .send_bincode_external(external)
 }
\ No newline at end of file
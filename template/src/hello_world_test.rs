@@ -1,11 +1,13 @@
 use hydro_lang::*;
+use hydro_lang::location::external_process::ExternalBincodeStream;
 
-pub fn hello_world_test(process: &Process) {
+pub fn hello_world_test<'a>(process: &Process<'a>, external: &ExternalProcess<'a, ()>) -> ExternalBincodeStream<()> {
     process
         .source_iter(q!(std::iter::once(())))
         .map(q!(|_| {
             // Legacy main function body wrapped in Hydro map operator
                 println!("Hello, world!");
         }))
-        .for_each(q!(|_| {}));
+        // Signal completion to the example once the legacy body has run
+        .send_bincode_external(external)
 }
\ No newline at end of file
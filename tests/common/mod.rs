@@ -0,0 +1,194 @@
+// Shared runners for the equivalence test crates. Each test crate only uses a
+// subset of these helpers.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use hydro_template::seeded_rng::{seed_legacy_program, SEED_ENV_VAR};
+use tokio::time::{timeout, Duration};
+
+/// Seed shared by the legacy and Hydro runs of programs that use `rand`
+pub const RNG_SEED: u64 = 42;
+
+/// Captured output of a single program run, kept per stream
+#[derive(Debug)]
+pub struct ProgramOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the process was terminated by a signal
+    pub exit_code: Option<i32>,
+}
+
+/// Compare a legacy run against a Hydro run stream by stream. The Hydro run's
+/// streams still carry deployment chatter, so the program output is extracted first.
+pub fn compare_outputs(legacy_output: &ProgramOutput, hydro_output: &ProgramOutput) -> Result<(), String> {
+    let hydro_stdout = extract_program_output_from_hydro(&hydro_output.stdout);
+    let hydro_stderr = extract_program_output_from_hydro(&hydro_output.stderr);
+    
+    if legacy_output.stdout.trim() != hydro_stdout.trim() {
+        return Err(format!("stdout differs:\n  legacy: {:?}\n  hydro:  {:?}",
+                           legacy_output.stdout.trim(), hydro_stdout.trim()));
+    }
+    if legacy_output.stderr.trim() != hydro_stderr.trim() {
+        return Err(format!("stderr differs:\n  legacy: {:?}\n  hydro:  {:?}",
+                           legacy_output.stderr.trim(), hydro_stderr.trim()));
+    }
+    if legacy_output.exit_code != hydro_output.exit_code {
+        return Err(format!("exit status differs: legacy {:?}, hydro {:?}",
+                           legacy_output.exit_code, hydro_output.exit_code));
+    }
+    
+    Ok(())
+}
+
+pub fn assert_outputs_match(legacy_output: &ProgramOutput, hydro_output: &ProgramOutput) {
+    if let Err(difference) = compare_outputs(legacy_output, hydro_output) {
+        panic!("Legacy and Hydro programs should behave identically, but {}", difference);
+    }
+}
+
+/// Copy a legacy program with its randomness seeded by [`RNG_SEED`], the seed the
+/// generated examples are given, so both runs draw the same sequence
+pub fn write_seeded_legacy_program(program_path: &str) -> Result<tempfile::NamedTempFile, Box<dyn std::error::Error>> {
+    let source = fs::read_to_string(program_path)?;
+    let seeded = seed_legacy_program(&source, RNG_SEED)?;
+    let mut file = tempfile::Builder::new().suffix(".rs").tempfile()?;
+    file.write_all(seeded.as_bytes())?;
+    Ok(file)
+}
+
+pub fn run_legacy_program(program_path: &str, stdin_script: Option<&str>) -> Result<ProgramOutput, Box<dyn std::error::Error>> {
+    // Create a temporary executable name, unique per program since tests run in parallel
+    let program_stem = Path::new(program_path).file_stem().unwrap_or_default().to_string_lossy();
+    let exe_name = format!("temp_legacy_{}_{}", program_stem, std::process::id());
+    
+    // Compile the program
+    let compile_result = Command::new("rustc")
+        .arg(program_path)
+        .arg("-o")
+        .arg(&exe_name)
+        .output()?;
+    
+    if !compile_result.status.success() {
+        return Err(format!("Legacy compilation failed: {}", 
+                          String::from_utf8_lossy(&compile_result.stderr)).into());
+    }
+    
+    // Run the compiled program
+    let run_result = run_with_stdin(Command::new(format!("./{}", exe_name)), stdin_script);
+    
+    // Clean up executable
+    fs::remove_file(&exe_name).ok();
+    let run_result = run_result?;
+    
+    // A nonzero exit is part of the observable behavior, not a harness failure
+    Ok(ProgramOutput {
+        stdout: String::from_utf8_lossy(&run_result.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&run_result.stderr).to_string(),
+        exit_code: run_result.status.code(),
+    })
+}
+
+/// Spawn a command, write the stdin script (if any) and close stdin so the
+/// program observes EOF, then wait for it to finish.
+pub fn run_with_stdin(mut command: Command, stdin_script: Option<&str>) -> std::io::Result<std::process::Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Dropping the handle at the end of this block closes the pipe
+    {
+        let mut stdin = child.stdin.take().expect("stdin was piped");
+        if let Some(script) = stdin_script {
+            stdin.write_all(script.as_bytes())?;
+        }
+    }
+
+    child.wait_with_output()
+}
+
+pub fn generate_hydro_program(legacy_path: &str, module_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    generate_hydro_program_with_args(legacy_path, module_name, &[])
+}
+
+/// Like [`generate_hydro_program`], with extra generator options such as
+/// `--backend syn --distribute reduce`
+pub fn generate_hydro_program_with_args(legacy_path: &str, module_name: &str, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    // Run our generator to create the Hydro version. The template keeps
+    // earlier outputs of these tests checked in (e.g. `hello_world_test`),
+    // so they are regenerated in place
+    let generate_result = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg(legacy_path)
+        .arg(module_name)
+        .arg("--overwrite")
+        .args(args)
+        .current_dir("generator")
+        .output()?;
+    
+    if !generate_result.status.success() {
+        return Err(format!("Generator failed: {}", 
+                          String::from_utf8_lossy(&generate_result.stderr)).into());
+    }
+    
+    Ok(())
+}
+
+pub async fn run_generated_hydro_program(module_name: &str, stdin_script: Option<&str>) -> Result<ProgramOutput, Box<dyn std::error::Error>> {
+    // Run the generated Hydro program with timeout
+    let result = timeout(Duration::from_secs(90), async {
+        let mut command = Command::new("cargo");
+        command
+            .arg("run")
+            .arg("--example")
+            .arg(module_name)
+            .env(SEED_ENV_VAR, RNG_SEED.to_string())
+            .current_dir("template");
+        // The generated example forwards its own stdin to the deployed process
+        let run_result = run_with_stdin(command, stdin_script)?;
+        let stdout = String::from_utf8_lossy(&run_result.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&run_result.stderr).to_string();
+        
+        // The example exits with the deployed process's status after logging it, or, when
+        // a library backend generated it, logs that the dataflow completed; without either
+        // event the example itself never got to run (e.g. a build failure)
+        if stderr.contains("\"event\":\"process_exited\"") || stderr.contains("\"event\":\"stream_completed\"") {
+            // Deployed processes echo both of their streams with the same process prefix
+            Ok::<ProgramOutput, Box<dyn std::error::Error>>(ProgramOutput {
+                stdout,
+                stderr,
+                exit_code: run_result.status.code(),
+            })
+        } else {
+            Err(format!("Hydro program execution failed: {}", stderr).into())
+        }
+    }).await??;
+    
+    Ok(result)
+}
+
+pub fn extract_program_output_from_hydro(hydro_output: &str) -> String {
+    // Works on a single stream (stdout or stderr) of the Hydro example run.
+    // Look for lines that contain the actual program output
+    // Format: [() (process 0)] Hello, world!
+    // The process is named by its type, like `Leader` in a cluster deployment, whose
+    // members print under names of their own
+    let mut program_lines = Vec::new();
+    
+    for line in hydro_output.lines() {
+        if line.contains(" (process 0)] ") && !line.contains("running command:") {
+            // Extract just the program output after the process identifier
+            if let Some(output_start) = line.find("] ") {
+                let program_output = &line[output_start + 2..];
+                program_lines.push(program_output);
+            }
+        }
+    }
+    
+    program_lines.join("\n")
+}
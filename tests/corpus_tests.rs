@@ -0,0 +1,171 @@
+// Corpus-driven equivalence tests: every legacy program in `corpus/` goes through
+// the full generate-deploy-compare cycle. See corpus/README.md for the layout.
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{compare_outputs, generate_hydro_program, run_generated_hydro_program, run_legacy_program};
+use hydro_template::backend::Transformer;
+use hydro_template::config::TransformerConfig;
+use hydro_template::formatting::format_module;
+use hydro_template::io_transformer::IOToHydroTransformer;
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+use hydro_template::transformer::LegacyToHydroTransformer;
+
+const CORPUS_DIR: &str = "corpus";
+
+/// A legacy program from the corpus together with its optional fixtures
+#[derive(Debug)]
+struct CorpusCase {
+    name: String,
+    program: PathBuf,
+    stdin: Option<String>,
+    expected_stdout: Option<String>,
+    expected_stderr: Option<String>,
+}
+
+#[tokio::test]
+async fn test_corpus_equivalence() {
+    let cases = discover_corpus(Path::new(CORPUS_DIR)).expect("Failed to read corpus directory");
+    assert!(!cases.is_empty(), "Corpus should contain at least one program");
+
+    let mut failures = Vec::new();
+    for case in &cases {
+        match run_case(case).await {
+            Ok(()) => println!("PASS {}", case.name),
+            Err(reason) => {
+                println!("FAIL {}: {}", case.name, reason);
+                failures.push(case.name.clone());
+            }
+        }
+    }
+
+    println!("Corpus: {} passed, {} failed", cases.len() - failures.len(), failures.len());
+    assert!(failures.is_empty(), "Corpus programs failed: {:?}", failures);
+}
+
+#[test]
+fn test_corpus_fixtures_have_programs() {
+    // A fixture without a program would silently never run
+    for entry in fs::read_dir(CORPUS_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if matches!(extension, "stdin" | "stdout" | "stderr") {
+            assert!(path.with_extension("rs").exists(),
+                    "Fixture {} has no matching program", path.display());
+        }
+    }
+}
+
+#[test]
+fn test_corpus_modules_are_formatted() {
+    // Each backend lays its modules out the same way every time, and as formatting them
+    // again would, apart from the legacy comments carried into them
+    let backends: Vec<Box<dyn Transformer>> = vec![
+        Box::new(LegacyToHydroTransformer::new()),
+        Box::new(SynLegacyToHydroTransformer::new()),
+        Box::new(IOToHydroTransformer::new()),
+    ];
+    for case in discover_corpus(Path::new(CORPUS_DIR)).unwrap() {
+        for backend in &backends {
+            let module_name = format!("corpus_{}", case.name);
+            let Ok(output) = backend.transform_program(&case.program, &module_name) else {
+                continue;
+            };
+            let again = backend.transform_program(&case.program, &module_name).unwrap();
+            assert_eq!(output.hydro_function, again.hydro_function,
+                       "{} backend laid out {} differently on a second run", backend.name(), case.name);
+
+            let uncommented: String = output
+                .hydro_function
+                .lines()
+                .filter(|line| {
+                    let line = line.trim_start();
+                    !(line.starts_with("//") && !line.starts_with("///")) && !line.starts_with("/*")
+                })
+                .map(|line| format!("{}\n", line))
+                .collect();
+            assert_eq!(format_module(&uncommented).unwrap(), uncommented,
+                       "{} backend left {} unformatted", backend.name(), case.name);
+        }
+    }
+}
+
+#[test]
+fn test_corpus_locations_can_be_generic() {
+    // Whatever a backend makes of a program, its example names the tag of every location
+    // a generic dataflow function takes
+    let generic = TransformerConfig::new().with_generic_locations(true);
+    let backends: Vec<(Box<dyn Transformer>, Box<dyn Transformer>)> = vec![
+        (Box::new(SynLegacyToHydroTransformer::new()), Box::new(SynLegacyToHydroTransformer::new().with_config(generic.clone()))),
+        (Box::new(IOToHydroTransformer::new()), Box::new(IOToHydroTransformer::new().with_config(generic.clone()))),
+    ];
+    for case in discover_corpus(Path::new(CORPUS_DIR)).unwrap() {
+        for (backend, generic_backend) in &backends {
+            let module_name = format!("corpus_{}", case.name);
+            if backend.transform_program(&case.program, &module_name).is_err() {
+                continue;
+            }
+            let output = generic_backend
+                .transform_program(&case.program, &module_name)
+                .unwrap_or_else(|error| panic!("{} backend couldn't make {} generic: {}", backend.name(), case.name, error));
+            for untagged in ["flow.process()", "flow.cluster()"] {
+                assert!(!output.example_program.contains(untagged), "{} backend left a tag out of {}", backend.name(), case.name);
+            }
+        }
+    }
+}
+
+/// Collect every `<name>.rs` program in the corpus directory, sorted by name
+fn discover_corpus(dir: &Path) -> std::io::Result<Vec<CorpusCase>> {
+    let mut cases = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let program = entry?.path();
+        if program.extension().and_then(|e| e.to_str()) != Some("rs") {
+            continue;
+        }
+
+        let name = program.file_stem().unwrap().to_string_lossy().to_string();
+        let fixture = |extension: &str| fs::read_to_string(program.with_extension(extension)).ok();
+        cases.push(CorpusCase {
+            name,
+            stdin: fixture("stdin"),
+            expected_stdout: fixture("stdout"),
+            expected_stderr: fixture("stderr"),
+            program,
+        });
+    }
+
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+async fn run_case(case: &CorpusCase) -> Result<(), String> {
+    let program_path = case.program.to_string_lossy();
+    let legacy_output = run_legacy_program(&program_path, case.stdin.as_deref())
+        .map_err(|e| format!("legacy run failed: {}", e))?;
+
+    // Validate the fixtures against the legacy program before involving Hydro
+    if let Some(expected) = &case.expected_stdout {
+        if legacy_output.stdout.trim() != expected.trim() {
+            return Err(format!("legacy stdout does not match {}.stdout", case.name));
+        }
+    }
+    if let Some(expected) = &case.expected_stderr {
+        if legacy_output.stderr.trim() != expected.trim() {
+            return Err(format!("legacy stderr does not match {}.stderr", case.name));
+        }
+    }
+
+    // The generator runs from its own directory, so point it back at the repo root
+    let module_name = format!("corpus_{}", case.name);
+    generate_hydro_program(&format!("../{}", program_path), &module_name)
+        .map_err(|e| format!("generation failed: {}", e))?;
+
+    let hydro_output = run_generated_hydro_program(&module_name, case.stdin.as_deref()).await
+        .map_err(|e| format!("Hydro run failed: {}", e))?;
+
+    compare_outputs(&legacy_output, &hydro_output)
+}
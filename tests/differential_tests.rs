@@ -0,0 +1,111 @@
+// Differential tests between the string-based and syn-based transformers. Both
+// backends should produce the same Hydro function modulo formatting and
+// comments; any divergence of the simpler backend is flagged here before it
+// can be deprecated in favor of the syn one.
+use std::fs;
+use std::path::PathBuf;
+
+use hydro_template::config::{Lowering, TransformerConfig};
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+use hydro_template::transformer::LegacyToHydroTransformer;
+use proc_macro2::{Delimiter, TokenStream, TokenTree};
+use syn::Item;
+
+const CORPORA: &[&str] = &["src/legacy", "corpus"];
+
+#[test]
+fn test_string_and_syn_backends_agree() {
+    let mut divergences = Vec::new();
+
+    for legacy_path in legacy_programs() {
+        let module_name = "differential_test";
+        let string_output = LegacyToHydroTransformer::new()
+            .transform_program(&legacy_path, module_name)
+            .map(|output| output.hydro_function);
+        // The string backend has no AST to lower print macros with, nor to split off the
+        // pure statements a body starts with, so compare against the syn backend running
+        // the body whole with that pass switched off
+        let config = TransformerConfig::new().with_lowering(Lowering::Wrap).with_print_rewriting(false);
+        let syn_output = SynLegacyToHydroTransformer::new()
+            .with_config(config)
+            .transform_program(&legacy_path, module_name)
+            .map(|output| output.hydro_function);
+
+        match (string_output, syn_output) {
+            (Ok(string_code), Ok(syn_code)) => {
+                let (string_tokens, syn_tokens) = (normalize(&string_code), normalize(&syn_code));
+                if string_tokens != syn_tokens {
+                    divergences.push(format!(
+                        "{}:\n    string: {}\n    syn:    {}",
+                        legacy_path.display(), string_tokens, syn_tokens
+                    ));
+                }
+            }
+            (Err(e), Ok(_)) => divergences.push(format!("{}: only the string backend failed: {}", legacy_path.display(), e)),
+            (Ok(_), Err(e)) => divergences.push(format!("{}: only the syn backend failed: {}", legacy_path.display(), e)),
+            // Both rejecting a program is agreement
+            (Err(_), Err(_)) => {}
+        }
+    }
+
+    assert!(divergences.is_empty(), "Backends diverged on:\n  {}", divergences.join("\n  "));
+}
+
+#[test]
+fn test_normalize_ignores_formatting_and_comments() {
+    let compact = "use hydro_lang::*;\npub fn f(process: &Process) { process.source_iter(q!(0..1)).for_each(q!(|_| {})); }";
+    let spread = "use hydro_lang::*;\n\n// a comment\npub fn f(process: &Process) {\n    process\n        .source_iter(q!(0..1))\n        .for_each(q!(| _ | {}));\n}\n";
+    assert_eq!(normalize(compact), normalize(spread));
+}
+
+/// Reduce a generated module to the flattened tokens of its function items,
+/// which drops comments, whitespace, punctuation spacing, and trailing commas
+/// (prettyplease adds them inside macro arguments) but keeps every other token
+fn normalize(code: &str) -> String {
+    let file = match syn::parse_file(code) {
+        Ok(file) => file,
+        Err(e) => return format!("<unparseable: {}>", e),
+    };
+    let mut tokens = Vec::new();
+    for item in file.items.iter().filter(|item| matches!(item, Item::Fn(_))) {
+        flatten(quote::ToTokens::to_token_stream(item), &mut tokens);
+    }
+    tokens.join(" ")
+}
+
+fn flatten(stream: TokenStream, tokens: &mut Vec<String>) {
+    for tree in stream {
+        match tree {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::None => ("", ""),
+                };
+                tokens.push(open.to_string());
+                flatten(group.stream(), tokens);
+                if tokens.last().is_some_and(|last| last == ",") {
+                    tokens.pop();
+                }
+                tokens.push(close.to_string());
+            }
+            TokenTree::Punct(punct) => tokens.push(punct.as_char().to_string()),
+            other => tokens.push(other.to_string()),
+        }
+    }
+}
+
+fn legacy_programs() -> Vec<PathBuf> {
+    let mut programs = Vec::new();
+    for corpus in CORPORA {
+        for entry in fs::read_dir(corpus).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "rs") && !path.ends_with("mod.rs") {
+                programs.push(path);
+            }
+        }
+    }
+    programs.sort();
+    programs
+}
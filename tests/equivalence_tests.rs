@@ -1,11 +1,14 @@
-use std::process::Command;
-use std::fs;
-use tokio::time::{timeout, Duration};
+mod common;
+
+use common::{
+    assert_outputs_match, extract_program_output_from_hydro, generate_hydro_program,
+    generate_hydro_program_with_args, run_generated_hydro_program, run_legacy_program,
+};
 
 #[tokio::test]
 async fn test_hello_world_equivalence() {
     // Run the original legacy program
-    let legacy_output = run_legacy_program("generator/legacy_programs/hello_world.rs")
+    let legacy_output = run_legacy_program("generator/legacy_programs/hello_world.rs", None)
         .expect("Failed to run legacy program");
     
     // Generate the Hydro version using our generator
@@ -13,24 +16,20 @@ async fn test_hello_world_equivalence() {
         .expect("Failed to generate Hydro program");
     
     // Run the generated Hydro program and capture output
-    let hydro_output = run_generated_hydro_program("hello_world_test").await
+    let hydro_output = run_generated_hydro_program("hello_world_test", None).await
         .expect("Failed to run Hydro program");
     
     // Compare outputs
     println!("Legacy output: {:?}", legacy_output);
     println!("Hydro output: {:?}", hydro_output);
     
-    // The hydro output will have deployment info, so we need to extract just the program output
-    let hydro_program_output = extract_program_output_from_hydro(&hydro_output);
-    
-    assert_eq!(legacy_output.trim(), hydro_program_output.trim(), 
-               "Legacy and Hydro programs should produce identical output");
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
 
 #[tokio::test]
 async fn test_counter_equivalence() {
     // Run the original legacy program
-    let legacy_output = run_legacy_program("generator/legacy_programs/counter.rs")
+    let legacy_output = run_legacy_program("generator/legacy_programs/counter.rs", None)
         .expect("Failed to run legacy program");
     
     // Generate the Hydro version using our generator
@@ -38,104 +37,189 @@ async fn test_counter_equivalence() {
         .expect("Failed to generate Hydro program");
     
     // Run the generated Hydro program and capture output
-    let hydro_output = run_generated_hydro_program("counter_test").await
+    let hydro_output = run_generated_hydro_program("counter_test", None).await
         .expect("Failed to run Hydro program");
     
     // Compare outputs
     println!("Legacy output: {:?}", legacy_output);
     println!("Hydro output: {:?}", hydro_output);
     
-    // The hydro output will have deployment info, so we need to extract just the program output
-    let hydro_program_output = extract_program_output_from_hydro(&hydro_output);
+    assert_outputs_match(&legacy_output, &hydro_output);
+}
+
+#[tokio::test]
+async fn test_mixed_io_equivalence() {
+    // mixed_io writes progress to both stdout and stderr
+    let legacy_output = run_legacy_program("src/legacy/mixed_io.rs", None)
+        .expect("Failed to run legacy program");
+    assert!(!legacy_output.stderr.is_empty(), "Legacy program should write to stderr");
+    
+    generate_hydro_program("../src/legacy/mixed_io.rs", "mixed_io_test")
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("mixed_io_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    println!("Legacy output: {:?}", legacy_output);
+    println!("Hydro output: {:?}", hydro_output);
     
-    assert_eq!(legacy_output.trim(), hydro_program_output.trim(), 
-               "Legacy and Hydro programs should produce identical output");
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
 
-fn run_legacy_program(program_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Create a temporary executable name
-    let exe_name = format!("temp_legacy_{}", std::process::id());
-    
-    // Compile the program
-    let compile_result = Command::new("rustc")
-        .arg(program_path)
-        .arg("-o")
-        .arg(&exe_name)
-        .output()?;
-    
-    if !compile_result.status.success() {
-        return Err(format!("Legacy compilation failed: {}", 
-                          String::from_utf8_lossy(&compile_result.stderr)).into());
-    }
-    
-    // Run the compiled program
-    let run_result = Command::new(format!("./{}", exe_name))
-        .output()?;
-    
-    // Clean up executable
-    fs::remove_file(&exe_name).ok();
-    
-    if run_result.status.success() {
-        Ok(String::from_utf8_lossy(&run_result.stdout).to_string())
-    } else {
-        Err(format!("Legacy program execution failed: {}", 
-                   String::from_utf8_lossy(&run_result.stderr)).into())
-    }
+#[tokio::test]
+async fn test_word_count_equivalence() {
+    // Counts words into a HashMap and prints them sorted, the shape the keyed-fold
+    // lowering takes apart
+    let legacy_output = run_legacy_program("src/legacy/word_count.rs", None)
+        .expect("Failed to run legacy program");
+    assert!(legacy_output.stdout.contains("the: 3"));
+    
+    generate_hydro_program("../src/legacy/word_count.rs", "word_count_test")
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("word_count_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    println!("Legacy output: {:?}", legacy_output);
+    println!("Hydro output: {:?}", hydro_output);
+    
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
 
-fn generate_hydro_program(legacy_path: &str, module_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Run our generator to create the Hydro version
-    let generate_result = Command::new("cargo")
-        .arg("run")
-        .arg("--")
-        .arg(legacy_path)
-        .arg(module_name)
-        .current_dir("generator")
-        .output()?;
-    
-    if !generate_result.status.success() {
-        return Err(format!("Generator failed: {}", 
-                          String::from_utf8_lossy(&generate_result.stderr)).into());
-    }
-    
-    Ok(())
+#[tokio::test]
+async fn test_map_reduce_equivalence() {
+    // Sums squares in a loop, the shape the reduce lowering spreads over a cluster;
+    // the generated example deploys the default number of workers
+    let legacy_output = run_legacy_program("src/legacy/map_reduce.rs", None)
+        .expect("Failed to run legacy program");
+    assert_eq!(legacy_output.stdout.trim(), "Sum of squares: 338350");
+    
+    generate_hydro_program_with_args("../src/legacy/map_reduce.rs", "map_reduce_test", &["--backend", "syn", "--distribute", "reduce"])
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("map_reduce_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    println!("Legacy output: {:?}", legacy_output);
+    println!("Hydro output: {:?}", hydro_output);
+    
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
 
-async fn run_generated_hydro_program(module_name: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Run the generated Hydro program with timeout
-    let result = timeout(Duration::from_secs(90), async {
-        let run_result = Command::new("cargo")
-            .arg("run")
-            .arg("--example")
-            .arg(module_name)
-            .current_dir("template")
-            .output()?;
-        
-        if run_result.status.success() {
-            Ok::<String, Box<dyn std::error::Error>>(String::from_utf8_lossy(&run_result.stdout).to_string())
-        } else {
-            Err(format!("Hydro program execution failed: {}", 
-                       String::from_utf8_lossy(&run_result.stderr)).into())
-        }
-    }).await??;
-    
-    Ok(result)
+#[tokio::test]
+async fn test_exit_code_equivalence() {
+    // The legacy program reports an error and exits with a nonzero status
+    let legacy_output = run_legacy_program("generator/legacy_programs/exit_code.rs", None)
+        .expect("Failed to run legacy program");
+    assert_eq!(legacy_output.exit_code, Some(3));
+    
+    generate_hydro_program("legacy_programs/exit_code.rs", "exit_code_test")
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("exit_code_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
 
-fn extract_program_output_from_hydro(hydro_output: &str) -> String {
-    // Look for lines that contain the actual program output
-    // Format: [() (process 0)] Hello, world!
-    let mut program_lines = Vec::new();
-    
-    for line in hydro_output.lines() {
-        if line.contains("[() (process 0)]") && !line.contains("running command:") {
-            // Extract just the program output after the process identifier
-            if let Some(output_start) = line.find("] ") {
-                let program_output = &line[output_start + 2..];
-                program_lines.push(program_output);
-            }
-        }
-    }
-    
-    program_lines.join("\n")
+#[tokio::test]
+async fn test_panic_equivalence() {
+    // The legacy program panics on an out-of-bounds index after printing some values
+    let legacy_output = run_legacy_program("generator/legacy_programs/panic.rs", None)
+        .expect("Failed to run legacy program");
+    assert_eq!(legacy_output.exit_code, Some(101), "Rust panics exit with status 101");
+    
+    generate_hydro_program("legacy_programs/panic.rs", "panic_test")
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("panic_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    // The panic message names the thread and source location, which differ between
+    // the two builds, so only stdout and the exit status are compared here
+    let hydro_stdout = extract_program_output_from_hydro(&hydro_output.stdout);
+    assert_eq!(legacy_output.stdout.trim(), hydro_stdout.trim());
+    assert_eq!(legacy_output.exit_code, hydro_output.exit_code,
+               "Legacy and Hydro programs should exit with the same status");
+}
+
+#[tokio::test]
+async fn test_exit_code_equivalence_syn() {
+    // The syn backend's example reports the status of the exited process too
+    let legacy_output = run_legacy_program("generator/legacy_programs/exit_code.rs", None)
+        .expect("Failed to run legacy program");
+    
+    generate_hydro_program_with_args("legacy_programs/exit_code.rs", "exit_code_syn_test", &["--backend", "syn"])
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("exit_code_syn_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    assert_outputs_match(&legacy_output, &hydro_output);
+}
+
+#[tokio::test]
+async fn test_panic_equivalence_syn() {
+    let legacy_output = run_legacy_program("generator/legacy_programs/panic.rs", None)
+        .expect("Failed to run legacy program");
+    
+    generate_hydro_program_with_args("legacy_programs/panic.rs", "panic_syn_test", &["--backend", "syn"])
+        .expect("Failed to generate Hydro program");
+    
+    let hydro_output = run_generated_hydro_program("panic_syn_test", None).await
+        .expect("Failed to run Hydro program");
+    
+    let hydro_stdout = extract_program_output_from_hydro(&hydro_output.stdout);
+    assert_eq!(legacy_output.stdout.trim(), hydro_stdout.trim());
+    assert_eq!(legacy_output.exit_code, hydro_output.exit_code,
+               "Legacy and Hydro programs should exit with the same status");
+}
+
+#[tokio::test]
+async fn test_interactive_hello_equivalence() {
+    assert_scripted_equivalence(
+        "src/legacy/interactive_hello.rs",
+        "interactive_hello_test",
+        "Alice\n",
+    ).await;
+}
+
+#[tokio::test]
+async fn test_echo_lines_equivalence() {
+    // Includes a blank line to exercise the `continue` path of the legacy loop
+    assert_scripted_equivalence(
+        "src/legacy/echo_lines.rs",
+        "echo_lines_test",
+        "first line\n\nsecond line\n",
+    ).await;
+}
+
+#[tokio::test]
+async fn test_kv_store_equivalence() {
+    // Includes an unknown command and a GET of a key that was never set
+    assert_scripted_equivalence(
+        "src/legacy/kv_store.rs",
+        "kv_store_test",
+        "SET a 1\nGET a\nGET b\nDEL a\nSET b two\nGET b\n",
+    ).await;
+}
+
+/// Feed the same stdin script to the legacy binary and to the deployed Hydro
+/// process, then compare what both of them printed.
+async fn assert_scripted_equivalence(legacy_path: &str, module_name: &str, stdin_script: &str) {
+    let legacy_output = run_legacy_program(legacy_path, Some(stdin_script))
+        .expect("Failed to run legacy program");
+
+    // The generator runs from its own directory, so point it back at the repo root
+    generate_hydro_program(&format!("../{}", legacy_path), module_name)
+        .expect("Failed to generate Hydro program");
+
+    let hydro_output = run_generated_hydro_program(module_name, Some(stdin_script)).await
+        .expect("Failed to run Hydro program");
+
+    println!("Stdin script: {:?}", stdin_script);
+    println!("Legacy output: {:?}", legacy_output);
+    println!("Hydro output: {:?}", hydro_output);
+
+    assert_outputs_match(&legacy_output, &hydro_output);
 }
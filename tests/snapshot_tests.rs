@@ -0,0 +1,75 @@
+// Golden-file snapshot tests of the generated Hydro modules and examples.
+//
+// Every legacy program in src/legacy is transformed by each backend and the
+// output compared against tests/snapshots. After an intentional codegen change,
+// bless the new output with:
+//
+//     UPDATE_SNAPSHOTS=1 cargo test --test snapshot_tests
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hydro_template::backend::Transformer;
+use hydro_template::io_transformer::IOToHydroTransformer;
+use hydro_template::output::TransformOutput;
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+use hydro_template::transformer::LegacyToHydroTransformer;
+
+const LEGACY_DIR: &str = "src/legacy";
+const SNAPSHOT_DIR: &str = "tests/snapshots";
+
+fn backends() -> Vec<Box<dyn Transformer>> {
+    vec![
+        Box::new(LegacyToHydroTransformer::new()),
+        Box::new(SynLegacyToHydroTransformer::new()),
+        Box::new(IOToHydroTransformer::new()),
+    ]
+}
+
+#[test]
+fn test_generated_code_snapshots() {
+    let updating = std::env::var_os("UPDATE_SNAPSHOTS").is_some();
+    let mut mismatches = Vec::new();
+
+    for legacy_path in legacy_programs() {
+        let program = legacy_path.file_stem().unwrap().to_string_lossy().to_string();
+        for transformer in backends() {
+            let backend = transformer.name();
+            let module_name = format!("{}_hydro", program);
+            let TransformOutput { hydro_function, example_program, .. } = transformer
+                .transform_program(&legacy_path, &module_name)
+                .unwrap_or_else(|e| panic!("{} backend failed on {}: {}", backend, program, e));
+
+            for (kind, generated) in [("module", hydro_function), ("example", example_program)] {
+                let snapshot = Path::new(SNAPSHOT_DIR).join(format!("{}__{}__{}.rs.snap", program, backend, kind));
+                if updating {
+                    fs::create_dir_all(SNAPSHOT_DIR).unwrap();
+                    fs::write(&snapshot, &generated).unwrap();
+                    continue;
+                }
+
+                match fs::read_to_string(&snapshot) {
+                    Ok(expected) if expected == generated => {}
+                    Ok(_) => mismatches.push(format!("{} (output changed)", snapshot.display())),
+                    Err(_) => mismatches.push(format!("{} (snapshot missing)", snapshot.display())),
+                }
+            }
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "Generated code differs from snapshots; rerun with UPDATE_SNAPSHOTS=1 if intended:\n  {}",
+        mismatches.join("\n  ")
+    );
+}
+
+/// Legacy programs in a stable order, skipping the module index
+fn legacy_programs() -> Vec<PathBuf> {
+    let mut programs: Vec<PathBuf> = fs::read_dir(LEGACY_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "rs") && !path.ends_with("mod.rs"))
+        .collect();
+    programs.sort();
+    programs
+}
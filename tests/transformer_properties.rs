@@ -0,0 +1,157 @@
+// Property-based tests that feed randomly generated legacy programs through the
+// transformers to catch extraction and codegen edge cases systematically.
+use std::io::Write;
+use std::process::Command;
+
+use hydro_template::output::TransformOutput;
+use hydro_template::syn_transformer::SynLegacyToHydroTransformer;
+use hydro_template::transformer::LegacyToHydroTransformer;
+use proptest::prelude::*;
+use syn::{Expr, Item, Stmt};
+use tempfile::NamedTempFile;
+
+/// A single legacy statement: prints, let bindings, and (recursively) loops and branches
+fn statement() -> impl Strategy<Value = String> {
+    let leaf = prop_oneof![
+        "[a-zA-Z ,.!]{0,12}".prop_map(|text| format!("println!(\"{}\");", text)),
+        (0..100i64).prop_map(|n| format!("println!(\"value {{}}\", {});", n)),
+        Just("print!(\"partial \");".to_string()),
+        (0..100i64, 0..100i64).prop_map(|(a, b)| format!("let total = {} + {};\nprintln!(\"{{}}\", total);", a, b)),
+        "[a-z]{1,6}".prop_map(|word| format!("let word = \"{}\";\neprintln!(\"{{}}\", word.len());", word)),
+    ];
+
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        prop_oneof![
+            (1..5u32, prop::collection::vec(inner.clone(), 1..4)).prop_map(|(n, body)| {
+                format!("for i in 0..{} {{\n    let _ = i;\n{}\n}}", n, indent(&body.join("\n")))
+            }),
+            (prop::collection::vec(inner.clone(), 1..3), prop::collection::vec(inner, 0..3)).prop_map(
+                |(then_branch, else_branch)| {
+                    format!(
+                        "if 2 > 1 {{\n{}\n}} else {{\n{}\n}}",
+                        indent(&then_branch.join("\n")),
+                        indent(&else_branch.join("\n"))
+                    )
+                }
+            ),
+        ]
+    })
+}
+
+/// A complete legacy program whose main body is a handful of generated statements
+fn legacy_program() -> impl Strategy<Value = String> {
+    prop::collection::vec(statement(), 1..6)
+        .prop_map(|stmts| format!("fn main() {{\n{}\n}}\n", indent(&stmts.join("\n"))))
+}
+
+fn indent(code: &str) -> String {
+    code.lines().map(|line| format!("    {}", line)).collect::<Vec<_>>().join("\n")
+}
+
+fn write_temp_program(source: &str) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(source.as_bytes()).unwrap();
+    file
+}
+
+/// Find the body of the `map(q!(|_| { ... }))` closure that holds the legacy main body,
+/// i.e. the first map after `source_iter` in any operator chain of the function
+fn wrapped_main_body(hydro_function: &str) -> Option<String> {
+    let file = syn::parse_file(hydro_function).ok()?;
+    let func = file.items.iter().find_map(|item| match item {
+        Item::Fn(func) => Some(func),
+        _ => None,
+    })?;
+
+    func.block.stmts.iter().find_map(|stmt| {
+        let chain = match stmt {
+            Stmt::Expr(expr, _) => expr,
+            Stmt::Local(local) => &*local.init.as_ref()?.expr,
+            _ => return None,
+        };
+        // Walk the operator chain from the sink back to the source
+        let mut expr = chain;
+        let mut closest_map = None;
+        while let Expr::MethodCall(call) = expr {
+            if call.method == "map" {
+                closest_map = call.args.first();
+            } else if call.method == "source_iter" {
+                let Some(Expr::Macro(quoted)) = closest_map else {
+                    return None;
+                };
+                let closure: syn::ExprClosure = quoted.mac.parse_body().ok()?;
+                return Some(quote::ToTokens::to_token_stream(&closure.body).to_string());
+            }
+            expr = &call.receiver;
+        }
+        None
+    })
+}
+
+/// Type-check a block of code on its own with rustc, without producing a binary
+fn type_checks(body: &str) -> Result<(), String> {
+    let source = write_temp_program(&format!("fn main() {{\n    let _ = (|_: ()| {})(());\n}}\n", body));
+    let out_dir = tempfile::tempdir().unwrap();
+    let output = Command::new("rustc")
+        .arg("--edition=2021")
+        .arg("--emit=metadata")
+        .arg("--crate-name=property_check")
+        .arg("--out-dir")
+        .arg(out_dir.path())
+        .arg(source.path())
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).to_string())
+    }
+}
+
+proptest! {
+    #[test]
+    fn string_transformer_output_parses(source in legacy_program()) {
+        let legacy_file = write_temp_program(&source);
+        let TransformOutput { hydro_function, example_program, .. } = LegacyToHydroTransformer::new()
+            .transform_program(legacy_file.path(), "property_test")
+            .map_err(|e| TestCaseError::fail(format!("transform failed: {}", e)))?;
+
+        prop_assert!(syn::parse_file(&hydro_function).is_ok(), "module does not parse:\n{}", hydro_function);
+        prop_assert!(syn::parse_file(&example_program).is_ok(), "example does not parse:\n{}", example_program);
+    }
+
+    #[test]
+    fn syn_transformer_output_parses(source in legacy_program()) {
+        let legacy_file = write_temp_program(&source);
+        let TransformOutput { hydro_function, example_program, .. } = SynLegacyToHydroTransformer::new()
+            .transform_program(legacy_file.path(), "property_test")
+            .map_err(|e| TestCaseError::fail(format!("transform failed: {}", e)))?;
+
+        prop_assert!(syn::parse_file(&hydro_function).is_ok(), "module does not parse:\n{}", hydro_function);
+        prop_assert!(syn::parse_file(&example_program).is_ok(), "example does not parse:\n{}", example_program);
+    }
+}
+
+proptest! {
+    // Each case spawns rustc, so keep the case count low
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn transformed_bodies_type_check(source in legacy_program()) {
+        let legacy_file = write_temp_program(&source);
+
+        // The Hydro wrapper itself needs the full hydro_lang toolchain, but the legacy
+        // body moved inside the quoted closure must stand on its own
+        for hydro_function in [
+            LegacyToHydroTransformer::new().transform_program(legacy_file.path(), "property_test").unwrap().hydro_function,
+            SynLegacyToHydroTransformer::new().transform_program(legacy_file.path(), "property_test").unwrap().hydro_function,
+        ] {
+            let body = wrapped_main_body(&hydro_function);
+            prop_assert!(body.is_some(), "no wrapped main body found in:\n{}", hydro_function);
+            if let Err(errors) = type_checks(&body.unwrap()) {
+                return Err(TestCaseError::fail(format!("body does not type-check:\n{}\n{}", source, errors)));
+            }
+        }
+    }
+}